@@ -0,0 +1,55 @@
+//! The export of a thousand services to a bootstrap, through the `export`
+//! command of the controller, from the parsing of the services file to the
+//! writing of the bootstrap.
+use criterion::{criterion_group, criterion_main, Criterion};
+use std::path::Path;
+use std::process::Command;
+
+const SERVICES: u32 = 1000;
+
+// Services with a couple of hosts and proxy rules each, as those of a big
+// tenant.
+fn services() -> serde_json::Value {
+    (1..=SERVICES)
+        .map(|id| {
+            serde_json::json!({
+                "id": id,
+                "hosts": [format!("service-{}", id), format!("service-{}.app", id)],
+                "policies": [],
+                "target_domain": format!("http://service-{}.app:80", id),
+                "proxy_rules": [
+                    {"pattern": "/", "http_method": "GET", "metric_system_name": "hits", "delta": 1},
+                    {"pattern": "/orders", "http_method": "POST", "metric_system_name": "orders", "delta": 1},
+                ],
+            })
+        })
+        .collect()
+}
+
+fn export(dir: &Path) {
+    let status = Command::new(env!("CARGO_BIN_EXE_gateway-ng-controller"))
+        .arg("export")
+        .arg("--services-config")
+        .arg(dir.join("services.json"))
+        .arg("--wasm-filter-path")
+        .arg(dir.join("filter.wasm"))
+        .args(["--log-level", "warn", "-o"])
+        .arg(dir.join("bootstrap.yaml"))
+        .status()
+        .unwrap();
+    assert!(status.success());
+}
+
+fn bench(c: &mut Criterion) {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("services.json"), services().to_string()).unwrap();
+    std::fs::write(dir.path().join("filter.wasm"), b"\0asm").unwrap();
+
+    let mut group = c.benchmark_group("export");
+    group.sample_size(10);
+    group.bench_function("1000 services", |b| b.iter(|| export(dir.path())));
+    group.finish();
+}
+
+criterion_group!(benches, bench);
+criterion_main!(benches);
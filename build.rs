@@ -1,30 +1,182 @@
+const PROTOS: &[&str] = &[
+    "./protos/envoyproxy/data-plane-api/envoy/config/cluster/v3/cluster.proto",
+    "./protos/envoyproxy/data-plane-api/envoy/config/listener/v3/listener.proto",
+    "./protos/envoyproxy/data-plane-api/envoy/service/cluster/v3/cds.proto",
+    "./protos/envoyproxy/data-plane-api/envoy/service/listener/v3/lds.proto",
+    "./protos/envoyproxy/data-plane-api/envoy/service/discovery/v3/ads.proto",
+    "./protos/envoyproxy/data-plane-api/envoy/service/secret/v3/sds.proto",
+    "./protos/envoyproxy/data-plane-api/envoy/config/endpoint/v3/endpoint.proto",
+    "./protos/envoyproxy/data-plane-api/envoy/extensions/filters/network/http_connection_manager/v3/http_connection_manager.proto",
+    "./protos/envoyproxy/data-plane-api/envoy/extensions/filters/http/router/v3/router.proto",
+    "./protos/envoyproxy/data-plane-api/envoy/extensions/filters/http/wasm/v3/wasm.proto",
+    "./protos/envoyproxy/data-plane-api/envoy/extensions/wasm/v3/wasm.proto",
+    "./protos/envoyproxy/data-plane-api/envoy/extensions/filters/http/jwt_authn/v3/config.proto",
+    "./protos/envoyproxy/data-plane-api/envoy/extensions/filters/http/rbac/v3/rbac.proto",
+    "./protos/envoyproxy/data-plane-api/envoy/extensions/filters/http/local_ratelimit/v3/local_rate_limit.proto",
+    "./protos/envoyproxy/data-plane-api/envoy/extensions/filters/http/dynamic_forward_proxy/v3/dynamic_forward_proxy.proto",
+    "./protos/envoyproxy/data-plane-api/envoy/extensions/filters/http/cors/v3/cors.proto",
+    "./protos/envoyproxy/data-plane-api/envoy/extensions/filters/listener/original_dst/v3/original_dst.proto",
+    "./protos/envoyproxy/data-plane-api/envoy/extensions/clusters/dynamic_forward_proxy/v3/cluster.proto",
+    "./protos/envoyproxy/data-plane-api/envoy/extensions/request_id/uuid/v3/uuid.proto",
+    "./protos/envoyproxy/data-plane-api/envoy/extensions/transport_sockets/tls/v3/tls.proto",
+    "./protos/envoyproxy/data-plane-api/envoy/extensions/transport_sockets/quic/v3/quic_transport.proto",
+    "./protos/grpc/grpc/health/v1/health.proto",
+    "./protos/grpc/grpc/reflection/v1alpha/reflection.proto",
+];
+
+// The gRPC health and reflection protos are vendored under protos/grpc, being
+// the only ones needed out of grpc-proto.
+const INCLUDES: &[&str] = &[
+    "./protos/envoyproxy/data-plane-api/",
+    "./protos/grpc/",
+    "./protos/googleapis/",
+    "./protos/envoyproxy/protoc-gen-validate/",
+    "./protos/cncf/udpa/",
+];
+
+// Descriptors of every message, for the protobuf JSON encoding of exported
+// resources.
+fn write_descriptor_set() -> Result<(), Box<dyn std::error::Error>> {
+    let out_dir = std::path::PathBuf::from(std::env::var("OUT_DIR")?);
+    let mut protoc = std::process::Command::new(prost_build::protoc());
+    protoc
+        .arg("--include_imports")
+        .arg("-o")
+        .arg(out_dir.join("descriptor_set.bin"));
+    for include in INCLUDES {
+        protoc.arg("-I").arg(include);
+    }
+    protoc.arg("-I").arg(prost_build::protoc_include());
+    let output = protoc.args(PROTOS).output()?;
+    if !output.status.success() {
+        return Err(format!("protoc failed: {}", String::from_utf8_lossy(&output.stderr)).into());
+    }
+    Ok(())
+}
+
+// The type URLs of the messages, the impls of the build script having no
+// constant of `src/type_urls.rs` to make them of.
+const TYPE_URL_PREFIX: &str = "type.googleapis.com/";
+
+// The comment prost-build writes before each message, for the impls to name
+// the message the way prost-build did.
+const MESSAGE_MARKER: &str = "// message: ";
+
+// The messages of the protos, each before those nested in it. The
+// well-known types are those of prost-types, and the maps are generated as
+// maps rather than messages.
+fn message_names(set: &prost_types::FileDescriptorSet) -> Vec<String> {
+    fn nested(names: &mut Vec<String>, parent: &str, message: &prost_types::DescriptorProto) {
+        if message.options.as_ref().and_then(|o| o.map_entry) == Some(true) {
+            return;
+        }
+        let name = format!("{}.{}", parent, message.name());
+        names.push(name.clone());
+        for message in &message.nested_type {
+            nested(names, &name, message);
+        }
+    }
+    let mut names = Vec::new();
+    for file in set
+        .file
+        .iter()
+        .filter(|file| file.package() != "google.protobuf")
+    {
+        for message in &file.message_type {
+            nested(&mut names, file.package(), message);
+        }
+    }
+    names
+}
+
+fn read_descriptor_set() -> Result<prost_types::FileDescriptorSet, Box<dyn std::error::Error>> {
+    use prost::Message;
+    let out_dir = std::path::PathBuf::from(std::env::var("OUT_DIR")?);
+    let set = std::fs::read(out_dir.join("descriptor_set.bin"))?;
+    Ok(prost_types::FileDescriptorSet::decode(&*set)?)
+}
+
+// The impls of the messages of `code`, a generated file, at the end of it.
+// Each message follows its marker, the modules of the nested ones being
+// closed at the indentation they were opened at.
+fn type_url_impls(code: &str) -> String {
+    let mut impls = String::new();
+    let mut modules: Vec<(usize, &str)> = Vec::new();
+    let mut marked = None;
+    for line in code.lines() {
+        let indent = line.len() - line.trim_start().len();
+        let line = line.trim();
+        if let Some(name) = line.strip_prefix(MESSAGE_MARKER) {
+            // the markers of the enclosing messages come first
+            marked = Some(name);
+        } else if let Some(rest) = line.strip_prefix("pub struct ") {
+            let name = match marked.take() {
+                Some(name) => name,
+                None => continue,
+            };
+            let ident = rest
+                .split(|c: char| !(c.is_alphanumeric() || c == '_' || c == '#'))
+                .next()
+                .unwrap_or_default();
+            let mut path: Vec<&str> = modules.iter().map(|(_, module)| *module).collect();
+            path.push(ident);
+            impls.push_str(&format!(
+                "impl crate::type_urls::TypeUrl for {} {{\n    const NAME: &'static str = \"{}\";\n    const TYPE_URL: &'static str = \"{}{}\";\n}}\n",
+                path.join("::"),
+                name,
+                TYPE_URL_PREFIX,
+                name
+            ));
+        } else if line.starts_with("pub enum ") {
+            // oneofs and enums are marked as their messages are
+            marked = None;
+        } else if let Some(rest) = line.strip_prefix("pub mod ") {
+            modules.push((indent, rest.trim_end_matches(" {")));
+        } else if line == "}" && modules.last().map(|(at, _)| *at) == Some(indent) {
+            modules.pop();
+        }
+    }
+    impls
+}
+
+// The type URLs of the generated messages, so that none is spelled by hand.
+fn write_type_urls() -> Result<(), Box<dyn std::error::Error>> {
+    use std::io::Write;
+    for entry in std::fs::read_dir("src/protobuf")? {
+        let path = entry?.path();
+        if path.extension().and_then(|extension| extension.to_str()) != Some("rs") {
+            continue;
+        }
+        let code = std::fs::read_to_string(&path)?;
+        // the files of packages the build no longer generates keep theirs
+        if code.contains("impl crate::type_urls::TypeUrl") {
+            continue;
+        }
+        let impls = type_url_impls(&code);
+        let mut file = std::fs::OpenOptions::new().append(true).open(path)?;
+        file.write_all(impls.as_bytes())?;
+    }
+    Ok(())
+}
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("cargo:rerun-if-changed=Cargo.lock");
     println!("cargo:rerun-if-changed=protos");
     // Best effort in creating the directory - could fail for many reasons
     let _ = std::fs::create_dir("src/protobuf");
+    // Maps are generated as BTreeMap so that resources encode the same way
+    // every time and their content hash is stable.
+    let mut config = prost_build::Config::new();
+    config.btree_map(["."]);
+    write_descriptor_set()?;
+    for name in message_names(&read_descriptor_set()?) {
+        config.type_attribute(format!(".{}", name), format!("{}{}", MESSAGE_MARKER, name));
+    }
     // Note: tonic_build by default uses rustfmt to prettify sources
-    tonic_build::configure().out_dir("src/protobuf").compile(
-        &[
-            "./protos/envoyproxy/data-plane-api/envoy/config/cluster/v3/cluster.proto",
-            "./protos/envoyproxy/data-plane-api/envoy/config/listener/v3/listener.proto",
-            "./protos/envoyproxy/data-plane-api/envoy/service/cluster/v3/cds.proto",
-            "./protos/envoyproxy/data-plane-api/envoy/service/listener/v3/lds.proto",
-            "./protos/envoyproxy/data-plane-api/envoy/config/endpoint/v3/endpoint.proto",
-            "./protos/envoyproxy/data-plane-api/envoy/extensions/filters/network/http_connection_manager/v3/http_connection_manager.proto",
-            "./protos/envoyproxy/data-plane-api/envoy/extensions/filters/http/router/v3/router.proto",
-            "./protos/envoyproxy/data-plane-api/envoy/extensions/filters/http/wasm/v3/wasm.proto",
-            "./protos/envoyproxy/data-plane-api/envoy/extensions/wasm/v3/wasm.proto",
-            "./protos/envoyproxy/data-plane-api/envoy/extensions/filters/http/jwt_authn/v3/config.proto",
-            "./protos/envoyproxy/data-plane-api/envoy/extensions/transport_sockets/tls/v3/tls.proto",
-        ],
-        &[
-            "./protos/envoyproxy/data-plane-api/",
-            "./protos/googleapis/",
-            "./protos/envoyproxy/protoc-gen-validate/",
-            "./protos/cncf/udpa/",
-        ],
-    )?;
+    tonic_build::configure()
+        .out_dir("src/protobuf")
+        .compile_with_config(config, PROTOS, INCLUDES)?;
+    write_type_urls()?;
 
     Ok(())
 }
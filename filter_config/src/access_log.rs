@@ -0,0 +1,146 @@
+//! What the filter tells the access logs of Envoy about its decisions.
+//!
+//! The filter sets the fields below as properties of the request before
+//! resuming or rejecting it. Envoy keeps them in the filter state of the
+//! request, prefixed with `wasm.`, for access logs to print them as in:
+//!
+//! ```text
+//! decision=%FILTER_STATE(wasm.com.3scale.gateway.decision:PLAIN)%
+//!   metric=%FILTER_STATE(wasm.com.3scale.gateway.metric:PLAIN)%
+//!   credential=%FILTER_STATE(wasm.com.3scale.gateway.credential_type:PLAIN)%
+//! ```
+//!
+//! The names are those of the namespace of the metadata the controller sets
+//! on the routes and clusters, and are not to change.
+use std::collections::BTreeMap;
+
+use crate::LimitKey;
+
+/// The namespace of the properties.
+pub const NAMESPACE: &str = "com.3scale.gateway";
+/// `allow` or `deny`.
+pub const DECISION: &str = "decision";
+/// The metrics the request matched, comma separated, empty when no mapping
+/// rule matched.
+pub const METRIC: &str = "metric";
+/// What the request was told apart by: `header` for the credential header
+/// of the local limits, `client_ip` for its address, `none` otherwise.
+pub const CREDENTIAL_TYPE: &str = "credential_type";
+
+/// The credential type of the requests carrying none.
+pub const NO_CREDENTIAL: &str = "none";
+
+/// The credential type of the requests told apart by `key`.
+pub fn credential_type(key: &LimitKey) -> &'static str {
+    match key {
+        LimitKey::Credential { .. } => "header",
+        LimitKey::ClientIp => "client_ip",
+    }
+}
+
+/// The properties telling the decision about a request, by the name the
+/// filter sets them with: whether it is `allowed`, the `metrics` it
+/// matched and its `credential_type`.
+pub fn properties(
+    allowed: bool,
+    metrics: Option<&BTreeMap<std::string::String, u32>>,
+    credential_type: &str,
+) -> Vec<(std::string::String, std::string::String)> {
+    let metrics = metrics
+        .map(|metrics| {
+            metrics
+                .keys()
+                .map(std::string::String::as_str)
+                .collect::<Vec<_>>()
+                .join(",")
+        })
+        .unwrap_or_default();
+    let decision = if allowed { "allow" } else { "deny" };
+    vec![
+        (format!("{}.{}", NAMESPACE, DECISION), decision.to_string()),
+        (format!("{}.{}", NAMESPACE, METRIC), metrics),
+        (
+            format!("{}.{}", NAMESPACE, CREDENTIAL_TYPE),
+            credential_type.to_string(),
+        ),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Decision, FilterConfig};
+
+    fn config(no_match_action: &str) -> FilterConfig {
+        serde_json::from_str(&format!(
+            r#"{{
+                "id": 1,
+                "proxy_rules": [
+                    {{"pattern": "/$", "http_method": "GET", "metric_system_name": "hits", "delta": 1}},
+                    {{"pattern": "/$", "http_method": "GET", "metric_system_name": "home", "delta": 1}}
+                ],
+                "no_match_action": {}
+            }}"#,
+            no_match_action
+        ))
+        .unwrap()
+    }
+
+    // The properties the filter sets for a request of `path`, the backend
+    // allowing the matched ones.
+    fn decided(
+        config: &FilterConfig,
+        path: &str,
+        credential: &str,
+    ) -> Vec<(&'static str, std::string::String)> {
+        let properties = match config.decide("GET".to_string(), path.to_string(), "") {
+            Decision::Authrep(metrics) => properties(true, Some(&metrics), credential),
+            Decision::Allow => properties(true, None, credential),
+            Decision::Deny(..) => properties(false, None, credential),
+        };
+        properties
+            .into_iter()
+            .map(|(name, value)| {
+                let field = [DECISION, METRIC, CREDENTIAL_TYPE]
+                    .iter()
+                    .find(|field| name == format!("com.3scale.gateway.{}", field))
+                    .unwrap();
+                (*field, value)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn decisions_are_told_to_the_access_logs() {
+        let header = credential_type(&LimitKey::Credential {
+            header: "x-api-key".to_string(),
+        });
+        let allowing = config(r#"{"action": "allow"}"#);
+        assert_eq!(
+            decided(&allowing, "/", header),
+            [
+                ("decision", "allow".to_string()),
+                ("metric", "hits,home".to_string()),
+                ("credential_type", "header".to_string()),
+            ]
+        );
+        // no mapping rule matched
+        assert_eq!(
+            decided(&allowing, "/nope", NO_CREDENTIAL),
+            [
+                ("decision", "allow".to_string()),
+                ("metric", "".to_string()),
+                ("credential_type", "none".to_string()),
+            ]
+        );
+        let denying = config(r#"{"action": "deny"}"#);
+        assert_eq!(
+            decided(&denying, "/nope", credential_type(&LimitKey::ClientIp)),
+            [
+                ("decision", "deny".to_string()),
+                ("metric", "".to_string()),
+                ("credential_type", "client_ip".to_string()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,661 @@
+//! Plugin configuration handed by the controller to the wasm filter.
+//!
+//! The controller builds a [`FilterConfig`] out of its own `Service` and
+//! serializes it into the `PluginConfig`, and the filter deserializes it back
+//! in `import_config`. Unknown fields are ignored on purpose, so a newer
+//! controller can roll out before the filter that understands its additions.
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+pub mod access_log;
+mod local_limits;
+#[cfg(test)]
+mod memory_store;
+pub mod pending_reports;
+mod request_id;
+pub mod rule_pattern;
+mod shared_store;
+
+pub use local_limits::{LimitKey, LocalLimits};
+pub use request_id::{denial_body, request_id, REQUEST_ID_HEADER};
+pub use shared_store::SharedStore;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct MappingRule {
+    pub pattern: std::string::String,
+    pub http_method: std::string::String, // @TODO this should be a enum, maybe from hyper
+    pub metric_system_name: std::string::String,
+    pub delta: u32,
+}
+
+impl MappingRule {
+    fn matches(
+        &self,
+        method: std::string::String,
+        path: std::string::String,
+        request_id: &str,
+    ) -> bool {
+        log::debug!(
+            "MappingRule:Match: METHOD:: '{}', PATH:: '{}', MappingRULE: '{:?}', request_id='{}'",
+            method,
+            path,
+            self,
+            request_id
+        );
+
+        if self.http_method != method {
+            return false;
+        }
+        rule_pattern::matches(&self.pattern, &path)
+    }
+}
+
+/// What to do with a request that does not match any mapping rule.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum NoMatchAction {
+    /// Let the request through without reporting anything.
+    Allow,
+    /// Reply locally with the given status and body.
+    Deny {
+        #[serde(default = "default_deny_status")]
+        status: u32,
+        #[serde(default = "default_deny_body")]
+        body: std::string::String,
+    },
+    /// Report a fixed metric, as if a catch-all mapping rule had matched.
+    ReportDefaultMetric {
+        metric: std::string::String,
+        #[serde(default = "default_delta")]
+        delta: u32,
+    },
+}
+
+fn default_deny_status() -> u32 {
+    403
+}
+
+fn default_deny_body() -> std::string::String {
+    "Mapping rule not found\n".to_string()
+}
+
+fn default_delta() -> u32 {
+    1
+}
+
+impl Default for NoMatchAction {
+    fn default() -> Self {
+        NoMatchAction::Deny {
+            status: default_deny_status(),
+            body: default_deny_body(),
+        }
+    }
+}
+
+impl NoMatchAction {
+    // There is no no-match behavior at the listener level: the route catching
+    // every path the rules don't comes last with the filter on, see
+    // `unmatched_requests_reach_the_filter` in the controller, so this action
+    // is the only one. These are checks of its values alone: a denial with a
+    // status that is not an error, or a metric without a name or a delta.
+    pub fn validate(&self) -> Result<(), std::string::String> {
+        match self {
+            NoMatchAction::Allow => Ok(()),
+            NoMatchAction::Deny { status, .. } => {
+                if !(400..600).contains(status) {
+                    return Err(format!(
+                        "no_match_action deny status must be a 4xx or 5xx code, got {}",
+                        status
+                    ));
+                }
+                Ok(())
+            }
+            NoMatchAction::ReportDefaultMetric { metric, delta } => {
+                if metric.is_empty() {
+                    return Err(
+                        "no_match_action report_default_metric needs a metric name".to_string()
+                    );
+                }
+                if *delta == 0 {
+                    return Err(
+                        "no_match_action report_default_metric delta must be positive".to_string(),
+                    );
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// How the matched metrics are rendered into the forwarded header.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum MetricsHeaderFormat {
+    /// `{"hits":1}`
+    #[default]
+    Json,
+    /// `hits=1,ticks=2`
+    CommaSeparated,
+}
+
+/// Forward the metrics a request was attributed to as a request header. Any
+/// client-provided value of the header is stripped.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MetricsHeader {
+    pub name: std::string::String,
+    #[serde(default)]
+    pub format: MetricsHeaderFormat,
+}
+
+impl MetricsHeader {
+    pub fn validate(&self) -> Result<(), std::string::String> {
+        let valid = !self.name.is_empty()
+            && self
+                .name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !valid {
+            return Err(format!(
+                "metrics_header name '{}' is not a valid header name",
+                self.name
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn render(&self, metrics: &BTreeMap<std::string::String, u32>) -> std::string::String {
+        match self.format {
+            MetricsHeaderFormat::Json => serde_json::to_string(metrics).unwrap(),
+            MetricsHeaderFormat::CommaSeparated => {
+                let mut entries: Vec<std::string::String> = metrics
+                    .iter()
+                    .map(|(name, delta)| format!("{}={}", name, delta))
+                    .collect();
+                entries.sort();
+                entries.join(",")
+            }
+        }
+    }
+}
+
+/// When usage gets reported to the backend.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ReportOn {
+    /// Authorize and report in one go in the request phase.
+    #[default]
+    Always,
+    /// Report in the response phase, only for 2xx and 3xx upstream responses.
+    SuccessfulOnly,
+    /// Report in the response phase for the listed classes, e.g. `["2xx", "4xx"]`.
+    StatusClasses(Vec<std::string::String>),
+}
+
+impl ReportOn {
+    pub fn validate(&self) -> Result<(), std::string::String> {
+        if let ReportOn::StatusClasses(classes) = self {
+            if classes.is_empty() {
+                return Err("report_on status_classes cannot be empty".to_string());
+            }
+            for class in classes {
+                if status_class(class).is_none() {
+                    return Err(format!(
+                        "report_on status class '{}' is not one of 1xx to 5xx",
+                        class
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether reporting is deferred to the upstream response.
+    pub fn on_response(&self) -> bool {
+        *self != ReportOn::Always
+    }
+
+    /// Whether a request answered with `status` by the upstream is reported.
+    pub fn should_report(&self, status: u32) -> bool {
+        match self {
+            ReportOn::Always => true,
+            ReportOn::SuccessfulOnly => (200..400).contains(&status),
+            ReportOn::StatusClasses(classes) => classes
+                .iter()
+                .filter_map(|class| status_class(class))
+                .any(|class| status / 100 == class),
+        }
+    }
+}
+
+fn status_class(class: &str) -> Option<u32> {
+    match class.to_ascii_lowercase().as_str() {
+        "1xx" => Some(1),
+        "2xx" => Some(2),
+        "3xx" => Some(3),
+        "4xx" => Some(4),
+        "5xx" => Some(5),
+        _ => None,
+    }
+}
+
+/// A policy of the service left to the filter, Envoy having no filter of
+/// its own for it, with its configuration as the services file has it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Policy {
+    pub name: std::string::String,
+    #[serde(default)]
+    pub configuration: serde_json::Value,
+}
+
+/// Outcome of matching a request against the service configuration.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Decision {
+    /// Authorize and report the matched metrics.
+    Authrep(BTreeMap<std::string::String, u32>),
+    Allow,
+    Deny(u32, std::string::String),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct FilterConfig {
+    pub id: u32,
+    pub proxy_rules: Vec<MappingRule>,
+    #[serde(default)]
+    pub no_match_action: NoMatchAction,
+    #[serde(default)]
+    pub metrics_header: Option<MetricsHeader>,
+    #[serde(default)]
+    pub local_limits: Option<LocalLimits>,
+    #[serde(default)]
+    pub report_on: ReportOn,
+    // in the order of the policies of the service
+    #[serde(default)]
+    pub policies: Vec<Policy>,
+    // labels of the service, for the logs of the filter
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub annotations: BTreeMap<std::string::String, std::string::String>,
+    // tell the id of the request in the bodies of the denials
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub request_id_in_denials: bool,
+}
+
+impl FilterConfig {
+    /// Parse the configuration as handed over by Envoy.
+    ///
+    /// The controller sends a `google.protobuf.Struct`, which Envoy renders as
+    /// a JSON object. Older controllers sent the JSON document inside a
+    /// `google.protobuf.StringValue`, which may show up either as the raw
+    /// string, as a JSON string literal, or still protobuf-encoded; all of
+    /// those are accepted while both controller versions are around.
+    pub fn from_bytes(config: &[u8]) -> Result<FilterConfig, std::string::String> {
+        let error = match serde_json::from_slice::<FilterConfig>(config) {
+            Ok(config) => return Ok(config),
+            Err(e) => e,
+        };
+
+        if let Ok(document) = serde_json::from_slice::<std::string::String>(config) {
+            return serde_json::from_str(document.as_str()).map_err(|e| e.to_string());
+        }
+
+        match decode_string_value(config) {
+            Some(document) => serde_json::from_slice(document).map_err(|e| e.to_string()),
+            None => Err(error.to_string()),
+        }
+    }
+
+    /// The change to make to the metrics header of the request going
+    /// upstream, out of the `headers` the client sent and the metrics the
+    /// request was attributed to, none until it is: the header set to the
+    /// metrics, or else removed when the client sent it, for a value of the
+    /// client never to get through. None without a metrics header.
+    pub fn upstream_metrics_header(
+        &self,
+        headers: &[(std::string::String, std::string::String)],
+        metrics: Option<&BTreeMap<std::string::String, u32>>,
+    ) -> Option<(&str, Option<std::string::String>)> {
+        let header = self.metrics_header.as_ref()?;
+        match metrics {
+            Some(metrics) => Some((&header.name, Some(header.render(metrics)))),
+            None if headers
+                .iter()
+                .any(|(name, _)| name.eq_ignore_ascii_case(&header.name)) =>
+            {
+                Some((&header.name, None))
+            }
+            None => None,
+        }
+    }
+
+    pub fn match_mapping_rule(
+        &self,
+        method: std::string::String,
+        path: std::string::String,
+        request_id: &str,
+    ) -> (bool, BTreeMap<std::string::String, u32>) {
+        let mut metrics: BTreeMap<std::string::String, u32> = BTreeMap::new();
+        for mapping_rule in &self.proxy_rules {
+            if mapping_rule.matches(method.clone(), path.clone(), request_id) {
+                log::debug!(
+                    "Mapping rule matches: {:?}, request_id='{}'",
+                    mapping_rule,
+                    request_id
+                );
+                metrics.insert(mapping_rule.metric_system_name.clone(), mapping_rule.delta);
+            }
+        }
+        (!metrics.is_empty(), metrics)
+    }
+
+    /// What to do with the request of `request_id`, the decision logged
+    /// with it.
+    pub fn decide(
+        &self,
+        method: std::string::String,
+        path: std::string::String,
+        request_id: &str,
+    ) -> Decision {
+        let (status, metrics) = self.match_mapping_rule(method, path, request_id);
+        if status {
+            log::debug!(
+                "Mapping rules matched for service {}, metrics={:?}, request_id='{}'",
+                self.id,
+                metrics,
+                request_id
+            );
+            return Decision::Authrep(metrics);
+        }
+
+        match &self.no_match_action {
+            NoMatchAction::Allow => {
+                log::debug!(
+                    "No mapping rule matched, letting the request through, request_id='{}'",
+                    request_id
+                );
+                Decision::Allow
+            }
+            NoMatchAction::Deny { status, body } => {
+                log::info!(
+                    "No mapping rule matched for service {}, denied with {}, request_id='{}'",
+                    self.id,
+                    status,
+                    request_id
+                );
+                let body = if self.request_id_in_denials {
+                    denial_body(body, request_id)
+                } else {
+                    body.clone()
+                };
+                Decision::Deny(*status, body)
+            }
+            NoMatchAction::ReportDefaultMetric { metric, delta } => {
+                log::debug!(
+                    "No mapping rule matched for service {}, reporting {}, request_id='{}'",
+                    self.id,
+                    metric,
+                    request_id
+                );
+                let mut metrics: BTreeMap<std::string::String, u32> = BTreeMap::new();
+                metrics.insert(metric.clone(), *delta);
+                Decision::Authrep(metrics)
+            }
+        }
+    }
+}
+
+// Extract field 1 of a protobuf encoded google.protobuf.StringValue.
+fn decode_string_value(buf: &[u8]) -> Option<&[u8]> {
+    let (tag, rest) = buf.split_first()?;
+    // field number 1, wire type 2 (length delimited)
+    if *tag != 0x0a {
+        return None;
+    }
+
+    let mut len: usize = 0;
+    for (i, byte) in rest.iter().enumerate().take(10) {
+        len |= ((byte & 0x7f) as usize) << (7 * i);
+        if byte & 0x80 == 0 {
+            let payload = &rest[i + 1..];
+            if payload.len() != len {
+                return None;
+            }
+            return Some(payload);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(extra: &str) -> FilterConfig {
+        let config = format!(
+            r#"{{
+                "id": 1,
+                "proxy_rules": [
+                    {{"pattern": "/$", "http_method": "GET", "metric_system_name": "hits", "delta": 1}}
+                ]
+                {}
+            }}"#,
+            extra
+        );
+        serde_json::from_str(config.as_str()).unwrap()
+    }
+
+    // The lines logged by the current thread, the tests running apart.
+    struct Captured;
+
+    thread_local! {
+        static LINES: std::cell::RefCell<Vec<std::string::String>> = Default::default();
+    }
+
+    impl log::Log for Captured {
+        fn enabled(&self, _: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            LINES.with(|lines| lines.borrow_mut().push(record.args().to_string()));
+        }
+
+        fn flush(&self) {}
+    }
+
+    fn captured(f: impl FnOnce()) -> Vec<std::string::String> {
+        static INIT: std::sync::Once = std::sync::Once::new();
+        INIT.call_once(|| {
+            log::set_logger(&Captured).unwrap();
+            log::set_max_level(log::LevelFilter::Trace);
+        });
+        LINES.with(|lines| lines.borrow_mut().clear());
+        f();
+        LINES.with(|lines| lines.borrow_mut().drain(..).collect())
+    }
+
+    fn metrics(name: &str, delta: u32) -> BTreeMap<std::string::String, u32> {
+        let mut metrics = BTreeMap::new();
+        metrics.insert(name.to_string(), delta);
+        metrics
+    }
+
+    #[test]
+    fn matched_rule_reports_its_metrics() {
+        let decision = config("").decide("GET".to_string(), "/".to_string(), "");
+        assert_eq!(decision, Decision::Authrep(metrics("hits", 1)));
+    }
+
+    #[test]
+    fn no_match_defaults_to_deny() {
+        let decision = config("").decide("GET".to_string(), "/nope".to_string(), "");
+        assert_eq!(
+            decision,
+            Decision::Deny(403, "Mapping rule not found\n".to_string())
+        );
+    }
+
+    #[test]
+    fn decisions_are_logged_with_the_request_id() {
+        let lines = captured(|| {
+            config("").decide("GET".to_string(), "/".to_string(), "req-1");
+        });
+        assert!(lines
+            .iter()
+            .any(|line| line.starts_with("Mapping rules matched for service 1")));
+        assert!(lines
+            .iter()
+            .all(|line| line.ends_with("request_id='req-1'")));
+
+        let mut denying = config("");
+        denying.request_id_in_denials = true;
+        let mut decision = None;
+        let lines = captured(|| {
+            decision = Some(denying.decide("GET".to_string(), "/nope".to_string(), "req-2"));
+        });
+        assert_eq!(
+            lines.last().unwrap(),
+            "No mapping rule matched for service 1, denied with 403, request_id='req-2'"
+        );
+        assert!(lines
+            .iter()
+            .all(|line| line.ends_with("request_id='req-2'")));
+        assert_eq!(
+            decision.unwrap(),
+            Decision::Deny(
+                403,
+                "Mapping rule not found\nrequest_id: req-2\n".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn no_match_allow() {
+        let decision = config(r#", "no_match_action": {"action": "allow"}"#).decide(
+            "GET".to_string(),
+            "/nope".to_string(),
+            "",
+        );
+        assert_eq!(decision, Decision::Allow);
+    }
+
+    #[test]
+    fn no_match_deny_with_custom_response() {
+        let config =
+            config(r#", "no_match_action": {"action": "deny", "status": 404, "body": "nope"}"#);
+        assert!(config.no_match_action.validate().is_ok());
+        let decision = config.decide("POST".to_string(), "/".to_string(), "");
+        assert_eq!(decision, Decision::Deny(404, "nope".to_string()));
+    }
+
+    #[test]
+    fn no_match_report_default_metric() {
+        let config = config(
+            r#", "no_match_action": {"action": "report_default_metric", "metric": "no_match"}"#,
+        );
+        assert!(config.no_match_action.validate().is_ok());
+        let decision = config.decide("GET".to_string(), "/nope".to_string(), "");
+        assert_eq!(decision, Decision::Authrep(metrics("no_match", 1)));
+    }
+
+    #[test]
+    fn no_match_action_validation() {
+        let action: NoMatchAction =
+            serde_json::from_str(r#"{"action": "deny", "status": 200}"#).unwrap();
+        assert!(action.validate().is_err());
+
+        let action: NoMatchAction =
+            serde_json::from_str(r#"{"action": "report_default_metric", "metric": ""}"#).unwrap();
+        assert!(action.validate().is_err());
+    }
+
+    #[test]
+    fn metrics_header_rendering() {
+        let mut metrics = metrics("ticks", 2);
+        metrics.insert("hits".to_string(), 1);
+
+        let header: MetricsHeader =
+            serde_json::from_str(r#"{"name": "x-3scale-metrics", "format": "comma_separated"}"#)
+                .unwrap();
+        assert!(header.validate().is_ok());
+        assert_eq!(header.render(&metrics), "hits=1,ticks=2");
+
+        let header: MetricsHeader = serde_json::from_str(r#"{"name": ":path"}"#).unwrap();
+        assert_eq!(header.format, MetricsHeaderFormat::Json);
+        assert!(header.validate().is_err());
+    }
+
+    #[test]
+    fn client_metrics_headers_never_get_upstream() {
+        let sent = vec![
+            (":path".to_string(), "/".to_string()),
+            ("X-3scale-Metrics".to_string(), "hits=1000".to_string()),
+        ];
+        let forwarding = config(r#", "metrics_header": {"name": "x-3scale-metrics"}"#);
+        // removed while nothing matched
+        assert_eq!(
+            forwarding.upstream_metrics_header(&sent, None),
+            Some(("x-3scale-metrics", None))
+        );
+        assert_eq!(forwarding.upstream_metrics_header(&sent[..1], None), None);
+        // replaced once metrics matched
+        assert_eq!(
+            forwarding.upstream_metrics_header(&sent, Some(&metrics("hits", 1))),
+            Some(("x-3scale-metrics", Some(r#"{"hits":1}"#.to_string())))
+        );
+        // left alone without the option
+        assert_eq!(config("").upstream_metrics_header(&sent, None), None);
+        assert_eq!(
+            config("").upstream_metrics_header(&sent, Some(&metrics("hits", 1))),
+            None
+        );
+    }
+
+    #[test]
+    fn report_on_gating() {
+        assert_eq!(config("").report_on, ReportOn::Always);
+        assert!(ReportOn::Always.should_report(503));
+        assert!(!ReportOn::Always.on_response());
+
+        let successful = config(r#", "report_on": "successful_only""#).report_on;
+        assert!(successful.on_response());
+        assert!(successful.should_report(200));
+        assert!(successful.should_report(204));
+        assert!(!successful.should_report(503));
+
+        let classes = config(r#", "report_on": {"status_classes": ["2xx", "4xx"]}"#).report_on;
+        assert!(classes.validate().is_ok());
+        assert!(classes.should_report(201));
+        assert!(classes.should_report(404));
+        assert!(!classes.should_report(302));
+        assert!(!classes.should_report(503));
+
+        assert!(ReportOn::StatusClasses(vec!["6xx".to_string()])
+            .validate()
+            .is_err());
+    }
+
+    #[test]
+    fn legacy_string_value_configurations() {
+        let document = r#"{"id": 7, "proxy_rules": []}"#;
+
+        let raw = FilterConfig::from_bytes(document.as_bytes()).unwrap();
+        assert_eq!(raw.id, 7);
+
+        let literal = serde_json::to_string(document).unwrap();
+        let literal = FilterConfig::from_bytes(literal.as_bytes()).unwrap();
+        assert_eq!(literal, raw);
+
+        let mut encoded = vec![0x0a, document.len() as u8];
+        encoded.extend_from_slice(document.as_bytes());
+        let encoded = FilterConfig::from_bytes(encoded.as_slice()).unwrap();
+        assert_eq!(encoded, raw);
+
+        assert!(FilterConfig::from_bytes(b"vm config").is_err());
+    }
+
+    #[test]
+    fn unknown_fields_are_tolerated() {
+        let config = config(r#", "hosts": ["web.app"], "added_in_a_later_release": {"a": 1}"#);
+        assert_eq!(config.id, 1);
+        assert_eq!(config.proxy_rules.len(), 1);
+    }
+}
@@ -0,0 +1,212 @@
+//! Coarse per-credential throttling enforced by the filter itself.
+//!
+//! Counters live in Envoy shared data so every worker thread sees the same
+//! value. Envoy never deletes shared data, so the counters of a service are
+//! spread over a fixed number of slots, the credentials hashed into them,
+//! rather than get a key each. Each slot holds the counts of its
+//! credentials in the current window, the time divided by the window
+//! length: a slot written in a new window drops the counts of the old one,
+//! those of the credentials that went quiet expiring with them.
+use crate::shared_store::{update, SharedStore};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+// How many keys the counters of a service take at most.
+const SLOTS: u64 = 1024;
+
+/// What a request is counted against.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LimitKey {
+    /// The credential sent in the given request header, i.e. a user_key.
+    Credential { header: std::string::String },
+    /// The downstream address of the request.
+    ClientIp,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LocalLimits {
+    pub window_seconds: u64,
+    pub max_requests: u64,
+    pub key: LimitKey,
+    #[serde(default = "default_limited_status")]
+    pub status: u32,
+    #[serde(default = "default_limited_body")]
+    pub body: std::string::String,
+}
+
+fn default_limited_status() -> u32 {
+    429
+}
+
+fn default_limited_body() -> std::string::String {
+    "Too many requests\n".to_string()
+}
+
+impl LocalLimits {
+    pub fn validate(&self) -> Result<(), std::string::String> {
+        if self.window_seconds == 0 {
+            return Err("local_limits window_seconds must be positive".to_string());
+        }
+        if self.max_requests == 0 {
+            return Err("local_limits max_requests must be positive".to_string());
+        }
+        if let LimitKey::Credential { ref header } = self.key {
+            if header.is_empty() {
+                return Err("local_limits credential header cannot be empty".to_string());
+            }
+        }
+        if !(400..600).contains(&self.status) {
+            return Err(format!(
+                "local_limits status must be a 4xx or 5xx code, got {}",
+                self.status
+            ));
+        }
+        Ok(())
+    }
+
+    /// Count a request for `key`, returning whether it is within the limit.
+    pub fn check(&self, store: &impl SharedStore, service_id: u32, key: &str, now: u64) -> bool {
+        let window = now / self.window_seconds;
+        let shared_key = format!("local_limits::{}::{}", service_id, slot(key));
+
+        let mut allowed = true;
+        let counted = update(store, shared_key.as_str(), |current| {
+            let mut counters = current
+                .and_then(|value| serde_json::from_slice::<Counters>(value).ok())
+                .filter(|counters| counters.window == window)
+                // no counter yet, or the window rolled over
+                .unwrap_or(Counters {
+                    window,
+                    counts: BTreeMap::new(),
+                });
+            let count = counters.counts.entry(key.to_string()).or_default();
+            allowed = *count < self.max_requests;
+            if !allowed {
+                return None;
+            }
+            *count += 1;
+            Some(serde_json::to_vec(&counters).unwrap())
+        });
+        if !counted {
+            log::warn!(
+                "Local limits counter '{}' is too contended, allowing the request",
+                shared_key
+            );
+            return true;
+        }
+        allowed
+    }
+}
+
+/// The counts of the credentials of a slot in a window.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct Counters {
+    window: u64,
+    counts: BTreeMap<std::string::String, u64>,
+}
+
+// The slot of `key`, the same in every worker: FNV-1a, as the standard
+// hasher may change with the compiler.
+fn slot(key: &str) -> u64 {
+    let hash = key.bytes().fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    });
+    hash % SLOTS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_store::MemoryStore;
+    use std::sync::Arc;
+
+    fn limits(max_requests: u64) -> LocalLimits {
+        serde_json::from_value(serde_json::json!({
+            "window_seconds": 60,
+            "max_requests": max_requests,
+            "key": {"credential": {"header": "x-api-key"}},
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn defaults_and_validation() {
+        let limits = limits(2);
+        assert_eq!(limits.status, 429);
+        assert!(limits.validate().is_ok());
+
+        let client_ip: LocalLimits = serde_json::from_value(serde_json::json!({
+            "window_seconds": 0,
+            "max_requests": 1,
+            "key": "client_ip",
+        }))
+        .unwrap();
+        assert_eq!(client_ip.key, LimitKey::ClientIp);
+        assert!(client_ip.validate().is_err());
+    }
+
+    #[test]
+    fn limit_per_key_and_window_rollover() {
+        let store = MemoryStore::default();
+        let limits = limits(2);
+
+        assert!(limits.check(&store, 1, "key-a", 0));
+        assert!(limits.check(&store, 1, "key-a", 10));
+        assert!(!limits.check(&store, 1, "key-a", 59));
+        // other credentials and services have their own counters
+        assert!(limits.check(&store, 1, "key-b", 59));
+        assert!(limits.check(&store, 2, "key-a", 59));
+        // next window starts from scratch
+        assert!(limits.check(&store, 1, "key-a", 60));
+        assert!(limits.check(&store, 1, "key-a", 61));
+        assert!(!limits.check(&store, 1, "key-a", 62));
+    }
+
+    #[test]
+    fn concurrent_increments_are_not_lost() {
+        let store = Arc::new(MemoryStore::default());
+        let limits = Arc::new(limits(101));
+        // every worker starting at once on an empty store, all racing to
+        // create the counter
+        let start = Arc::new(std::sync::Barrier::new(8));
+        let workers: Vec<_> = (0..8)
+            .map(|_| {
+                let store = Arc::clone(&store);
+                let limits = Arc::clone(&limits);
+                let start = Arc::clone(&start);
+                std::thread::spawn(move || {
+                    start.wait();
+                    (0..25)
+                        .filter(|_| limits.check(store.as_ref(), 1, "key", 5))
+                        .count()
+                })
+            })
+            .collect();
+        let allowed: usize = workers.into_iter().map(|w| w.join().unwrap()).sum();
+
+        assert_eq!(allowed, 101);
+        let (value, _) = store.get(&format!("local_limits::1::{}", slot("key")));
+        let counters: Counters = serde_json::from_slice(&value.unwrap()).unwrap();
+        assert_eq!(counters.counts["key"], 101);
+    }
+
+    #[test]
+    fn counters_take_a_bounded_number_of_keys() {
+        let store = MemoryStore::default();
+        let limits = limits(1);
+        for i in 0..5000 {
+            assert!(limits.check(&store, 1, &format!("key-{}", i), 0));
+        }
+        assert!(store.key_count() as u64 <= SLOTS);
+        // credentials sharing a slot still count apart
+        assert!(!limits.check(&store, 1, "key-1", 0));
+
+        // the counts of an old window go with the first write of the next
+        assert!(limits.check(&store, 1, "key-1", 60));
+        let (value, _) = store.get(&format!("local_limits::1::{}", slot("key-1")));
+        let counters: Counters = serde_json::from_slice(&value.unwrap()).unwrap();
+        assert_eq!(counters.window, 1);
+        assert_eq!(counters.counts.keys().collect::<Vec<_>>(), ["key-1"]);
+    }
+}
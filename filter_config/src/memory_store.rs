@@ -0,0 +1,45 @@
+use crate::SharedStore;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+// Same semantics as Envoy shared data: every write gets the next CAS token,
+// and a write with a token creates a value that does not exist yet.
+#[derive(Default)]
+pub struct MemoryStore {
+    data: Mutex<Entries>,
+}
+
+// The values by key with their CAS tokens, and the token of the last write.
+#[derive(Default)]
+struct Entries {
+    values: HashMap<std::string::String, (Vec<u8>, u32)>,
+    last_cas: u32,
+}
+
+impl MemoryStore {
+    pub fn key_count(&self) -> usize {
+        self.data.lock().unwrap().values.len()
+    }
+}
+
+impl SharedStore for MemoryStore {
+    fn get(&self, key: &str) -> (Option<Vec<u8>>, Option<u32>) {
+        match self.data.lock().unwrap().values.get(key) {
+            Some((value, cas)) => (Some(value.clone()), Some(*cas)),
+            None => (None, None),
+        }
+    }
+
+    fn set(&self, key: &str, value: &[u8], cas: Option<u32>) -> bool {
+        let mut data = self.data.lock().unwrap();
+        let current = data.values.get(key).map(|(_, cas)| *cas);
+        if cas.is_some() && current.is_some() && cas != current {
+            return false;
+        }
+        data.last_cas += 1;
+        let last_cas = data.last_cas;
+        data.values
+            .insert(key.to_string(), (value.to_vec(), last_cas));
+        true
+    }
+}
@@ -0,0 +1,182 @@
+//! Usage the filter could not report yet, kept for a later report.
+//!
+//! Reports are sent as the requests are matched. Those that cannot be sent,
+//! or that the backend fails, leave their usage in Envoy shared data under
+//! `pending_reports::<service>`, as the JSON of the metrics, for the next
+//! report of the service or the last flush of the VM to send along. Shared
+//! data outlives the configurations of the VM, so a reload keeps the usage
+//! of its service. The usage given up on is added to
+//! `lost_reports::<service>`, in hits.
+use crate::shared_store::{update, SharedStore};
+use std::collections::BTreeMap;
+
+/// The metrics of a report and their deltas.
+pub type Usage = BTreeMap<std::string::String, u32>;
+
+fn pending_key(service_id: u32) -> std::string::String {
+    format!("pending_reports::{}", service_id)
+}
+
+fn lost_key(service_id: u32) -> std::string::String {
+    format!("lost_reports::{}", service_id)
+}
+
+fn parse(value: Option<&[u8]>) -> Usage {
+    value
+        .filter(|value| !value.is_empty())
+        .and_then(|value| serde_json::from_slice(value).ok())
+        .unwrap_or_default()
+}
+
+/// Keep `usage` of the service for a later report, along with the usage
+/// already kept.
+pub fn keep(store: &impl SharedStore, service_id: u32, usage: &Usage) {
+    let kept = update(store, pending_key(service_id).as_str(), |current| {
+        let mut pending = parse(current);
+        for (metric, delta) in usage {
+            let total = pending.entry(metric.clone()).or_default();
+            *total = total.saturating_add(*delta);
+        }
+        Some(serde_json::to_vec(&pending).unwrap())
+    });
+    if !kept {
+        lose(store, service_id, usage);
+    }
+}
+
+/// Take the usage kept for the service, for the caller to report it.
+pub fn take(store: &impl SharedStore, service_id: u32) -> Usage {
+    let mut taken = Usage::new();
+    let key = pending_key(service_id);
+    // most reports find nothing kept, and need no write
+    if store
+        .get(key.as_str())
+        .0
+        .is_none_or(|value| value.is_empty())
+    {
+        return taken;
+    }
+    if update(store, key.as_str(), |current| {
+        taken = parse(current);
+        Some(Vec::new())
+    }) {
+        taken
+    } else {
+        // left for the next report, rather than sent twice
+        Usage::new()
+    }
+}
+
+/// Give up on reporting `usage` of the service, counting it as lost.
+pub fn lose(store: &impl SharedStore, service_id: u32, usage: &Usage) {
+    let hits: u64 = usage.values().map(|delta| u64::from(*delta)).sum();
+    let counted = update(store, lost_key(service_id).as_str(), |current| {
+        let total = current
+            .and_then(|value| std::str::from_utf8(value).ok())
+            .and_then(|value| value.parse::<u64>().ok())
+            .unwrap_or_default();
+        Some((total + hits).to_string().into_bytes())
+    });
+    log::warn!(
+        "Lost the usage of service {}, metrics={:?}, counted={}",
+        service_id,
+        usage,
+        counted
+    );
+}
+
+/// How many hits of the service were lost.
+pub fn lost(store: &impl SharedStore, service_id: u32) -> u64 {
+    store
+        .get(lost_key(service_id).as_str())
+        .0
+        .and_then(|value| std::str::from_utf8(&value).ok()?.parse().ok())
+        .unwrap_or_default()
+}
+
+/// Settle the usage the configuration of the service `previous`, if any,
+/// left when the one of `current` replaces it: that of the same service is
+/// kept for its next report, that of another one is lost, the reports of
+/// the new configuration being of another service.
+pub fn reloaded(store: &impl SharedStore, previous: Option<u32>, current: u32) {
+    if let Some(previous) = previous.filter(|previous| *previous != current) {
+        let usage = take(store, previous);
+        if !usage.is_empty() {
+            lose(store, previous, &usage);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_store::MemoryStore;
+
+    fn usage(metrics: &[(&str, u32)]) -> Usage {
+        metrics
+            .iter()
+            .map(|(metric, delta)| (metric.to_string(), *delta))
+            .collect()
+    }
+
+    #[test]
+    fn kept_usage_adds_up_until_taken() {
+        let store = MemoryStore::default();
+        assert!(take(&store, 1).is_empty());
+
+        keep(&store, 1, &usage(&[("hits", 1)]));
+        keep(&store, 1, &usage(&[("hits", 2), ("ticks", 1)]));
+        keep(&store, 2, &usage(&[("hits", 5)]));
+        assert_eq!(take(&store, 1), usage(&[("hits", 3), ("ticks", 1)]));
+        assert!(take(&store, 1).is_empty());
+        assert_eq!(take(&store, 2), usage(&[("hits", 5)]));
+        assert_eq!(lost(&store, 1), 0);
+    }
+
+    #[test]
+    fn reloads_keep_the_usage_of_their_service() {
+        let store = MemoryStore::default();
+        keep(&store, 1, &usage(&[("hits", 2)]));
+
+        // the same service, reconfigured: the usage survives for its next
+        // report
+        reloaded(&store, Some(1), 1);
+        reloaded(&store, None, 1);
+        assert_eq!(take(&store, 1), usage(&[("hits", 2)]));
+
+        // another service: nothing will report it anymore
+        keep(&store, 1, &usage(&[("hits", 2), ("ticks", 1)]));
+        reloaded(&store, Some(1), 2);
+        assert!(take(&store, 1).is_empty());
+        assert_eq!(lost(&store, 1), 3);
+
+        lose(&store, 1, &usage(&[("hits", 4)]));
+        assert_eq!(lost(&store, 1), 7);
+    }
+
+    #[test]
+    fn usage_kept_at_once_adds_up() {
+        use std::sync::{Arc, Barrier};
+
+        let store = Arc::new(MemoryStore::default());
+        // every worker keeping usage at once in an empty store
+        let start = Arc::new(Barrier::new(8));
+        let workers: Vec<_> = (0..8)
+            .map(|_| {
+                let store = Arc::clone(&store);
+                let start = Arc::clone(&start);
+                std::thread::spawn(move || {
+                    start.wait();
+                    for _ in 0..25 {
+                        keep(store.as_ref(), 1, &usage(&[("hits", 1)]));
+                    }
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        let lost = lost(store.as_ref(), 1);
+        assert_eq!(take(store.as_ref(), 1)["hits"] as u64 + lost, 200);
+    }
+}
@@ -0,0 +1,66 @@
+/// The header Envoy puts the id of every request in, generated or kept from
+/// a trusted hop, and logs along with the request.
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+// Length of the ids made up for the requests without one, enough to tell
+// apart those in flight.
+const GENERATED_DIGITS: usize = 8;
+
+/// The id the logs of a request carry: the one of its `x-request-id` header
+/// for them to match the access log of Envoy, or else one generated out of
+/// the `context_id` of the request and the time `now` in nanoseconds, for
+/// the logs of the filter to still match with one another.
+pub fn request_id(
+    header: Option<std::string::String>,
+    context_id: u32,
+    now: u64,
+) -> std::string::String {
+    match header {
+        Some(id) if !id.is_empty() => id,
+        _ => {
+            // splitmix64, contexts being numbered in sequence
+            let mut id = now ^ (u64::from(context_id) << 32);
+            id = (id ^ (id >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            id = (id ^ (id >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            id ^= id >> 31;
+            format!("{:016x}", id)[..GENERATED_DIGITS].to_string()
+        }
+    }
+}
+
+/// The `body` of a denial telling the id of the request, for the clients to
+/// hand it over along with their complaint.
+pub fn denial_body(body: &str, request_id: &str) -> std::string::String {
+    let separator = if body.is_empty() || body.ends_with('\n') {
+        ""
+    } else {
+        "\n"
+    };
+    format!("{}{}request_id: {}\n", body, separator, request_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests_without_an_id_get_a_short_one() {
+        let id = "5c1d4a3e-0b7f-4e2a-9d61-6f0e2b8a7c95";
+        assert_eq!(request_id(Some(id.to_string()), 2, 0), id);
+
+        let generated = request_id(None, 2, 1_600_000_000_000_000_000);
+        assert_eq!(generated.len(), GENERATED_DIGITS);
+        assert!(generated.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_eq!(
+            request_id(Some("".to_string()), 2, 1_600_000_000_000_000_000),
+            generated
+        );
+        assert_ne!(request_id(None, 3, 1_600_000_000_000_000_000), generated);
+
+        assert_eq!(
+            denial_body("Mapping rule not found\n", "abc"),
+            "Mapping rule not found\nrequest_id: abc\n"
+        );
+        assert_eq!(denial_body("nope", "abc"), "nope\nrequest_id: abc\n");
+    }
+}
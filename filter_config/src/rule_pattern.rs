@@ -0,0 +1,220 @@
+//! The paths the pattern of a mapping rule matches, the same for the route
+//! the controller builds for the rule and for the filter matching the rule.
+//!
+//! Placeholders, as in `/things/{id}`, match a segment, and patterns match
+//! the paths they start unless ending with `$`. The query string of the
+//! request is left out of the path, as Envoy does matching routes, the
+//! parameters of the query of a pattern being checked by the filter alone.
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+// What a placeholder of a pattern matches, a single segment of the path.
+const PLACEHOLDER: &str = "([^/]+)";
+
+thread_local! {
+    // The regexes of the paths of the patterns, matching whole paths, the
+    // filter matching the rules of its service on every request.
+    static COMPILED: RefCell<HashMap<std::string::String, regex::Regex>> =
+        RefCell::new(HashMap::new());
+}
+
+/// The RE2 regex matching the paths the mapping rule `pattern` matches, the
+/// whole path being matched the way Envoy does. Patterns with a query string
+/// can't be expressed, the path of a route leaving the query out.
+pub fn path_regex(pattern: &str) -> Result<std::string::String, std::string::String> {
+    let (path, anchored) = match pattern.strip_suffix('$') {
+        Some(path) => (path, true),
+        None => (pattern, false),
+    };
+    if path.contains('?') {
+        return Err("has a query string, which routes don't match".to_string());
+    }
+    let mut regex = std::string::String::new();
+    let mut rest = path;
+    while let Some(start) = rest.find('{') {
+        let end = match rest[start..].find('}') {
+            Some(end) => start + end,
+            None => return Err("has an unbalanced placeholder".to_string()),
+        };
+        regex.push_str(&regex::escape(&rest[..start]));
+        regex.push_str(PLACEHOLDER);
+        rest = &rest[end + 1..];
+    }
+    if rest.contains('}') {
+        return Err("has an unbalanced placeholder".to_string());
+    }
+    regex.push_str(&regex::escape(rest));
+    if !anchored {
+        regex.push_str(".*");
+    }
+    // the regex crate refuses what RE2 does, like backreferences
+    regex::Regex::new(&regex).map_err(|e| format!("is not an RE2 regex: {}", e))?;
+    Ok(regex)
+}
+
+/// The part of `pattern` before what a regex can't express.
+pub fn prefix(pattern: &str) -> &str {
+    let end = pattern.find(&['{', '?'][..]).unwrap_or(pattern.len());
+    pattern[..end].trim_end_matches('$')
+}
+
+/// The regex of `pattern`, or else the one of its prefix, matching more
+/// paths than the rule.
+pub fn loose_regex(pattern: &str) -> std::string::String {
+    path_regex(pattern).unwrap_or_else(|_| format!("{}.*", regex::escape(prefix(pattern))))
+}
+
+/// Whether the request `path`, its query string included, is one of those
+/// of the mapping rule `pattern`: its path by the regex of the route of the
+/// rule, and its query having the parameters of the query of the pattern,
+/// if any, those of placeholders with any value.
+pub fn matches(pattern: &str, path: &str) -> bool {
+    let (path, query) = split_query(path);
+    let (pattern, pattern_query) = split_query(pattern);
+    if !matches_path(pattern, path) {
+        return false;
+    }
+    let pattern_query = match pattern_query {
+        Some(query) => query.strip_suffix('$').unwrap_or(query),
+        None => return true,
+    };
+    let sent: Vec<_> = query.into_iter().flat_map(params).collect();
+    params(pattern_query).all(|(name, value)| {
+        sent.iter().any(|(param, param_value)| {
+            *param == name
+                && match value {
+                    None => true,
+                    Some(value) if value.starts_with('{') && value.ends_with('}') => true,
+                    Some(value) => *param_value == Some(value),
+                }
+        })
+    })
+}
+
+// Whether the path of a request, without its query, is one of those of the
+// path of a pattern, as the route of the rule has it.
+fn matches_path(pattern: &str, path: &str) -> bool {
+    COMPILED.with(|compiled| {
+        let mut compiled = compiled.borrow_mut();
+        if let Some(regex) = compiled.get(pattern) {
+            return regex.is_match(path);
+        }
+        match regex::Regex::new(&format!("^(?:{})$", loose_regex(pattern))) {
+            Ok(regex) => {
+                let matched = regex.is_match(path);
+                compiled.insert(pattern.to_string(), regex);
+                matched
+            }
+            Err(_) => false,
+        }
+    })
+}
+
+fn split_query(path: &str) -> (&str, Option<&str>) {
+    match path.find('?') {
+        Some(start) => (&path[..start], Some(&path[start + 1..])),
+        None => (path, None),
+    }
+}
+
+// The parameters of a query, by name, with their values if any.
+fn params(query: &str) -> impl Iterator<Item = (&str, Option<&str>)> {
+    query
+        .split('&')
+        .filter(|param| !param.is_empty())
+        .map(|param| match param.find('=') {
+            Some(start) => (&param[..start], Some(&param[start + 1..])),
+            None => (param, None),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn patterns_translate_to_anchored_regexes() {
+        // pattern, regex, paths matched, paths not matched
+        let cases: &[(&str, &str, &[&str], &[&str])] = &[
+            ("/", "/.*", &["/", "/things"], &[""]),
+            (
+                "/things$",
+                "/things",
+                &["/things"],
+                &["/things/1", "/thing"],
+            ),
+            (
+                "/things/{id}",
+                "/things/([^/]+).*",
+                &["/things/1", "/things/1/parts"],
+                &["/things/", "/things"],
+            ),
+            (
+                "/things/{id}/parts/{part}$",
+                "/things/([^/]+)/parts/([^/]+)",
+                &["/things/1/parts/2"],
+                &["/things/1/parts/2/x", "/things//parts/2"],
+            ),
+            (
+                "/v1.0/things+{id}.json$",
+                r"/v1\.0/things\+([^/]+)\.json",
+                &["/v1.0/things+7.json"],
+                &["/v1x0/things+7.json", "/v1.0/things+7xjson"],
+            ),
+        ];
+        for (pattern, expected, matched, unmatched) in cases {
+            let regex = path_regex(pattern).unwrap();
+            assert_eq!(&regex, expected, "{}", pattern);
+            // matching whole paths, as Envoy does
+            let full = regex::Regex::new(&format!("^(?:{})$", regex)).unwrap();
+            for path in *matched {
+                assert!(full.is_match(path), "{} should match {}", pattern, path);
+                assert!(matches(pattern, path), "{} should match {}", pattern, path);
+            }
+            for path in *unmatched {
+                assert!(
+                    !full.is_match(path),
+                    "{} should not match {}",
+                    pattern,
+                    path
+                );
+                assert!(
+                    !matches(pattern, path),
+                    "{} should not match {}",
+                    pattern,
+                    path
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn queries_are_matched_by_their_parameters() {
+        // the query of the request is not part of its path
+        assert!(matches("/things/{id}$", "/things/1?expand=parts"));
+        assert!(!matches("/things$", "/things/1?expand=parts"));
+
+        for path in &[
+            "/things?id=1",
+            "/things?page=2&id=1",
+            "/things/new?id=1&all",
+        ] {
+            assert!(matches("/things?id={id}", path), "{}", path);
+        }
+        for path in &["/things", "/things?ids=1", "/other?id=1"] {
+            assert!(!matches("/things?id={id}", path), "{}", path);
+        }
+        assert!(matches("/things?id=1&all$", "/things?all&id=1"));
+        assert!(!matches("/things?id=1&all$", "/things?id=2&all"));
+        assert!(!matches("/things?id=1&all$", "/things?id=1"));
+    }
+
+    #[test]
+    fn patterns_not_expressed_match_by_their_prefixes() {
+        for (pattern, prefix) in &[("/things/{id", "/things/"), ("/things/id}", "/things/id}")] {
+            assert!(path_regex(pattern).is_err(), "{}", pattern);
+            assert_eq!(loose_regex(pattern), format!("{}.*", regex::escape(prefix)));
+            assert!(matches(pattern, &format!("{}x/y", prefix)));
+        }
+    }
+}
@@ -0,0 +1,69 @@
+//! Envoy shared data, as the filter instances of every worker write it.
+//!
+//! Every write gets a new CAS token, and a write with a token only goes
+//! through when it is still the one of the value. Envoy has no write that
+//! only creates a value, but a write with a token creates a value that does
+//! not exist yet, so one with a token no value has creates the value unless
+//! another worker created it first.
+
+// Give up on the CAS loop after this many conflicting writes, rather than
+// spin on a hot value.
+const MAX_CAS_ATTEMPTS: usize = 16;
+
+// The token of the creating writes. Envoy starts its tokens at 1 and counts
+// up, so no value has this one until billions of writes later.
+const CREATE_CAS: u32 = u32::MAX;
+
+/// Storage shared between filter instances, with compare-and-swap writes.
+pub trait SharedStore {
+    /// Current value and its CAS token, if any.
+    fn get(&self, key: &str) -> (Option<Vec<u8>>, Option<u32>);
+    /// Store `value` if the CAS token still matches, or, when there is no
+    /// value yet, whatever the token. Returns false on a mismatch, meaning
+    /// somebody else wrote in between.
+    fn set(&self, key: &str, value: &[u8], cas: Option<u32>) -> bool;
+}
+
+/// Replace the value of `key` with the one `f` makes out of it, none when
+/// there is nothing to write, unless other workers keep writing it
+/// meanwhile. A missing value is created, never overwriting the one another
+/// worker created or updated in between.
+pub fn update(
+    store: &impl SharedStore,
+    key: &str,
+    mut f: impl FnMut(Option<&[u8]>) -> Option<Vec<u8>>,
+) -> bool {
+    for _ in 0..MAX_CAS_ATTEMPTS {
+        let (current, cas) = store.get(key);
+        let value = match f(current.as_deref()) {
+            Some(value) => value,
+            None => return true,
+        };
+        if store.set(key, value.as_slice(), Some(cas.unwrap_or(CREATE_CAS))) {
+            return true;
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_store::MemoryStore;
+
+    #[test]
+    fn values_are_created_once() {
+        let store = MemoryStore::default();
+        assert!(update(&store, "key", |current| {
+            assert!(current.is_none());
+            Some(b"1".to_vec())
+        }));
+        // a worker that saw no value yet doesn't overwrite it
+        assert!(!store.set("key", b"other", Some(CREATE_CAS)));
+        assert!(update(&store, "key", |current| {
+            assert_eq!(current, Some(&b"1"[..]));
+            None
+        }));
+        assert_eq!(store.get("key").0.unwrap(), b"1");
+    }
+}
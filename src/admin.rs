@@ -0,0 +1,567 @@
+use std::convert::Infallible;
+use std::sync::{Arc, RwLock};
+
+use serde::Deserialize;
+use warp::http::StatusCode;
+use warp::hyper::body::Bytes;
+use warp::Filter;
+
+use crate::configuration;
+use crate::environment;
+use crate::envoy_helpers::EnvoyExport;
+use crate::leader::Leadership;
+use crate::node_status::NodeStatuses;
+use crate::panics;
+use crate::proto_json::Registry;
+use crate::reload::{Outcome, Reloader};
+use crate::validate;
+use crate::xds_auth;
+
+// Largest service accepted for a preview.
+const PREVIEW_BODY_LIMIT: u64 = 1024 * 1024;
+
+/// On demand reloads of the services, behind `token` when set.
+#[derive(Clone, Default)]
+pub struct Reload {
+    pub reloader: Reloader,
+    pub token: Option<std::string::String>,
+}
+
+impl Reload {
+    async fn respond(self, token: Option<std::string::String>) -> Box<dyn warp::Reply> {
+        let message = |message: &str, status| {
+            Box::new(warp::reply::with_status(
+                warp::reply::json(&message),
+                status,
+            ))
+        };
+        if let Some(ref expected) = self.token {
+            let given = token.unwrap_or_default();
+            if ring::constant_time::verify_slices_are_equal(given.as_bytes(), expected.as_bytes())
+                .is_err()
+            {
+                return message("missing or wrong X-Reload-Token", StatusCode::UNAUTHORIZED);
+            }
+        }
+        let reloader = self.reloader;
+        match tokio::task::spawn_blocking(move || reloader.reload()).await {
+            Ok(Outcome::Published(version)) => Box::new(warp::reply::json(
+                &serde_json::json!({ "version": version }),
+            )),
+            Ok(Outcome::Unchanged) => Box::new(StatusCode::NO_CONTENT),
+            Ok(Outcome::Rejected(errors)) => Box::new(warp::reply::with_status(
+                warp::reply::json(&serde_json::json!({ "errors": errors })),
+                StatusCode::UNPROCESSABLE_ENTITY,
+            )),
+            Ok(Outcome::Unsupported) => message(
+                "the services source does not reload on demand",
+                StatusCode::NOT_IMPLEMENTED,
+            ),
+            Err(e) => {
+                tracing::error!("Reload panicked: {}", e);
+                message("the reload failed", StatusCode::INTERNAL_SERVER_ERROR)
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct PreviewOptions {
+    // skip the OIDC discovery, as the validate command does offline
+    #[serde(default)]
+    offline: bool,
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct TenantFilter {
+    // every tenant when unset
+    tenant: Option<std::string::String>,
+}
+
+impl TenantFilter {
+    fn admits(&self, tenant: &str) -> bool {
+        self.tenant.as_deref().is_none_or(|wanted| wanted == tenant)
+    }
+}
+
+// The resources as they are served, decoded to protobuf JSON.
+fn render(exports: &[EnvoyExport], registry: &Registry) -> anyhow::Result<Vec<serde_json::Value>> {
+    exports
+        .iter()
+        .map(|export| {
+            Ok(serde_json::json!({
+                "key": export.key,
+                "name": export.config.name(),
+                "type_url": export.config.type_url(),
+                "resource": registry.any_to_json(&export.config.to_any()?)?,
+            }))
+        })
+        .collect()
+}
+
+fn rendered(
+    exports: &[EnvoyExport],
+    registry: &Registry,
+) -> warp::reply::WithStatus<warp::reply::Json> {
+    match render(exports, registry) {
+        Ok(resources) => warp::reply::with_status(warp::reply::json(&resources), StatusCode::OK),
+        Err(e) => warp::reply::with_status(
+            warp::reply::json(&format!("cannot render the resources: {:#}", e)),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        ),
+    }
+}
+
+// Export a service the way the validate command checks it, leaving the
+// snapshot being served alone.
+async fn preview(
+    config: Arc<RwLock<configuration::Config>>,
+    registry: Arc<Registry>,
+    options: PreviewOptions,
+    body: Bytes,
+) -> warp::reply::WithStatus<warp::reply::Json> {
+    let wasm = config.read().unwrap().wasm().clone();
+    let checked = tokio::task::spawn_blocking(move || {
+        let value = match serde_json::from_slice(&body) {
+            Ok(value) => value,
+            Err(e) => {
+                let report = validate::ServiceReport {
+                    id: None,
+                    passed: false,
+                    error: Some(format!("not a service: {}", e)),
+                    errors: Vec::new(),
+                    warnings: Vec::new(),
+                };
+                return Err(report);
+            }
+        };
+        match validate::check_service(value, "", false, &wasm, options.offline) {
+            (_, Some(exports)) => Ok(rendered(&exports, &registry)),
+            (report, None) => Err(report),
+        }
+    })
+    .await;
+    match checked {
+        Ok(Ok(reply)) => reply,
+        Ok(Err(report)) => {
+            warp::reply::with_status(warp::reply::json(&report), StatusCode::UNPROCESSABLE_ENTITY)
+        }
+        Err(e) => {
+            tracing::error!("Preview panicked: {}", e);
+            warp::reply::with_status(
+                warp::reply::json(&"the preview failed"),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+        }
+    }
+}
+
+/// Admin HTTP routes, served next to the static files when enabled. Apart
+/// from requesting a reload they only ever read the state of the
+/// controller, previews included.
+pub fn routes(
+    config: Arc<RwLock<configuration::Config>>,
+    statuses: NodeStatuses,
+    registry: Arc<Registry>,
+    reload: Reload,
+    leadership: Leadership,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let nodes_statuses = statuses.clone();
+    let nodes = warp::path!("admin" / "nodes")
+        .and(warp::get())
+        .map(move || warp::reply::json(&nodes_statuses.get()));
+
+    let snapshot_config = Arc::clone(&config);
+    let snapshot = warp::path!("admin" / "snapshot")
+        .and(warp::get())
+        .and(warp::query::<TenantFilter>())
+        .map(move |filter: TenantFilter| {
+            let config = snapshot_config.read().unwrap();
+            let groups: serde_json::Map<_, _> = config
+                .group_snapshots()
+                .iter()
+                .filter(|(key, _)| filter.admits(configuration::split_key(key).0))
+                .map(|(group, snapshot)| {
+                    let resources: serde_json::Map<_, _> = snapshot
+                        .type_versions()
+                        .keys()
+                        .map(|type_url| {
+                            let names: Vec<_> = snapshot
+                                .resources(type_url)
+                                .into_iter()
+                                .flat_map(|resources| resources.keys())
+                                .collect();
+                            (type_url.to_string(), serde_json::json!(names))
+                        })
+                        .collect();
+                    let snapshot = serde_json::json!({
+                        "version": snapshot.version(),
+                        "hash": snapshot.hash(),
+                        "types": snapshot.type_versions(),
+                        "resources": resources,
+                        "removed": snapshot.removed(),
+                    });
+                    (group.clone(), snapshot)
+                })
+                .collect();
+            warp::reply::json(&serde_json::json!({
+                "version": config.get_version(),
+                "revision": config.revision(),
+                "role": leadership.role(),
+                "groups": groups,
+                "nodes": statuses.get(),
+                "quarantined": config.quarantined(),
+                "export_failures": config.export_failures(),
+                "missing_wasm_filters": config.missing_filters(),
+                "publications": config.publications(),
+                "panics": panics::stats(),
+                "xds_auth": xds_auth::stats(),
+            }))
+        });
+
+    let diff_config = Arc::clone(&config);
+    let diff = warp::path!("admin" / "diff")
+        .and(warp::get())
+        .map(move || warp::reply::json(&diff_config.read().unwrap().last_diff()));
+
+    let services_config = Arc::clone(&config);
+    let services = warp::path!("admin" / "services")
+        .and(warp::get())
+        .and(warp::query::<TenantFilter>())
+        .map(move |filter: TenantFilter| {
+            let mut services = services_config.read().unwrap().get_services();
+            services.retain(|service| filter.admits(&service.tenant));
+            warp::reply::json(&services)
+        });
+
+    let resources_config = Arc::clone(&config);
+    let resources_registry = Arc::clone(&registry);
+    let resources = warp::path!("admin" / "services" / u32 / "resources")
+        .and(warp::get())
+        .map(move |id| {
+            let config = resources_config.read().unwrap();
+            match config.service_exports(id) {
+                Some(exports) => rendered(exports, &resources_registry),
+                None => warp::reply::with_status(
+                    warp::reply::json(&format!("no resources for service {}", id)),
+                    StatusCode::NOT_FOUND,
+                ),
+            }
+        });
+
+    // the service once its staging settings are promoted to production,
+    // for the tools managing the services files to write it back
+    let promoted_config = Arc::clone(&config);
+    let promoted = warp::path!("admin" / "services" / u32 / "promoted")
+        .and(warp::get())
+        .map(move |id| {
+            let services = promoted_config.read().unwrap().get_services();
+            match services.into_iter().find(|service| service.id == id) {
+                Some(mut service) => {
+                    service.environments = environment::promote(&service.environments);
+                    warp::reply::with_status(warp::reply::json(&service), StatusCode::OK)
+                }
+                None => warp::reply::with_status(
+                    warp::reply::json(&format!("no service {}", id)),
+                    StatusCode::NOT_FOUND,
+                ),
+            }
+        });
+
+    let preview = warp::path!("admin" / "preview")
+        .and(warp::post())
+        .and(warp::query::<PreviewOptions>())
+        .and(warp::body::content_length_limit(PREVIEW_BODY_LIMIT))
+        .and(warp::body::bytes())
+        .and_then(move |options, body| {
+            let config = Arc::clone(&config);
+            let registry = Arc::clone(&registry);
+            async move { Ok::<_, Infallible>(preview(config, registry, options, body).await) }
+        });
+
+    // reloads coalesce into the one running, which the reloader takes care of
+    let reload = warp::path!("admin" / "reload")
+        .and(warp::post())
+        .and(warp::header::optional::<std::string::String>(
+            "x-reload-token",
+        ))
+        .and_then(move |token| {
+            let reload = reload.clone();
+            async move { Ok::<_, Infallible>(reload.respond(token).await) }
+        });
+
+    nodes
+        .or(snapshot)
+        .or(diff)
+        .or(services)
+        .or(resources)
+        .or(promoted)
+        .or(preview)
+        .or(reload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::envoy_helpers::{CLUSTER_TYPE_URL, LISTENER_TYPE_URL};
+    use crate::publisher::Publisher;
+    use crate::service::WasmSettings;
+    use crate::source::Source;
+    use crate::watcher::ConfigWatcher;
+    use std::time::Duration;
+
+    const SERVICES: &str = r#"[
+        {"id": 1, "hosts": ["one.app"], "policies": [], "target_domain": "http://one:80", "proxy_rules": []},
+        {"id": 2, "hosts": ["two.app"], "policies": [], "target_domain": "https://two:443", "proxy_rules": [
+            {"pattern": "/", "http_method": "GET", "metric_system_name": "hits", "delta": 1}
+        ]}
+    ]"#;
+
+    fn admin() -> (
+        impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone,
+        NodeStatuses,
+    ) {
+        let (admin, statuses, _) = admin_with(Reload::default());
+        (admin, statuses)
+    }
+
+    fn admin_with(
+        reload: Reload,
+    ) -> (
+        impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone,
+        NodeStatuses,
+        Arc<RwLock<configuration::Config>>,
+    ) {
+        let mut config = configuration::Config::default();
+        config.set_wasm(WasmSettings {
+            skip_sha: true,
+            ..Default::default()
+        });
+        let config = Arc::new(RwLock::new(config));
+        let services = serde_json::from_str(SERVICES).unwrap();
+        configuration::publish(
+            &config,
+            configuration::Config::from_services(services, SERVICES),
+        )
+        .unwrap();
+        let statuses = NodeStatuses::default();
+        let registry = Arc::new(Registry::new().unwrap());
+        (
+            routes(
+                Arc::clone(&config),
+                statuses.clone(),
+                registry,
+                reload,
+                Leadership::default(),
+            ),
+            statuses,
+            config,
+        )
+    }
+
+    async fn get(
+        admin: &(impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone + 'static),
+        path: &str,
+    ) -> (StatusCode, serde_json::Value) {
+        let response = warp::test::request().path(path).reply(admin).await;
+        let body = serde_json::from_slice(response.body()).unwrap();
+        (response.status(), body)
+    }
+
+    #[tokio::test]
+    async fn services_and_their_resources() {
+        let (admin, _) = admin();
+
+        let (status, services) = get(&admin, "/admin/services").await;
+        assert_eq!(status, StatusCode::OK);
+        let ids: Vec<_> = services
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|service| service["id"].as_u64().unwrap())
+            .collect();
+        assert_eq!(ids, [1, 2]);
+
+        let (status, resources) = get(&admin, "/admin/services/2/resources").await;
+        assert_eq!(status, StatusCode::OK);
+        let cluster = &resources[0];
+        assert_eq!(cluster["type_url"], CLUSTER_TYPE_URL);
+        assert_eq!(cluster["resource"]["name"], "Cluster::service::2");
+        // the Any of the transport socket decoded by its type
+        assert_eq!(
+            cluster["resource"]["transport_socket"]["typed_config"]["sni"],
+            "two"
+        );
+        let listener = &resources[1];
+        assert_eq!(listener["type_url"], LISTENER_TYPE_URL);
+        assert_eq!(listener["name"], "service 2");
+
+        assert_eq!(
+            get(&admin, "/admin/services/3/resources").await.0,
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[tokio::test]
+    async fn services_promoted() {
+        let (admin, _, config) = admin_with(Reload::default());
+        let staged = r#"[{"id": 3, "hosts": ["three.app"], "policies": [], "target_domain": "http://three:80", "proxy_rules": [],
+            "environments": {"staging": {"hosts": ["staging.three.app"], "target_domain": "http://three-v2:80"}}}]"#;
+        let services = serde_json::from_str(staged).unwrap();
+        configuration::publish(
+            &config,
+            configuration::Config::from_services(services, staged),
+        )
+        .unwrap();
+
+        let (status, service) = get(&admin, "/admin/services/3/promoted").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            service["environments"]["production"]["target_domain"],
+            "http://three-v2:80"
+        );
+        assert_eq!(
+            service["environments"]["staging"]["hosts"],
+            serde_json::json!(["staging.three.app"])
+        );
+        // the services served are left alone
+        assert!(!config.read().unwrap().get_services()[0]
+            .environments
+            .contains_key(&environment::Environment::Production));
+
+        assert_eq!(
+            get(&admin, "/admin/services/1/promoted").await.0,
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[tokio::test]
+    async fn snapshot_with_node_acks() {
+        let (admin, statuses) = admin();
+        let mut stream = statuses.stream();
+        stream.on_request(Some("envoy-1"), CLUSTER_TYPE_URL, Some(""), "", None);
+        stream.sent(CLUSTER_TYPE_URL, "v1", "1", Vec::new());
+        stream.on_request(Some("envoy-1"), CLUSTER_TYPE_URL, Some("v1"), "1", None);
+
+        let (status, snapshot) = get(&admin, "/admin/snapshot").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(snapshot["version"], 1);
+        assert_eq!(snapshot["role"], "leader");
+        assert_eq!(
+            snapshot["groups"]["default"]["resources"][CLUSTER_TYPE_URL],
+            serde_json::json!(["Cluster::service::1", "Cluster::service::2"])
+        );
+        assert_eq!(
+            snapshot["nodes"]["envoy-1"]["types"][CLUSTER_TYPE_URL]["last_acked_version"],
+            "v1"
+        );
+    }
+
+    #[tokio::test]
+    async fn previews_leave_the_snapshot_alone() {
+        let (admin, _) = admin();
+        let preview = |body: &str| {
+            warp::test::request()
+                .method("POST")
+                .path("/admin/preview?offline=true")
+                .body(body)
+                .reply(&admin)
+        };
+
+        // the issuer is not reachable, offline skips the discovery
+        let response = preview(
+            r#"{"id": 3, "hosts": ["three.app", "www.three.app"], "policies": [],
+                "target_domain": "http://three:80", "proxy_rules": [],
+                "oidc_issuer": "http://keycloak:8080/auth/realms/three"}"#,
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let resources: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        let listener = resources
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|resource| resource["type_url"] == LISTENER_TYPE_URL)
+            .unwrap();
+        let manager = &listener["resource"]["filter_chains"][0]["filters"][0]["typed_config"];
+        assert_eq!(
+            manager["route_config"]["virtual_hosts"][0]["domains"],
+            serde_json::json!(["three.app", "www.three.app"]),
+            "{}",
+            listener
+        );
+
+        let response = preview(r#"{"id": 4, "hosts": ["four.app"], "target_domain": ""}"#).await;
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let report: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(report["id"], 4);
+        assert_eq!(report["passed"], false);
+        assert!(report["error"]
+            .as_str()
+            .unwrap()
+            .starts_with("invalid service"));
+        assert_eq!(
+            preview("[").await.status(),
+            StatusCode::UNPROCESSABLE_ENTITY
+        );
+
+        let (_, snapshot) = get(&admin, "/admin/snapshot").await;
+        assert_eq!(snapshot["version"], 1);
+        assert_eq!(
+            snapshot["groups"]["default"]["resources"][CLUSTER_TYPE_URL],
+            serde_json::json!(["Cluster::service::1", "Cluster::service::2"])
+        );
+    }
+
+    #[tokio::test]
+    async fn reloads_on_request() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("services.json");
+        let reload = Reload {
+            reloader: Reloader::default(),
+            token: Some("s3cr3t".to_string()),
+        };
+        let (admin, _, config) = admin_with(reload.clone());
+        let publisher = Publisher::new(config, Duration::from_millis(0));
+        let watcher = ConfigWatcher::new(&path, Default::default(), publisher.clone());
+        Source::File(Arc::new(watcher)).install_reload(&reload.reloader, &publisher);
+        let post = |token: Option<&'static str>| {
+            let request = warp::test::request().method("POST").path("/admin/reload");
+            let request = match token {
+                Some(token) => request.header("x-reload-token", token),
+                None => request,
+            };
+            request.reply(&admin)
+        };
+
+        // service 2 goes away
+        let services: serde_json::Value = serde_json::from_str(SERVICES).unwrap();
+        std::fs::write(&path, serde_json::json!([services[0]]).to_string()).unwrap();
+        for token in &[None, Some("wrong")] {
+            let response = post(*token).await;
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        }
+        assert_eq!(get(&admin, "/admin/snapshot").await.1["version"], 1);
+
+        let response = post(Some("s3cr3t")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["version"], 2);
+
+        let response = post(Some("s3cr3t")).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert!(response.body().is_empty());
+
+        std::fs::write(&path, "[{\"id\": 1}]").unwrap();
+        let response = post(Some("s3cr3t")).await;
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert!(!body["errors"].as_array().unwrap().is_empty(), "{}", body);
+        let (_, snapshot) = get(&admin, "/admin/snapshot").await;
+        assert_eq!(snapshot["version"], 2);
+        assert_eq!(
+            snapshot["groups"]["default"]["resources"][CLUSTER_TYPE_URL],
+            serde_json::json!(["Cluster::service::1"])
+        );
+    }
+}
@@ -0,0 +1,2179 @@
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use clap::{App, AppSettings, Arg, ArgMatches, ErrorKind, SubCommand};
+use tracing_subscriber::filter::LevelFilter;
+
+use crate::configuration::{self, ServicesFormat};
+use crate::conflicts::HostConflicts;
+use crate::field_errors::Validate;
+#[cfg(feature = "git-source")]
+use crate::git;
+use crate::header_options::{HeaderOptions, ServerHeader, StripHostPort, Timeout};
+use crate::http_client::{self, HttpClient};
+use crate::identity::{self, Identity};
+use crate::leader;
+use crate::listener_address::{self, ListenerAddress};
+use crate::porta::{self, PortaSettings};
+use crate::remote::{self, HttpSourceSettings, TlsOptions};
+use crate::request_id::RequestId;
+use crate::service::{WasmModule, WasmSettings};
+use crate::source;
+use crate::telemetry::{self, Telemetry};
+use crate::tls::TlsSettings;
+use crate::type_urls::TypeUrls;
+use crate::util::file_utils::DigestAlgorithm;
+use crate::wasm_module::{self, RemoteModule};
+use crate::xds_auth::XdsAuth;
+
+const DEFAULT_XDS_ADDRESS: &str = "0.0.0.0:5000";
+const DEFAULT_LISTENER_ADDRESS: &str = "0.0.0.0";
+const DEFAULT_ADMIN_PORT: &str = "5001";
+const DEFAULT_HEALTH_PORT: &str = "5002";
+const DEFAULT_XDS_KEEPALIVE: &str = "60";
+const DEFAULT_XDS_STREAM_WINDOW: &str = "4194304";
+const DEFAULT_XDS_CONNECTION_WINDOW: &str = "16777216";
+// the range of HTTP/2 windows
+const MIN_WINDOW: u32 = 65_535;
+const MAX_WINDOW: u32 = 2_147_483_647;
+const DEFAULT_SERVICES_CONFIG: &str = "./log.json";
+const DEFAULT_GRACE_PERIOD: &str = "10";
+const DEFAULT_PUBLISH_WINDOW: &str = "300";
+const DEFAULT_EXPORT_CONCURRENCY: &str = "8";
+const DEFAULT_MAX_VIRTUAL_CLUSTERS: &str = "0";
+const DEFAULT_HTTP_CONNECT_TIMEOUT: &str = "10";
+const DEFAULT_HTTP_TIMEOUT: &str = "30";
+const DEFAULT_OTEL_SAMPLING_RATIO: &str = "1";
+
+/// What the controller was asked to do.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Command {
+    /// Serve the services to Envoy, the default.
+    Serve,
+    /// Check that every service of the services files exports.
+    Validate,
+    /// Render the resources exported from the services file as a static
+    /// Envoy bootstrap.
+    Export,
+    /// Compare the resources exported from the services file with those of
+    /// an Envoy config dump.
+    Diff,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    Text,
+    // multi-line, for following a single service by hand
+    Pretty,
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReportFormat {
+    Text,
+    Json,
+}
+
+/// Options of the validate command.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Validation {
+    pub files: Vec<PathBuf>,
+    // skip OIDC discovery, checking the issuers are URLs only
+    pub offline: bool,
+    pub format: ReportFormat,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExportFormat {
+    Yaml,
+    Json,
+}
+
+/// Options of the export command.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Export {
+    pub format: ExportFormat,
+    // stdout when unset
+    pub output: Option<PathBuf>,
+    pub node_group: std::string::String,
+}
+
+/// Options of the diff command.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DumpDiff {
+    // the config dump of the Envoy the resources are compared with
+    pub config_dump: PathBuf,
+    pub format: ReportFormat,
+    pub node_group: std::string::String,
+}
+
+/// The built-in server of the wasm modules.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WasmServer {
+    pub port: u16,
+    // the modules are served at their path relative to it
+    pub root: PathBuf,
+}
+
+/// How the discovery server handles its connections. The windows default
+/// to far more than the 64 KiB of HTTP/2, for snapshots of megabytes to go
+/// out without waiting on the nodes for every few kilobytes. Messages are
+/// never limited in size, neither the responses nor the requests.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GrpcSettings {
+    // of TCP, the HTTP/2 pings of the streams being up to the nodes; none
+    // when 0, for idle connections to be dropped along the way
+    pub tcp_keepalive: Option<Duration>,
+    pub stream_window: u32,
+    pub connection_window: u32,
+    // HTTP/2 streams a node opens on one connection, as many as it likes
+    // without it
+    pub max_concurrent_streams: Option<u32>,
+    // requests of a connection handled at once, streams included
+    pub concurrency_limit: Option<usize>,
+}
+
+/// Election of the replica publishing the services.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LeaderElection {
+    pub kind: leader::Kind,
+    // the lock of the file election
+    pub lock_path: Option<PathBuf>,
+    // followers serve nothing until elected without it
+    pub snapshot_path: Option<PathBuf>,
+    pub snapshot_digest: DigestAlgorithm,
+    // the lease of the kube election, its defaults when unset
+    pub lease_name: Option<std::string::String>,
+    pub lease_namespace: Option<std::string::String>,
+    pub lease_duration: Option<Duration>,
+}
+
+/// Runtime settings of the controller, from the command line or the
+/// environment variables backing each flag.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ControllerConfig {
+    pub command: Command,
+    pub xds_address: SocketAddr,
+    pub admin_port: u16,
+    pub health_port: u16,
+    pub services_source: source::Kind,
+    pub services_config: PathBuf,
+    // read by the http source only
+    pub http_source: Option<HttpSourceSettings>,
+    // read by the porta source only
+    pub porta_source: Option<PortaSettings>,
+    // read by the git source only
+    #[cfg(feature = "git-source")]
+    pub git_source: Option<git::GitSettings>,
+    // watched by the kube source, the namespace of the kube config context
+    // when empty
+    pub kube_namespaces: Vec<std::string::String>,
+    // the one of the extension of each services file when unset
+    pub services_format: Option<ServicesFormat>,
+    // unknown fields and deprecated problems of the services fail them
+    // rather than being only warned about
+    pub strict_config: bool,
+    pub host_conflicts: HostConflicts,
+    pub wasm: WasmSettings,
+    pub log_level: LevelFilter,
+    pub log_format: LogFormat,
+    // the spans of the controller are only exported when set
+    pub telemetry: Option<Telemetry>,
+    // the discovery server listens in plaintext without it
+    pub tls: Option<TlsSettings>,
+    pub rollback_on_nack: bool,
+    pub shutdown_notify_streams: bool,
+    pub shutdown_grace_period: Duration,
+    // changes within it are published as a single version
+    pub publish_window: Duration,
+    pub export_concurrency: usize,
+    // a service failing to export fails the whole config
+    pub strict_export: bool,
+    // describes the discovery services to any client when set
+    pub xds_reflection: bool,
+    pub grpc: GrpcSettings,
+    // identities the nodes have to be one of, anyone getting anything
+    // without it
+    pub xds_auth_config: Option<PathBuf>,
+    // the admin API is only served when set
+    pub admin_enabled: bool,
+    // reloads through the admin API need it in a header when set
+    pub admin_reload_token: Option<std::string::String>,
+    // every replica publishes without it
+    pub leader_election: Option<LeaderElection>,
+    pub validation: Validation,
+    pub export: Export,
+    pub diff: DumpDiff,
+    // Envoy fetches the filters from elsewhere without it
+    pub wasm_server: Option<WasmServer>,
+    // the REST discovery endpoints are only served when set
+    pub rest_xds_port: Option<u16>,
+}
+
+// Flags of every command.
+fn common_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("services-config")
+            .long("services-config")
+            .env("SERVICES_CONFIG")
+            .value_name("PATH")
+            .default_value(DEFAULT_SERVICES_CONFIG)
+            .help("Services file, or directory of them, read by the file source"),
+        Arg::with_name("services-format")
+            .long("services-format")
+            .env("SERVICES_FORMAT")
+            .value_name("FORMAT")
+            .possible_values(&["json", "yaml"])
+            .help("Format of the services files, YAML for .yaml and .yml files and JSON otherwise by default"),
+        Arg::with_name("host-conflicts")
+            .long("host-conflicts")
+            .env("HOST_CONFLICTS")
+            .value_name("POLICY")
+            .possible_values(&["error", "warn"])
+            .default_value("warn")
+            .help("Whether services of a node group serving the same hosts are refused, or only warned about"),
+        Arg::with_name("strict-config")
+            .long("strict-config")
+            .help("Refuse services with fields the controller doesn't know, or with deprecated problems, rather than warn about them [env: STRICT_CONFIG=]"),
+        Arg::with_name("wasm-base-url")
+            .long("wasm-base-url")
+            .env("WASM_BASE_URL")
+            .value_name("URL")
+            .help("URL Envoy fetches the wasm filters from [default: http://control-plane-main:5001]"),
+        Arg::with_name("wasm-optional")
+            .long("wasm-optional")
+            .help("Export the services without their services filter when the file is missing, as in development, the snapshot being degraded; those missing their 3scale auth filter still fail [env: WASM_OPTIONAL=]"),
+        Arg::with_name("wasm-filter-path")
+            .long("wasm-filter-path")
+            .env("WASM_FILTER_PATH")
+            .value_name("PATH")
+            .help("Services filter, on disk and under the wasm base URL [default: static/filter.wasm]"),
+        Arg::with_name("wasm-module-url")
+            .long("wasm-module-url")
+            .env("WASM_MODULE_URL")
+            .value_name("URL")
+            .conflicts_with("wasm-filter-path")
+            .requires("wasm-module-sha256")
+            .help("URL the services filter is downloaded from, rather than read from the filter path"),
+        Arg::with_name("wasm-module-sha256")
+            .long("wasm-module-sha256")
+            .env("WASM_MODULE_SHA256")
+            .value_name("HEX")
+            .requires("wasm-module-url")
+            .help("SHA-256 the downloaded filter must have"),
+        Arg::with_name("wasm-module-cache")
+            .long("wasm-module-cache")
+            .env("WASM_MODULE_CACHE")
+            .value_name("PATH")
+            .default_value(wasm_module::DEFAULT_CACHE)
+            .help("Directory the downloaded filters are kept in, under the wasm server root to be served"),
+        Arg::with_name("wasm-registry")
+            .long("wasm-registry")
+            .env("WASM_REGISTRY")
+            .value_name("NAME=PATH[@SHA256]")
+            .multiple(true)
+            .number_of_values(1)
+            .use_delimiter(true)
+            .help("Filters the services may pick by name, rather than the services filter, pinned to a digest when given"),
+        Arg::with_name("type-url-override")
+            .long("type-url-override")
+            .env("TYPE_URL_OVERRIDE")
+            .value_name("TYPE=TYPE")
+            .multiple(true)
+            .number_of_values(1)
+            .use_delimiter(true)
+            .help("Name a filter type the way the Envoy being configured knows it, for builds whose types differ from the upstream protos"),
+        Arg::with_name("no-generate-request-id")
+            .long("no-generate-request-id")
+            .help("Leave the requests without an x-request-id header without one, for the services not setting it [env: NO_GENERATE_REQUEST_ID=]"),
+        Arg::with_name("preserve-external-request-id")
+            .long("preserve-external-request-id")
+            .help("Keep the x-request-id header of the requests from outside, like from a trusted load balancer, for the services not setting it [env: PRESERVE_EXTERNAL_REQUEST_ID=]"),
+        Arg::with_name("always-set-request-id-in-response")
+            .long("always-set-request-id-in-response")
+            .help("Set the x-request-id header of the requests in their responses, for the services not setting it [env: ALWAYS_SET_REQUEST_ID_IN_RESPONSE=]"),
+        Arg::with_name("max-virtual-clusters")
+            .long("max-virtual-clusters")
+            .env("MAX_VIRTUAL_CLUSTERS")
+            .value_name("RULES")
+            .default_value(DEFAULT_MAX_VIRTUAL_CLUSTERS)
+            .help("Most mapping rules of a service Envoy keeps request stats of, as a virtual cluster each named after its metric, none when 0"),
+        Arg::with_name("uuid-request-id")
+            .long("uuid-request-id")
+            .help("Configure the UUID request id extension rather than leave it to Envoy, for the services not setting it [env: UUID_REQUEST_ID=]"),
+        Arg::with_name("request-id-in-filter-denials")
+            .long("request-id-in-filter-denials")
+            .help("Have the wasm filter tell the request id in the bodies of the requests it denies, for the services not setting it [env: REQUEST_ID_IN_FILTER_DENIALS=]"),
+        Arg::with_name("listener-address")
+            .long("listener-address")
+            .env("LISTENER_ADDRESS")
+            .value_name("ADDRESS")
+            .default_value(DEFAULT_LISTENER_ADDRESS)
+            .help("IPv4 or IPv6 address the listeners of the services bind, for the services not setting it"),
+        Arg::with_name("dual-stack")
+            .long("dual-stack")
+            .help("Bind a listener on each of 0.0.0.0 and :: for every service not setting its address, the listener address being either [env: DUAL_STACK=]"),
+        Arg::with_name("strip-host-port")
+            .long("strip-host-port")
+            .env("STRIP_HOST_PORT")
+            .value_name("PORTS")
+            .possible_values(&["keep", "matching", "any"])
+            .help("Remove the port of the host of the requests before routing, that of the listener or any, for the services not setting it"),
+        Arg::with_name("server-header")
+            .long("server-header")
+            .env("SERVER_HEADER")
+            .value_name("TRANSFORMATION")
+            .possible_values(&["overwrite", "append_if_absent", "pass_through"])
+            .help("How Envoy sets the Server header of the responses, for the services not setting it"),
+        Arg::with_name("server-name")
+            .long("server-name")
+            .env("SERVER_NAME")
+            .value_name("NAME")
+            .help("Server header Envoy sets on the responses, for the services not setting it"),
+        Arg::with_name("codec")
+            .long("codec")
+            .env("CODEC")
+            .value_name("CODEC")
+            .possible_values(&["auto", "http1", "http2"])
+            .help("Protocol of the downstream connections of the TCP listeners, for the services not setting it, Envoy telling them apart by default"),
+        Arg::with_name("idle-timeout")
+            .long("idle-timeout")
+            .env("IDLE_TIMEOUT")
+            .value_name("DURATION")
+            .help("How long the listeners keep the connections with no request in flight, as 30s or 5m, 0 for ever, for the services not setting it"),
+        Arg::with_name("drain-timeout")
+            .long("drain-timeout")
+            .env("DRAIN_TIMEOUT")
+            .value_name("DURATION")
+            .help("Grace period of the HTTP/2 connections the listeners close for the new streams racing the final GOAWAY, for the services not setting it"),
+        Arg::with_name("delayed-close-timeout")
+            .long("delayed-close-timeout")
+            .env("DELAYED_CLOSE_TIMEOUT")
+            .value_name("DURATION")
+            .help("How long the listeners wait for the clients to close the connections first, 0 closing them at once, for the services not setting it"),
+        Arg::with_name("proxy")
+            .long("proxy")
+            .value_name("URL")
+            .help("Proxy of the requests of the controller, as the OIDC discoveries, the Porta and HTTP sources and the module downloads, rather than those of HTTPS_PROXY and HTTP_PROXY"),
+        Arg::with_name("no-proxy")
+            .long("no-proxy")
+            .value_name("HOSTS")
+            .help("Hosts the requests of the controller reach without the proxy, comma separated domains, matching their subdomains, addresses and networks as 10.0.0.0/8, rather than those of no_proxy and NO_PROXY"),
+        Arg::with_name("ca-bundle")
+            .long("ca-bundle")
+            .env("CA_BUNDLE")
+            .value_name("PATH")
+            .help("PEM file of the CAs verifying the servers and proxies the controller requests, rather than the system ones"),
+        Arg::with_name("http-connect-timeout")
+            .long("http-connect-timeout")
+            .env("HTTP_CONNECT_TIMEOUT")
+            .value_name("SECONDS")
+            .default_value(DEFAULT_HTTP_CONNECT_TIMEOUT)
+            .help("Seconds the requests of the controller may take to connect"),
+        Arg::with_name("http-timeout")
+            .long("http-timeout")
+            .env("HTTP_TIMEOUT")
+            .value_name("SECONDS")
+            .default_value(DEFAULT_HTTP_TIMEOUT)
+            .help("Seconds the requests of the controller may take, the module downloads taking at least 100"),
+        Arg::with_name("identity-header")
+            .long("identity-header")
+            .env("IDENTITY_HEADER")
+            .value_name("NAME")
+            .help("Header naming the controller, its version and cluster on its requests and on those Envoy proxies to the upstreams, as x-gateway or via"),
+        Arg::with_name("cluster-name")
+            .long("cluster-name")
+            .env("CLUSTER_NAME")
+            .value_name("NAME")
+            .help("Cluster the controller runs in, named by the user agent of its requests and the identity header"),
+        Arg::with_name("allow-dynamic-forward-proxy")
+            .long("allow-dynamic-forward-proxy")
+            .help("Export the services of kind dynamic_forward_proxy, proxying to the hosts the requests name within their allowed domains [env: ALLOW_DYNAMIC_FORWARD_PROXY=]"),
+        Arg::with_name("allow-original-dst-header")
+            .long("allow-original-dst-header")
+            .help("Export the original_dst services taking their upstreams from the x-envoy-original-dst-host header of internal requests, within their allowed destinations [env: ALLOW_ORIGINAL_DST_HEADER=]"),
+        Arg::with_name("allow-http3")
+            .long("allow-http3")
+            .help("Export the HTTP/3 listeners of the services asking for them, for an Envoy built with QUIC [env: ALLOW_HTTP3=]"),
+        Arg::with_name("no-metadata")
+            .long("no-metadata")
+            .help("Leave out the metadata naming the services and metrics of the routes and clusters, for smaller configurations [env: NO_METADATA=]"),
+        Arg::with_name("wasm-module-serve-copy")
+            .long("wasm-module-serve-copy")
+            .requires("wasm-module-url")
+            .help("Have Envoy fetch the downloaded filter from the wasm base URL rather than its own [env: WASM_MODULE_SERVE_COPY=]"),
+        Arg::with_name("log-level")
+            .long("log-level")
+            .env("LOG_LEVEL")
+            .value_name("LEVEL")
+            .possible_values(&["off", "error", "warn", "info", "debug", "trace"])
+            .default_value("info")
+            .help("Log level, refined per module by RUST_LOG"),
+        Arg::with_name("log-format")
+            .long("log-format")
+            .env("LOG_FORMAT")
+            .value_name("FORMAT")
+            .possible_values(&["text", "pretty", "json"])
+            .default_value("text")
+            .help("Log line format"),
+        Arg::with_name("otel-endpoint")
+            .long("otel-endpoint")
+            .env("OTEL_EXPORTER_OTLP_ENDPOINT")
+            .value_name("URL")
+            .help("OTLP gRPC endpoint of the collector the spans of the controller are exported to, for a controller built with the otel feature"),
+        Arg::with_name("otel-sampling-ratio")
+            .long("otel-sampling-ratio")
+            .env("OTEL_TRACES_SAMPLER_ARG")
+            .value_name("RATIO")
+            .default_value(DEFAULT_OTEL_SAMPLING_RATIO)
+            .help("Share of the traces of the controller exported, from 0 to 1"),
+        Arg::with_name("otel-service-name")
+            .long("otel-service-name")
+            .env("OTEL_SERVICE_NAME")
+            .value_name("NAME")
+            .default_value(telemetry::DEFAULT_SERVICE_NAME)
+            .help("service.name of the spans exported"),
+    ]
+}
+
+// Flags of the validate command.
+fn validate_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("files")
+            .value_name("FILE")
+            .multiple(true)
+            .help("Services files to check, the services config by default"),
+        Arg::with_name("offline")
+            .long("offline")
+            .help("Skip OIDC discovery, only checking the issuers are URLs"),
+        Arg::with_name("skip-sha")
+            .long("skip-sha")
+            .help("Leave the wasm filter digests out, not reading the filters"),
+        Arg::with_name("format")
+            .long("format")
+            .value_name("FORMAT")
+            .possible_values(&["text", "json"])
+            .default_value("text")
+            .help("Report format"),
+    ]
+}
+
+// Flags of the export command.
+fn export_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("format")
+            .long("format")
+            .value_name("FORMAT")
+            .possible_values(&["yaml", "json"])
+            .default_value("yaml")
+            .help("Bootstrap format"),
+        Arg::with_name("output")
+            .long("output")
+            .short("o")
+            .value_name("PATH")
+            .help("File to write the bootstrap to, instead of stdout"),
+        Arg::with_name("node-group")
+            .long("node-group")
+            .value_name("GROUP")
+            .default_value(configuration::DEFAULT_NODE_GROUP)
+            .help("Node group whose resources are exported"),
+    ]
+}
+
+// Flags of the diff command.
+fn diff_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("config-dump")
+            .value_name("CONFIG_DUMP")
+            .required(true)
+            .help("JSON of the /config_dump of the Envoy to compare with, whose listeners and clusters are compared"),
+        Arg::with_name("format")
+            .long("format")
+            .value_name("FORMAT")
+            .possible_values(&["text", "json"])
+            .default_value("text")
+            .help("Report format"),
+        Arg::with_name("node-group")
+            .long("node-group")
+            .value_name("GROUP")
+            .default_value(configuration::DEFAULT_NODE_GROUP)
+            .help("Node group whose resources are compared"),
+    ]
+}
+
+// Flags of the serve command.
+fn serve_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("xds-address")
+            .long("xds-address")
+            .env("XDS_ADDRESS")
+            .value_name("ADDRESS")
+            .default_value(DEFAULT_XDS_ADDRESS)
+            .help("Address the discovery server binds to"),
+        Arg::with_name("admin-port")
+            .long("admin-port")
+            .env("ADMIN_PORT")
+            .value_name("PORT")
+            .default_value(DEFAULT_ADMIN_PORT)
+            .help("Port of the static files and the admin API"),
+        Arg::with_name("health-port")
+            .long("health-port")
+            .env("HEALTH_PORT")
+            .value_name("PORT")
+            .default_value(DEFAULT_HEALTH_PORT)
+            .help("Port of the health endpoints"),
+        Arg::with_name("services-source")
+            .long("services-source")
+            .env("SERVICES_SOURCE")
+            .value_name("SOURCE")
+            .possible_values(&source::Kind::names())
+            .default_value("file")
+            .help("Where the services are read from"),
+        Arg::with_name("http-source-url")
+            .long("http-source-url")
+            .env("HTTP_SOURCE_URL")
+            .value_name("URL")
+            .help("Services document the http source polls"),
+        Arg::with_name("http-source-poll-interval")
+            .long("http-source-poll-interval")
+            .env("HTTP_SOURCE_POLL_INTERVAL")
+            .value_name("SECONDS")
+            .help("Seconds between the fetches of the http source, up to a day, 30 by default"),
+        Arg::with_name("http-source-max-staleness")
+            .long("http-source-max-staleness")
+            .env("HTTP_SOURCE_MAX_STALENESS")
+            .value_name("SECONDS")
+            .help("Seconds the fetches of the http source may fail before the replica is degraded, none by default"),
+        Arg::with_name("http-source-token")
+            .long("http-source-token")
+            .env("HTTP_SOURCE_TOKEN")
+            .value_name("TOKEN")
+            .hide_env_values(true)
+            .help("Bearer token of the fetches of the http source"),
+        Arg::with_name("http-source-ca-file")
+            .long("http-source-ca-file")
+            .env("HTTP_SOURCE_CA_FILE")
+            .value_name("PATH")
+            .help("CA the server of the http source is verified against, rather than the system ones"),
+        Arg::with_name("http-source-cert-file")
+            .long("http-source-cert-file")
+            .env("HTTP_SOURCE_CERT_FILE")
+            .value_name("PATH")
+            .requires("http-source-key-file")
+            .help("Client certificate of the fetches of the http source"),
+        Arg::with_name("http-source-key-file")
+            .long("http-source-key-file")
+            .env("HTTP_SOURCE_KEY_FILE")
+            .value_name("PATH")
+            .requires("http-source-cert-file")
+            .help("Private key of the client certificate of the http source"),
+        Arg::with_name("porta-admin-url")
+            .long("porta-admin-url")
+            .env("PORTA_ADMIN_URL")
+            .value_name("URL")
+            .help("Admin portal of the 3scale account the porta source polls"),
+        Arg::with_name("porta-access-token")
+            .long("porta-access-token")
+            .env("PORTA_ACCESS_TOKEN")
+            .value_name("TOKEN")
+            .hide_env_values(true)
+            .help("Access token of the Account Management API for the porta source"),
+        Arg::with_name("porta-poll-interval")
+            .long("porta-poll-interval")
+            .env("PORTA_POLL_INTERVAL")
+            .value_name("SECONDS")
+            .help("Seconds between the syncs of the porta source, up to a day, 60 by default"),
+        Arg::with_name("porta-environment")
+            .long("porta-environment")
+            .env("PORTA_ENVIRONMENT")
+            .value_name("ENVIRONMENT")
+            .possible_values(&["production", "staging"])
+            .help("Environment of the proxy configs the porta source reads [default: production]"),
+        Arg::with_name("git-source-url")
+            .long("git-source-url")
+            .env("GIT_SOURCE_URL")
+            .value_name("URL")
+            .help("Repository the git source polls"),
+        Arg::with_name("git-source-branch")
+            .long("git-source-branch")
+            .env("GIT_SOURCE_BRANCH")
+            .value_name("BRANCH")
+            .help("Branch of the repository the git source reads [default: main]"),
+        Arg::with_name("git-source-path")
+            .long("git-source-path")
+            .env("GIT_SOURCE_PATH")
+            .value_name("PATH")
+            .help("Services file, or directory of them, in the repository, its root by default"),
+        Arg::with_name("git-source-checkout")
+            .long("git-source-checkout")
+            .env("GIT_SOURCE_CHECKOUT")
+            .value_name("PATH")
+            .help("Directory the git source checks the commits out in, under the temporary one by default"),
+        Arg::with_name("git-source-poll-interval")
+            .long("git-source-poll-interval")
+            .env("GIT_SOURCE_POLL_INTERVAL")
+            .value_name("SECONDS")
+            .help("Seconds between the fetches of the git source, up to a day, 60 by default"),
+        Arg::with_name("git-source-ssh-key")
+            .long("git-source-ssh-key")
+            .env("GIT_SOURCE_SSH_KEY")
+            .value_name("PATH")
+            .conflicts_with("git-source-token")
+            .help("Private SSH key the git source fetches with, for ssh:// and git@ URLs"),
+        Arg::with_name("git-source-token")
+            .long("git-source-token")
+            .env("GIT_SOURCE_TOKEN")
+            .value_name("TOKEN")
+            .hide_env_values(true)
+            .help("Bearer token the git source fetches with, for https:// URLs"),
+        Arg::with_name("kube-namespaces")
+            .long("kube-namespaces")
+            .env("KUBE_NAMESPACES")
+            .value_name("NAMESPACES")
+            .help("Comma separated namespaces the kube source watches, that of the kube config context by default"),
+        Arg::with_name("tls-cert")
+            .long("tls-cert")
+            .env("XDS_TLS_CERT")
+            .value_name("PATH")
+            .requires("tls-key")
+            .help("Certificate of the discovery server, enabling TLS"),
+        Arg::with_name("tls-key")
+            .long("tls-key")
+            .env("XDS_TLS_KEY")
+            .value_name("PATH")
+            .requires("tls-cert")
+            .help("Private key of the discovery server certificate"),
+        Arg::with_name("tls-client-ca")
+            .long("tls-client-ca")
+            .env("XDS_TLS_CLIENT_CA")
+            .value_name("PATH")
+            .requires("tls-cert")
+            .help("CA client certificates are verified against"),
+        Arg::with_name("tls-require-client-cert")
+            .long("tls-require-client-cert")
+            .requires("tls-client-ca")
+            .help("Refuse clients without a certificate [env: XDS_TLS_REQUIRE_CLIENT_CERT=]"),
+        Arg::with_name("xds-reflection")
+            .long("xds-reflection")
+            .help("Serve gRPC server reflection next to the discovery services [env: XDS_REFLECTION=]"),
+        Arg::with_name("xds-auth-config")
+            .long("xds-auth-config")
+            .env("XDS_AUTH_CONFIG")
+            .value_name("PATH")
+            .help("Identities the nodes have to present, by bearer token or client certificate, and the node groups each may get"),
+        Arg::with_name("xds-keepalive")
+            .long("xds-keepalive")
+            .env("XDS_KEEPALIVE")
+            .value_name("SECONDS")
+            .default_value(DEFAULT_XDS_KEEPALIVE)
+            .help("Interval of the TCP keepalives of the discovery connections, none when 0"),
+        Arg::with_name("xds-stream-window")
+            .long("xds-stream-window")
+            .env("XDS_STREAM_WINDOW")
+            .value_name("BYTES")
+            .default_value(DEFAULT_XDS_STREAM_WINDOW)
+            .help("Initial HTTP/2 window of the discovery streams"),
+        Arg::with_name("xds-connection-window")
+            .long("xds-connection-window")
+            .env("XDS_CONNECTION_WINDOW")
+            .value_name("BYTES")
+            .default_value(DEFAULT_XDS_CONNECTION_WINDOW)
+            .help("Initial HTTP/2 window of the discovery connections"),
+        Arg::with_name("xds-max-concurrent-streams")
+            .long("xds-max-concurrent-streams")
+            .env("XDS_MAX_CONCURRENT_STREAMS")
+            .value_name("STREAMS")
+            .help("Most HTTP/2 streams of a discovery connection, unlimited unless set"),
+        Arg::with_name("xds-concurrency-limit")
+            .long("xds-concurrency-limit")
+            .env("XDS_CONCURRENCY_LIMIT")
+            .value_name("REQUESTS")
+            .help("Most requests of a discovery connection handled at once, streams included, unlimited unless set"),
+        Arg::with_name("admin-enabled")
+            .long("admin-enabled")
+            .help("Serve the admin API next to the static files [env: ADMIN_ENABLED=]"),
+        Arg::with_name("admin-reload-token")
+            .long("admin-reload-token")
+            .env("ADMIN_RELOAD_TOKEN")
+            .value_name("TOKEN")
+            .hide_env_values(true)
+            .help("Shared secret the X-Reload-Token header needs for POST /admin/reload"),
+        Arg::with_name("leader-election")
+            .long("leader-election")
+            .env("LEADER_ELECTION")
+            .value_name("KIND")
+            .possible_values(&leader::Kind::names())
+            .help("Elect the replica polling the sources and publishing, the others following it"),
+        Arg::with_name("leader-lock-path")
+            .long("leader-lock-path")
+            .env("LEADER_LOCK_PATH")
+            .value_name("PATH")
+            .requires("leader-election")
+            .help("File the replicas lock to lead, for the file election"),
+        Arg::with_name("leader-snapshot-path")
+            .long("leader-snapshot-path")
+            .env("LEADER_SNAPSHOT_PATH")
+            .value_name("PATH")
+            .requires("leader-election")
+            .help("File the leader persists the services to, for the followers to serve"),
+        Arg::with_name("leader-snapshot-digest")
+            .long("leader-snapshot-digest")
+            .env("LEADER_SNAPSHOT_DIGEST")
+            .value_name("ALGORITHM")
+            .possible_values(DigestAlgorithm::NAMES)
+            .requires("leader-snapshot-path")
+            .help("Digest the followers check the persisted services against [default: sha256]"),
+        Arg::with_name("leader-lease-name")
+            .long("leader-lease-name")
+            .env("LEADER_LEASE_NAME")
+            .value_name("NAME")
+            .requires("leader-election")
+            .help("Lease the replicas take to lead, for the kube election [default: gateway-ng-controller]"),
+        Arg::with_name("leader-lease-namespace")
+            .long("leader-lease-namespace")
+            .env("LEADER_LEASE_NAMESPACE")
+            .value_name("NAMESPACE")
+            .requires("leader-election")
+            .help("Namespace of the lease, that of the kube config context by default"),
+        Arg::with_name("leader-lease-duration")
+            .long("leader-lease-duration")
+            .env("LEADER_LEASE_DURATION")
+            .value_name("SECONDS")
+            .requires("leader-election")
+            .help("Seconds the lease lasts unless renewed, 15 by default"),
+        Arg::with_name("rollback-on-nack")
+            .long("rollback-on-nack")
+            .help("Roll back the services whose resources Envoy rejects [env: ROLLBACK_ON_NACK=]"),
+        Arg::with_name("shutdown-notify-streams")
+            .long("shutdown-notify-streams")
+            .help("End discovery streams with UNAVAILABLE on shutdown [env: SHUTDOWN_NOTIFY_STREAMS=]"),
+        Arg::with_name("shutdown-grace-period")
+            .long("shutdown-grace-period")
+            .env("SHUTDOWN_GRACE_PERIOD")
+            .value_name("SECONDS")
+            .default_value(DEFAULT_GRACE_PERIOD)
+            .help("How long streams get to drain on shutdown"),
+        Arg::with_name("publish-window")
+            .long("publish-window")
+            .env("PUBLISH_WINDOW")
+            .value_name("MILLISECONDS")
+            .default_value(DEFAULT_PUBLISH_WINDOW)
+            .help("How long changes of the services are gathered for before publishing a version"),
+        Arg::with_name("strict-export")
+            .long("strict-export")
+            .help("Keep the current version when any service fails to export [env: STRICT_EXPORT=]"),
+        Arg::with_name("export-concurrency")
+            .long("export-concurrency")
+            .env("EXPORT_CONCURRENCY")
+            .value_name("SERVICES")
+            .default_value(DEFAULT_EXPORT_CONCURRENCY)
+            .help("How many services are exported at once"),
+        Arg::with_name("rest-xds-port")
+            .long("rest-xds-port")
+            .env("REST_XDS_PORT")
+            .value_name("PORT")
+            .help("Port of the REST discovery endpoints, for the nodes fetching their resources over HTTP rather than gRPC, over TLS with --tls-cert"),
+        Arg::with_name("wasm-server-port")
+            .long("wasm-server-port")
+            .env("WASM_SERVER_PORT")
+            .value_name("PORT")
+            .help("Port of the wasm modules server, which the wasm base URL defaults to when set"),
+        Arg::with_name("wasm-server-root")
+            .long("wasm-server-root")
+            .env("WASM_SERVER_ROOT")
+            .value_name("PATH")
+            .default_value(".")
+            .help("Directory the wasm modules are served from"),
+    ]
+}
+
+fn app() -> App<'static, 'static> {
+    App::new(env!("CARGO_PKG_NAME"))
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("Envoy control plane serving 3scale services")
+        // flags without a command are the ones of serve
+        .setting(AppSettings::ArgsNegateSubcommands)
+        .args(&common_args())
+        .args(&serve_args())
+        .subcommand(
+            SubCommand::with_name("serve")
+                .about("Serve the services to Envoy, the default")
+                .args(&common_args())
+                .args(&serve_args()),
+        )
+        .subcommand(
+            SubCommand::with_name("validate")
+                .about("Check that every service of the services files exports")
+                .args(&common_args())
+                .args(&validate_args()),
+        )
+        .subcommand(
+            SubCommand::with_name("export")
+                .about("Render the services file as a static Envoy bootstrap")
+                .args(&common_args())
+                .args(&export_args()),
+        )
+        .subcommand(
+            SubCommand::with_name("diff")
+                .about("Compare the resources of the services file with those of an Envoy config dump, failing when they differ")
+                .args(&common_args())
+                .args(&diff_args()),
+        )
+}
+
+fn invalid(name: &str, value: impl std::fmt::Display) -> clap::Error {
+    clap::Error::value_validation_auto(format!("invalid value '{}' for --{}", value, name))
+}
+
+// Parse the value of `name`, `default` when the command has no such flag.
+fn parse<T: FromStr>(matches: &ArgMatches, name: &str, default: &str) -> clap::Result<T> {
+    let value = matches.value_of(name).unwrap_or(default);
+    value.parse().map_err(|_| invalid(name, value))
+}
+
+// The environment variables the command line reads besides those backing
+// the flags, clap reading these, like the proxies of the environment.
+type Vars<'a> = &'a dyn Fn(&str) -> Option<std::string::String>;
+
+// The first of the environment variables `names` set.
+fn first_var(vars: Vars, names: &[&str]) -> Option<std::string::String> {
+    names
+        .iter()
+        .filter_map(|name| vars(name))
+        .find(|value| !value.is_empty())
+}
+
+// How the controller makes its own requests, the proxies of the environment
+// being those of their scheme unless `--proxy` is given.
+fn http_client(matches: &ArgMatches, vars: Vars) -> clap::Result<HttpClient> {
+    // as curl, taking the scheme to be http when left out
+    let proxy = |value: &str| match value.contains("://") {
+        true => url::Url::parse(value),
+        false => url::Url::parse(&format!("http://{}", value)),
+    };
+    let from_env = |names: &[&str]| match first_var(vars, names) {
+        Some(value) => proxy(&value).map(Some).map_err(|_| {
+            clap::Error::value_validation_auto(format!(
+                "invalid value '{}' for {}",
+                value, names[1]
+            ))
+        }),
+        None => Ok(None),
+    };
+    let (http_proxy, https_proxy) = match matches.value_of("proxy") {
+        Some(url) => {
+            let url = proxy(url).map_err(|_| invalid("proxy", url))?;
+            (Some(url.clone()), Some(url))
+        }
+        None => (
+            from_env(&["http_proxy", "HTTP_PROXY"])?,
+            from_env(&["https_proxy", "HTTPS_PROXY"])?,
+        ),
+    };
+    let no_proxy = matches
+        .value_of("no-proxy")
+        .map(str::to_string)
+        .or_else(|| first_var(vars, &["no_proxy", "NO_PROXY"]))
+        .unwrap_or_default();
+    let seconds = |name, default| parse(matches, name, default).map(Duration::from_secs);
+    Ok(HttpClient {
+        http_proxy,
+        https_proxy,
+        no_proxy: http_client::parse_no_proxy(&no_proxy)
+            .map_err(|_| invalid("no-proxy", &no_proxy))?,
+        ca_file: path(matches, "ca-bundle"),
+        connect_timeout: seconds("http-connect-timeout", DEFAULT_HTTP_CONNECT_TIMEOUT)?,
+        timeout: seconds("http-timeout", DEFAULT_HTTP_TIMEOUT)?,
+        identity: identity(matches)?,
+    })
+}
+
+// What the requests of the controller and those Envoy proxies are told
+// apart by.
+fn identity(matches: &ArgMatches) -> clap::Result<Identity> {
+    let header = matches.value_of("identity-header");
+    if let Some(Err(e)) = header.map(identity::check_header) {
+        return Err(clap::Error::with_description(
+            &format!("--identity-header {}", e),
+            ErrorKind::InvalidValue,
+        ));
+    }
+    let cluster = matches.value_of("cluster-name");
+    if let Some(Err(e)) = cluster.map(identity::check_cluster) {
+        return Err(clap::Error::with_description(
+            &format!("--cluster-name {}", e),
+            ErrorKind::InvalidValue,
+        ));
+    }
+    Ok(Identity {
+        header: header.map(str::to_string),
+        cluster: cluster.map(str::to_string),
+    })
+}
+
+// The address of the listeners of the services not setting theirs.
+fn listener_address(matches: &ArgMatches, vars: Vars) -> clap::Result<ListenerAddress> {
+    let address = matches
+        .value_of("listener-address")
+        .unwrap_or(DEFAULT_LISTENER_ADDRESS);
+    let address = ListenerAddress {
+        address: listener_address::parse(address)
+            .map_err(|_| invalid("listener-address", address))?
+            .to_string(),
+        dual_stack: switch(matches, vars, "dual-stack", "DUAL_STACK"),
+    };
+    match address.findings("").errors.first() {
+        Some(error) => Err(clap::Error::with_description(
+            &format!("--dual-stack {}", error.message),
+            ErrorKind::ArgumentConflict,
+        )),
+        None => Ok(address),
+    }
+}
+
+// The options of the connection managers, Envoy's for those not given.
+fn header_options(matches: &ArgMatches) -> clap::Result<HeaderOptions> {
+    let timeout = |name| -> clap::Result<Option<Timeout>> {
+        matches
+            .value_of(name)
+            .map(|value| value.parse().map_err(|_| invalid(name, value)))
+            .transpose()
+    };
+    let options = HeaderOptions {
+        strip_host_port: matches
+            .value_of("strip-host-port")
+            .map(|ports| match ports {
+                "matching" => StripHostPort::Matching,
+                "any" => StripHostPort::Any,
+                _ => StripHostPort::Keep,
+            }),
+        server_header: matches
+            .value_of("server-header")
+            .map(|header| match header {
+                "append_if_absent" => ServerHeader::AppendIfAbsent,
+                "pass_through" => ServerHeader::PassThrough,
+                _ => ServerHeader::Overwrite,
+            }),
+        server_name: matches.value_of("server-name").map(str::to_string),
+        codec: matches
+            .value_of("codec")
+            .map(|codec| codec.parse().map_err(|_| invalid("codec", codec)))
+            .transpose()?,
+        idle_timeout: timeout("idle-timeout")?,
+        drain_timeout: timeout("drain-timeout")?,
+        delayed_close_timeout: timeout("delayed-close-timeout")?,
+    };
+    // the flags are named after the fields
+    match options.findings("").errors.first() {
+        Some(error) => Err(clap::Error::with_description(
+            &format!("--{} {}", error.path.replace('_', "-"), error.message),
+            ErrorKind::ValueValidation,
+        )),
+        None => Ok(options),
+    }
+}
+
+fn path(matches: &ArgMatches, name: &str) -> Option<PathBuf> {
+    matches.value_of_os(name).map(PathBuf::from)
+}
+
+// A filter of the registry, as in `v2=static/filter-v2.wasm@<sha256>`.
+fn wasm_module(value: &str) -> Option<(std::string::String, WasmModule)> {
+    let (name, path) = value.split_once('=')?;
+    let (path, sha256) = match path.rsplit_once('@') {
+        Some((path, sha256)) => (path, Some(sha256.to_lowercase())),
+        None => (path, None),
+    };
+    if name.is_empty() || path.is_empty() || !sha256.iter().all(|s| wasm_module::is_sha256(s)) {
+        return None;
+    }
+    Some((
+        name.to_string(),
+        WasmModule {
+            path: path.into(),
+            sha256,
+        },
+    ))
+}
+
+// Switches are set by their flag, or by their variable being `true`.
+fn switch(matches: &ArgMatches, vars: Vars, name: &str, var: &str) -> bool {
+    matches.is_present(name) || vars(var).as_deref() == Some("true")
+}
+
+fn grpc(matches: &ArgMatches) -> clap::Result<GrpcSettings> {
+    let window = |name, default| -> clap::Result<u32> {
+        let window = parse(matches, name, default)?;
+        if !(MIN_WINDOW..=MAX_WINDOW).contains(&window) {
+            return Err(invalid(name, window));
+        }
+        Ok(window)
+    };
+    let optional = |name| -> clap::Result<Option<u32>> {
+        match matches.value_of(name) {
+            Some(value) => match value.parse() {
+                Ok(0) | Err(_) => Err(invalid(name, value)),
+                Ok(value) => Ok(Some(value)),
+            },
+            None => Ok(None),
+        }
+    };
+    let keepalive = parse(matches, "xds-keepalive", DEFAULT_XDS_KEEPALIVE)?;
+    Ok(GrpcSettings {
+        tcp_keepalive: Some(Duration::from_secs(keepalive)).filter(|_| keepalive > 0),
+        stream_window: window("xds-stream-window", DEFAULT_XDS_STREAM_WINDOW)?,
+        connection_window: window("xds-connection-window", DEFAULT_XDS_CONNECTION_WINDOW)?,
+        max_concurrent_streams: optional("xds-max-concurrent-streams")?,
+        concurrency_limit: optional("xds-concurrency-limit")?.map(|limit| limit as usize),
+    })
+}
+
+fn tls(matches: &ArgMatches, vars: Vars) -> clap::Result<Option<TlsSettings>> {
+    let require_client_cert = switch(
+        matches,
+        vars,
+        "tls-require-client-cert",
+        "XDS_TLS_REQUIRE_CLIENT_CERT",
+    );
+    let client_ca_path = path(matches, "tls-client-ca");
+    let missing = |message: &str| {
+        Err(clap::Error::with_description(
+            message,
+            ErrorKind::MissingRequiredArgument,
+        ))
+    };
+    match (path(matches, "tls-cert"), path(matches, "tls-key")) {
+        (Some(cert_path), Some(key_path)) => {
+            if require_client_cert && client_ca_path.is_none() {
+                return missing("requiring client certificates needs --tls-client-ca");
+            }
+            Ok(Some(TlsSettings {
+                cert_path,
+                key_path,
+                client_ca_path,
+                require_client_cert,
+            }))
+        }
+        (None, None) if client_ca_path.is_none() && !require_client_cert => Ok(None),
+        (None, None) => missing("client certificate settings need --tls-cert and --tls-key"),
+        _ => missing("--tls-cert and --tls-key go together"),
+    }
+}
+
+// Where the spans are exported to, if anywhere.
+fn telemetry(matches: &ArgMatches) -> clap::Result<Option<Telemetry>> {
+    let endpoint = match matches.value_of("otel-endpoint") {
+        Some(endpoint) => endpoint,
+        None => return Ok(None),
+    };
+    if !cfg!(feature = "otel") {
+        return Err(clap::Error::with_description(
+            "--otel-endpoint needs a controller built with the otel feature",
+            ErrorKind::ArgumentConflict,
+        ));
+    }
+    url::Url::parse(endpoint).map_err(|_| invalid("otel-endpoint", endpoint))?;
+    let sampling_ratio: f64 = parse(matches, "otel-sampling-ratio", DEFAULT_OTEL_SAMPLING_RATIO)?;
+    if !(0.0..=1.0).contains(&sampling_ratio) {
+        return Err(invalid("otel-sampling-ratio", sampling_ratio));
+    }
+    Ok(Some(Telemetry {
+        endpoint: endpoint.to_string(),
+        sampling_ratio,
+        service_name: matches
+            .value_of("otel-service-name")
+            .unwrap_or(telemetry::DEFAULT_SERVICE_NAME)
+            .to_string(),
+    }))
+}
+
+// The settings of the http source, when it is the one the services are read
+// from.
+fn http_source(
+    matches: &ArgMatches,
+    kind: source::Kind,
+) -> clap::Result<Option<HttpSourceSettings>> {
+    if kind != source::Kind::Http {
+        return Ok(None);
+    }
+    let url = match matches.value_of("http-source-url") {
+        Some(value) => url::Url::parse(value)
+            .ok()
+            .filter(|url| url.scheme() == "http" || url.scheme() == "https")
+            .ok_or_else(|| invalid("http-source-url", value))?,
+        None => {
+            return Err(clap::Error::with_description(
+                "the http source needs --http-source-url",
+                ErrorKind::MissingRequiredArgument,
+            ))
+        }
+    };
+    Ok(Some(HttpSourceSettings {
+        url,
+        poll_interval: poll_interval(
+            matches,
+            "http-source-poll-interval",
+            remote::DEFAULT_POLL_INTERVAL,
+        )?,
+        max_staleness: seconds(matches, "http-source-max-staleness")?,
+        token: matches.value_of("http-source-token").map(str::to_string),
+        tls: TlsOptions {
+            ca_file: path(matches, "http-source-ca-file"),
+            cert_file: path(matches, "http-source-cert-file"),
+            key_file: path(matches, "http-source-key-file"),
+        },
+    }))
+}
+
+// The seconds of `name`, if given.
+fn seconds(matches: &ArgMatches, name: &str) -> clap::Result<Option<Duration>> {
+    match matches.value_of(name) {
+        Some(value) => value
+            .parse()
+            .map(|seconds| Some(Duration::from_secs(seconds)))
+            .map_err(|_| invalid(name, value)),
+        None => Ok(None),
+    }
+}
+
+// The seconds between the polls of a source, `default` unless given, from
+// a second to `remote::MAX_POLL_INTERVAL`.
+fn poll_interval(matches: &ArgMatches, name: &str, default: Duration) -> clap::Result<Duration> {
+    let interval = seconds(matches, name)?.unwrap_or(default);
+    if interval < Duration::from_secs(1) || interval > remote::MAX_POLL_INTERVAL {
+        return Err(invalid(name, interval.as_secs()));
+    }
+    Ok(interval)
+}
+
+// The settings of the porta source, when it is the one the services are
+// read from.
+fn porta_source(matches: &ArgMatches, kind: source::Kind) -> clap::Result<Option<PortaSettings>> {
+    if kind != source::Kind::Porta {
+        return Ok(None);
+    }
+    let (admin_url, access_token) = match (
+        matches.value_of("porta-admin-url"),
+        matches.value_of("porta-access-token"),
+    ) {
+        (Some(url), Some(token)) => (
+            url::Url::parse(url).map_err(|_| invalid("porta-admin-url", url))?,
+            token.to_string(),
+        ),
+        _ => {
+            return Err(clap::Error::with_description(
+                "the porta source needs --porta-admin-url and --porta-access-token",
+                ErrorKind::MissingRequiredArgument,
+            ))
+        }
+    };
+    Ok(Some(PortaSettings {
+        admin_url,
+        access_token,
+        poll_interval: poll_interval(matches, "porta-poll-interval", porta::DEFAULT_POLL_INTERVAL)?,
+        environment: matches
+            .value_of("porta-environment")
+            .unwrap_or("production")
+            .to_string(),
+    }))
+}
+
+// The settings of the git source, when it is the one the services are read
+// from.
+#[cfg(feature = "git-source")]
+fn git_source(matches: &ArgMatches, kind: source::Kind) -> clap::Result<Option<git::GitSettings>> {
+    if kind != source::Kind::Git {
+        return Ok(None);
+    }
+    let url = match matches.value_of("git-source-url") {
+        Some(url) => url.to_string(),
+        None => {
+            return Err(clap::Error::with_description(
+                "the git source needs --git-source-url",
+                ErrorKind::MissingRequiredArgument,
+            ))
+        }
+    };
+    let auth = match (
+        path(matches, "git-source-ssh-key"),
+        matches.value_of("git-source-token"),
+    ) {
+        (Some(key), _) => Some(git::GitAuth::SshKey(key)),
+        (None, Some(token)) => Some(git::GitAuth::Token(token.to_string())),
+        (None, None) => None,
+    };
+    Ok(Some(git::GitSettings {
+        url,
+        branch: matches
+            .value_of("git-source-branch")
+            .unwrap_or(git::DEFAULT_BRANCH)
+            .to_string(),
+        path: path(matches, "git-source-path").unwrap_or_default(),
+        checkout: path(matches, "git-source-checkout")
+            .unwrap_or_else(|| std::env::temp_dir().join("gateway-ng-controller-git")),
+        poll_interval: poll_interval(
+            matches,
+            "git-source-poll-interval",
+            git::DEFAULT_POLL_INTERVAL,
+        )?,
+        auth,
+    }))
+}
+
+// The namespaces the kube source watches, none for that of the kube config
+// context.
+fn kube_namespaces(matches: &ArgMatches) -> Vec<std::string::String> {
+    matches
+        .value_of("kube-namespaces")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|namespace| !namespace.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+fn leader_election(matches: &ArgMatches) -> clap::Result<Option<LeaderElection>> {
+    let kind = match matches.value_of("leader-election") {
+        Some(kind) => kind.parse().map_err(|_| invalid("leader-election", kind))?,
+        None => return Ok(None),
+    };
+    let lock_path = path(matches, "leader-lock-path");
+    if kind == leader::Kind::File && lock_path.is_none() {
+        return Err(clap::Error::with_description(
+            "the file election needs --leader-lock-path",
+            ErrorKind::MissingRequiredArgument,
+        ));
+    }
+    let snapshot_digest = match matches.value_of("leader-snapshot-digest") {
+        Some(digest) => digest
+            .parse()
+            .map_err(|_| invalid("leader-snapshot-digest", digest))?,
+        None => DigestAlgorithm::default(),
+    };
+    Ok(Some(LeaderElection {
+        kind,
+        lock_path,
+        snapshot_path: path(matches, "leader-snapshot-path"),
+        snapshot_digest,
+        lease_name: matches.value_of("leader-lease-name").map(str::to_string),
+        lease_namespace: matches
+            .value_of("leader-lease-namespace")
+            .map(str::to_string),
+        lease_duration: seconds(matches, "leader-lease-duration")?,
+    }))
+}
+
+impl ControllerConfig {
+    /// How the services files are read.
+    pub fn parse_options(&self) -> configuration::ParseOptions {
+        configuration::ParseOptions {
+            format: self.services_format,
+            strict: self.strict_config,
+            host_conflicts: self.host_conflicts,
+        }
+    }
+
+    /// Who may stream discovery, as read from `--xds-auth-config`.
+    pub fn xds_auth(&self) -> anyhow::Result<XdsAuth> {
+        let mutual_tls = self
+            .tls
+            .as_ref()
+            .is_some_and(|tls| tls.client_ca_path.is_some());
+        match self.xds_auth_config {
+            Some(ref path) => XdsAuth::load(path, mutual_tls),
+            None => Ok(XdsAuth::default()),
+        }
+    }
+
+    /// Parse a command line, `args` starting with the binary name. Errors
+    /// carry the usage, and `exit` prints them.
+    pub fn from_args<I, T>(args: I) -> clap::Result<ControllerConfig>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString> + Clone,
+    {
+        ControllerConfig::from_args_in(args, &|name| std::env::var(name).ok())
+    }
+
+    // Parse a command line, in the environment of `vars`.
+    fn from_args_in<I, T>(args: I, vars: Vars) -> clap::Result<ControllerConfig>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString> + Clone,
+    {
+        let matches = app().get_matches_from_safe(args)?;
+        let (command, matches) = match matches.subcommand() {
+            ("validate", Some(matches)) => (Command::Validate, matches),
+            ("export", Some(matches)) => (Command::Export, matches),
+            ("diff", Some(matches)) => (Command::Diff, matches),
+            ("serve", Some(matches)) => (Command::Serve, matches),
+            _ => (Command::Serve, &matches),
+        };
+
+        let services_source = parse(matches, "services-source", "file")?;
+        if services_source != source::Kind::File && matches.occurrences_of("services-config") > 0 {
+            return Err(clap::Error::with_description(
+                "--services-config is only read by the file source",
+                ErrorKind::ArgumentConflict,
+            ));
+        }
+        let wasm_server = match matches.value_of("wasm-server-port") {
+            Some(port) => Some(WasmServer {
+                port: port
+                    .parse()
+                    .map_err(|_| invalid("wasm-server-port", port))?,
+                root: path(matches, "wasm-server-root").unwrap_or_else(|| ".".into()),
+            }),
+            None => None,
+        };
+        let rest_xds_port = match matches.value_of("rest-xds-port") {
+            Some(port) => Some(port.parse().map_err(|_| invalid("rest-xds-port", port))?),
+            None => None,
+        };
+        // the tokens of the nodes are not sent in the clear
+        let insecure =
+            matches.value_of("xds-auth-config").is_some() && matches.value_of("tls-cert").is_none();
+        if rest_xds_port.is_some() && insecure {
+            return Err(clap::Error::with_description(
+                "--rest-xds-port needs --tls-cert along with --xds-auth-config",
+                ErrorKind::ArgumentConflict,
+            ));
+        }
+        let defaults = WasmSettings::default();
+        let default_base_url = match wasm_server {
+            Some(ref server) => format!("http://control-plane-main:{}", server.port),
+            None => defaults.base_url,
+        };
+        let remote = match matches.value_of("wasm-module-url") {
+            Some(url) => {
+                let sha256 = matches.value_of("wasm-module-sha256").unwrap_or_default();
+                if !wasm_module::is_sha256(sha256) {
+                    return Err(invalid("wasm-module-sha256", sha256));
+                }
+                Some(RemoteModule {
+                    url: url::Url::parse(url).map_err(|_| invalid("wasm-module-url", url))?,
+                    sha256: sha256.to_lowercase(),
+                    cache: path(matches, "wasm-module-cache")
+                        .unwrap_or_else(|| wasm_module::DEFAULT_CACHE.into()),
+                    serve_copy: switch(
+                        matches,
+                        vars,
+                        "wasm-module-serve-copy",
+                        "WASM_MODULE_SERVE_COPY",
+                    ),
+                })
+            }
+            None => None,
+        };
+        let mut modules = BTreeMap::new();
+        for module in matches.values_of("wasm-registry").into_iter().flatten() {
+            let (name, module) =
+                wasm_module(module).ok_or_else(|| invalid("wasm-registry", module))?;
+            modules.insert(name, module);
+        }
+        let mut type_urls = TypeUrls::default();
+        for type_override in matches.values_of("type-url-override").into_iter().flatten() {
+            match type_override.split_once('=') {
+                Some((from, to)) if !from.is_empty() && !to.is_empty() && !to.contains('/') => {
+                    type_urls.overrides.insert(from.to_string(), to.to_string());
+                }
+                _ => return Err(invalid("type-url-override", type_override)),
+            }
+        }
+        // Envoy's own unless set
+        let enabled = |name, var| Some(true).filter(|_| switch(matches, vars, name, var));
+        let request_id = RequestId {
+            generate_request_id: Some(false).filter(|_| {
+                switch(
+                    matches,
+                    vars,
+                    "no-generate-request-id",
+                    "NO_GENERATE_REQUEST_ID",
+                )
+            }),
+            preserve_external_request_id: enabled(
+                "preserve-external-request-id",
+                "PRESERVE_EXTERNAL_REQUEST_ID",
+            ),
+            always_set_request_id_in_response: enabled(
+                "always-set-request-id-in-response",
+                "ALWAYS_SET_REQUEST_ID_IN_RESPONSE",
+            ),
+            uuid_extension: enabled("uuid-request-id", "UUID_REQUEST_ID"),
+            in_filter_denials: enabled(
+                "request-id-in-filter-denials",
+                "REQUEST_ID_IN_FILTER_DENIALS",
+            ),
+        };
+        let wasm = WasmSettings {
+            base_url: matches
+                .value_of("wasm-base-url")
+                .map(str::to_string)
+                .unwrap_or(default_base_url),
+            filter_path: match remote {
+                Some(ref remote) => remote.path(),
+                None => path(matches, "wasm-filter-path").unwrap_or(defaults.filter_path),
+            },
+            skip_sha: matches.is_present("skip-sha"),
+            wasm_required: !switch(matches, vars, "wasm-optional", "WASM_OPTIONAL"),
+            remote,
+            modules,
+            type_urls,
+            request_id,
+            virtual_clusters: parse(
+                matches,
+                "max-virtual-clusters",
+                DEFAULT_MAX_VIRTUAL_CLUSTERS,
+            )?,
+            metadata: !switch(matches, vars, "no-metadata", "NO_METADATA"),
+            dynamic_forward_proxy: switch(
+                matches,
+                vars,
+                "allow-dynamic-forward-proxy",
+                "ALLOW_DYNAMIC_FORWARD_PROXY",
+            ),
+            original_dst_header: switch(
+                matches,
+                vars,
+                "allow-original-dst-header",
+                "ALLOW_ORIGINAL_DST_HEADER",
+            ),
+            http3: switch(matches, vars, "allow-http3", "ALLOW_HTTP3"),
+            listener_address: listener_address(matches, vars)?,
+            http_client: http_client(matches, vars)?,
+            header_options: header_options(matches)?,
+        };
+        let services_format = match matches.value_of("services-format") {
+            Some("yaml") => Some(ServicesFormat::Yaml),
+            Some(_) => Some(ServicesFormat::Json),
+            None => None,
+        };
+        let log_format = match matches.value_of("log-format") {
+            Some("json") => LogFormat::Json,
+            Some("pretty") => LogFormat::Pretty,
+            _ => LogFormat::Text,
+        };
+        let export = Export {
+            format: match matches.value_of("format") {
+                Some("json") => ExportFormat::Json,
+                _ => ExportFormat::Yaml,
+            },
+            output: path(matches, "output"),
+            node_group: matches
+                .value_of("node-group")
+                .unwrap_or(configuration::DEFAULT_NODE_GROUP)
+                .to_string(),
+        };
+        let diff = DumpDiff {
+            config_dump: path(matches, "config-dump").unwrap_or_default(),
+            format: match matches.value_of("format") {
+                Some("json") => ReportFormat::Json,
+                _ => ReportFormat::Text,
+            },
+            node_group: matches
+                .value_of("node-group")
+                .unwrap_or(configuration::DEFAULT_NODE_GROUP)
+                .to_string(),
+        };
+        let grace_period: u64 = parse(matches, "shutdown-grace-period", DEFAULT_GRACE_PERIOD)?;
+        let publish_window: u64 = parse(matches, "publish-window", DEFAULT_PUBLISH_WINDOW)?;
+        let services_config =
+            path(matches, "services-config").unwrap_or_else(|| DEFAULT_SERVICES_CONFIG.into());
+        let validation = Validation {
+            files: match matches.values_of_os("files") {
+                Some(files) => files.map(PathBuf::from).collect(),
+                None => vec![services_config.clone()],
+            },
+            offline: matches.is_present("offline"),
+            format: match matches.value_of("format") {
+                Some("json") => ReportFormat::Json,
+                _ => ReportFormat::Text,
+            },
+        };
+
+        Ok(ControllerConfig {
+            command,
+            xds_address: parse(matches, "xds-address", DEFAULT_XDS_ADDRESS)?,
+            admin_port: parse(matches, "admin-port", DEFAULT_ADMIN_PORT)?,
+            health_port: parse(matches, "health-port", DEFAULT_HEALTH_PORT)?,
+            services_source,
+            services_config,
+            http_source: http_source(matches, services_source)?,
+            porta_source: porta_source(matches, services_source)?,
+            #[cfg(feature = "git-source")]
+            git_source: git_source(matches, services_source)?,
+            kube_namespaces: kube_namespaces(matches),
+            services_format,
+            strict_config: switch(matches, vars, "strict-config", "STRICT_CONFIG"),
+            host_conflicts: parse(matches, "host-conflicts", "warn")?,
+            wasm,
+            log_level: parse(matches, "log-level", "info")?,
+            log_format,
+            telemetry: telemetry(matches)?,
+            tls: tls(matches, vars)?,
+            rollback_on_nack: switch(matches, vars, "rollback-on-nack", "ROLLBACK_ON_NACK"),
+            shutdown_notify_streams: switch(
+                matches,
+                vars,
+                "shutdown-notify-streams",
+                "SHUTDOWN_NOTIFY_STREAMS",
+            ),
+            shutdown_grace_period: Duration::from_secs(grace_period),
+            publish_window: Duration::from_millis(publish_window),
+            export_concurrency: parse(matches, "export-concurrency", DEFAULT_EXPORT_CONCURRENCY)?,
+            strict_export: switch(matches, vars, "strict-export", "STRICT_EXPORT"),
+            xds_reflection: switch(matches, vars, "xds-reflection", "XDS_REFLECTION"),
+            grpc: grpc(matches)?,
+            xds_auth_config: path(matches, "xds-auth-config"),
+            admin_enabled: switch(matches, vars, "admin-enabled", "ADMIN_ENABLED"),
+            admin_reload_token: matches.value_of("admin-reload-token").map(str::to_string),
+            leader_election: leader_election(matches)?,
+            validation,
+            export,
+            diff,
+            wasm_server,
+            rest_xds_port,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the command line alone, whatever the proxies and switches of the
+    // environment the tests run in
+    fn parse(args: &str) -> clap::Result<ControllerConfig> {
+        ControllerConfig::from_args_in(
+            std::iter::once("gateway-ng-controller").chain(args.split_whitespace()),
+            &|_| None,
+        )
+    }
+
+    #[test]
+    fn serves_by_default() {
+        let config = parse("").unwrap();
+        assert_eq!(config.command, Command::Serve);
+        assert_eq!(config.xds_address, "0.0.0.0:5000".parse().unwrap());
+        assert_eq!(config.admin_port, 5001);
+        assert_eq!(config.health_port, 5002);
+        assert_eq!(config.services_source, source::Kind::File);
+        assert_eq!(config.services_config, PathBuf::from("./log.json"));
+        assert_eq!(config.services_format, None);
+        assert!(!config.strict_config);
+        assert_eq!(config.host_conflicts, HostConflicts::Warn);
+        assert_eq!(config.wasm, WasmSettings::default());
+        assert_eq!(config.tls, None);
+        assert_eq!(config.telemetry, None);
+        assert_eq!(config.shutdown_grace_period, Duration::from_secs(10));
+        assert_eq!(config.publish_window, Duration::from_millis(300));
+        assert_eq!(config.export_concurrency, 8);
+        assert!(!config.strict_export);
+        assert_eq!(config.wasm_server, None);
+        assert_eq!(config.rest_xds_port, None);
+        assert!(!config.xds_reflection);
+        assert_eq!(
+            config.grpc,
+            GrpcSettings {
+                tcp_keepalive: Some(Duration::from_secs(60)),
+                stream_window: 4 * 1024 * 1024,
+                connection_window: 16 * 1024 * 1024,
+                max_concurrent_streams: None,
+                concurrency_limit: None,
+            }
+        );
+        assert_eq!(config.xds_auth_config, None);
+        assert!(!config.admin_enabled);
+        assert_eq!(config.admin_reload_token, None);
+        assert_eq!(config.leader_election, None);
+        assert_eq!(config.http_source, None);
+        assert_eq!(config.porta_source, None);
+    }
+
+    #[test]
+    fn serve_flags() {
+        let config = parse(
+            "serve --xds-address 127.0.0.1:18000 --admin-port 8001 --services-config /etc/services.json \
+             --wasm-base-url http://files:8080/ --wasm-filter-path static/v2.wasm --wasm-optional --log-level debug \
+             --log-format json --tls-cert tls.crt --tls-key tls.key --tls-client-ca ca.crt \
+             --tls-require-client-cert --rollback-on-nack --shutdown-grace-period 30 --publish-window 50 --export-concurrency 32 \
+             --strict-export --xds-reflection --xds-keepalive 0 --xds-stream-window 65535 \
+             --xds-connection-window 1048576 --xds-max-concurrent-streams 100 --xds-concurrency-limit 8 --xds-auth-config auth.yaml --admin-enabled \
+             --admin-reload-token s3cr3t --leader-election file --leader-lock-path /run/leader.lock \
+             --leader-snapshot-path /var/lib/snapshot.json --leader-snapshot-digest sha512",
+        )
+        .unwrap();
+        assert_eq!(config.xds_address, "127.0.0.1:18000".parse().unwrap());
+        assert_eq!(config.admin_port, 8001);
+        assert_eq!(config.services_config, PathBuf::from("/etc/services.json"));
+        assert_eq!(
+            config.wasm.url(&config.wasm.filter_path),
+            "http://files:8080/static/v2.wasm"
+        );
+        assert!(!config.wasm.wasm_required);
+        assert_eq!(config.log_level, LevelFilter::DEBUG);
+        assert_eq!(config.log_format, LogFormat::Json);
+        assert_eq!(
+            config.tls,
+            Some(TlsSettings {
+                cert_path: "tls.crt".into(),
+                key_path: "tls.key".into(),
+                client_ca_path: Some("ca.crt".into()),
+                require_client_cert: true,
+            })
+        );
+        assert!(config.rollback_on_nack);
+        assert!(config.xds_reflection);
+        assert_eq!(
+            config.grpc,
+            GrpcSettings {
+                tcp_keepalive: None,
+                stream_window: 65_535,
+                connection_window: 1024 * 1024,
+                max_concurrent_streams: Some(100),
+                concurrency_limit: Some(8),
+            }
+        );
+        assert_eq!(config.xds_auth_config, Some(PathBuf::from("auth.yaml")));
+        assert!(config.admin_enabled);
+        assert_eq!(config.admin_reload_token.as_deref(), Some("s3cr3t"));
+        assert_eq!(config.shutdown_grace_period, Duration::from_secs(30));
+        assert_eq!(config.publish_window, Duration::from_millis(50));
+        assert_eq!(config.export_concurrency, 32);
+        assert!(config.strict_export);
+        assert_eq!(
+            config.leader_election,
+            Some(LeaderElection {
+                kind: leader::Kind::File,
+                lock_path: Some("/run/leader.lock".into()),
+                snapshot_path: Some("/var/lib/snapshot.json".into()),
+                snapshot_digest: DigestAlgorithm::Sha512,
+                lease_name: None,
+                lease_namespace: None,
+                lease_duration: None,
+            })
+        );
+        assert_eq!(
+            parse("--admin-port 8001").unwrap(),
+            ControllerConfig {
+                command: Command::Serve,
+                ..parse("serve --admin-port 8001").unwrap()
+            }
+        );
+    }
+
+    #[test]
+    fn wasm_server_is_the_default_base_url() {
+        let config = parse("--wasm-server-port 8080 --wasm-server-root /srv").unwrap();
+        assert_eq!(
+            config.wasm_server,
+            Some(WasmServer {
+                port: 8080,
+                root: "/srv".into(),
+            })
+        );
+        assert_eq!(
+            config.wasm.url(&config.wasm.filter_path),
+            "http://control-plane-main:8080/static/filter.wasm"
+        );
+        let config = parse("--wasm-server-port 8080 --wasm-base-url http://files/").unwrap();
+        assert_eq!(config.wasm.base_url, "http://files/");
+    }
+
+    #[test]
+    fn spans_are_exported_by_an_otel_build_only() {
+        let args = "--otel-endpoint http://otel-collector:4317 --otel-sampling-ratio 0.25 \
+                    --otel-service-name controller-eu";
+        if !cfg!(feature = "otel") {
+            let e = parse(args).unwrap_err();
+            assert!(e.message.contains("otel feature"), "{}", e);
+            return;
+        }
+        assert_eq!(
+            parse(args).unwrap().telemetry,
+            Some(Telemetry {
+                endpoint: "http://otel-collector:4317".to_string(),
+                sampling_ratio: 0.25,
+                service_name: "controller-eu".to_string(),
+            })
+        );
+        assert_eq!(
+            parse("--otel-endpoint http://otel-collector:4317")
+                .unwrap()
+                .telemetry
+                .map(|telemetry| (telemetry.sampling_ratio, telemetry.service_name)),
+            Some((1.0, "gateway-ng-controller".to_string()))
+        );
+        for args in &[
+            "--otel-endpoint otel-collector",
+            "--otel-endpoint http://otel-collector:4317 --otel-sampling-ratio 1.5",
+            "--otel-endpoint http://otel-collector:4317 --otel-sampling-ratio -1",
+        ] {
+            assert!(parse(args).is_err(), "{}", args);
+        }
+    }
+
+    #[test]
+    fn remote_filters_are_pinned() {
+        let sha256 = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        let config = parse(&format!(
+            "--wasm-module-url https://registry/filter/1.2.wasm --wasm-module-sha256 {} \
+             --wasm-module-cache cache/wasm --wasm-module-serve-copy",
+            sha256.to_uppercase()
+        ))
+        .unwrap();
+        let remote = config.wasm.remote.clone().unwrap();
+        assert_eq!(remote.sha256, sha256);
+        assert!(remote.serve_copy);
+        assert_eq!(config.wasm.filter_path, remote.path());
+        assert!(config.wasm.filter_path.starts_with("cache/wasm"));
+        assert!(config
+            .wasm
+            .filter_url(&config.wasm.filter_path)
+            .starts_with("http://control-plane-main:5001/cache/wasm/"));
+
+        let config = parse(&format!(
+            "--wasm-module-url https://registry/filter/1.2.wasm --wasm-module-sha256 {}",
+            sha256
+        ))
+        .unwrap();
+        assert_eq!(
+            config.wasm.filter_url(&config.wasm.filter_path),
+            "https://registry/filter/1.2.wasm"
+        );
+
+        for args in &[
+            "--wasm-module-url https://registry/filter.wasm".to_string(),
+            "--wasm-module-url https://registry/filter.wasm --wasm-module-sha256 abc".to_string(),
+            format!(
+                "--wasm-module-url https://registry/filter.wasm --wasm-module-sha256 {} \
+                 --wasm-filter-path static/filter.wasm",
+                sha256
+            ),
+        ] {
+            assert!(parse(args).is_err(), "{}", args);
+        }
+    }
+
+    #[test]
+    fn registry_modules_are_named() {
+        let sha256 = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        let config = parse(&format!(
+            "--wasm-registry v2=static/filter-v2.wasm@{} --wasm-registry canary=static/canary.wasm",
+            sha256.to_uppercase()
+        ))
+        .unwrap();
+        assert_eq!(
+            config.wasm.modules.into_iter().collect::<Vec<_>>(),
+            [
+                (
+                    "canary".to_string(),
+                    WasmModule {
+                        path: "static/canary.wasm".into(),
+                        sha256: None,
+                    }
+                ),
+                (
+                    "v2".to_string(),
+                    WasmModule {
+                        path: "static/filter-v2.wasm".into(),
+                        sha256: Some(sha256.to_string()),
+                    }
+                ),
+            ]
+        );
+        for module in &["static/filter.wasm", "v2=", "v2=static/filter.wasm@abc"] {
+            assert!(parse(&format!("--wasm-registry {}", module)).is_err());
+        }
+    }
+
+    #[test]
+    fn type_urls_are_overridden() {
+        let config = parse(
+            "--type-url-override envoy.extensions.filters.http.wasm.v3.Wasm=envoy.extensions.filters.http.wasm.v4alpha.Wasm",
+        )
+        .unwrap();
+        assert_eq!(
+            config
+                .wasm
+                .type_urls
+                .url("envoy.extensions.filters.http.wasm.v3.Wasm"),
+            "type.googleapis.com/envoy.extensions.filters.http.wasm.v4alpha.Wasm"
+        );
+        for type_override in &[
+            "envoy.Wasm",
+            "=envoy.Wasm",
+            "envoy.Wasm=type.googleapis.com/envoy.Wasm",
+        ] {
+            assert!(parse(&format!("--type-url-override {}", type_override)).is_err());
+        }
+    }
+
+    #[test]
+    fn listener_addresses_are_checked() {
+        assert_eq!(
+            parse("").unwrap().wasm.listener_address,
+            ListenerAddress::default()
+        );
+        let config = parse("--listener-address :: --dual-stack").unwrap();
+        assert_eq!(
+            config.wasm.listener_address,
+            ListenerAddress {
+                address: "::".to_string(),
+                dual_stack: true,
+            }
+        );
+        for args in &[
+            "--listener-address fe80::1%eth0",
+            "--listener-address [::]",
+            "--listener-address 0.0.0",
+            "--listener-address ::1 --dual-stack",
+        ] {
+            assert!(parse(args).is_err(), "{}", args);
+        }
+    }
+
+    #[test]
+    fn http_timeouts_are_durations() {
+        assert_eq!(
+            parse("").unwrap().wasm.header_options,
+            HeaderOptions::default()
+        );
+        let config = parse("--idle-timeout 5m --delayed-close-timeout 0").unwrap();
+        assert_eq!(
+            config.wasm.header_options,
+            HeaderOptions {
+                idle_timeout: Some(Timeout(Duration::from_secs(300))),
+                delayed_close_timeout: Some(Timeout(Duration::from_secs(0))),
+                ..Default::default()
+            }
+        );
+        for (args, flag) in &[
+            ("--idle-timeout 30", "--idle-timeout"),
+            ("--drain-timeout 0", "--drain-timeout"),
+            ("--delayed-close-timeout 1d", "--delayed-close-timeout"),
+        ] {
+            let error = parse(args).unwrap_err().to_string();
+            assert!(error.contains(flag), "{}: {}", args, error);
+        }
+    }
+
+    #[test]
+    fn codecs_are_named() {
+        use crate::header_options::Codec;
+
+        assert_eq!(parse("").unwrap().wasm.header_options.codec, None);
+        assert_eq!(
+            parse("--codec http2").unwrap().wasm.header_options.codec,
+            Some(Codec::Http2)
+        );
+        assert_eq!(
+            parse("--codec http3").unwrap_err().kind,
+            ErrorKind::InvalidValue
+        );
+    }
+
+    #[test]
+    fn requests_of_the_controller_go_through_the_proxy() {
+        let config =
+            parse("--proxy proxy.corp:3128 --no-proxy .svc,10.0.0.0/8 --http-timeout 5").unwrap();
+        let client = config.wasm.http_client;
+        let proxy = url::Url::parse("http://proxy.corp:3128").unwrap();
+        assert_eq!(client.http_proxy.as_ref(), Some(&proxy));
+        assert_eq!(client.https_proxy.as_ref(), Some(&proxy));
+        assert_eq!(client.no_proxy.len(), 2);
+        assert_eq!(client.timeout, Duration::from_secs(5));
+        assert_eq!(client.connect_timeout, Duration::from_secs(10));
+
+        for args in &["--no-proxy 10.0.0.0/40", "--http-timeout soon"] {
+            assert!(parse(args).is_err(), "{}", args);
+        }
+    }
+
+    #[test]
+    fn proxies_and_switches_fall_back_to_the_environment() {
+        let vars = |name: &str| match name {
+            "HTTPS_PROXY" => Some("proxy.corp:3128".to_string()),
+            "no_proxy" => Some(".svc".to_string()),
+            "STRICT_CONFIG" => Some("true".to_string()),
+            "ROLLBACK_ON_NACK" => Some("yes".to_string()),
+            _ => None,
+        };
+        let config = ControllerConfig::from_args_in(vec!["gateway-ng-controller"], &vars).unwrap();
+        let client = config.wasm.http_client;
+        assert_eq!(client.http_proxy, None);
+        assert_eq!(
+            client.https_proxy,
+            Some(url::Url::parse("http://proxy.corp:3128").unwrap())
+        );
+        assert_eq!(client.no_proxy.len(), 1);
+        assert!(config.strict_config);
+        // only `true` sets a switch
+        assert!(!config.rollback_on_nack);
+    }
+
+    #[test]
+    fn hosts_without_the_proxy_are_read_in_either_case() {
+        let vars = |name: &str| match name {
+            "NO_PROXY" => Some(".svc,10.0.0.0/8".to_string()),
+            _ => None,
+        };
+        let config = ControllerConfig::from_args_in(vec!["gateway-ng-controller"], &vars).unwrap();
+        assert_eq!(config.wasm.http_client.no_proxy.len(), 2);
+        // the flag first
+        let config = ControllerConfig::from_args_in(
+            vec!["gateway-ng-controller", "--no-proxy", ".svc"],
+            &vars,
+        )
+        .unwrap();
+        assert_eq!(config.wasm.http_client.no_proxy.len(), 1);
+    }
+
+    #[test]
+    fn source_flags() {
+        let config = parse(
+            "--services-source porta --porta-admin-url https://tenant-admin.3scale.net \
+             --porta-access-token s3cr3t --porta-poll-interval 300 --porta-environment staging",
+        )
+        .unwrap();
+        assert_eq!(
+            config.porta_source,
+            Some(PortaSettings {
+                admin_url: url::Url::parse("https://tenant-admin.3scale.net").unwrap(),
+                access_token: "s3cr3t".to_string(),
+                poll_interval: Duration::from_secs(300),
+                environment: "staging".to_string(),
+            })
+        );
+        let config = parse(
+            "--services-source porta --porta-admin-url https://tenant-admin.3scale.net --porta-access-token s3cr3t",
+        )
+        .unwrap();
+        let porta = config.porta_source.unwrap();
+        assert_eq!(porta.poll_interval, porta::DEFAULT_POLL_INTERVAL);
+        assert_eq!(porta.environment, "production");
+
+        let config = parse("--kube-namespaces gateway,,apps --leader-election file --leader-lock-path /run/leader.lock --leader-lease-name edge --leader-lease-duration 30").unwrap();
+        assert_eq!(config.kube_namespaces, ["gateway", "apps"]);
+        let election = config.leader_election.unwrap();
+        assert_eq!(election.lease_name.as_deref(), Some("edge"));
+        assert_eq!(election.lease_namespace, None);
+        assert_eq!(election.lease_duration, Some(Duration::from_secs(30)));
+        assert!(parse("").unwrap().kube_namespaces.is_empty());
+    }
+
+    #[cfg(feature = "git-source")]
+    #[test]
+    fn git_source_flags() {
+        let config = parse(
+            "--services-source git --git-source-url https://git.corp/gateway.git --git-source-branch live \
+             --git-source-path services --git-source-checkout /var/lib/checkout --git-source-token s3cr3t",
+        )
+        .unwrap();
+        assert_eq!(
+            config.git_source,
+            Some(git::GitSettings {
+                url: "https://git.corp/gateway.git".to_string(),
+                branch: "live".to_string(),
+                path: "services".into(),
+                checkout: "/var/lib/checkout".into(),
+                poll_interval: git::DEFAULT_POLL_INTERVAL,
+                auth: Some(git::GitAuth::Token("s3cr3t".to_string())),
+            })
+        );
+        let config = parse("--services-source git --git-source-url git@git.corp:gateway.git --git-source-ssh-key id_ed25519").unwrap();
+        let git = config.git_source.unwrap();
+        assert_eq!(git.branch, git::DEFAULT_BRANCH);
+        assert_eq!(git.auth, Some(git::GitAuth::SshKey("id_ed25519".into())));
+        assert_eq!(
+            parse("--services-source git").unwrap_err().kind,
+            ErrorKind::MissingRequiredArgument
+        );
+    }
+
+    #[test]
+    fn request_id_defaults_are_envoy_ones_unless_set() {
+        let config = parse("").unwrap();
+        assert_eq!(config.wasm.request_id, RequestId::default());
+
+        let config = parse(
+            "--no-generate-request-id --preserve-external-request-id \
+             --always-set-request-id-in-response --uuid-request-id \
+             --request-id-in-filter-denials",
+        )
+        .unwrap();
+        assert_eq!(
+            config.wasm.request_id,
+            RequestId {
+                generate_request_id: Some(false),
+                preserve_external_request_id: Some(true),
+                always_set_request_id_in_response: Some(true),
+                uuid_extension: Some(true),
+                in_filter_denials: Some(true),
+            }
+        );
+    }
+
+    #[test]
+    fn validate_and_export_options() {
+        let config = parse("validate --services-config services.json").unwrap();
+        assert_eq!(config.command, Command::Validate);
+        assert_eq!(config.services_config, PathBuf::from("services.json"));
+        assert_eq!(config.validation.files, [PathBuf::from("services.json")]);
+        assert_eq!(config.validation.format, ReportFormat::Text);
+
+        let config = parse(
+            "validate --offline --skip-sha --format json --services-format yaml --strict-config \
+             --host-conflicts error a.json b.json",
+        )
+        .unwrap();
+        assert!(config.validation.offline);
+        assert_eq!(config.services_format, Some(ServicesFormat::Yaml));
+        assert!(config.parse_options().strict);
+        assert_eq!(config.host_conflicts, HostConflicts::Error);
+        assert!(config.wasm.skip_sha);
+        assert_eq!(config.validation.format, ReportFormat::Json);
+        assert_eq!(
+            config.validation.files,
+            [PathBuf::from("a.json"), PathBuf::from("b.json")]
+        );
+
+        let config = parse("export --format json -o bootstrap.json --node-group edge").unwrap();
+        assert_eq!(config.command, Command::Export);
+        assert_eq!(
+            config.export,
+            Export {
+                format: ExportFormat::Json,
+                output: Some("bootstrap.json".into()),
+                node_group: "edge".to_string(),
+            }
+        );
+
+        let config = parse("diff --format json envoy.json").unwrap();
+        assert_eq!(config.command, Command::Diff);
+        assert_eq!(
+            config.diff,
+            DumpDiff {
+                config_dump: "envoy.json".into(),
+                format: ReportFormat::Json,
+                node_group: "default".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn bad_command_lines_are_explained() {
+        let kind = |args: &str| parse(args).unwrap_err().kind;
+        assert_eq!(kind("--xds-port 5000"), ErrorKind::UnknownArgument);
+        assert_eq!(
+            kind("validate --admin-port 8001"),
+            ErrorKind::UnknownArgument
+        );
+        // commands come first
+        assert_eq!(
+            kind("--admin-port 8001 validate"),
+            ErrorKind::UnknownArgument
+        );
+        assert_eq!(kind("--admin-port http"), ErrorKind::ValueValidation);
+        assert_eq!(kind("--log-format yaml"), ErrorKind::InvalidValue);
+        // HTTP/2 windows are of 64 KiB at least
+        assert_eq!(kind("--xds-stream-window 1024"), ErrorKind::ValueValidation);
+        assert_eq!(
+            kind("--xds-max-concurrent-streams 0"),
+            ErrorKind::ValueValidation
+        );
+        assert_eq!(
+            kind("--tls-cert tls.crt"),
+            ErrorKind::MissingRequiredArgument
+        );
+        assert_eq!(
+            kind("--services-source porta --services-config services.json"),
+            ErrorKind::ArgumentConflict
+        );
+        assert_eq!(
+            kind("--leader-election file"),
+            ErrorKind::MissingRequiredArgument
+        );
+        assert_eq!(
+            kind("--services-source http"),
+            ErrorKind::MissingRequiredArgument
+        );
+        assert_eq!(
+            kind("--services-source porta --porta-admin-url https://tenant-admin.3scale.net"),
+            ErrorKind::MissingRequiredArgument
+        );
+        assert_eq!(
+            kind("--services-source porta --porta-admin-url admin --porta-access-token s3cr3t"),
+            ErrorKind::ValueValidation
+        );
+        assert_eq!(
+            kind("--services-source porta --porta-admin-url https://tenant-admin.3scale.net --porta-access-token s3cr3t --porta-poll-interval 0"),
+            ErrorKind::ValueValidation
+        );
+        assert_eq!(
+            kind("--services-source http --http-source-url ftp://config/services.json"),
+            ErrorKind::ValueValidation
+        );
+        for header in &["cookie", "authorization", "content-length", "x-envoy-peer"] {
+            assert_eq!(
+                kind(&format!("--identity-header {}", header)),
+                ErrorKind::InvalidValue
+            );
+        }
+        let injected = ControllerConfig::from_args(vec![
+            "gateway-ng-controller",
+            "--cluster-name",
+            "eu-west\r\nx-injected: 1",
+        ]);
+        assert_eq!(injected.unwrap_err().kind, ErrorKind::InvalidValue);
+        assert_eq!(
+            kind("--rest-xds-port 18001 --xds-auth-config auth.yaml"),
+            ErrorKind::ArgumentConflict
+        );
+        // polling without a pause, or once in ages
+        for interval in &["0", "86401", "18446744073709551615"] {
+            assert_eq!(
+                kind(&format!(
+                    "--services-source http --http-source-url http://config/ --http-source-poll-interval {}",
+                    interval
+                )),
+                ErrorKind::ValueValidation
+            );
+        }
+    }
+
+    #[test]
+    fn http_source_flags() {
+        let config = parse(
+            "serve --services-source http --http-source-url https://config/services.yaml \
+             --http-source-poll-interval 5 --http-source-max-staleness 600 --http-source-token s3cr3t \
+             --http-source-ca-file ca.crt --http-source-cert-file client.crt --http-source-key-file client.key",
+        )
+        .unwrap();
+        assert_eq!(
+            config.http_source,
+            Some(HttpSourceSettings {
+                url: url::Url::parse("https://config/services.yaml").unwrap(),
+                poll_interval: Duration::from_secs(5),
+                max_staleness: Some(Duration::from_secs(600)),
+                token: Some("s3cr3t".to_string()),
+                tls: TlsOptions {
+                    ca_file: Some("ca.crt".into()),
+                    cert_file: Some("client.crt".into()),
+                    key_file: Some("client.key".into()),
+                },
+            })
+        );
+
+        let config =
+            parse("serve --services-source http --http-source-url http://config/").unwrap();
+        let http = config.http_source.unwrap();
+        assert_eq!(http.poll_interval, remote::DEFAULT_POLL_INTERVAL);
+        assert_eq!(http.max_staleness, None);
+    }
+}
@@ -1,31 +1,502 @@
-use crate::envoy_helpers::EnvoyExportList;
+use crate::conflicts::{self, HostConflicts};
+use crate::diff::{self, ConfigDiff};
+use crate::envoy_helpers::{canonical_hash, EnvoyExport, SharedExports};
+use crate::export_cache::{self, ExportCache};
+use crate::field_errors::{self, FieldErrors};
+use crate::migration;
+use crate::node_status::Nack;
+use crate::panics;
+use crate::propagation::Propagation;
+use crate::protobuf::envoy::config::core::v3::Node;
+use crate::publisher::PublisherStats;
+use crate::reconcile;
+use crate::rollback::{Quarantine, Rollback, ServiceExports};
 use crate::service;
+use crate::snapshot::Snapshot;
+use crate::snapshot_cache::Versions;
+use crate::snippets;
+use crate::util;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::fs::File;
 use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Instant;
 
 use std::collections::hash_map::DefaultHasher;
 use std::hash::Hasher;
 
-use anyhow::Result;
+use anyhow::{anyhow, Context, Result};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
 
-type ServicesList = Vec<service::Service>;
+pub type ServicesList = Vec<service::Service>;
+
+/// Node group of the services, and the Envoy nodes, not assigned to any.
+pub const DEFAULT_NODE_GROUP: &str = "default";
+
+/// Field of the Envoy node metadata naming the group of the node.
+pub const NODE_GROUP_METADATA: &str = "node_group";
+
+/// Field of the Envoy node metadata naming the tenant of the node.
+pub const TENANT_METADATA: &str = "tenant";
+
+/// The key of the snapshot served to the `group` nodes of `tenant`: the
+/// group itself for the default tenant, `tenant/group` for the others.
+pub fn snapshot_key(tenant: &str, group: &str) -> std::string::String {
+    if tenant == service::DEFAULT_TENANT {
+        group.to_string()
+    } else {
+        format!("{}/{}", tenant, group)
+    }
+}
+
+/// The tenant and the node group of a snapshot key, neither naming one
+/// with a `/`.
+pub fn split_key(key: &str) -> (&str, &str) {
+    key.split_once('/')
+        .unwrap_or((service::DEFAULT_TENANT, key))
+}
+
+/// The key the statuses of the node `id` are kept by, once it gets the
+/// snapshot of `key`: `tenant/id` for the other tenants than the default
+/// one, as for the snapshots, the same id being another node in another
+/// tenant.
+pub fn node_key(key: &str, id: &str) -> std::string::String {
+    snapshot_key(split_key(key).0, id)
+}
+
+fn metadata_string(node: &Node, name: &str) -> Option<std::string::String> {
+    match node.metadata.as_ref()?.fields.get(name)?.kind {
+        Some(prost_types::value::Kind::StringValue(ref value)) => Some(value.clone()),
+        _ => None,
+    }
+}
+
+/// Format of a services file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ServicesFormat {
+    Json,
+    // anchors and aliases are resolved, comments ignored
+    Yaml,
+}
+
+impl ServicesFormat {
+    /// YAML for `.yaml` and `.yml` files, JSON for anything else.
+    pub fn of(path: &Path) -> ServicesFormat {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("yaml") | Some("yml") => ServicesFormat::Yaml,
+            _ => ServicesFormat::Json,
+        }
+    }
+
+    /// Deserialize `content`, errors pointing at the line and column.
+    pub fn parse<T: DeserializeOwned>(self, content: &str) -> Result<T> {
+        match self {
+            ServicesFormat::Json => Ok(serde_json::from_str(content)?),
+            // the source of the errors repeats their message
+            ServicesFormat::Yaml => serde_yaml::from_str(content).map_err(|e| anyhow!("{}", e)),
+        }
+    }
+
+    /// Deserialize the services of `content`, a list of them or a single
+    /// one, once migrated to the current version of the services files.
+    /// Fails with every problem found in any of them, fields serde doesn't
+    /// know and deprecated problems being one when `strict`.
+    /// Services conflicting with each other fail it too, as the default
+    /// policy says.
+    #[cfg(test)]
+    pub fn parse_services(self, content: &str, strict: bool) -> Result<ServicesList> {
+        let placed = self.parse_placed(content, strict)?;
+        let checked: Vec<_> = placed
+            .iter()
+            .map(|(path, service)| conflicts::Placed {
+                service,
+                file: None,
+                path: path.clone(),
+            })
+            .collect();
+        check_conflicts(&checked, HostConflicts::default())?;
+        Ok(placed.into_iter().map(|(_, service)| service).collect())
+    }
+
+    // The services of `content` along with their paths in it.
+    fn parse_placed(
+        self,
+        content: &str,
+        strict: bool,
+    ) -> Result<Vec<(std::string::String, service::Service)>> {
+        let document: serde_json::Value = self.parse(content)?;
+        let migrated = migration::migrate(document)?;
+        for applied in &migrated.applied {
+            tracing::info!("Migrated the services: {}", applied);
+        }
+        let base = migrated.path;
+        let snippets = migrated.snippets;
+        // the file read as it is, its services as they'd be deserialized
+        let unchanged = migrated.applied.is_empty() && snippets.is_empty();
+        let list = migrated.services.is_array();
+        let values = match migrated.services {
+            serde_json::Value::Array(values) => values
+                .into_iter()
+                .enumerate()
+                .map(|(i, value)| (field_errors::index(base, i), value))
+                .collect(),
+            value => vec![(base.to_string(), value)],
+        };
+        let mut services = Vec::new();
+        let mut errors = Vec::new();
+        for (path, mut value) in values {
+            if let Err(error) = snippets::expand(&mut value, &snippets, &path) {
+                errors.push(error);
+                continue;
+            }
+            let (service, findings) = service::Service::from_value(&value, &path, strict);
+            for warning in &findings.warnings {
+                if first_warned(&warning.to_string()) {
+                    tracing::warn!("Service warning: {}", warning);
+                }
+            }
+            match service {
+                Some(service) => services.push((path, service)),
+                None => errors.extend(findings.errors),
+            }
+        }
+        if !errors.is_empty() {
+            if unchanged {
+                self.locate(content, base, list, &mut errors);
+            }
+            return Err(FieldErrors(errors).into());
+        }
+        Ok(services)
+    }
+
+    // The line and column of the first problem serde finds in the services
+    // of `content`, appended to that one of `errors`: the JSON values the
+    // services are read from have lost them.
+    fn locate(
+        self,
+        content: &str,
+        base: &str,
+        list: bool,
+        errors: &mut [field_errors::FieldError],
+    ) {
+        // only deserialized for its errors
+        #[allow(dead_code)]
+        #[derive(serde::Deserialize)]
+        struct Versioned<T> {
+            services: T,
+        }
+        let error = match (base.is_empty(), list) {
+            (true, true) => self.parse::<ServicesList>(content).err(),
+            (true, false) => self.parse::<service::Service>(content).err(),
+            (false, true) => self.parse::<Versioned<ServicesList>>(content).err(),
+            (false, false) => self.parse::<Versioned<service::Service>>(content).err(),
+        };
+        let error = match error {
+            Some(error) => error.to_string(),
+            None => return,
+        };
+        let (message, location) = match error.rfind(" at line ") {
+            Some(at) => error.split_at(at),
+            None => return,
+        };
+        if let Some(problem) = errors
+            .iter_mut()
+            .find(|problem| message.ends_with(&problem.message))
+        {
+            problem.message.push_str(location);
+        }
+    }
+}
+
+/// How the services files are read.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ParseOptions {
+    // the one of the extension of each services file when unset
+    pub format: Option<ServicesFormat>,
+    // refuse the fields serde doesn't know, and the deprecated problems,
+    // rather than only warn about them
+    pub strict: bool,
+    // what services serving the same hosts make
+    pub host_conflicts: HostConflicts,
+}
+
+// Whether `warning` of the services is new, those already logged not being
+// logged again every time the services are read, as the sources polling
+// them do, like the one of a policy left to the wasm filter.
+fn first_warned(warning: &str) -> bool {
+    static WARNED: Mutex<BTreeSet<std::string::String>> = Mutex::new(BTreeSet::new());
+    let mut warned = WARNED.lock().unwrap_or_else(|e| e.into_inner());
+    warned.insert(warning.to_string())
+}
+
+// Refuse the services conflicting with each other, only warning about the
+// conflicts the policy tolerates.
+fn check_conflicts(services: &[conflicts::Placed], policy: HostConflicts) -> Result<()> {
+    let errors = conflict_errors(services, policy);
+    if errors.is_empty() {
+        return Ok(());
+    }
+    let errors = errors.into_iter().map(|(_, error)| error).collect();
+    Err(FieldErrors(errors).into())
+}
+
+// The conflicts the policy refuses, along with the index of the service at
+// fault, the others only warned about.
+fn conflict_errors(
+    services: &[conflicts::Placed],
+    policy: HostConflicts,
+) -> Vec<(usize, field_errors::FieldError)> {
+    let found = conflicts::check(services, policy);
+    for (_, warning) in &found.warnings {
+        tracing::warn!("Conflicting services: {}", warning);
+    }
+    found.errors
+}
+
+/// `services` but those conflicting with the earlier ones, which come back
+/// with their errors, for the sources refusing their services one at a
+/// time rather than as a whole, as the services files are. Each service
+/// comes with what it was read from, if anything else than the source.
+pub fn without_conflicts(
+    services: Vec<(Option<std::string::String>, service::Service)>,
+    policy: HostConflicts,
+) -> (ServicesList, Vec<(u32, anyhow::Error)>) {
+    let checked: Vec<_> = services
+        .iter()
+        .map(|(origin, service)| conflicts::Placed {
+            service,
+            file: origin.clone(),
+            path: std::string::String::new(),
+        })
+        .collect();
+    let mut refused: BTreeMap<usize, Vec<field_errors::FieldError>> = BTreeMap::new();
+    for (i, error) in conflict_errors(&checked, policy) {
+        refused.entry(i).or_default().push(error);
+    }
+    let mut kept = Vec::with_capacity(services.len());
+    let mut errors = Vec::with_capacity(refused.len());
+    for (i, (_, service)) in services.into_iter().enumerate() {
+        match refused.remove(&i) {
+            Some(found) => errors.push((service.id, FieldErrors(found).into())),
+            None => kept.push(service),
+        }
+    }
+    (kept, errors)
+}
+
+// Resources of any service, by type and name, along with the service that
+// exported them first and the hash of their content.
+type Claimed = HashMap<(&'static str, std::string::String), (u32, [u8; 32])>;
+
+// The exports of service `id`, unless one of them has the name of another
+// resource, of that service or another, but not its content, which it would
+// replace. Resources shared as they are, like the clusters of a 3scale
+// backend, are fine, those a service exports twice kept once.
+fn claim(claimed: &mut Claimed, id: u32, exports: SharedExports) -> Result<SharedExports> {
+    let mut contents = HashMap::with_capacity(exports.len());
+    let mut unique = Vec::with_capacity(exports.len());
+    for export in exports.iter() {
+        let key = (export.config.type_url(), export.config.name().to_string());
+        let content = canonical_hash(&export.config);
+        if let Some(own) = contents.get(&key) {
+            if *own != content {
+                return Err(anyhow!(
+                    "resource '{}' is exported twice by service {}, with different contents",
+                    key.1,
+                    id
+                ));
+            }
+            continue;
+        }
+        if let Some((other, other_content)) = claimed.get(&key) {
+            if *other != id && *other_content != content {
+                return Err(anyhow!(
+                    "resource '{}' of service {} is named like one of service {}, with a different content",
+                    key.1,
+                    id,
+                    other
+                ));
+            }
+        }
+        contents.insert(key, content);
+        unique.push(export);
+    }
+    for (key, content) in contents {
+        claimed.entry(key).or_insert((id, content));
+    }
+    // copied only when exported twice, as the exports that don't change
+    // stay shared with the cache
+    if unique.len() == exports.len() {
+        return Ok(exports);
+    }
+    Ok(unique.into_iter().cloned().collect())
+}
+
+/// The services files under `dir`, in the order of their paths. Hidden
+/// entries are skipped, like the `..data` links of mounted config maps,
+/// which would have every service twice.
+pub fn services_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        let entries =
+            std::fs::read_dir(&dir).with_context(|| format!("cannot list {}", dir.display()))?;
+        for entry in entries {
+            let path = entry?.path();
+            match path.file_name().and_then(|name| name.to_str()) {
+                Some(name) if !name.starts_with('.') => {}
+                _ => continue,
+            }
+            if path.is_dir() {
+                pending.push(path);
+                continue;
+            }
+            let extension = path.extension().and_then(|extension| extension.to_str());
+            if let Some("json") | Some("yaml") | Some("yml") = extension {
+                files.push(path);
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Whether the snapshot being served is up to date with the services.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Readiness {
+    /// No snapshot was published yet, or it was not fully exported.
+    NotReady(std::string::String),
+    Ready,
+    /// The last reload failed, the previous snapshot is still served.
+    Degraded(std::string::String),
+}
+
+/// A service left out of the snapshot, with the chain of errors why, and
+/// when the reconciler exports it again.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct ExportFailure {
+    pub id: u32,
+    pub errors: Vec<std::string::String>,
+    // exports tried again since the service failed
+    pub retries: u32,
+    #[serde(rename = "next_retry_in_ms", serialize_with = "millis_until")]
+    pub next_retry: Instant,
+}
+
+fn millis_until<S: serde::Serializer>(at: &Instant, serializer: S) -> Result<S::Ok, S::Error> {
+    let left = at.saturating_duration_since(Instant::now());
+    serializer.serialize_u64(left.as_millis() as u64)
+}
+
+impl ExportFailure {
+    fn retried(id: u32, error: &anyhow::Error, retries: u32) -> ExportFailure {
+        ExportFailure {
+            id,
+            errors: error.chain().map(|cause| cause.to_string()).collect(),
+            retries,
+            next_retry: Instant::now() + reconcile::backoff(retries),
+        }
+    }
+}
 
 #[derive(Default, Debug, Clone)]
 pub struct Config {
     services: ServicesList,
     hash: std::string::String,
     version: u32,
+    // Envoy resources exported from `services`, and the snapshot served to
+    // every node group out of them.
+    exports: ServiceExports,
+    snapshots: BTreeMap<std::string::String, Arc<Snapshot>>,
+    // Where the versions are published to the streams, as the snapshots
+    // are updated.
+    versions: Versions,
+    // Set when rejected updates are rolled back.
+    rollback: Option<Rollback>,
+    // The versions published that no node acked yet.
+    propagation: Propagation,
+    // Services of the last config exported that failed to, why the last
+    // reload failed if it did, and whether every service was exported once.
+    export_failures: Vec<ExportFailure>,
+    reload_error: Option<std::string::String>,
+    exported: bool,
+    // The filters of the services left out for missing, as they may be in
+    // development.
+    missing_filters: BTreeSet<PathBuf>,
+    // The services warned about having more mapping rules than virtual
+    // clusters, with their number of rules, for each to be warned about
+    // once rather than at every export.
+    too_many_rules: BTreeSet<(u32, usize)>,
+    // Where the exported filters are fetched from, and how many services
+    // are exported at once, one at a time when not set.
+    wasm: service::WasmSettings,
+    export_concurrency: usize,
+    // The exports of the services that didn't change since they were
+    // exported, shared by the configs reloaded out of this one.
+    export_cache: ExportCache,
+    // Refuse a config as a whole when any of its services fails to export,
+    // rather than serving the others, the last one refused being published
+    // again as a whole once its retries are due.
+    strict: bool,
+    refused: Option<Box<Config>>,
+    // The content of the config the failures are those of, for those
+    // failing again in the same config to count as retries.
+    failures_hash: std::string::String,
+    // What changed in the services of the last version published.
+    last_diff: Option<ConfigDiff>,
+    // How the publications of the sources were coalesced.
+    publications: PublisherStats,
+    // Commit the services were read out of, by the git source.
+    revision: Option<std::string::String>,
 }
 
 impl Config {
-    pub fn parse_config(path: &str) -> Config {
+    /// Read the services file at `path`, in the format of `options` or else
+    /// the one of its extension. The services files of a directory are
+    /// merged, each holding a service or a list of them.
+    pub fn parse_config(path: &str, options: ParseOptions) -> Result<Config> {
         let mut config = Config {
             services: Vec::new(),
             ..Default::default()
         };
-        let raw_config = config.read_path(path);
+        if Path::new(path).is_dir() {
+            config.parse_dir(Path::new(path), options)?;
+            return Ok(config);
+        }
+        let raw_config = config.read_path(path)?;
         config.set_hash(&raw_config);
-        config.parse_json(raw_config);
+        let format = options
+            .format
+            .unwrap_or_else(|| ServicesFormat::of(Path::new(path)));
+        config.parse_services(raw_config, format, options)?;
+        Ok(config)
+    }
+
+    /// Read the services of `content`, fetched from elsewhere than a file.
+    pub fn parse_content(
+        content: &str,
+        format: ServicesFormat,
+        options: ParseOptions,
+    ) -> Result<Config> {
+        let mut config = Config {
+            services: Vec::new(),
+            ..Default::default()
+        };
+        config.set_hash(content);
+        config.parse_services(content.to_string(), format, options)?;
+        Ok(config)
+    }
+
+    /// Build a config out of services obtained elsewhere, `content` being
+    /// whatever they were read from so that unchanged inputs hash the same.
+    pub fn from_services(services: ServicesList, content: &str) -> Config {
+        let mut config = Config {
+            services,
+            ..Default::default()
+        };
+        config.set_hash(content);
         config
     }
 
@@ -41,67 +512,1610 @@ impl Config {
         self.hash.clone()
     }
 
-    fn parse_json(&mut self, raw_config: std::string::String) {
-        let mut result: Vec<service::Service> = Vec::new();
-
-        // @TODO handle error properly here
-        let v: Vec<service::Service> = serde_json::from_str(raw_config.as_str()).unwrap();
-        for val in v {
-            log::debug!("Service with id='{}' added to the config pool", val.id);
-            result.push(val);
+    #[tracing::instrument(name = "validate", skip(self, raw_config, options))]
+    fn parse_services(
+        &mut self,
+        raw_config: std::string::String,
+        format: ServicesFormat,
+        options: ParseOptions,
+    ) -> Result<()> {
+        let placed = format
+            .parse_placed(raw_config.as_str(), options.strict)
+            .context("invalid services config")?;
+        let checked: Vec<_> = placed
+            .iter()
+            .map(|(path, service)| conflicts::Placed {
+                service,
+                file: None,
+                path: path.clone(),
+            })
+            .collect();
+        check_conflicts(&checked, options.host_conflicts).context("invalid services config")?;
+        for (_, service) in &placed {
+            tracing::debug!("Service with id='{}' added to the config pool", service.id);
         }
         // Update services.
-        self.services = result;
+        self.services = placed.into_iter().map(|(_, service)| service).collect();
+        Ok(())
     }
 
-    fn read_path(&self, path: &str) -> std::string::String {
-        let mut file = match File::open(path) {
-            Ok(file) => file,
-            Err(error) => panic!("There was a problem opening the file {}: {:?}", path, error),
-        };
+    // Merge the services files under `dir`, errors naming the file at fault.
+    fn parse_dir(&mut self, dir: &Path, options: ParseOptions) -> Result<()> {
+        let mut contents = std::string::String::new();
+        let mut placed = Vec::new();
+        for path in services_files(dir)? {
+            let name = path.display().to_string();
+            let raw_config = self.read_path(&name)?;
+            // moving a service to another file changes the config too
+            contents.push_str(&name);
+            contents.push('\n');
+            contents.push_str(&raw_config);
+
+            let format = options.format.unwrap_or_else(|| ServicesFormat::of(&path));
+            let services = format
+                .parse_placed(&raw_config, options.strict)
+                .with_context(|| format!("invalid services config {}", name))?;
+            placed.extend(
+                services
+                    .into_iter()
+                    .map(|(path, service)| (name.clone(), path, service)),
+            );
+        }
+        // a service id is only in one of the files, and so on
+        let checked: Vec<_> = placed
+            .iter()
+            .map(|(name, path, service)| conflicts::Placed {
+                service,
+                file: Some(name.clone()),
+                path: path.clone(),
+            })
+            .collect();
+        check_conflicts(&checked, options.host_conflicts)
+            .with_context(|| format!("invalid services config {}", dir.display()))?;
+        for (name, _, service) in placed {
+            tracing::debug!("Service with id='{}' added from {}", service.id, name);
+            self.services.push(service);
+        }
+        self.set_hash(&contents);
+        Ok(())
+    }
+
+    #[tracing::instrument(name = "fetch", skip(self))]
+    fn read_path(&self, path: &str) -> Result<std::string::String> {
+        let mut file = File::open(path)
+            .with_context(|| format!("There was a problem opening the file {}", path))?;
         let mut contents = String::new();
         file.read_to_string(&mut contents)
-            .expect("Error reading the file");
+            .with_context(|| format!("Error reading the file {}", path))?;
 
-        contents
+        Ok(contents)
     }
 
-    pub fn export_config_to_envoy(&self) -> EnvoyExportList {
-        let (exportlist, errorlist): (Vec<_>, Vec<_>) = self
+    /// Export the enabled services on up to `limit` threads, OIDC discovery
+    /// blocking on the issuers. Errors come in the order of the services.
+    /// The services exported out of the same as last time, settings and
+    /// filters included, get the resources they had then.
+    #[tracing::instrument(
+        name = "export_services",
+        skip(self, wasm, limit),
+        fields(services = self.services.len())
+    )]
+    pub fn export_concurrently(
+        &self,
+        wasm: &service::WasmSettings,
+        limit: usize,
+    ) -> (ServiceExports, Vec<(u32, anyhow::Error)>) {
+        // the exports of the workers still belong to the current span
+        let span = tracing::Span::current();
+        let enabled: Vec<_> = self
             .services
             .iter()
-            .map(service::Service::export)
-            .partition(|it| it.is_ok());
-
-        if !errorlist.is_empty() {
-            log::error!("Some data could not be exported");
-            errorlist
-                .into_iter()
-                .map(Result::unwrap_err)
-                .for_each(|err| {
+            .filter(|service| {
+                if !service.enabled {
+                    tracing::debug!(service.id = service.id, "Service disabled, not exported");
+                }
+                service.enabled
+            })
+            .collect();
+        // fetched once for every service, which all run it
+        if let Some(ref remote) = wasm.remote {
+            if let Err(e) = remote.fetch(&wasm.http_client) {
+                tracing::error!("Cannot fetch the services filter: {:#}", e);
+                let errors = enabled
+                    .iter()
+                    .map(|service| (service.id, anyhow!("{:#}", e)))
+                    .collect();
+                return (ServiceExports::new(), errors);
+            }
+        }
+        let digests = export_cache::digests(enabled.iter().copied(), wasm);
+        let results = util::concurrency::map_bounded(&enabled, limit, |service| {
+            // a panic of an export fails that service only
+            let exported = panics::catch("export", || {
+                span.in_scope(|| self.export_cache.export(service, wasm, &digests))
+            });
+            exported.unwrap_or_else(|e| (Err(e), false))
+        });
+        let reused = results.iter().filter(|(_, reused)| *reused).count();
+        tracing::debug!(
+            services = enabled.len(),
+            reused,
+            "Services exported, reusing the exports of those unchanged"
+        );
+        let results: Vec<_> = results.into_iter().map(|(result, _)| result).collect();
+        let ids: HashSet<_> = enabled.iter().map(|service| service.id).collect();
+        self.export_cache.retain(|id| ids.contains(&id));
+        let mut exports = ServiceExports::new();
+        let mut errors = Vec::new();
+        // the resources of a tenant are only ever served along with those
+        // of its other services
+        let mut claimed: HashMap<&str, Claimed> = HashMap::new();
+        for (service, result) in enabled.iter().zip(results) {
+            let tenant = claimed.entry(service.tenant.as_str()).or_default();
+            let result =
+                result.and_then(|service_exports| claim(tenant, service.id, service_exports));
+            // the exports of a service would replace those of another with
+            // its id, which the checks of the sources leave out
+            let result = result.and_then(|service_exports| {
+                if exports.contains_key(&service.id) {
+                    Err(anyhow!("another service has the id {}", service.id))
+                } else {
+                    Ok(service_exports)
+                }
+            });
+            match result {
+                Ok(service_exports) => {
+                    exports.insert(service.id, service_exports);
+                }
+                Err(err) => {
                     // Print extended error information with causes
                     // Will also print backtrace if enabled via envvar on nightly
-                    log::error!("-> {:?}", err);
-                });
+                    // with every cause, as the context of each step narrows
+                    // down what failed
+                    tracing::error!(
+                        service.id = service.id,
+                        "Service could not be exported: {:#}",
+                        err
+                    );
+                    errors.push((service.id, err));
+                }
+            }
         }
+        (exports, errors)
+    }
 
-        let mut result = Vec::with_capacity(exportlist.len());
-        result.extend(exportlist.into_iter().flat_map(Result::unwrap));
+    /// Commit of the repository the services were read out of, if any.
+    pub fn revision(&self) -> Option<&str> {
+        self.revision.as_deref()
+    }
 
-        result
+    #[cfg(feature = "git-source")]
+    pub fn set_revision(&mut self, revision: std::string::String) {
+        self.revision = Some(revision);
     }
 
     pub fn get_version(&self) -> u32 {
         self.version
     }
 
+    pub fn set_wasm(&mut self, wasm: service::WasmSettings) {
+        self.wasm = wasm;
+    }
+
+    pub fn wasm(&self) -> &service::WasmSettings {
+        &self.wasm
+    }
+
+    pub fn set_export_concurrency(&mut self, limit: usize) {
+        self.export_concurrency = limit;
+    }
+
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
     pub fn get_services(&self) -> ServicesList {
         self.services.clone()
     }
 
-    pub fn import(&mut self, services: ServicesList, hash: std::string::String) {
+    /// The resources last exported from service `id`, served or held back.
+    pub fn service_exports(&self, id: u32) -> Option<&[EnvoyExport]> {
+        self.exports.get(&id).map(|exports| &exports[..])
+    }
+
+    /// The snapshot of the default node group.
+    #[cfg(test)]
+    pub fn get_snapshot(&self) -> Arc<Snapshot> {
+        self.group_snapshot(DEFAULT_NODE_GROUP)
+    }
+
+    /// The snapshot served to the nodes of `group`, empty for a group no
+    /// service is in.
+    pub fn group_snapshot(&self, group: &str) -> Arc<Snapshot> {
+        self.snapshots.get(group).cloned().unwrap_or_default()
+    }
+
+    /// Serve `snapshot` to the nodes of `group` as is, under a new version.
+    #[cfg(test)]
+    pub fn set_group_snapshot(&mut self, group: &str, snapshot: Arc<Snapshot>) {
+        self.snapshots.insert(group.to_string(), snapshot);
+        self.version += 1;
+        self.versions.publish(self.version);
+    }
+
+    pub fn versions(&self) -> &Versions {
+        &self.versions
+    }
+
+    pub fn group_snapshots(&self) -> &BTreeMap<std::string::String, Arc<Snapshot>> {
+        &self.snapshots
+    }
+
+    /// The snapshot key of an Envoy node: the group named in its metadata,
+    /// or else the prefix of its id up to the first dot when services are
+    /// in a group by that name, as in `internal.envoy-1`, of the tenant its
+    /// metadata names, if any.
+    pub fn node_group(&self, node: Option<&Node>) -> std::string::String {
+        let node = match node {
+            Some(node) => node,
+            None => return DEFAULT_NODE_GROUP.to_string(),
+        };
+        // the nodes naming what no service can be get the default snapshot
+        // of their tenant, rather than that of another one
+        let tenant = metadata_string(node, TENANT_METADATA)
+            .filter(|tenant| {
+                let valid = service::check_tenant(tenant).is_ok();
+                if !valid {
+                    tracing::warn!(node = %node.id, tenant = %tenant, "Invalid tenant of a node");
+                }
+                valid
+            })
+            .unwrap_or_else(|| service::DEFAULT_TENANT.to_string());
+        let metadata = metadata_string(node, NODE_GROUP_METADATA).filter(|group| {
+            if group.contains('/') {
+                tracing::warn!(node = %node.id, node_group = %group, "Invalid node group of a node");
+            }
+            !group.contains('/')
+        });
+        if let Some(group) = metadata {
+            return snapshot_key(&tenant, &group);
+        }
+        match node.id.split('.').next() {
+            Some(prefix) if prefix != node.id => {
+                let key = snapshot_key(&tenant, prefix);
+                if self.snapshots.contains_key(&key) {
+                    return key;
+                }
+                snapshot_key(&tenant, DEFAULT_NODE_GROUP)
+            }
+            _ => snapshot_key(&tenant, DEFAULT_NODE_GROUP),
+        }
+    }
+
+    // Record the services of the config of `hash` that failed to export,
+    // failing when strict, the filters of `services` left out, and those
+    // with rules beyond their virtual clusters.
+    fn check_exports(
+        &mut self,
+        hash: &str,
+        services: &[service::Service],
+        errors: &[(u32, anyhow::Error)],
+    ) -> Result<()> {
+        self.missing_filters = self.wasm.missing_filters(services);
+        if !self.missing_filters.is_empty() {
+            tracing::warn!(
+                "Serving the services without their wasm filters, missing: {}",
+                self.missing_filters
+                    .iter()
+                    .map(|path| path.display().to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+        let max = self.wasm.virtual_clusters;
+        let too_many_rules: BTreeSet<_> = services
+            .iter()
+            .filter(|service| service.enabled && service.has_rules_beyond(max))
+            .map(|service| (service.id, service.proxy_rules.len()))
+            .collect();
+        for (id, rules) in too_many_rules.difference(&self.too_many_rules) {
+            tracing::warn!(
+                service.id = id,
+                rules,
+                max,
+                "Too many mapping rules for a virtual cluster each, the last ones have none"
+            );
+        }
+        // the services gone are warned about again if they come back
+        self.too_many_rules = too_many_rules;
+        let retries: HashMap<u32, u32> = match self.failures_hash == hash {
+            true => self
+                .export_failures
+                .iter()
+                .map(|failure| (failure.id, failure.retries + 1))
+                .collect(),
+            false => HashMap::new(),
+        };
+        self.failures_hash = hash.to_string();
+        self.export_failures = errors
+            .iter()
+            .map(|(id, error)| {
+                let retries = retries.get(id).copied().unwrap_or_default();
+                ExportFailure::retried(*id, error, retries)
+            })
+            .collect();
+        if self.strict && !errors.is_empty() {
+            let ids: Vec<_> = errors.iter().map(|(id, _)| id.to_string()).collect();
+            let error = anyhow!(
+                "services {} failed to export, strict exports keep the current version",
+                ids.join(", ")
+            );
+            self.reload_error = Some(format!("{:#}", error));
+            return Err(error);
+        }
+        Ok(())
+    }
+
+    pub fn readiness(&self) -> Readiness {
+        let error = match self.reload_error {
+            Some(ref error) => Some(error.clone()),
+            None if !self.export_failures.is_empty() => Some(format!(
+                "failed to export {} services: {}",
+                self.export_failures.len(),
+                self.export_failures
+                    .iter()
+                    .map(|failure| format!("{}: {}", failure.id, failure.errors.join(": ")))
+                    .collect::<Vec<_>>()
+                    .join("; ")
+            )),
+            None if !self.missing_filters.is_empty() => Some(format!(
+                "serving without the missing wasm filters {}",
+                self.missing_filters
+                    .iter()
+                    .map(|path| path.display().to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )),
+            None => None,
+        };
+        match (self.exported, error) {
+            (false, error) => Readiness::NotReady(
+                error.unwrap_or_else(|| "no snapshot published yet".to_string()),
+            ),
+            (true, Some(error)) => Readiness::Degraded(error),
+            (true, None) => Readiness::Ready,
+        }
+    }
+
+    /// Roll back the services whose resources Envoy rejects, see `on_nack`.
+    pub fn enable_rollback(&mut self) {
+        self.rollback.get_or_insert_with(Rollback::default);
+    }
+
+    /// The services of the last config exported that failed to, cleared by
+    /// a config they all export from.
+    pub fn export_failures(&self) -> &[ExportFailure] {
+        &self.export_failures
+    }
+
+    /// The filters of the services left out for missing.
+    pub fn missing_filters(&self) -> &BTreeSet<PathBuf> {
+        &self.missing_filters
+    }
+
+    /// Whether a failed service is due to be exported again. Strict configs
+    /// failing are not imported, they are retried as a whole.
+    pub fn retry_due(&self, now: Instant) -> bool {
+        self.export_failures
+            .iter()
+            .any(|failure| failure.next_retry <= now)
+    }
+
+    pub fn last_diff(&self) -> Option<&ConfigDiff> {
+        self.last_diff.as_ref()
+    }
+
+    pub fn publications(&self) -> PublisherStats {
+        self.publications
+    }
+
+    pub fn set_publications(&mut self, stats: PublisherStats) {
+        self.publications = stats;
+    }
+
+    /// Services held back after Envoy rejected them.
+    pub fn quarantined(&self) -> BTreeMap<u32, Quarantine> {
+        self.rollback
+            .as_ref()
+            .map(|rollback| rollback.quarantined().clone())
+            .unwrap_or_default()
+    }
+
+    /// Take over a new set of services and their resources. The version is
+    /// only bumped, and `true` returned, when the resources differ from the
+    /// ones being served.
+    pub fn import(
+        &mut self,
+        services: ServicesList,
+        hash: std::string::String,
+        exports: ServiceExports,
+    ) -> bool {
+        let diffs = diff::services(&self.services, &services);
         self.services = services;
         self.hash = hash;
-        self.version += 1;
+        self.exports = exports;
+        if let Some(ref mut rollback) = self.rollback {
+            rollback.retry();
+        }
+        if !self.update_snapshot() {
+            return false;
+        }
+        for diff in &diffs {
+            tracing::info!(service.id = diff.id, "{}", diff.summary());
+            for detail in &diff.details {
+                tracing::debug!(service.id = diff.id, "{}", detail);
+            }
+        }
+        self.last_diff = Some(ConfigDiff {
+            version: self.version,
+            services: diffs,
+        });
+        true
+    }
+
+    // Rebuild the snapshot of every node group, only bumping the version of
+    // groups whose resources changed.
+    fn update_snapshot(&mut self) -> bool {
+        let (exports, held_back) = match self.rollback {
+            Some(ref rollback) => rollback.hold_back(&self.exports),
+            None => (self.exports.clone(), Default::default()),
+        };
+
+        // groups that lost all their services are emptied
+        let mut groups: BTreeMap<std::string::String, ServiceExports> = self
+            .snapshots
+            .keys()
+            .map(|group| (group.clone(), ServiceExports::new()))
+            .collect();
+        groups.entry(DEFAULT_NODE_GROUP.to_string()).or_default();
+        // the groups of every tenant are apart from those of the others,
+        // the resources the services of several tenants share being in
+        // the snapshots of each
+        // and those of a service taken by its id are those of the first
+        // service of the id, as exported
+        let mut service_groups: HashMap<u32, Vec<std::string::String>> = HashMap::new();
+        for service in self.services.iter().filter(|service| service.enabled) {
+            service_groups
+                .entry(service.id)
+                .or_insert_with(|| match service.node_groups.len() {
+                    0 => vec![snapshot_key(&service.tenant, DEFAULT_NODE_GROUP)],
+                    _ => service
+                        .node_groups
+                        .iter()
+                        .map(|group| snapshot_key(&service.tenant, group))
+                        .collect(),
+                });
+        }
+        for (id, service_exports) in exports {
+            match service_groups.get(&id) {
+                Some(keys) => {
+                    for key in keys {
+                        groups
+                            .entry(key.clone())
+                            .or_default()
+                            .insert(id, service_exports.clone());
+                    }
+                }
+                None => {
+                    groups
+                        .entry(DEFAULT_NODE_GROUP.to_string())
+                        .or_default()
+                        .insert(id, service_exports);
+                }
+            }
+        }
+
+        let mut updated = false;
+        for (name, exports) in groups {
+            let mut resources: Vec<_> = exports
+                .values()
+                .flat_map(|exports| exports.iter())
+                .collect();
+            // stable, for the resources services share to keep the order of
+            // the services
+            resources.sort_by(|a, b| a.key.cmp(&b.key));
+            let current = self.snapshots.entry(name.clone()).or_default();
+            let snapshot = Snapshot::new(current.version() + 1, resources).following(current);
+            if current.version() > 0 && snapshot.hash() == current.hash() {
+                continue;
+            }
+            tracing::debug!(
+                node_group = %name,
+                version = snapshot.version(),
+                hash = snapshot.hash(),
+                removed = snapshot.removed_count(),
+                "Node group snapshot updated"
+            );
+            self.propagation.published(&name, &snapshot, current);
+            *current = Arc::new(snapshot);
+            updated = true;
+            if let Some(ref mut rollback) = self.rollback {
+                rollback.published(Arc::clone(current), exports, held_back.clone());
+            }
+        }
+        if updated {
+            self.version += 1;
+            self.versions.publish(self.version);
+        }
+        updated
+    }
+
+    /// Handle `node` rejecting `type_url` resources while still running the
+    /// `running` version of them: the services that changed in between are
+    /// quarantined and the resources it accepted published again. Returns
+    /// whether a new version was published.
+    pub fn on_nack(&mut self, node: &str, type_url: &str, nack: &Nack, running: &str) -> bool {
+        let quarantined = match self.rollback {
+            Some(ref mut rollback) => rollback.on_nack(node, type_url, nack, running),
+            None => false,
+        };
+        if !quarantined || !self.update_snapshot() {
+            return false;
+        }
+        tracing::info!(
+            type_url,
+            running,
+            version = self.version,
+            "Rolled back to the version the node runs"
+        );
+        true
+    }
+
+    pub fn on_ack(&mut self, node_group: &str, node: &str, type_url: &str, version: &str) {
+        if let Some(ref mut rollback) = self.rollback {
+            rollback.on_ack(type_url, version);
+        }
+        self.propagation.acked(node_group, node, type_url, version);
+    }
+}
+
+/// Publish `new_config` as the next snapshot unless its content, or the
+/// resources exported from it, are the ones already being served. Returns
+/// whether a new version was published. The services that fail to export are
+/// left out, unless strict where the config fails as a whole.
+#[tracing::instrument(
+    skip(shared, new_config),
+    fields(hash = %new_config.get_hash(), version = tracing::field::Empty)
+)]
+pub fn publish(shared: &RwLock<Config>, mut new_config: Config) -> Result<bool> {
+    if new_config.get_hash() == shared.read().unwrap().get_hash() {
+        // back to the content being served
+        let mut config = shared.write().unwrap();
+        config.reload_error = None;
+        // the same services, at another commit
+        config.revision = new_config.revision;
+        tracing::info!(version = config.get_version(), "Config unchanged");
+        return Ok(false);
+    }
+
+    // Export outside of the lock, it may reach out to OIDC issuers.
+    let (wasm, limit) = {
+        let config = shared.read().unwrap();
+        new_config.export_cache = config.export_cache.clone();
+        (config.wasm.clone(), config.export_concurrency)
+    };
+    let (resources, errors) = new_config.export_concurrently(&wasm, limit);
+    let mut config = shared.write().unwrap();
+    let hash = new_config.get_hash();
+    if let Err(e) = config.check_exports(&hash, &new_config.services, &errors) {
+        config.refused = Some(Box::new(new_config));
+        return Err(e);
+    }
+    config.refused = None;
+    let updated = config.import(new_config.get_services(), hash, resources);
+    config.exported |= errors.is_empty();
+    config.reload_error = None;
+    config.revision = new_config.revision;
+    if updated {
+        tracing::Span::current().record("version", &config.get_version());
+        tracing::info!(version = config.get_version(), "Config updated");
+    } else {
+        tracing::info!(
+            version = config.get_version(),
+            "Config reloaded without changes"
+        );
+    }
+    Ok(updated)
+}
+
+/// Export the services being served once more, for the files they refer
+/// to, like certificates, to be read again. Only the resources whose content
+/// changed get new versions. Returns whether a new version was published.
+#[tracing::instrument(skip(shared))]
+pub fn reexport(shared: &RwLock<Config>) -> Result<bool> {
+    export_again(shared, true)
+}
+
+/// Export once more the services whose filters changed, the others keeping
+/// their exports, the digests of the filters being part of what they were
+/// exported out of. Returns whether a new version was published.
+#[tracing::instrument(skip(shared))]
+pub fn reexport_filters(shared: &RwLock<Config>) -> Result<bool> {
+    export_again(shared, false)
+}
+
+fn export_again(shared: &RwLock<Config>, everything: bool) -> Result<bool> {
+    let (services, hash, wasm, limit) = {
+        let config = shared.read().unwrap();
+        let mut services = Config::from_services(config.get_services(), "");
+        if everything {
+            // the files are read again by exporting every service anew
+            config.export_cache.clear();
+        }
+        services.export_cache = config.export_cache.clone();
+        (
+            services,
+            config.get_hash(),
+            config.wasm.clone(),
+            config.export_concurrency,
+        )
+    };
+    let (resources, errors) = services.export_concurrently(&wasm, limit);
+    let mut config = shared.write().unwrap();
+    if config.get_hash() != hash {
+        // reloaded meanwhile, with the files read already
+        return Ok(false);
+    }
+    config.check_exports(&hash, &services.services, &errors)?;
+    let updated = config.import(services.get_services(), hash, resources);
+    config.exported |= errors.is_empty();
+    if updated {
+        tracing::info!(version = config.get_version(), "Config updated");
+    }
+    Ok(updated)
+}
+
+/// Export again the services that failed to and are due to be retried, like
+/// those of an OIDC issuer that was down, merging those that export now into
+/// the snapshot. The other services keep their exports, and those still
+/// failing are retried later. Returns whether a new version was published.
+#[tracing::instrument(skip(shared), fields(services = tracing::field::Empty))]
+pub fn retry_failed(shared: &RwLock<Config>) -> Result<bool> {
+    let now = Instant::now();
+    let (due, hash, wasm, limit) = {
+        let config = shared.read().unwrap();
+        if !config.retry_due(now) {
+            return Ok(false);
+        }
+        if config.strict {
+            // the config refused, or else the one served failing to export
+            // again, as a whole
+            let refused = config.refused.clone();
+            drop(config);
+            return match refused {
+                Some(refused) => publish(shared, *refused),
+                None => reexport_filters(shared),
+            };
+        }
+        let retries: HashMap<u32, u32> = config
+            .export_failures
+            .iter()
+            .filter(|failure| failure.next_retry <= now)
+            .map(|failure| (failure.id, failure.retries))
+            .collect();
+        let services = config
+            .services
+            .iter()
+            .filter(|service| retries.contains_key(&service.id))
+            .cloned()
+            .collect();
+        // without the export cache, which forgets the services it isn't
+        // told of, the failed ones having no exports to reuse anyway
+        let due = Config::from_services(services, "");
+        (
+            (due, retries),
+            config.get_hash(),
+            config.wasm.clone(),
+            config.export_concurrency,
+        )
+    };
+    let (due, retries) = due;
+    tracing::Span::current().record("services", &due.services.len());
+    let (resources, errors) = due.export_concurrently(&wasm, limit);
+
+    let mut config = shared.write().unwrap();
+    if config.get_hash() != hash {
+        // reloaded meanwhile, with failures of its own
+        return Ok(false);
+    }
+    // named like the resources of the services being served, they would
+    // replace them
+    let tenants: HashMap<u32, std::string::String> = config
+        .services
+        .iter()
+        .map(|service| (service.id, service.tenant.clone()))
+        .collect();
+    let tenant = |id: &u32| tenants.get(id).cloned().unwrap_or_default();
+    let mut claimed: HashMap<std::string::String, Claimed> = HashMap::new();
+    for (id, exports) in &config.exports {
+        claim(claimed.entry(tenant(id)).or_default(), *id, exports.clone())?;
+    }
+    let mut exports = config.exports.clone();
+    let mut failures: Vec<_> = config
+        .export_failures
+        .iter()
+        .filter(|failure| !retries.contains_key(&failure.id))
+        .cloned()
+        .collect();
+    for (id, error) in &errors {
+        failures.push(ExportFailure::retried(*id, error, retries[id] + 1));
+    }
+    for (id, resources) in resources {
+        match claim(claimed.entry(tenant(&id)).or_default(), id, resources) {
+            Ok(resources) => {
+                tracing::info!(service.id = id, "Service exported on retry");
+                exports.insert(id, resources);
+            }
+            Err(e) => failures.push(ExportFailure::retried(id, &e, retries[&id] + 1)),
+        }
+    }
+    failures.sort_by_key(|failure| failure.id);
+    config.export_failures = failures;
+    config.exports = exports;
+    config.exported |= config.export_failures.is_empty();
+    let updated = config.update_snapshot();
+    if updated {
+        tracing::info!(version = config.get_version(), "Config updated");
+    }
+    Ok(updated)
+}
+
+/// Record that loading the services failed, while the current snapshot
+/// keeps being served.
+pub fn reload_failed(shared: &RwLock<Config>, error: &anyhow::Error) {
+    shared.write().unwrap().reload_error = Some(format!("{:#}", error));
+}
+
+/// The services being served are known to be up to date again, without
+/// loading them once more.
+pub fn reload_recovered(shared: &RwLock<Config>) {
+    shared.write().unwrap().reload_error = None;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::envoy_helpers::{
+        get_envoy_cluster, EnvoyExport, EnvoyResource, CLUSTER_TYPE_URL, LISTENER_TYPE_URL,
+    };
+    use crate::protobuf::envoy::config::core::v3::Node;
+
+    fn exports(url: &str) -> ServiceExports {
+        let export = EnvoyExport {
+            key: "service::id::1::cluster".to_string(),
+            config: EnvoyResource::Cluster(
+                get_envoy_cluster("Cluster::service::1".to_string(), url, &Default::default())
+                    .unwrap(),
+            ),
+        };
+        vec![(1, vec![export].into())].into_iter().collect()
+    }
+
+    #[test]
+    fn identical_exports_keep_the_version() {
+        let mut config = Config::default();
+        assert!(config.import(Vec::new(), "a".to_string(), exports("http://one:80")));
+        let hash = config.get_snapshot().hash().to_string();
+
+        // a reformatted config file exporting the same resources
+        assert!(!config.import(Vec::new(), "b".to_string(), exports("http://one:80")));
+        assert_eq!(config.get_version(), 1);
+        assert_eq!(config.get_snapshot().hash(), hash);
+        assert_eq!(config.get_hash(), "b");
+
+        assert!(config.import(Vec::new(), "c".to_string(), exports("http://one:81")));
+        assert_eq!(config.get_version(), 2);
+        assert_eq!(config.last_diff().unwrap().version, 2);
+    }
+
+    fn grouped(groups: &[(u32, &[&str])]) -> (ServicesList, ServiceExports) {
+        let services = groups
+            .iter()
+            .map(|(id, groups)| {
+                serde_json::from_value(serde_json::json!({
+                    "id": id,
+                    "hosts": [],
+                    "policies": [],
+                    "target_domain": "http://one:80",
+                    "proxy_rules": [],
+                    "node_groups": groups,
+                }))
+                .unwrap()
+            })
+            .collect();
+        let exports = groups
+            .iter()
+            .map(|(id, _)| {
+                let export = EnvoyExport {
+                    key: format!("service::id::{}::cluster", id),
+                    config: EnvoyResource::Cluster(
+                        get_envoy_cluster(
+                            format!("Cluster::service::{}", id),
+                            "http://one:80",
+                            &Default::default(),
+                        )
+                        .unwrap(),
+                    ),
+                };
+                (*id, vec![export].into())
+            })
+            .collect();
+        (services, exports)
+    }
+
+    fn versions(config: &Config) -> Vec<(&str, u32)> {
+        config
+            .group_snapshots()
+            .iter()
+            .map(|(group, snapshot)| (group.as_str(), snapshot.version()))
+            .collect()
+    }
+
+    #[test]
+    fn membership_changes_only_bump_affected_groups() {
+        let mut config = Config::default();
+        let (services, exports) = grouped(&[(1, &["public"]), (2, &["internal"]), (3, &[])]);
+        config.import(services, "a".to_string(), exports);
+        assert_eq!(
+            versions(&config),
+            [("default", 1), ("internal", 1), ("public", 1)]
+        );
+
+        // 3 joins the internal group
+        let (services, exports) =
+            grouped(&[(1, &["public"]), (2, &["internal"]), (3, &["internal"])]);
+        config.import(services, "b".to_string(), exports);
+        assert_eq!(
+            versions(&config),
+            [("default", 2), ("internal", 2), ("public", 1)]
+        );
+        assert!(config
+            .get_snapshot()
+            .resources(crate::envoy_helpers::CLUSTER_TYPE_URL)
+            .is_none());
+    }
+
+    #[test]
+    fn nodes_are_grouped_by_metadata_or_id_prefix() {
+        let mut config = Config::default();
+        let (services, exports) = grouped(&[(1, &["public"])]);
+        config.import(services, "a".to_string(), exports);
+
+        let node = |id: &str, group: Option<&str>| Node {
+            id: id.to_string(),
+            metadata: group.map(|group| {
+                let value = prost_types::Value {
+                    kind: Some(prost_types::value::Kind::StringValue(group.to_string())),
+                };
+                prost_types::Struct {
+                    fields: vec![(NODE_GROUP_METADATA.to_string(), value)]
+                        .into_iter()
+                        .collect(),
+                }
+            }),
+            ..Default::default()
+        };
+        assert_eq!(
+            config.node_group(Some(&node("envoy-1", Some("internal")))),
+            "internal"
+        );
+        assert_eq!(
+            config.node_group(Some(&node("public.envoy-1", None))),
+            "public"
+        );
+        // only prefixes naming a group count
+        assert_eq!(
+            config.node_group(Some(&node("envoy.local", None))),
+            "default"
+        );
+        assert_eq!(config.node_group(Some(&node("public", None))), "default");
+        assert_eq!(config.node_group(None), "default");
+    }
+
+    #[test]
+    fn concurrent_exports_are_deterministic() {
+        let services: Vec<_> = (1..=20)
+            .map(|id| {
+                serde_json::json!({
+                    "id": id,
+                    "hosts": [format!("{}.app", id)],
+                    "policies": [],
+                    "target_domain": format!("http://{}.app:80", id),
+                    "proxy_rules": [
+                        {"pattern": "/", "http_method": "GET", "metric_system_name": "hits", "delta": id}
+                    ],
+                })
+            })
+            .collect();
+        let content = serde_json::to_string(&services).unwrap();
+        let config = Config::from_services(serde_json::from_str(&content).unwrap(), &content);
+        let wasm = service::WasmSettings {
+            skip_sha: true,
+            ..Default::default()
+        };
+        let encoded = |limit| {
+            let (exports, errors) = config.export_concurrently(&wasm, limit);
+            assert!(errors.is_empty());
+            exports
+                .values()
+                .flat_map(|exports| exports.iter())
+                .map(|export| (export.key.clone(), export.config.to_any().unwrap().value))
+                .collect::<Vec<_>>()
+        };
+
+        let sequential = encoded(1);
+        assert_eq!(sequential.len(), 40);
+        assert_eq!(encoded(4), sequential);
+        assert_eq!(encoded(16), sequential);
+    }
+
+    #[test]
+    fn exporting_twice_gives_the_same_bytes() {
+        let services: Vec<_> = (1..=200)
+            .map(|id| {
+                serde_json::json!({
+                    "id": id,
+                    "hosts": [format!("{}.app", id), format!("www.{}.app", id)],
+                    "policies": [
+                        "cors",
+                        {
+                            "name": "routing",
+                            "configuration": {
+                                "upstreams": {"v2": format!("http://v2.{}.app:8080", id)},
+                                "rules": [{"upstream": "v2", "path_prefix": "/v2/"}],
+                            },
+                        },
+                    ],
+                    "target_domain": format!("http://{}.app:80", id),
+                    "proxy_rules": [
+                        {"pattern": "/", "http_method": "GET", "metric_system_name": "hits", "delta": 1},
+                        {"pattern": "/{id}", "http_method": "PUT", "metric_system_name": "updates", "delta": 2}
+                    ],
+                    "auth_config": {
+                        "path": "static/threescale_wasm_auth.wasm",
+                        "wasm_config": {
+                            "backend": {
+                                "cluster_name": format!("backend_{}", id % 3),
+                                "url": "https://backend.app/",
+                                "timeout": 5,
+                                "extensions": ["no_body"],
+                            },
+                            "services": [{"id": id.to_string(), "token": "secret"}],
+                            "system": {"name": "system", "token": "token"},
+                        },
+                    },
+                })
+            })
+            .collect();
+        let content = serde_json::to_string(&services).unwrap();
+        let wasm = service::WasmSettings {
+            skip_sha: true,
+            ..Default::default()
+        };
+        // each config exports afresh, not sharing its exports
+        let encoded = || {
+            let config = Config::from_services(serde_json::from_str(&content).unwrap(), &content);
+            let (exports, errors) = config.export_concurrently(&wasm, 8);
+            assert!(errors.is_empty(), "{:?}", errors);
+            exports
+                .values()
+                .flat_map(|exports| exports.iter())
+                .map(|export| (export.key.clone(), export.config.to_any().unwrap().value))
+                .collect::<Vec<_>>()
+        };
+
+        let first = encoded();
+        assert_eq!(first.len(), 800);
+        assert!(first == encoded());
+    }
+
+    #[test]
+    fn services_of_the_same_id_are_exported_once() {
+        let services = serde_json::json!([
+            {"id": 7, "hosts": ["one.app"], "policies": [], "target_domain": "http://one:80", "proxy_rules": []},
+            {"id": 7, "hosts": ["two.app"], "policies": [], "target_domain": "http://two:80", "proxy_rules": []},
+        ]);
+        let content = services.to_string();
+        let config = Config::from_services(serde_json::from_value(services).unwrap(), &content);
+        let wasm = service::WasmSettings {
+            skip_sha: true,
+            ..Default::default()
+        };
+        let encoded = |config: &Config| {
+            let (exports, errors) = config.export_concurrently(&wasm, 2);
+            let encoded: Vec<_> = exports
+                .values()
+                .flat_map(|exports| exports.iter())
+                .map(|export| export.config.to_any().unwrap().value)
+                .collect();
+            (encoded, errors)
+        };
+        let (exported, errors) = encoded(&config);
+        // those of the first service
+        let first = Config::from_services(vec![config.get_services()[0].clone()], &content);
+        assert!(exported == encoded(&first).0);
+        let errors: Vec<_> = errors.iter().map(|(id, e)| (*id, e.to_string())).collect();
+        assert_eq!(errors, [(7, "another service has the id 7".to_string())]);
+    }
+
+    fn three_services(broken: bool) -> Config {
+        let services: Vec<_> = (1..=3)
+            .map(|id| {
+                let target = if broken && id == 2 {
+                    "not a url".to_string()
+                } else {
+                    format!("http://{}.app:80", id)
+                };
+                serde_json::json!({
+                    "id": id,
+                    "hosts": [format!("{}.app", id)],
+                    "policies": [],
+                    "target_domain": target,
+                    "proxy_rules": [],
+                })
+            })
+            .collect();
+        let content = serde_json::to_string(&services).unwrap();
+        Config::from_services(serde_json::from_str(&content).unwrap(), &content)
+    }
+
+    fn shared(strict: bool) -> RwLock<Config> {
+        let mut config = Config::default();
+        config.set_wasm(service::WasmSettings {
+            skip_sha: true,
+            ..Default::default()
+        });
+        config.set_strict(strict);
+        RwLock::new(config)
+    }
+
+    fn clusters(config: &RwLock<Config>) -> Vec<std::string::String> {
+        config
+            .read()
+            .unwrap()
+            .get_snapshot()
+            .resources(CLUSTER_TYPE_URL)
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect()
+    }
+
+    #[test]
+    fn broken_services_are_left_out_and_reported() {
+        let config = shared(false);
+        assert!(publish(&config, three_services(true)).unwrap());
+        assert_eq!(
+            clusters(&config),
+            ["Cluster::service::1", "Cluster::service::3"]
+        );
+        {
+            let config = config.read().unwrap();
+            let failures = config.export_failures();
+            assert_eq!(failures.len(), 1);
+            assert_eq!(failures[0].id, 2);
+            assert!(failures[0].errors.len() > 1, "{:?}", failures[0]);
+            assert!(matches!(config.readiness(), Readiness::NotReady(_)));
+        }
+
+        // fixed
+        assert!(publish(&config, three_services(false)).unwrap());
+        assert_eq!(clusters(&config).len(), 3);
+        assert!(config.read().unwrap().export_failures().is_empty());
+        assert_eq!(config.read().unwrap().readiness(), Readiness::Ready);
+    }
+
+    #[test]
+    fn disabled_services_leave_the_snapshot() {
+        let with_second = |enabled: bool| {
+            let mut services = three_services(false).get_services();
+            services[1].enabled = enabled;
+            Config::from_services(services, &format!("second enabled: {}", enabled))
+        };
+        let resources = |config: &RwLock<Config>, type_url| -> Vec<std::string::String> {
+            let snapshot = config.read().unwrap().get_snapshot();
+            snapshot
+                .resources(type_url)
+                .unwrap()
+                .keys()
+                .cloned()
+                .collect()
+        };
+        let config = shared(false);
+        assert!(publish(&config, with_second(true)).unwrap());
+        let listeners = resources(&config, LISTENER_TYPE_URL);
+        let enabled = config.read().unwrap().get_snapshot();
+
+        assert!(publish(&config, with_second(false)).unwrap());
+        assert_eq!(
+            clusters(&config),
+            ["Cluster::service::1", "Cluster::service::3"]
+        );
+        let disabled = config.read().unwrap().get_snapshot();
+        let removed = &disabled.removed()[CLUSTER_TYPE_URL];
+        assert_eq!(removed.iter().collect::<Vec<_>>(), ["Cluster::service::2"]);
+        let removed = &disabled.removed()[LISTENER_TYPE_URL];
+        assert_eq!(removed.len(), 1);
+        assert!(!resources(&config, LISTENER_TYPE_URL).contains(removed.iter().next().unwrap()));
+        // still checked, and served by the admin API
+        assert_eq!(config.read().unwrap().get_services().len(), 3);
+
+        assert!(publish(&config, with_second(true)).unwrap());
+        assert_eq!(clusters(&config).len(), 3);
+        assert_eq!(resources(&config, LISTENER_TYPE_URL), listeners);
+        let reenabled = config.read().unwrap().get_snapshot();
+        assert!(reenabled.removed().is_empty());
+        assert!(reenabled.version() > disabled.version());
+        assert_ne!(
+            reenabled.type_version(CLUSTER_TYPE_URL),
+            disabled.type_version(CLUSTER_TYPE_URL)
+        );
+        assert_eq!(
+            reenabled.type_version(CLUSTER_TYPE_URL),
+            enabled.type_version(CLUSTER_TYPE_URL)
+        );
+    }
+
+    #[test]
+    fn shared_clusters_leave_with_their_last_service() {
+        let issuer = crate::oidc::serve_issuer();
+        let oidc_cluster = format!("OIDC::{}", issuer);
+        let with_oidc = |ids: &[u32]| {
+            let services: Vec<_> = three_services(false)
+                .get_services()
+                .into_iter()
+                .filter(|service| ids.contains(&service.id))
+                .map(|mut service| {
+                    if service.id != 3 {
+                        service.oidc_issuer = Some(format!("{}/auth/realms/app", issuer));
+                    }
+                    service
+                })
+                .collect();
+            Config::from_services(services, &format!("services {:?}", ids))
+        };
+        let references = |config: &RwLock<Config>| {
+            let snapshot = config.read().unwrap().get_snapshot();
+            snapshot.resources(CLUSTER_TYPE_URL).unwrap()[&oidc_cluster].references
+        };
+        let config = shared(false);
+        assert!(publish(&config, with_oidc(&[1, 2, 3])).unwrap());
+        assert_eq!(references(&config), 2);
+
+        assert!(publish(&config, with_oidc(&[2, 3])).unwrap());
+        assert!(clusters(&config).contains(&oidc_cluster));
+        assert_eq!(references(&config), 1);
+        let snapshot = config.read().unwrap().get_snapshot();
+        let removed = &snapshot.removed()[CLUSTER_TYPE_URL];
+        assert!(!removed.contains(&oidc_cluster), "{:?}", removed);
+
+        assert!(publish(&config, with_oidc(&[3])).unwrap());
+        assert!(!clusters(&config).contains(&oidc_cluster));
+        let snapshot = config.read().unwrap().get_snapshot();
+        assert!(snapshot.removed()[CLUSTER_TYPE_URL].contains(&oidc_cluster));
+    }
+
+    #[test]
+    fn renamed_services_rename_their_resources() {
+        let named = |name: Option<&str>| {
+            let mut services = three_services(false).get_services();
+            services[1].name = name.map(str::to_string);
+            Config::from_services(services, &format!("second named {:?}", name))
+        };
+        let config = shared(false);
+        assert!(publish(&config, named(None)).unwrap());
+
+        assert!(publish(&config, named(Some("payments"))).unwrap());
+        assert_eq!(
+            clusters(&config),
+            [
+                "Cluster::service::1",
+                "Cluster::service::2_payments",
+                "Cluster::service::3"
+            ]
+        );
+        let renamed = config.read().unwrap().get_snapshot();
+        let removed = &renamed.removed()[CLUSTER_TYPE_URL];
+        assert_eq!(removed.iter().collect::<Vec<_>>(), ["Cluster::service::2"]);
+        let removed = &renamed.removed()[LISTENER_TYPE_URL];
+        assert_eq!(removed.iter().collect::<Vec<_>>(), ["service 2"]);
+        assert!(renamed
+            .resources(LISTENER_TYPE_URL)
+            .unwrap()
+            .contains_key("service 2_payments"));
+    }
+
+    #[test]
+    fn resources_named_alike_are_refused() {
+        let cluster = |name: &str, url: &str| EnvoyExport {
+            key: name.to_string(),
+            config: EnvoyResource::Cluster(
+                get_envoy_cluster(name.to_string(), url, &Default::default()).unwrap(),
+            ),
+        };
+        let mut claimed = Claimed::new();
+        let exports = SharedExports::from(vec![cluster("backend", "http://one:80")]);
+        // the exports claiming nothing twice stay shared with the cache
+        let unique = claim(&mut claimed, 1, Arc::clone(&exports)).unwrap();
+        assert!(Arc::ptr_eq(&unique, &exports));
+        // shared as it is
+        claim(
+            &mut claimed,
+            2,
+            vec![cluster("backend", "http://one:80")].into(),
+        )
+        .unwrap();
+        let error = claim(
+            &mut claimed,
+            3,
+            vec![
+                cluster("Cluster::service::3", "http://three:80"),
+                cluster("backend", "http://three:80"),
+            ]
+            .into(),
+        )
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "resource 'backend' of service 3 is named like one of service 1, with a different content"
+        );
+        // nothing of the refused service is claimed
+        claim(
+            &mut claimed,
+            4,
+            vec![cluster("Cluster::service::3", "http://four:80")].into(),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn resources_a_service_exports_twice_are_kept_once() {
+        let cluster = |name: &str, url: &str| EnvoyExport {
+            key: name.to_string(),
+            config: EnvoyResource::Cluster(
+                get_envoy_cluster(name.to_string(), url, &Default::default()).unwrap(),
+            ),
+        };
+        let mut claimed = Claimed::new();
+        let exports = claim(
+            &mut claimed,
+            1,
+            vec![
+                cluster("Cluster::service::1", "http://one:80"),
+                cluster("oidc", "http://issuer:80"),
+                cluster("oidc", "http://issuer:80"),
+            ]
+            .into(),
+        )
+        .unwrap();
+        let names: Vec<_> = exports.iter().map(|export| export.config.name()).collect();
+        assert_eq!(names, ["Cluster::service::1", "oidc"]);
+
+        let error = claim(
+            &mut claimed,
+            2,
+            vec![
+                cluster("oidc", "http://issuer:80"),
+                cluster("oidc", "http://backend:80"),
+            ]
+            .into(),
+        )
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "resource 'oidc' is exported twice by service 2, with different contents"
+        );
+    }
+
+    #[test]
+    fn strict_exports_keep_the_current_version() {
+        let config = shared(true);
+        assert!(publish(&config, three_services(false)).unwrap());
+
+        assert!(publish(&config, three_services(true)).is_err());
+        assert_eq!(config.read().unwrap().get_version(), 1);
+        assert_eq!(clusters(&config).len(), 3);
+        assert_eq!(config.read().unwrap().export_failures()[0].id, 2);
+        assert!(matches!(
+            config.read().unwrap().readiness(),
+            Readiness::Degraded(_)
+        ));
+    }
+
+    #[test]
+    fn missing_filters_degrade_the_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("filter.wasm");
+        let mut config = Config::default();
+        config.set_wasm(service::WasmSettings {
+            filter_path: path.clone(),
+            wasm_required: false,
+            ..Default::default()
+        });
+        let config = RwLock::new(config);
+        assert!(publish(&config, three_services(false)).unwrap());
+        assert_eq!(clusters(&config).len(), 3);
+        assert!(matches!(
+            config.read().unwrap().readiness(),
+            Readiness::Degraded(reason) if reason.ends_with("filter.wasm")
+        ));
+        assert_eq!(config.read().unwrap().missing_filters().len(), 1);
+
+        std::fs::write(&path, b"\0asm").unwrap();
+        assert!(reexport_filters(&config).unwrap());
+        assert!(config.read().unwrap().missing_filters().is_empty());
+        assert!(matches!(
+            config.read().unwrap().readiness(),
+            Readiness::Ready
+        ));
+    }
+
+    #[test]
+    fn services_with_too_many_rules_are_tracked_while_served() {
+        let mut config = Config::default();
+        config.set_wasm(service::WasmSettings {
+            skip_sha: true,
+            virtual_clusters: 1,
+            ..Default::default()
+        });
+        let config = RwLock::new(config);
+        let with_rules = |rules: &[usize]| {
+            let services: Vec<_> = rules
+                .iter()
+                .enumerate()
+                .map(|(i, rules)| {
+                    let rules: Vec<_> = (0..*rules)
+                        .map(|rule| {
+                            serde_json::json!({
+                                "pattern": format!("/{}$", rule),
+                                "http_method": "GET",
+                                "metric_system_name": "hits",
+                                "delta": 1,
+                            })
+                        })
+                        .collect();
+                    serde_json::json!({
+                        "id": i + 1,
+                        "hosts": [format!("{}.app", i + 1)],
+                        "policies": [],
+                        "target_domain": format!("http://{}.app:80", i + 1),
+                        "proxy_rules": rules,
+                    })
+                })
+                .collect();
+            let content = serde_json::to_string(&services).unwrap();
+            Config::from_services(serde_json::from_str(&content).unwrap(), &content)
+        };
+        let warned = |config: &RwLock<Config>| config.read().unwrap().too_many_rules.clone();
+
+        assert!(publish(&config, with_rules(&[1, 3])).unwrap());
+        assert_eq!(warned(&config), vec![(2, 3)].into_iter().collect());
+        assert!(publish(&config, with_rules(&[2, 3])).unwrap());
+        assert_eq!(warned(&config), vec![(1, 2), (2, 3)].into_iter().collect());
+        // forgotten along with the service
+        assert!(publish(&config, with_rules(&[2])).unwrap());
+        assert_eq!(warned(&config), vec![(1, 2)].into_iter().collect());
+    }
+
+    #[test]
+    fn every_problem_of_the_services_is_reported() {
+        let content = r#"[
+            {"id": 1, "hosts": ["one.app"], "policies": [], "target_domain": "http://one.app:80", "proxy_rules": []},
+            {"id": 2, "hosts": [], "policies": [], "target_domain": "one.app", "proxy_rules": [
+                {"pattern": "/", "http_method": "GET", "metric_system_name": "hits", "delta": 1},
+                {"pattern": "/{id", "http_method": "get", "metric_system_name": "hits", "delta": 1}
+            ]},
+            {"id": 3, "hosts": ["three.app"], "policies": [], "target_domain": "http://three.app:80",
+             "proxy_rules": [{"pattern": "/", "http_method": "GET", "metric_system_name": "hits", "delta": "1"}]}
+        ]"#;
+        let error = ServicesFormat::Json
+            .parse_services(content, true)
+            .unwrap_err();
+        let errors: Vec<_> = error
+            .downcast_ref::<FieldErrors>()
+            .unwrap()
+            .0
+            .iter()
+            .map(|error| (error.service, error.path.as_str()))
+            .collect();
+        assert_eq!(
+            errors,
+            [
+                (Some(2), "[1].hosts"),
+                (Some(2), "[1].target_domain"),
+                (Some(2), "[1].proxy_rules[1].pattern"),
+                (Some(2), "[1].proxy_rules[1].http_method"),
+                (Some(3), "[2].proxy_rules[0].delta"),
+            ]
+        );
+        assert!(error
+            .to_string()
+            .starts_with("5 errors: [1].hosts (service 2): "));
+    }
+
+    #[test]
+    fn deprecated_problems_fail_strict_configs_only() {
+        let content = r#"[{"id": 1, "hosts": [], "policies": [], "target_domain": "http://one.app:80",
+            "proxy_rules": [{"pattern": "/", "http_method": "get", "metric_system_name": "hits", "delta": 1}]}]"#;
+        let services = ServicesFormat::Json.parse_services(content, false).unwrap();
+        assert_eq!(services.len(), 1);
+
+        let (_, findings) = service::Service::from_value(
+            &ServicesFormat::Json
+                .parse::<serde_json::Value>(content)
+                .unwrap()[0],
+            "[0]",
+            false,
+        );
+        assert!(findings.errors.is_empty());
+        let warnings: Vec<_> = findings.warnings.iter().map(|w| w.path.as_str()).collect();
+        assert_eq!(warnings, ["[0].hosts", "[0].proxy_rules[0].http_method"]);
+        assert!(findings.warnings[0]
+            .message
+            .ends_with("--strict-config refuses it, as later releases will"));
+
+        let error = ServicesFormat::Json
+            .parse_services(content, true)
+            .unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .starts_with("2 errors: [0].hosts (service 1): needs at least one host;"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn service_warnings_are_logged_once() {
+        let warning = "[0].policies[0].configuration (service 1): 'warned_once' is not a known policy, it is left to the wasm filter";
+        assert!(first_warned(warning));
+        assert!(!first_warned(warning));
+        assert!(first_warned(&warning.replace("[0]", "[1]")));
+    }
+
+    #[test]
+    fn json_errors_point_at_their_line() {
+        let content = "[\n  {\"id\": 1, \"hosts\": \"web\"}\n]";
+        let error = ServicesFormat::Json
+            .parse_services(content, false)
+            .unwrap_err();
+        assert!(error.to_string().contains("at line 2 column"), "{}", error);
+    }
+
+    #[test]
+    fn unknown_fields_fail_strict_configs_only() {
+        let content = r#"[{
+            "id": 1, "hosts": ["one.app"], "target_domain": "http://one.app:80",
+            "proxy_rules": [], "node_group": ["edge"],
+            "policies": [{
+                "name": "cors", "version": "builtin",
+                "configuration": {"allow_origins": ["*"], "allow_origin": "*"}
+            }],
+            "auth_config": {
+                "path": "static/threescale_wasm_auth.wasm",
+                "wasm_config": {
+                    "backend": {
+                        "cluster_name": "backend", "url": "https://backend.app/", "timeout": 5,
+                        "endpoints": [], "lb_policy": null
+                    },
+                    "services": []
+                }
+            }
+        }]"#;
+        let value: serde_json::Value = serde_json::from_str(content).unwrap();
+        // the settings of the auth filter pass through
+        assert_eq!(
+            field_errors::unknown_fields::<ServicesList>(&value, ""),
+            ["[0].node_group"]
+        );
+
+        let error = ServicesFormat::Json
+            .parse_services(content, true)
+            .unwrap_err();
+        // the fields left to their default, and the versions of the APIcast
+        // policies, are known
+        assert_eq!(
+            error.to_string(),
+            "2 errors: [0].policies[0].configuration.allow_origin (service 1): unknown field; \
+             [0].node_group (service 1): unknown field"
+        );
+    }
+
+    #[test]
+    fn services_include_the_snippets_of_their_file() {
+        let content = r#"
+version: 1
+snippets:
+  upstream:
+    policies: []
+    target_domain: http://upstream:80
+    proxy_rules: []
+services:
+  - {id: 1, include: upstream, hosts: [one.app]}
+  - {id: 2, include: upstream, hosts: [two.app], target_domain: "http://two:80"}
+  - {id: 3, include: missing, hosts: [three.app]}
+"#;
+        let error = ServicesFormat::Yaml
+            .parse_services(content, true)
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "services[2].include (service 3): unknown snippet 'missing'"
+        );
+
+        let content = content.replace("include: missing", "include: upstream");
+        let services = ServicesFormat::Yaml.parse_services(&content, true).unwrap();
+        assert_eq!(services[0].target_domain, "http://upstream:80");
+        assert_eq!(services[1].target_domain, "http://two:80");
+    }
+
+    #[test]
+    fn directories_merge_their_services_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let service = |id: u32| {
+            format!(
+                r#"{{"id": {0}, "hosts": ["{0}.app"], "policies": [], "target_domain": "http://{0}.app:80", "proxy_rules": []}}"#,
+                id
+            )
+        };
+        let write = |name: &str, content: &str| {
+            let path = dir.path().join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        };
+        write("teams/b.json", &format!("[{}, {}]", service(2), service(3)));
+        write("a.yaml", "id: 1\nhosts: [1.app]\npolicies: []\ntarget_domain: http://1.app:80\nproxy_rules: []\n");
+        write("notes.txt", "not a services file");
+        // the links of a mounted config map
+        write("..data/b.json", &format!("[{}]", service(2)));
+        let path = dir.path().to_string_lossy().to_string();
+
+        let config = Config::parse_config(&path, ParseOptions::default()).unwrap();
+        let ids: Vec<_> = config.get_services().iter().map(|s| s.id).collect();
+        assert_eq!(ids, [1, 2, 3]);
+
+        write("teams/c.json", &service(3));
+        let error = format!(
+            "{:#}",
+            Config::parse_config(&path, ParseOptions::default()).unwrap_err()
+        );
+        assert!(error.contains("has the same id"), "{}", error);
+        assert!(
+            error.contains("b.json") && error.contains("c.json"),
+            "{}",
+            error
+        );
+
+        write("teams/c.json", r#"{"id": 4, "hosts": []"#);
+        let error = format!(
+            "{:#}",
+            Config::parse_config(&path, ParseOptions::default()).unwrap_err()
+        );
+        assert!(error.starts_with("invalid services config"), "{}", error);
+        assert!(error.contains("c.json"), "{}", error);
+        write(
+            "teams/c.json",
+            &service(4).replace(
+                r#""proxy_rules": []"#,
+                r#""proxy_rules": [], "no_match_action": {"action": "deny", "status": 200}"#,
+            ),
+        );
+        let error = format!(
+            "{:#}",
+            Config::parse_config(&path, ParseOptions::default()).unwrap_err()
+        );
+        assert!(error.contains("(service 4)"), "{}", error);
+        assert!(error.contains("c.json"), "{}", error);
     }
 }
@@ -0,0 +1,340 @@
+use std::collections::HashMap;
+
+use anyhow::bail;
+
+use crate::configuration::DEFAULT_NODE_GROUP;
+use crate::environment::Environment;
+use crate::field_errors::{field, index, FieldError};
+use crate::service::Service;
+
+/// What a host served by more than one service of a node group makes.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum HostConflicts {
+    /// The services are refused.
+    Error,
+    /// They are only warned about, whichever service Envoy picks serving
+    /// the host.
+    #[default]
+    Warn,
+}
+
+impl std::str::FromStr for HostConflicts {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> anyhow::Result<HostConflicts> {
+        match name {
+            "error" => Ok(HostConflicts::Error),
+            "warn" => Ok(HostConflicts::Warn),
+            _ => bail!("unknown host conflicts policy '{}'", name),
+        }
+    }
+}
+
+/// A service checked against the others, with where it was read from.
+pub struct Placed<'a> {
+    pub service: &'a Service,
+    // the services file, when a directory of them is read
+    pub file: Option<std::string::String>,
+    // path of the service in its file
+    pub path: std::string::String,
+}
+
+impl Placed<'_> {
+    fn name(&self) -> std::string::String {
+        let mut name = format!("service {}", self.service.id);
+        if let Some(ref file) = self.file {
+            name = format!("{} in {}", name, file);
+        }
+        if !self.path.is_empty() {
+            name = format!("{} at {}", name, self.path);
+        }
+        name
+    }
+
+    fn error(&self, path: std::string::String, message: std::string::String) -> FieldError {
+        FieldError {
+            file: self.file.clone(),
+            path,
+            service: Some(self.service.id.into()),
+            message,
+        }
+    }
+}
+
+/// What `check` found, along with the index of the service at fault.
+#[derive(Debug, Default)]
+pub struct Conflicts {
+    pub errors: Vec<(usize, FieldError)>,
+    pub warnings: Vec<(usize, FieldError)>,
+}
+
+/// Check the services of a config against each other. Ids must be unique,
+/// the exports of a service replacing those of another with its id, names
+/// too, or the stats of the services would read alike, and a
+/// host served by several enabled services of a node group, through
+/// wildcards or not and in any of their environments, is an error or a warning as `policy` says. The later
+/// service is the one at fault, its error naming the earlier one.
+pub fn check(services: &[Placed], policy: HostConflicts) -> Conflicts {
+    let mut conflicts = Conflicts::default();
+    let mut ids: HashMap<u32, usize> = HashMap::new();
+    let mut names: HashMap<&str, usize> = HashMap::new();
+    for (i, placed) in services.iter().enumerate() {
+        let service = placed.service;
+        if let Some(&first) = ids.get(&service.id) {
+            let message = format!("{} has the same id", services[first].name());
+            conflicts
+                .errors
+                .push((i, placed.error(field(&placed.path, "id"), message)));
+            continue;
+        }
+        ids.insert(service.id, i);
+        if let Some(ref name) = service.name {
+            match names.get(name.as_str()) {
+                Some(&first) => {
+                    let message = format!("{} has the same name", services[first].name());
+                    conflicts
+                        .errors
+                        .push((i, placed.error(field(&placed.path, "name"), message)));
+                }
+                None => {
+                    names.insert(name, i);
+                }
+            }
+        }
+        if !service.enabled {
+            continue;
+        }
+
+        for (host_path, host) in served_hosts(service) {
+            let overlapping = services[..i]
+                .iter()
+                .filter(|other| other.service.enabled && shares_a_group(service, other.service))
+                .find_map(|other| {
+                    let (_, other_host) = served_hosts(other.service)
+                        .into_iter()
+                        .find(|(_, o)| overlap(host, o))?;
+                    Some((other, other_host))
+                });
+            if let Some((other, other_host)) = overlapping {
+                let message = if host.eq_ignore_ascii_case(other_host) {
+                    format!("'{}' is served by {} too", host, other.name())
+                } else {
+                    format!("'{}' overlaps '{}' of {}", host, other_host, other.name())
+                };
+                let error = placed.error(field(&placed.path, &host_path), message);
+                match policy {
+                    HostConflicts::Error => conflicts.errors.push((i, error)),
+                    HostConflicts::Warn => conflicts.warnings.push((i, error)),
+                }
+            }
+        }
+    }
+    conflicts
+}
+
+// The hosts `service` is served to in each of its environments, with
+// their paths in the service.
+fn served_hosts(service: &Service) -> Vec<(std::string::String, &str)> {
+    let mut hosts = Vec::new();
+    let production = service.environments.get(&Environment::Production);
+    match production.and_then(|overrides| overrides.hosts.as_ref()) {
+        Some(production) => hosts.push(("environments.production.hosts", production)),
+        None => hosts.push(("hosts", &service.hosts)),
+    }
+    let staging = service.environments.get(&Environment::Staging);
+    if let Some(staging) = staging.and_then(|overrides| overrides.hosts.as_ref()) {
+        hosts.push(("environments.staging.hosts", staging));
+    }
+    hosts
+        .into_iter()
+        .flat_map(|(path, hosts)| {
+            hosts
+                .iter()
+                .enumerate()
+                .map(move |(i, host)| (index(path, i), host.as_str()))
+        })
+        .collect()
+}
+
+// Services of different tenants are never served to the same nodes.
+fn shares_a_group(service: &Service, other: &Service) -> bool {
+    if service.tenant != other.tenant {
+        return false;
+    }
+    let groups = |service: &Service| match service.node_groups.len() {
+        0 => vec![DEFAULT_NODE_GROUP.to_string()],
+        _ => service.node_groups.clone(),
+    };
+    let others = groups(other);
+    groups(service).iter().any(|group| others.contains(group))
+}
+
+// A domain of a virtual host catching the hosts ending or starting with
+// some text, Envoy's only wildcards besides `*`.
+enum Wildcard<'a> {
+    Suffix(&'a str),
+    Prefix(&'a str),
+}
+
+impl Wildcard<'_> {
+    fn of(domain: &str) -> Option<Wildcard<'_>> {
+        domain
+            .strip_prefix('*')
+            .map(Wildcard::Suffix)
+            .or_else(|| domain.strip_suffix('*').map(Wildcard::Prefix))
+    }
+
+    // the wildcard standing for one character at least
+    fn matches(&self, host: &str) -> bool {
+        match self {
+            Wildcard::Suffix(suffix) => host.len() > suffix.len() && host.ends_with(suffix),
+            Wildcard::Prefix(prefix) => host.len() > prefix.len() && host.starts_with(prefix),
+        }
+    }
+}
+
+/// Whether some request could match both domains, case insensitively as
+/// Envoy does.
+pub fn overlap(a: &str, b: &str) -> bool {
+    let (a, b) = (a.to_ascii_lowercase(), b.to_ascii_lowercase());
+    if a == b || a == "*" || b == "*" {
+        return true;
+    }
+    match (Wildcard::of(&a), Wildcard::of(&b)) {
+        (None, None) => false,
+        (Some(wildcard), None) => wildcard.matches(&b),
+        (None, Some(wildcard)) => wildcard.matches(&a),
+        (Some(Wildcard::Suffix(a)), Some(Wildcard::Suffix(b))) => a.ends_with(b) || b.ends_with(a),
+        (Some(Wildcard::Prefix(a)), Some(Wildcard::Prefix(b))) => {
+            a.starts_with(b) || b.starts_with(a)
+        }
+        // a host starting with the one and ending with the other
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn service(id: u32, hosts: &[&str], groups: &[&str]) -> Service {
+        serde_json::from_value(serde_json::json!({
+            "id": id,
+            "hosts": hosts,
+            "policies": [],
+            "target_domain": "http://one:80",
+            "proxy_rules": [],
+            "node_groups": groups,
+        }))
+        .unwrap()
+    }
+
+    fn placed(services: &[Service]) -> Vec<Placed<'_>> {
+        services
+            .iter()
+            .enumerate()
+            .map(|(i, service)| Placed {
+                service,
+                file: None,
+                path: index("", i),
+            })
+            .collect()
+    }
+
+    fn messages(found: &[(usize, FieldError)]) -> Vec<std::string::String> {
+        found.iter().map(|(_, error)| error.to_string()).collect()
+    }
+
+    #[test]
+    fn domains_overlap_through_wildcards() {
+        assert!(overlap("api.example.com", "API.example.com"));
+        assert!(overlap("*.example.com", "api.example.com"));
+        assert!(!overlap("*.example.com", "example.com"));
+        assert!(overlap("*.com", "*.example.com"));
+        assert!(overlap("api.*", "api.example.*"));
+        assert!(!overlap("api.*", "web.*"));
+        assert!(overlap("api.*", "*.com"));
+        assert!(overlap("*", "web.app"));
+        assert!(!overlap("web.app", "api.app"));
+    }
+
+    #[test]
+    fn duplicate_ids_are_errors() {
+        let services = [
+            service(1, &["one.app"], &[]),
+            service(2, &["two.app"], &[]),
+            service(1, &["three.app"], &[]),
+        ];
+        let found = check(&placed(&services), HostConflicts::Error);
+        assert_eq!(
+            messages(&found.errors),
+            ["[2].id (service 1): service 1 at [0] has the same id"]
+        );
+        assert_eq!(found.errors[0].0, 2);
+        assert!(found.warnings.is_empty());
+
+        let mut services = [
+            service(1, &["one.app"], &[]),
+            service(2, &["two.app"], &[]),
+            service(3, &["three.app"], &[]),
+        ];
+        for service in &mut services {
+            service.name = Some("payments".to_string());
+        }
+        services[1].name = Some("billing".to_string());
+        let found = check(&placed(&services), HostConflicts::Error);
+        assert_eq!(
+            messages(&found.errors),
+            ["[2].name (service 3): service 1 at [0] has the same name"]
+        );
+    }
+
+    #[test]
+    fn host_conflicts_follow_the_policy() {
+        // disabled services serve no host
+        let mut disabled = service(5, &["web.app"], &[]);
+        disabled.enabled = false;
+        let services = [
+            service(1, &["*.example.com"], &[]),
+            service(2, &["web.app", "api.example.com"], &[]),
+            service(3, &["web.app"], &["edge"]),
+            service(4, &["web.app"], &["edge", "default"]),
+            disabled,
+        ];
+        let expected = [
+            "[1].hosts[1] (service 2): 'api.example.com' overlaps '*.example.com' of service 1 at [0]",
+            "[3].hosts[0] (service 4): 'web.app' is served by service 2 at [1] too",
+        ];
+        let found = check(&placed(&services), HostConflicts::Error);
+        assert_eq!(messages(&found.errors), expected);
+
+        // the services of other groups are left alone either way
+        let found = check(&placed(&services), HostConflicts::Warn);
+        assert!(found.errors.is_empty());
+        assert_eq!(messages(&found.warnings), expected);
+    }
+
+    #[test]
+    fn the_hosts_of_every_environment_conflict() {
+        let mut staged = service(2, &["two.app"], &[]);
+        staged.environments = serde_json::from_value(serde_json::json!({
+            "staging": {"hosts": ["ONE.app"]},
+        }))
+        .unwrap();
+        let mut moved = service(3, &["three.app"], &[]);
+        moved.environments = serde_json::from_value(serde_json::json!({
+            "production": {"hosts": ["three.app", "two.app"]},
+        }))
+        .unwrap();
+        let services = [service(1, &["one.app"], &[]), staged, moved];
+
+        let found = check(&placed(&services), HostConflicts::Error);
+        assert_eq!(
+            messages(&found.errors),
+            [
+                "[1].environments.staging.hosts[0] (service 2): 'ONE.app' is served by service 1 at [0] too",
+                "[2].environments.production.hosts[1] (service 3): 'two.app' is served by service 2 at [1] too",
+            ]
+        );
+    }
+}
@@ -0,0 +1,321 @@
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::interpolation;
+use crate::policy::Policy;
+use crate::service::Service;
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Change {
+    Added,
+    Removed,
+    Changed,
+}
+
+/// How a service changed from one config to the next.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct ServiceDiff {
+    pub id: u32,
+    pub change: Change,
+    // one line per change of a changed service
+    pub changes: Vec<std::string::String>,
+    // the same changes, with the values before and after, secrets redacted
+    #[serde(skip)]
+    pub details: Vec<std::string::String>,
+}
+
+// A value as the diff compares it, with its secrets, and as it describes
+// it, without.
+struct Serialized {
+    revealed: Value,
+    redacted: Value,
+}
+
+impl Serialized {
+    fn new<T: Serialize>(value: &T) -> Serialized {
+        let revealed = interpolation::revealed(|| serde_json::to_value(value)).unwrap_or_default();
+        let mut redacted = serde_json::to_value(value).unwrap_or_default();
+        interpolation::redact(&mut redacted);
+        Serialized { revealed, redacted }
+    }
+
+    // The field `key` of the value, null when it has none.
+    fn get(&self, key: &str) -> Serialized {
+        let field = |value: &Value| value.get(key).cloned().unwrap_or_default();
+        Serialized {
+            revealed: field(&self.revealed),
+            redacted: field(&self.redacted),
+        }
+    }
+
+    fn keys(&self) -> impl Iterator<Item = &std::string::String> {
+        self.revealed
+            .as_object()
+            .into_iter()
+            .flat_map(|fields| fields.keys())
+    }
+}
+
+// The fields of the services whose items are told added or removed, rather
+// than the field changed.
+const LISTS: &[&str] = &["hosts", "policies", "proxy_rules", "node_groups"];
+
+impl ServiceDiff {
+    fn new(id: u32, change: Change) -> ServiceDiff {
+        ServiceDiff {
+            id,
+            change,
+            changes: Vec::new(),
+            details: Vec::new(),
+        }
+    }
+
+    /// The one line summary of the change, as logged.
+    pub fn summary(&self) -> std::string::String {
+        match self.change {
+            Change::Added => format!("service {}: added", self.id),
+            Change::Removed => format!("service {}: removed", self.id),
+            Change::Changed => format!("service {}: {}", self.id, self.changes.join("; ")),
+        }
+    }
+
+    // Items of a list field that were added or removed.
+    fn list<T: PartialEq>(
+        &mut self,
+        what: &str,
+        old: &[T],
+        new: &[T],
+        describe: impl Fn(&T) -> std::string::String,
+    ) {
+        let added = new.iter().filter(|item| !old.contains(item));
+        let removed = old.iter().filter(|item| !new.contains(item));
+        let lines: Vec<_> = added
+            .map(|item| format!("{} added {}", what, describe(item)))
+            .chain(removed.map(|item| format!("{} removed {}", what, describe(item))))
+            .collect();
+        self.details.extend(lines.iter().cloned());
+        self.changes.extend(lines);
+    }
+
+    // The policies added or removed, by name, and those whose settings
+    // changed.
+    fn policies(&mut self, old: &[Policy], new: &[Policy]) {
+        let names = |policies: &[Policy]| -> Vec<std::string::String> {
+            policies
+                .iter()
+                .map(|policy| policy.name().to_string())
+                .collect()
+        };
+        self.list("policy", &names(old), &names(new), Clone::clone);
+        for policy in new {
+            if let Some(previous) = old.iter().find(|previous| previous.name() == policy.name()) {
+                self.field(
+                    &format!("policy {}", policy.name()),
+                    &Serialized::new(previous),
+                    &Serialized::new(policy),
+                );
+            }
+        }
+    }
+
+    fn field(&mut self, what: &str, old: &Serialized, new: &Serialized) {
+        if old.revealed != new.revealed {
+            self.changes.push(format!("{} changed", what));
+            self.details.push(format!(
+                "{} changed from {} to {}",
+                what, old.redacted, new.redacted
+            ));
+        }
+    }
+}
+
+/// The changes to the services of a published snapshot.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct ConfigDiff {
+    pub version: u32,
+    pub services: Vec<ServiceDiff>,
+}
+
+// The lists of `old` and `new` item by item, and any other of their fields,
+// as they serialize, for the diff to cover every field of the services.
+fn changes(old: &Service, new: &Service) -> ServiceDiff {
+    let mut diff = ServiceDiff::new(new.id, Change::Changed);
+    diff.list("host", &old.hosts, &new.hosts, |host| host.clone());
+    diff.policies(&old.policies, &new.policies);
+    diff.list("mapping rule", &old.proxy_rules, &new.proxy_rules, |rule| {
+        rule.describe()
+    });
+    diff.list("node group", &old.node_groups, &new.node_groups, |group| {
+        group.clone()
+    });
+    let (old, new) = (Serialized::new(old), Serialized::new(new));
+    let mut keys: Vec<_> = old.keys().chain(new.keys()).collect();
+    keys.sort();
+    keys.dedup();
+    for key in keys
+        .into_iter()
+        .filter(|key| !LISTS.contains(&key.as_str()))
+    {
+        diff.field(&key.replace('_', " "), &old.get(key), &new.get(key));
+    }
+    diff
+}
+
+/// The services added, removed or changed from `old` to `new`, by id.
+pub fn services(old: &[Service], new: &[Service]) -> Vec<ServiceDiff> {
+    let old: BTreeMap<_, _> = old.iter().map(|service| (service.id, service)).collect();
+    let new: BTreeMap<_, _> = new.iter().map(|service| (service.id, service)).collect();
+    let mut diffs = Vec::new();
+    for (id, service) in &new {
+        match old.get(id) {
+            None => diffs.push(ServiceDiff::new(*id, Change::Added)),
+            Some(previous) if previous != service => diffs.push(changes(previous, service)),
+            Some(_) => {}
+        }
+    }
+    for id in old.keys().filter(|id| !new.contains_key(id)) {
+        diffs.push(ServiceDiff::new(*id, Change::Removed));
+    }
+    diffs.sort_by_key(|diff| diff.id);
+    diffs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn service(id: u32, extra: serde_json::Value) -> Service {
+        let mut service = serde_json::json!({
+            "id": id,
+            "hosts": ["web.app"],
+            "policies": [],
+            "target_domain": "http://web.app:80",
+            "proxy_rules": [
+                {"pattern": "/", "http_method": "GET", "metric_system_name": "hits", "delta": 1}
+            ],
+        });
+        for (key, value) in extra.as_object().unwrap() {
+            service[key] = value.clone();
+        }
+        serde_json::from_value(service).unwrap()
+    }
+
+    #[test]
+    fn added_and_removed_services() {
+        let old = [
+            service(17, serde_json::json!({})),
+            service(1, serde_json::json!({})),
+        ];
+        let new = [
+            service(1, serde_json::json!({})),
+            service(42, serde_json::json!({})),
+        ];
+
+        let diffs = services(&old, &new);
+        let summaries: Vec<_> = diffs.iter().map(ServiceDiff::summary).collect();
+        assert_eq!(summaries, ["service 17: removed", "service 42: added"]);
+        assert!(services(&old, &old).is_empty());
+    }
+
+    #[test]
+    fn field_changes_are_described() {
+        let old = [service(42, serde_json::json!({}))];
+        let new = [service(
+            42,
+            serde_json::json!({
+                "hosts": ["web.app", "api.app"],
+                "proxy_rules": [
+                    {"pattern": "/", "http_method": "GET", "metric_system_name": "hits", "delta": 1},
+                    {"pattern": "/v2/things", "http_method": "GET", "metric_system_name": "hits", "delta": 1},
+                    {"pattern": "/v2/orders", "http_method": "POST", "metric_system_name": "orders", "delta": 5}
+                ],
+                "target_domain": "http://web.app:8080",
+            }),
+        )];
+
+        let diffs = services(&old, &new);
+        assert_eq!(diffs.len(), 1);
+        assert_eq!(diffs[0].change, Change::Changed);
+        assert_eq!(
+            diffs[0].summary(),
+            "service 42: host added api.app; \
+             mapping rule added GET /v2/things → hits; \
+             mapping rule added POST /v2/orders → orders +5; target domain changed"
+        );
+        assert!(diffs[0].details.contains(
+            &"target domain changed from \"http://web.app:80\" to \"http://web.app:8080\""
+                .to_string()
+        ));
+
+        // back again
+        let diffs = services(&new, &old);
+        assert_eq!(
+            diffs[0].changes,
+            [
+                "host removed api.app",
+                "mapping rule removed GET /v2/things → hits",
+                "mapping rule removed POST /v2/orders → orders +5",
+                "target domain changed"
+            ]
+        );
+    }
+
+    #[test]
+    fn every_field_is_compared() {
+        let old = [service(42, serde_json::json!({"policies": ["cors"]}))];
+        let new = [service(
+            42,
+            serde_json::json!({
+                "policies": [
+                    {"name": "cors", "configuration": {"allow_origins": ["https://web.app"]}},
+                    "security_headers"
+                ],
+                "enabled": false,
+                "http3": true,
+                "environments": {"staging": {"hosts": ["staging.web.app"]}},
+            }),
+        )];
+
+        let diffs = services(&old, &new);
+        assert_eq!(
+            diffs[0].changes,
+            [
+                "policy added security_headers",
+                "policy cors changed",
+                "enabled changed",
+                "environments changed",
+                "http3 changed"
+            ]
+        );
+        assert!(diffs[0]
+            .details
+            .contains(&"enabled changed from true to false".to_string()));
+    }
+
+    #[test]
+    fn secrets_are_redacted() {
+        let auth = |token: &str| {
+            serde_json::json!({"auth_config": {
+                "path": "static/threescale_wasm_auth.wasm",
+                "wasm_config": {
+                    "backend": {"cluster_name": "backend", "url": "https://su1.3scale.net/"},
+                    "services": [{"id": "web", "token": token}],
+                },
+            }})
+        };
+        let old = [service(42, auth("0ld-t0ken"))];
+        let new = [service(42, auth("n3w-t0ken"))];
+
+        let diffs = services(&old, &new);
+        assert_eq!(diffs[0].changes, ["auth config changed"]);
+        assert!(diffs[0].details[0].contains("<redacted>"));
+        assert!(
+            !diffs[0].details[0].contains("t0ken"),
+            "{}",
+            diffs[0].details[0]
+        );
+    }
+}
@@ -0,0 +1,368 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::cli::{ControllerConfig, ReportFormat};
+use crate::envoy_helpers::{EnvoyResource, CLUSTER_TYPE_URL, LISTENER_TYPE_URL};
+use crate::export;
+use crate::proto_json::Registry;
+use crate::snapshot::Snapshot;
+
+use crate::protobuf::envoy::config::cluster::v3::Cluster;
+use crate::protobuf::envoy::config::listener::v3::Listener;
+
+/// How a resource of the reference Envoy differs from the one of the same
+/// name the controller generates.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Difference {
+    // the reference has it, the controller doesn't
+    Missing,
+    // the controller generates it, the reference doesn't have it
+    Extra,
+    Changed(Vec<FieldChange>),
+}
+
+/// A field of a resource set differently, `null` on the side without it.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct FieldChange {
+    // as `filter_chains[0].filters[0].typed_config.route_config`
+    pub path: std::string::String,
+    pub reference: Value,
+    pub generated: Value,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct ResourceDiff {
+    // `listener` or `cluster`
+    pub kind: &'static str,
+    pub name: std::string::String,
+    pub difference: Difference,
+}
+
+/// What `diff` found comparing the listeners and clusters of the config
+/// dump with those the controller generates, those alike left out.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct Report {
+    pub compared: usize,
+    pub resources: Vec<ResourceDiff>,
+}
+
+impl Report {
+    pub fn exit_code(&self) -> i32 {
+        if self.resources.is_empty() {
+            0
+        } else {
+            1
+        }
+    }
+
+    pub fn render(&self, format: ReportFormat) -> std::string::String {
+        match format {
+            ReportFormat::Json => serde_json::to_string_pretty(self).unwrap() + "\n",
+            ReportFormat::Text => self.render_text(),
+        }
+    }
+
+    fn render_text(&self) -> std::string::String {
+        let mut text = std::string::String::new();
+        let (mut changed, mut missing, mut extra) = (0, 0, 0);
+        for resource in &self.resources {
+            match resource.difference {
+                Difference::Missing => {
+                    writeln!(text, "MISSING {} {}", resource.kind, resource.name).unwrap();
+                    missing += 1;
+                }
+                Difference::Extra => {
+                    writeln!(text, "EXTRA {} {}", resource.kind, resource.name).unwrap();
+                    extra += 1;
+                }
+                Difference::Changed(ref fields) => {
+                    writeln!(text, "CHANGED {} {}", resource.kind, resource.name).unwrap();
+                    for field in fields {
+                        writeln!(
+                            text,
+                            "  {}: {} -> {}",
+                            field.path, field.reference, field.generated
+                        )
+                        .unwrap();
+                    }
+                    changed += 1;
+                }
+            }
+        }
+        writeln!(
+            text,
+            "{} resources compared, {} changed, {} missing, {} extra",
+            self.compared, changed, missing, extra
+        )
+        .unwrap();
+        text
+    }
+}
+
+// The listeners or clusters of a config dump, active and warming, static
+// and dynamic, by name.
+fn dumped(dump: &Value) -> Result<BTreeMap<(&'static str, std::string::String), Value>> {
+    let configs = dump
+        .get("configs")
+        .and_then(Value::as_array)
+        .ok_or_else(|| anyhow!("not a config dump, it has no configs"))?;
+    let mut resources = BTreeMap::new();
+    for config in configs {
+        let type_url = config.get("@type").and_then(Value::as_str).unwrap_or("");
+        let (kind, lists, field): (_, &[&str], _) = if type_url.ends_with(".ListenersConfigDump") {
+            (
+                "listener",
+                &["static_listeners", "dynamic_listeners"],
+                "listener",
+            )
+        } else if type_url.ends_with(".ClustersConfigDump") {
+            (
+                "cluster",
+                &[
+                    "static_clusters",
+                    "dynamic_active_clusters",
+                    "dynamic_warming_clusters",
+                ],
+                "cluster",
+            )
+        } else {
+            continue;
+        };
+        let entries = lists
+            .iter()
+            .filter_map(|list| config.get(*list).and_then(Value::as_array))
+            .flatten();
+        for entry in entries {
+            // the dynamic listeners are in their state
+            let resource = ["active_state", "warming_state"]
+                .iter()
+                .find_map(|state| entry.get(*state))
+                .unwrap_or(entry)
+                .get(field);
+            let resource = match resource {
+                Some(resource) => resource,
+                None => continue,
+            };
+            let name = resource
+                .get("name")
+                .and_then(Value::as_str)
+                .ok_or_else(|| anyhow!("a {} of the config dump has no name", kind))?;
+            resources.insert((kind, name.to_string()), resource.clone());
+        }
+    }
+    Ok(resources)
+}
+
+// The JSON of a resource of the config dump the way the controller encodes
+// its own: decoded and encoded again, leaving out the fields set to their
+// default and naming the fields the same.
+fn normalized(registry: &Registry, kind: &str, json: &Value) -> Result<Value> {
+    use prost::Message;
+
+    let mut json = json.clone();
+    if json.get("@type").is_none() {
+        let type_url = match kind {
+            "listener" => LISTENER_TYPE_URL,
+            _ => CLUSTER_TYPE_URL,
+        };
+        json.as_object_mut()
+            .unwrap()
+            .insert("@type".to_string(), Value::from(type_url));
+    }
+    let any = registry.json_to_any(&json)?;
+    let resource = match kind {
+        "listener" => EnvoyResource::Listener(Listener::decode(any.value.as_slice())?),
+        _ => EnvoyResource::Cluster(Cluster::decode(any.value.as_slice())?),
+    };
+    registry.any_to_json(&resource.to_any()?)
+}
+
+// Every field of `generated` set differently in `reference`, under `path`.
+fn changes(path: &str, reference: &Value, generated: &Value, found: &mut Vec<FieldChange>) {
+    let at = |key: &str| match path {
+        "" => key.to_string(),
+        _ => format!("{}.{}", path, key),
+    };
+    match (reference, generated) {
+        (Value::Object(reference), Value::Object(generated)) => {
+            let keys: std::collections::BTreeSet<_> =
+                reference.keys().chain(generated.keys()).collect();
+            for key in keys {
+                let (reference, generated) = (
+                    reference.get(key).unwrap_or(&Value::Null),
+                    generated.get(key).unwrap_or(&Value::Null),
+                );
+                changes(&at(key), reference, generated, found);
+            }
+        }
+        (Value::Array(reference), Value::Array(generated)) => {
+            for i in 0..reference.len().max(generated.len()) {
+                changes(
+                    &format!("{}[{}]", path, i),
+                    reference.get(i).unwrap_or(&Value::Null),
+                    generated.get(i).unwrap_or(&Value::Null),
+                    found,
+                );
+            }
+        }
+        _ if reference != generated => found.push(FieldChange {
+            path: path.to_string(),
+            reference: reference.clone(),
+            generated: generated.clone(),
+        }),
+        _ => {}
+    }
+}
+
+/// Compare the listeners and clusters of `dump`, the JSON of the
+/// `/config_dump` of an Envoy, with those of `snapshot`.
+pub fn diff(registry: &Registry, snapshot: &Snapshot, dump: &Value) -> Result<Report> {
+    let mut generated = BTreeMap::new();
+    for (kind, type_url) in &[
+        ("listener", LISTENER_TYPE_URL),
+        ("cluster", CLUSTER_TYPE_URL),
+    ] {
+        for resource in snapshot
+            .resources(type_url)
+            .into_iter()
+            .flat_map(|r| r.values())
+        {
+            let json = registry
+                .any_to_json(&resource.resource)
+                .with_context(|| format!("cannot encode {}", resource.name))?;
+            generated.insert((*kind, resource.name.clone()), json);
+        }
+    }
+    let mut reference = BTreeMap::new();
+    for ((kind, name), json) in dumped(dump)? {
+        let json = normalized(registry, kind, &json)
+            .with_context(|| format!("cannot decode {} {} of the config dump", kind, name))?;
+        reference.insert((kind, name), json);
+    }
+
+    let mut keys: Vec<_> = reference.keys().chain(generated.keys()).collect();
+    keys.sort();
+    keys.dedup();
+    let mut resources = Vec::new();
+    for key in &keys {
+        let difference = match (reference.get(key), generated.get(key)) {
+            (Some(reference), Some(generated)) => {
+                let mut found = Vec::new();
+                changes("", reference, generated, &mut found);
+                if found.is_empty() {
+                    continue;
+                }
+                Difference::Changed(found)
+            }
+            (Some(_), None) => Difference::Missing,
+            _ => Difference::Extra,
+        };
+        resources.push(ResourceDiff {
+            kind: key.0,
+            name: key.1.clone(),
+            difference,
+        });
+    }
+    Ok(Report {
+        compared: keys.len(),
+        resources,
+    })
+}
+
+/// Compare the config dump of the diff command with the resources of its
+/// node group.
+pub fn run(settings: &ControllerConfig) -> Result<Report> {
+    let path = &settings.diff.config_dump;
+    let dump: Value = serde_json::from_slice(
+        &std::fs::read(path).with_context(|| format!("cannot read {}", path.display()))?,
+    )
+    .with_context(|| format!("{} is not JSON", path.display()))?;
+    let config = export::load(settings)?;
+    let group = &settings.diff.node_group;
+    if !config.group_snapshots().contains_key(group) {
+        bail!("no service is served to node group '{}'", group);
+    }
+    diff(&Registry::new()?, &config.group_snapshot(group), &dump)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configuration::{Config, ServicesList};
+    use crate::service::WasmSettings;
+
+    const SERVICES: &str = include_str!("../testdata/bootstrap/services.json");
+    const CONFIG_DUMP: &str = include_str!("../testdata/diff/config_dump.json");
+
+    fn snapshot() -> std::sync::Arc<Snapshot> {
+        let services: ServicesList = serde_json::from_str(SERVICES).unwrap();
+        let wasm = WasmSettings {
+            skip_sha: true,
+            ..Default::default()
+        };
+        let exports = services
+            .iter()
+            .map(|service| (service.id, service.export(&wasm).unwrap().into()))
+            .collect();
+        let mut config = Config::default();
+        config.import(services, "services".to_string(), exports);
+        config.get_snapshot()
+    }
+
+    #[test]
+    fn config_dumps_of_the_same_resources_are_alike() {
+        let dump: Value = serde_json::from_str(CONFIG_DUMP).unwrap();
+        let report = diff(&Registry::new().unwrap(), &snapshot(), &dump).unwrap();
+        assert_eq!(report.resources, []);
+        assert_eq!(report.compared, 3);
+        assert_eq!(report.exit_code(), 0);
+        assert_eq!(
+            report.render(ReportFormat::Text),
+            "3 resources compared, 0 changed, 0 missing, 0 extra\n"
+        );
+    }
+
+    #[test]
+    fn differences_are_told_by_resource_and_field() {
+        let mut dump: Value = serde_json::from_str(CONFIG_DUMP).unwrap();
+        let listener = "/configs/2/dynamic_listeners/0/active_state/listener";
+        let route = "/filter_chains/0/filters/0/typed_config/route_config/virtual_hosts/0/routes/0";
+        *dump
+            .pointer_mut(&format!("{}{}/match/prefix", listener, route))
+            .unwrap() = Value::from("/v1");
+        let clusters = dump
+            .pointer_mut("/configs/1/static_clusters")
+            .unwrap()
+            .as_array_mut()
+            .unwrap();
+        clusters.retain(|entry| entry["cluster"]["name"] != "Cluster::service::1");
+        clusters.push(serde_json::json!({"cluster": {
+            "name": "xds_cluster",
+            "connect_timeout": "0.250s",
+        }}));
+
+        let report = diff(&Registry::new().unwrap(), &snapshot(), &dump).unwrap();
+        assert_eq!(
+            report.render(ReportFormat::Text),
+            "EXTRA cluster Cluster::service::1\n\
+             MISSING cluster xds_cluster\n\
+             CHANGED listener service 1\n\
+             \x20 filter_chains[0].filters[0].typed_config.route_config.virtual_hosts[0].routes[0].match.prefix: \"/v1\" -> \"/\"\n\
+             4 resources compared, 1 changed, 1 missing, 1 extra\n"
+        );
+        assert_eq!(report.exit_code(), 1);
+
+        // resources Envoy would not take are errors rather than differences
+        dump["configs"][1]["static_clusters"][0]["cluster"]["connect_timeout"] = Value::from(1);
+        let error = diff(&Registry::new().unwrap(), &snapshot(), &dump).unwrap_err();
+        assert_eq!(
+            format!("{:#}", error),
+            "cannot decode cluster 3scale-saas-backend of the config dump: invalid connect_timeout: 1 is not a duration"
+        );
+    }
+}
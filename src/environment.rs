@@ -0,0 +1,116 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::policy::Policy;
+
+/// The annotation telling the environment of the resources of a service.
+pub const ANNOTATION: &str = "environment";
+
+/// The environments of a service, served at once, as the staging and
+/// production proxies of 3scale. The settings of the service are those of
+/// production, the environments overriding some of them.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Environment {
+    Staging,
+    Production,
+}
+
+impl Environment {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Environment::Staging => "staging",
+            Environment::Production => "production",
+        }
+    }
+}
+
+impl std::fmt::Display for Environment {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// The settings of a service an environment overrides, those left out
+/// being the ones of the service.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Overrides {
+    #[serde(default)]
+    pub hosts: Option<Vec<std::string::String>>,
+    #[serde(default)]
+    pub target_domain: Option<std::string::String>,
+    #[serde(default)]
+    pub policies: Option<Vec<Policy>>,
+}
+
+pub type Environments = BTreeMap<Environment, Overrides>;
+
+/// `environments` once staging is promoted: production takes the settings
+/// staging overrides, but for its hosts, those of each environment being
+/// their own. Without staging, nothing is promoted.
+pub fn promote(environments: &Environments) -> Environments {
+    let mut promoted = environments.clone();
+    if let Some(staging) = environments.get(&Environment::Staging) {
+        let production = promoted.entry(Environment::Production).or_default();
+        production.target_domain = staging
+            .target_domain
+            .clone()
+            .or_else(|| production.target_domain.take());
+        production.policies = staging
+            .policies
+            .clone()
+            .or_else(|| production.policies.take());
+    }
+    promoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn environments(value: serde_json::Value) -> Environments {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn promotions_take_the_settings_of_staging_but_its_hosts() {
+        let promoted = promote(&environments(serde_json::json!({
+            "staging": {
+                "hosts": ["staging.web.app"],
+                "target_domain": "http://web-v2:80",
+                "policies": ["cors"],
+            },
+            "production": {"target_domain": "http://web:80"},
+        })));
+        assert_eq!(
+            promoted,
+            environments(serde_json::json!({
+                "staging": {
+                    "hosts": ["staging.web.app"],
+                    "target_domain": "http://web-v2:80",
+                    "policies": ["cors"],
+                },
+                "production": {"target_domain": "http://web-v2:80", "policies": ["cors"]},
+            }))
+        );
+
+        // what staging leaves to the service, production keeps
+        let promoted = promote(&environments(serde_json::json!({
+            "staging": {"hosts": ["staging.web.app"]},
+            "production": {"hosts": ["web.app"], "target_domain": "http://web:80"},
+        })));
+        assert_eq!(
+            promoted[&Environment::Production],
+            Overrides {
+                hosts: Some(vec!["web.app".to_string()]),
+                target_domain: Some("http://web:80".to_string()),
+                policies: None,
+            }
+        );
+
+        let production = environments(serde_json::json!({"production": {"policies": []}}));
+        assert_eq!(promote(&production), production);
+    }
+}
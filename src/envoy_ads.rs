@@ -0,0 +1,524 @@
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use futures::{Stream, StreamExt};
+use tokio::sync::mpsc;
+use tonic::{Request, Response, Status, Streaming};
+
+use crate::configuration;
+use crate::envoy_delta::POLL_INTERVAL;
+use crate::envoy_helpers::{
+    CLUSTER_TYPE_URL, ENDPOINT_TYPE_URL, LISTENER_TYPE_URL, ROUTE_TYPE_URL, SECRET_TYPE_URL,
+};
+use crate::node_status::{NodeStatuses, Reply};
+use crate::protobuf::envoy::service::discovery::v3::aggregated_discovery_service_server::AggregatedDiscoveryService;
+use crate::protobuf::envoy::service::discovery::v3::{
+    DeltaDiscoveryRequest, DeltaDiscoveryResponse, DiscoveryRequest, DiscoveryResponse,
+};
+use crate::shutdown::{self, Shutdown};
+use crate::snapshot::Snapshot;
+use crate::snapshot_cache::SnapshotCache;
+use crate::xds_auth::Authorized;
+
+// Order in which updates are pushed so that Envoy never waits on a resource
+// that is only sent later: clusters before the endpoints they load balance,
+// and all of them, along with the secrets of the listeners, before the
+// listeners and routes referencing them.
+const TYPE_ORDER: [&str; 5] = [
+    CLUSTER_TYPE_URL,
+    ENDPOINT_TYPE_URL,
+    SECRET_TYPE_URL,
+    LISTENER_TYPE_URL,
+    ROUTE_TYPE_URL,
+];
+
+fn type_priority(type_url: &str) -> usize {
+    TYPE_ORDER
+        .iter()
+        .position(|known| *known == type_url)
+        .unwrap_or_else(|| TYPE_ORDER.len())
+}
+
+#[derive(Debug, Default)]
+struct Subscription {
+    names: Vec<std::string::String>,
+    // type version last sent, None when the client is due a response
+    version: Option<std::string::String>,
+    nonce: std::string::String,
+}
+
+/// State of a state-of-the-world discovery stream: a subscription per type
+/// URL the client asked for. Streams of the per-type services are bound to
+/// their type, ADS streams serve any type.
+#[derive(Debug, Default)]
+pub struct DiscoveryState {
+    type_url: Option<&'static str>,
+    subscriptions: HashMap<std::string::String, Subscription>,
+    nonce: u64,
+}
+
+impl DiscoveryState {
+    pub fn new(type_url: Option<&'static str>) -> DiscoveryState {
+        DiscoveryState {
+            type_url,
+            ..Default::default()
+        }
+    }
+
+    /// The type URL a request is about.
+    pub fn type_url<'a>(&self, request: &'a DiscoveryRequest) -> &'a str {
+        match self.type_url {
+            Some(type_url) => type_url,
+            None => &request.type_url,
+        }
+    }
+
+    pub fn on_request(&mut self, request: &DiscoveryRequest) {
+        let type_url = self.type_url(request).to_string();
+        let subscription = self.subscriptions.entry(type_url).or_default();
+
+        // responses to an older nonce are superseded by the last one sent
+        if !request.response_nonce.is_empty() && request.response_nonce != subscription.nonce {
+            tracing::debug!(
+                "Ignoring stale {} request for nonce {}",
+                request.type_url,
+                request.response_nonce
+            );
+            return;
+        }
+        if let Some(ref error) = request.error_detail {
+            tracing::warn!(
+                type_url = %request.type_url,
+                version = %request.version_info,
+                "Envoy rejected the resources: {}",
+                error.message
+            );
+        }
+
+        if request.response_nonce.is_empty() || subscription.names != request.resource_names {
+            subscription.names = request.resource_names.clone();
+            subscription.version = None;
+        }
+    }
+
+    /// Responses bringing every subscription up to date with `snapshot`, in
+    /// the order they have to be sent, along with the names of the resources
+    /// in them.
+    pub fn responses(
+        &mut self,
+        snapshot: &Snapshot,
+    ) -> Vec<(DiscoveryResponse, Vec<std::string::String>)> {
+        let mut pending: Vec<(&std::string::String, &mut Subscription)> = self
+            .subscriptions
+            .iter_mut()
+            .filter(|(type_url, subscription)| {
+                subscription.version.as_deref() != Some(snapshot.type_version(type_url).as_str())
+            })
+            .collect();
+        pending.sort_by_key(|(type_url, _)| (type_priority(type_url), type_url.to_string()));
+
+        let mut responses = Vec::with_capacity(pending.len());
+        for (type_url, subscription) in pending {
+            let selected: Vec<_> = snapshot
+                .resources(type_url)
+                .map(|resources| {
+                    resources
+                        .values()
+                        .filter(|resource| {
+                            subscription.names.is_empty()
+                                || subscription.names.contains(&resource.name)
+                        })
+                        .collect()
+                })
+                .unwrap_or_default();
+            let names = selected.iter().map(|r| r.name.clone()).collect();
+            let resources = selected.iter().map(|r| r.resource.clone()).collect();
+
+            let version = snapshot.type_version(type_url);
+            self.nonce += 1;
+            subscription.version = Some(version.clone());
+            subscription.nonce = self.nonce.to_string();
+            let response = DiscoveryResponse {
+                version_info: version,
+                resources,
+                type_url: type_url.to_string(),
+                nonce: subscription.nonce.clone(),
+                ..Default::default()
+            };
+            responses.push((response, names));
+        }
+        responses
+    }
+}
+
+/// Serve a state-of-the-world discovery stream: of `type_url` resources
+/// only, or multiplexing every resource type requested by the client when
+/// `type_url` is `None`.
+pub fn stream<S>(
+    cache: Arc<dyn SnapshotCache>,
+    statuses: NodeStatuses,
+    shutdown: Shutdown,
+    type_url: Option<&'static str>,
+    authorized: Authorized,
+    mut requests: S,
+) -> mpsc::Receiver<Result<DiscoveryResponse, Status>>
+where
+    S: Stream<Item = Result<DiscoveryRequest, Status>> + Unpin + Send + 'static,
+{
+    let (mut tx, rx) = mpsc::channel(TYPE_ORDER.len());
+    tokio::spawn(async move {
+        if shutdown.is_draining() {
+            let _ = tx.send(Err(shutdown::refused())).await;
+            return;
+        }
+        let mut state = DiscoveryState::new(type_url);
+        let mut status = statuses.stream();
+        let mut group = None;
+        let mut versions = cache.subscribe();
+        'stream: loop {
+            tokio::select! {
+                request = requests.next() => match request {
+                    Some(Ok(request)) => {
+                        if group.is_none() {
+                            match authorized.snapshot_key(&*cache, request.node.as_ref()) {
+                                Ok(key) => group = Some(key),
+                                Err(refused) => {
+                                    let _ = tx.send(Err(refused)).await;
+                                    break;
+                                }
+                            }
+                        }
+                        let type_url = state.type_url(&request);
+                        let reply = status.on_request(
+                            request
+                                .node
+                                .as_ref()
+                                .zip(group.as_deref())
+                                .map(|(node, key)| configuration::node_key(key, &node.id))
+                                .as_deref(),
+                            type_url,
+                            Some(&request.version_info),
+                            &request.response_nonce,
+                            request.error_detail.as_ref(),
+                        );
+                        // version_info is the version the node kept running
+                        match reply {
+                            Some(Reply::Ack) => cache.on_ack(
+                                group.as_deref().unwrap_or_default(),
+                                status.node(),
+                                type_url,
+                                &request.version_info,
+                            ),
+                            Some(Reply::Nack(nack)) => {
+                                cache.on_nack(
+                                    status.node(),
+                                    type_url,
+                                    &nack,
+                                    &request.version_info,
+                                );
+                            }
+                            None => {}
+                        }
+                        state.on_request(&request);
+                    }
+                    Some(Err(e)) => {
+                        tracing::debug!("Discovery stream failed: {:?}", e);
+                        break;
+                    }
+                    None => break,
+                },
+                // a new version, or one the stream missed
+                Some(_) = versions.next() => {}
+                _ = tokio::time::delay_for(POLL_INTERVAL) => {}
+                // responses already sent are complete, only the next ones
+                // are given up on
+                _ = shutdown.wait() => {
+                    if let Some(end) = shutdown.stream_end() {
+                        let _ = tx.send(Err(end)).await;
+                    }
+                    break;
+                }
+            }
+
+            let snapshot = match group {
+                Some(ref group) => cache.snapshot(group),
+                None => continue,
+            };
+            for (response, names) in state.responses(&snapshot) {
+                tracing::info!(
+                    type_url = %response.type_url,
+                    version = %response.version_info,
+                    snapshot = snapshot.version(),
+                    node = status.node(),
+                    "Sending resources"
+                );
+                status.sent(
+                    &response.type_url,
+                    &response.version_info,
+                    &response.nonce,
+                    names,
+                );
+                if tx.send(Ok(response)).await.is_err() {
+                    break 'stream;
+                }
+            }
+        }
+        tracing::debug!("Discovery stream of {} closed", status.node());
+    });
+    rx
+}
+
+// named like the CDS and LDS services
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone)]
+pub struct ADS {
+    cache: Arc<dyn SnapshotCache>,
+    statuses: NodeStatuses,
+    shutdown: Shutdown,
+}
+
+impl ADS {
+    pub fn new(cache: Arc<dyn SnapshotCache>, statuses: NodeStatuses, shutdown: Shutdown) -> ADS {
+        ADS {
+            cache,
+            statuses,
+            shutdown,
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl AggregatedDiscoveryService for ADS {
+    type StreamAggregatedResourcesStream = Pin<
+        Box<dyn Stream<Item = Result<DiscoveryResponse, tonic::Status>> + Send + Sync + 'static>,
+    >;
+
+    type DeltaAggregatedResourcesStream = Pin<
+        Box<
+            dyn Stream<Item = Result<DeltaDiscoveryResponse, tonic::Status>>
+                + Send
+                + Sync
+                + 'static,
+        >,
+    >;
+
+    async fn stream_aggregated_resources(
+        &self,
+        request: Request<Streaming<DiscoveryRequest>>,
+    ) -> Result<Response<Self::StreamAggregatedResourcesStream>, Status> {
+        tracing::info!("Stream aggregated resources request");
+        let responses = stream(
+            Arc::clone(&self.cache),
+            self.statuses.clone(),
+            self.shutdown.clone(),
+            None,
+            Authorized::of(request.metadata()),
+            request.into_inner(),
+        );
+        Ok(Response::new(
+            Box::pin(responses) as Self::StreamAggregatedResourcesStream
+        ))
+    }
+
+    async fn delta_aggregated_resources(
+        &self,
+        _request: Request<Streaming<DeltaDiscoveryRequest>>,
+    ) -> Result<Response<Self::DeltaAggregatedResourcesStream>, Status> {
+        tracing::debug!("Delta aggregated resources request");
+        Err(Status::unimplemented("not implemented"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::RwLock;
+
+    use crate::configuration;
+    use crate::envoy_helpers::{get_envoy_cluster, EnvoyExport, EnvoyResource};
+    use crate::protobuf::envoy::config::core::v3::Node;
+    use crate::protobuf::envoy::config::listener::v3::Listener;
+    use prost::Message;
+
+    fn exports(port: u32) -> Vec<EnvoyExport> {
+        vec![
+            EnvoyExport {
+                key: "service::id::1::cluster".to_string(),
+                config: EnvoyResource::Cluster(
+                    get_envoy_cluster(
+                        "one".to_string(),
+                        &format!("http://one:{}", port),
+                        &Default::default(),
+                    )
+                    .unwrap(),
+                ),
+            },
+            EnvoyExport {
+                key: "service::id::1::listener".to_string(),
+                config: EnvoyResource::Listener(Listener {
+                    name: format!("listener {}", port),
+                    ..Default::default()
+                }),
+            },
+        ]
+    }
+
+    fn request(type_url: &str, nonce: &str) -> DiscoveryRequest {
+        DiscoveryRequest {
+            type_url: type_url.to_string(),
+            response_nonce: nonce.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn stale_nonces_are_ignored() {
+        let snapshot = Snapshot::new(1, &exports(80));
+        let mut state = DiscoveryState::new(None);
+        state.on_request(&request(CLUSTER_TYPE_URL, ""));
+        let (first, _) = state.responses(&snapshot).remove(0);
+
+        // the client changing its mind about an old response
+        let mut stale = request(CLUSTER_TYPE_URL, "stale");
+        stale.resource_names = vec!["two".to_string()];
+        state.on_request(&stale);
+        assert!(state.responses(&snapshot).is_empty());
+
+        state.on_request(&request(CLUSTER_TYPE_URL, &first.nonce));
+        assert!(state.responses(&snapshot).is_empty());
+    }
+
+    #[tokio::test]
+    async fn multiplexes_types_on_one_stream() {
+        let config = Arc::new(RwLock::new(configuration::Config::default()));
+        config.write().unwrap().import(
+            Vec::new(),
+            "a".to_string(),
+            vec![(1, exports(80).into())].into_iter().collect(),
+        );
+
+        // stub ADS client asking for listeners, clusters and routes
+        let (mut client, requests) = mpsc::channel(8);
+        let mut responses = stream(
+            config.clone(),
+            NodeStatuses::default(),
+            Shutdown::default(),
+            None,
+            Authorized::default(),
+            requests,
+        );
+        for type_url in &[LISTENER_TYPE_URL, CLUSTER_TYPE_URL, ROUTE_TYPE_URL] {
+            client.send(Ok(request(type_url, ""))).await.unwrap();
+        }
+
+        let mut initial = HashMap::new();
+        while initial.len() < 3 {
+            let response = responses.next().await.unwrap().unwrap();
+            initial.insert(response.type_url.clone(), response);
+        }
+        assert_eq!(initial[LISTENER_TYPE_URL].resources.len(), 1);
+        assert_eq!(initial[CLUSTER_TYPE_URL].resources.len(), 1);
+        // routes are inlined into the listeners
+        assert!(initial[ROUTE_TYPE_URL].resources.is_empty());
+
+        // a new version is pushed clusters first, and routes didn't change
+        config.write().unwrap().import(
+            Vec::new(),
+            "b".to_string(),
+            vec![(1, exports(8080).into())].into_iter().collect(),
+        );
+        client
+            .send(Ok(request(ROUTE_TYPE_URL, &initial[ROUTE_TYPE_URL].nonce)))
+            .await
+            .unwrap();
+
+        let snapshot = config.read().unwrap().get_snapshot();
+        let mut order = Vec::new();
+        for _ in 0..2 {
+            let response = responses.next().await.unwrap().unwrap();
+            assert_eq!(
+                response.version_info,
+                snapshot.type_version(&response.type_url)
+            );
+            order.push(response.type_url);
+        }
+        assert_eq!(order, [CLUSTER_TYPE_URL, LISTENER_TYPE_URL]);
+    }
+
+    fn grouped_service(id: u32, groups: &[&str]) -> crate::service::Service {
+        serde_json::from_value(serde_json::json!({
+            "id": id,
+            "hosts": [],
+            "policies": [],
+            "target_domain": "http://one:80",
+            "proxy_rules": [],
+            "node_groups": groups,
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn node_groups_receive_disjoint_listeners() {
+        let config = Arc::new(RwLock::new(configuration::Config::default()));
+        let services = vec![
+            grouped_service(1, &["public"]),
+            grouped_service(2, &["internal"]),
+            grouped_service(3, &["public", "internal"]),
+        ];
+        let exports = (1..=3)
+            .map(|id| {
+                let listener = EnvoyExport {
+                    key: format!("service::id::{}::listener", id),
+                    config: EnvoyResource::Listener(Listener {
+                        name: format!("listener {}", id),
+                        ..Default::default()
+                    }),
+                };
+                (id, vec![listener].into())
+            })
+            .collect();
+        config
+            .write()
+            .unwrap()
+            .import(services, "a".to_string(), exports);
+
+        for (group, expected) in &[
+            ("public", ["listener 1", "listener 3"]),
+            ("internal", ["listener 2", "listener 3"]),
+        ] {
+            // fake Envoy of the group, telling it in its node metadata
+            let (mut client, requests) = mpsc::channel(1);
+            let mut responses = stream(
+                config.clone(),
+                NodeStatuses::default(),
+                Shutdown::default(),
+                Some(LISTENER_TYPE_URL),
+                Authorized::default(),
+                requests,
+            );
+            let mut metadata = prost_types::Struct::default();
+            metadata.fields.insert(
+                configuration::NODE_GROUP_METADATA.to_string(),
+                prost_types::Value {
+                    kind: Some(prost_types::value::Kind::StringValue(group.to_string())),
+                },
+            );
+            let mut discovery = request(LISTENER_TYPE_URL, "");
+            discovery.node = Some(Node {
+                id: format!("{}-envoy", group),
+                metadata: Some(metadata),
+                ..Default::default()
+            });
+            client.send(Ok(discovery)).await.unwrap();
+
+            let response = responses.next().await.unwrap().unwrap();
+            let names: Vec<std::string::String> = response
+                .resources
+                .iter()
+                .map(|resource| Listener::decode(resource.value.as_slice()).unwrap().name)
+                .collect();
+            assert_eq!(&names, expected);
+        }
+    }
+}
@@ -1,104 +1,35 @@
 use std::pin::Pin;
-use std::sync::{Arc, RwLock};
-use std::task::{Context, Poll};
-use std::thread;
-use std::time;
+use std::sync::Arc;
 use tokio::stream::Stream;
 use tonic::{Request, Response, Status, Streaming};
 
-use crate::configuration;
+use crate::envoy_ads;
+use crate::envoy_delta;
 use crate::envoy_helpers;
-use crate::protobuf::envoy::config::cluster::v3::Cluster;
+use crate::node_status::NodeStatuses;
 use crate::protobuf::envoy::service::cluster::v3::cluster_discovery_service_server::ClusterDiscoveryService;
 use crate::protobuf::envoy::service::discovery::v3::{
     DeltaDiscoveryRequest, DeltaDiscoveryResponse, DiscoveryRequest, DiscoveryResponse,
 };
+use crate::shutdown::Shutdown;
+use crate::snapshot_cache::SnapshotCache;
+use crate::xds_auth::Authorized;
 
 #[derive(Debug, Clone)]
 pub struct CDS {
-    clusters: Vec<Cluster>,
-    version: u32,
-    config: Arc<RwLock<configuration::Config>>,
+    cache: Arc<dyn SnapshotCache>,
+    statuses: NodeStatuses,
+    shutdown: Shutdown,
 }
 
 impl CDS {
-    pub fn new(config: Arc<RwLock<configuration::Config>>) -> CDS {
+    pub fn new(cache: Arc<dyn SnapshotCache>, statuses: NodeStatuses, shutdown: Shutdown) -> CDS {
         CDS {
-            clusters: Vec::new(),
-            version: 0,
-            config,
+            cache,
+            statuses,
+            shutdown,
         }
     }
-
-    pub fn refresh_data(&mut self) {
-        let cfg = self.config.read().unwrap();
-        if cfg.get_version() <= self.version {
-            return;
-        }
-
-        let mut new_clusters: Vec<Cluster> = Vec::new();
-        let services = cfg.export_config_to_envoy();
-        for k in &services {
-            match &k.config {
-                envoy_helpers::EnvoyResource::Cluster(c) => new_clusters.push(c.clone()),
-                envoy_helpers::EnvoyResource::Listener(_) => continue,
-            }
-        }
-
-        self.clusters = new_clusters;
-        self.version = cfg.get_version();
-    }
-}
-
-impl tokio::stream::Stream for CDS {
-    type Item = Result<DiscoveryResponse, tonic::Status>;
-
-    fn poll_next(
-        mut self: std::pin::Pin<&mut Self>,
-        ctx: &mut Context<'_>,
-    ) -> Poll<Option<Self::Item>> {
-        let mut send_data = false;
-        {
-            let cfg = self.config.clone();
-            let version = cfg.read().unwrap().get_version();
-            if self.version != version {
-                send_data = true;
-            }
-        }
-
-        if !(send_data) {
-            // @TODO config should return a future when a config is updated
-            log::trace!("Sleep CDS because no changes made");
-            let waker = ctx.waker().clone();
-            thread::spawn(move || {
-                thread::sleep(time::Duration::from_secs(5));
-                waker.wake();
-            });
-            return Poll::Pending;
-        }
-        log::info!("Refreshing CDS config due a version mistmatch");
-        self.refresh_data();
-
-        let mut clusters: Vec<prost_types::Any> = Vec::new();
-
-        for cds_cluster in &self.clusters {
-            let mut buf = Vec::new();
-            prost::Message::encode(cds_cluster, &mut buf).unwrap();
-            clusters.push(prost_types::Any {
-                type_url: "type.googleapis.com/envoy.config.cluster.v3.Cluster".to_string(),
-                value: buf,
-            });
-        }
-
-        let discovery = DiscoveryResponse {
-            version_info: self.version.to_string(),
-            type_url: "type.googleapis.com/envoy.config.cluster.v3.Cluster".to_string(),
-            resources: clusters,
-            ..Default::default()
-        };
-
-        Poll::Ready(Some(Ok(discovery)))
-    }
 }
 
 #[tonic::async_trait]
@@ -118,20 +49,38 @@ impl ClusterDiscoveryService for CDS {
 
     async fn stream_clusters(
         &self,
-        _request: Request<tonic::Streaming<DiscoveryRequest>>,
+        request: Request<tonic::Streaming<DiscoveryRequest>>,
     ) -> Result<Response<Self::StreamClustersStream>, Status> {
-        log::info!("Stream cluster request");
+        tracing::info!("Stream cluster request");
+        let responses = envoy_ads::stream(
+            Arc::clone(&self.cache),
+            self.statuses.clone(),
+            self.shutdown.clone(),
+            Some(envoy_helpers::CLUSTER_TYPE_URL),
+            Authorized::of(request.metadata()),
+            request.into_inner(),
+        );
         Ok(Response::new(
-            Box::pin(self.clone()) as Self::StreamClustersStream
+            Box::pin(responses) as Self::StreamClustersStream
         ))
     }
 
     async fn delta_clusters(
         &self,
-        _request: Request<Streaming<DeltaDiscoveryRequest>>,
+        request: Request<Streaming<DeltaDiscoveryRequest>>,
     ) -> Result<Response<Self::DeltaClustersStream>, Status> {
-        println!("Delta cluster");
-        Err(Status::unimplemented("not implemented"))
+        tracing::info!("Delta cluster request");
+        let responses = envoy_delta::stream(
+            Arc::clone(&self.cache),
+            self.statuses.clone(),
+            self.shutdown.clone(),
+            envoy_helpers::CLUSTER_TYPE_URL,
+            Authorized::of(request.metadata()),
+            request.into_inner(),
+        );
+        Ok(Response::new(
+            Box::pin(responses) as Self::DeltaClustersStream
+        ))
     }
 
     async fn fetch_clusters(
@@ -0,0 +1,405 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time;
+
+use futures::{Stream, StreamExt};
+use tokio::sync::mpsc;
+use tonic::Status;
+
+use crate::configuration;
+use crate::node_status::NodeStatuses;
+use crate::protobuf::envoy::service::discovery::v3::{
+    DeltaDiscoveryRequest, DeltaDiscoveryResponse, Resource,
+};
+use crate::shutdown::{self, Shutdown};
+use crate::snapshot::Snapshot;
+use crate::snapshot_cache::SnapshotCache;
+use crate::xds_auth::Authorized;
+
+/// How often the discovery streams, delta or not, look for a new snapshot
+/// version.
+pub const POLL_INTERVAL: time::Duration = time::Duration::from_secs(5);
+
+/// Per-stream state of an incremental xDS subscription: what the client
+/// subscribed to and which version of each resource it has been sent.
+#[derive(Debug)]
+pub struct DeltaState {
+    type_url: &'static str,
+    wildcard: bool,
+    subscribed: HashSet<std::string::String>,
+    sent: HashMap<std::string::String, std::string::String>,
+    nonce: u64,
+    initialized: bool,
+}
+
+impl DeltaState {
+    pub fn new(type_url: &'static str) -> DeltaState {
+        DeltaState {
+            type_url,
+            wildcard: false,
+            subscribed: HashSet::new(),
+            sent: HashMap::new(),
+            nonce: 0,
+            initialized: false,
+        }
+    }
+
+    /// Apply the subscription changes of a client request.
+    pub fn on_request(&mut self, request: &DeltaDiscoveryRequest) {
+        if !self.initialized {
+            self.initialized = true;
+            // an empty initial subscription asks for everything
+            self.wildcard = request.resource_names_subscribe.is_empty();
+            // resources the client already has from a previous stream
+            for (name, version) in &request.initial_resource_versions {
+                self.sent.insert(name.clone(), version.clone());
+            }
+        }
+
+        if let Some(ref error) = request.error_detail {
+            tracing::warn!(
+                type_url = self.type_url,
+                nonce = %request.response_nonce,
+                "Envoy rejected the delta: {}",
+                error.message
+            );
+        }
+
+        for name in &request.resource_names_subscribe {
+            if name == "*" {
+                self.wildcard = true;
+            } else if self.subscribed.insert(name.clone()) && !self.wildcard {
+                // resend it, even if it was sent before unsubscribing
+                self.sent.remove(name);
+            }
+        }
+        for name in &request.resource_names_unsubscribe {
+            if name == "*" {
+                self.wildcard = false;
+            } else {
+                self.subscribed.remove(name);
+            }
+            if !self.wildcard {
+                self.sent.remove(name);
+            }
+        }
+    }
+
+    fn wants(&self, name: &str) -> bool {
+        self.wildcard || self.subscribed.contains(name)
+    }
+
+    /// The response bringing the client up to date with `snapshot`, if it is
+    /// missing anything.
+    pub fn response(&mut self, snapshot: &Snapshot) -> Option<DeltaDiscoveryResponse> {
+        if !self.initialized {
+            return None;
+        }
+
+        let mut resources = Vec::new();
+        let mut names = HashSet::new();
+        if let Some(current) = snapshot.resources(self.type_url) {
+            for (name, resource) in current {
+                if !self.wants(name) {
+                    continue;
+                }
+                names.insert(name.as_str());
+                if self.sent.get(name) == Some(&resource.version) {
+                    continue;
+                }
+                resources.push(Resource {
+                    name: resource.name.clone(),
+                    version: resource.version.clone(),
+                    resource: Some(resource.resource.clone()),
+                    ..Default::default()
+                });
+            }
+        }
+
+        let mut removed_resources: Vec<std::string::String> = self
+            .sent
+            .keys()
+            .filter(|name| !names.contains(name.as_str()))
+            .cloned()
+            .collect();
+        removed_resources.sort();
+
+        if resources.is_empty() && removed_resources.is_empty() {
+            return None;
+        }
+
+        for resource in &resources {
+            self.sent
+                .insert(resource.name.clone(), resource.version.clone());
+        }
+        for name in &removed_resources {
+            self.sent.remove(name);
+        }
+        self.nonce += 1;
+
+        Some(DeltaDiscoveryResponse {
+            system_version_info: snapshot.version().to_string(),
+            resources,
+            type_url: self.type_url.to_string(),
+            removed_resources,
+            nonce: self.nonce.to_string(),
+            ..Default::default()
+        })
+    }
+}
+
+/// Serve an incremental xDS stream of `type_url` resources, sending changes
+/// as the client subscribes and as new snapshots are published.
+pub fn stream<S>(
+    cache: Arc<dyn SnapshotCache>,
+    statuses: NodeStatuses,
+    shutdown: Shutdown,
+    type_url: &'static str,
+    authorized: Authorized,
+    mut requests: S,
+) -> mpsc::Receiver<Result<DeltaDiscoveryResponse, Status>>
+where
+    S: Stream<Item = Result<DeltaDiscoveryRequest, Status>> + Unpin + Send + 'static,
+{
+    let (mut tx, rx) = mpsc::channel(4);
+    tokio::spawn(async move {
+        if shutdown.is_draining() {
+            let _ = tx.send(Err(shutdown::refused())).await;
+            return;
+        }
+        let mut state = DeltaState::new(type_url);
+        let mut status = statuses.stream();
+        let mut group = None;
+        let mut versions = cache.subscribe();
+        loop {
+            tokio::select! {
+                request = requests.next() => match request {
+                    Some(Ok(request)) => {
+                        if group.is_none() {
+                            match authorized.snapshot_key(&*cache, request.node.as_ref()) {
+                                Ok(key) => group = Some(key),
+                                Err(refused) => {
+                                    let _ = tx.send(Err(refused)).await;
+                                    break;
+                                }
+                            }
+                        }
+                        status.on_request(
+                            request
+                                .node
+                                .as_ref()
+                                .zip(group.as_deref())
+                                .map(|(node, key)| configuration::node_key(key, &node.id))
+                                .as_deref(),
+                            type_url,
+                            None,
+                            &request.response_nonce,
+                            request.error_detail.as_ref(),
+                        );
+                        state.on_request(&request);
+                    }
+                    Some(Err(e)) => {
+                        tracing::debug!("Delta {} stream failed: {:?}", type_url, e);
+                        break;
+                    }
+                    None => break,
+                },
+                // a new version, or one the stream missed
+                Some(_) = versions.next() => {}
+                _ = tokio::time::delay_for(POLL_INTERVAL) => {}
+                _ = shutdown.wait() => {
+                    if let Some(end) = shutdown.stream_end() {
+                        let _ = tx.send(Err(end)).await;
+                    }
+                    break;
+                }
+            }
+
+            let snapshot = match group {
+                Some(ref group) => cache.snapshot(group),
+                None => continue,
+            };
+            if let Some(response) = state.response(&snapshot) {
+                tracing::info!(
+                    type_url,
+                    snapshot = snapshot.version(),
+                    node = status.node(),
+                    updated = response.resources.len(),
+                    removed = response.removed_resources.len(),
+                    "Sending delta"
+                );
+                status.sent(
+                    type_url,
+                    &response.system_version_info,
+                    &response.nonce,
+                    response.resources.iter().map(|r| r.name.clone()).collect(),
+                );
+                if tx.send(Ok(response)).await.is_err() {
+                    break;
+                }
+            }
+        }
+        tracing::debug!("Delta {} stream closed", type_url);
+    });
+    rx
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::RwLock;
+
+    use crate::configuration;
+    use crate::envoy_helpers::{get_envoy_cluster, EnvoyExport, EnvoyResource, CLUSTER_TYPE_URL};
+
+    fn exports(clusters: &[(&str, &str)]) -> Vec<EnvoyExport> {
+        clusters
+            .iter()
+            .map(|(name, url)| EnvoyExport {
+                key: format!("{}::cluster", name),
+                config: EnvoyResource::Cluster(
+                    get_envoy_cluster(name.to_string(), url, &Default::default()).unwrap(),
+                ),
+            })
+            .collect()
+    }
+
+    fn request(subscribe: &[&str], unsubscribe: &[&str]) -> DeltaDiscoveryRequest {
+        DeltaDiscoveryRequest {
+            type_url: CLUSTER_TYPE_URL.to_string(),
+            resource_names_subscribe: subscribe.iter().map(|s| s.to_string()).collect(),
+            resource_names_unsubscribe: unsubscribe.iter().map(|s| s.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    fn names(response: &DeltaDiscoveryResponse) -> Vec<&str> {
+        response.resources.iter().map(|r| r.name.as_str()).collect()
+    }
+
+    #[test]
+    fn wildcard_only_resends_changed_resources() {
+        let v1 = Snapshot::new(
+            1,
+            &exports(&[("one", "http://one:80"), ("two", "http://two:80")]),
+        );
+        let v2 = Snapshot::new(
+            2,
+            &exports(&[("one", "http://one:80"), ("two", "http://two:8080")]),
+        );
+        let v3 = Snapshot::new(3, &exports(&[("two", "http://two:8080")]));
+
+        let mut state = DeltaState::new(CLUSTER_TYPE_URL);
+        state.on_request(&request(&[], &[]));
+
+        let response = state.response(&v1).unwrap();
+        assert_eq!(names(&response), ["one", "two"]);
+        assert_eq!(response.system_version_info, "1");
+        assert!(state.response(&v1).is_none());
+
+        let response = state.response(&v2).unwrap();
+        assert_eq!(names(&response), ["two"]);
+        assert!(response.removed_resources.is_empty());
+
+        let response = state.response(&v3).unwrap();
+        assert!(response.resources.is_empty());
+        assert_eq!(response.removed_resources, ["one"]);
+        assert_ne!(response.nonce, "");
+    }
+
+    #[test]
+    fn explicit_subscriptions() {
+        let v1 = Snapshot::new(
+            1,
+            &exports(&[("one", "http://one:80"), ("two", "http://two:80")]),
+        );
+
+        let mut state = DeltaState::new(CLUSTER_TYPE_URL);
+        state.on_request(&request(&["two"], &[]));
+        assert_eq!(names(&state.response(&v1).unwrap()), ["two"]);
+
+        state.on_request(&request(&["one"], &[]));
+        assert_eq!(names(&state.response(&v1).unwrap()), ["one"]);
+
+        // unsubscribing needs no response, and subscribing again resends
+        state.on_request(&request(&[], &["two"]));
+        assert!(state.response(&v1).is_none());
+        state.on_request(&request(&["two"], &[]));
+        assert_eq!(names(&state.response(&v1).unwrap()), ["two"]);
+    }
+
+    #[test]
+    fn initial_versions_are_not_resent() {
+        let v1 = Snapshot::new(
+            1,
+            &exports(&[("one", "http://one:80"), ("two", "http://two:80")]),
+        );
+        let version = v1.resources(CLUSTER_TYPE_URL).unwrap()["one"]
+            .version
+            .clone();
+
+        let mut state = DeltaState::new(CLUSTER_TYPE_URL);
+        let mut initial = request(&[], &[]);
+        initial
+            .initial_resource_versions
+            .insert("one".to_string(), version);
+        initial
+            .initial_resource_versions
+            .insert("gone".to_string(), "0".to_string());
+        state.on_request(&initial);
+
+        let response = state.response(&v1).unwrap();
+        assert_eq!(names(&response), ["two"]);
+        assert_eq!(response.removed_resources, ["gone"]);
+    }
+
+    #[tokio::test]
+    async fn stream_pushes_a_single_service_change() {
+        let config = Arc::new(RwLock::new(configuration::Config::default()));
+        config.write().unwrap().import(
+            Vec::new(),
+            "a".to_string(),
+            vec![(
+                1,
+                exports(&[("one", "http://one:80"), ("two", "http://two:80")]).into(),
+            )]
+            .into_iter()
+            .collect(),
+        );
+
+        // fake Envoy: subscribe to everything and then stay connected
+        let (mut client, requests) = mpsc::channel(4);
+        let mut responses = stream(
+            config.clone(),
+            NodeStatuses::default(),
+            Shutdown::default(),
+            CLUSTER_TYPE_URL,
+            Authorized::default(),
+            requests,
+        );
+        client.send(Ok(request(&[], &[]))).await.unwrap();
+
+        let response = responses.next().await.unwrap().unwrap();
+        assert_eq!(names(&response), ["one", "two"]);
+
+        config.write().unwrap().import(
+            Vec::new(),
+            "b".to_string(),
+            vec![(
+                1,
+                exports(&[("one", "http://one:80"), ("two", "http://two:8080")]).into(),
+            )]
+            .into_iter()
+            .collect(),
+        );
+        // ACK the first response, which also wakes up the stream
+        let mut ack = request(&[], &[]);
+        ack.response_nonce = response.nonce;
+        client.send(Ok(ack)).await.unwrap();
+
+        let response = responses.next().await.unwrap().unwrap();
+        assert_eq!(names(&response), ["two"]);
+        assert_eq!(response.system_version_info, "2");
+        assert!(response.removed_resources.is_empty());
+    }
+}
@@ -12,14 +12,27 @@ use crate::protobuf::envoy::config::endpoint::v3::Endpoint;
 use crate::protobuf::envoy::config::endpoint::v3::LbEndpoint;
 use crate::protobuf::envoy::config::endpoint::v3::LocalityLbEndpoints;
 use crate::protobuf::envoy::config::listener::v3::Listener;
+use crate::protobuf::envoy::extensions::transport_sockets::tls::v3::Secret;
 
+use prost_types::value::Kind;
 use prost_types::Duration;
 
 use anyhow::Result;
 use url::Url;
 
+use crate::type_urls::TypeUrls;
+
 pub type EnvoyExportList = Vec<EnvoyExport>;
 
+/// The exports of a service as the export cache, the snapshots of its node
+/// groups and the rollback history all keep them, shared rather than copied
+/// at every reload.
+pub type SharedExports = std::sync::Arc<[EnvoyExport]>;
+
+pub use crate::type_urls::{
+    CLUSTER_TYPE_URL, ENDPOINT_TYPE_URL, LISTENER_TYPE_URL, ROUTE_TYPE_URL, SECRET_TYPE_URL,
+};
+
 // These are structs to export config to the config:cache
 // Variables shouldn't be public at all.
 #[derive(Debug, Clone)]
@@ -28,17 +41,85 @@ pub struct EnvoyExport {
     pub config: EnvoyResource,
 }
 
+// Few of these live at a time, boxing the variants isn't worth it.
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug, Clone)]
 pub enum EnvoyResource {
     Cluster(Cluster),
     Listener(Listener),
+    Secret(Secret),
+}
+
+impl EnvoyResource {
+    /// The name Envoy knows the resource by.
+    pub fn name(&self) -> &str {
+        match self {
+            EnvoyResource::Cluster(cluster) => &cluster.name,
+            EnvoyResource::Listener(listener) => &listener.name,
+            EnvoyResource::Secret(secret) => &secret.name,
+        }
+    }
+
+    pub fn type_url(&self) -> &'static str {
+        match self {
+            EnvoyResource::Cluster(_) => CLUSTER_TYPE_URL,
+            EnvoyResource::Listener(_) => LISTENER_TYPE_URL,
+            EnvoyResource::Secret(_) => SECRET_TYPE_URL,
+        }
+    }
+
+    /// The resource in an `Any` of its upstream type, the one the discovery
+    /// requests name whatever the type URL overrides.
+    pub fn to_any(&self) -> Result<prost_types::Any> {
+        let upstream = TypeUrls::default();
+        match self {
+            EnvoyResource::Cluster(cluster) => upstream.pack(cluster),
+            EnvoyResource::Listener(listener) => upstream.pack(listener),
+            EnvoyResource::Secret(secret) => upstream.pack(secret),
+        }
+    }
 }
 
+/// The SHA-256 of `resource` as sent to Envoy, equal for equal resources
+/// however they were built: prost encodes the fields in the order of their
+/// tags, the maps being `BTreeMap`s in the order of their keys, and the
+/// `Any`s within the resource are packed by `TypeUrls::pack` alike. The versions of
+/// the snapshots, the claims of the names of the resources and the golden
+/// digests all hash them this way.
+pub fn canonical_hash(resource: &EnvoyResource) -> [u8; 32] {
+    let mut data = Vec::new();
+    // a vector grows as needed, encoding into one can't fail
+    let _ = match resource {
+        EnvoyResource::Cluster(cluster) => prost::Message::encode(cluster, &mut data),
+        EnvoyResource::Listener(listener) => prost::Message::encode(listener, &mut data),
+        EnvoyResource::Secret(secret) => prost::Message::encode(secret, &mut data),
+    };
+    let mut hash = [0; 32];
+    hash.copy_from_slice(ring::digest::digest(&ring::digest::SHA256, &data).as_ref());
+    hash
+}
+
+/// Sort `exports` by key, for the same resources to always come in the
+/// same order, failing when two of them share a key.
+pub fn sort_by_key(exports: &mut EnvoyExportList) -> Result<()> {
+    exports.sort_by(|a, b| a.key.cmp(&b.key));
+    match exports.windows(2).find(|pair| pair[0].key == pair[1].key) {
+        Some(pair) => Err(anyhow::anyhow!(
+            "resource key '{}' is exported twice",
+            pair[0].key
+        )),
+        None => Ok(()),
+    }
+}
+
+/// The cluster of `target_url`, over TLS for `https` ones, the TLS context
+/// being packed as `type_urls` say.
 pub fn get_envoy_cluster(
     name: std::string::String,
-    target_url: std::string::String,
+    target_url: &str,
+    type_urls: &TypeUrls,
 ) -> Result<Cluster> {
-    let target_host = Url::parse(target_url.as_str())?;
+    let target_host = Url::parse(target_url)?;
 
     let socketaddress = AddressType::SocketAddress(SocketAddress {
         address: target_host.host_str().unwrap().to_string(),
@@ -80,23 +161,116 @@ pub fn get_envoy_cluster(
         use crate::protobuf::envoy::extensions::transport_sockets::tls::v3::UpstreamTlsContext;
         cluster.transport_socket = Some(TransportSocket {
             name: "envoy.transport_sockets.tls".to_string(),
-            config_type: Some(ConfigType::TypedConfig(prost_types::Any {
-                type_url:
-                    "type.googleapis.com/envoy.extensions.transport_sockets.tls.v3.UpstreamTlsContext"
-                        .to_string(),
-                value: encode(UpstreamTlsContext {
+            config_type: Some(ConfigType::TypedConfig(type_urls.pack(
+                &UpstreamTlsContext {
                     sni: target_host.host_str().unwrap().to_string(),
                     ..Default::default()
-                })
-                .unwrap(),
-            })),
+                },
+            )?)),
         })
     }
     Ok(cluster)
 }
 
-pub fn encode(arg: impl prost::Message) -> Result<Vec<u8>> {
+/// Convert a JSON object into a `google.protobuf.Struct`.
+pub fn json_to_struct(value: serde_json::Value) -> Result<prost_types::Struct> {
+    match json_to_value(value).kind {
+        Some(Kind::StructValue(result)) => Ok(result),
+        _ => Err(anyhow::anyhow!(
+            "only JSON objects can be converted to a Struct"
+        )),
+    }
+}
+
+fn json_to_value(value: serde_json::Value) -> prost_types::Value {
+    let kind = match value {
+        serde_json::Value::Null => Kind::NullValue(0),
+        serde_json::Value::Bool(b) => Kind::BoolValue(b),
+        // Struct only knows about doubles
+        serde_json::Value::Number(n) => Kind::NumberValue(n.as_f64().unwrap_or_default()),
+        serde_json::Value::String(s) => Kind::StringValue(s),
+        serde_json::Value::Array(values) => Kind::ListValue(prost_types::ListValue {
+            values: values.into_iter().map(json_to_value).collect(),
+        }),
+        serde_json::Value::Object(map) => Kind::StructValue(prost_types::Struct {
+            fields: map
+                .into_iter()
+                .map(|(k, v)| (k, json_to_value(v)))
+                .collect(),
+        }),
+    };
+    prost_types::Value { kind: Some(kind) }
+}
+
+pub fn encode(arg: &impl prost::Message) -> Result<Vec<u8>> {
     let mut buf = Vec::new();
-    prost::Message::encode(&arg, &mut buf)?;
+    prost::Message::encode(arg, &mut buf)?;
     Ok(buf)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protobuf::envoy::config::core::v3::Metadata;
+
+    fn cluster(name: &str, url: &str) -> Cluster {
+        get_envoy_cluster(name.to_string(), url, &Default::default()).unwrap()
+    }
+
+    #[test]
+    fn equal_resources_hash_equal_and_any_change_tells() {
+        let urls = ["http://one:80", "https://one:443", "http://two:8080"];
+        for url in &urls {
+            let resource = EnvoyResource::Cluster(cluster("backend", url));
+            // built again, or cloned, the same
+            let rebuilt = EnvoyResource::Cluster(cluster("backend", url));
+            assert_eq!(canonical_hash(&resource), canonical_hash(&rebuilt));
+            assert_eq!(canonical_hash(&resource), canonical_hash(&resource.clone()));
+        }
+
+        let base = cluster("backend", "https://one:443");
+        let changes: Vec<fn(&mut Cluster)> = vec![
+            |cluster| cluster.name.push('2'),
+            |cluster| {
+                cluster.connect_timeout = Some(Duration {
+                    seconds: 2,
+                    nanos: 0,
+                })
+            },
+            |cluster| cluster.dns_refresh_rate = None,
+            |cluster| {
+                cluster
+                    .load_assignment
+                    .as_mut()
+                    .unwrap()
+                    .cluster_name
+                    .clear()
+            },
+            |cluster| cluster.transport_socket.as_mut().unwrap().name.push('2'),
+            |cluster| cluster.respect_dns_ttl = true,
+        ];
+        let mut hashes = vec![canonical_hash(&EnvoyResource::Cluster(base.clone()))];
+        for change in changes {
+            let mut changed = base.clone();
+            change(&mut changed);
+            hashes.push(canonical_hash(&EnvoyResource::Cluster(changed)));
+        }
+        let unique: std::collections::BTreeSet<_> = hashes.iter().collect();
+        assert_eq!(unique.len(), hashes.len());
+
+        // maps hash the same whatever order their entries were added in
+        let metadata = |keys: &[&str]| {
+            let mut metadata = Metadata::default();
+            for key in keys {
+                metadata
+                    .filter_metadata
+                    .insert(key.to_string(), prost_types::Struct::default());
+            }
+            let mut cluster = base.clone();
+            cluster.metadata = Some(metadata);
+            canonical_hash(&EnvoyResource::Cluster(cluster))
+        };
+        assert_eq!(metadata(&["a", "b", "c"]), metadata(&["c", "a", "b"]));
+        assert_ne!(metadata(&["a", "b"]), metadata(&["a", "b", "c"]));
+    }
+}
@@ -0,0 +1,298 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use serde::Deserialize;
+use warp::http::StatusCode;
+use warp::Filter;
+
+use crate::envoy_helpers::{
+    self, CLUSTER_TYPE_URL, ENDPOINT_TYPE_URL, LISTENER_TYPE_URL, ROUTE_TYPE_URL, SECRET_TYPE_URL,
+};
+use crate::leader::Leadership;
+use crate::proto_json::Registry;
+use crate::protobuf::envoy::config::core::v3::Node;
+use crate::snapshot_cache::SnapshotCache;
+use crate::xds_auth::{Authorized, XdsAuth};
+
+// Discovery requests are small, whatever the size of the responses.
+const REQUEST_BODY_LIMIT: u64 = 1024 * 1024;
+
+/// A `DiscoveryRequest` in protobuf JSON, with the fields the fetches use,
+/// named either way protobuf JSON names them.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+struct FetchRequest {
+    #[serde(alias = "versionInfo")]
+    version_info: std::string::String,
+    node: Option<FetchNode>,
+    #[serde(alias = "resourceNames")]
+    resource_names: Vec<std::string::String>,
+    #[serde(alias = "errorDetail")]
+    error_detail: Option<ErrorDetail>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+struct FetchNode {
+    id: std::string::String,
+    cluster: std::string::String,
+    metadata: Option<serde_json::Value>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+struct ErrorDetail {
+    message: std::string::String,
+}
+
+impl FetchNode {
+    fn node(&self) -> Node {
+        Node {
+            id: self.id.clone(),
+            cluster: self.cluster.clone(),
+            // the node groups are read out of the metadata
+            metadata: self
+                .metadata
+                .clone()
+                .and_then(|metadata| envoy_helpers::json_to_struct(metadata).ok()),
+            ..Default::default()
+        }
+    }
+}
+
+// The type of the resources of `/v3/discovery:<resources>`.
+fn type_url(resources: &str) -> Option<&'static str> {
+    match resources {
+        "discovery:clusters" => Some(CLUSTER_TYPE_URL),
+        "discovery:endpoints" => Some(ENDPOINT_TYPE_URL),
+        "discovery:secrets" => Some(SECRET_TYPE_URL),
+        "discovery:listeners" => Some(LISTENER_TYPE_URL),
+        "discovery:routes" => Some(ROUTE_TYPE_URL),
+        _ => None,
+    }
+}
+
+fn refusal(status: tonic::Status, code: StatusCode) -> Box<dyn warp::Reply> {
+    Box::new(warp::reply::with_status(
+        warp::reply::json(&status.message()),
+        code,
+    ))
+}
+
+fn fetch(
+    cache: &dyn SnapshotCache,
+    registry: &Registry,
+    type_url: &'static str,
+    authorized: Authorized,
+    request: FetchRequest,
+) -> Box<dyn warp::Reply> {
+    let node = request.node.as_ref().map(FetchNode::node);
+    let node_id = node.as_ref().map(|node| node.id.as_str()).unwrap_or("");
+    let group = match authorized.snapshot_key(cache, node.as_ref()) {
+        Ok(key) => key,
+        Err(refused) => return refusal(refused, StatusCode::FORBIDDEN),
+    };
+    if let Some(ref error) = request.error_detail {
+        tracing::warn!(
+            node = node_id,
+            type_url,
+            version = %request.version_info,
+            "Envoy rejected the fetched resources: {}",
+            error.message
+        );
+    }
+    let snapshot = cache.snapshot(&group);
+    let version = snapshot.type_version(type_url);
+    // the node runs the version already, that of the resources it asks for
+    // whatever their names
+    if request.version_info == version {
+        return Box::new(StatusCode::NOT_MODIFIED);
+    }
+    let rendered: anyhow::Result<Vec<_>> = snapshot
+        .resources(type_url)
+        .into_iter()
+        .flat_map(|resources| resources.values())
+        .filter(|resource| {
+            request.resource_names.is_empty() || request.resource_names.contains(&resource.name)
+        })
+        .map(|resource| {
+            let mut json = registry.any_to_json(&resource.resource)?;
+            if let Some(fields) = json.as_object_mut() {
+                fields.insert(
+                    "@type".to_string(),
+                    resource.resource.type_url.clone().into(),
+                );
+            }
+            Ok(json)
+        })
+        .collect();
+    match rendered {
+        Ok(resources) => {
+            tracing::info!(
+                type_url,
+                version = %version,
+                snapshot = snapshot.version(),
+                node = node_id,
+                "Fetched resources"
+            );
+            // fetches are answered at once, the version telling the
+            // responses apart as the nonce of a stream would
+            Box::new(warp::reply::json(&serde_json::json!({
+                "version_info": version,
+                "resources": resources,
+                "type_url": type_url,
+                "nonce": version,
+            })))
+        }
+        Err(e) => Box::new(warp::reply::with_status(
+            warp::reply::json(&format!("cannot render the resources: {:#}", e)),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )),
+    }
+}
+
+/// The REST discovery endpoints, `POST /v3/discovery:clusters` and those
+/// of the other types, for the nodes fetching their resources over HTTP
+/// with `api_type: REST` rather than streaming them over gRPC. They serve
+/// the snapshots the streams do, encoded in protobuf JSON. Envoy polls
+/// them, getting a `304 Not Modified` while it runs the current version.
+/// The acks and nacks of those nodes are only logged. The nodes present
+/// the tokens of their identities as they would over gRPC, their client
+/// certificates having no say, and only the leader answers them.
+pub fn routes(
+    cache: Arc<dyn SnapshotCache>,
+    registry: Arc<Registry>,
+    auth: XdsAuth,
+    leadership: Leadership,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("v3" / String)
+        .and(warp::post())
+        .and_then(|resources: String| async move {
+            type_url(&resources).ok_or_else(warp::reject::not_found)
+        })
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::addr::remote())
+        .and(warp::body::content_length_limit(REQUEST_BODY_LIMIT))
+        .and(warp::body::json())
+        .map(
+            move |type_url, authorization: Option<String>, peer: Option<SocketAddr>, request| {
+                if !leadership.is_leader() {
+                    let status = tonic::Status::unavailable("only the leader serves discovery");
+                    return refusal(status, StatusCode::SERVICE_UNAVAILABLE);
+                }
+                let peer = peer.map_or_else(|| "unknown peer".to_string(), |peer| peer.to_string());
+                match auth.authorize(authorization.as_deref(), None, &peer) {
+                    Ok(authorized) => fetch(&*cache, &registry, type_url, authorized, request),
+                    Err(refused) => refusal(refused, StatusCode::UNAUTHORIZED),
+                }
+            },
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configuration::{self, Config};
+    use crate::leader::Role;
+    use crate::service::WasmSettings;
+    use std::sync::RwLock;
+
+    const SERVICES: &str = r#"[
+        {"id": 1, "hosts": ["one.app"], "policies": [], "target_domain": "http://one:80", "proxy_rules": []},
+        {"id": 2, "hosts": ["two.app"], "policies": [], "target_domain": "http://two:80", "proxy_rules": []}
+    ]"#;
+
+    async fn post(
+        routes: &(impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone + 'static),
+        path: &str,
+        request: serde_json::Value,
+    ) -> (StatusCode, Option<serde_json::Value>) {
+        let response = warp::test::request()
+            .method("POST")
+            .path(path)
+            .json(&request)
+            .reply(routes)
+            .await;
+        let body = serde_json::from_slice(response.body()).ok();
+        (response.status(), body)
+    }
+
+    #[tokio::test]
+    async fn nodes_fetch_until_they_run_the_current_version() {
+        let mut config = Config::default();
+        config.set_wasm(WasmSettings {
+            skip_sha: true,
+            ..Default::default()
+        });
+        let config = Arc::new(RwLock::new(config));
+        let services = serde_json::from_str(SERVICES).unwrap();
+        configuration::publish(&config, Config::from_services(services, SERVICES)).unwrap();
+        let routes = routes(
+            config.clone(),
+            Arc::new(Registry::new().unwrap()),
+            XdsAuth::default(),
+            Leadership::default(),
+        );
+
+        let request = serde_json::json!({"node": {"id": "envoy-1", "cluster": "test"}});
+        let (status, body) = post(&routes, "/v3/discovery:clusters", request).await;
+        assert_eq!(status, StatusCode::OK);
+        let body = body.unwrap();
+        assert_eq!(body["type_url"], CLUSTER_TYPE_URL);
+        let names: Vec<_> = body["resources"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|cluster| {
+                assert_eq!(cluster["@type"], CLUSTER_TYPE_URL);
+                cluster["name"].as_str().unwrap()
+            })
+            .collect();
+        assert_eq!(names, ["Cluster::service::1", "Cluster::service::2"]);
+        let version = body["version_info"].as_str().unwrap();
+        let expected = config
+            .read()
+            .unwrap()
+            .get_snapshot()
+            .type_version(CLUSTER_TYPE_URL);
+        assert_eq!(version, expected);
+
+        // nothing new for the version the node runs
+        let request = serde_json::json!({
+            "versionInfo": version,
+            "node": {"id": "envoy-1"},
+            "responseNonce": body["nonce"],
+        });
+        let (status, body) = post(&routes, "/v3/discovery:clusters", request).await;
+        assert_eq!(status, StatusCode::NOT_MODIFIED);
+        assert_eq!(body, None);
+
+        let request = serde_json::json!({"resource_names": ["service 2"]});
+        let (status, body) = post(&routes, "/v3/discovery:listeners", request).await;
+        assert_eq!(status, StatusCode::OK);
+        let listeners = &body.unwrap()["resources"];
+        assert_eq!(listeners.as_array().unwrap().len(), 1);
+        assert_eq!(listeners[0]["name"], "service 2");
+
+        let (status, _) = post(&routes, "/v3/discovery:things", serde_json::json!({})).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn followers_leave_the_fetches_to_the_leader() {
+        let leadership = Leadership::new(Role::Follower, None);
+        let routes = routes(
+            Arc::new(RwLock::new(Config::default())),
+            Arc::new(Registry::new().unwrap()),
+            XdsAuth::default(),
+            leadership.clone(),
+        );
+        let request = serde_json::json!({"node": {"id": "envoy-1"}});
+        let (status, _) = post(&routes, "/v3/discovery:clusters", request.clone()).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+
+        leadership.set(Role::Leader);
+        let (status, _) = post(&routes, "/v3/discovery:clusters", request).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+}
@@ -0,0 +1,95 @@
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio::stream::Stream;
+use tonic::{Request, Response, Status, Streaming};
+
+use crate::envoy_ads;
+use crate::envoy_delta;
+use crate::envoy_helpers;
+use crate::node_status::NodeStatuses;
+use crate::protobuf::envoy::service::discovery::v3::{
+    DeltaDiscoveryRequest, DeltaDiscoveryResponse, DiscoveryRequest, DiscoveryResponse,
+};
+use crate::protobuf::envoy::service::secret::v3::secret_discovery_service_server::SecretDiscoveryService;
+use crate::shutdown::Shutdown;
+use crate::snapshot_cache::SnapshotCache;
+use crate::xds_auth::Authorized;
+
+// named like the CDS and LDS services
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone)]
+pub struct SDS {
+    cache: Arc<dyn SnapshotCache>,
+    statuses: NodeStatuses,
+    shutdown: Shutdown,
+}
+
+impl SDS {
+    pub fn new(cache: Arc<dyn SnapshotCache>, statuses: NodeStatuses, shutdown: Shutdown) -> SDS {
+        SDS {
+            cache,
+            statuses,
+            shutdown,
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl SecretDiscoveryService for SDS {
+    type StreamSecretsStream = Pin<
+        Box<dyn Stream<Item = Result<DiscoveryResponse, tonic::Status>> + Send + Sync + 'static>,
+    >;
+
+    type DeltaSecretsStream = Pin<
+        Box<
+            dyn Stream<Item = Result<DeltaDiscoveryResponse, tonic::Status>>
+                + Send
+                + Sync
+                + 'static,
+        >,
+    >;
+
+    async fn stream_secrets(
+        &self,
+        request: Request<tonic::Streaming<DiscoveryRequest>>,
+    ) -> Result<Response<Self::StreamSecretsStream>, Status> {
+        tracing::info!("Stream secret request");
+        let responses = envoy_ads::stream(
+            Arc::clone(&self.cache),
+            self.statuses.clone(),
+            self.shutdown.clone(),
+            Some(envoy_helpers::SECRET_TYPE_URL),
+            Authorized::of(request.metadata()),
+            request.into_inner(),
+        );
+        Ok(Response::new(
+            Box::pin(responses) as Self::StreamSecretsStream
+        ))
+    }
+
+    async fn delta_secrets(
+        &self,
+        request: Request<Streaming<DeltaDiscoveryRequest>>,
+    ) -> Result<Response<Self::DeltaSecretsStream>, Status> {
+        tracing::info!("Delta secret request");
+        let responses = envoy_delta::stream(
+            Arc::clone(&self.cache),
+            self.statuses.clone(),
+            self.shutdown.clone(),
+            envoy_helpers::SECRET_TYPE_URL,
+            Authorized::of(request.metadata()),
+            request.into_inner(),
+        );
+        Ok(Response::new(
+            Box::pin(responses) as Self::DeltaSecretsStream
+        ))
+    }
+
+    async fn fetch_secrets(
+        &self,
+        _request: Request<DiscoveryRequest>,
+    ) -> Result<Response<DiscoveryResponse>, Status> {
+        tracing::debug!("Fetch secret request");
+        Err(Status::unimplemented("not implemented"))
+    }
+}
@@ -0,0 +1,168 @@
+use anyhow::{bail, Context, Result};
+use serde_json::Value;
+
+use crate::cli::{ControllerConfig, ExportFormat};
+use crate::configuration::Config;
+use crate::envoy_helpers::{EnvoyResource, CLUSTER_TYPE_URL, LISTENER_TYPE_URL};
+use crate::proto_json::Registry;
+use crate::service::WasmSettings;
+use crate::snapshot::Snapshot;
+use crate::util::file_utils;
+
+/// A static Envoy bootstrap serving the resources of `snapshot`, for Envoy
+/// to run without the control plane, along with the cluster the filters
+/// are fetched through.
+pub fn bootstrap(registry: &Registry, snapshot: &Snapshot, wasm: &WasmSettings) -> Result<Value> {
+    let resources = |type_url| -> Result<Vec<Value>> {
+        snapshot
+            .resources(type_url)
+            .into_iter()
+            .flat_map(|resources| resources.values())
+            .map(|resource| {
+                registry
+                    .any_to_json(&resource.resource)
+                    .with_context(|| format!("cannot encode {}", resource.name))
+            })
+            .collect()
+    };
+    let mut clusters = resources(CLUSTER_TYPE_URL)?;
+    clusters.push(registry.any_to_json(&EnvoyResource::Cluster(wasm.files_cluster()?).to_any()?)?);
+    Ok(serde_json::json!({
+        "static_resources": {
+            "listeners": resources(LISTENER_TYPE_URL)?,
+            "clusters": clusters,
+        }
+    }))
+}
+
+pub fn render(document: &Value, format: ExportFormat) -> Result<std::string::String> {
+    match format {
+        ExportFormat::Json => Ok(serde_json::to_string_pretty(document)? + "\n"),
+        ExportFormat::Yaml => Ok(serde_yaml::to_string(document)?),
+    }
+}
+
+/// The services file exported the way it would be served.
+pub fn load(settings: &ControllerConfig) -> Result<Config> {
+    let path = settings.services_config.to_string_lossy();
+    let services = Config::parse_config(&path, settings.parse_options())?;
+    let (exports, errors) =
+        services.export_concurrently(&settings.wasm, settings.export_concurrency);
+    if !errors.is_empty() {
+        bail!(
+            "{} of {} services failed to export, see validate",
+            errors.len(),
+            services.get_services().len()
+        );
+    }
+    let mut config = Config::default();
+    config.import(services.get_services(), services.get_hash(), exports);
+    Ok(config)
+}
+
+/// Write the bootstrap of the export command.
+pub fn run(settings: &ControllerConfig) -> Result<()> {
+    let config = load(settings)?;
+    let group = &settings.export.node_group;
+    if !config.group_snapshots().contains_key(group) {
+        bail!("no service is served to node group '{}'", group);
+    }
+    let document = bootstrap(
+        &Registry::new()?,
+        &config.group_snapshot(group),
+        &settings.wasm,
+    )?;
+    let output = render(&document, settings.export.format)?;
+    match settings.export.output {
+        Some(ref path) => file_utils::write_atomic(path, output.as_bytes()),
+        None => {
+            print!("{}", output);
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configuration::ServicesList;
+    use crate::service::WasmSettings;
+
+    const SERVICES: &str = include_str!("../testdata/bootstrap/services.json");
+    const BOOTSTRAP: &str = include_str!("../testdata/bootstrap/bootstrap.yaml");
+
+    #[test]
+    fn services_render_as_a_static_bootstrap() {
+        // the filters are read, for Envoy to check them against their digests
+        let services = SERVICES.replace(
+            "static/threescale_wasm_auth.wasm",
+            "testdata/bootstrap/static/threescale_wasm_auth.wasm",
+        );
+        let services: ServicesList = serde_json::from_str(&services).unwrap();
+        let wasm = WasmSettings {
+            filter_path: "testdata/bootstrap/static/filter.wasm".into(),
+            ..Default::default()
+        };
+        let exports = services
+            .iter()
+            .map(|service| (service.id, service.export(&wasm).unwrap().into()))
+            .collect();
+        let mut config = Config::default();
+        config.import(services, "services".to_string(), exports);
+
+        let document = bootstrap(&Registry::new().unwrap(), &config.get_snapshot(), &wasm).unwrap();
+        let yaml = render(&document, ExportFormat::Yaml).unwrap();
+        // compared as values, the Kubernetes client keeping the fields of
+        // serde_json maps in their order
+        let expected: Value = serde_yaml::from_str(BOOTSTRAP).unwrap();
+        assert!(document == expected, "bootstrap changed:\n{}", yaml);
+        let clusters = &document["static_resources"]["clusters"];
+        assert_eq!(clusters[0]["type"], "LOGICAL_DNS");
+        // the cluster the filters are fetched through is defined
+        assert_eq!(clusters[2]["name"], "wasm_files");
+        assert_eq!(
+            clusters[2]["load_assignment"]["endpoints"][0]["lb_endpoints"][0]["endpoint"]
+                ["address"]["socket_address"]["address"],
+            "control-plane-main"
+        );
+    }
+
+    fn render_file(path: &str) -> Result<std::string::String> {
+        let services = Config::parse_config(path, Default::default())?;
+        let wasm = WasmSettings {
+            skip_sha: true,
+            ..Default::default()
+        };
+        let (exports, errors) = services.export_concurrently(&wasm, 1);
+        assert!(errors.is_empty());
+        let mut config = Config::default();
+        config.import(services.get_services(), services.get_hash(), exports);
+        render(
+            &bootstrap(&Registry::new()?, &config.get_snapshot(), &wasm)?,
+            ExportFormat::Yaml,
+        )
+    }
+
+    #[test]
+    fn yaml_services_export_like_json() {
+        let testdata = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/bootstrap");
+        let json = render_file(&format!("{}/services.json", testdata)).unwrap();
+        let yaml = render_file(&format!("{}/services.yaml", testdata)).unwrap();
+        assert!(
+            yaml == json,
+            "YAML services exported differently:\n{}",
+            yaml
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("services.yml");
+        std::fs::write(&path, "- id: 1\n  hosts: web\n").unwrap();
+        let error = format!("{:#}", render_file(&path.to_string_lossy()).unwrap_err());
+        assert!(
+            error.contains("[0].hosts (service 1): invalid type"),
+            "{}",
+            error
+        );
+        assert!(error.contains("at line 2 column"), "{}", error);
+    }
+}
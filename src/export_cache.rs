@@ -0,0 +1,216 @@
+use std::collections::btree_map::Entry;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+
+use crate::envoy_helpers::SharedExports;
+use crate::interpolation;
+use crate::service::{Service, WasmSettings};
+
+/// Digests of the filters the services refer to, by path, read once for
+/// every service exported at a time.
+pub type Digests = BTreeMap<PathBuf, std::string::String>;
+
+/// Digests of the filters of `services`, those that cannot be read being
+/// left out, for the exports to fail on them.
+pub fn digests<'a>(
+    services: impl IntoIterator<Item = &'a Service>,
+    wasm: &WasmSettings,
+) -> Digests {
+    let mut digests = Digests::new();
+    for service in services {
+        for path in service.wasm_files(wasm) {
+            if let Entry::Vacant(entry) = digests.entry(path) {
+                if let Ok(digest) = wasm.sha256(entry.key()) {
+                    entry.insert(digest);
+                }
+            }
+        }
+    }
+    digests
+}
+
+// The exports of a service, along with the key of what they were exported
+// from.
+type Cached = (u64, SharedExports);
+
+/// The exports of the services, by id, for a service that didn't change to
+/// be exported once rather than at every reload. Clones share their
+/// exports.
+#[derive(Debug, Clone, Default)]
+pub struct ExportCache {
+    exports: Arc<Mutex<HashMap<u32, Cached>>>,
+}
+
+impl ExportCache {
+    /// What the exports of `service` are made of: the service as a whole,
+    /// the settings of the filters and their digests. There is none for
+    /// the services exported out of more, like the keys an OIDC issuer
+    /// serves, which are always exported again.
+    fn key(service: &Service, wasm: &WasmSettings, digests: &Digests) -> Option<u64> {
+        if service.oidc_issuer.is_some() {
+            return None;
+        }
+        let mut hasher = DefaultHasher::new();
+        interpolation::revealed(|| serde_json::to_string(service))
+            .ok()?
+            .hash(&mut hasher);
+        wasm.hash(&mut hasher);
+        for path in service.wasm_files(wasm) {
+            digests.get(&path).hash(&mut hasher);
+        }
+        Some(hasher.finish())
+    }
+
+    /// The exports of `service`, those of its last export when it was
+    /// exported out of the same, along with whether they were.
+    pub fn export(
+        &self,
+        service: &Service,
+        wasm: &WasmSettings,
+        digests: &Digests,
+    ) -> (Result<SharedExports>, bool) {
+        let key = match ExportCache::key(service, wasm, digests) {
+            Some(key) => key,
+            None => return (service.export(wasm).map(SharedExports::from), false),
+        };
+        if let Some((cached, exports)) = self.exports.lock().unwrap().get(&service.id) {
+            if *cached == key {
+                return (Ok(Arc::clone(exports)), true);
+            }
+        }
+        let result = service.export(wasm).map(SharedExports::from);
+        if let Ok(ref exports) = result {
+            self.exports
+                .lock()
+                .unwrap()
+                .insert(service.id, (key, Arc::clone(exports)));
+        }
+        (result, false)
+    }
+
+    /// Forget the services `keep` doesn't tell, gone or disabled.
+    pub fn retain(&self, keep: impl Fn(u32) -> bool) {
+        self.exports.lock().unwrap().retain(|id, _| keep(*id));
+    }
+
+    /// Forget every export, for the files the services refer to, like
+    /// certificates, to be read again.
+    pub fn clear(&self) {
+        self.exports.lock().unwrap().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::envoy_helpers::canonical_hash;
+
+    fn services() -> Vec<Service> {
+        (1..=3)
+            .map(|id| {
+                serde_json::from_value(serde_json::json!({
+                    "id": id,
+                    "hosts": [format!("{}.app", id)],
+                    "policies": ["cors"],
+                    "target_domain": format!("http://{}.app:80", id),
+                    "proxy_rules": [
+                        {"pattern": "/", "http_method": "GET", "metric_system_name": "hits", "delta": 1}
+                    ],
+                }))
+                .unwrap()
+            })
+            .collect()
+    }
+
+    // The hashes of the resources of each service, and whether they were reused.
+    fn exported(
+        cache: &ExportCache,
+        services: &[Service],
+        wasm: &WasmSettings,
+    ) -> Vec<(Vec<[u8; 32]>, bool)> {
+        let digests = digests(services, wasm);
+        services
+            .iter()
+            .map(|service| {
+                let (exports, reused) = cache.export(service, wasm, &digests);
+                let encoded = exports
+                    .unwrap()
+                    .iter()
+                    .map(|export| canonical_hash(&export.config))
+                    .collect();
+                (encoded, reused)
+            })
+            .collect()
+    }
+
+    fn reused(exported: &[(Vec<[u8; 32]>, bool)]) -> Vec<bool> {
+        exported.iter().map(|(_, reused)| *reused).collect()
+    }
+
+    #[test]
+    fn unchanged_services_reuse_their_exports() {
+        let dir = tempfile::tempdir().unwrap();
+        let filter = dir.path().join("filter.wasm");
+        std::fs::write(&filter, "filter").unwrap();
+        let wasm = WasmSettings {
+            filter_path: filter.clone(),
+            ..Default::default()
+        };
+        let cache = ExportCache::default();
+        let mut services = services();
+
+        let first = exported(&cache, &services, &wasm);
+        assert_eq!(reused(&first), [false, false, false]);
+        let second = exported(&cache, &services, &wasm);
+        assert_eq!(reused(&second), [true, true, true]);
+        assert_eq!(
+            second,
+            first
+                .iter()
+                .map(|(bytes, _)| (bytes.clone(), true))
+                .collect::<Vec<_>>()
+        );
+
+        // a field of one service only invalidates its exports
+        services[1].hosts.push("two.app".to_string());
+        let third = exported(&cache, &services, &wasm);
+        assert_eq!(reused(&third), [true, false, true]);
+        assert_ne!(third[1].0, first[1].0);
+
+        // the settings and the filter shared by the services invalidate all
+        // of them
+        let moved = WasmSettings {
+            base_url: "http://files:8080".to_string(),
+            ..wasm.clone()
+        };
+        assert_eq!(
+            reused(&exported(&cache, &services, &moved)),
+            [false, false, false]
+        );
+        std::fs::write(&filter, "filter v2").unwrap();
+        assert_eq!(
+            reused(&exported(&cache, &services, &moved)),
+            [false, false, false]
+        );
+        assert_eq!(
+            reused(&exported(&cache, &services, &moved)),
+            [true, true, true]
+        );
+
+        cache.retain(|id| id != 3);
+        assert_eq!(
+            reused(&exported(&cache, &services, &moved)),
+            [true, true, false]
+        );
+        cache.clear();
+        assert_eq!(
+            reused(&exported(&cache, &services, &moved)),
+            [false, false, false]
+        );
+    }
+}
@@ -0,0 +1,298 @@
+use std::fmt;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// A problem with a field of the services, at a path as in
+/// `[2].proxy_rules[0].pattern`.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct FieldError {
+    // services file at fault, when a directory of them is read
+    pub file: Option<std::string::String>,
+    // empty for the document as a whole
+    pub path: std::string::String,
+    // id of the service at fault, when it has one
+    pub service: Option<u64>,
+    pub message: std::string::String,
+}
+
+impl FieldError {
+    pub fn new(path: impl Into<std::string::String>, message: impl fmt::Display) -> FieldError {
+        FieldError {
+            file: None,
+            path: path.into(),
+            service: None,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for FieldError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(ref file) = self.file {
+            write!(f, "{}: ", file)?;
+        }
+        if !self.path.is_empty() {
+            write!(f, "{}", self.path)?;
+            if let Some(id) = self.service {
+                write!(f, " (service {})", id)?;
+            }
+            write!(f, ": ")?;
+        } else if let Some(id) = self.service {
+            write!(f, "service {}: ", id)?;
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+/// Every problem found at once, rather than only the first one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldErrors(pub Vec<FieldError>);
+
+impl fmt::Display for FieldErrors {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0.len() > 1 {
+            write!(f, "{} errors: ", self.0.len())?;
+        }
+        let errors: Vec<_> = self.0.iter().map(FieldError::to_string).collect();
+        write!(f, "{}", errors.join("; "))
+    }
+}
+
+impl std::error::Error for FieldErrors {}
+
+/// How much a problem found in the services matters.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    /// The service is refused.
+    Error,
+    /// The service is served anyway, the problem only reported.
+    Warning,
+    /// An error of the strict findings, and a warning of the others, the
+    /// problem being one the services used to be accepted with.
+    Deprecated,
+}
+
+/// Every problem found in some settings, by severity.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Findings {
+    pub errors: Vec<FieldError>,
+    pub warnings: Vec<FieldError>,
+    // the deprecated problems are errors
+    strict: bool,
+}
+
+impl Findings {
+    pub fn push(&mut self, severity: Severity, mut problem: FieldError) {
+        match severity {
+            Severity::Error => self.errors.push(problem),
+            Severity::Warning => self.warnings.push(problem),
+            Severity::Deprecated if self.strict => self.errors.push(problem),
+            Severity::Deprecated => {
+                problem.message.push_str(
+                    ", which is deprecated: --strict-config refuses it, as later releases will",
+                );
+                self.warnings.push(problem);
+            }
+        }
+    }
+
+    /// No problem found yet, the deprecated ones being errors.
+    pub fn strict() -> Findings {
+        Findings {
+            strict: true,
+            ..Findings::default()
+        }
+    }
+
+    pub fn error(&mut self, path: impl Into<std::string::String>, message: impl fmt::Display) {
+        self.push(Severity::Error, FieldError::new(path, message));
+    }
+
+    pub fn warning(&mut self, path: impl Into<std::string::String>, message: impl fmt::Display) {
+        self.push(Severity::Warning, FieldError::new(path, message));
+    }
+
+    pub fn deprecated(&mut self, path: impl Into<std::string::String>, message: impl fmt::Display) {
+        self.push(Severity::Deprecated, FieldError::new(path, message));
+    }
+
+    /// Every problem, attributed to the service `id`.
+    pub fn of_service(mut self, id: Option<u64>) -> Findings {
+        for problem in self.errors.iter_mut().chain(self.warnings.iter_mut()) {
+            problem.service = id;
+        }
+        self
+    }
+}
+
+/// Settings checking themselves, for every problem at once rather than
+/// the first one, each at its path under `path`, where the settings are.
+pub trait Validate {
+    fn validate(&self, path: &str, findings: &mut Findings);
+
+    /// What `validate` finds, on its own.
+    fn findings(&self, path: &str) -> Findings {
+        let mut findings = Findings::default();
+        self.validate(path, &mut findings);
+        findings
+    }
+}
+
+/// Path of `name` in the object at `path`.
+pub fn field(path: &str, name: &str) -> std::string::String {
+    if path.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}.{}", path, name)
+    }
+}
+
+/// Path of the element `index` of the list at `path`.
+pub fn index(path: &str, index: usize) -> std::string::String {
+    format!("{}[{}]", path, index)
+}
+
+/// Deserialize `value`, found at `path`, the error pointing at the field at
+/// fault. The value is laid out one token per line so that the line serde
+/// stops at tells the field.
+pub fn from_value<T: DeserializeOwned>(
+    value: &serde_json::Value,
+    path: &str,
+) -> Result<T, FieldError> {
+    let mut text = std::string::String::new();
+    let mut paths = Vec::new();
+    lay_out(value, path, &mut text, &mut paths);
+    serde_json::from_str(&text).map_err(|e| {
+        let path = e
+            .line()
+            .checked_sub(1)
+            .and_then(|line| paths.get(line))
+            .map(std::string::String::as_str)
+            .unwrap_or(path);
+        // the position is in the laid out text, which nobody sees
+        let message = e.to_string();
+        let message = match message.rfind(" at line ") {
+            Some(end) => &message[..end],
+            None => &message,
+        };
+        FieldError::new(path, message)
+    })
+}
+
+/// Paths of the fields of `value`, found at `path`, that serde ignores
+/// when deserializing a `T` out of it, none when it can't. The maps
+/// flattened in a structure, which pass their fields through on purpose,
+/// keep them all, as do the values deserialized through an untagged enum,
+/// or any other buffered on their way, which serde reads as a whole.
+pub fn unknown_fields<T: DeserializeOwned>(
+    value: &serde_json::Value,
+    path: &str,
+) -> Vec<std::string::String> {
+    let mut unknown = Vec::new();
+    let _: Result<T, _> =
+        serde_ignored::deserialize(value, |ignored| unknown.push(ignored_path(&ignored, path)));
+    unknown
+}
+
+// The path of a field serde ignored, under `root`.
+fn ignored_path(ignored: &serde_ignored::Path, root: &str) -> std::string::String {
+    use serde_ignored::Path;
+    match ignored {
+        Path::Root => root.to_string(),
+        Path::Seq { parent, index: i } => index(&ignored_path(parent, root), *i),
+        Path::Map { parent, key } => field(&ignored_path(parent, root), key),
+        Path::Some { parent }
+        | Path::NewtypeStruct { parent }
+        | Path::NewtypeVariant { parent } => ignored_path(parent, root),
+    }
+}
+
+// Write `value` with every token on a line of its own, along with the path
+// of each line.
+fn lay_out(
+    value: &serde_json::Value,
+    path: &str,
+    text: &mut std::string::String,
+    paths: &mut Vec<std::string::String>,
+) {
+    fn line(
+        text: &mut std::string::String,
+        paths: &mut Vec<std::string::String>,
+        content: &str,
+        path: &str,
+    ) {
+        text.push_str(content);
+        text.push('\n');
+        paths.push(path.to_string());
+    }
+    match value {
+        serde_json::Value::Array(values) => {
+            line(text, paths, "[", path);
+            for (i, value) in values.iter().enumerate() {
+                if i > 0 {
+                    line(text, paths, ",", path);
+                }
+                lay_out(value, &index(path, i), text, paths);
+            }
+            line(text, paths, "]", path);
+        }
+        serde_json::Value::Object(fields) => {
+            line(text, paths, "{", path);
+            for (i, (name, value)) in fields.iter().enumerate() {
+                if i > 0 {
+                    line(text, paths, ",", path);
+                }
+                let path = field(path, name);
+                let name = serde_json::Value::String(name.clone()).to_string() + ":";
+                line(text, paths, &name, &path);
+                lay_out(value, &path, text, paths);
+            }
+            line(text, paths, "}", path);
+        }
+        scalar => line(text, paths, &scalar.to_string(), path),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, serde::Deserialize)]
+    #[allow(dead_code)]
+    struct Rule {
+        pattern: std::string::String,
+        delta: u32,
+    }
+
+    #[derive(Debug, serde::Deserialize)]
+    #[allow(dead_code)]
+    struct Rules {
+        rules: Vec<Rule>,
+        url: url::Url,
+    }
+
+    fn error(value: serde_json::Value) -> std::string::String {
+        from_value::<Rules>(&value, "[3]").unwrap_err().to_string()
+    }
+
+    #[test]
+    fn errors_point_at_the_field() {
+        let rules =
+            serde_json::json!([{"pattern": "/", "delta": 1}, {"pattern": "/", "delta": -1}]);
+        assert_eq!(
+            error(serde_json::json!({"url": "http://one", "rules": rules})),
+            "[3].rules[1].delta: invalid value: integer `-1`, expected u32"
+        );
+        assert!(error(serde_json::json!({"url": "one", "rules": []}))
+            .starts_with("[3].url: invalid value: string \"one\""));
+        assert_eq!(
+            error(serde_json::json!({"url": "http://one", "rules": [{"delta": 1}]})),
+            "[3].rules[0]: missing field `pattern`"
+        );
+        assert_eq!(
+            error(serde_json::json!({"url": "http://one", "rules": "/"})),
+            "[3].rules: invalid type: string \"/\", expected a sequence"
+        );
+    }
+}
@@ -0,0 +1,359 @@
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::field_errors::{field, index, Findings, Validate};
+use crate::http_filters;
+use crate::rule_patterns;
+use crate::type_urls::TypeUrls;
+
+use crate::protobuf::envoy::config::cluster::v3::cluster::{
+    ClusterDiscoveryType, CustomClusterType, LbPolicy,
+};
+use crate::protobuf::envoy::config::cluster::v3::Cluster;
+use crate::protobuf::envoy::config::core::v3::transport_socket::ConfigType;
+use crate::protobuf::envoy::config::core::v3::{data_source, DataSource};
+use crate::protobuf::envoy::config::core::v3::{TransportSocket, UpstreamHttpProtocolOptions};
+use crate::protobuf::envoy::config::route::v3::header_matcher::HeaderMatchSpecifier;
+use crate::protobuf::envoy::config::route::v3::HeaderMatcher;
+use crate::protobuf::envoy::extensions::clusters::dynamic_forward_proxy::v3::{
+    cluster_config, ClusterConfig,
+};
+use crate::protobuf::envoy::extensions::common::dynamic_forward_proxy::v3::DnsCacheConfig;
+use crate::protobuf::envoy::extensions::filters::http::dynamic_forward_proxy::v3::{
+    filter_config, per_route_config, FilterConfig, PerRouteConfig,
+};
+use crate::protobuf::envoy::extensions::filters::network::http_connection_manager::v3::{
+    http_filter, HttpFilter,
+};
+use crate::protobuf::envoy::extensions::transport_sockets::tls::v3::{
+    common_tls_context, CertificateValidationContext, CommonTlsContext, UpstreamTlsContext,
+};
+
+const CLUSTER_TYPE: &str = "envoy.clusters.dynamic_forward_proxy";
+
+/// The CA bundle of the system, that of the Envoy images.
+const SYSTEM_CA_FILE: &str = "/etc/ssl/certs/ca-certificates.crt";
+
+/// The settings of a service of kind `dynamic_forward_proxy`, whose
+/// upstream is the host each request names, as for egress, rather than
+/// its target domain. Envoy resolves the hosts as the requests come, and
+/// only proxies those to the allowed domains, the service being an open
+/// proxy otherwise.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ForwardProxy {
+    // the hosts the requests may reach, as `api.example.com`, or as
+    // `*.example.com` for the subdomains of example.com
+    pub allowed_domains: Vec<std::string::String>,
+    // the header naming the host of the upstream, rather than the host of
+    // the request
+    #[serde(default)]
+    pub host_header: Option<std::string::String>,
+    // the upstreams are reached over TLS, checked against the host
+    #[serde(default)]
+    pub tls: bool,
+    // the CA bundle the certificates of the upstreams are checked against,
+    // rather than the one of the system
+    #[serde(default)]
+    pub ca_file: Option<std::string::String>,
+    // seconds between resolutions of each host
+    #[serde(default = "a_minute")]
+    pub dns_refresh_rate: u64,
+    // seconds an unused host is kept resolved
+    #[serde(default = "five_minutes")]
+    pub host_ttl: u64,
+    #[serde(default = "default_max_hosts")]
+    pub max_hosts: u32,
+}
+
+fn a_minute() -> u64 {
+    60
+}
+
+fn five_minutes() -> u64 {
+    5 * 60
+}
+
+// Envoy's default
+fn default_max_hosts() -> u32 {
+    1024
+}
+
+impl Validate for ForwardProxy {
+    fn validate(&self, path: &str, findings: &mut Findings) {
+        if self.allowed_domains.is_empty() {
+            findings.error(
+                field(path, "allowed_domains"),
+                "needs the domains the requests may reach, for the proxy not to be an open one",
+            );
+        }
+        for (i, domain) in self.allowed_domains.iter().enumerate() {
+            let name = domain.strip_prefix("*.").unwrap_or(domain);
+            let allowed = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '.';
+            if name.is_empty() || !name.chars().all(allowed) {
+                findings.error(
+                    index(&field(path, "allowed_domains"), i),
+                    format!(
+                        "'{}' is not a domain, nor a wildcard as *.example.com",
+                        domain
+                    ),
+                );
+            }
+        }
+        if let Some(ref header) = self.host_header {
+            if header.is_empty() || header.starts_with(':') || header.contains(char::is_whitespace)
+            {
+                findings.error(
+                    field(path, "host_header"),
+                    format!("'{}' is not a header name", header),
+                );
+            }
+        }
+        if self.ca_file.as_deref() == Some("") {
+            findings.error(field(path, "ca_file"), "must name a file");
+        }
+        for (name, value) in &[
+            ("dns_refresh_rate", self.dns_refresh_rate),
+            ("host_ttl", self.host_ttl),
+            ("max_hosts", self.max_hosts.into()),
+        ] {
+            if *value == 0 {
+                findings.error(field(path, name), "must be above 0");
+            }
+        }
+    }
+}
+
+impl ForwardProxy {
+    /// The DNS cache named `name`, which the filter and the cluster of the
+    /// service share, Envoy refusing them unless their settings match.
+    pub fn dns_cache(&self, name: &str) -> DnsCacheConfig {
+        DnsCacheConfig {
+            name: name.to_string(),
+            dns_refresh_rate: Some(std::time::Duration::from_secs(self.dns_refresh_rate).into()),
+            host_ttl: Some(std::time::Duration::from_secs(self.host_ttl).into()),
+            max_hosts: Some(self.max_hosts),
+            ..Default::default()
+        }
+    }
+
+    pub fn filter(&self, cache: &str, type_urls: &TypeUrls) -> Result<HttpFilter> {
+        let config = FilterConfig {
+            implementation_specifier: Some(filter_config::ImplementationSpecifier::DnsCacheConfig(
+                self.dns_cache(cache),
+            )),
+            ..Default::default()
+        };
+        Ok(HttpFilter {
+            name: http_filters::DYNAMIC_FORWARD_PROXY.to_string(),
+            config_type: Some(http_filter::ConfigType::TypedConfig(
+                type_urls.pack(&config)?,
+            )),
+        })
+    }
+
+    /// The cluster named `name` proxying to the hosts of the requests, as
+    /// the filter resolved them.
+    pub fn cluster(
+        &self,
+        name: std::string::String,
+        cache: &str,
+        type_urls: &TypeUrls,
+    ) -> Result<Cluster> {
+        let config = ClusterConfig {
+            cluster_implementation_specifier: Some(
+                cluster_config::ClusterImplementationSpecifier::DnsCacheConfig(
+                    self.dns_cache(cache),
+                ),
+            ),
+            ..Default::default()
+        };
+        let mut cluster = Cluster {
+            name,
+            connect_timeout: Some(std::time::Duration::from_secs(1).into()),
+            lb_policy: LbPolicy::ClusterProvided as i32,
+            cluster_discovery_type: Some(ClusterDiscoveryType::ClusterType(CustomClusterType {
+                name: CLUSTER_TYPE.to_string(),
+                typed_config: Some(type_urls.pack(&config)?),
+            })),
+            ..Default::default()
+        };
+        if self.tls {
+            let validation = CertificateValidationContext {
+                trusted_ca: Some(DataSource {
+                    specifier: Some(data_source::Specifier::Filename(
+                        self.ca_file
+                            .clone()
+                            .unwrap_or_else(|| SYSTEM_CA_FILE.to_string()),
+                    )),
+                }),
+                ..Default::default()
+            };
+            let context = UpstreamTlsContext {
+                common_tls_context: Some(CommonTlsContext {
+                    validation_context_type: Some(
+                        common_tls_context::ValidationContextType::ValidationContext(validation),
+                    ),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            };
+            cluster.transport_socket = Some(TransportSocket {
+                name: "envoy.transport_sockets.tls".to_string(),
+                config_type: Some(ConfigType::TypedConfig(type_urls.pack(&context)?)),
+            });
+            // the SNI and the names the certificate is checked for are
+            // those of the host of each request
+            cluster.upstream_http_protocol_options = Some(UpstreamHttpProtocolOptions {
+                auto_sni: true,
+                auto_san_validation: true,
+                ..Default::default()
+            });
+        }
+        Ok(cluster)
+    }
+
+    /// The matcher of the requests to the allowed domains, by the header
+    /// naming their host, with any port.
+    pub fn destination_matcher(&self) -> HeaderMatcher {
+        let domains: Vec<_> = self
+            .allowed_domains
+            .iter()
+            .map(|domain| match domain.strip_prefix("*.") {
+                Some(parent) => format!(r"[^/:]+\.{}", regex::escape(parent)),
+                None => regex::escape(domain),
+            })
+            .collect();
+        HeaderMatcher {
+            name: self
+                .host_header
+                .clone()
+                .unwrap_or_else(|| ":authority".to_string()),
+            header_match_specifier: Some(HeaderMatchSpecifier::SafeRegexMatch(rule_patterns::re2(
+                format!("(?:{})(?::[0-9]+)?", domains.join("|")),
+            ))),
+            ..Default::default()
+        }
+    }
+
+    /// The per-filter config of the route of the service, having the filter
+    /// take the host from the host header, if any.
+    pub fn route_config(
+        &self,
+        type_urls: &TypeUrls,
+    ) -> Result<BTreeMap<std::string::String, prost_types::Any>> {
+        let mut configs = BTreeMap::new();
+        if let Some(ref header) = self.host_header {
+            let config = PerRouteConfig {
+                host_rewrite_specifier: Some(
+                    per_route_config::HostRewriteSpecifier::HostRewriteHeader(header.clone()),
+                ),
+            };
+            configs.insert(
+                http_filters::DYNAMIC_FORWARD_PROXY.to_string(),
+                type_urls.pack(&config)?,
+            );
+        }
+        Ok(configs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn proxy(settings: serde_json::Value) -> ForwardProxy {
+        serde_json::from_value(settings).unwrap()
+    }
+
+    #[test]
+    fn requests_only_reach_the_allowed_domains() {
+        let proxy = proxy(serde_json::json!({
+            "allowed_domains": ["api.example.com", "*.internal.example.com"],
+        }));
+        let matcher = proxy.destination_matcher();
+        assert_eq!(matcher.name, ":authority");
+        let regex = match matcher.header_match_specifier {
+            Some(HeaderMatchSpecifier::SafeRegexMatch(matcher)) => matcher.regex,
+            specifier => panic!("{:?}", specifier),
+        };
+        // matching whole values, as Envoy does
+        let full = regex::Regex::new(&format!("^(?:{})$", regex)).unwrap();
+        for host in &[
+            "api.example.com",
+            "api.example.com:8443",
+            "billing.internal.example.com",
+        ] {
+            assert!(full.is_match(host), "{}", host);
+        }
+        for host in &[
+            "apixexample.com",
+            "example.com",
+            "internal.example.com",
+            "evil.com/.internal.example.com",
+            "api.example.com.evil.com",
+        ] {
+            assert!(!full.is_match(host), "{}", host);
+        }
+    }
+
+    #[test]
+    fn upstream_certificates_are_checked_against_the_host() {
+        use prost::Message;
+
+        let trusted_ca = |proxy: &ForwardProxy| {
+            let cluster = proxy
+                .cluster("proxy".to_string(), "cache", &TypeUrls::default())
+                .unwrap();
+            let options = cluster.upstream_http_protocol_options.unwrap();
+            assert!(options.auto_sni && options.auto_san_validation);
+            let any = match cluster.transport_socket.unwrap().config_type {
+                Some(ConfigType::TypedConfig(any)) => any,
+                config => panic!("{:?}", config),
+            };
+            let context = UpstreamTlsContext::decode(any.value.as_slice()).unwrap();
+            match context.common_tls_context.unwrap().validation_context_type {
+                Some(common_tls_context::ValidationContextType::ValidationContext(validation)) => {
+                    validation.trusted_ca.unwrap().specifier
+                }
+                context => panic!("{:?}", context),
+            }
+        };
+        let mut tls = proxy(serde_json::json!({
+            "allowed_domains": ["api.example.com"],
+            "tls": true,
+        }));
+        assert_eq!(
+            trusted_ca(&tls),
+            Some(data_source::Specifier::Filename(SYSTEM_CA_FILE.to_string()))
+        );
+        tls.ca_file = Some("/etc/egress/ca.pem".to_string());
+        assert_eq!(
+            trusted_ca(&tls),
+            Some(data_source::Specifier::Filename(
+                "/etc/egress/ca.pem".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn open_proxies_are_refused() {
+        let open = proxy(serde_json::json!({
+            "allowed_domains": [],
+            "host_header": ":authority",
+            "host_ttl": 0,
+        }));
+        let paths: Vec<_> = open
+            .findings("")
+            .errors
+            .into_iter()
+            .map(|error| error.path)
+            .collect();
+        assert_eq!(paths, ["allowed_domains", "host_header", "host_ttl"]);
+
+        let wildcard = proxy(serde_json::json!({"allowed_domains": ["*"]}));
+        assert_eq!(wildcard.findings("").errors[0].path, "allowed_domains[0]");
+    }
+}
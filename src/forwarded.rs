@@ -0,0 +1,80 @@
+use serde::{Deserialize, Serialize};
+
+use crate::protobuf::envoy::config::core::v3::{
+    scheme_header_transformation::Transformation, HeaderValue, HeaderValueOption,
+    SchemeHeaderTransformation,
+};
+use crate::protobuf::envoy::config::route::v3::{route::Action, VirtualHost};
+use crate::protobuf::envoy::extensions::filters::network::http_connection_manager::v3::HttpConnectionManager;
+
+/// How the `X-Forwarded-*` headers of the requests of a service reach its
+/// upstream, for Envoy running behind a load balancer terminating TLS. Left
+/// out, Envoy handles them the way it does by default.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Forwarded {
+    // proxies in front of Envoy whose `X-Forwarded-For` addresses are
+    // trusted, the client address being the one before theirs
+    #[serde(default)]
+    pub xff_num_trusted_hops: u32,
+    // the host the client asked for, before a route rewrites it
+    #[serde(default)]
+    pub append_x_forwarded_host: bool,
+    // the scheme the client used with the load balancer, overwriting the
+    // one of the requests and their `X-Forwarded-Proto`
+    #[serde(default)]
+    pub scheme: Option<Scheme>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Scheme {
+    Http,
+    Https,
+}
+
+impl Scheme {
+    fn as_str(self) -> &'static str {
+        match self {
+            Scheme::Http => "http",
+            Scheme::Https => "https",
+        }
+    }
+}
+
+impl Forwarded {
+    /// Apply the settings to the virtual host of the service, once its
+    /// routes are all there, Envoy having no setting of the connection
+    /// manager for the host and the proto forwarded.
+    pub fn apply_to_host(&self, host: &mut VirtualHost) {
+        if self.append_x_forwarded_host {
+            for route in &mut host.routes {
+                if let Some(Action::Route(ref mut action)) = route.action {
+                    action.append_x_forwarded_host = true;
+                }
+            }
+        }
+        if let Some(scheme) = self.scheme {
+            host.request_headers_to_add.push(HeaderValueOption {
+                header: Some(HeaderValue {
+                    key: "x-forwarded-proto".to_string(),
+                    value: scheme.as_str().to_string(),
+                    ..Default::default()
+                }),
+                append: Some(false),
+                ..Default::default()
+            });
+        }
+    }
+
+    pub fn apply(&self, connection_manager: &mut HttpConnectionManager) {
+        connection_manager.xff_num_trusted_hops = self.xff_num_trusted_hops;
+        if let Some(scheme) = self.scheme {
+            connection_manager.scheme_header_transformation = Some(SchemeHeaderTransformation {
+                transformation: Some(Transformation::SchemeToOverwrite(
+                    scheme.as_str().to_string(),
+                )),
+            });
+        }
+    }
+}
@@ -0,0 +1,330 @@
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+
+use crate::configuration::{self, ParseOptions};
+use crate::publisher::Publisher;
+
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(60);
+pub const DEFAULT_BRANCH: &str = "main";
+
+/// The repository the git source polls, and how often, as given on the
+/// command line.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GitSettings {
+    pub url: std::string::String,
+    pub branch: std::string::String,
+    // of the services file, or directory of them, in the repository
+    pub path: PathBuf,
+    // where the commits are checked out
+    pub checkout: PathBuf,
+    pub poll_interval: Duration,
+    pub auth: Option<GitAuth>,
+}
+
+/// Services source polling a branch of a git repository, reading the
+/// services files under a path of its latest commit. It drives the git
+/// command line, which has to be installed.
+#[derive(Debug, Clone)]
+pub struct GitSource {
+    url: std::string::String,
+    branch: std::string::String,
+    // of the services file, or directory of them, in the repository
+    path: PathBuf,
+    // where the commits are checked out
+    checkout: PathBuf,
+    poll_interval: Duration,
+    auth: Option<GitAuth>,
+    options: ParseOptions,
+    // the commit published last, shared with the reloads on demand, its
+    // lock held for a whole sync for no two to run git in the checkout
+    published: Arc<Mutex<Option<std::string::String>>>,
+}
+
+/// How the repository is fetched from, when not anonymously.
+#[derive(Debug, Clone, PartialEq)]
+pub enum GitAuth {
+    /// A private SSH key, for `ssh://` and `git@` URLs.
+    SshKey(PathBuf),
+    /// A token sent as a bearer token, for `https://` URLs.
+    Token(std::string::String),
+}
+
+impl GitSource {
+    pub fn new(url: impl Into<std::string::String>, checkout: PathBuf) -> GitSource {
+        GitSource {
+            url: url.into(),
+            branch: DEFAULT_BRANCH.to_string(),
+            path: PathBuf::new(),
+            checkout,
+            poll_interval: DEFAULT_POLL_INTERVAL,
+            auth: None,
+            options: ParseOptions::default(),
+            published: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// A source of `settings`, reading the services as `options` say.
+    pub fn from_settings(settings: &GitSettings, options: ParseOptions) -> GitSource {
+        let mut source = GitSource::new(settings.url.clone(), settings.checkout.clone());
+        source.branch = settings.branch.clone();
+        source.path = settings.path.clone();
+        source.poll_interval = settings.poll_interval;
+        source.auth = settings.auth.clone();
+        source.options = options;
+        source
+    }
+
+    fn git(&self, dir: &Path, args: &[&str]) -> Result<std::string::String> {
+        let mut command = Command::new("git");
+        command
+            .arg("-C")
+            .arg(dir)
+            .args(args)
+            .env("GIT_TERMINAL_PROMPT", "0");
+        match self.auth {
+            Some(GitAuth::SshKey(ref key)) => {
+                command.env(
+                    "GIT_SSH_COMMAND",
+                    format!(
+                        "ssh -i {} -o IdentitiesOnly=yes -o StrictHostKeyChecking=accept-new",
+                        shell_quote(&key.to_string_lossy())
+                    ),
+                );
+            }
+            // in the environment rather than the arguments, which anybody
+            // may list
+            Some(GitAuth::Token(ref token)) => {
+                command
+                    .env("GIT_CONFIG_COUNT", "1")
+                    .env("GIT_CONFIG_KEY_0", "http.extraHeader")
+                    .env(
+                        "GIT_CONFIG_VALUE_0",
+                        format!("Authorization: Bearer {}", token),
+                    );
+            }
+            None => {}
+        }
+        let output = command
+            .output()
+            .context("cannot run git, is it installed?")?;
+        if !output.status.success() {
+            bail!(
+                "git {} failed: {}",
+                args[0],
+                std::string::String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        Ok(std::string::String::from_utf8_lossy(&output.stdout)
+            .trim()
+            .to_string())
+    }
+
+    /// Fetch the latest commit of the branch, returning its SHA.
+    pub fn fetch(&self) -> Result<std::string::String> {
+        if !self.checkout.join(".git").exists() {
+            std::fs::create_dir_all(&self.checkout)
+                .with_context(|| format!("cannot create {}", self.checkout.display()))?;
+            self.git(&self.checkout, &["init", "-q"])?;
+        }
+        let remote_branch = format!("refs/remotes/origin/{}", self.branch);
+        let refspec = format!("+refs/heads/{}:{}", self.branch, remote_branch);
+        self.git(
+            &self.checkout,
+            &["fetch", "-q", "--depth", "1", &self.url, &refspec],
+        )
+        .with_context(|| format!("cannot fetch branch {} of {}", self.branch, self.url))?;
+        self.git(&self.checkout, &["rev-parse", &remote_branch])
+    }
+
+    /// Check out the latest commit, unless it is the one published already,
+    /// and load its services, returning whether a new version was published.
+    /// A commit failing to load leaves the one published served.
+    pub fn reload(&self, publisher: &Publisher) -> Result<bool> {
+        let mut published = self.published.lock().unwrap();
+        let commit = self.fetch()?;
+        if published.as_ref() == Some(&commit) {
+            return Ok(false);
+        }
+        let updated = self
+            .load(&commit)
+            .and_then(|mut config| {
+                config.set_revision(commit.clone());
+                publisher.publish(config)
+            })
+            .with_context(|| format!("commit {} of branch {}", commit, self.branch))?;
+        tracing::info!(
+            commit = commit.as_str(),
+            "Services of commit {} published",
+            commit
+        );
+        *published = Some(commit);
+        Ok(updated)
+    }
+
+    fn load(&self, commit: &str) -> Result<configuration::Config> {
+        self.git(
+            &self.checkout,
+            &["checkout", "-q", "--force", "--detach", commit],
+        )?;
+        let path = self.checkout.join(&self.path);
+        configuration::Config::parse_config(&path.to_string_lossy(), self.options)
+    }
+
+    fn reload_logged(&self, publisher: &Publisher) {
+        if let Err(e) = self.reload(publisher) {
+            let config = publisher.config();
+            tracing::error!(
+                version = config.read().unwrap().get_version(),
+                "Git sync failed, still serving the current version: {:#}",
+                e
+            );
+            configuration::reload_failed(config, &e);
+        }
+    }
+
+    /// Sync once and keep polling the repository in the background, for as
+    /// long as the replica leads.
+    pub fn spawn(self, publisher: Publisher) {
+        if publisher.leadership().is_leader() {
+            self.reload_logged(&publisher);
+        }
+        std::thread::spawn(move || loop {
+            std::thread::sleep(self.poll_interval);
+            if publisher.leadership().is_leader() {
+                self.reload_logged(&publisher);
+            }
+        });
+    }
+}
+
+// `value` as a single word of the shell git runs `GIT_SSH_COMMAND` with.
+fn shell_quote(value: &str) -> std::string::String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::RwLock;
+
+    const SERVICES: &str = r#"[{"id": 1, "hosts": ["one"], "policies": [], "target_domain": "http://one:80", "proxy_rules": []}]"#;
+
+    fn git(dir: &Path, args: &[&str]) -> std::string::String {
+        let output = Command::new("git")
+            .arg("-C")
+            .arg(dir)
+            .args(["-c", "user.name=test", "-c", "user.email=test@example.com"])
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{:?}", output);
+        std::string::String::from_utf8(output.stdout)
+            .unwrap()
+            .trim()
+            .to_string()
+    }
+
+    // Commit `content` as the services file of the work tree, pushing it to
+    // the bare repository it was cloned from.
+    fn push(work: &Path, content: &str) -> std::string::String {
+        std::fs::create_dir_all(work.join("services")).unwrap();
+        std::fs::write(work.join("services/services.json"), content).unwrap();
+        git(work, &["add", "-A"]);
+        git(work, &["commit", "-q", "-m", "services"]);
+        git(work, &["push", "-q", "origin", "HEAD:refs/heads/main"]);
+        git(work, &["rev-parse", "HEAD"])
+    }
+
+    fn config(publisher: &Publisher) -> std::sync::RwLockReadGuard<'_, configuration::Config> {
+        publisher.config().read().unwrap()
+    }
+
+    // A source of the bare repository of `dir`, with the work tree pushing
+    // to it.
+    fn repository(dir: &Path) -> (GitSource, PathBuf) {
+        let bare = dir.join("services.git");
+        let work = dir.join("work");
+        git(dir, &["init", "-q", "--bare", &bare.to_string_lossy()]);
+        git(dir, &["init", "-q", &work.to_string_lossy()]);
+        git(&work, &["remote", "add", "origin", &bare.to_string_lossy()]);
+
+        let url = format!("file://{}", bare.display());
+        let mut source = GitSource::new(url, dir.join("checkout"));
+        source.path = PathBuf::from("services");
+        (source, work)
+    }
+
+    fn publisher() -> Publisher {
+        Publisher::new(
+            Arc::new(RwLock::new(configuration::Config::default())),
+            Duration::from_millis(0),
+        )
+    }
+
+    #[test]
+    fn commits_are_published_until_one_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let (source, work) = repository(dir.path());
+        let publisher = publisher();
+
+        let good = push(&work, SERVICES);
+        source.reload(&publisher).unwrap();
+        assert_eq!(config(&publisher).revision(), Some(good.as_str()));
+        assert_eq!(config(&publisher).get_services().len(), 1);
+        // nothing new to check out
+        assert!(!source.reload(&publisher).unwrap());
+
+        let bad = push(&work, "{");
+        let error = source.reload(&publisher).unwrap_err();
+        assert!(
+            format!("{:#}", error).starts_with(&format!("commit {} of branch main", bad)),
+            "{:#}",
+            error
+        );
+        assert_eq!(config(&publisher).revision(), Some(good.as_str()));
+        assert_eq!(config(&publisher).get_services().len(), 1);
+
+        let fixed = push(&work, "[]");
+        source.reload(&publisher).unwrap();
+        assert_eq!(config(&publisher).revision(), Some(fixed.as_str()));
+        assert!(config(&publisher).get_services().is_empty());
+    }
+
+    #[test]
+    fn syncs_run_one_at_a_time() {
+        let dir = tempfile::tempdir().unwrap();
+        let (source, work) = repository(dir.path());
+        let publisher = publisher();
+        push(&work, SERVICES);
+
+        // the polls and the reloads on demand share the checkout
+        let syncs: Vec<_> = (0..4)
+            .map(|_| {
+                let source = source.clone();
+                let publisher = publisher.clone();
+                std::thread::spawn(move || source.reload(&publisher))
+            })
+            .collect();
+        let published = syncs
+            .into_iter()
+            .map(|sync| sync.join().unwrap().unwrap())
+            .filter(|&updated| updated)
+            .count();
+        assert_eq!(published, 1);
+    }
+
+    #[test]
+    fn ssh_keys_are_quoted() {
+        let key = "/keys/it's mine";
+        let output = Command::new("sh")
+            .arg("-c")
+            .arg(format!("printf %s {}", shell_quote(key)))
+            .output()
+            .unwrap();
+        assert_eq!(std::string::String::from_utf8(output.stdout).unwrap(), key);
+    }
+}
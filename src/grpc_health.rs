@@ -0,0 +1,187 @@
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use tokio::sync::mpsc;
+use tonic::{Request, Response, Status};
+
+use crate::configuration::{self, Readiness};
+use crate::protobuf::grpc::health::v1::health_check_response::ServingStatus;
+use crate::protobuf::grpc::health::v1::health_server;
+use crate::protobuf::grpc::health::v1::{HealthCheckRequest, HealthCheckResponse};
+use crate::shutdown::Shutdown;
+
+// How often watched statuses are checked for changes.
+const WATCH_INTERVAL: Duration = Duration::from_millis(500);
+
+/// The `grpc.health.v1.Health` service of the discovery server. The server,
+/// and every service it serves, is serving when `/readyz` is ready.
+#[derive(Debug, Clone)]
+pub struct Health {
+    config: Arc<RwLock<configuration::Config>>,
+    shutdown: Shutdown,
+    services: Vec<&'static str>,
+}
+
+impl Health {
+    pub fn new(
+        config: Arc<RwLock<configuration::Config>>,
+        shutdown: Shutdown,
+        services: Vec<&'static str>,
+    ) -> Health {
+        Health {
+            config,
+            shutdown,
+            services,
+        }
+    }
+
+    // `None` for services not served, the empty name standing for the server.
+    fn status(&self, service: &str) -> Option<ServingStatus> {
+        if !service.is_empty() && !self.services.contains(&service) {
+            return None;
+        }
+        let ready = !self.shutdown.is_draining()
+            && self.config.read().unwrap().readiness() == Readiness::Ready;
+        Some(if ready {
+            ServingStatus::Serving
+        } else {
+            ServingStatus::NotServing
+        })
+    }
+}
+
+fn response(status: ServingStatus) -> HealthCheckResponse {
+    HealthCheckResponse {
+        status: status as i32,
+    }
+}
+
+#[tonic::async_trait]
+impl health_server::Health for Health {
+    type WatchStream = mpsc::Receiver<Result<HealthCheckResponse, Status>>;
+
+    async fn check(
+        &self,
+        request: Request<HealthCheckRequest>,
+    ) -> Result<Response<HealthCheckResponse>, Status> {
+        let service = request.into_inner().service;
+        match self.status(&service) {
+            Some(status) => Ok(Response::new(response(status))),
+            None => Err(Status::not_found(format!("unknown service {}", service))),
+        }
+    }
+
+    async fn watch(
+        &self,
+        request: Request<HealthCheckRequest>,
+    ) -> Result<Response<Self::WatchStream>, Status> {
+        let service = request.into_inner().service;
+        let health = self.clone();
+        let (mut tx, rx) = mpsc::channel(1);
+        tokio::spawn(async move {
+            let mut last = None;
+            loop {
+                let status = health
+                    .status(&service)
+                    .unwrap_or(ServingStatus::ServiceUnknown);
+                if last != Some(status) {
+                    last = Some(status);
+                    if tx.send(Ok(response(status))).await.is_err() {
+                        return;
+                    }
+                }
+                // the stream ends with the server, once told it stops serving
+                if health.shutdown.is_draining() {
+                    return;
+                }
+                tokio::select! {
+                    _ = tokio::time::delay_for(WATCH_INTERVAL) => {}
+                    _ = health.shutdown.wait() => {}
+                }
+            }
+        });
+        Ok(Response::new(rx))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protobuf::grpc::health::v1::health_client::HealthClient;
+    use tokio::net::{TcpListener, TcpStream};
+    use tonic::transport::{Endpoint, Server, Uri};
+
+    async fn next_status(
+        stream: &mut tonic::Streaming<HealthCheckResponse>,
+    ) -> Option<ServingStatus> {
+        let message = tokio::time::timeout(Duration::from_secs(2), stream.message())
+            .await
+            .expect("no status change")
+            .unwrap();
+        message.map(|response| ServingStatus::from_i32(response.status).unwrap())
+    }
+
+    #[tokio::test]
+    async fn health_follows_readiness() {
+        let config = Arc::new(RwLock::new(configuration::Config::default()));
+        let shutdown = Shutdown::default();
+        let health = Health::new(
+            Arc::clone(&config),
+            shutdown.clone(),
+            vec!["envoy.service.discovery.v3.AggregatedDiscoveryService"],
+        );
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let mut listener = TcpListener::from_std(listener).unwrap();
+        tokio::spawn(async move {
+            Server::builder()
+                .add_service(health_server::HealthServer::new(health))
+                .serve_with_incoming(listener.incoming())
+                .await
+        });
+        let channel = Endpoint::from_static("http://localhost")
+            .connect_with_connector(tower::service_fn(move |_: Uri| async move {
+                let stream = std::net::TcpStream::connect(addr)?;
+                stream.set_nonblocking(true)?;
+                TcpStream::from_std(stream)
+            }))
+            .await
+            .unwrap();
+        let mut client = HealthClient::new(channel);
+        let check = |service: &str| HealthCheckRequest {
+            service: service.to_string(),
+        };
+
+        let response = client.check(check("")).await.unwrap().into_inner();
+        assert_eq!(response.status, ServingStatus::NotServing as i32);
+        let unknown = client.check(check("grpc.Unknown")).await.unwrap_err();
+        assert_eq!(unknown.code(), tonic::Code::NotFound);
+
+        let mut watch = client
+            .watch(check(
+                "envoy.service.discovery.v3.AggregatedDiscoveryService",
+            ))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(
+            next_status(&mut watch).await,
+            Some(ServingStatus::NotServing)
+        );
+        configuration::publish(
+            &config,
+            configuration::Config::from_services(Vec::new(), "[]"),
+        )
+        .unwrap();
+        assert_eq!(next_status(&mut watch).await, Some(ServingStatus::Serving));
+
+        shutdown.trigger();
+        assert_eq!(
+            next_status(&mut watch).await,
+            Some(ServingStatus::NotServing)
+        );
+        assert_eq!(next_status(&mut watch).await, None);
+    }
+}
@@ -0,0 +1,283 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use prost::Message;
+use prost_types::{DescriptorProto, EnumDescriptorProto, FileDescriptorSet};
+use tokio::sync::mpsc;
+use tonic::{Code, Request, Response, Status, Streaming};
+
+use crate::envoy_helpers::encode;
+use crate::proto_json::DESCRIPTOR_SET;
+use crate::protobuf::grpc::reflection::v1alpha::server_reflection_request::MessageRequest;
+use crate::protobuf::grpc::reflection::v1alpha::server_reflection_response::MessageResponse;
+use crate::protobuf::grpc::reflection::v1alpha::server_reflection_server;
+use crate::protobuf::grpc::reflection::v1alpha::{
+    ErrorResponse, FileDescriptorResponse, ListServiceResponse, ServerReflectionRequest,
+    ServerReflectionResponse, ServiceResponse,
+};
+
+struct File {
+    encoded: Vec<u8>,
+    dependencies: Vec<std::string::String>,
+}
+
+#[derive(Default)]
+struct Descriptors {
+    files: HashMap<std::string::String, File>,
+    // fully qualified names of the messages, enums, services and methods to
+    // the file declaring them
+    symbols: HashMap<std::string::String, std::string::String>,
+}
+
+impl Descriptors {
+    fn add_message(&mut self, scope: &str, message: &DescriptorProto, file: &str) {
+        let name = format!("{}{}", scope, message.name());
+        for nested in &message.nested_type {
+            self.add_message(&format!("{}.", name), nested, file);
+        }
+        for descriptor in &message.enum_type {
+            self.add_enum(&format!("{}.", name), descriptor, file);
+        }
+        self.symbols.insert(name, file.to_string());
+    }
+
+    fn add_enum(&mut self, scope: &str, descriptor: &EnumDescriptorProto, file: &str) {
+        self.symbols
+            .insert(format!("{}{}", scope, descriptor.name()), file.to_string());
+    }
+
+    // The file and the ones it depends on, transitively, each once.
+    fn with_dependencies(&self, name: &str) -> Option<Vec<Vec<u8>>> {
+        let mut pending = vec![name.to_string()];
+        let mut seen = HashSet::new();
+        let mut encoded = Vec::new();
+        while let Some(name) = pending.pop() {
+            if !seen.insert(name.clone()) {
+                continue;
+            }
+            let file = self.files.get(&name)?;
+            encoded.push(file.encoded.clone());
+            pending.extend(file.dependencies.iter().rev().cloned());
+        }
+        Some(encoded)
+    }
+}
+
+/// The `grpc.reflection.v1alpha.ServerReflection` service of the discovery
+/// server, describing `services` out of the descriptors of the protos the
+/// controller is built from.
+#[derive(Clone)]
+pub struct Reflection {
+    descriptors: Arc<Descriptors>,
+    services: Vec<&'static str>,
+}
+
+impl Reflection {
+    pub fn new(services: Vec<&'static str>) -> Result<Reflection> {
+        let set = FileDescriptorSet::decode(DESCRIPTOR_SET).context("invalid descriptor set")?;
+        let mut descriptors = Descriptors::default();
+        for file in set.file {
+            let name = file.name().to_string();
+            let scope = match file.package {
+                Some(ref package) => format!("{}.", package),
+                None => std::string::String::new(),
+            };
+            for message in &file.message_type {
+                descriptors.add_message(&scope, message, &name);
+            }
+            for descriptor in &file.enum_type {
+                descriptors.add_enum(&scope, descriptor, &name);
+            }
+            for service in &file.service {
+                let service_name = format!("{}{}", scope, service.name());
+                for method in &service.method {
+                    descriptors
+                        .symbols
+                        .insert(format!("{}.{}", service_name, method.name()), name.clone());
+                }
+                descriptors.symbols.insert(service_name, name.clone());
+            }
+            let dependencies = file.dependency.clone();
+            let encoded = encode(&file)?;
+            descriptors.files.insert(
+                name,
+                File {
+                    encoded,
+                    dependencies,
+                },
+            );
+        }
+        Ok(Reflection {
+            descriptors: Arc::new(descriptors),
+            services,
+        })
+    }
+
+    fn respond(&self, request: &ServerReflectionRequest) -> MessageResponse {
+        let not_found = |what: &str, name: &str| {
+            MessageResponse::ErrorResponse(ErrorResponse {
+                error_code: Code::NotFound as i32,
+                error_message: format!("{} {} not found", what, name),
+            })
+        };
+        let files = |encoded| {
+            MessageResponse::FileDescriptorResponse(FileDescriptorResponse {
+                file_descriptor_proto: encoded,
+            })
+        };
+        match request.message_request {
+            Some(MessageRequest::ListServices(_)) => {
+                MessageResponse::ListServicesResponse(ListServiceResponse {
+                    service: self
+                        .services
+                        .iter()
+                        .map(|name| ServiceResponse {
+                            name: name.to_string(),
+                        })
+                        .collect(),
+                })
+            }
+            Some(MessageRequest::FileByFilename(ref name)) => {
+                match self.descriptors.with_dependencies(name) {
+                    Some(encoded) => files(encoded),
+                    None => not_found("file", name),
+                }
+            }
+            Some(MessageRequest::FileContainingSymbol(ref symbol)) => {
+                let symbol = symbol.trim_start_matches('.');
+                match self
+                    .descriptors
+                    .symbols
+                    .get(symbol)
+                    .and_then(|file| self.descriptors.with_dependencies(file))
+                {
+                    Some(encoded) => files(encoded),
+                    None => not_found("symbol", symbol),
+                }
+            }
+            // none of the protos declares extensions
+            Some(MessageRequest::FileContainingExtension(ref extension)) => {
+                not_found("extension of", &extension.containing_type)
+            }
+            Some(MessageRequest::AllExtensionNumbersOfType(_)) | None => {
+                MessageResponse::ErrorResponse(ErrorResponse {
+                    error_code: Code::Unimplemented as i32,
+                    error_message: "not implemented".to_string(),
+                })
+            }
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl server_reflection_server::ServerReflection for Reflection {
+    type ServerReflectionInfoStream = mpsc::Receiver<Result<ServerReflectionResponse, Status>>;
+
+    async fn server_reflection_info(
+        &self,
+        request: Request<Streaming<ServerReflectionRequest>>,
+    ) -> Result<Response<Self::ServerReflectionInfoStream>, Status> {
+        let mut requests = request.into_inner();
+        let reflection = self.clone();
+        let (mut tx, rx) = mpsc::channel(1);
+        tokio::spawn(async move {
+            while let Ok(Some(request)) = requests.message().await {
+                let response = ServerReflectionResponse {
+                    valid_host: request.host.clone(),
+                    message_response: Some(reflection.respond(&request)),
+                    original_request: Some(request),
+                };
+                if tx.send(Ok(response)).await.is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(Response::new(rx))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prost_types::FileDescriptorProto;
+
+    const ADS: &str = "envoy.service.discovery.v3.AggregatedDiscoveryService";
+
+    fn request(message_request: MessageRequest) -> ServerReflectionRequest {
+        ServerReflectionRequest {
+            host: std::string::String::new(),
+            message_request: Some(message_request),
+        }
+    }
+
+    fn file_names(response: MessageResponse) -> Vec<std::string::String> {
+        match response {
+            MessageResponse::FileDescriptorResponse(files) => files
+                .file_descriptor_proto
+                .iter()
+                .map(|encoded| {
+                    FileDescriptorProto::decode(encoded.as_slice())
+                        .unwrap()
+                        .name()
+                        .to_string()
+                })
+                .collect(),
+            other => panic!("unexpected response {:?}", other),
+        }
+    }
+
+    #[test]
+    fn services_are_described_with_their_dependencies() {
+        let reflection = Reflection::new(vec![ADS, "grpc.health.v1.Health"]).unwrap();
+
+        let services = reflection.respond(&request(MessageRequest::ListServices(
+            std::string::String::new(),
+        )));
+        assert_eq!(
+            services,
+            MessageResponse::ListServicesResponse(ListServiceResponse {
+                service: vec![
+                    ServiceResponse {
+                        name: ADS.to_string()
+                    },
+                    ServiceResponse {
+                        name: "grpc.health.v1.Health".to_string()
+                    },
+                ],
+            })
+        );
+
+        let files = file_names(
+            reflection.respond(&request(MessageRequest::FileContainingSymbol(format!(
+                "{}.StreamAggregatedResources",
+                ADS
+            )))),
+        );
+        assert_eq!(files[0], "envoy/service/discovery/v3/ads.proto");
+        assert!(files.contains(&"envoy/service/discovery/v3/discovery.proto".to_string()));
+        assert!(files.contains(&"google/protobuf/any.proto".to_string()));
+        let mut unique = files.clone();
+        unique.sort();
+        unique.dedup();
+        assert_eq!(unique.len(), files.len());
+
+        let message = "envoy.service.discovery.v3.DiscoveryRequest";
+        assert_eq!(
+            file_names(
+                reflection.respond(&request(MessageRequest::FileContainingSymbol(
+                    message.to_string()
+                )))
+            )[0],
+            "envoy/service/discovery/v3/discovery.proto"
+        );
+        match reflection.respond(&request(MessageRequest::FileByFilename(
+            "missing.proto".to_string(),
+        ))) {
+            MessageResponse::ErrorResponse(error) => {
+                assert_eq!(error.error_code, Code::NotFound as i32)
+            }
+            other => panic!("unexpected response {:?}", other),
+        }
+    }
+}
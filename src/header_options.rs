@@ -0,0 +1,240 @@
+use std::str::FromStr;
+use std::time::Duration;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::field_errors::{field, Findings, Validate};
+
+use crate::protobuf::envoy::config::core::v3::HttpProtocolOptions;
+use crate::protobuf::envoy::extensions::filters::network::http_connection_manager::v3::http_connection_manager::{
+    CodecType, ServerHeaderTransformation, StripPortMode,
+};
+use crate::protobuf::envoy::extensions::filters::network::http_connection_manager::v3::HttpConnectionManager;
+
+/// The options of the connection manager of the listener of a service: the
+/// host port of the requests, the `Server` header of the responses, the
+/// codec of the downstream connections and how long they are kept. A
+/// service sets those it needs, the controller the others, Envoy keeping
+/// the port, sending `Server: envoy`, telling HTTP/1.1 and HTTP/2 apart,
+/// closing idle connections after an hour, draining for 5s and delaying the
+/// close by 1s for those neither sets. An idle or delayed close timeout of
+/// `0` disables it.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Hash)]
+#[serde(deny_unknown_fields)]
+pub struct HeaderOptions {
+    #[serde(default)]
+    pub strip_host_port: Option<StripHostPort>,
+    #[serde(default)]
+    pub server_header: Option<ServerHeader>,
+    // the `Server` header set by Envoy
+    #[serde(default)]
+    pub server_name: Option<std::string::String>,
+    // for the Envoy builds, or the clients, that can't tell the protocols
+    // apart
+    #[serde(default)]
+    pub codec: Option<Codec>,
+    // how long a connection with no request in flight is kept
+    #[serde(default)]
+    pub idle_timeout: Option<Timeout>,
+    // between the two GOAWAY frames of the HTTP/2 connections closed
+    #[serde(default)]
+    pub drain_timeout: Option<Timeout>,
+    // how long Envoy waits for the client to close first
+    #[serde(default)]
+    pub delayed_close_timeout: Option<Timeout>,
+}
+
+/// Whether the port of the host of the requests is removed before routing,
+/// as with `api.example.com:443` matching the host `api.example.com`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum StripHostPort {
+    Keep,
+    // only the port of the listener
+    Matching,
+    Any,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum ServerHeader {
+    Overwrite,
+    AppendIfAbsent,
+    PassThrough,
+}
+
+/// The protocol of the downstream connections of the TCP listeners, the
+/// HTTP/3 ones always being QUIC.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Codec {
+    // the one of each connection, with HTTP/2 prior knowledge or ALPN
+    Auto,
+    Http1,
+    Http2,
+}
+
+impl FromStr for Codec {
+    type Err = std::string::String;
+
+    fn from_str(name: &str) -> Result<Codec, Self::Err> {
+        match name {
+            "auto" => Ok(Codec::Auto),
+            "http1" => Ok(Codec::Http1),
+            "http2" => Ok(Codec::Http2),
+            _ => Err(format!("unknown codec '{}'", name)),
+        }
+    }
+}
+
+/// A duration written as `30s`, `500ms`, `5m` or `1h`, `0` alone needing no
+/// unit.
+#[derive(Debug, Clone, Copy, PartialEq, Hash)]
+pub struct Timeout(pub Duration);
+
+impl FromStr for Timeout {
+    type Err = std::string::String;
+
+    fn from_str(value: &str) -> Result<Timeout, Self::Err> {
+        if value == "0" {
+            return Ok(Timeout(Duration::from_secs(0)));
+        }
+        let digits = value
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(value.len());
+        let (amount, unit) = value.split_at(digits);
+        let amount: u64 = amount
+            .parse()
+            .map_err(|_| format!("'{}' is not a duration, like 30s", value))?;
+        let millis = match unit {
+            "ms" => Some(amount),
+            "s" => amount.checked_mul(1000),
+            "m" => amount.checked_mul(60 * 1000),
+            "h" => amount.checked_mul(60 * 60 * 1000),
+            _ => return Err(format!("'{}' has no unit among ms, s, m and h", value)),
+        };
+        millis
+            .map(|millis| Timeout(Duration::from_millis(millis)))
+            .ok_or_else(|| format!("'{}' is too long", value))
+    }
+}
+
+impl std::fmt::Display for Timeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let millis = self.0.as_millis();
+        match millis {
+            0 => write!(f, "0"),
+            _ if !millis.is_multiple_of(1000) => write!(f, "{}ms", millis),
+            _ => write!(f, "{}s", millis / 1000),
+        }
+    }
+}
+
+impl Serialize for Timeout {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Timeout {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Timeout, D::Error> {
+        let value = std::string::String::deserialize(deserializer)?;
+        value.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+impl Validate for HeaderOptions {
+    fn validate(&self, path: &str, findings: &mut Findings) {
+        if let Some(ref name) = self.server_name {
+            if name.is_empty() || name.contains(|c: char| c.is_control()) {
+                findings.error(field(path, "server_name"), "needs a value on a single line");
+            }
+        }
+        if let (Some(_), Some(ServerHeader::PassThrough)) = (&self.server_name, self.server_header)
+        {
+            findings.warning(
+                field(path, "server_name"),
+                "is never sent, the server header passing through",
+            );
+        }
+        // Envoy has no way to skip the drain, a final GOAWAY racing the new
+        // streams instead
+        if self
+            .drain_timeout
+            .is_some_and(|timeout| timeout.0.as_millis() == 0)
+        {
+            findings.error(
+                field(path, "drain_timeout"),
+                "cannot be 0, Envoy always drains the HTTP/2 connections",
+            );
+        }
+        if self
+            .idle_timeout
+            .is_some_and(|timeout| timeout.0.as_millis() == 0)
+        {
+            findings.warning(
+                field(path, "idle_timeout"),
+                "of 0 keeps the idle connections for ever, leaking those of lost FINs",
+            );
+        }
+    }
+}
+
+impl HeaderOptions {
+    /// These settings, falling back to `defaults` for those not set.
+    pub fn or(&self, defaults: &HeaderOptions) -> HeaderOptions {
+        HeaderOptions {
+            strip_host_port: self.strip_host_port.or(defaults.strip_host_port),
+            server_header: self.server_header.or(defaults.server_header),
+            server_name: self
+                .server_name
+                .clone()
+                .or_else(|| defaults.server_name.clone()),
+            codec: self.codec.or(defaults.codec),
+            idle_timeout: self.idle_timeout.or(defaults.idle_timeout),
+            drain_timeout: self.drain_timeout.or(defaults.drain_timeout),
+            delayed_close_timeout: self
+                .delayed_close_timeout
+                .or(defaults.delayed_close_timeout),
+        }
+    }
+
+    pub fn apply(&self, connection_manager: &mut HttpConnectionManager) {
+        match self.strip_host_port {
+            Some(StripHostPort::Matching) => connection_manager.strip_matching_host_port = true,
+            Some(StripHostPort::Any) => {
+                connection_manager.strip_port_mode = Some(StripPortMode::StripAnyHostPort(true))
+            }
+            Some(StripHostPort::Keep) | None => {}
+        }
+        if let Some(header) = self.server_header {
+            connection_manager.server_header_transformation = match header {
+                ServerHeader::Overwrite => ServerHeaderTransformation::Overwrite,
+                ServerHeader::AppendIfAbsent => ServerHeaderTransformation::AppendIfAbsent,
+                ServerHeader::PassThrough => ServerHeaderTransformation::PassThrough,
+            } as i32;
+        }
+        if let Some(ref name) = self.server_name {
+            connection_manager.server_name = name.clone();
+        }
+        if let Some(codec) = self.codec {
+            connection_manager.codec_type = match codec {
+                Codec::Auto => CodecType::Auto,
+                Codec::Http1 => CodecType::Http1,
+                Codec::Http2 => CodecType::Http2,
+            } as i32;
+        }
+        if let Some(Timeout(timeout)) = self.idle_timeout {
+            connection_manager
+                .common_http_protocol_options
+                .get_or_insert_with(HttpProtocolOptions::default)
+                .idle_timeout = Some(timeout.into());
+        }
+        if let Some(Timeout(timeout)) = self.drain_timeout {
+            connection_manager.drain_timeout = Some(timeout.into());
+        }
+        if let Some(Timeout(timeout)) = self.delayed_close_timeout {
+            connection_manager.delayed_close_timeout = Some(timeout.into());
+        }
+    }
+}
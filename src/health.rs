@@ -0,0 +1,146 @@
+use std::sync::{Arc, RwLock};
+
+use warp::http::StatusCode;
+use warp::Filter;
+
+use crate::configuration::{self, Readiness};
+use crate::shutdown::Shutdown;
+
+/// Liveness and readiness probes: `/healthz` answers as long as the process
+/// does, `/readyz` only once an up to date snapshot is served and until
+/// shutdown starts.
+pub fn routes(
+    config: Arc<RwLock<configuration::Config>>,
+    shutdown: Shutdown,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let healthz = warp::path!("healthz").and(warp::get()).map(|| "ok");
+
+    let readyz = warp::path!("readyz").and(warp::get()).map(move || {
+        if shutdown.is_draining() {
+            return warp::reply::with_status(
+                "draining".to_string(),
+                StatusCode::SERVICE_UNAVAILABLE,
+            );
+        }
+        let (status, body) = match config.read().unwrap().readiness() {
+            Readiness::Ready => (StatusCode::OK, "ready".to_string()),
+            Readiness::NotReady(reason) => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                format!("not ready: {}", reason),
+            ),
+            Readiness::Degraded(reason) => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                format!("degraded: {}", reason),
+            ),
+        };
+        warp::reply::with_status(body, status)
+    });
+
+    healthz.or(readyz)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn get(config: &Arc<RwLock<configuration::Config>>, path: &str) -> (StatusCode, String) {
+        get_draining(config, &Shutdown::default(), path).await
+    }
+
+    async fn get_draining(
+        config: &Arc<RwLock<configuration::Config>>,
+        shutdown: &Shutdown,
+        path: &str,
+    ) -> (StatusCode, String) {
+        let response = warp::test::request()
+            .path(path)
+            .reply(&routes(Arc::clone(config), shutdown.clone()))
+            .await;
+        let body = std::string::String::from_utf8(response.body().to_vec()).unwrap();
+        (response.status(), body)
+    }
+
+    fn services(content: &str) -> configuration::Config {
+        let services: configuration::ServicesList = serde_json::from_str(content).unwrap();
+        configuration::Config::from_services(services, content)
+    }
+
+    #[tokio::test]
+    async fn not_ready_until_every_service_is_exported() {
+        let config = Arc::new(RwLock::new(configuration::Config::default()));
+        assert_eq!(
+            get(&config, "/healthz").await,
+            (StatusCode::OK, "ok".to_string())
+        );
+        assert_eq!(
+            get(&config, "/readyz").await,
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "not ready: no snapshot published yet".to_string()
+            )
+        );
+
+        configuration::reload_failed(&config, &anyhow::anyhow!("invalid services config"));
+        assert_eq!(
+            get(&config, "/readyz").await,
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "not ready: invalid services config".to_string()
+            )
+        );
+
+        // a service missing its target domain doesn't export
+        let bad =
+            r#"[{"id": 1, "hosts": [], "policies": [], "target_domain": "", "proxy_rules": []}]"#;
+        configuration::publish(&config, services(bad)).unwrap();
+        let (status, body) = get(&config, "/readyz").await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(
+            body.starts_with("not ready: failed to export 1 services"),
+            "{}",
+            body
+        );
+    }
+
+    #[tokio::test]
+    async fn degraded_while_serving_an_old_snapshot() {
+        let config = Arc::new(RwLock::new(configuration::Config::default()));
+        configuration::publish(&config, services("[]")).unwrap();
+        assert_eq!(
+            get(&config, "/readyz").await,
+            (StatusCode::OK, "ready".to_string())
+        );
+
+        configuration::reload_failed(&config, &anyhow::anyhow!("cannot reach Porta"));
+        assert_eq!(
+            get(&config, "/readyz").await,
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "degraded: cannot reach Porta".to_string()
+            )
+        );
+
+        // loading the served content again recovers
+        configuration::publish(&config, services("[]")).unwrap();
+        assert_eq!(
+            get(&config, "/readyz").await,
+            (StatusCode::OK, "ready".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn not_ready_while_draining() {
+        let config = Arc::new(RwLock::new(configuration::Config::default()));
+        configuration::publish(&config, services("[]")).unwrap();
+        let shutdown = Shutdown::default();
+        shutdown.trigger();
+        assert_eq!(
+            get_draining(&config, &shutdown, "/readyz").await,
+            (StatusCode::SERVICE_UNAVAILABLE, "draining".to_string())
+        );
+        assert_eq!(
+            get_draining(&config, &shutdown, "/healthz").await,
+            (StatusCode::OK, "ok".to_string())
+        );
+    }
+}
@@ -0,0 +1,308 @@
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use curl::easy::{Easy, List};
+
+use crate::identity::Identity;
+
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How the controller makes its own requests: the OIDC discoveries, the
+/// fetches of the Porta and HTTP sources and the downloads of the wasm
+/// modules. They go through the proxy of their scheme unless their host is
+/// one of `no_proxy`, and never through one curl would pick from the
+/// environment itself, the environment being read along with the flags.
+/// They carry the identity of the controller.
+#[derive(Debug, Clone, PartialEq, Hash)]
+pub struct HttpClient {
+    pub http_proxy: Option<url::Url>,
+    pub https_proxy: Option<url::Url>,
+    pub no_proxy: Vec<NoProxy>,
+    // PEM bundle verifying the servers and the proxies, the system CAs
+    // otherwise
+    pub ca_file: Option<PathBuf>,
+    pub connect_timeout: Duration,
+    // of whole requests, downloads of modules taking longer
+    pub timeout: Duration,
+    pub identity: Identity,
+}
+
+/// A host reached without the proxy.
+#[derive(Debug, Clone, PartialEq, Hash)]
+pub enum NoProxy {
+    // `*`, every host
+    Any,
+    // the domain and its subdomains, as with `example.com` and
+    // `.example.com`
+    Domain(std::string::String),
+    // the addresses of a network, as `10.0.0.0/8`, or an address; hosts
+    // named by a domain are not resolved to match them
+    Network(IpAddr, u8),
+}
+
+impl Default for HttpClient {
+    fn default() -> HttpClient {
+        HttpClient {
+            http_proxy: None,
+            https_proxy: None,
+            no_proxy: Vec::new(),
+            ca_file: None,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            timeout: DEFAULT_TIMEOUT,
+            identity: Identity::default(),
+        }
+    }
+}
+
+/// The hosts of a `NO_PROXY` list, comma separated, or why one isn't. Ports
+/// are ignored, as curl does.
+pub fn parse_no_proxy(list: &str) -> Result<Vec<NoProxy>, std::string::String> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            if entry == "*" {
+                return Ok(NoProxy::Any);
+            }
+            if let Some((address, prefix)) = entry.split_once('/') {
+                let address: IpAddr = address
+                    .parse()
+                    .map_err(|_| format!("'{}' is not a network", entry))?;
+                let max = if address.is_ipv4() { 32 } else { 128 };
+                return match prefix.parse() {
+                    Ok(prefix) if prefix <= max => Ok(NoProxy::Network(address, prefix)),
+                    _ => Err(format!("'{}' has an invalid prefix length", entry)),
+                };
+            }
+            let host = entry.trim_start_matches('[');
+            let host = match host.split_once(']') {
+                Some((address, _)) => address,
+                // a port after a single colon, IPv6 addresses having more
+                None if host.matches(':').count() == 1 => host.split(':').next().unwrap(),
+                None => host,
+            };
+            if let Ok(address) = host.parse::<IpAddr>() {
+                let prefix = if address.is_ipv4() { 32 } else { 128 };
+                return Ok(NoProxy::Network(address, prefix));
+            }
+            let domain = host.trim_start_matches("*.").trim_start_matches('.');
+            let allowed = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '.';
+            if domain.is_empty() || !domain.chars().all(allowed) {
+                return Err(format!(
+                    "'{}' is not a domain, an address nor a network",
+                    entry
+                ));
+            }
+            Ok(NoProxy::Domain(domain.to_lowercase()))
+        })
+        .collect()
+}
+
+fn in_network(address: IpAddr, network: IpAddr, prefix: u8) -> bool {
+    let (address, network, bits) = match (address, network) {
+        (IpAddr::V4(address), IpAddr::V4(network)) => {
+            (u32::from(address).into(), u32::from(network).into(), 32)
+        }
+        (IpAddr::V6(address), IpAddr::V6(network)) => {
+            (u128::from(address), u128::from(network), 128)
+        }
+        _ => return false,
+    };
+    let shift = bits - u32::from(prefix);
+    prefix == 0 || address >> shift == network >> shift
+}
+
+impl NoProxy {
+    fn matches(&self, host: &url::Host<&str>) -> bool {
+        match (self, host) {
+            (NoProxy::Any, _) => true,
+            (NoProxy::Domain(domain), url::Host::Domain(host)) => {
+                let host = host.to_lowercase();
+                host == *domain || host.ends_with(&format!(".{}", domain))
+            }
+            (NoProxy::Network(network, prefix), url::Host::Ipv4(address)) => {
+                in_network(IpAddr::V4(*address), *network, *prefix)
+            }
+            (NoProxy::Network(network, prefix), url::Host::Ipv6(address)) => {
+                in_network(IpAddr::V6(*address), *network, *prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl HttpClient {
+    /// The proxy the requests to `url` go through, if any.
+    pub fn proxy(&self, url: &url::Url) -> Option<&url::Url> {
+        let proxy = match url.scheme() {
+            "https" => self.https_proxy.as_ref(),
+            _ => self.http_proxy.as_ref(),
+        };
+        let bypassed = match url.host() {
+            Some(host) => self.no_proxy.iter().any(|entry| entry.matches(&host)),
+            None => true,
+        };
+        proxy.filter(|_| !bypassed)
+    }
+
+    /// The headers of every request, those setting their own appending
+    /// them to these.
+    pub fn headers(&self) -> Result<List> {
+        let mut headers = List::new();
+        if let Some(line) = self.identity.header_line() {
+            headers.append(&line)?;
+        }
+        Ok(headers)
+    }
+
+    /// A request to `url`, through the proxy and with the CAs, timeouts and
+    /// headers of the client, for the caller to add to and perform.
+    pub fn easy(&self, url: &url::Url) -> Result<Easy> {
+        let mut easy = Easy::new();
+        easy.url(url.as_str())?;
+        easy.useragent(&self.identity.value())?;
+        easy.http_headers(self.headers()?)?;
+        easy.connect_timeout(self.connect_timeout)?;
+        easy.timeout(self.timeout)?;
+        // an empty one rather than none, for curl not to read the
+        // environment again
+        easy.proxy(self.proxy(url).map(url::Url::as_str).unwrap_or_default())?;
+        if let Some(ref ca_file) = self.ca_file {
+            easy.cainfo(ca_file)?;
+            let ca_file = ca_file
+                .to_str()
+                .with_context(|| format!("{} is not a UTF-8 path", ca_file.display()))?;
+            easy.proxy_cainfo(ca_file)?;
+        }
+        Ok(easy)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http_harness::{Response, Server};
+
+    // The requests `server` got, as their request lines along with their
+    // user agents and x-gateway headers.
+    fn received(server: &Server) -> Vec<std::string::String> {
+        server
+            .requests()
+            .iter()
+            .map(|request| {
+                let mut agent = request.header("user-agent").unwrap_or_default().to_string();
+                if let Some(identity) = request.header("x-gateway") {
+                    agent = format!("{}, {}", agent, identity);
+                }
+                format!("{} {} HTTP/1.1 {}", request.method, request.target, agent)
+            })
+            .collect()
+    }
+
+    fn get(client: &HttpClient, url: &str) -> std::string::String {
+        let mut easy = client.easy(&url::Url::parse(url).unwrap()).unwrap();
+        let mut body = Vec::new();
+        {
+            let mut transfer = easy.transfer();
+            transfer
+                .write_function(|data| {
+                    body.extend_from_slice(data);
+                    Ok(data.len())
+                })
+                .unwrap();
+            transfer.perform().unwrap();
+        }
+        std::string::String::from_utf8(body).unwrap()
+    }
+
+    #[test]
+    fn requests_go_through_the_proxy_unless_bypassed() {
+        let proxy = Server::start(|_| Response::ok("proxied"));
+        let server = Server::start(|_| Response::ok("direct"));
+        let mut client = HttpClient {
+            http_proxy: Some(url::Url::parse(&proxy.url()).unwrap()),
+            ..Default::default()
+        };
+
+        assert_eq!(get(&client, "http://issuer.example.com/certs"), "proxied");
+        assert_eq!(
+            received(&proxy),
+            [format!(
+                "GET http://issuer.example.com/certs HTTP/1.1 {}",
+                Identity::default().value()
+            )]
+        );
+
+        client.no_proxy = parse_no_proxy("localhost, 127.0.0.0/8").unwrap();
+        assert_eq!(get(&client, &format!("{}/certs", server.url())), "direct");
+        let port = url::Url::parse(&server.url()).unwrap().port().unwrap();
+        let localhost = format!("http://localhost:{}/certs", port);
+        assert_eq!(get(&client, &localhost), "direct");
+        assert_eq!(proxy.requests().len(), 1);
+        assert_eq!(server.requests().len(), 2);
+    }
+
+    #[test]
+    fn requests_carry_the_identity_of_the_controller() {
+        let server = Server::start(|_| Response::ok(""));
+        let client = HttpClient {
+            identity: Identity {
+                header: Some("x-gateway".to_string()),
+                cluster: Some("eu-west".to_string()),
+            },
+            ..Default::default()
+        };
+        get(&client, &format!("{}/services", server.url()));
+        let identity = format!(
+            "gateway-ng-controller/{} (cluster eu-west)",
+            env!("CARGO_PKG_VERSION")
+        );
+        assert_eq!(
+            received(&server),
+            [format!("GET /services HTTP/1.1 {}, {}", identity, identity)]
+        );
+    }
+
+    #[test]
+    fn no_proxy_matches_domain_suffixes_and_networks() {
+        let client = HttpClient {
+            http_proxy: Some(url::Url::parse("http://proxy:3128").unwrap()),
+            https_proxy: Some(url::Url::parse("http://proxy:3129").unwrap()),
+            no_proxy: parse_no_proxy("example.com,*.svc,10.0.0.0/8,[fd00::1]:8080,172.16.0.1:80")
+                .unwrap(),
+            ..Default::default()
+        };
+        let proxied = |url: &str| client.proxy(&url::Url::parse(url).unwrap()).is_some();
+        for url in &[
+            "https://example.com/",
+            "https://API.example.com/",
+            "http://porta.apps.svc:3000/",
+            "http://10.1.2.3/",
+            "http://[fd00::1]/",
+            "http://172.16.0.1/",
+        ] {
+            assert!(!proxied(url), "{}", url);
+        }
+        for url in &[
+            "https://notexample.com/",
+            "https://example.com.evil.com/",
+            "http://11.0.0.1/",
+            "http://172.16.0.2/",
+            "http://[fd00::2]/",
+        ] {
+            assert!(proxied(url), "{}", url);
+        }
+        assert_eq!(
+            client.proxy(&url::Url::parse("https://3scale.net/").unwrap()),
+            client.https_proxy.as_ref()
+        );
+
+        for list in &["10.0.0.0/33", "a/b", "exa mple.com"] {
+            assert!(parse_no_proxy(list).is_err(), "{}", list);
+        }
+        assert_eq!(parse_no_proxy("*").unwrap(), [NoProxy::Any]);
+    }
+}
@@ -0,0 +1,47 @@
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+
+use crate::type_urls::TypeUrls;
+
+use crate::protobuf::envoy::config::route::v3::FilterConfig;
+use crate::protobuf::envoy::extensions::filters::network::http_connection_manager::v3::HttpFilter;
+
+// The names of the HTTP filters of the listeners, which the routes refer to
+// the filters by.
+pub const RBAC: &str = "envoy.filters.http.rbac";
+pub const CORS: &str = "envoy.filters.http.cors";
+pub const LOCAL_RATE_LIMIT: &str = "envoy.filters.http.local_ratelimit";
+pub const JWT_AUTHN: &str = "envoy.filters.http.jwt_authn";
+// the 3scale authorization filter, a wasm filter named apart from the
+// services filter for routes to tell them apart
+pub const THREESCALE_AUTH: &str = "threescale.filters.http.auth";
+// the services filter, reporting to 3scale
+pub const WASM: &str = "envoy.filters.http.wasm";
+// resolving the hosts of the requests of dynamic forward proxies
+pub const DYNAMIC_FORWARD_PROXY: &str = "envoy.filters.http.dynamic_forward_proxy";
+pub const ROUTER: &str = "envoy.filters.http.router";
+
+/// The per-filter config of a route disabling the filters named `names`.
+pub fn disabled(
+    names: &[&str],
+    type_urls: &TypeUrls,
+) -> Result<BTreeMap<std::string::String, prost_types::Any>> {
+    let config = type_urls.pack(&FilterConfig {
+        disabled: true,
+        ..Default::default()
+    })?;
+    Ok(names
+        .iter()
+        .map(|name| (name.to_string(), config.clone()))
+        .collect())
+}
+
+/// Leave out of the per-filter `configs` of a route those of the filters
+/// the listener doesn't have, which Envoy would refuse.
+pub fn retain_present(
+    configs: &mut BTreeMap<std::string::String, prost_types::Any>,
+    filters: &[HttpFilter],
+) {
+    configs.retain(|name, _| filters.iter().any(|filter| &filter.name == name));
+}
@@ -0,0 +1,133 @@
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex};
+
+/// A request the server got, with its header lines as they were sent.
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: std::string::String,
+    pub target: std::string::String,
+    pub headers: Vec<std::string::String>,
+}
+
+impl Request {
+    /// The target of the request, but for its query string.
+    pub fn path(&self) -> &str {
+        self.target.split('?').next().unwrap_or_default()
+    }
+
+    /// The value of the header `name`, whatever its case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find_map(|line| {
+            let (header, value) = line.split_once(':')?;
+            Some(value.trim()).filter(|_| header.eq_ignore_ascii_case(name))
+        })
+    }
+}
+
+/// What the server answers a request with.
+#[derive(Debug, Clone)]
+pub struct Response {
+    pub status: u32,
+    pub headers: Vec<(std::string::String, std::string::String)>,
+    pub body: std::string::String,
+}
+
+impl Response {
+    pub fn ok(body: impl Into<std::string::String>) -> Response {
+        Response::status(200, body)
+    }
+
+    pub fn status(status: u32, body: impl Into<std::string::String>) -> Response {
+        Response {
+            status,
+            headers: Vec::new(),
+            body: body.into(),
+        }
+    }
+
+    pub fn not_found() -> Response {
+        Response::status(404, "")
+    }
+
+    pub fn header(mut self, name: &str, value: impl Into<std::string::String>) -> Response {
+        self.headers.push((name.to_string(), value.into()));
+        self
+    }
+}
+
+/// An HTTP/1.1 server on a port of its own, for as long as the tests run,
+/// answering each request, one connection at a time, with what its handler
+/// returns, and keeping the requests for the tests to look at.
+pub struct Server {
+    address: std::net::SocketAddr,
+    requests: Arc<Mutex<Vec<Request>>>,
+}
+
+impl Server {
+    pub fn start(handler: impl Fn(&Request) -> Response + Send + 'static) -> Server {
+        Server::start_on(TcpListener::bind("127.0.0.1:0").unwrap(), handler)
+    }
+
+    /// The server answering on `listener`, like the port of one that was
+    /// down.
+    pub fn start_on(
+        listener: TcpListener,
+        handler: impl Fn(&Request) -> Response + Send + 'static,
+    ) -> Server {
+        let address = listener.local_addr().unwrap();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let received = Arc::clone(&requests);
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request_line = std::string::String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let mut parts = request_line.split_whitespace();
+                let mut request = Request {
+                    method: parts.next().unwrap_or_default().to_string(),
+                    target: parts.next().unwrap_or_default().to_string(),
+                    headers: Vec::new(),
+                };
+                loop {
+                    let mut line = std::string::String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line == "\r\n" || line.is_empty() {
+                        break;
+                    }
+                    request.headers.push(line.trim_end().to_string());
+                }
+                let response = handler(&request);
+                received.lock().unwrap().push(request);
+                let mut head = format!("HTTP/1.1 {} X\r\n", response.status);
+                for (name, value) in &response.headers {
+                    head.push_str(&format!("{}: {}\r\n", name, value));
+                }
+                write!(
+                    stream,
+                    "{}Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    head,
+                    response.body.len(),
+                    response.body
+                )
+                .unwrap();
+            }
+        });
+        Server { address, requests }
+    }
+
+    /// The URL of the server, as `http://127.0.0.1:41234`.
+    pub fn url(&self) -> std::string::String {
+        format!("http://{}", self.address)
+    }
+
+    /// The requests the server got so far.
+    pub fn requests(&self) -> Vec<Request> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    pub fn clear(&self) {
+        self.requests.lock().unwrap().clear();
+    }
+}
@@ -0,0 +1,77 @@
+use crate::protobuf::envoy::config::core::v3::{HeaderValue, HeaderValueOption};
+
+/// What the requests of the infrastructure are told apart by: those of the
+/// controller have it as their user agent, and, when `header` is given,
+/// those of the controller and those Envoy proxies to the upstreams of the
+/// services carry it in that header too, as `x-gateway`. It names the
+/// controller, its version and, when given, the `cluster` it runs in, as
+/// `gateway-ng-controller/0.1.0 (cluster eu-west)`, nothing secret that
+/// the logs would have to keep out.
+#[derive(Debug, Clone, Default, PartialEq, Hash)]
+pub struct Identity {
+    pub header: Option<std::string::String>,
+    pub cluster: Option<std::string::String>,
+}
+
+impl Identity {
+    pub fn value(&self) -> std::string::String {
+        let controller = format!("{}/{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+        match self.cluster {
+            Some(ref cluster) => format!("{} (cluster {})", controller, cluster),
+            None => controller,
+        }
+    }
+
+    /// The header line of the requests of the controller, if any.
+    pub fn header_line(&self) -> Option<std::string::String> {
+        self.header
+            .as_ref()
+            .map(|header| format!("{}: {}", header, self.value()))
+    }
+
+    /// The header Envoy sets on the requests it proxies, rather than the
+    /// one a client could have sent, if any: `via` is appended to instead,
+    /// for the proxies on the way to still tell the loops.
+    pub fn request_header(&self) -> Option<HeaderValueOption> {
+        self.header.as_ref().map(|header| HeaderValueOption {
+            header: Some(HeaderValue {
+                key: header.clone(),
+                value: self.value(),
+                ..Default::default()
+            }),
+            append: Some(header == "via"),
+            ..Default::default()
+        })
+    }
+}
+
+/// Why `name` can't name the header, if it can't: a token of lowercase
+/// letters, digits and `-`, not one of the pseudo-headers nor one Envoy
+/// sets itself, nor one of those of the clients the header would replace
+/// on every request.
+pub fn check_header(name: &str) -> Result<(), &'static str> {
+    let allowed = |c: char| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-';
+    if name.is_empty() || !name.chars().all(allowed) {
+        return Err("must be lowercase letters, digits or '-'");
+    }
+    let clients = [
+        "host",
+        "user-agent",
+        "authorization",
+        "cookie",
+        "content-length",
+    ];
+    if clients.contains(&name) || name.starts_with("x-envoy-") {
+        return Err("is a header Envoy or the clients set");
+    }
+    Ok(())
+}
+
+/// Why `name` can't name the cluster, if it can't: it is written in the
+/// header lines of the requests, which a control character would break.
+pub fn check_cluster(name: &str) -> Result<(), &'static str> {
+    if name.chars().any(|c| c.is_ascii_control()) {
+        return Err("must not have control characters");
+    }
+    Ok(())
+}
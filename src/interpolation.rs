@@ -0,0 +1,172 @@
+use std::cell::Cell;
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize, Serializer};
+
+/// The prefix of the variables of the controller environment the secrets
+/// may be read from, for the services files not to read any other, like
+/// the credentials of the controller itself.
+pub const VARIABLE_PREFIX: &str = "GATEWAY_SECRET_";
+
+const REDACTED: &str = "<redacted>";
+
+thread_local! {
+    // whether the secrets written in the services files serialize as they
+    // are, see `revealed`
+    static REVEALED: Cell<bool> = const { Cell::new(false) };
+}
+
+/// A secret of the services files, as `${GATEWAY_SECRET_IDP_API_KEY}` for
+/// the value of that variable of the controller environment, read when the
+/// secret is used rather than when the file is, or else the value itself.
+/// Its `Debug` only names the variable, and it serializes as its variable
+/// or redacted, for the secrets to stay out of the logs, the diffs of the
+/// services and the admin API.
+#[derive(Deserialize, Clone, PartialEq)]
+#[serde(transparent)]
+pub struct SecretValue(std::string::String);
+
+impl SecretValue {
+    /// The variable the value is read from, if any.
+    pub fn variable(&self) -> Option<&str> {
+        self.0
+            .strip_prefix("${")
+            .and_then(|name| name.strip_suffix('}'))
+            .filter(|name| {
+                !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+            })
+    }
+
+    /// Why the value can't be read, if it can't: a variable without the
+    /// prefix of those of the secrets.
+    pub fn check(&self) -> Result<(), std::string::String> {
+        match self.variable() {
+            Some(name) if !name.starts_with(VARIABLE_PREFIX) => Err(format!(
+                "reads {}, the secrets only being read from the variables starting with {}",
+                name, VARIABLE_PREFIX
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn resolve(&self) -> Result<std::string::String> {
+        self.check().map_err(|e| anyhow!(e))?;
+        match self.variable() {
+            Some(name) => std::env::var(name).with_context(|| format!("{} is not set", name)),
+            None => Ok(self.0.clone()),
+        }
+    }
+}
+
+impl Serialize for SecretValue {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        if self.variable().is_some() || REVEALED.with(Cell::get) {
+            serializer.serialize_str(&self.0)
+        } else {
+            serializer.serialize_str(REDACTED)
+        }
+    }
+}
+
+impl std::fmt::Debug for SecretValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.variable() {
+            Some(name) => write!(f, "${{{}}}", name),
+            None => f.write_str(REDACTED),
+        }
+    }
+}
+
+impl From<&str> for SecretValue {
+    fn from(value: &str) -> SecretValue {
+        SecretValue(value.to_string())
+    }
+}
+
+/// What `f` returns, the secrets it serializes being written as they are,
+/// for the copies of the services the controller reads back, like the
+/// snapshot the leader persists for its followers.
+pub fn revealed<T>(f: impl FnOnce() -> T) -> T {
+    // redacting again even when `f` panics
+    struct Reveal(bool);
+    impl Drop for Reveal {
+        fn drop(&mut self) {
+            let previous = self.0;
+            REVEALED.with(|revealed| revealed.set(previous));
+        }
+    }
+    let _reveal = Reveal(REVEALED.with(|revealed| revealed.replace(true)));
+    f()
+}
+
+/// `value` with the strings of its fields named like secrets, as the
+/// `token` of the 3scale auth settings, redacted: the settings kept as JSON
+/// have no `SecretValue` to redact them.
+pub fn redact(value: &mut serde_json::Value) {
+    let secret = |key: &str| {
+        let key = key.to_ascii_lowercase();
+        ["token", "secret", "password"]
+            .iter()
+            .any(|name| key.contains(name))
+    };
+    match value {
+        serde_json::Value::Object(fields) => {
+            for (key, value) in fields.iter_mut() {
+                if secret(key) && value.is_string() {
+                    *value = REDACTED.into();
+                } else {
+                    redact(value);
+                }
+            }
+        }
+        serde_json::Value::Array(items) => items.iter_mut().for_each(redact),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn secrets_are_read_from_the_environment_and_redacted() {
+        std::env::set_var("GATEWAY_SECRET_INTERPOLATION_TEST_TOKEN", "s3cr3t");
+        let interpolated = SecretValue::from("${GATEWAY_SECRET_INTERPOLATION_TEST_TOKEN}");
+        assert_eq!(interpolated.resolve().unwrap(), "s3cr3t");
+        assert_eq!(
+            format!("{:?}", interpolated),
+            "${GATEWAY_SECRET_INTERPOLATION_TEST_TOKEN}"
+        );
+        assert_eq!(
+            serde_json::to_value(&interpolated).unwrap(),
+            "${GATEWAY_SECRET_INTERPOLATION_TEST_TOKEN}"
+        );
+
+        let literal = SecretValue::from("s3cr3t");
+        assert_eq!(literal.resolve().unwrap(), "s3cr3t");
+        assert_eq!(format!("{:?}", literal), "<redacted>");
+        assert_eq!(serde_json::to_value(&literal).unwrap(), "<redacted>");
+        assert_eq!(
+            revealed(|| serde_json::to_value(&literal).unwrap()),
+            "s3cr3t"
+        );
+        assert_eq!(serde_json::to_value(&literal).unwrap(), "<redacted>");
+
+        let unset = SecretValue::from("${GATEWAY_SECRET_INTERPOLATION_TEST_UNSET}");
+        assert_eq!(
+            format!("{:#}", unset.resolve().unwrap_err()),
+            "GATEWAY_SECRET_INTERPOLATION_TEST_UNSET is not set: environment variable not found"
+        );
+    }
+
+    #[test]
+    fn secrets_are_only_read_from_their_variables() {
+        std::env::set_var("INTERPOLATION_TEST_CONTROLLER_TOKEN", "s3cr3t");
+        let other = SecretValue::from("${INTERPOLATION_TEST_CONTROLLER_TOKEN}");
+        assert!(other.check().is_err());
+        assert_eq!(
+            format!("{:#}", other.resolve().unwrap_err()),
+            "reads INTERPOLATION_TEST_CONTROLLER_TOKEN, the secrets only being read from the variables starting with GATEWAY_SECRET_"
+        );
+    }
+}
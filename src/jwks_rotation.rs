@@ -0,0 +1,152 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use crate::configuration;
+use crate::publisher::Publisher;
+
+// How often the keys the services inline are fetched again.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(300);
+
+// The keys the services inline, by service, those that could not be fetched
+// left out.
+fn key_sets(config: &RwLock<configuration::Config>) -> BTreeMap<u32, std::string::String> {
+    let (services, client) = {
+        let config = config.read().unwrap();
+        (config.get_services(), config.wasm().http_client.clone())
+    };
+    services
+        .iter()
+        .filter_map(|service| match service.inline_jwks(&client)? {
+            Ok(jwks) => Some((service.id, jwks)),
+            Err(e) => {
+                tracing::warn!(
+                    service.id = service.id,
+                    "Cannot fetch the OIDC keys: {:#}",
+                    e
+                );
+                None
+            }
+        })
+        .collect()
+}
+
+async fn fetch(config: &Arc<RwLock<configuration::Config>>) -> BTreeMap<u32, std::string::String> {
+    let config = Arc::clone(config);
+    tokio::task::spawn_blocking(move || key_sets(&config))
+        .await
+        .unwrap_or_default()
+}
+
+// Whether a key set of `current` differs from the one of `last`, the ones
+// that failed to be fetched keeping theirs.
+fn rotated(
+    last: &BTreeMap<u32, std::string::String>,
+    current: &BTreeMap<u32, std::string::String>,
+) -> bool {
+    current
+        .iter()
+        .any(|(id, jwks)| last.get(id).is_some_and(|last| last != jwks))
+}
+
+// Fetch the keys again, scheduling the services for export when some
+// rotated, only on the leader, the followers serving the keys it persisted.
+async fn refresh(publisher: &Publisher, last: &mut BTreeMap<u32, std::string::String>) -> bool {
+    if !publisher.leadership().is_leader() {
+        return false;
+    }
+    let current = fetch(publisher.config()).await;
+    let changed = rotated(last, &current);
+    last.extend(current);
+    if changed {
+        tracing::info!("OIDC keys of the services changed, exporting them again");
+        // the services of an issuer are always exported anew
+        publisher.schedule_filters();
+    }
+    changed
+}
+
+async fn reload(publisher: Publisher, interval: Duration) {
+    let mut last = BTreeMap::new();
+    refresh(&publisher, &mut last).await;
+    loop {
+        tokio::time::delay_for(interval).await;
+        refresh(&publisher, &mut last).await;
+    }
+}
+
+/// Export the services inlining the keys of their OIDC issuer again
+/// whenever the issuer rotates them, the providers of those services
+/// getting the new keys. Only the leader watches the issuers.
+pub fn spawn_reloader(publisher: Publisher) {
+    tokio::spawn(reload(publisher, REFRESH_INTERVAL));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http_harness::{Response, Server};
+    use crate::leader::{Leadership, Role};
+    use std::sync::Mutex;
+
+    fn keys(kid: &str) -> std::string::String {
+        format!(
+            r#"{{"keys": [{{"kty": "RSA", "kid": "{}", "n": "AQAB", "e": "AQAB"}}]}}"#,
+            kid
+        )
+    }
+
+    // An issuer serving the keys of `jwks` at `/certs`, along with a config
+    // of a service inlining them.
+    fn issuer(jwks: &Arc<Mutex<std::string::String>>) -> (Server, configuration::Config) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let discovery = format!(r#"{{"jwks_uri": "{}/certs"}}"#, url);
+        let jwks = Arc::clone(jwks);
+        let server = Server::start_on(listener, move |request| match request.path() {
+            "/certs" => Response::ok(jwks.lock().unwrap().as_str()),
+            _ => Response::ok(discovery.as_str()),
+        });
+        let content = format!(
+            r#"[{{"id": 1, "hosts": ["one"], "policies": [], "target_domain": "http://one:80", "proxy_rules": [],
+                "oidc_issuer": "{}", "oidc_discovery": {{"jwks_mode": "inline"}}}}]"#,
+            url
+        );
+        let services = serde_json::from_str(&content).unwrap();
+        (
+            server,
+            configuration::Config::from_services(services, &content),
+        )
+    }
+
+    #[tokio::test]
+    async fn rotated_keys_are_exported_again_by_the_leader() {
+        let jwks = Arc::new(Mutex::new(keys("k1")));
+        let (server, config) = issuer(&jwks);
+        let publisher = Publisher::new(Arc::new(RwLock::new(config)), Duration::from_millis(0));
+
+        let mut last = BTreeMap::new();
+        assert!(!refresh(&publisher, &mut last).await);
+        assert_eq!(last[&1], keys("k1"));
+        // served again alike, nothing is exported
+        assert!(!refresh(&publisher, &mut last).await);
+
+        *jwks.lock().unwrap() = keys("k2");
+        assert!(refresh(&publisher, &mut last).await);
+        assert_eq!(last[&1], keys("k2"));
+        assert!(!refresh(&publisher, &mut last).await);
+
+        // the followers leave the issuers be
+        let (follower_server, config) = issuer(&jwks);
+        let follower = Publisher::with_leadership(
+            Arc::new(RwLock::new(config)),
+            Duration::from_millis(0),
+            Leadership::new(Role::Follower, None),
+        );
+        let mut last = BTreeMap::new();
+        assert!(!refresh(&follower, &mut last).await);
+        assert!(last.is_empty());
+        assert!(follower_server.requests().is_empty());
+        assert!(!server.requests().is_empty());
+    }
+}
@@ -0,0 +1,536 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use futures::{StreamExt, TryStreamExt};
+use k8s_openapi::api::coordination::v1::{Lease, LeaseSpec};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::{MicroTime, ObjectMeta};
+use k8s_openapi::chrono::{self, Utc};
+use kube::api::{Api, ListParams, Meta, PatchParams, PatchStrategy, PostParams, WatchEvent};
+use kube::{Client, CustomResource};
+use serde::{Deserialize, Serialize};
+
+use crate::cli::LeaderElection;
+use crate::configuration::{self, ParseOptions};
+use crate::conflicts::HostConflicts;
+use crate::field_errors::FieldErrors;
+use crate::leader::{self, Leadership, Role};
+use crate::publisher::Publisher;
+use crate::service::Service;
+
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+const DEFAULT_LEASE_NAME: &str = "gateway-ng-controller";
+const DEFAULT_LEASE_DURATION: Duration = Duration::from_secs(15);
+
+/// A gateway service declared as a custom resource, its spec being a
+/// `Service` as found in the services config file. The spec is kept as plain
+/// JSON so that a malformed resource is rejected on its own instead of
+/// failing the whole list.
+#[derive(CustomResource, Serialize, Deserialize, Debug, Clone)]
+#[kube(
+    group = "gateway.3scale.net",
+    version = "v1alpha1",
+    kind = "GatewayService",
+    namespaced,
+    status = "GatewayServiceStatus"
+)]
+pub struct GatewayServiceSpec {
+    #[serde(flatten)]
+    pub fields: serde_json::Map<std::string::String, serde_json::Value>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct GatewayServiceStatus {
+    pub accepted: bool,
+    // serialized as null when unset so that merge patches clear it
+    #[serde(default)]
+    pub message: Option<std::string::String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub observed_generation: Option<i64>,
+}
+
+// Resources keyed by namespace and name, with the generation each was at.
+type Resources = BTreeMap<(std::string::String, std::string::String), (i64, Service)>;
+
+/// Services source watching `GatewayService` resources in a set of
+/// namespaces.
+pub struct KubeSource {
+    namespaces: Vec<std::string::String>,
+    // reject the resources with fields serde doesn't know, rather than only
+    // warn about them
+    strict: bool,
+    // what resources serving the same hosts make
+    host_conflicts: HostConflicts,
+}
+
+impl KubeSource {
+    /// A source watching `namespaces`, or the namespace of the kube config
+    /// context when there are none. The specs are read as the services
+    /// files are, strictly or not, and checked against each other the same
+    /// way.
+    pub fn new(namespaces: Vec<std::string::String>, options: ParseOptions) -> KubeSource {
+        KubeSource {
+            namespaces,
+            strict: options.strict,
+            host_conflicts: options.host_conflicts,
+        }
+    }
+
+    /// Watch every namespace in the background, reconnecting with backoff,
+    /// for as long as the replica leads.
+    pub fn spawn(self, publisher: Publisher) {
+        tokio::spawn(async move {
+            let kube_config = match kube::Config::infer().await {
+                Ok(kube_config) => kube_config,
+                Err(e) => {
+                    tracing::error!("Cannot load the kube config: {:?}", e);
+                    return;
+                }
+            };
+            let namespaces = if self.namespaces.is_empty() {
+                vec![kube_config.default_ns.clone()]
+            } else {
+                self.namespaces
+            };
+            let client = Client::new(kube_config);
+            // whether the replica leads, as the watchers wait for it
+            let leadership = publisher.leadership();
+            let (leads, leading) = tokio::sync::watch::channel(leadership.is_leader());
+            leadership.on_change(move |role| {
+                let _ = leads.broadcast(role == Role::Leader);
+            });
+
+            let resources = Arc::new(Mutex::new(Resources::new()));
+            for namespace in namespaces {
+                tracing::info!("Watching GatewayService resources in {}", namespace);
+                let watcher = NamespaceWatcher {
+                    api: Api::namespaced(client.clone(), &namespace),
+                    namespace,
+                    resources: Arc::clone(&resources),
+                    publisher: publisher.clone(),
+                    strict: self.strict,
+                    host_conflicts: self.host_conflicts,
+                };
+                tokio::spawn(watcher.run(leading.clone()));
+            }
+        });
+    }
+}
+
+struct NamespaceWatcher {
+    api: Api<GatewayService>,
+    namespace: std::string::String,
+    resources: Arc<Mutex<Resources>>,
+    publisher: Publisher,
+    strict: bool,
+    host_conflicts: HostConflicts,
+}
+
+impl NamespaceWatcher {
+    // Watch while `leading` tells the replica leads, the followers leaving
+    // the resources to the leader.
+    async fn run(self, mut leading: tokio::sync::watch::Receiver<bool>) {
+        let mut backoff = MIN_BACKOFF;
+        loop {
+            while !*leading.borrow() {
+                if leading.recv().await.is_none() {
+                    return;
+                }
+            }
+            let lost = async { while let Some(true) = leading.recv().await {} };
+            let watched = tokio::select! {
+                watched = self.watch(&mut backoff) => watched,
+                _ = lost => {
+                    tracing::info!(
+                        "Leaving the GatewayService resources of {} to the leader",
+                        self.namespace
+                    );
+                    continue;
+                }
+            };
+            match watched {
+                Ok(()) => tracing::debug!("Watch on {} expired, resuming", self.namespace),
+                Err(e) => {
+                    tracing::error!(
+                        "Watch on {} failed, retrying in {:?}: {:#}",
+                        self.namespace,
+                        backoff,
+                        e
+                    );
+                    configuration::reload_failed(self.publisher.config(), &e);
+                    tokio::time::delay_for(backoff).await;
+                    backoff = std::cmp::min(backoff * 2, MAX_BACKOFF);
+                }
+            }
+        }
+    }
+
+    // List everything in the namespace, then follow the changes from there
+    // until the watch ends.
+    async fn watch(&self, backoff: &mut Duration) -> Result<()> {
+        let lp = ListParams::default();
+        let list = self.api.list(&lp).await.context("failed to list")?;
+        *backoff = MIN_BACKOFF;
+
+        let mut version = list.metadata.resource_version.clone().unwrap_or_default();
+        self.resources
+            .lock()
+            .unwrap()
+            .retain(|(namespace, _), _| *namespace != self.namespace);
+        for resource in list.items {
+            self.apply(resource).await;
+        }
+        self.publish();
+
+        let mut events = self
+            .api
+            .watch(&lp, &version)
+            .await
+            .context("failed to watch")?
+            .boxed();
+        while let Some(event) = events.try_next().await.context("watch failed")? {
+            match event {
+                WatchEvent::Added(resource) | WatchEvent::Modified(resource) => {
+                    version = Meta::resource_ver(&resource).unwrap_or(version);
+                    self.apply(resource).await;
+                }
+                WatchEvent::Deleted(resource) => {
+                    version = Meta::resource_ver(&resource).unwrap_or(version);
+                    self.resources
+                        .lock()
+                        .unwrap()
+                        .remove(&(self.namespace.clone(), Meta::name(&resource)));
+                }
+                WatchEvent::Bookmark(bookmark) => {
+                    version = bookmark.metadata.resource_version;
+                    continue;
+                }
+                // usually the resource version being too old, relist
+                WatchEvent::Error(e) => return Err(anyhow::Error::new(e)),
+            }
+            self.publish();
+        }
+        Ok(())
+    }
+
+    // Track the resource if it converts to a valid service and report the
+    // outcome on its status.
+    async fn apply(&self, resource: GatewayService) {
+        let name = Meta::name(&resource);
+        let generation = resource.metadata.generation.unwrap_or_default();
+        let key = (self.namespace.clone(), name.clone());
+
+        let status = match to_service(&resource, self.strict) {
+            Ok(service) => {
+                self.resources
+                    .lock()
+                    .unwrap()
+                    .insert(key, (generation, service));
+                GatewayServiceStatus {
+                    accepted: true,
+                    message: None,
+                    observed_generation: Some(generation),
+                }
+            }
+            Err(e) => {
+                tracing::error!("Rejecting {}/{}: {:#}", self.namespace, name, e);
+                self.resources.lock().unwrap().remove(&key);
+                GatewayServiceStatus {
+                    accepted: false,
+                    message: Some(format!("{:#}", e)),
+                    observed_generation: Some(generation),
+                }
+            }
+        };
+
+        // status updates come back as watch events, so only write changes,
+        // and leave them to the leader
+        if resource.status.as_ref() == Some(&status) || !self.publisher.leadership().is_leader() {
+            return;
+        }
+        let patch = serde_json::json!({ "status": status });
+        let pp = PatchParams {
+            patch_strategy: PatchStrategy::Merge,
+            ..Default::default()
+        };
+        let patch = serde_json::to_vec(&patch).unwrap();
+        if let Err(e) = self.api.patch_status(&name, &pp, patch).await {
+            tracing::warn!(
+                "Cannot update the status of {}/{}: {:?}",
+                self.namespace,
+                name,
+                e
+            );
+        }
+    }
+
+    fn publish(&self) {
+        let (services, content) = {
+            let resources = self.resources.lock().unwrap();
+            let services = resources
+                .iter()
+                .map(|((namespace, name), (_, service))| {
+                    (Some(format!("{}/{}", namespace, name)), service.clone())
+                })
+                .collect();
+            // status writes don't bump the generation, spec changes do
+            let content: Vec<std::string::String> = resources
+                .iter()
+                .map(|((namespace, name), (generation, _))| {
+                    format!("{}/{}@{}", namespace, name, generation)
+                })
+                .collect();
+            (services, content.join("\n"))
+        };
+        let (services, conflicting) =
+            configuration::without_conflicts(services, self.host_conflicts);
+        for (_, e) in conflicting {
+            tracing::error!("Leaving out a conflicting GatewayService: {:#}", e);
+        }
+
+        // coalesced with the events of the other namespaces
+        self.publisher
+            .schedule(configuration::Config::from_services(services, &content));
+    }
+}
+
+/// Leader election through a `Lease`, which the leader renews a few times
+/// per lease duration and the followers take over once it expires.
+pub struct LeaseElection {
+    name: std::string::String,
+    namespace: Option<std::string::String>,
+    identity: std::string::String,
+    duration: Duration,
+}
+
+impl LeaseElection {
+    /// The lease of `election`, named `gateway-ng-controller` and lasting
+    /// 15 seconds unless it says otherwise. The namespace defaults to the
+    /// one of the kube config context, and replicas are told apart by
+    /// `POD_NAME`.
+    pub fn new(election: &LeaderElection) -> LeaseElection {
+        LeaseElection {
+            name: election
+                .lease_name
+                .clone()
+                .unwrap_or_else(|| DEFAULT_LEASE_NAME.to_string()),
+            namespace: election.lease_namespace.clone(),
+            identity: leader::identity(),
+            duration: election.lease_duration.unwrap_or(DEFAULT_LEASE_DURATION),
+        }
+    }
+
+    fn spec(&self, acquired: Option<MicroTime>, transitions: i32) -> LeaseSpec {
+        let now = MicroTime(Utc::now());
+        LeaseSpec {
+            holder_identity: Some(self.identity.clone()),
+            lease_duration_seconds: Some(self.duration.as_secs() as i32),
+            acquire_time: Some(acquired.unwrap_or_else(|| now.clone())),
+            renew_time: Some(now),
+            lease_transitions: Some(transitions),
+        }
+    }
+
+    // Acquire or renew the lease, returning whether it is held. Conflicting
+    // writes mean another replica got to it first.
+    async fn try_acquire(&self, api: &Api<Lease>) -> Result<bool> {
+        let conflict = |e: &kube::Error| matches!(e, kube::Error::Api(e) if e.code == 409);
+        let mut lease = match api.get(&self.name).await {
+            Ok(lease) => lease,
+            Err(kube::Error::Api(ref e)) if e.code == 404 => {
+                let lease = Lease {
+                    metadata: ObjectMeta {
+                        name: Some(self.name.clone()),
+                        ..Default::default()
+                    },
+                    spec: Some(self.spec(None, 0)),
+                };
+                return match api.create(&PostParams::default(), &lease).await {
+                    Ok(_) => Ok(true),
+                    Err(ref e) if conflict(e) => Ok(false),
+                    Err(e) => Err(e).context("cannot create the lease"),
+                };
+            }
+            Err(e) => return Err(e).context("cannot get the lease"),
+        };
+
+        let spec = lease.spec.take().unwrap_or_default();
+        let held = spec.holder_identity.as_deref() == Some(&self.identity);
+        let expired = match spec.renew_time {
+            Some(MicroTime(renewed)) => {
+                let duration = spec.lease_duration_seconds.unwrap_or_default();
+                renewed + chrono::Duration::seconds(duration.into()) < Utc::now()
+            }
+            None => true,
+        };
+        if !held && !expired {
+            return Ok(false);
+        }
+        let transitions = spec.lease_transitions.unwrap_or_default();
+        lease.spec = Some(if held {
+            self.spec(spec.acquire_time, transitions)
+        } else {
+            self.spec(None, transitions + 1)
+        });
+        // the resource version read makes the replace fail if it changed
+        match api
+            .replace(&self.name, &PostParams::default(), &lease)
+            .await
+        {
+            Ok(_) => Ok(true),
+            Err(ref e) if conflict(e) => Ok(false),
+            Err(e) => Err(e).context("cannot update the lease"),
+        }
+    }
+
+    /// Compete for the lease in the background. A leader failing to renew
+    /// it steps down once it expires.
+    pub fn spawn(self, leadership: Leadership) {
+        tokio::spawn(async move {
+            let kube_config = match kube::Config::infer().await {
+                Ok(kube_config) => kube_config,
+                Err(e) => {
+                    tracing::error!("Cannot load the kube config: {:?}", e);
+                    return;
+                }
+            };
+            let namespace = self
+                .namespace
+                .clone()
+                .unwrap_or_else(|| kube_config.default_ns.clone());
+            tracing::info!(
+                "Competing for the lease {}/{} as {}",
+                namespace,
+                self.name,
+                self.identity
+            );
+            let api: Api<Lease> = Api::namespaced(Client::new(kube_config), &namespace);
+            let mut renewed: Option<std::time::Instant> = None;
+            loop {
+                match self.try_acquire(&api).await {
+                    Ok(true) => {
+                        renewed = Some(std::time::Instant::now());
+                        leadership.set(Role::Leader);
+                    }
+                    Ok(false) => {
+                        renewed = None;
+                        leadership.set(Role::Follower);
+                    }
+                    Err(e) => {
+                        tracing::warn!("Cannot compete for the leadership: {:#}", e);
+                        // leading until the lease expires
+                        let expired = match renewed {
+                            Some(at) => at.elapsed() >= self.duration,
+                            None => true,
+                        };
+                        if expired {
+                            leadership.set(Role::Follower);
+                        }
+                    }
+                }
+                tokio::time::delay_for(self.duration / 3).await;
+            }
+        });
+    }
+}
+
+/// Convert a `GatewayService` into the service it declares, the fields
+/// serde doesn't know being errors when `strict`.
+pub fn to_service(resource: &GatewayService, strict: bool) -> Result<Service> {
+    let spec = serde_json::Value::Object(resource.spec.fields.clone());
+    let (service, findings) = Service::from_value(&spec, "spec", strict);
+    for warning in &findings.warnings {
+        tracing::warn!(resource = ?resource.metadata.name, "Service warning: {}", warning);
+    }
+    service.ok_or_else(|| FieldErrors(findings.errors).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gateway_service(spec: serde_json::Value) -> serde_json::Result<GatewayService> {
+        serde_json::from_value(serde_json::json!({
+            "apiVersion": "gateway.3scale.net/v1alpha1",
+            "kind": "GatewayService",
+            "metadata": {"name": "web", "namespace": "apps", "generation": 2},
+            "spec": spec,
+        }))
+    }
+
+    #[test]
+    fn converts_the_spec_into_a_service() {
+        let resource = gateway_service(serde_json::json!({
+            "id": 7,
+            "hosts": ["web.app"],
+            "policies": [],
+            "target_domain": "http://web.app:80",
+            "proxy_rules": [
+                {"pattern": "/", "http_method": "GET", "metric_system_name": "hits", "delta": 1}
+            ],
+            "report_on": "successful_only",
+        }))
+        .unwrap();
+
+        let service = to_service(&resource, false).unwrap();
+        assert_eq!(service.id, 7);
+        assert_eq!(service.hosts, ["web.app"]);
+        assert_eq!(
+            service.filter_config(&Default::default()).proxy_rules.len(),
+            1
+        );
+        assert!(resource.status.is_none());
+    }
+
+    #[test]
+    fn invalid_specs_are_rejected() {
+        let resource = gateway_service(serde_json::json!({
+            "id": 7,
+            "hosts": ["web.app"],
+            "policies": [],
+            "target_domain": "http://web.app:80",
+            "proxy_rules": [],
+            "no_match_action": {"action": "deny", "status": 200},
+        }))
+        .unwrap();
+        assert!(to_service(&resource, false).is_err());
+
+        let missing_id = gateway_service(serde_json::json!({"hosts": []})).unwrap();
+        assert!(to_service(&missing_id, false).is_err());
+    }
+
+    #[test]
+    fn unknown_fields_fail_strict_sources_only() {
+        let resource = gateway_service(serde_json::json!({
+            "id": 7,
+            "hosts": ["web.app"],
+            "policies": [],
+            "target_domain": "http://web.app:80",
+            "proxy_rules": [],
+            "node_group": "edge",
+        }))
+        .unwrap();
+        assert!(to_service(&resource, false).is_ok());
+        assert_eq!(
+            format!("{:#}", to_service(&resource, true).unwrap_err()),
+            "spec.node_group (service 7): unknown field"
+        );
+    }
+
+    #[test]
+    fn status_uses_kubernetes_casing() {
+        let status = GatewayServiceStatus {
+            accepted: false,
+            message: Some("bad".to_string()),
+            observed_generation: Some(2),
+        };
+        assert_eq!(
+            serde_json::to_value(&status).unwrap(),
+            serde_json::json!({"accepted": false, "message": "bad", "observedGeneration": 2})
+        );
+    }
+}
@@ -0,0 +1,523 @@
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{bail, Context, Result};
+use data_encoding::HEXLOWER;
+use serde::Serialize;
+
+use crate::cli::LeaderElection;
+use crate::configuration;
+use crate::interpolation;
+#[cfg(feature = "kube-source")]
+use crate::kubernetes;
+use crate::migration;
+use crate::util::concurrency;
+use crate::util::file_utils::{self, DigestAlgorithm};
+
+// How often followers try to take over, and look for a newer snapshot.
+const RETRY_INTERVAL: Duration = Duration::from_secs(2);
+
+/// What a replica does with the services.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Reads the sources and publishes the versions.
+    Leader,
+    /// Serves the snapshot the leader persisted, until it takes over.
+    Follower,
+}
+
+/// Kind of election between the replicas.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    File,
+    #[cfg(feature = "kube-source")]
+    Kube,
+}
+
+impl Kind {
+    /// Names of the kinds this build supports.
+    pub fn names() -> Vec<&'static str> {
+        #[allow(unused_mut)]
+        let mut names = vec!["file"];
+        #[cfg(feature = "kube-source")]
+        names.push("kube");
+        names
+    }
+}
+
+impl std::str::FromStr for Kind {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> Result<Kind> {
+        match name {
+            "file" => Ok(Kind::File),
+            #[cfg(feature = "kube-source")]
+            "kube" => Ok(Kind::Kube),
+            _ => bail!("unknown leader election '{}'", name),
+        }
+    }
+}
+
+/// Name of this replica in the elections: the pod name in Kubernetes, or
+/// else the host name and process id.
+pub fn identity() -> std::string::String {
+    match std::env::var("POD_NAME") {
+        Ok(name) => name,
+        Err(_) => format!(
+            "{}:{}",
+            std::env::var("HOSTNAME").unwrap_or_else(|_| "localhost".to_string()),
+            std::process::id()
+        ),
+    }
+}
+
+type Listener = Box<dyn Fn(Role) + Send + Sync>;
+
+struct Shared {
+    role: Mutex<Role>,
+    listeners: Mutex<Vec<Listener>>,
+}
+
+/// The role of the replica, as elected. A replica without an election
+/// always leads.
+#[derive(Clone)]
+pub struct Leadership {
+    shared: Arc<Shared>,
+    // where the leader persists the services it publishes, for the
+    // followers to serve
+    snapshot: Option<PathBuf>,
+    // what the snapshot is checked with
+    digest: DigestAlgorithm,
+}
+
+impl Default for Leadership {
+    fn default() -> Leadership {
+        Leadership::new(Role::Leader, None)
+    }
+}
+
+impl Leadership {
+    pub fn new(role: Role, snapshot: Option<PathBuf>) -> Leadership {
+        Leadership {
+            shared: Arc::new(Shared {
+                role: Mutex::new(role),
+                listeners: Mutex::new(Vec::new()),
+            }),
+            snapshot,
+            digest: DigestAlgorithm::default(),
+        }
+    }
+
+    /// Check the snapshots with `digest`, the leader writing it next to
+    /// them.
+    pub fn with_digest(mut self, digest: DigestAlgorithm) -> Leadership {
+        self.digest = digest;
+        self
+    }
+
+    pub fn role(&self) -> Role {
+        *self.shared.role.lock().unwrap()
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.role() == Role::Leader
+    }
+
+    /// Call `listener` on every change of role, from whatever elected it.
+    pub fn on_change(&self, listener: impl Fn(Role) + Send + Sync + 'static) {
+        self.shared
+            .listeners
+            .lock()
+            .unwrap()
+            .push(Box::new(listener));
+    }
+
+    /// Take on the role an election gave.
+    pub fn set(&self, role: Role) {
+        {
+            let mut current = self.shared.role.lock().unwrap();
+            if *current == role {
+                return;
+            }
+            *current = role;
+        }
+        match role {
+            Role::Leader => tracing::info!("Elected leader, publishing the services"),
+            Role::Follower => tracing::warn!("Lost the leadership, following the leader"),
+        }
+        for listener in self.shared.listeners.lock().unwrap().iter() {
+            listener(role);
+        }
+    }
+
+    /// Persist the services of `config` for the followers, as a versioned
+    /// services file with a footer of its length and digest. Written
+    /// atomically, the followers never read half of it, and refuse one a
+    /// crash or the disk damaged.
+    pub fn persist(&self, config: &configuration::Config) -> Result<()> {
+        let path = match self.snapshot {
+            Some(ref path) => path,
+            None => return Ok(()),
+        };
+        // the followers read the secrets written in the services file too
+        let content = interpolation::revealed(|| {
+            serde_json::to_vec(&serde_json::json!({
+                "version": migration::CURRENT_VERSION,
+                "services": config.get_services(),
+            }))
+        })?;
+        file_utils::write_verified(path, &content, self.digest)
+    }
+
+    /// Serve the snapshot persisted by the leader whenever the replica
+    /// follows, checking it for changes in the background.
+    pub fn spawn_follower(&self, config: Arc<RwLock<configuration::Config>>) {
+        let path = match self.snapshot {
+            Some(ref path) => path.clone(),
+            None => return,
+        };
+        let digest = self.digest;
+        let leadership = self.clone();
+        concurrency::spawn(move || {
+            let mut last_modified = None;
+            loop {
+                if !leadership.is_leader() {
+                    let modified = modified(&path);
+                    if modified.is_some() && modified != last_modified {
+                        last_modified = modified;
+                        follow(&config, &path, digest);
+                    }
+                }
+                std::thread::sleep(RETRY_INTERVAL);
+            }
+        });
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    path.metadata().and_then(|m| m.modified()).ok()
+}
+
+// Where the digest of the snapshot at `path` is, as in `snapshot.json.sha256`.
+fn digest_path(path: &Path, digest: DigestAlgorithm) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".");
+    name.push(digest.name());
+    PathBuf::from(name)
+}
+
+// The snapshot at `path`, checked against its footer, or, without one,
+// against the digest file older leaders wrote along with it. A snapshot
+// with neither is refused, as one cut short.
+fn read_snapshot(path: &Path, digest: DigestAlgorithm) -> Result<configuration::Config> {
+    let content = match file_utils::read_verified(path) {
+        Ok(content) => content,
+        Err(e) => {
+            let content =
+                std::fs::read(path).with_context(|| format!("cannot read {}", path.display()))?;
+            if file_utils::has_footer(&content) {
+                return Err(e);
+            }
+            let digest_path = digest_path(path, digest);
+            let expected = std::fs::read_to_string(&digest_path).with_context(|| {
+                format!(
+                    "{} has no footer, as if cut short, nor a digest in {}",
+                    path.display(),
+                    digest_path.display()
+                )
+            })?;
+            let actual = HEXLOWER
+                .encode(file_utils::file_digest_blocking(digest, path.to_path_buf())?.as_ref());
+            if actual != expected.trim() {
+                bail!(
+                    "{} does not match {}",
+                    path.display(),
+                    digest_path.display()
+                );
+            }
+            content
+        }
+    };
+    configuration::Config::parse_content(
+        std::str::from_utf8(&content)?,
+        configuration::ServicesFormat::Json,
+        Default::default(),
+    )
+}
+
+fn follow(config: &RwLock<configuration::Config>, path: &Path, digest: DigestAlgorithm) {
+    let result =
+        read_snapshot(path, digest).and_then(|snapshot| configuration::publish(config, snapshot));
+    match result {
+        Ok(true) => tracing::info!(
+            version = config.read().unwrap().get_version(),
+            "Serving the snapshot of the leader"
+        ),
+        Ok(false) => {}
+        Err(e) => {
+            tracing::error!(
+                "Cannot serve the snapshot of the leader from {}: {:#}",
+                path.display(),
+                e
+            );
+            configuration::reload_failed(config, &e);
+        }
+    }
+}
+
+/// Election through an advisory lock on a file shared by the replicas, for
+/// those running outside of Kubernetes. The lock is held for as long as the
+/// process runs, or until the file is replaced.
+pub struct FileElection {
+    path: PathBuf,
+    retry: Duration,
+}
+
+impl FileElection {
+    pub fn new(path: impl AsRef<Path>) -> FileElection {
+        FileElection {
+            path: path.as_ref().to_path_buf(),
+            retry: RETRY_INTERVAL,
+        }
+    }
+
+    // The lock file, or `None` when another replica holds it.
+    fn try_lock(&self) -> Result<Option<File>> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(false)
+            .open(&self.path)
+            .with_context(|| format!("cannot open {}", self.path.display()))?;
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => return Ok(None),
+            Err(TryLockError::Error(e)) => {
+                return Err(e).with_context(|| format!("cannot lock {}", self.path.display()))
+            }
+        }
+        // for whoever wonders which replica leads
+        file.set_len(0)?;
+        writeln!(file, "{}", identity())?;
+        Ok(Some(file))
+    }
+
+    // Whether `file` is still the one at the path, rather than one deleted
+    // or replaced, which another replica could lock.
+    fn holds(&self, file: &File) -> bool {
+        use std::os::unix::fs::MetadataExt;
+
+        match (file.metadata(), self.path.metadata()) {
+            (Ok(held), Ok(current)) => held.dev() == current.dev() && held.ino() == current.ino(),
+            _ => false,
+        }
+    }
+
+    /// Compete for the lock in the background.
+    pub fn spawn(self, leadership: Leadership) {
+        tracing::info!("Competing for the lock of {}", self.path.display());
+        std::thread::spawn(move || {
+            let mut held = None;
+            loop {
+                held = match held.take() {
+                    Some(file) if self.holds(&file) => Some(file),
+                    Some(_) => {
+                        tracing::warn!("{} was replaced, stepping down", self.path.display());
+                        leadership.set(Role::Follower);
+                        None
+                    }
+                    None => match self.try_lock() {
+                        Ok(Some(file)) => {
+                            leadership.set(Role::Leader);
+                            Some(file)
+                        }
+                        Ok(None) => None,
+                        Err(e) => {
+                            tracing::warn!("Cannot compete for the leadership: {:#}", e);
+                            None
+                        }
+                    },
+                };
+                std::thread::sleep(self.retry);
+            }
+        });
+    }
+}
+
+/// How the replicas elect their leader.
+pub enum Election {
+    File(FileElection),
+    /// A `Lease` in the namespace of the controller.
+    #[cfg(feature = "kube-source")]
+    Kube(kubernetes::LeaseElection),
+}
+
+impl Election {
+    /// Set up the election `election` says, the file election locking its
+    /// lock path, the kube one taking its lease.
+    pub fn new(election: &LeaderElection) -> Result<Election> {
+        match election.kind {
+            Kind::File => match election.lock_path {
+                Some(ref path) => Ok(Election::File(FileElection::new(path))),
+                None => bail!("the file election needs a lock path"),
+            },
+            #[cfg(feature = "kube-source")]
+            Kind::Kube => Ok(Election::Kube(kubernetes::LeaseElection::new(election))),
+        }
+    }
+
+    pub fn spawn(self, leadership: Leadership) {
+        match self {
+            Election::File(election) => election.spawn(leadership),
+            #[cfg(feature = "kube-source")]
+            Election::Kube(election) => election.spawn(leadership),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::WasmSettings;
+
+    fn wait_for(leadership: &Leadership, role: Role) {
+        for _ in 0..100 {
+            if leadership.role() == role {
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        panic!("still {:?}", leadership.role());
+    }
+
+    fn shared() -> Arc<RwLock<configuration::Config>> {
+        let mut config = configuration::Config::default();
+        config.set_wasm(WasmSettings {
+            skip_sha: true,
+            ..Default::default()
+        });
+        Arc::new(RwLock::new(config))
+    }
+
+    #[test]
+    fn a_single_replica_holds_the_lock() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("leader.lock");
+        let election = |leadership: &Leadership| {
+            let mut election = FileElection::new(&path);
+            election.retry = Duration::from_millis(20);
+            election.spawn(leadership.clone());
+        };
+
+        let first = Leadership::new(Role::Follower, None);
+        election(&first);
+        wait_for(&first, Role::Leader);
+        let second = Leadership::new(Role::Follower, None);
+        election(&second);
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(second.role(), Role::Follower);
+
+        // a replaced lock file is up for grabs
+        std::fs::remove_file(&path).unwrap();
+        std::fs::write(&path, "").unwrap();
+        std::thread::sleep(Duration::from_millis(100));
+        assert_ne!(first.role(), second.role());
+    }
+
+    #[test]
+    fn followers_serve_the_persisted_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        let snapshot = dir.path().join("snapshot.json");
+        let content = serde_json::json!([{
+            "id": 1,
+            "hosts": ["one.app"],
+            "policies": [],
+            "target_domain": "http://one.app:80",
+            "proxy_rules": [],
+        }])
+        .to_string();
+        let leader = shared();
+        configuration::publish(
+            &leader,
+            configuration::Config::from_services(serde_json::from_str(&content).unwrap(), &content),
+        )
+        .unwrap();
+        Leadership::new(Role::Leader, Some(snapshot.clone()))
+            .persist(&leader.read().unwrap())
+            .unwrap();
+
+        let follower = shared();
+        let leadership = Leadership::new(Role::Follower, Some(snapshot));
+        leadership.spawn_follower(Arc::clone(&follower));
+        for _ in 0..100 {
+            if follower.read().unwrap().get_version() > 0 {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let follower = follower.read().unwrap();
+        assert_eq!(follower.get_version(), 1);
+        assert_eq!(
+            follower.get_snapshot().hash(),
+            leader.read().unwrap().get_snapshot().hash()
+        );
+    }
+
+    #[test]
+    fn snapshots_are_checked_against_their_digest() {
+        let dir = tempfile::tempdir().unwrap();
+        let snapshot = dir.path().join("snapshot.json");
+        let content = r#"[{"id": 1, "hosts": ["one.app"], "policies": [], "target_domain": "http://one.app:80", "proxy_rules": []}]"#;
+        let leader = shared();
+        configuration::publish(
+            &leader,
+            configuration::Config::from_services(serde_json::from_str(content).unwrap(), content),
+        )
+        .unwrap();
+        Leadership::new(Role::Leader, Some(snapshot.clone()))
+            .with_digest(DigestAlgorithm::Sha512)
+            .persist(&leader.read().unwrap())
+            .unwrap();
+        assert!(file_utils::has_footer(&std::fs::read(&snapshot).unwrap()));
+        let services = read_snapshot(&snapshot, DigestAlgorithm::Sha512)
+            .unwrap()
+            .get_services();
+        assert_eq!(services, leader.read().unwrap().get_services());
+
+        let persisted = std::fs::read_to_string(&snapshot).unwrap();
+        std::fs::write(&snapshot, persisted.replace("one.app", "evil.app")).unwrap();
+        let error = read_snapshot(&snapshot, DigestAlgorithm::Sha512).unwrap_err();
+        assert!(error.to_string().contains("is corrupted"), "{}", error);
+        // cut short by a crash, even where the JSON is whole
+        let (whole, _) = persisted.rsplit_once("\n#verified").unwrap();
+        for cut in &[&persisted[..persisted.len() / 2], whole] {
+            std::fs::write(&snapshot, cut).unwrap();
+            let error = read_snapshot(&snapshot, DigestAlgorithm::Sha512).unwrap_err();
+            assert!(
+                error.to_string().contains("has no footer, as if cut short"),
+                "{}",
+                error
+            );
+        }
+
+        // older leaders wrote the digest apart
+        let legacy = whole;
+        std::fs::write(&snapshot, legacy.replace("one.app", "evil.app")).unwrap();
+        let digest = file_utils::digest(DigestAlgorithm::Sha512, legacy.as_bytes()).unwrap();
+        std::fs::write(
+            dir.path().join("snapshot.json.sha512"),
+            HEXLOWER.encode(digest.as_ref()),
+        )
+        .unwrap();
+        let error = read_snapshot(&snapshot, DigestAlgorithm::Sha512).unwrap_err();
+        assert!(error.to_string().contains("does not match"), "{}", error);
+        std::fs::write(&snapshot, legacy).unwrap();
+        assert!(read_snapshot(&snapshot, DigestAlgorithm::Sha512).is_ok());
+        // nor are those of another digest taken as they are
+        assert!(read_snapshot(&snapshot, DigestAlgorithm::Sha256).is_err());
+    }
+}
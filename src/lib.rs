@@ -0,0 +1,89 @@
+//! The controller serving the 3scale services to Envoy over xDS, as a
+//! library for the tools embedding it, e.g. to build services in code with
+//! [`service::Service::builder`], and for the controller binary.
+#![deny(clippy::all)]
+
+pub mod admin;
+pub mod cli;
+pub mod configuration;
+pub mod conflicts;
+pub mod diff;
+pub mod dump_diff;
+pub mod environment;
+pub mod envoy_ads;
+pub mod envoy_cds;
+pub mod envoy_delta;
+pub mod envoy_helpers;
+pub mod envoy_lds;
+pub mod envoy_rest;
+pub mod envoy_sds;
+pub mod export;
+pub mod export_cache;
+pub mod field_errors;
+pub mod forward_proxy;
+pub mod forwarded;
+#[cfg(feature = "git-source")]
+pub mod git;
+pub mod grpc_health;
+pub mod grpc_reflection;
+pub mod header_options;
+pub mod health;
+pub mod http_client;
+pub mod http_filters;
+#[cfg(test)]
+mod http_harness;
+pub mod identity;
+pub mod interpolation;
+pub mod jwks_rotation;
+#[cfg(feature = "kube-source")]
+pub mod kubernetes;
+pub mod leader;
+pub mod listener_address;
+pub mod maintenance;
+pub mod metadata;
+pub mod migration;
+pub mod node_status;
+pub mod oidc;
+pub mod original_dst;
+pub mod panics;
+pub mod policy;
+pub mod porta;
+pub mod processor;
+pub mod propagation;
+pub mod proto_json;
+// rustfmt stable will break down with #[path = "..."] in modules, so skip
+// this module for now. See https://github.com/rust-lang/rustfmt/issues/4446.
+#[rustfmt::skip]
+pub mod protobuf;
+pub mod publisher;
+pub mod reconcile;
+pub mod reload;
+pub mod remote;
+pub mod request_id;
+pub mod rollback;
+pub mod routing;
+pub mod rule_patterns;
+pub mod secret;
+pub mod security_headers;
+pub mod service;
+pub mod shutdown;
+#[cfg(test)]
+mod signal_harness;
+pub mod snapshot;
+pub mod snapshot_cache;
+pub mod snippets;
+pub mod source;
+pub mod telemetry;
+pub mod threescale_auth;
+pub mod tls;
+pub mod type_urls;
+pub mod url_rewriting;
+pub mod util;
+pub mod validate;
+pub mod wasm_files;
+pub mod wasm_module;
+pub mod wasm_server;
+pub mod watcher;
+pub mod xds_auth;
+#[cfg(test)]
+mod xds_harness;
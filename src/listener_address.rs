@@ -0,0 +1,154 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::field_errors::{field, Findings, Validate};
+
+/// Where the listener of a service binds: an IPv4 or IPv6 address, or, when
+/// `dual_stack`, every address of both, with a listener on each of
+/// `0.0.0.0` and `::`. The IPv6 listeners only take IPv6 clients, Envoy
+/// binding them without `ipv4_compat`, so that both bind the same port
+/// whatever the `bindv6only` setting of the host.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Hash)]
+#[serde(deny_unknown_fields)]
+pub struct ListenerAddress {
+    // a literal, without brackets nor scope id
+    #[serde(default = "any_ipv4")]
+    pub address: std::string::String,
+    #[serde(default)]
+    pub dual_stack: bool,
+}
+
+fn any_ipv4() -> std::string::String {
+    Ipv4Addr::UNSPECIFIED.to_string()
+}
+
+impl Default for ListenerAddress {
+    fn default() -> ListenerAddress {
+        ListenerAddress {
+            address: any_ipv4(),
+            dual_stack: false,
+        }
+    }
+}
+
+/// The address of `literal`, or why it isn't one a listener binds.
+pub fn parse(literal: &str) -> Result<IpAddr, std::string::String> {
+    if literal.contains('%') {
+        return Err(format!(
+            "'{}' has a scope id, which listeners can't bind",
+            literal
+        ));
+    }
+    if literal.starts_with('[') {
+        return Err(format!("'{}' must be given without brackets", literal));
+    }
+    literal
+        .parse()
+        .map_err(|_| format!("'{}' is not an IPv4 nor an IPv6 address", literal))
+}
+
+impl Validate for ListenerAddress {
+    fn validate(&self, path: &str, findings: &mut Findings) {
+        match parse(&self.address) {
+            Err(e) => findings.error(field(path, "address"), e),
+            Ok(address) if self.dual_stack && !address.is_unspecified() => findings.error(
+                field(path, "dual_stack"),
+                format!(
+                    "needs the address to be 0.0.0.0 or ::, not {}, binding every address of both",
+                    address
+                ),
+            ),
+            Ok(_) => {}
+        }
+    }
+}
+
+impl ListenerAddress {
+    /// The addresses of the listeners, the one given first.
+    pub fn addresses(&self) -> Vec<IpAddr> {
+        let address = match parse(&self.address) {
+            Ok(address) => address,
+            // checked along with the service
+            Err(_) => return Vec::new(),
+        };
+        match (self.dual_stack, address) {
+            (true, IpAddr::V4(_)) => vec![address, IpAddr::V6(Ipv6Addr::UNSPECIFIED)],
+            (true, IpAddr::V6(_)) => vec![address, IpAddr::V4(Ipv4Addr::UNSPECIFIED)],
+            (false, _) => vec![address],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(settings: serde_json::Value) -> ListenerAddress {
+        serde_json::from_value(settings).unwrap()
+    }
+
+    #[test]
+    fn dual_stack_listeners_bind_both_families() {
+        let v6 = address(serde_json::json!({"address": "::"}));
+        assert_eq!(v6.addresses(), [IpAddr::V6(Ipv6Addr::UNSPECIFIED)]);
+        assert!(v6.findings("").errors.is_empty());
+
+        let dual = address(serde_json::json!({"dual_stack": true}));
+        assert!(dual.findings("").errors.is_empty());
+        assert_eq!(
+            dual.addresses(),
+            [
+                IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+            ]
+        );
+        let dual = address(serde_json::json!({"address": "::", "dual_stack": true}));
+        assert_eq!(
+            dual.addresses(),
+            [
+                IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+                IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_addresses_are_refused() {
+        for (settings, path, message) in &[
+            (
+                serde_json::json!({"address": "fe80::1%eth0"}),
+                "address",
+                "'fe80::1%eth0' has a scope id, which listeners can't bind",
+            ),
+            (
+                serde_json::json!({"address": "[::1]"}),
+                "address",
+                "'[::1]' must be given without brackets",
+            ),
+            (
+                serde_json::json!({"address": "2001:db8::g"}),
+                "address",
+                "'2001:db8::g' is not an IPv4 nor an IPv6 address",
+            ),
+            (
+                serde_json::json!({"address": "10.0.0.256"}),
+                "address",
+                "'10.0.0.256' is not an IPv4 nor an IPv6 address",
+            ),
+            (
+                serde_json::json!({"address": "::1", "dual_stack": true}),
+                "dual_stack",
+                "needs the address to be 0.0.0.0 or ::, not ::1, binding every address of both",
+            ),
+        ] {
+            let errors: Vec<_> = address(settings.clone())
+                .findings("")
+                .errors
+                .into_iter()
+                .map(|error| (error.path, error.message))
+                .collect();
+            assert_eq!(errors, [(path.to_string(), message.to_string())]);
+        }
+    }
+}
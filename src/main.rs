@@ -1,36 +1,126 @@
 #![deny(clippy::all)]
 
-use env_logger::Env;
+use std::sync::Arc;
+
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, EnvFilter};
 use warp::Filter;
 
-mod configuration;
-mod envoy_cds;
-mod envoy_helpers;
-mod envoy_lds;
-mod oidc;
-mod processor;
-// rustfmt stable will break down with #[path = "..."] in modules, so skip
-// this module for now. See https://github.com/rust-lang/rustfmt/issues/4446.
-#[rustfmt::skip]
-mod protobuf;
-mod service;
-mod threescale_auth;
-mod util;
-
-use processor::MasterProcess;
+use gateway_ng_controller::cli::{Command, ControllerConfig, LogFormat};
+use gateway_ng_controller::processor::MasterProcess;
+use gateway_ng_controller::{
+    admin, dump_diff, envoy_rest, export, health, proto_json, telemetry, tls, validate, wasm_server,
+};
+
+fn init_logger(settings: &ControllerConfig) -> anyhow::Result<telemetry::Guard> {
+    // RUST_LOG refines the level per module
+    let mut filter = EnvFilter::default().add_directive(settings.log_level.into());
+    if let Ok(directives) = std::env::var(EnvFilter::DEFAULT_ENV) {
+        for directive in directives.split(',').filter_map(|d| d.parse().ok()) {
+            filter = filter.add_directive(directive);
+        }
+    }
+    let (spans, guard) = telemetry::layer(settings.telemetry.as_ref())?;
+    // the events of libraries still on `log` are forwarded too
+    let registry = tracing_subscriber::registry().with(filter).with(spans);
+    match settings.log_format {
+        LogFormat::Text => registry.with(fmt::layer()).init(),
+        LogFormat::Pretty => registry.with(fmt::layer().pretty()).init(),
+        LogFormat::Json => registry
+            .with(fmt::layer().json().with_current_span(true))
+            .init(),
+    }
+    Ok(guard)
+}
+
+async fn serve(settings: ControllerConfig) -> anyhow::Result<()> {
+    settings.wasm.check_modules()?;
+    let (admin_port, health_port) = (settings.admin_port, settings.health_port);
+    let admin_enabled = settings.admin_enabled;
+    let reload_token = settings.admin_reload_token.clone();
+    if let Some(ref server) = settings.wasm_server {
+        let route = wasm_server::routes(server.root.clone());
+        tokio::spawn(warp::serve(route).run(([0, 0, 0, 0], server.port)));
+    }
+    let rest_xds_port = settings.rest_xds_port;
+    let rest_auth = settings.xds_auth()?;
+    let rest_tls = settings.tls.clone();
+    let keepalive = settings.grpc.tcp_keepalive;
+    let mut master_process = MasterProcess::new(settings);
+    if let Some(port) = rest_xds_port {
+        let registry = Arc::new(proto_json::Registry::new()?);
+        let rest = envoy_rest::routes(
+            master_process.config(),
+            registry,
+            rest_auth,
+            master_process.leadership(),
+        );
+        // over TLS as the gRPC discovery is
+        match rest_tls {
+            Some(settings) => {
+                let acceptor = tls::ReloadableAcceptor::new(settings)?;
+                acceptor.spawn_reloader()?;
+                let listener = tokio::net::TcpListener::bind(("0.0.0.0", port)).await?;
+                let incoming = tls::incoming(listener, acceptor, keepalive);
+                tokio::spawn(warp::serve(rest).run_incoming(incoming));
+            }
+            None => {
+                tokio::spawn(warp::serve(rest).run(([0, 0, 0, 0], port)));
+            }
+        }
+    }
+
+    let health = health::routes(master_process.config(), master_process.shutdown());
+    tokio::spawn(warp::serve(health).run(([0, 0, 0, 0], health_port)));
+
+    let files = warp::path("static").and(warp::fs::dir("static"));
+    if admin_enabled {
+        let registry = Arc::new(proto_json::Registry::new()?);
+        let admin = admin::routes(
+            master_process.config(),
+            master_process.statuses(),
+            registry,
+            admin::Reload {
+                reloader: master_process.reloader(),
+                token: reload_token,
+            },
+            master_process.leadership(),
+        );
+        tokio::spawn(warp::serve(files.or(admin)).run(([0, 0, 0, 0], admin_port)));
+    } else {
+        tokio::spawn(warp::serve(files).run(([0, 0, 0, 0], admin_port)));
+    }
+
+    master_process.start().await
+}
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
-    tokio::spawn(async move {
-        let route = warp::path("static").and(warp::fs::dir("static"));
-        warp::serve(route).run(([0, 0, 0, 0], 5001)).await;
-    });
-
-    let mut master_process = MasterProcess::default();
-    master_process
-        .start("0.0.0.0:5000".parse().unwrap())
-        .await?;
+    let settings = ControllerConfig::from_args(std::env::args_os()).unwrap_or_else(|e| e.exit());
+    // kept for the spans left to be exported when done
+    let _telemetry = init_logger(&settings)?;
+
+    match settings.command {
+        Command::Serve => serve(settings).await?,
+        Command::Validate => {
+            let report = validate::run(&settings);
+            print!("{}", report.render(settings.validation.format));
+            std::process::exit(report.exit_code());
+        }
+        Command::Export => export::run(&settings)?,
+        Command::Diff => match dump_diff::run(&settings) {
+            Ok(report) => {
+                print!("{}", report.render(settings.diff.format));
+                std::process::exit(report.exit_code());
+            }
+            // apart from the differences, for CI to tell them from errors
+            Err(e) => {
+                eprintln!("Error: {:#}", e);
+                std::process::exit(2);
+            }
+        },
+    }
     Ok(())
 }
 
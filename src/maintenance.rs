@@ -0,0 +1,317 @@
+use serde::{Deserialize, Serialize};
+
+use crate::field_errors::{field, index, Findings, Validate};
+use crate::rule_patterns;
+
+use crate::protobuf::envoy::config::core::v3::{
+    data_source::Specifier, DataSource, HeaderValue, HeaderValueOption,
+};
+use crate::protobuf::envoy::config::route::v3::{
+    route::Action, route_match::PathSpecifier, DirectResponseAction, Route, RouteMatch, VirtualHost,
+};
+
+/// The `maintenance_mode` policy of APIcast: requests answered by Envoy
+/// rather than proxied, all of them or those of the paths its condition
+/// tells.
+///
+/// Maintenance wins over the other policies. Without a condition, the
+/// listener of the service keeps none of the filters of the other policies
+/// or 3scale, the requests being neither authorized nor reported. With one,
+/// its routes come before those of the other policies, whose filters still
+/// run first.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MaintenanceMode {
+    #[serde(default = "MaintenanceMode::default_status")]
+    pub status: u32,
+    #[serde(default = "MaintenanceMode::default_message")]
+    pub message: std::string::String,
+    #[serde(default = "MaintenanceMode::default_content_type")]
+    pub message_content_type: std::string::String,
+    #[serde(default)]
+    pub condition: Option<Condition>,
+}
+
+impl Default for MaintenanceMode {
+    fn default() -> MaintenanceMode {
+        MaintenanceMode {
+            status: MaintenanceMode::default_status(),
+            message: MaintenanceMode::default_message(),
+            message_content_type: MaintenanceMode::default_content_type(),
+            condition: None,
+        }
+    }
+}
+
+/// Operations on the request, the maintenance applying when all of them
+/// hold, or any of them.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Condition {
+    #[serde(default)]
+    pub combine_op: CombineOp,
+    pub operations: Vec<Operation>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum CombineOp {
+    #[default]
+    And,
+    Or,
+}
+
+/// An operation of APIcast conditions. Only those routes can tell apart
+/// are taken: the path, as the `original_request.path` or `uri` Liquid
+/// variables, being equal to a plain value or matching a regex.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Operation {
+    pub left: std::string::String,
+    #[serde(default)]
+    pub left_type: ValueType,
+    pub op: std::string::String,
+    pub right: std::string::String,
+    #[serde(default)]
+    pub right_type: ValueType,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ValueType {
+    #[default]
+    Plain,
+    Liquid,
+}
+
+// Liquid variables holding the path of the request. Routes match the path
+// before other policies rewrite it, which is the original one anyway.
+const PATHS: &[&str] = &["original_request.path", "uri"];
+
+impl Operation {
+    fn path_specifier(&self) -> Result<PathSpecifier, std::string::String> {
+        let variable = self
+            .left
+            .trim()
+            .strip_prefix("{{")
+            .and_then(|left| left.strip_suffix("}}"))
+            .map(str::trim);
+        match variable {
+            Some(variable) if self.left_type == ValueType::Liquid && PATHS.contains(&variable) => {}
+            _ => {
+                return Err(format!(
+                    "'{}' is not the path, as {{{{ original_request.path }}}}, the one operand of routes",
+                    self.left
+                ))
+            }
+        }
+        if self.right_type != ValueType::Plain {
+            return Err("the path can only be compared with plain values".to_string());
+        }
+        match self.op.as_str() {
+            "==" => Ok(PathSpecifier::Path(self.right.clone())),
+            "matches" => {
+                regex::Regex::new(&self.right).map_err(|e| format!("not an RE2 regex: {}", e))?;
+                // ngx.re.match finds the regex anywhere in the path, while
+                // routes match it whole
+                Ok(PathSpecifier::SafeRegex(rule_patterns::re2(format!(
+                    ".*(?:{}).*",
+                    self.right
+                ))))
+            }
+            op => Err(format!(
+                "'{}' cannot be routed, only '==' and 'matches' can",
+                op
+            )),
+        }
+    }
+}
+
+impl MaintenanceMode {
+    fn default_status() -> u32 {
+        503
+    }
+
+    fn default_message() -> std::string::String {
+        "Service Unavailable - Maintenance".to_string()
+    }
+
+    fn default_content_type() -> std::string::String {
+        "text/plain; charset=utf-8".to_string()
+    }
+
+    /// Whether every request of the service is in maintenance.
+    pub fn is_total(&self) -> bool {
+        self.condition.is_none()
+    }
+
+    fn action(&self) -> Action {
+        Action::DirectResponse(DirectResponseAction {
+            status: self.status,
+            body: Some(DataSource {
+                specifier: Some(Specifier::InlineString(self.message.clone())),
+            }),
+        })
+    }
+
+    fn answer(&self, route: &mut Route) {
+        route.action = Some(self.action());
+        route.response_headers_to_add.push(HeaderValueOption {
+            header: Some(HeaderValue {
+                key: "content-type".to_string(),
+                value: self.message_content_type.clone(),
+                ..Default::default()
+            }),
+            append: Some(false),
+            ..Default::default()
+        });
+    }
+
+    /// Answer the requests in maintenance of the virtual host.
+    pub fn apply(&self, host: &mut VirtualHost) {
+        let condition = match self.condition {
+            Some(ref condition) => condition,
+            None => {
+                for route in host.routes.iter_mut() {
+                    self.answer(route);
+                }
+                return;
+            }
+        };
+        let routes: Vec<_> = condition
+            .operations
+            .iter()
+            .filter_map(|operation| operation.path_specifier().ok())
+            .map(|path_specifier| {
+                let mut route = Route {
+                    r#match: Some(RouteMatch {
+                        path_specifier: Some(path_specifier),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                };
+                self.answer(&mut route);
+                route
+            })
+            .collect();
+        // first, for maintenance to win over the routes of other policies
+        host.routes.splice(0..0, routes);
+    }
+}
+
+impl Validate for MaintenanceMode {
+    fn validate(&self, path: &str, findings: &mut Findings) {
+        if !(200..600).contains(&self.status) {
+            findings.error(
+                field(path, "status"),
+                format!("{} is not an HTTP status", self.status),
+            );
+        }
+        if let Some(ref condition) = self.condition {
+            let path = field(path, "condition");
+            let operations = field(&path, "operations");
+            if condition.operations.is_empty() {
+                findings.error(&operations, "needs at least one operation");
+            }
+            if condition.combine_op == CombineOp::And && condition.operations.len() > 1 {
+                findings.error(
+                    field(&path, "combine_op"),
+                    "needs to be 'or' for several operations, a route matching a path once",
+                );
+            }
+            for (i, operation) in condition.operations.iter().enumerate() {
+                if let Err(e) = operation.path_specifier() {
+                    findings.error(index(&operations, i), e);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An APIcast maintenance_mode policy, the way the 3scale admin portal
+    // saves it.
+    const POLICY: &str = include_str!("../testdata/apicast/maintenance_mode.json");
+
+    fn catch_all() -> VirtualHost {
+        VirtualHost {
+            routes: vec![Route {
+                r#match: Some(RouteMatch {
+                    path_specifier: Some(PathSpecifier::Prefix("/".to_string())),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn apicast_conditions_scope_the_maintenance() {
+        let policy: serde_json::Value = serde_json::from_str(POLICY).unwrap();
+        let maintenance: MaintenanceMode =
+            serde_json::from_value(policy["configuration"].clone()).unwrap();
+        let errors = maintenance.findings("").errors;
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert!(!maintenance.is_total());
+
+        let mut host = catch_all();
+        maintenance.apply(&mut host);
+        assert_eq!(host.routes.len(), 3);
+        let specifiers: Vec<_> = host
+            .routes
+            .iter()
+            .map(|route| route.r#match.as_ref().unwrap().path_specifier.clone())
+            .collect();
+        assert_eq!(
+            specifiers[0],
+            Some(PathSpecifier::Path("/status".to_string()))
+        );
+        match specifiers[1] {
+            Some(PathSpecifier::SafeRegex(ref regex)) => {
+                assert_eq!(regex.regex, ".*(?:^/api/v1/).*")
+            }
+            ref specifier => panic!("{:?}", specifier),
+        }
+        let route = &host.routes[0];
+        match route.action {
+            Some(Action::DirectResponse(ref response)) => {
+                assert_eq!(response.status, 503);
+                assert_eq!(
+                    response.body.as_ref().unwrap().specifier,
+                    Some(Specifier::InlineString(
+                        "{\"error\": \"Back in five minutes\"}".to_string()
+                    ))
+                );
+            }
+            ref action => panic!("{:?}", action),
+        }
+        let content_type = route.response_headers_to_add[0].header.clone().unwrap();
+        assert_eq!(content_type.value, "application/json");
+        // the other paths are proxied
+        assert_eq!(host.routes[2], catch_all().routes[0]);
+    }
+
+    #[test]
+    fn conditions_routes_cannot_tell_are_refused() {
+        let maintenance: MaintenanceMode = serde_json::from_value(serde_json::json!({
+            "condition": {
+                "operations": [
+                    {"left": "{{ original_request.path }}", "left_type": "liquid", "op": "!=", "right": "/"},
+                    {"left": "{{ headers['X-Debug'] }}", "left_type": "liquid", "op": "==", "right": "1"},
+                ],
+            },
+        }))
+        .unwrap();
+        let errors = maintenance.findings("").errors;
+        let paths: Vec<_> = errors.iter().map(|error| error.path.as_str()).collect();
+        assert_eq!(
+            paths,
+            [
+                "condition.combine_op",
+                "condition.operations[0]",
+                "condition.operations[1]"
+            ]
+        );
+    }
+}
@@ -0,0 +1,94 @@
+use std::collections::BTreeMap;
+
+use crate::protobuf::envoy::config::core::v3::Metadata;
+
+/// The namespace of the metadata of the routes and clusters of the
+/// services, naming the 3scale objects Envoy serves them for, for access
+/// logs and traces to tell the services and rules apart. Access logs refer
+/// to them as in:
+///
+/// ```text
+/// [%START_TIME%] "%REQ(:METHOD)% %REQ(X-ENVOY-ORIGINAL-PATH?:PATH)%" %RESPONSE_CODE%
+///   service=%METADATA(ROUTE:com.3scale.gateway:service_id)%
+///   metric=%METADATA(ROUTE:com.3scale.gateway:metric_system_name)%
+///   upstream=%UPSTREAM_METADATA(com.3scale.gateway:target_domain)%
+/// ```
+///
+/// The wasm filter tells its decisions in the filter state of the requests,
+/// under the same namespace, with the fields of `filter_config::access_log`.
+pub const NAMESPACE: &str = filter_config::access_log::NAMESPACE;
+
+/// The namespace of the annotations of the services on their listeners and
+/// clusters, as in `%METADATA(LISTENER:com.3scale.gateway.annotations:team)%`
+/// of an access log.
+pub const ANNOTATIONS_NAMESPACE: &str = "com.3scale.gateway.annotations";
+
+const MAX_ANNOTATION_KEY: usize = 63;
+const MAX_ANNOTATION_VALUE: usize = 256;
+
+fn string_value(value: &str) -> prost_types::Value {
+    prost_types::Value {
+        kind: Some(prost_types::value::Kind::StringValue(value.to_string())),
+    }
+}
+
+/// Metadata under the namespace of the controller, with `fields` as
+/// strings.
+pub fn metadata(fields: &[(&str, &str)]) -> Metadata {
+    let fields = fields
+        .iter()
+        .map(|(key, value)| (key.to_string(), string_value(value)))
+        .collect();
+    let mut metadata = Metadata::default();
+    metadata
+        .filter_metadata
+        .insert(NAMESPACE.to_string(), prost_types::Struct { fields });
+    metadata
+}
+
+/// Why an annotation of a service isn't one, if it isn't. Keys are those of
+/// Kubernetes labels, prefix aside: letters, digits, '-', '_', '.' and '/',
+/// starting and ending with a letter or a digit.
+pub fn check_annotation(key: &str, value: &str) -> Result<(), std::string::String> {
+    let allowed = |c: char| c.is_ascii_alphanumeric() || "-_./".contains(c);
+    let bounded = |c: Option<char>| c.is_some_and(|c| c.is_ascii_alphanumeric());
+    if key.len() > MAX_ANNOTATION_KEY
+        || !key.chars().all(allowed)
+        || !bounded(key.chars().next())
+        || !bounded(key.chars().last())
+    {
+        return Err(format!(
+            "'{}' must be 1 to {} letters, digits, '-', '_', '.' or '/', starting and ending with a letter or a digit",
+            key, MAX_ANNOTATION_KEY
+        ));
+    }
+    if value.len() > MAX_ANNOTATION_VALUE || value.contains(|c: char| c.is_control()) {
+        return Err(format!(
+            "needs a value of at most {} bytes on a single line",
+            MAX_ANNOTATION_VALUE
+        ));
+    }
+    Ok(())
+}
+
+/// Add `annotations` to `metadata`, under their namespace, in the order of
+/// their keys. Nothing is added, not even empty metadata, without any.
+pub fn annotate(
+    metadata: &mut Option<Metadata>,
+    annotations: &BTreeMap<std::string::String, std::string::String>,
+) {
+    if annotations.is_empty() {
+        return;
+    }
+    let fields = annotations
+        .iter()
+        .map(|(key, value)| (key.clone(), string_value(value)))
+        .collect();
+    metadata
+        .get_or_insert_with(Metadata::default)
+        .filter_metadata
+        .insert(
+            ANNOTATIONS_NAMESPACE.to_string(),
+            prost_types::Struct { fields },
+        );
+}
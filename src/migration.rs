@@ -0,0 +1,207 @@
+use anyhow::{bail, Result};
+
+use crate::threescale_auth;
+
+/// Version of the services files this build reads as they are. Files
+/// without a version predate them, and are version 0.
+pub const CURRENT_VERSION: u64 = 1;
+
+// A change of the shape of the services, from version `from` to the next.
+struct Migration {
+    from: u64,
+    description: &'static str,
+    // the service in the next version, left alone if it has nothing to
+    // migrate
+    migrate: fn(serde_json::Value) -> serde_json::Value,
+}
+
+const MIGRATIONS: &[Migration] = &[Migration {
+    from: 0,
+    description: "backend URL moved into auth_config.wasm_config.backend",
+    migrate: backend_into_auth_config,
+}];
+
+/// A services file brought to the current version.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Migrated {
+    /// Its services, a list of them or a single one.
+    pub services: serde_json::Value,
+    /// Path of the services in the file, for errors to point into it.
+    pub path: &'static str,
+    /// What the migrations changed, in the order they were applied.
+    pub applied: Vec<std::string::String>,
+    /// Snippets the services may include, see `snippets::expand`.
+    pub snippets: serde_json::Map<std::string::String, serde_json::Value>,
+}
+
+/// Bring the services file `document` to the current version. Versioned
+/// files are an object with their `version`, `services` and, optionally,
+/// `snippets`, unversioned ones a list of services or a single one.
+pub fn migrate(document: serde_json::Value) -> Result<Migrated> {
+    let mut snippets = serde_json::Map::new();
+    let (version, mut services, path) = match document {
+        serde_json::Value::Object(mut fields) if fields.contains_key("version") => {
+            let version = match fields.remove("version").and_then(|v| v.as_u64()) {
+                Some(version) => version,
+                None => bail!("the version of a services file must be an integer"),
+            };
+            let services = match fields.remove("services") {
+                Some(services) => services,
+                None => bail!("version {} services file has no services", version),
+            };
+            match fields.remove("snippets") {
+                Some(serde_json::Value::Object(map)) => snippets = map,
+                Some(_) => bail!("the snippets of a services file must be a map of them"),
+                None => {}
+            }
+            (version, services, "services")
+        }
+        document => (0, document, ""),
+    };
+    if version > CURRENT_VERSION {
+        bail!(
+            "services file version {} is newer than version {}, the latest this controller reads",
+            version,
+            CURRENT_VERSION
+        );
+    }
+
+    let mut applied = Vec::new();
+    for migration in MIGRATIONS.iter().filter(|m| m.from >= version) {
+        let mut changed = false;
+        services = match services {
+            serde_json::Value::Array(values) => serde_json::Value::Array(
+                values
+                    .into_iter()
+                    .map(|value| migrate_service(migration, value, &mut changed))
+                    .collect(),
+            ),
+            value => migrate_service(migration, value, &mut changed),
+        };
+        if changed {
+            applied.push(format!(
+                "{} (version {} to {})",
+                migration.description,
+                migration.from,
+                migration.from + 1
+            ));
+        }
+    }
+    Ok(Migrated {
+        services,
+        path,
+        applied,
+        snippets,
+    })
+}
+
+fn migrate_service(
+    migration: &Migration,
+    value: serde_json::Value,
+    changed: &mut bool,
+) -> serde_json::Value {
+    let migrated = (migration.migrate)(value.clone());
+    *changed |= migrated != value;
+    migrated
+}
+
+// Services used to name the 3scale backend they report to with a URL of
+// their own, now in the settings of the 3scale auth filter.
+fn backend_into_auth_config(mut service: serde_json::Value) -> serde_json::Value {
+    let fields = match service.as_object_mut() {
+        Some(fields) => fields,
+        None => return service,
+    };
+    let url = match fields.get("backend") {
+        Some(serde_json::Value::String(url)) => url.clone(),
+        _ => return service,
+    };
+    let id = fields.get("id").cloned().unwrap_or(serde_json::Value::Null);
+    let auth_config = fields.entry("auth_config").or_insert_with(|| {
+        serde_json::json!({
+            "path": threescale_auth::WASM_PATH,
+            "wasm_config": {},
+        })
+    });
+    let wasm_config = match auth_config
+        .get_mut("wasm_config")
+        .and_then(serde_json::Value::as_object_mut)
+    {
+        // the backend of the filter wins over the legacy one
+        Some(wasm_config) if !wasm_config.contains_key("backend") => wasm_config,
+        _ => return service,
+    };
+    wasm_config.insert(
+        "backend".to_string(),
+        serde_json::json!({
+            "cluster_name": threescale_auth::backend_cluster_name(&id),
+            "url": url,
+            "timeout": 5,
+        }),
+    );
+    fields.remove("backend");
+    service
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn legacy_backends_move_into_the_auth_config() {
+        let legacy = serde_json::json!({"id": 7, "backend": "https://backend.app/"});
+        assert_eq!(
+            backend_into_auth_config(legacy),
+            serde_json::json!({
+                "id": 7,
+                "auth_config": {
+                    "path": "static/threescale_wasm_auth.wasm",
+                    "wasm_config": {
+                        "backend": {
+                            "cluster_name": "Service::7::backend",
+                            "url": "https://backend.app/",
+                            "timeout": 5,
+                        },
+                    },
+                },
+            })
+        );
+
+        // nothing to migrate
+        let current = serde_json::json!({"id": 7, "auth_config": {"wasm_config": {}}});
+        assert_eq!(backend_into_auth_config(current.clone()), current);
+        let both = serde_json::json!({
+            "id": 7,
+            "backend": "https://backend.app/",
+            "auth_config": {"wasm_config": {"backend": {"url": "https://other.app/"}}},
+        });
+        assert_eq!(backend_into_auth_config(both.clone()), both);
+    }
+
+    #[test]
+    fn files_are_brought_to_the_current_version() {
+        let service = serde_json::json!({"id": 7, "backend": "https://backend.app/"});
+        let migrated = migrate(serde_json::json!([service])).unwrap();
+        assert_eq!(migrated.path, "");
+        assert_eq!(
+            migrated.applied,
+            ["backend URL moved into auth_config.wasm_config.backend (version 0 to 1)"]
+        );
+        assert!(migrated.services[0]["auth_config"].is_object());
+
+        // the current version has nothing to migrate
+        let current = serde_json::json!({"version": 1, "services": [service]});
+        let migrated = migrate(current).unwrap();
+        assert_eq!(migrated.path, "services");
+        assert!(migrated.applied.is_empty());
+        assert_eq!(migrated.services, serde_json::json!([service]));
+
+        let error = migrate(serde_json::json!({"version": 2, "services": []})).unwrap_err();
+        assert!(
+            error.to_string().contains("newer than version 1"),
+            "{}",
+            error
+        );
+        assert!(migrate(serde_json::json!({"version": 1})).is_err());
+    }
+}
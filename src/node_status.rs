@@ -0,0 +1,343 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock};
+
+use serde::Serialize;
+
+use crate::protobuf::google::rpc::Status as RpcStatus;
+
+/// A response Envoy rejected.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct Nack {
+    pub version: std::string::String,
+    pub nonce: std::string::String,
+    pub message: std::string::String,
+    pub resources: Vec<std::string::String>,
+}
+
+/// How a request answered the last response sent.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Reply {
+    Ack,
+    Nack(Nack),
+}
+
+/// What a node was sent of a type URL and what it did with it.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct TypeStatus {
+    pub last_sent_version: Option<std::string::String>,
+    pub last_sent_nonce: Option<std::string::String>,
+    pub last_acked_version: Option<std::string::String>,
+    pub last_nack: Option<Nack>,
+    #[serde(skip)]
+    sent_resources: Vec<std::string::String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct NodeStatus {
+    pub streams: u32,
+    pub types: BTreeMap<std::string::String, TypeStatus>,
+}
+
+/// ACK/NACK state of every node connected to the discovery services, those
+/// whose streams all closed left out.
+#[derive(Debug, Clone, Default)]
+pub struct NodeStatuses {
+    nodes: Arc<RwLock<BTreeMap<std::string::String, NodeStatus>>>,
+}
+
+impl NodeStatuses {
+    pub fn get(&self) -> BTreeMap<std::string::String, NodeStatus> {
+        self.nodes.read().unwrap().clone()
+    }
+
+    /// Track a new stream, until the returned guard is dropped.
+    pub fn stream(&self) -> StreamStatus {
+        StreamStatus {
+            statuses: self.clone(),
+            node: None,
+        }
+    }
+
+    fn update<F: FnOnce(&mut TypeStatus)>(&self, node: &str, type_url: &str, f: F) {
+        let mut nodes = self.nodes.write().unwrap();
+        let node = nodes.entry(node.to_string()).or_default();
+        f(node.types.entry(type_url.to_string()).or_default());
+    }
+}
+
+/// Status tracking of a single discovery stream.
+#[derive(Debug)]
+pub struct StreamStatus {
+    statuses: NodeStatuses,
+    node: Option<std::string::String>,
+}
+
+impl StreamStatus {
+    pub fn node(&self) -> &str {
+        self.node.as_deref().unwrap_or("unknown")
+    }
+
+    /// Record a request, correlating its nonce with the response it answers.
+    /// The delta requests have no `version_info`, the version they ack
+    /// being the last sent.
+    pub fn on_request(
+        &mut self,
+        node: Option<&str>,
+        type_url: &str,
+        version_info: Option<&str>,
+        response_nonce: &str,
+        error_detail: Option<&RpcStatus>,
+    ) -> Option<Reply> {
+        // only the first request of a stream is required to carry the node
+        if self.node.is_none() {
+            if let Some(node) = node {
+                self.node = Some(node.to_string());
+                let mut nodes = self.statuses.nodes.write().unwrap();
+                nodes.entry(node.to_string()).or_default().streams += 1;
+            }
+        }
+        let node = self.node().to_string();
+
+        let mut reply = None;
+        self.statuses.update(&node, type_url, |status| {
+            if response_nonce.is_empty() {
+                // a reconnecting Envoy reports the version it is running
+                if let Some(version) = version_info.filter(|version| !version.is_empty()) {
+                    status.last_acked_version = Some(version.to_string());
+                }
+                return;
+            }
+            if status.last_sent_nonce.as_deref() != Some(response_nonce) {
+                return;
+            }
+            match error_detail {
+                Some(error) => {
+                    let nack = Nack {
+                        version: status.last_sent_version.clone().unwrap_or_default(),
+                        nonce: response_nonce.to_string(),
+                        message: error.message.clone(),
+                        resources: status.sent_resources.clone(),
+                    };
+                    tracing::error!(
+                        "Node {} rejected {} version {} ({}): {}",
+                        node,
+                        type_url,
+                        nack.version,
+                        nack.resources.join(", "),
+                        nack.message
+                    );
+                    reply = Some(Reply::Nack(nack.clone()));
+                    status.last_nack = Some(nack);
+                }
+                None => {
+                    status.last_acked_version = status.last_sent_version.clone();
+                    status.last_nack = None;
+                    reply = Some(Reply::Ack);
+                }
+            }
+        });
+        reply
+    }
+
+    /// Record a response sent on the stream.
+    pub fn sent(
+        &self,
+        type_url: &str,
+        version: &str,
+        nonce: &str,
+        resources: Vec<std::string::String>,
+    ) {
+        self.statuses.update(self.node(), type_url, |status| {
+            status.last_sent_version = Some(version.to_string());
+            status.last_sent_nonce = Some(nonce.to_string());
+            status.sent_resources = resources;
+        });
+    }
+}
+
+impl Drop for StreamStatus {
+    fn drop(&mut self) {
+        if let Some(ref node) = self.node {
+            let mut nodes = self.statuses.nodes.write().unwrap();
+            if let Some(status) = nodes.get_mut(node) {
+                status.streams = status.streams.saturating_sub(1);
+                // the last stream of the node is done
+                if status.streams == 0 {
+                    nodes.remove(node);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configuration;
+    use crate::envoy_ads;
+    use crate::envoy_helpers::{get_envoy_cluster, EnvoyExport, EnvoyResource, CLUSTER_TYPE_URL};
+    use crate::protobuf::envoy::config::core::v3::Node;
+    use crate::protobuf::envoy::service::discovery::v3::{DiscoveryRequest, DiscoveryResponse};
+    use crate::shutdown::Shutdown;
+    use crate::xds_auth::Authorized;
+    use futures::StreamExt;
+    use tokio::sync::mpsc;
+
+    type Config = Arc<RwLock<configuration::Config>>;
+
+    fn publish(config: &Config, port: u32) {
+        let exports = vec![EnvoyExport {
+            key: "service::id::1::cluster".to_string(),
+            config: EnvoyResource::Cluster(
+                get_envoy_cluster(
+                    "Cluster::service::1".to_string(),
+                    &format!("http://one:{}", port),
+                    &Default::default(),
+                )
+                .unwrap(),
+            ),
+        }];
+        config.write().unwrap().import(
+            Vec::new(),
+            port.to_string(),
+            vec![(1, exports.into())].into_iter().collect(),
+        );
+    }
+
+    // Fake CDS client of node `envoy-1`.
+    struct Client {
+        requests: mpsc::Sender<Result<DiscoveryRequest, tonic::Status>>,
+        responses: mpsc::Receiver<Result<DiscoveryResponse, tonic::Status>>,
+    }
+
+    impl Client {
+        fn connect(config: &Config, statuses: &NodeStatuses) -> Client {
+            let (requests, rx) = mpsc::channel(4);
+            let responses = envoy_ads::stream(
+                config.clone(),
+                statuses.clone(),
+                Shutdown::default(),
+                Some(CLUSTER_TYPE_URL),
+                Authorized::default(),
+                rx,
+            );
+            Client {
+                requests,
+                responses,
+            }
+        }
+
+        async fn send(&mut self, version: &str, nonce: &str, error: Option<&str>) {
+            let request = DiscoveryRequest {
+                node: Some(Node {
+                    id: "envoy-1".to_string(),
+                    ..Default::default()
+                }),
+                type_url: CLUSTER_TYPE_URL.to_string(),
+                version_info: version.to_string(),
+                response_nonce: nonce.to_string(),
+                error_detail: error.map(|message| RpcStatus {
+                    code: 3,
+                    message: message.to_string(),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            };
+            self.requests.send(Ok(request)).await.unwrap();
+        }
+
+        async fn receive(&mut self) -> DiscoveryResponse {
+            self.responses.next().await.unwrap().unwrap()
+        }
+    }
+
+    fn cluster_status(statuses: &NodeStatuses) -> (u32, TypeStatus) {
+        let nodes = statuses.get();
+        let node = &nodes["envoy-1"];
+        (node.streams, node.types[CLUSTER_TYPE_URL].clone())
+    }
+
+    // Let the stream run until it handled the requests `done` tells.
+    async fn handled(done: impl Fn() -> bool) {
+        while !done() {
+            tokio::time::delay_for(std::time::Duration::from_millis(1)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn ack_nack_and_reconnect() {
+        let config = Config::default();
+        let statuses = NodeStatuses::default();
+        publish(&config, 80);
+
+        let mut client = Client::connect(&config, &statuses);
+        client.send("", "", None).await;
+        let first = client.receive().await;
+        client.send(&first.version_info, &first.nonce, None).await;
+        handled(|| cluster_status(&statuses).1.last_acked_version.is_some()).await;
+
+        // the next version is sent as soon as it is published
+        publish(&config, 81);
+        let second = client.receive().await;
+        let (streams, status) = cluster_status(&statuses);
+        assert_eq!(streams, 1);
+        assert_eq!(
+            status.last_acked_version.as_ref(),
+            Some(&first.version_info)
+        );
+        assert_eq!(
+            status.last_sent_version.as_ref(),
+            Some(&second.version_info)
+        );
+
+        // NACK, keeping the previous version
+        client
+            .send(&first.version_info, &second.nonce, Some("bad cluster"))
+            .await;
+        handled(|| cluster_status(&statuses).1.last_nack.is_some()).await;
+        publish(&config, 82);
+        let third = client.receive().await;
+        let (_, status) = cluster_status(&statuses);
+        assert_eq!(
+            status.last_acked_version.as_ref(),
+            Some(&first.version_info)
+        );
+        assert_eq!(
+            status.last_nack,
+            Some(Nack {
+                version: second.version_info.clone(),
+                nonce: second.nonce.clone(),
+                message: "bad cluster".to_string(),
+                resources: vec!["Cluster::service::1".to_string()],
+            })
+        );
+
+        // the ACK of the next version clears the NACK
+        client.send(&third.version_info, &third.nonce, None).await;
+        handled(|| cluster_status(&statuses).1.last_nack.is_none()).await;
+        let (_, status) = cluster_status(&statuses);
+        assert_eq!(status.last_acked_version, Some(third.version_info.clone()));
+
+        // the node is forgotten once disconnected, and reconnecting reports
+        // the running version
+        drop(client);
+        handled(|| statuses.get().is_empty()).await;
+        let mut client = Client::connect(&config, &statuses);
+        client.send(&first.version_info, "", None).await;
+        let resent = client.receive().await;
+        assert_eq!(resent.version_info, third.version_info);
+        let (streams, status) = cluster_status(&statuses);
+        assert_eq!(streams, 1);
+        assert_eq!(
+            status.last_acked_version.as_ref(),
+            Some(&first.version_info)
+        );
+        assert!(status.last_nack.is_none());
+
+        client.send(&resent.version_info, &resent.nonce, None).await;
+        handled(|| cluster_status(&statuses).1.last_acked_version != status.last_acked_version)
+            .await;
+        let (_, status) = cluster_status(&statuses);
+        assert_eq!(status.last_acked_version, Some(resent.version_info));
+    }
+}
@@ -0,0 +1,185 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::field_errors::{field, index, Findings, Validate};
+use crate::rule_patterns;
+use crate::type_urls::TypeUrls;
+
+use crate::protobuf::envoy::config::cluster::v3::cluster::{
+    ClusterDiscoveryType, DiscoveryType, LbConfig, LbPolicy, OriginalDstLbConfig,
+};
+use crate::protobuf::envoy::config::cluster::v3::Cluster;
+use crate::protobuf::envoy::config::listener::v3::{listener_filter, ListenerFilter};
+use crate::protobuf::envoy::config::route::v3::header_matcher::HeaderMatchSpecifier;
+use crate::protobuf::envoy::config::route::v3::HeaderMatcher;
+use crate::protobuf::envoy::extensions::filters::listener::original_dst::v3::OriginalDst as OriginalDstFilter;
+
+const LISTENER_FILTER: &str = "envoy.filters.listener.original_dst";
+
+/// The header naming the upstream of a request, when the services use it.
+pub const HEADER: &str = "x-envoy-original-dst-host";
+
+/// The settings of a service of kind `original_dst`, as in a mesh whose
+/// connections are redirected to the listener of the service: the upstream
+/// is the address each connection was sent to rather than its target
+/// domain. The filters of the service still authorize and report the
+/// requests.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct OriginalDst {
+    // the upstream is the one of the `x-envoy-original-dst-host` header of
+    // the requests, rather than the destination of their connection, which
+    // the controller only exports with --allow-original-dst-header
+    #[serde(default)]
+    pub use_http_header: bool,
+    // the addresses the header may name, as `10.0.0.5` for any of its ports
+    // or `10.0.0.5:8080`, the requests naming others matching no route
+    #[serde(default)]
+    pub allowed_destinations: Vec<std::string::String>,
+}
+
+impl Validate for OriginalDst {
+    fn validate(&self, path: &str, findings: &mut Findings) {
+        if !self.use_http_header {
+            if !self.allowed_destinations.is_empty() {
+                findings.warning(
+                    field(path, "allowed_destinations"),
+                    "is ignored, the upstreams being the destinations of the connections",
+                );
+            }
+            return;
+        }
+        if self.allowed_destinations.is_empty() {
+            findings.error(
+                field(path, "allowed_destinations"),
+                "needs the addresses the header may name, for the requests not to reach any",
+            );
+        }
+        for (i, destination) in self.allowed_destinations.iter().enumerate() {
+            let address = destination.parse::<std::net::IpAddr>().is_ok()
+                || destination.parse::<std::net::SocketAddr>().is_ok();
+            if !address {
+                findings.error(
+                    index(&field(path, "allowed_destinations"), i),
+                    format!(
+                        "'{}' is not an address, as 10.0.0.5 or 10.0.0.5:8080",
+                        destination
+                    ),
+                );
+            }
+        }
+    }
+}
+
+impl OriginalDst {
+    /// The cluster named `name` proxying to the original destination of
+    /// the requests.
+    pub fn cluster(&self, name: std::string::String) -> Cluster {
+        Cluster {
+            name,
+            connect_timeout: Some(std::time::Duration::from_secs(1).into()),
+            lb_policy: LbPolicy::ClusterProvided as i32,
+            cluster_discovery_type: Some(ClusterDiscoveryType::Type(
+                DiscoveryType::OriginalDst as i32,
+            )),
+            lb_config: Some(LbConfig::OriginalDstLbConfig(OriginalDstLbConfig {
+                use_http_header: self.use_http_header,
+                ..Default::default()
+            })),
+            ..Default::default()
+        }
+    }
+
+    /// The matcher of the requests whose header names one of the allowed
+    /// destinations, when the header picks the upstream.
+    pub fn destination_matcher(&self) -> Option<HeaderMatcher> {
+        if !self.use_http_header {
+            return None;
+        }
+        let destinations: Vec<_> = self
+            .allowed_destinations
+            .iter()
+            .map(
+                |destination| match destination.parse::<std::net::IpAddr>() {
+                    Ok(std::net::IpAddr::V6(ip)) => {
+                        format!(r"\[{}\]:[0-9]+", regex::escape(&ip.to_string()))
+                    }
+                    Ok(ip) => format!("{}:[0-9]+", regex::escape(&ip.to_string())),
+                    Err(_) => regex::escape(destination),
+                },
+            )
+            .collect();
+        Some(HeaderMatcher {
+            name: HEADER.to_string(),
+            header_match_specifier: Some(HeaderMatchSpecifier::SafeRegexMatch(rule_patterns::re2(
+                format!("(?:{})", destinations.join("|")),
+            ))),
+            ..Default::default()
+        })
+    }
+
+    /// The listener filter restoring the destination of the connections
+    /// redirected to the listener.
+    pub fn listener_filter(type_urls: &TypeUrls) -> Result<ListenerFilter> {
+        Ok(ListenerFilter {
+            name: LISTENER_FILTER.to_string(),
+            config_type: Some(listener_filter::ConfigType::TypedConfig(
+                type_urls.pack(&OriginalDstFilter::default())?,
+            )),
+            ..Default::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn original_dst(settings: serde_json::Value) -> OriginalDst {
+        serde_json::from_value(settings).unwrap()
+    }
+
+    #[test]
+    fn headers_only_name_the_allowed_destinations() {
+        let open = original_dst(serde_json::json!({"use_http_header": true}));
+        assert_eq!(open.findings("").errors[0].path, "allowed_destinations");
+        let hosts = original_dst(serde_json::json!({
+            "use_http_header": true,
+            "allowed_destinations": ["10.0.0.5", "backend:80"],
+        }));
+        assert_eq!(hosts.findings("").errors[0].path, "allowed_destinations[1]");
+
+        let allowed = original_dst(serde_json::json!({
+            "use_http_header": true,
+            "allowed_destinations": ["10.0.0.5", "10.0.0.6:8080", "fd00::1"],
+        }));
+        assert_eq!(allowed.findings(""), Findings::default());
+        let matcher = allowed.destination_matcher().unwrap();
+        assert_eq!(matcher.name, HEADER);
+        let regex = match matcher.header_match_specifier {
+            Some(HeaderMatchSpecifier::SafeRegexMatch(matcher)) => matcher.regex,
+            specifier => panic!("{:?}", specifier),
+        };
+        // matching whole values, as Envoy does
+        let full = regex::Regex::new(&format!("^(?:{})$", regex)).unwrap();
+        for destination in &[
+            "10.0.0.5:80",
+            "10.0.0.5:443",
+            "10.0.0.6:8080",
+            "[fd00::1]:80",
+        ] {
+            assert!(full.is_match(destination), "{}", destination);
+        }
+        for destination in &[
+            "10.0.0.50:80",
+            "10.0.0.6:80",
+            "169.254.169.254:80",
+            "10.0.0.5",
+        ] {
+            assert!(!full.is_match(destination), "{}", destination);
+        }
+
+        let connections = original_dst(serde_json::json!({}));
+        assert!(connections.destination_matcher().is_none());
+    }
+}
@@ -0,0 +1,80 @@
+use std::any::Any;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+
+static PANICS: AtomicU64 = AtomicU64::new(0);
+static LAST: Mutex<Option<std::string::String>> = Mutex::new(None);
+
+/// The panics the controller caught, as the admin snapshot tells them.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct Panics {
+    pub controller_panics_total: u64,
+    pub last_message: Option<std::string::String>,
+}
+
+fn message(payload: &(dyn Any + Send)) -> std::string::String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<std::string::String>() {
+        message.clone()
+    } else {
+        "unknown cause".to_string()
+    }
+}
+
+/// Run `f`, a panic of it counted and turned into an error naming `what`
+/// panicked, for the callers to handle as any failure of theirs. Whatever
+/// `f` left half updated is for the caller to make up for, as with a lock
+/// it poisoned.
+pub fn catch<T>(what: &str, f: impl FnOnce() -> T) -> Result<T> {
+    catch_unwind(AssertUnwindSafe(f)).map_err(|payload| {
+        let message = message(&*payload);
+        PANICS.fetch_add(1, Ordering::Relaxed);
+        *LAST.lock().unwrap_or_else(|e| e.into_inner()) = Some(message.clone());
+        tracing::error!(
+            controller_panics_total = total(),
+            "{} panicked: {}",
+            what,
+            message
+        );
+        anyhow!("{} panicked: {}", what, message)
+    })
+}
+
+/// How many panics were caught since the controller started.
+pub fn total() -> u64 {
+    PANICS.load(Ordering::Relaxed)
+}
+
+pub fn stats() -> Panics {
+    Panics {
+        controller_panics_total: total(),
+        last_message: LAST.lock().unwrap_or_else(|e| e.into_inner()).clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn panics_become_errors_and_are_counted() {
+        let before = total();
+        assert_eq!(catch("export", || 42).unwrap(), 42);
+
+        let items: &[u32] = &[1, 2];
+        let error = catch("export", || items[3]).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "export panicked: index out of bounds: the len is 2 but the index is 3"
+        );
+        let error = catch("reload", || panic!("bad {}", "config")).unwrap_err();
+        assert_eq!(error.to_string(), "reload panicked: bad config");
+        // other tests may panic meanwhile
+        assert!(total() >= before + 2);
+    }
+}
@@ -0,0 +1,893 @@
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::net::IpAddr;
+
+use anyhow::Result;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::field_errors::{field, index, unknown_fields, Findings, Validate};
+use crate::http_filters;
+use crate::maintenance::MaintenanceMode;
+use crate::routing::Routing;
+use crate::rule_patterns;
+use crate::security_headers::SecurityHeaders;
+use crate::type_urls::TypeUrls;
+use crate::url_rewriting::UrlRewriting;
+
+use crate::protobuf::envoy::config::core::v3::{
+    CidrRange, HeaderValue, HeaderValueOption, RuntimeFractionalPercent,
+};
+use crate::protobuf::envoy::config::rbac::v3::{
+    permission, principal, rbac, Permission, Policy as RbacPolicy, Principal, Rbac as RbacRules,
+};
+use crate::protobuf::envoy::config::route::v3::{CorsPolicy, VirtualHost};
+use crate::protobuf::envoy::extensions::filters::http::cors::v3::Cors as CorsFilter;
+use crate::protobuf::envoy::extensions::filters::http::local_ratelimit::v3::LocalRateLimit;
+use crate::protobuf::envoy::extensions::filters::http::rbac::v3::Rbac;
+use crate::protobuf::envoy::extensions::filters::network::http_connection_manager::v3::{
+    http_filter, HttpFilter,
+};
+use crate::protobuf::envoy::r#type::matcher::v3::{string_matcher::MatchPattern, StringMatcher};
+use crate::protobuf::envoy::r#type::v3::{FractionalPercent, TokenBucket};
+
+const HEADERS: &str = "headers";
+const URL_REWRITING: &str = "url_rewriting";
+const IP_CHECK: &str = "ip_check";
+const CORS: &str = "cors";
+const RATE_LIMIT: &str = "rate_limit";
+const MAINTENANCE_MODE: &str = "maintenance_mode";
+const ROUTING: &str = "routing";
+const SECURITY_HEADERS: &str = "security_headers";
+
+/// A policy of a service, applied by Envoy to the requests of its hosts.
+/// Policies are objects naming the policy and holding its `configuration`,
+/// or only named for those configured by default, the way they used to be
+/// listed. Policies whose name is unknown are kept as they are and left to
+/// the wasm filter, in the configuration it is handed, for the filter to
+/// apply those it implements.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(try_from = "RawPolicy", into = "RawPolicy")]
+pub enum Policy {
+    Headers(Headers),
+    UrlRewriting(UrlRewriting),
+    IpCheck(IpCheck),
+    Cors(Cors),
+    RateLimit(RateLimit),
+    MaintenanceMode(MaintenanceMode),
+    Routing(Routing),
+    SecurityHeaders(SecurityHeaders),
+    Custom {
+        name: std::string::String,
+        configuration: serde_json::Value,
+    },
+}
+
+// The policy as written in the services files.
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum RawPolicy {
+    Name(std::string::String),
+    Configured {
+        name: std::string::String,
+        #[serde(default)]
+        configuration: serde_json::Value,
+    },
+}
+
+fn configuration<T: DeserializeOwned>(
+    name: &str,
+    configuration: serde_json::Value,
+) -> Result<T, std::string::String> {
+    let configuration = match configuration {
+        serde_json::Value::Null => serde_json::Value::Object(Default::default()),
+        configuration => configuration,
+    };
+    serde_json::from_value(configuration).map_err(|e| format!("policy '{}': {}", name, e))
+}
+
+impl TryFrom<RawPolicy> for Policy {
+    type Error = std::string::String;
+
+    fn try_from(raw: RawPolicy) -> Result<Policy, std::string::String> {
+        let (name, config) = match raw {
+            RawPolicy::Name(name) => (name, serde_json::Value::Null),
+            RawPolicy::Configured {
+                name,
+                configuration,
+            } => (name, configuration),
+        };
+        Ok(match name.as_str() {
+            HEADERS => Policy::Headers(configuration(&name, config)?),
+            URL_REWRITING => Policy::UrlRewriting(configuration(&name, config)?),
+            IP_CHECK => Policy::IpCheck(configuration(&name, config)?),
+            CORS => Policy::Cors(configuration(&name, config)?),
+            RATE_LIMIT => Policy::RateLimit(configuration(&name, config)?),
+            MAINTENANCE_MODE => Policy::MaintenanceMode(configuration(&name, config)?),
+            ROUTING => Policy::Routing(configuration(&name, config)?),
+            SECURITY_HEADERS => Policy::SecurityHeaders(configuration(&name, config)?),
+            _ => Policy::Custom {
+                name,
+                configuration: config,
+            },
+        })
+    }
+}
+
+impl From<Policy> for RawPolicy {
+    fn from(policy: Policy) -> RawPolicy {
+        let name = policy.name().to_string();
+        let configuration = match policy {
+            Policy::Headers(config) => serde_json::to_value(config),
+            Policy::UrlRewriting(config) => serde_json::to_value(config),
+            Policy::IpCheck(config) => serde_json::to_value(config),
+            Policy::Cors(config) => serde_json::to_value(config),
+            Policy::RateLimit(config) => serde_json::to_value(config),
+            Policy::MaintenanceMode(config) => serde_json::to_value(config),
+            Policy::Routing(config) => serde_json::to_value(config),
+            Policy::SecurityHeaders(config) => serde_json::to_value(config),
+            Policy::Custom { configuration, .. } => Ok(configuration),
+        };
+        RawPolicy::Configured {
+            name,
+            // plain structures, which always serialize
+            configuration: configuration.unwrap_or_default(),
+        }
+    }
+}
+
+/// Headers set, added or removed on the requests, before they are proxied,
+/// and on the responses.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Headers {
+    #[serde(default)]
+    pub request: HeaderOperations,
+    #[serde(default)]
+    pub response: HeaderOperations,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct HeaderOperations {
+    // replacing the values the headers have
+    #[serde(default)]
+    pub set: BTreeMap<std::string::String, std::string::String>,
+    // along with the values the headers have
+    #[serde(default)]
+    pub add: BTreeMap<std::string::String, std::string::String>,
+    #[serde(default)]
+    pub remove: Vec<std::string::String>,
+}
+
+impl Validate for Headers {
+    fn validate(&self, path: &str, findings: &mut Findings) {
+        self.request.validate(&field(path, "request"), findings);
+        self.response.validate(&field(path, "response"), findings);
+    }
+}
+
+impl Validate for HeaderOperations {
+    fn validate(&self, path: &str, findings: &mut Findings) {
+        let names = self
+            .set
+            .keys()
+            .map(|name| (field(&field(path, "set"), name), name))
+            .chain(
+                self.add
+                    .keys()
+                    .map(|name| (field(&field(path, "add"), name), name)),
+            )
+            .chain(
+                self.remove
+                    .iter()
+                    .enumerate()
+                    .map(|(i, name)| (index(&field(path, "remove"), i), name)),
+            );
+        for (at, name) in names {
+            if name.is_empty()
+                || name.starts_with(':')
+                || name.eq_ignore_ascii_case("host")
+                || name.contains(|c: char| c.is_whitespace())
+            {
+                findings.error(
+                    at,
+                    format!("'{}' is not a header that can be changed", name),
+                );
+            }
+        }
+    }
+}
+
+impl HeaderOperations {
+    fn options(&self) -> Vec<HeaderValueOption> {
+        let option = |(key, value): (&std::string::String, &std::string::String), append| {
+            HeaderValueOption {
+                header: Some(HeaderValue {
+                    key: key.clone(),
+                    value: value.clone(),
+                    ..Default::default()
+                }),
+                append: Some(append),
+                ..Default::default()
+            }
+        };
+        let set = self.set.iter().map(|header| option(header, false));
+        let add = self.add.iter().map(|header| option(header, true));
+        set.chain(add).collect()
+    }
+}
+
+/// Requests accepted or refused by the address of the client, the one
+/// connected to Envoy.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct IpCheck {
+    // addresses or CIDR ranges
+    #[serde(default)]
+    pub ips: Vec<std::string::String>,
+    #[serde(default)]
+    pub check_type: IpCheckType,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum IpCheckType {
+    /// Only the clients listed are accepted.
+    Allow,
+    /// The clients listed are refused.
+    #[default]
+    Deny,
+}
+
+// The address and prefix length of an address or CIDR range.
+fn cidr_range(ip: &str) -> Option<(IpAddr, u32)> {
+    let (address, length) = match ip.split_once('/') {
+        Some((address, length)) => (address, Some(length)),
+        None => (ip, None),
+    };
+    let address: IpAddr = address.parse().ok()?;
+    let max = if address.is_ipv4() { 32 } else { 128 };
+    let length = match length {
+        Some(length) => length.parse().ok().filter(|length| *length <= max)?,
+        None => max,
+    };
+    Some((address, length))
+}
+
+/// Cross-origin requests answered by Envoy.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Cors {
+    // `*` for any
+    #[serde(default = "any_origin")]
+    pub allow_origins: Vec<std::string::String>,
+    #[serde(default)]
+    pub allow_methods: Vec<std::string::String>,
+    #[serde(default)]
+    pub allow_headers: Vec<std::string::String>,
+    #[serde(default)]
+    pub expose_headers: Vec<std::string::String>,
+    // seconds the answers to preflight requests are cached
+    #[serde(default)]
+    pub max_age: Option<u32>,
+    #[serde(default)]
+    pub allow_credentials: bool,
+}
+
+fn any_origin() -> Vec<std::string::String> {
+    vec!["*".to_string()]
+}
+
+impl Default for Cors {
+    fn default() -> Cors {
+        Cors {
+            allow_origins: any_origin(),
+            allow_methods: Vec::new(),
+            allow_headers: Vec::new(),
+            expose_headers: Vec::new(),
+            max_age: None,
+            allow_credentials: false,
+        }
+    }
+}
+
+/// Requests limited by Envoy to a number every interval, for each Envoy,
+/// the requests beyond refused with a 429.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct RateLimit {
+    pub requests: u32,
+    // seconds
+    pub interval: u64,
+}
+
+impl Validate for IpCheck {
+    fn validate(&self, path: &str, findings: &mut Findings) {
+        let ips = field(path, "ips");
+        if self.ips.is_empty() {
+            findings.error(&ips, "needs at least one address");
+        }
+        for (i, ip) in self.ips.iter().enumerate() {
+            if cidr_range(ip).is_none() {
+                findings.error(
+                    index(&ips, i),
+                    format!("'{}' is not an address or CIDR range", ip),
+                );
+            }
+        }
+    }
+}
+
+impl Validate for Cors {
+    fn validate(&self, path: &str, findings: &mut Findings) {
+        if self.allow_origins.is_empty() {
+            findings.error(
+                field(path, "allow_origins"),
+                "needs at least one origin, '*' for any",
+            );
+        }
+        if self.allow_credentials && self.allow_origins.iter().any(|o| o == "*") {
+            findings.error(
+                field(path, "allow_credentials"),
+                "needs the origins listed rather than '*'",
+            );
+        }
+    }
+}
+
+impl Validate for RateLimit {
+    fn validate(&self, path: &str, findings: &mut Findings) {
+        if self.requests == 0 {
+            findings.error(field(path, "requests"), "must be at least 1");
+        }
+        if self.interval == 0 {
+            findings.error(field(path, "interval"), "must be at least 1 second");
+        } else if i64::try_from(self.interval).is_err() {
+            let message = format!("must be at most {} seconds", i64::MAX);
+            findings.error(field(path, "interval"), message);
+        }
+    }
+}
+
+/// The problems of the configuration of the policy found at `path`. The
+/// policies left to the wasm filter are only warned about, the filter
+/// ignoring those it doesn't implement.
+impl Validate for Policy {
+    fn validate(&self, path: &str, findings: &mut Findings) {
+        let path = field(path, "configuration");
+        match self {
+            Policy::Headers(headers) => headers.validate(&path, findings),
+            Policy::UrlRewriting(rewriting) => rewriting.validate(&path, findings),
+            Policy::IpCheck(check) => check.validate(&path, findings),
+            Policy::Cors(cors) => cors.validate(&path, findings),
+            Policy::RateLimit(limit) => limit.validate(&path, findings),
+            Policy::MaintenanceMode(maintenance) => maintenance.validate(&path, findings),
+            Policy::Routing(routing) => routing.validate(&path, findings),
+            Policy::SecurityHeaders(headers) => headers.validate(&path, findings),
+            Policy::Custom { name, .. } => findings.warning(
+                path,
+                format!(
+                    "'{}' is not a known policy, it is left to the wasm filter",
+                    name
+                ),
+            ),
+        }
+    }
+}
+
+fn typed_filter(name: &str, config: prost_types::Any) -> HttpFilter {
+    HttpFilter {
+        name: name.to_string(),
+        config_type: Some(http_filter::ConfigType::TypedConfig(config)),
+    }
+}
+
+fn always() -> Option<RuntimeFractionalPercent> {
+    Some(RuntimeFractionalPercent {
+        default_value: Some(FractionalPercent {
+            numerator: 100,
+            denominator: 0,
+        }),
+        ..Default::default()
+    })
+}
+
+// The fields of the policies, APIcast ones also having their version,
+// which Envoy has no use for.
+const POLICY_FIELDS: [&str; 3] = ["name", "version", "configuration"];
+
+impl Policy {
+    /// Paths of the fields of `raw`, the policy at `path`, that reading it
+    /// ignores, the configurations left to the wasm filter all passing
+    /// through.
+    pub fn unknown_fields(raw: &serde_json::Value, path: &str) -> Vec<std::string::String> {
+        let fields = match raw.as_object() {
+            Some(fields) => fields,
+            None => return Vec::new(),
+        };
+        let mut unknown: Vec<_> = fields
+            .keys()
+            .filter(|name| !POLICY_FIELDS.contains(&name.as_str()))
+            .map(|name| field(path, name))
+            .collect();
+        let name = fields.get("name").and_then(serde_json::Value::as_str);
+        let configuration = match fields.get("configuration") {
+            None | Some(serde_json::Value::Null) => return unknown,
+            Some(configuration) => configuration,
+        };
+        let path = field(path, "configuration");
+        unknown.extend(match name.unwrap_or_default() {
+            HEADERS => unknown_fields::<Headers>(configuration, &path),
+            URL_REWRITING => unknown_fields::<UrlRewriting>(configuration, &path),
+            IP_CHECK => unknown_fields::<IpCheck>(configuration, &path),
+            CORS => unknown_fields::<Cors>(configuration, &path),
+            RATE_LIMIT => unknown_fields::<RateLimit>(configuration, &path),
+            MAINTENANCE_MODE => unknown_fields::<MaintenanceMode>(configuration, &path),
+            ROUTING => unknown_fields::<Routing>(configuration, &path),
+            SECURITY_HEADERS => unknown_fields::<SecurityHeaders>(configuration, &path),
+            _ => Vec::new(),
+        });
+        unknown
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            Policy::Headers(_) => HEADERS,
+            Policy::UrlRewriting(_) => URL_REWRITING,
+            Policy::IpCheck(_) => IP_CHECK,
+            Policy::Cors(_) => CORS,
+            Policy::RateLimit(_) => RATE_LIMIT,
+            Policy::MaintenanceMode(_) => MAINTENANCE_MODE,
+            Policy::Routing(_) => ROUTING,
+            Policy::SecurityHeaders(_) => SECURITY_HEADERS,
+            Policy::Custom { ref name, .. } => name,
+        }
+    }
+
+    /// The policy as handed to the wasm filter, for those translated to no
+    /// Envoy configuration.
+    pub fn filter_policy(&self) -> Option<filter_config::Policy> {
+        match self {
+            Policy::Custom {
+                name,
+                configuration,
+            } => Some(filter_config::Policy {
+                name: name.clone(),
+                configuration: configuration.clone(),
+            }),
+            _ => None,
+        }
+    }
+
+    /// Whether the policy puts every request of the service in maintenance.
+    pub fn is_total_maintenance(&self) -> bool {
+        matches!(self, Policy::MaintenanceMode(maintenance) if maintenance.is_total())
+    }
+
+    /// Apply the policy to the virtual host of a service, whose routes end
+    /// with the one catching every path, and to the HTTP filters run before
+    /// the 3scale ones. `stat_prefix` tells the stats of its filters apart,
+    /// `type_urls` names their types, and `tls` is whether the service is
+    /// served over TLS.
+    pub fn apply(
+        &self,
+        host: &mut VirtualHost,
+        filters: &mut Vec<HttpFilter>,
+        stat_prefix: &str,
+        type_urls: &TypeUrls,
+        tls: bool,
+    ) -> Result<()> {
+        match self {
+            Policy::Headers(headers) => {
+                host.request_headers_to_add
+                    .extend(headers.request.options());
+                host.request_headers_to_remove
+                    .extend(headers.request.remove.iter().cloned());
+                host.response_headers_to_add
+                    .extend(headers.response.options());
+                host.response_headers_to_remove
+                    .extend(headers.response.remove.iter().cloned());
+            }
+            Policy::UrlRewriting(rewriting) => {
+                // the rewriting routes go before the catch all one
+                if let Some(catch_all) = host.routes.pop() {
+                    host.routes.extend(rewriting.routes(&catch_all));
+                    host.routes.push(catch_all);
+                }
+            }
+            Policy::IpCheck(check) => {
+                let principals = check
+                    .ips
+                    .iter()
+                    .filter_map(|ip| cidr_range(ip))
+                    .map(|(address, length)| Principal {
+                        identifier: Some(principal::Identifier::DirectRemoteIp(CidrRange {
+                            address_prefix: address.to_string(),
+                            prefix_len: Some(length),
+                        })),
+                    })
+                    .collect();
+                let mut policies = BTreeMap::new();
+                policies.insert(
+                    IP_CHECK.to_string(),
+                    RbacPolicy {
+                        permissions: vec![Permission {
+                            rule: Some(permission::Rule::Any(true)),
+                        }],
+                        principals,
+                        ..Default::default()
+                    },
+                );
+                let action = match check.check_type {
+                    IpCheckType::Allow => rbac::Action::Allow,
+                    IpCheckType::Deny => rbac::Action::Deny,
+                };
+                let rbac = Rbac {
+                    rules: Some(RbacRules {
+                        action: action as i32,
+                        policies,
+                        ..Default::default()
+                    }),
+                    rules_stat_prefix: format!("{}_ip_check_", stat_prefix),
+                    ..Default::default()
+                };
+                filters.push(typed_filter(http_filters::RBAC, type_urls.pack(&rbac)?));
+            }
+            Policy::Cors(cors) => {
+                let origins = cors
+                    .allow_origins
+                    .iter()
+                    .map(|origin| StringMatcher {
+                        match_pattern: Some(match origin.as_str() {
+                            "*" => MatchPattern::SafeRegex(rule_patterns::re2(".*".to_string())),
+                            origin => MatchPattern::Exact(origin.to_string()),
+                        }),
+                        ..Default::default()
+                    })
+                    .collect();
+                host.cors = Some(CorsPolicy {
+                    allow_origin_string_match: origins,
+                    allow_methods: cors.allow_methods.join(","),
+                    allow_headers: cors.allow_headers.join(","),
+                    expose_headers: cors.expose_headers.join(","),
+                    max_age: cors.max_age.map(|age| age.to_string()).unwrap_or_default(),
+                    allow_credentials: Some(cors.allow_credentials),
+                    ..Default::default()
+                });
+                // the filter takes the policy of the virtual host, and has
+                // no settings of its own
+                filters.push(typed_filter(
+                    http_filters::CORS,
+                    type_urls.pack(&CorsFilter::default())?,
+                ));
+            }
+            Policy::RateLimit(limit) => {
+                let interval = i64::try_from(limit.interval)
+                    .map_err(|_| anyhow::anyhow!("rate limit interval too long"))?;
+                let rate_limit = LocalRateLimit {
+                    stat_prefix: format!("{}_rate_limit", stat_prefix),
+                    token_bucket: Some(TokenBucket {
+                        max_tokens: limit.requests,
+                        tokens_per_fill: Some(limit.requests),
+                        fill_interval: Some(prost_types::Duration {
+                            seconds: interval,
+                            nanos: 0,
+                        }),
+                    }),
+                    filter_enabled: always(),
+                    filter_enforced: always(),
+                    ..Default::default()
+                };
+                filters.push(typed_filter(
+                    http_filters::LOCAL_RATE_LIMIT,
+                    type_urls.pack(&rate_limit)?,
+                ));
+            }
+            Policy::MaintenanceMode(maintenance) => maintenance.apply(host),
+            Policy::Routing(routing) => routing.apply(host),
+            Policy::SecurityHeaders(headers) => headers.apply(host, tls),
+            Policy::Custom { .. } => {}
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::field_errors::FieldError;
+    use crate::protobuf::envoy::config::route::v3::{
+        route::Action, route_action, route_match::PathSpecifier, Route, RouteAction, RouteMatch,
+    };
+    use prost::Message;
+
+    fn policy(value: serde_json::Value) -> Policy {
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn catch_all() -> VirtualHost {
+        VirtualHost {
+            routes: vec![Route {
+                r#match: Some(RouteMatch {
+                    path_specifier: Some(PathSpecifier::Prefix("/".to_string())),
+                    ..Default::default()
+                }),
+                action: Some(Action::Route(RouteAction {
+                    cluster_specifier: Some(route_action::ClusterSpecifier::Cluster(
+                        "cluster".to_string(),
+                    )),
+                    ..Default::default()
+                })),
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    // The virtual host and filters once `policy` is applied.
+    fn applied(policy: &Policy) -> (VirtualHost, Vec<HttpFilter>) {
+        assert!(
+            policy.findings("").errors.is_empty(),
+            "{:?}",
+            policy.findings("").errors
+        );
+        let mut host = catch_all();
+        let mut filters = Vec::new();
+        policy
+            .apply(
+                &mut host,
+                &mut filters,
+                "service_1",
+                &TypeUrls::default(),
+                false,
+            )
+            .unwrap();
+        (host, filters)
+    }
+
+    fn filter_config<T: Message + Default>(filter: &HttpFilter) -> T {
+        match filter.config_type {
+            Some(http_filter::ConfigType::TypedConfig(ref any)) => {
+                T::decode(any.value.as_slice()).unwrap()
+            }
+            _ => panic!("{:?} has no typed config", filter),
+        }
+    }
+
+    fn paths(errors: Vec<FieldError>) -> Vec<std::string::String> {
+        errors.into_iter().map(|error| error.path).collect()
+    }
+
+    #[test]
+    fn headers_are_changed_on_the_virtual_host() {
+        let headers = policy(serde_json::json!({
+            "name": "headers",
+            "configuration": {
+                "request": {"set": {"x-env": "prod"}, "remove": ["x-debug"]},
+                "response": {"add": {"x-served-by": "envoy"}},
+            },
+        }));
+        let (host, filters) = applied(&headers);
+        assert!(filters.is_empty());
+        let header = host.request_headers_to_add[0].clone();
+        assert_eq!(header.header.unwrap().key, "x-env");
+        assert_eq!(header.append, Some(false));
+        assert_eq!(host.request_headers_to_remove, ["x-debug"]);
+        assert_eq!(host.response_headers_to_add[0].append, Some(true));
+
+        let invalid = policy(serde_json::json!({
+            "name": "headers",
+            "configuration": {"request": {"set": {":path": "/"}, "remove": ["Host"]}},
+        }));
+        assert_eq!(
+            paths(invalid.findings("[0]").errors),
+            [
+                "[0].configuration.request.set.:path",
+                "[0].configuration.request.remove[0]"
+            ]
+        );
+    }
+
+    #[test]
+    fn url_rewriting_routes_go_before_the_catch_all() {
+        let rewriting = policy(serde_json::json!({
+            "name": "url_rewriting",
+            "configuration": {"commands": [
+                {"op": "sub", "regex": "^/v1/", "replace": "/api/", "break": true},
+                {"op": "gsub", "regex": "/old/(\\w+)", "replace": "/new/$1"},
+            ]},
+        }));
+        let (host, _) = applied(&rewriting);
+        assert_eq!(host.routes.len(), 3);
+        let specifier = |route: &Route| route.r#match.as_ref().unwrap().path_specifier.clone();
+        let action = |route: &Route| match route.action {
+            Some(Action::Route(ref action)) => action.clone(),
+            ref action => panic!("{:?}", action),
+        };
+        assert_eq!(
+            specifier(&host.routes[0]),
+            Some(PathSpecifier::Prefix("/v1/".to_string()))
+        );
+        assert_eq!(action(&host.routes[0]).prefix_rewrite, "/api/");
+        match specifier(&host.routes[1]) {
+            Some(PathSpecifier::SafeRegex(regex)) => assert_eq!(regex.regex, ".*(?:/old/(\\w+)).*"),
+            specifier => panic!("{:?}", specifier),
+        }
+        let rewrite = action(&host.routes[1]).regex_rewrite.unwrap();
+        assert_eq!(rewrite.substitution, "/new/\\1");
+        assert_eq!(host.routes[2], catch_all().routes[0]);
+
+        assert_eq!(
+            paths(
+                policy(serde_json::json!("url_rewriting"))
+                    .findings("")
+                    .errors
+            ),
+            ["configuration.commands"]
+        );
+    }
+
+    #[test]
+    fn ip_checks_filter_with_rbac() {
+        let check = policy(serde_json::json!({
+            "name": "ip_check",
+            "configuration": {"ips": ["10.0.0.0/8", "::1"], "check_type": "allow"},
+        }));
+        let (_, filters) = applied(&check);
+        assert_eq!(filters[0].name, "envoy.filters.http.rbac");
+        let rules = filter_config::<Rbac>(&filters[0]).rules.unwrap();
+        assert_eq!(rules.action, rbac::Action::Allow as i32);
+        let ranges: Vec<_> = rules.policies[IP_CHECK]
+            .principals
+            .iter()
+            .map(|principal| match principal.identifier {
+                Some(principal::Identifier::DirectRemoteIp(ref range)) => {
+                    format!("{}/{}", range.address_prefix, range.prefix_len.unwrap())
+                }
+                ref identifier => panic!("{:?}", identifier),
+            })
+            .collect();
+        assert_eq!(ranges, ["10.0.0.0/8", "::1/128"]);
+
+        let invalid = policy(serde_json::json!({
+            "name": "ip_check",
+            "configuration": {"ips": ["10.0.0.0/33", "example.com", "192.168.0.1"]},
+        }));
+        assert_eq!(
+            paths(invalid.findings("").errors),
+            ["configuration.ips[0]", "configuration.ips[1]"]
+        );
+    }
+
+    #[test]
+    fn cors_is_a_policy_of_the_virtual_host() {
+        let cors = policy(serde_json::json!({
+            "name": "cors",
+            "configuration": {
+                "allow_origins": ["https://web.app"],
+                "allow_methods": ["GET", "POST"],
+                "max_age": 600,
+                "allow_credentials": true,
+            },
+        }));
+        let (host, filters) = applied(&cors);
+        let cors = host.cors.unwrap();
+        assert_eq!(
+            cors.allow_origin_string_match[0].match_pattern,
+            Some(MatchPattern::Exact("https://web.app".to_string()))
+        );
+        assert_eq!(cors.allow_methods, "GET,POST");
+        assert_eq!(cors.max_age, "600");
+        assert_eq!(filters[0].name, "envoy.filters.http.cors");
+
+        // any origin by default
+        let (host, _) = applied(&policy(serde_json::json!("cors")));
+        assert!(matches!(
+            host.cors.unwrap().allow_origin_string_match[0].match_pattern,
+            Some(MatchPattern::SafeRegex(_))
+        ));
+        let invalid = policy(serde_json::json!({
+            "name": "cors",
+            "configuration": {"allow_credentials": true},
+        }));
+        assert_eq!(
+            paths(invalid.findings("").errors),
+            ["configuration.allow_credentials"]
+        );
+    }
+
+    #[test]
+    fn rate_limits_fill_a_token_bucket() {
+        let limit = policy(serde_json::json!({
+            "name": "rate_limit",
+            "configuration": {"requests": 100, "interval": 60},
+        }));
+        let (_, filters) = applied(&limit);
+        let config = filter_config::<LocalRateLimit>(&filters[0]);
+        assert_eq!(config.stat_prefix, "service_1_rate_limit");
+        let bucket = config.token_bucket.unwrap();
+        assert_eq!(bucket.max_tokens, 100);
+        assert_eq!(bucket.tokens_per_fill, Some(100));
+        assert_eq!(bucket.fill_interval.unwrap().seconds, 60);
+        assert!(config.filter_enforced.is_some());
+
+        let invalid = policy(serde_json::json!({
+            "name": "rate_limit",
+            "configuration": {"requests": 0, "interval": 0},
+        }));
+        assert_eq!(
+            paths(invalid.findings("").errors),
+            ["configuration.requests", "configuration.interval"]
+        );
+        let endless = policy(serde_json::json!({
+            "name": "rate_limit",
+            "configuration": {"requests": 1, "interval": u64::MAX},
+        }));
+        assert_eq!(
+            paths(endless.findings("").errors),
+            ["configuration.interval"]
+        );
+        let error = serde_json::from_value::<Policy>(serde_json::json!({"name": "rate_limit"}))
+            .unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .starts_with("policy 'rate_limit': missing field"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn maintenance_mode_answers_every_route() {
+        let rewriting = policy(serde_json::json!({
+            "name": "url_rewriting",
+            "configuration": {"commands": [{"op": "sub", "regex": "^/v1/", "replace": "/api/"}]},
+        }));
+        let maintenance = policy(serde_json::json!("maintenance_mode"));
+        let (mut host, mut filters) = applied(&maintenance);
+        // whichever policy comes first
+        rewriting
+            .apply(
+                &mut host,
+                &mut filters,
+                "service_1",
+                &TypeUrls::default(),
+                false,
+            )
+            .unwrap();
+        assert_eq!(host.routes.len(), 2);
+        for route in &host.routes {
+            match route.action {
+                Some(Action::DirectResponse(ref response)) => assert_eq!(response.status, 503),
+                ref action => panic!("{:?}", action),
+            }
+        }
+
+        let invalid = policy(serde_json::json!({
+            "name": "maintenance_mode",
+            "configuration": {"status": 42},
+        }));
+        assert_eq!(paths(invalid.findings("").errors), ["configuration.status"]);
+    }
+
+    #[test]
+    fn unknown_policies_are_kept_as_they_are() {
+        let value = serde_json::json!({"name": "soap", "configuration": {"path": "/soap"}});
+        let custom = policy(value.clone());
+        assert_eq!(
+            custom,
+            Policy::Custom {
+                name: "soap".to_string(),
+                configuration: serde_json::json!({"path": "/soap"}),
+            }
+        );
+        assert_eq!(serde_json::to_value(&custom).unwrap(), value);
+        let (host, filters) = applied(&custom);
+        assert_eq!(host, catch_all());
+        assert!(filters.is_empty());
+
+        // known policies round trip with their defaults
+        let cors = policy(serde_json::json!("cors"));
+        assert_eq!(
+            policy(serde_json::to_value(&cors).unwrap()),
+            Policy::Cors(Cors::default())
+        );
+    }
+}
@@ -0,0 +1,637 @@
+use std::convert::TryFrom;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+
+use crate::configuration;
+use crate::conflicts::HostConflicts;
+use crate::http_client::HttpClient;
+use crate::publisher::Publisher;
+use crate::service::{MappingRules, Service};
+use crate::threescale_auth::{self, ThreescaleAuth};
+
+const DEFAULT_BACKEND_URL: &str = "https://su1.3scale.net/";
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(60);
+const PER_PAGE: u32 = 500;
+
+/// The 3scale account the Porta source polls, and how often, as given on
+/// the command line.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PortaSettings {
+    pub admin_url: url::Url,
+    pub access_token: std::string::String,
+    pub poll_interval: Duration,
+    // `production` or `staging`
+    pub environment: std::string::String,
+}
+
+/// Services source polling the 3scale Porta Account Management API.
+#[derive(Debug, Clone)]
+pub struct PortaSource {
+    admin_url: url::Url,
+    access_token: std::string::String,
+    poll_interval: Duration,
+    environment: std::string::String,
+    client: HttpClient,
+    // what services serving the same hosts make
+    host_conflicts: HostConflicts,
+}
+
+/// Outcome of a sync: the services that could be mapped plus the reason
+/// each of the other ones was left out.
+pub struct Sync {
+    pub config: configuration::Config,
+    pub errors: Vec<(u64, anyhow::Error)>,
+}
+
+#[derive(Deserialize)]
+struct ServicesPage {
+    services: Vec<ServiceItem>,
+    metadata: Option<Pagination>,
+}
+
+#[derive(Deserialize)]
+struct ServiceItem {
+    service: ServiceEntry,
+}
+
+#[derive(Deserialize)]
+struct ServiceEntry {
+    id: u64,
+}
+
+#[derive(Deserialize)]
+struct Pagination {
+    current_page: u32,
+    total_pages: u32,
+}
+
+#[derive(Deserialize)]
+struct ProxyConfigResponse {
+    proxy_config: ProxyConfig,
+}
+
+#[derive(Deserialize)]
+struct ProxyConfig {
+    content: ProxyConfigContent,
+}
+
+#[derive(Deserialize)]
+struct ProxyConfigContent {
+    id: u64,
+    #[serde(default)]
+    backend_version: Option<std::string::String>,
+    #[serde(default)]
+    backend_authentication_value: Option<std::string::String>,
+    proxy: Proxy,
+}
+
+#[derive(Deserialize)]
+struct Proxy {
+    #[serde(default)]
+    api_backend: Option<std::string::String>,
+    #[serde(default)]
+    hosts: Vec<std::string::String>,
+    #[serde(default)]
+    endpoint: Option<std::string::String>,
+    #[serde(default)]
+    oidc_issuer_endpoint: Option<std::string::String>,
+    #[serde(default)]
+    credentials_location: Option<std::string::String>,
+    #[serde(default)]
+    auth_user_key: Option<std::string::String>,
+    #[serde(default)]
+    auth_app_id: Option<std::string::String>,
+    #[serde(default)]
+    auth_app_key: Option<std::string::String>,
+    #[serde(default)]
+    backend: Option<ProxyBackend>,
+    #[serde(default)]
+    proxy_rules: Vec<ProxyRule>,
+}
+
+#[derive(Deserialize)]
+struct ProxyBackend {
+    endpoint: std::string::String,
+}
+
+#[derive(Deserialize)]
+struct ProxyRule {
+    http_method: std::string::String,
+    pattern: std::string::String,
+    metric_system_name: std::string::String,
+    delta: u32,
+}
+
+impl PortaSource {
+    pub fn new(admin_url: url::Url, access_token: std::string::String) -> PortaSource {
+        PortaSource {
+            admin_url,
+            access_token,
+            poll_interval: DEFAULT_POLL_INTERVAL,
+            environment: "production".to_string(),
+            client: HttpClient::default(),
+            host_conflicts: HostConflicts::default(),
+        }
+    }
+
+    /// A source of `settings`, the requests going through `client`. The
+    /// services conflicting with the earlier ones are left out as
+    /// `host_conflicts` says.
+    pub fn from_settings(
+        settings: &PortaSettings,
+        client: &HttpClient,
+        host_conflicts: HostConflicts,
+    ) -> PortaSource {
+        let mut source =
+            PortaSource::new(settings.admin_url.clone(), settings.access_token.clone());
+        source.poll_interval = settings.poll_interval;
+        source.environment = settings.environment.clone();
+        source.client = client.clone();
+        source.host_conflicts = host_conflicts;
+        source
+    }
+
+    fn request(&self, path: &str, query: &[(&str, &str)]) -> Result<std::string::String> {
+        let mut target_url = self.admin_url.join(path)?;
+        target_url
+            .query_pairs_mut()
+            .append_pair("access_token", &self.access_token)
+            .extend_pairs(query);
+
+        let mut dst = Vec::new();
+        let mut easy = self.client.easy(&target_url)?;
+        {
+            let mut transfer = easy.transfer();
+            transfer.write_function(|data| {
+                dst.extend_from_slice(data);
+                Ok(data.len())
+            })?;
+            transfer
+                .perform()
+                .with_context(|| format!("GET {} failed", path))?;
+        }
+        let status = easy.response_code()?;
+        if status != 200 {
+            bail!("GET {} returned status {}", path, status);
+        }
+        Ok(std::string::String::from_utf8(dst)?)
+    }
+
+    // Walks every page of the services list, appending the raw pages to
+    // `content`.
+    fn fetch_service_ids(&self, content: &mut std::string::String) -> Result<Vec<u64>> {
+        let mut ids = Vec::new();
+        let per_page = PER_PAGE.to_string();
+        let mut page = 1;
+        loop {
+            let raw = self.request(
+                "admin/api/services.json",
+                &[("page", &page.to_string()), ("per_page", &per_page)],
+            )?;
+            let services: ServicesPage = serde_json::from_str(&raw)
+                .with_context(|| format!("invalid services list, page {}", page))?;
+            content.push_str(&raw);
+
+            let received = services.services.len();
+            ids.extend(services.services.into_iter().map(|item| item.service.id));
+            let last_page = match services.metadata {
+                Some(metadata) => metadata.current_page >= metadata.total_pages,
+                // older Porta releases don't paginate metadata
+                None => received < PER_PAGE as usize,
+            };
+            if last_page {
+                return Ok(ids);
+            }
+            page += 1;
+        }
+    }
+
+    fn fetch_proxy_config(&self, id: u64) -> Result<std::string::String> {
+        self.request(
+            &format!(
+                "admin/api/services/{}/proxy/configs/{}/latest.json",
+                id, self.environment
+            ),
+            &[],
+        )
+    }
+
+    /// Fetch every service with its latest proxy config. Failing to list the
+    /// services fails the sync, a service that can't be fetched or mapped,
+    /// or that conflicts with an earlier one, is only reported.
+    pub fn sync(&self) -> Result<Sync> {
+        let mut content = std::string::String::new();
+        let ids = self.fetch_service_ids(&mut content)?;
+
+        let mut services = Vec::with_capacity(ids.len());
+        let mut errors = Vec::new();
+        for id in ids {
+            let service = self.fetch_proxy_config(id).and_then(|raw| {
+                let service = map_service(&raw)?;
+                content.push_str(&raw);
+                Ok(service)
+            });
+            match service {
+                Ok(service) => services.push((None, service)),
+                Err(e) => errors.push((id, e)),
+            }
+        }
+        let (services, conflicting) =
+            configuration::without_conflicts(services, self.host_conflicts);
+        errors.extend(conflicting.into_iter().map(|(id, e)| (u64::from(id), e)));
+
+        Ok(Sync {
+            config: configuration::Config::from_services(services, &content),
+            errors,
+        })
+    }
+
+    /// Sync once, returning whether a new version was published.
+    pub fn reload(&self, publisher: &Publisher) -> Result<bool> {
+        let sync = self.sync()?;
+        for (id, e) in &sync.errors {
+            tracing::error!(service.id = id, "Skipping Porta service: {:#}", e);
+        }
+        publisher.publish(sync.config)
+    }
+
+    fn sync_logged(&self, publisher: &Publisher) {
+        if let Err(e) = self.reload(publisher) {
+            let config = publisher.config();
+            tracing::error!(
+                version = config.read().unwrap().get_version(),
+                "Porta sync failed, still serving the current version: {:#}",
+                e
+            );
+            configuration::reload_failed(config, &e);
+        }
+    }
+
+    /// Sync once and keep polling Porta in the background, for as long as
+    /// the replica leads.
+    pub fn spawn(self, publisher: Publisher) {
+        if publisher.leadership().is_leader() {
+            self.sync_logged(&publisher);
+        }
+        std::thread::spawn(move || loop {
+            std::thread::sleep(self.poll_interval);
+            if publisher.leadership().is_leader() {
+                self.sync_logged(&publisher);
+            }
+        });
+    }
+}
+
+fn map_service(raw: &str) -> Result<Service> {
+    let response: ProxyConfigResponse =
+        serde_json::from_str(raw).context("invalid proxy config")?;
+    let content = response.proxy_config.content;
+    let proxy = content.proxy;
+
+    let porta_id = content.id;
+    let id = u32::try_from(porta_id)
+        .with_context(|| format!("service id {} is out of range", porta_id))?;
+    let target_domain = proxy
+        .api_backend
+        .context("the proxy config has no api_backend")?;
+
+    let mut hosts = proxy.hosts;
+    if hosts.is_empty() {
+        if let Some(ref endpoint) = proxy.endpoint {
+            let endpoint = url::Url::parse(endpoint).context("invalid proxy endpoint")?;
+            hosts.extend(endpoint.host_str().map(str::to_string));
+        }
+    }
+    if hosts.is_empty() {
+        bail!("the proxy config has no hosts");
+    }
+
+    // Porta embeds the client credentials in the issuer endpoint.
+    let oidc_issuer = match proxy.oidc_issuer_endpoint {
+        Some(ref issuer) if !issuer.is_empty() => {
+            let mut issuer = url::Url::parse(issuer).context("invalid oidc_issuer_endpoint")?;
+            let _ = issuer.set_username("");
+            let _ = issuer.set_password(None);
+            Some(issuer.as_str().trim_end_matches('/').to_string())
+        }
+        _ => None,
+    };
+
+    let proxy_rules: Vec<MappingRules> = proxy
+        .proxy_rules
+        .into_iter()
+        .map(|rule| {
+            MappingRules::new(
+                rule.pattern,
+                rule.http_method,
+                rule.metric_system_name,
+                rule.delta,
+            )
+        })
+        .collect();
+
+    let token = content
+        .backend_authentication_value
+        .context("the proxy config has no backend authentication")?;
+    let location = match proxy.credentials_location.as_deref() {
+        Some("query") => "query_string",
+        _ => "header",
+    };
+    let credentials = match content.backend_version.as_deref() {
+        Some("2") => serde_json::json!([
+            {
+                "kind": "app_id",
+                "key": proxy.auth_app_id.unwrap_or_else(|| "app_id".to_string()),
+                "locations": [location],
+            },
+            {
+                "kind": "app_key",
+                "key": proxy.auth_app_key.unwrap_or_else(|| "app_key".to_string()),
+                "locations": [location],
+            },
+        ]),
+        Some("oidc") | Some("oauth") => serde_json::json!([]),
+        _ => serde_json::json!([{
+            "kind": "user_key",
+            "key": proxy.auth_user_key.unwrap_or_else(|| "user_key".to_string()),
+            "locations": [location],
+        }]),
+    };
+    let backend_url = proxy
+        .backend
+        .map(|backend| backend.endpoint)
+        .unwrap_or_else(|| DEFAULT_BACKEND_URL.to_string());
+    let auth_config: ThreescaleAuth = serde_json::from_value(serde_json::json!({
+        "path": threescale_auth::WASM_PATH,
+        "wasm_config": {
+            "backend": {
+                "cluster_name": threescale_auth::backend_cluster_name(id),
+                "url": backend_url,
+                "timeout": 5,
+            },
+            "services": [{
+                "id": porta_id.to_string(),
+                "token": token,
+                "authorities": hosts,
+                "credentials": credentials,
+                "mapping_rules": proxy_rules
+                    .iter()
+                    .map(|rule| {
+                        let rule = rule.filter_rule();
+                        serde_json::json!({
+                            "method": rule.http_method.to_lowercase(),
+                            "pattern": rule.pattern,
+                            "usages": [{"name": rule.metric_system_name, "delta": rule.delta}],
+                        })
+                    })
+                    .collect::<Vec<_>>(),
+            }],
+        },
+    }))
+    .context("invalid 3scale auth config")?;
+
+    let mut builder = Service::builder()
+        .id(id)
+        .target_domain(target_domain)
+        .threescale_auth(auth_config);
+    for host in hosts {
+        builder = builder.host(host);
+    }
+    for rule in proxy_rules {
+        builder = builder.mapping_rule(rule);
+    }
+    if let Some(issuer) = oidc_issuer {
+        builder = builder.oidc_issuer(issuer);
+    }
+    builder.build()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http_harness::{Response, Server};
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    // Serves canned responses by path, ignoring the query string but for
+    // the page, and keeps the requests around.
+    struct MockPorta {
+        url: url::Url,
+        server: Server,
+        responses: Arc<Mutex<HashMap<std::string::String, (u32, std::string::String)>>>,
+    }
+
+    impl MockPorta {
+        fn start() -> MockPorta {
+            let responses = Arc::new(Mutex::new(HashMap::new()));
+            let canned = Arc::clone(&responses);
+            let server = Server::start(move |request| {
+                let page = request
+                    .target
+                    .split(['?', '&'])
+                    .find(|param| param.starts_with("page="))
+                    .map(|param| format!("?{}", param))
+                    .unwrap_or_default();
+                let responses = canned.lock().unwrap();
+                let (status, body) = responses
+                    .get(&format!("{}{}", request.path(), page))
+                    .or_else(|| responses.get(request.path()))
+                    .cloned()
+                    .unwrap_or((404, r#"{"status": "Not found"}"#.to_string()));
+                Response::status(status, body).header("Content-Type", "application/json")
+            });
+            MockPorta {
+                url: url::Url::parse(&format!("{}/", server.url())).unwrap(),
+                server,
+                responses,
+            }
+        }
+
+        fn respond(&self, path: &str, status: u32, body: std::string::String) {
+            self.responses
+                .lock()
+                .unwrap()
+                .insert(path.to_string(), (status, body));
+        }
+
+        fn source(&self) -> PortaSource {
+            PortaSource::new(self.url.clone(), "secret".to_string())
+        }
+    }
+
+    fn services_page(ids: &[u64], current_page: u32, total_pages: u32) -> std::string::String {
+        let services: Vec<_> = ids
+            .iter()
+            .map(|id| serde_json::json!({"service": {"id": id, "name": format!("API {}", id)}}))
+            .collect();
+        serde_json::json!({
+            "services": services,
+            "metadata": {
+                "per_page": 500,
+                "total_entries": 3,
+                "total_pages": total_pages,
+                "current_page": current_page,
+            },
+        })
+        .to_string()
+    }
+
+    fn proxy_config(id: u64, api_backend: Option<&str>) -> std::string::String {
+        serde_json::json!({
+            "proxy_config": {
+                "id": 10,
+                "version": 3,
+                "environment": "production",
+                "content": {
+                    "id": id,
+                    "backend_version": "1",
+                    "backend_authentication_type": "service_token",
+                    "backend_authentication_value": format!("token-{}", id),
+                    "proxy": {
+                        "api_backend": api_backend,
+                        "hosts": [format!("api-{}.example.com", id)],
+                        "endpoint": format!("https://api-{}.example.com:443", id),
+                        "auth_user_key": "user_key",
+                        "credentials_location": "query",
+                        "oidc_issuer_endpoint": null,
+                        "backend": {"endpoint": "https://su1.3scale.net", "host": "su1.3scale.net"},
+                        "policy_chain": [{"name": "apicast", "version": "builtin", "configuration": {}}],
+                        "proxy_rules": [
+                            {"id": 1, "http_method": "GET", "pattern": "/", "metric_id": 5,
+                             "metric_system_name": "hits", "delta": 1, "position": 1, "last": false},
+                            {"id": 2, "http_method": "POST", "pattern": "/orders", "metric_id": 6,
+                             "metric_system_name": "orders", "delta": 2, "position": 2, "last": true},
+                        ],
+                    },
+                },
+            },
+        })
+        .to_string()
+    }
+
+    fn latest(id: u64) -> std::string::String {
+        format!(
+            "/admin/api/services/{}/proxy/configs/production/latest.json",
+            id
+        )
+    }
+
+    #[test]
+    fn sync_walks_all_pages() {
+        let porta = MockPorta::start();
+        porta.respond(
+            "/admin/api/services.json?page=1",
+            200,
+            services_page(&[1, 2], 1, 2),
+        );
+        porta.respond(
+            "/admin/api/services.json?page=2",
+            200,
+            services_page(&[3], 2, 2),
+        );
+        for id in 1..=3 {
+            porta.respond(&latest(id), 200, proxy_config(id, Some("https://echo:443")));
+        }
+
+        let sync = porta.source().sync().unwrap();
+        assert!(sync.errors.is_empty());
+
+        let services = sync.config.get_services();
+        assert_eq!(services.iter().map(|s| s.id).collect::<Vec<_>>(), [1, 2, 3]);
+        assert_eq!(services[1].hosts, ["api-2.example.com"]);
+        assert_eq!(services[1].target_domain, "https://echo:443");
+
+        let filter_config = services[1].filter_config(&Default::default());
+        assert_eq!(filter_config.proxy_rules.len(), 2);
+        assert_eq!(filter_config.proxy_rules[1].metric_system_name, "orders");
+        assert_eq!(filter_config.proxy_rules[1].delta, 2);
+
+        let requests = porta.server.requests();
+        assert!(requests
+            .iter()
+            .all(|r| r.target.contains("access_token=secret")));
+    }
+
+    #[test]
+    fn mapping_errors_do_not_abort_the_sync() {
+        let porta = MockPorta::start();
+        porta.respond(
+            "/admin/api/services.json",
+            200,
+            services_page(&[1, 2, 3], 1, 1),
+        );
+        porta.respond(&latest(1), 200, proxy_config(1, Some("https://echo:443")));
+        porta.respond(&latest(2), 200, proxy_config(2, None));
+        // service 3 was never deployed, so it has no proxy config
+
+        let sync = porta.source().sync().unwrap();
+        let services = sync.config.get_services();
+        assert_eq!(services.len(), 1);
+        assert_eq!(services[0].id, 1);
+        assert_eq!(
+            sync.errors.iter().map(|(id, _)| *id).collect::<Vec<_>>(),
+            [2, 3]
+        );
+    }
+
+    #[test]
+    fn conflicting_services_are_left_out() {
+        let porta = MockPorta::start();
+        porta.respond(
+            "/admin/api/services.json",
+            200,
+            services_page(&[1, 2], 1, 1),
+        );
+        porta.respond(&latest(1), 200, proxy_config(1, Some("https://echo:443")));
+        let mut second: serde_json::Value =
+            serde_json::from_str(&proxy_config(2, Some("https://echo:443"))).unwrap();
+        second["proxy_config"]["content"]["proxy"]["hosts"] =
+            serde_json::json!(["api-1.example.com"]);
+        porta.respond(&latest(2), 200, second.to_string());
+
+        // only warned about by default
+        let sync = porta.source().sync().unwrap();
+        assert_eq!(sync.config.get_services().len(), 2);
+        assert!(sync.errors.is_empty());
+
+        let mut source = porta.source();
+        source.host_conflicts = HostConflicts::Error;
+        let sync = source.sync().unwrap();
+        let services = sync.config.get_services();
+        assert_eq!(services.iter().map(|s| s.id).collect::<Vec<_>>(), [1]);
+        let errors: Vec<_> = sync
+            .errors
+            .iter()
+            .map(|(id, e)| (*id, e.to_string()))
+            .collect();
+        assert_eq!(
+            errors,
+            [(
+                2,
+                "hosts[0] (service 2): 'api-1.example.com' is served by service 1 too".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn listing_failures_fail_the_sync() {
+        let porta = MockPorta::start();
+        porta.respond("/admin/api/services.json", 403, "{}".to_string());
+        assert!(porta.source().sync().is_err());
+    }
+
+    #[test]
+    fn unchanged_content_hashes_the_same() {
+        let porta = MockPorta::start();
+        porta.respond("/admin/api/services.json", 200, services_page(&[1], 1, 1));
+        porta.respond(&latest(1), 200, proxy_config(1, Some("https://echo:443")));
+
+        let first = porta.source().sync().unwrap().config.get_hash();
+        assert_eq!(porta.source().sync().unwrap().config.get_hash(), first);
+
+        porta.respond(&latest(1), 200, proxy_config(1, Some("https://other:443")));
+        assert_ne!(porta.source().sync().unwrap().config.get_hash(), first);
+    }
+}
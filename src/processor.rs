@@ -1,63 +1,257 @@
 use std::sync::{Arc, RwLock};
+use std::time::Duration;
 
 use crate::protobuf::envoy::service::cluster::v3::cluster_discovery_service_server::ClusterDiscoveryServiceServer;
+use crate::protobuf::envoy::service::discovery::v3::aggregated_discovery_service_server::AggregatedDiscoveryServiceServer;
 use crate::protobuf::envoy::service::listener::v3::listener_discovery_service_server::ListenerDiscoveryServiceServer;
-use tonic::transport::Server;
-use tonic::{Request, Status};
+use crate::protobuf::envoy::service::secret::v3::secret_discovery_service_server::SecretDiscoveryServiceServer;
+use crate::protobuf::grpc::health::v1::health_server::HealthServer;
+use crate::protobuf::grpc::reflection::v1alpha::server_reflection_server::ServerReflectionServer;
+use futures::Stream;
+use tokio::net::{TcpListener, TcpStream};
+use tonic::transport::{NamedService, Server};
 
+use crate::cli::ControllerConfig;
 use crate::configuration;
+use crate::envoy_ads;
 use crate::envoy_cds;
 use crate::envoy_lds;
+use crate::envoy_sds;
+use crate::grpc_health;
+use crate::grpc_reflection;
+use crate::jwks_rotation;
+use crate::leader::{self, Leadership, Role};
+use crate::node_status::NodeStatuses;
+use crate::publisher::Publisher;
+use crate::reconcile;
+use crate::reload::Reloader;
+use crate::secret;
+use crate::shutdown::Shutdown;
+use crate::source;
+use crate::tls;
+use crate::wasm_files;
+
+// The connections accepted on `listener`.
+fn incoming(
+    listener: TcpListener,
+    keepalive: Option<Duration>,
+) -> impl Stream<Item = std::io::Result<TcpStream>> {
+    futures::stream::unfold(listener, move |mut listener| async move {
+        let connection = listener.accept().await.map(|(stream, _)| {
+            if let Err(e) = stream.set_keepalive(keepalive) {
+                tracing::warn!("Cannot keep the connection alive: {:?}", e);
+            }
+            stream
+        });
+        Some((connection, listener))
+    })
+}
 
-#[derive(Default)]
 pub struct MasterProcess {
+    settings: ControllerConfig,
     config: Arc<RwLock<configuration::Config>>,
+    publisher: Publisher,
+    statuses: NodeStatuses,
+    shutdown: Shutdown,
+    reloader: Reloader,
+    leadership: Leadership,
 }
 
 impl MasterProcess {
-    pub fn config_thread(&'_ self) {
-        let mut initial_config = "".to_string();
-        let cfg = Arc::clone(&self.config);
-        tokio::task::spawn_blocking(move || loop {
-            let config = &configuration::Config::parse_config("./log.json");
-            if config.get_hash() != initial_config {
-                initial_config = config.get_hash();
-
-                let mut self_config = cfg.write().unwrap();
-                self_config.import(config.get_services(), initial_config.clone());
-                log::info!("Config update to version: {}", self_config.get_version());
+    pub fn new(settings: ControllerConfig) -> MasterProcess {
+        let mut config = configuration::Config::default();
+        config.set_wasm(settings.wasm.clone());
+        config.set_export_concurrency(settings.export_concurrency);
+        config.set_strict(settings.strict_export);
+        let config = Arc::new(RwLock::new(config));
+        // replicas follow until elected
+        let leadership = match settings.leader_election {
+            Some(ref election) => Leadership::new(Role::Follower, election.snapshot_path.clone())
+                .with_digest(election.snapshot_digest),
+            None => Leadership::default(),
+        };
+        MasterProcess {
+            shutdown: Shutdown::new(settings.shutdown_notify_streams),
+            publisher: Publisher::with_leadership(
+                Arc::clone(&config),
+                settings.publish_window,
+                leadership.clone(),
+            ),
+            config,
+            statuses: NodeStatuses::default(),
+            reloader: Reloader::default(),
+            leadership,
+            settings,
+        }
+    }
+
+    pub fn config(&self) -> Arc<RwLock<configuration::Config>> {
+        Arc::clone(&self.config)
+    }
+
+    pub fn statuses(&self) -> NodeStatuses {
+        self.statuses.clone()
+    }
+
+    pub fn shutdown(&self) -> Shutdown {
+        self.shutdown.clone()
+    }
+
+    pub fn reloader(&self) -> Reloader {
+        self.reloader.clone()
+    }
+
+    pub fn leadership(&self) -> Leadership {
+        self.leadership.clone()
+    }
+
+    // Follow the leader until elected, then load the services afresh, the
+    // sources having been left alone meanwhile.
+    fn elect(&self) -> anyhow::Result<()> {
+        let election = match self.settings.leader_election {
+            Some(ref election) => election,
+            None => return Ok(()),
+        };
+        let reloader = self.reloader.clone();
+        self.leadership.on_change(move |role| {
+            if role == Role::Leader {
+                let reloader = reloader.clone();
+                std::thread::spawn(move || reloader.reload());
             }
-            std::thread::sleep(std::time::Duration::from_secs(5));
         });
+        self.leadership.spawn_follower(Arc::clone(&self.config));
+        leader::Election::new(election)?.spawn(self.leadership.clone());
+        Ok(())
     }
 
-    pub async fn start(
-        &mut self,
-        addr: std::net::SocketAddr,
-    ) -> Result<(), tonic::transport::Error> {
+    pub fn config_thread(&'_ self) {
+        // opt-in, as rolling back affects every node
+        if self.settings.rollback_on_nack {
+            tracing::info!("Rejected updates will be rolled back");
+            self.config.write().unwrap().enable_rollback();
+        }
+        if let Err(e) = self.elect() {
+            tracing::error!("Cannot elect the leader: {:#}", e);
+        }
+        let result = source::Source::new(&self.settings, &self.publisher).and_then(|source| {
+            source.install_reload(&self.reloader, &self.publisher);
+            source.spawn(self.publisher.clone())
+        });
+        if let Err(e) = result {
+            tracing::error!("Cannot load the services config: {:#}", e);
+        }
+        secret::spawn_reloader(self.publisher.clone());
+        wasm_files::spawn_reloader(self.publisher.clone());
+        jwks_rotation::spawn_reloader(self.publisher.clone());
+        reconcile::spawn_reconciler(self.publisher.clone());
+    }
+
+    pub async fn start(&mut self) -> anyhow::Result<()> {
+        let listener = TcpListener::bind(self.settings.xds_address).await?;
+        self.serve(listener).await
+    }
+
+    /// Load the services and serve discovery on `listener` until shut down.
+    pub async fn serve(&mut self, listener: TcpListener) -> anyhow::Result<()> {
         {
+            let addr = listener.local_addr()?;
+            let tls = self
+                .settings
+                .tls
+                .clone()
+                .map(tls::ReloadableAcceptor::new)
+                .transpose()?;
+            let grace = self.settings.shutdown_grace_period;
+            self.shutdown.on_signals()?;
             self.config_thread();
-            //@TODO to delete  this wait until process start.
-            std::thread::sleep(std::time::Duration::from_secs(3));
 
-            fn intercept(req: Request<()>) -> Result<Request<()>, Status> {
-                println!("Intercepting request: {:?}", req);
-                Ok(req)
-            }
+            // the nodes are authenticated before any request is handled
+            let intercept = self.settings.xds_auth()?.interceptor();
 
             // Services sections
-            let cds = envoy_cds::CDS::new(Arc::clone(&self.config));
-            let lds = envoy_lds::LDS::new(Arc::clone(&self.config));
+            let cds = envoy_cds::CDS::new(self.config.clone(), self.statuses(), self.shutdown());
+            let lds = envoy_lds::LDS::new(self.config.clone(), self.statuses(), self.shutdown());
+            let sds = envoy_sds::SDS::new(self.config.clone(), self.statuses(), self.shutdown());
+            let ads = envoy_ads::ADS::new(self.config.clone(), self.statuses(), self.shutdown());
+
+            // the health of every service is the readiness of the server
+            let mut services = vec![
+                ClusterDiscoveryServiceServer::<envoy_cds::CDS>::NAME,
+                ListenerDiscoveryServiceServer::<envoy_lds::LDS>::NAME,
+                SecretDiscoveryServiceServer::<envoy_sds::SDS>::NAME,
+                AggregatedDiscoveryServiceServer::<envoy_ads::ADS>::NAME,
+                HealthServer::<grpc_health::Health>::NAME,
+            ];
+            let health = grpc_health::Health::new(
+                Arc::clone(&self.config),
+                self.shutdown(),
+                services.clone(),
+            );
+            let reflection = if self.settings.xds_reflection {
+                services.push(ServerReflectionServer::<grpc_reflection::Reflection>::NAME);
+                Some(ServerReflectionServer::with_interceptor(
+                    grpc_reflection::Reflection::new(services)?,
+                    intercept.clone(),
+                ))
+            } else {
+                None
+            };
 
-            Server::builder()
+            let grpc = &self.settings.grpc;
+            let mut server = Server::builder()
+                .initial_stream_window_size(grpc.stream_window)
+                .initial_connection_window_size(grpc.connection_window)
+                .max_concurrent_streams(grpc.max_concurrent_streams);
+            if let Some(limit) = grpc.concurrency_limit {
+                server = server.concurrency_limit_per_connection(limit);
+            }
+            let keepalive = grpc.tcp_keepalive;
+            let router = server
                 .add_service(ClusterDiscoveryServiceServer::with_interceptor(
-                    cds, intercept,
+                    cds,
+                    intercept.clone(),
                 ))
                 .add_service(ListenerDiscoveryServiceServer::with_interceptor(
-                    lds, intercept,
+                    lds,
+                    intercept.clone(),
                 ))
-                .serve(addr)
-                .await
+                .add_service(SecretDiscoveryServiceServer::with_interceptor(
+                    sds,
+                    intercept.clone(),
+                ))
+                .add_service(AggregatedDiscoveryServiceServer::with_interceptor(
+                    ads,
+                    intercept.clone(),
+                ))
+                // unknown nodes are not told of the services by their health
+                // or reflection either
+                .add_service(HealthServer::with_interceptor(health, intercept))
+                .add_optional_service(reflection);
+
+            // stop accepting connections on shutdown, and let the open
+            // streams finish their responses for up to the grace period
+            let shutdown = self.shutdown();
+            match tls {
+                Some(acceptor) => {
+                    tracing::info!("Serving discovery over TLS on {}", addr);
+                    acceptor.spawn_reloader()?;
+                    let server = router.serve_with_incoming_shutdown(
+                        tls::incoming(listener, acceptor, keepalive),
+                        shutdown.wait(),
+                    );
+                    shutdown.drain(server, grace).await?
+                }
+                None => {
+                    tracing::info!("Serving discovery on {}", addr);
+                    let server = router.serve_with_incoming_shutdown(
+                        incoming(listener, keepalive),
+                        shutdown.wait(),
+                    );
+                    shutdown.drain(server, grace).await?
+                }
+            }
+            tracing::info!("Discovery server stopped");
+            Ok(())
         }
     }
 }
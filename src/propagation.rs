@@ -0,0 +1,193 @@
+use std::time::Instant;
+
+use crate::snapshot::Snapshot;
+
+// A version of the resources of a type, published to a node group and not
+// acked by any node yet.
+#[derive(Debug, Clone)]
+struct Pending {
+    node_group: std::string::String,
+    type_url: &'static str,
+    version: std::string::String,
+    // open until the first ack, lasting as long as the version took to
+    // reach a node
+    span: tracing::Span,
+    published: Instant,
+}
+
+/// The versions on their way to the nodes, for the time from publishing a
+/// version to the first node acking it to be traced. Each gets a
+/// `propagation` span, in the span of the publication, and the first ack a
+/// span following from it.
+#[derive(Debug, Clone, Default)]
+pub struct Propagation {
+    pending: Vec<Pending>,
+}
+
+impl Propagation {
+    /// Track the versions of `snapshot` of `node_group` that changed, those
+    /// they replace being left untracked.
+    pub fn published(&mut self, node_group: &str, snapshot: &Snapshot, previous: &Snapshot) {
+        for (&type_url, version) in snapshot.type_versions() {
+            if previous.type_versions().get(type_url) == Some(version) {
+                continue;
+            }
+            self.pending
+                .retain(|pending| pending.node_group != node_group || pending.type_url != type_url);
+            self.pending.push(Pending {
+                node_group: node_group.to_string(),
+                type_url,
+                version: version.clone(),
+                span: tracing::info_span!(
+                    "propagation",
+                    node_group,
+                    snapshot.version = snapshot.version(),
+                    type_url,
+                    version = version.as_str(),
+                ),
+                published: Instant::now(),
+            });
+        }
+    }
+
+    /// Record `node` of `node_group` acking the `version` of `type_url`
+    /// resources, when the first node of the group to.
+    pub fn acked(&mut self, node_group: &str, node: &str, type_url: &str, version: &str) {
+        let position = self.pending.iter().position(|pending| {
+            pending.node_group == node_group
+                && pending.type_url == type_url
+                && pending.version == version
+        });
+        let pending = match position {
+            Some(position) => self.pending.remove(position),
+            None => return,
+        };
+        let span = tracing::info_span!(
+            parent: None,
+            "ack",
+            node.id = node,
+            node_group,
+            type_url,
+            version,
+        );
+        span.follows_from(&pending.span);
+        let _entered = span.enter();
+        tracing::info!(
+            time_to_ack_ms = pending.published.elapsed().as_millis() as u64,
+            "Version acked"
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::configuration::{self, Config, DEFAULT_NODE_GROUP};
+    use crate::envoy_helpers::CLUSTER_TYPE_URL;
+    use crate::service::WasmSettings;
+    use std::sync::{Arc, Mutex, RwLock};
+    use tracing_subscriber::layer::{Context, SubscriberExt};
+    use tracing_subscriber::registry::LookupSpan;
+
+    // Records the spans created, as `name < parent`, and which spans follow
+    // from which, as `name -> name`.
+    #[derive(Clone, Default)]
+    struct Spans(Arc<Mutex<Vec<std::string::String>>>);
+
+    impl<S> tracing_subscriber::Layer<S> for Spans
+    where
+        S: tracing::Subscriber + for<'a> LookupSpan<'a>,
+    {
+        fn new_span(
+            &self,
+            _: &tracing::span::Attributes<'_>,
+            id: &tracing::span::Id,
+            ctx: Context<'_, S>,
+        ) {
+            let span = ctx.span(id).unwrap();
+            let line = match span.parent() {
+                Some(parent) => format!("{} < {}", span.name(), parent.name()),
+                None => span.name().to_string(),
+            };
+            self.0.lock().unwrap().push(line);
+        }
+
+        fn on_follows_from(
+            &self,
+            id: &tracing::span::Id,
+            follows: &tracing::span::Id,
+            ctx: Context<'_, S>,
+        ) {
+            let (span, follows) = (ctx.span(id).unwrap(), ctx.span(follows).unwrap());
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("{} -> {}", span.name(), follows.name()));
+        }
+    }
+
+    #[test]
+    fn a_reload_is_traced_up_to_the_first_ack() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("services.json");
+        std::fs::write(
+            &path,
+            r#"[{"id": 1, "hosts": ["one"], "policies": [], "target_domain": "http://one:80", "proxy_rules": []}]"#,
+        )
+        .unwrap();
+        let mut config = Config::default();
+        config.set_wasm(WasmSettings {
+            skip_sha: true,
+            ..Default::default()
+        });
+        let shared = RwLock::new(config);
+
+        let spans = Spans::default();
+        let subscriber = tracing_subscriber::registry().with(spans.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info_span!("reload").in_scope(|| {
+                let services =
+                    Config::parse_config(&path.to_string_lossy(), Default::default()).unwrap();
+                assert!(configuration::publish(&shared, services).unwrap());
+            });
+            let version = shared
+                .read()
+                .unwrap()
+                .get_snapshot()
+                .type_version(CLUSTER_TYPE_URL);
+            // a node of another group running the same version doesn't
+            // tell anything of the default one
+            shared
+                .write()
+                .unwrap()
+                .on_ack("edge", "edge-1", CLUSTER_TYPE_URL, &version);
+            assert!(!spans.0.lock().unwrap().iter().any(|span| span == "ack"));
+            // the first ack of the version only
+            for node in &["envoy-1", "envoy-2"] {
+                shared.write().unwrap().on_ack(
+                    DEFAULT_NODE_GROUP,
+                    node,
+                    CLUSTER_TYPE_URL,
+                    &version,
+                );
+                shared
+                    .write()
+                    .unwrap()
+                    .on_ack(DEFAULT_NODE_GROUP, node, CLUSTER_TYPE_URL, "other");
+            }
+        });
+
+        let spans = spans.0.lock().unwrap();
+        let propagations = shared.read().unwrap().get_snapshot().type_versions().len();
+        let mut expected = vec![
+            "reload",
+            "fetch < reload",
+            "validate < reload",
+            "publish < reload",
+            "export_services < publish",
+            "export < export_services",
+        ];
+        expected.extend(vec!["propagation < publish"; propagations]);
+        expected.extend(&["ack", "ack -> propagation"]);
+        assert_eq!(*spans, expected);
+    }
+}
@@ -0,0 +1,955 @@
+use std::collections::HashMap;
+
+use anyhow::{anyhow, bail, Context, Result};
+use data_encoding::BASE64;
+use prost::Message;
+use prost_types::field_descriptor_proto::{Label, Type};
+use prost_types::{DescriptorProto, EnumDescriptorProto, FieldDescriptorProto, FileDescriptorSet};
+use serde_json::{Map, Value};
+
+use crate::type_urls::TYPE_URL_PREFIX;
+
+// Written by build.rs from the protos the resources are generated from.
+pub(crate) const DESCRIPTOR_SET: &[u8] =
+    include_bytes!(concat!(env!("OUT_DIR"), "/descriptor_set.bin"));
+
+/// Protobuf JSON encoding of encoded messages, `Any` fields included,
+/// driven by the descriptors of the Envoy protos. Fields keep their proto
+/// names, which Envoy accepts along with the camel case ones.
+pub struct Registry {
+    messages: HashMap<std::string::String, DescriptorProto>,
+    enums: HashMap<std::string::String, EnumDescriptorProto>,
+}
+
+impl Registry {
+    pub fn new() -> Result<Registry> {
+        let set = FileDescriptorSet::decode(DESCRIPTOR_SET).context("invalid descriptor set")?;
+        let mut registry = Registry {
+            messages: HashMap::new(),
+            enums: HashMap::new(),
+        };
+        for file in set.file {
+            let package = match file.package {
+                Some(ref package) => format!(".{}", package),
+                None => std::string::String::new(),
+            };
+            for message in file.message_type {
+                registry.add_message(&package, message);
+            }
+            for descriptor in file.enum_type {
+                let name = format!("{}.{}", package, descriptor.name.as_deref().unwrap_or(""));
+                registry.enums.insert(name, descriptor);
+            }
+        }
+        Ok(registry)
+    }
+
+    fn add_message(&mut self, scope: &str, mut message: DescriptorProto) {
+        let name = format!("{}.{}", scope, message.name.as_deref().unwrap_or(""));
+        for nested in std::mem::take(&mut message.nested_type) {
+            self.add_message(&name, nested);
+        }
+        for descriptor in std::mem::take(&mut message.enum_type) {
+            let enum_name = format!("{}.{}", name, descriptor.name.as_deref().unwrap_or(""));
+            self.enums.insert(enum_name, descriptor);
+        }
+        self.messages.insert(name, message);
+    }
+
+    /// JSON of the message packed in `any`, without its `@type`.
+    pub fn any_to_json(&self, any: &prost_types::Any) -> Result<Value> {
+        self.message(type_name(&any.type_url)?.as_str(), &any.value)
+    }
+
+    /// The message of `json`, named by its `@type`, packed: the reverse of
+    /// `any_to_json`, taking the proto names of the fields or their camel
+    /// case ones. The fields set to their default are left out, as when
+    /// encoding, so that the JSON of the `Any` has none of them, and so are
+    /// those set to `null`. The fields the protos of the controller don't
+    /// have, as those of a newer Envoy, are left out with a warning.
+    pub fn json_to_any(&self, json: &Value) -> Result<prost_types::Any> {
+        let type_url = json
+            .get("@type")
+            .and_then(Value::as_str)
+            .ok_or_else(|| anyhow!("the message has no @type"))?;
+        Ok(prost_types::Any {
+            type_url: type_url.to_string(),
+            value: self.encode_any_value(type_url, json)?,
+        })
+    }
+
+    fn descriptor(&self, name: &str) -> Result<&DescriptorProto> {
+        self.messages
+            .get(name)
+            .ok_or_else(|| anyhow!("no descriptor of {}", name))
+    }
+
+    fn message(&self, name: &str, bytes: &[u8]) -> Result<Value> {
+        match self.well_known(name, bytes)? {
+            Some(value) => Ok(value),
+            None => Ok(Value::Object(self.fields(name, bytes)?)),
+        }
+    }
+
+    fn fields(&self, name: &str, mut bytes: &[u8]) -> Result<Map<std::string::String, Value>> {
+        let descriptor = self.descriptor(name)?;
+        let mut fields = Map::new();
+        while !bytes.is_empty() {
+            let key = varint(&mut bytes)?;
+            let (number, wire_type) = ((key >> 3) as i32, (key & 7) as u8);
+            let field = descriptor
+                .field
+                .iter()
+                .find(|field| field.number == Some(number))
+                .ok_or_else(|| anyhow!("unknown field {} of {}", number, name))?;
+            let field_name = field.name.clone().unwrap_or_default();
+
+            let repeated = field.label == Some(Label::Repeated as i32);
+            if repeated && wire_type == 2 && is_packable(field) {
+                let mut packed = length_delimited(&mut bytes)?;
+                while !packed.is_empty() {
+                    let value = self.scalar(field, packed_wire_type(field), &mut packed)?;
+                    push(&mut fields, field_name.clone(), value);
+                }
+                continue;
+            }
+            let value = self.value(field, wire_type, &mut bytes)?;
+            match self.map_entry(field) {
+                Some(entry) => {
+                    let map = fields
+                        .entry(field_name)
+                        .or_insert_with(|| Value::Object(Map::new()));
+                    let (key, value) = map_entry(entry, value)?;
+                    map.as_object_mut().unwrap().insert(key, value);
+                }
+                None if repeated => push(&mut fields, field_name, value),
+                None => {
+                    // the last one of a singular field wins, as when decoding
+                    fields.insert(field_name, value);
+                }
+            }
+        }
+        Ok(fields)
+    }
+
+    fn map_entry(&self, field: &FieldDescriptorProto) -> Option<&DescriptorProto> {
+        if field.r#type != Some(Type::Message as i32) {
+            return None;
+        }
+        let descriptor = self.messages.get(field.type_name.as_deref()?)?;
+        let is_entry = descriptor.options.as_ref()?.map_entry?;
+        if is_entry {
+            Some(descriptor)
+        } else {
+            None
+        }
+    }
+
+    fn value(
+        &self,
+        field: &FieldDescriptorProto,
+        wire_type: u8,
+        bytes: &mut &[u8],
+    ) -> Result<Value> {
+        let field_type = field_type(field)?;
+        if !is_packable(field) && wire_type != 2 {
+            bail!(
+                "field {} has wire type {} instead of 2",
+                field.name.as_deref().unwrap_or(""),
+                wire_type
+            );
+        }
+        match field_type {
+            Type::Message => {
+                let message = length_delimited(bytes)?;
+                self.message(field.type_name.as_deref().unwrap_or(""), message)
+            }
+            Type::String => {
+                let string = length_delimited(bytes)?;
+                Ok(Value::String(std::str::from_utf8(string)?.to_string()))
+            }
+            Type::Bytes => Ok(Value::String(BASE64.encode(length_delimited(bytes)?))),
+            Type::Group => bail!("groups are not supported"),
+            _ => self.scalar(field, wire_type, bytes),
+        }
+    }
+
+    fn scalar(
+        &self,
+        field: &FieldDescriptorProto,
+        wire_type: u8,
+        bytes: &mut &[u8],
+    ) -> Result<Value> {
+        let field_type = field_type(field)?;
+        let expected = packed_wire_type(field);
+        if wire_type != expected {
+            bail!(
+                "field {} has wire type {} instead of {}",
+                field.name.as_deref().unwrap_or(""),
+                wire_type,
+                expected
+            );
+        }
+        let value = match field_type {
+            Type::Double => float_value(f64::from_bits(fixed64(bytes)?)),
+            Type::Float => float_value(f32::from_bits(fixed32(bytes)?) as f64),
+            // 64 bits integers are strings in protobuf JSON
+            Type::Int64 => Value::from((varint(bytes)? as i64).to_string()),
+            Type::Uint64 => Value::from(varint(bytes)?.to_string()),
+            Type::Fixed64 => Value::from(fixed64(bytes)?.to_string()),
+            Type::Sfixed64 => Value::from((fixed64(bytes)? as i64).to_string()),
+            Type::Sint64 => Value::from(zigzag(varint(bytes)?).to_string()),
+            Type::Int32 => Value::from(varint(bytes)? as i32),
+            Type::Uint32 => Value::from(varint(bytes)? as u32),
+            Type::Fixed32 => Value::from(fixed32(bytes)?),
+            Type::Sfixed32 => Value::from(fixed32(bytes)? as i32),
+            Type::Sint32 => Value::from(zigzag(varint(bytes)?) as i32),
+            Type::Bool => Value::from(varint(bytes)? != 0),
+            Type::Enum => {
+                let number = varint(bytes)? as i32;
+                self.enum_name(field.type_name.as_deref().unwrap_or(""), number)
+            }
+            _ => bail!("{:?} is not a scalar", field_type),
+        };
+        Ok(value)
+    }
+
+    fn enum_name(&self, name: &str, number: i32) -> Value {
+        if name == ".google.protobuf.NullValue" {
+            return Value::Null;
+        }
+        self.enums
+            .get(name)
+            .and_then(|descriptor| {
+                descriptor
+                    .value
+                    .iter()
+                    .find(|value| value.number == Some(number))
+            })
+            .and_then(|value| value.name.clone())
+            .map(Value::String)
+            // unknown values are kept as numbers
+            .unwrap_or_else(|| Value::from(number))
+    }
+
+    // Types with a JSON representation of their own.
+    fn well_known(&self, name: &str, bytes: &[u8]) -> Result<Option<Value>> {
+        let value = match name {
+            ".google.protobuf.Any" => {
+                let any = prost_types::Any::decode(bytes)?;
+                let mut value = self.any_to_json(&any)?;
+                // messages with a representation of their own are wrapped
+                if !value.is_object() || is_well_known(&type_name(&any.type_url)?) {
+                    let mut object = Map::new();
+                    object.insert("value".to_string(), value);
+                    value = Value::Object(object);
+                }
+                value
+                    .as_object_mut()
+                    .unwrap()
+                    .insert("@type".to_string(), Value::String(any.type_url));
+                value
+            }
+            ".google.protobuf.Duration" => {
+                let duration = prost_types::Duration::decode(bytes)?;
+                let nanos = duration.nanos.abs();
+                let mut text = duration.seconds.to_string();
+                if duration.seconds == 0 && duration.nanos < 0 {
+                    text.insert(0, '-');
+                }
+                if nanos != 0 {
+                    text.push_str(format!(".{:09}", nanos).trim_end_matches('0'));
+                }
+                Value::String(format!("{}s", text))
+            }
+            ".google.protobuf.Struct" => {
+                let fields = self.fields(name, bytes)?;
+                fields
+                    .get("fields")
+                    .cloned()
+                    .unwrap_or_else(|| Value::Object(Map::new()))
+            }
+            ".google.protobuf.ListValue" => {
+                let fields = self.fields(name, bytes)?;
+                fields
+                    .get("values")
+                    .cloned()
+                    .unwrap_or_else(|| Value::Array(Vec::new()))
+            }
+            ".google.protobuf.Value" => {
+                let fields = self.fields(name, bytes)?;
+                fields.values().next().cloned().unwrap_or(Value::Null)
+            }
+            name if WRAPPERS.contains(&name) => {
+                let fields = self.fields(name, bytes)?;
+                match fields.get("value") {
+                    Some(value) => value.clone(),
+                    // a default value, which is not encoded
+                    None => self.default_wrapped(name),
+                }
+            }
+            _ => return Ok(None),
+        };
+        Ok(Some(value))
+    }
+
+    // The message packed in an `Any` of `json`, which holds it under `value`
+    // when it has a representation of its own.
+    fn encode_any_value(&self, type_url: &str, json: &Value) -> Result<Vec<u8>> {
+        let name = type_name(type_url)?;
+        match json.get("value") {
+            Some(value) if is_well_known(&name) => self.encode_message(&name, value),
+            _ => self.encode_message(&name, json),
+        }
+    }
+
+    fn encode_message(&self, name: &str, json: &Value) -> Result<Vec<u8>> {
+        if let Some(bytes) = self.encode_well_known(name, json)? {
+            return Ok(bytes);
+        }
+        let object = json
+            .as_object()
+            .ok_or_else(|| anyhow!("{} is not an object but {}", name, json))?;
+        let descriptor = self.descriptor(name)?;
+        let mut bytes = Vec::new();
+        for (key, value) in object {
+            if key == "@type" {
+                continue;
+            }
+            let field = descriptor.field.iter().find(|field| {
+                field.name.as_deref() == Some(key) || field.json_name.as_deref() == Some(key)
+            });
+            let field = match field {
+                Some(field) => field,
+                None => {
+                    tracing::warn!("Leaving out the unknown field {} of {}", key, name);
+                    continue;
+                }
+            };
+            // null is the default of any field but of those of a `Value`
+            if value.is_null() && field.type_name.as_deref() != Some(".google.protobuf.Value") {
+                continue;
+            }
+            self.encode_field(field, value, &mut bytes)
+                .with_context(|| format!("invalid {}", key))?;
+        }
+        Ok(bytes)
+    }
+
+    fn encode_field(
+        &self,
+        field: &FieldDescriptorProto,
+        value: &Value,
+        bytes: &mut Vec<u8>,
+    ) -> Result<()> {
+        let number = u64::from(field.number.unwrap_or_default() as u32);
+        if let Some(entry) = self.map_entry(field) {
+            let object = value.as_object().context("a map is not an object")?;
+            let entry_field = |number| {
+                entry
+                    .field
+                    .iter()
+                    .find(|field| field.number == Some(number))
+            };
+            let key_field = entry_field(1).context("a map entry has no key")?;
+            let value_field = entry_field(2).context("a map entry has no value")?;
+            for (key, value) in object {
+                let mut entry = Vec::new();
+                self.encode_field(key_field, &map_key(key_field, key), &mut entry)?;
+                self.encode_field(value_field, value, &mut entry)?;
+                put_varint(bytes, number << 3 | 2);
+                put_length_delimited(bytes, &entry);
+            }
+            return Ok(());
+        }
+        if field.label == Some(Label::Repeated as i32) {
+            let values = value
+                .as_array()
+                .context("a repeated field is not an array")?;
+            for value in values {
+                self.encode_value(field, value, bytes)?;
+            }
+            return Ok(());
+        }
+        let mut encoded = Vec::new();
+        self.encode_value(field, value, &mut encoded)?;
+        // but for those of the oneofs, fields of their default are not
+        // encoded: a zero, an empty string, or fixed bytes all zeros
+        let key_length = varint_length(number << 3);
+        let default = match packed_wire_type(field) {
+            1 | 5 => encoded[key_length..].iter().all(|byte| *byte == 0),
+            _ => encoded[key_length..] == [0],
+        };
+        if field.oneof_index.is_some() || field_type(field)? == Type::Message || !default {
+            bytes.extend_from_slice(&encoded);
+        }
+        Ok(())
+    }
+
+    fn encode_value(
+        &self,
+        field: &FieldDescriptorProto,
+        value: &Value,
+        bytes: &mut Vec<u8>,
+    ) -> Result<()> {
+        let number = u64::from(field.number.unwrap_or_default() as u32);
+        let field_type = field_type(field)?;
+        let string = || {
+            value
+                .as_str()
+                .ok_or_else(|| anyhow!("{} is not a string", value))
+        };
+        match field_type {
+            Type::Message => {
+                let message =
+                    self.encode_message(field.type_name.as_deref().unwrap_or(""), value)?;
+                put_varint(bytes, number << 3 | 2);
+                put_length_delimited(bytes, &message);
+            }
+            Type::String => {
+                put_varint(bytes, number << 3 | 2);
+                put_length_delimited(bytes, string()?.as_bytes());
+            }
+            Type::Bytes => {
+                let decoded = BASE64
+                    .decode(string()?.as_bytes())
+                    .map_err(|e| anyhow!("{} is not base64: {}", value, e))?;
+                put_varint(bytes, number << 3 | 2);
+                put_length_delimited(bytes, &decoded);
+            }
+            Type::Group => bail!("groups are not supported"),
+            _ => {
+                put_varint(bytes, number << 3 | u64::from(packed_wire_type(field)));
+                self.encode_scalar(field, field_type, value, bytes)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn encode_scalar(
+        &self,
+        field: &FieldDescriptorProto,
+        field_type: Type,
+        value: &Value,
+        bytes: &mut Vec<u8>,
+    ) -> Result<()> {
+        match field_type {
+            Type::Double => bytes.extend_from_slice(&float(value)?.to_bits().to_le_bytes()),
+            Type::Float => bytes.extend_from_slice(&(float(value)? as f32).to_bits().to_le_bytes()),
+            Type::Int64 | Type::Int32 => put_varint(bytes, integer(value)? as i64 as u64),
+            Type::Uint64 | Type::Uint32 => put_varint(bytes, integer(value)? as u64),
+            Type::Sint64 | Type::Sint32 => {
+                let value = integer(value)? as i64;
+                put_varint(bytes, ((value << 1) ^ (value >> 63)) as u64);
+            }
+            Type::Fixed64 | Type::Sfixed64 => {
+                bytes.extend_from_slice(&(integer(value)? as u64).to_le_bytes())
+            }
+            Type::Fixed32 | Type::Sfixed32 => {
+                bytes.extend_from_slice(&(integer(value)? as u32).to_le_bytes())
+            }
+            Type::Bool => match value {
+                Value::Bool(value) => put_varint(bytes, *value as u64),
+                Value::String(value) if value == "true" || value == "false" => {
+                    put_varint(bytes, (value == "true") as u64)
+                }
+                _ => bail!("{} is not a bool", value),
+            },
+            Type::Enum => {
+                let number = self.enum_number(field.type_name.as_deref().unwrap_or(""), value)?;
+                put_varint(bytes, number as i64 as u64);
+            }
+            _ => bail!("{:?} is not a scalar", field_type),
+        }
+        Ok(())
+    }
+
+    fn enum_number(&self, name: &str, value: &Value) -> Result<i32> {
+        match value {
+            Value::Null if name == ".google.protobuf.NullValue" => Ok(0),
+            Value::String(value_name) => self
+                .enums
+                .get(name)
+                .and_then(|descriptor| {
+                    descriptor
+                        .value
+                        .iter()
+                        .find(|value| value.name.as_deref() == Some(value_name))
+                })
+                .and_then(|value| value.number)
+                .ok_or_else(|| anyhow!("{} is not a value of {}", value, name)),
+            _ => Ok(integer(value)? as i32),
+        }
+    }
+
+    // The encoding of the types with a JSON representation of their own.
+    fn encode_well_known(&self, name: &str, json: &Value) -> Result<Option<Vec<u8>>> {
+        let mut bytes = Vec::new();
+        match name {
+            ".google.protobuf.Any" => {
+                let type_url = json
+                    .get("@type")
+                    .and_then(Value::as_str)
+                    .ok_or_else(|| anyhow!("an Any has no @type"))?;
+                put_varint(&mut bytes, 1 << 3 | 2);
+                put_length_delimited(&mut bytes, type_url.as_bytes());
+                let value = self.encode_any_value(type_url, json)?;
+                if !value.is_empty() {
+                    put_varint(&mut bytes, 2 << 3 | 2);
+                    put_length_delimited(&mut bytes, &value);
+                }
+            }
+            ".google.protobuf.Duration" => {
+                let text = json
+                    .as_str()
+                    .and_then(|text| text.strip_suffix('s'))
+                    .ok_or_else(|| anyhow!("{} is not a duration", json))?;
+                let negative = text.starts_with('-');
+                let (seconds, fraction) = text.split_once('.').unwrap_or((text, ""));
+                let seconds: i64 = seconds
+                    .parse()
+                    .map_err(|_| anyhow!("{} is not a duration", json))?;
+                let mut nanos: i32 = match fraction.len() {
+                    0 => 0,
+                    1..=9 => format!("{:0<9}", fraction)
+                        .parse()
+                        .map_err(|_| anyhow!("{} is not a duration", json))?,
+                    _ => bail!("{} is more precise than nanoseconds", json),
+                };
+                if negative {
+                    nanos = -nanos;
+                }
+                if seconds != 0 {
+                    put_varint(&mut bytes, 1 << 3);
+                    put_varint(&mut bytes, seconds as u64);
+                }
+                if nanos != 0 {
+                    put_varint(&mut bytes, 2 << 3);
+                    put_varint(&mut bytes, nanos as i64 as u64);
+                }
+            }
+            ".google.protobuf.Struct" => {
+                let object = json
+                    .as_object()
+                    .ok_or_else(|| anyhow!("{} is not an object", json))?;
+                for (key, value) in object {
+                    let mut entry = Vec::new();
+                    put_varint(&mut entry, 1 << 3 | 2);
+                    put_length_delimited(&mut entry, key.as_bytes());
+                    put_varint(&mut entry, 2 << 3 | 2);
+                    put_length_delimited(
+                        &mut entry,
+                        &self.encode_message(".google.protobuf.Value", value)?,
+                    );
+                    put_varint(&mut bytes, 1 << 3 | 2);
+                    put_length_delimited(&mut bytes, &entry);
+                }
+            }
+            ".google.protobuf.ListValue" => {
+                let values = json
+                    .as_array()
+                    .ok_or_else(|| anyhow!("{} is not an array", json))?;
+                for value in values {
+                    put_varint(&mut bytes, 1 << 3 | 2);
+                    put_length_delimited(
+                        &mut bytes,
+                        &self.encode_message(".google.protobuf.Value", value)?,
+                    );
+                }
+            }
+            ".google.protobuf.Value" => match json {
+                Value::Null => {
+                    put_varint(&mut bytes, 1 << 3);
+                    put_varint(&mut bytes, 0);
+                }
+                Value::Number(_) => {
+                    put_varint(&mut bytes, 2 << 3 | 1);
+                    bytes.extend_from_slice(&float(json)?.to_bits().to_le_bytes());
+                }
+                Value::String(value) => {
+                    put_varint(&mut bytes, 3 << 3 | 2);
+                    put_length_delimited(&mut bytes, value.as_bytes());
+                }
+                Value::Bool(value) => {
+                    put_varint(&mut bytes, 4 << 3);
+                    put_varint(&mut bytes, *value as u64);
+                }
+                Value::Object(_) => {
+                    put_varint(&mut bytes, 5 << 3 | 2);
+                    let message = self.encode_message(".google.protobuf.Struct", json)?;
+                    put_length_delimited(&mut bytes, &message);
+                }
+                Value::Array(_) => {
+                    put_varint(&mut bytes, 6 << 3 | 2);
+                    let message = self.encode_message(".google.protobuf.ListValue", json)?;
+                    put_length_delimited(&mut bytes, &message);
+                }
+            },
+            name if WRAPPERS.contains(&name) => {
+                let descriptor = self.descriptor(name)?;
+                let field = descriptor
+                    .field
+                    .iter()
+                    .find(|field| field.number == Some(1))
+                    .ok_or_else(|| anyhow!("{} has no value", name))?;
+                self.encode_field(field, json, &mut bytes)?;
+            }
+            _ => return Ok(None),
+        }
+        Ok(Some(bytes))
+    }
+
+    fn default_wrapped(&self, name: &str) -> Value {
+        match name {
+            ".google.protobuf.BoolValue" => Value::Bool(false),
+            ".google.protobuf.StringValue" | ".google.protobuf.BytesValue" => {
+                Value::String(std::string::String::new())
+            }
+            ".google.protobuf.Int64Value" | ".google.protobuf.UInt64Value" => {
+                Value::String("0".to_string())
+            }
+            _ => Value::from(0),
+        }
+    }
+}
+
+const WRAPPERS: [&str; 9] = [
+    ".google.protobuf.DoubleValue",
+    ".google.protobuf.FloatValue",
+    ".google.protobuf.Int64Value",
+    ".google.protobuf.UInt64Value",
+    ".google.protobuf.Int32Value",
+    ".google.protobuf.UInt32Value",
+    ".google.protobuf.BoolValue",
+    ".google.protobuf.StringValue",
+    ".google.protobuf.BytesValue",
+];
+
+fn is_well_known(name: &str) -> bool {
+    WRAPPERS.contains(&name)
+        || [
+            ".google.protobuf.Any",
+            ".google.protobuf.Duration",
+            ".google.protobuf.Struct",
+            ".google.protobuf.ListValue",
+            ".google.protobuf.Value",
+        ]
+        .contains(&name)
+}
+
+fn type_name(type_url: &str) -> Result<std::string::String> {
+    let name = type_url
+        .strip_prefix(TYPE_URL_PREFIX)
+        .or_else(|| type_url.rsplit('/').next())
+        .filter(|name| !name.is_empty())
+        .ok_or_else(|| anyhow!("invalid type URL '{}'", type_url))?;
+    Ok(format!(".{}", name))
+}
+
+fn field_type(field: &FieldDescriptorProto) -> Result<Type> {
+    field
+        .r#type
+        .and_then(Type::from_i32)
+        .ok_or_else(|| anyhow!("field {:?} has no type", field.name))
+}
+
+fn is_packable(field: &FieldDescriptorProto) -> bool {
+    !matches!(
+        field_type(field),
+        Ok(Type::Message) | Ok(Type::String) | Ok(Type::Bytes) | Ok(Type::Group) | Err(_)
+    )
+}
+
+// Wire type of a single value of a scalar field.
+fn packed_wire_type(field: &FieldDescriptorProto) -> u8 {
+    match field_type(field) {
+        Ok(Type::Double) | Ok(Type::Fixed64) | Ok(Type::Sfixed64) => 1,
+        Ok(Type::Float) | Ok(Type::Fixed32) | Ok(Type::Sfixed32) => 5,
+        _ => 0,
+    }
+}
+
+fn map_entry(entry: &DescriptorProto, value: Value) -> Result<(std::string::String, Value)> {
+    let mut value = match value {
+        Value::Object(object) => object,
+        _ => bail!("invalid map entry of {:?}", entry.name),
+    };
+    let key = match value.remove("key") {
+        Some(Value::String(key)) => key,
+        Some(key) => key.to_string(),
+        None => std::string::String::new(),
+    };
+    Ok((key, value.remove("value").unwrap_or(Value::Null)))
+}
+
+fn push(fields: &mut Map<std::string::String, Value>, name: std::string::String, value: Value) {
+    let values = fields
+        .entry(name)
+        .or_insert_with(|| Value::Array(Vec::new()));
+    values.as_array_mut().unwrap().push(value);
+}
+
+fn varint(bytes: &mut &[u8]) -> Result<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (byte, rest) = bytes.split_first().context("truncated varint")?;
+        *bytes = rest;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    bail!("varint too long")
+}
+
+fn put_varint(bytes: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        bytes.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+fn varint_length(value: u64) -> usize {
+    let mut encoded = Vec::new();
+    put_varint(&mut encoded, value);
+    encoded.len()
+}
+
+fn put_length_delimited(bytes: &mut Vec<u8>, value: &[u8]) {
+    put_varint(bytes, value.len() as u64);
+    bytes.extend_from_slice(value);
+}
+
+// The keys of maps are strings in JSON whatever their type.
+fn map_key(field: &FieldDescriptorProto, key: &str) -> Value {
+    match field_type(field) {
+        Ok(Type::Bool) => Value::Bool(key == "true"),
+        _ => Value::String(key.to_string()),
+    }
+}
+
+// An integer of JSON, a number or, for 64 bits ones, a string.
+fn integer(value: &Value) -> Result<i128> {
+    let integer = match value {
+        Value::Number(number) => number
+            .as_i64()
+            .map(i128::from)
+            .or_else(|| number.as_u64().map(i128::from))
+            .or_else(|| {
+                number
+                    .as_f64()
+                    .filter(|float| float.fract() == 0.0)
+                    .map(|float| float as i128)
+            }),
+        Value::String(text) => text.parse().ok(),
+        _ => None,
+    };
+    integer.ok_or_else(|| anyhow!("{} is not an integer", value))
+}
+
+fn float(value: &Value) -> Result<f64> {
+    match value {
+        Value::Number(number) => number.as_f64(),
+        Value::String(text) => match text.as_str() {
+            "NaN" => Some(f64::NAN),
+            "Infinity" => Some(f64::INFINITY),
+            "-Infinity" => Some(f64::NEG_INFINITY),
+            text => text.parse().ok(),
+        },
+        _ => None,
+    }
+    .ok_or_else(|| anyhow!("{} is not a number", value))
+}
+
+// A float of JSON, the ones JSON has no number for being strings.
+fn float_value(value: f64) -> Value {
+    match value {
+        value if value.is_nan() => Value::from("NaN"),
+        value if value == f64::INFINITY => Value::from("Infinity"),
+        value if value == f64::NEG_INFINITY => Value::from("-Infinity"),
+        value => Value::from(value),
+    }
+}
+
+fn zigzag(value: u64) -> i64 {
+    ((value >> 1) as i64) ^ -((value & 1) as i64)
+}
+
+fn take<'a>(bytes: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+    if bytes.len() < len {
+        bail!("truncated field");
+    }
+    let (value, rest) = bytes.split_at(len);
+    *bytes = rest;
+    Ok(value)
+}
+
+fn length_delimited<'a>(bytes: &mut &'a [u8]) -> Result<&'a [u8]> {
+    let len = varint(bytes)? as usize;
+    take(bytes, len)
+}
+
+fn fixed64(bytes: &mut &[u8]) -> Result<u64> {
+    let mut value = [0; 8];
+    value.copy_from_slice(take(bytes, 8)?);
+    Ok(u64::from_le_bytes(value))
+}
+
+fn fixed32(bytes: &mut &[u8]) -> Result<u32> {
+    let mut value = [0; 4];
+    value.copy_from_slice(take(bytes, 4)?);
+    Ok(u32::from_le_bytes(value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::envoy_helpers::{get_envoy_cluster, json_to_struct};
+    use crate::protobuf::envoy::config::cluster::v3::Cluster;
+    use crate::type_urls::{TypeUrls, CLUSTER_TYPE_URL};
+
+    #[test]
+    fn resources_encode_with_their_proto_names() {
+        let registry = Registry::new().unwrap();
+        let cluster = get_envoy_cluster(
+            "backend".to_string(),
+            "https://backend:8443",
+            &Default::default(),
+        )
+        .unwrap();
+        let any = TypeUrls::default().pack(&cluster).unwrap();
+
+        let mut json = registry.any_to_json(&any).unwrap();
+        assert_eq!(json["type"], "LOGICAL_DNS");
+        assert_eq!(json["connect_timeout"], "1s");
+        assert_eq!(
+            json["load_assignment"]["endpoints"][0]["lb_endpoints"][0]["endpoint"]["address"]
+                ["socket_address"]["port_value"],
+            8443
+        );
+        assert_eq!(
+            json["transport_socket"]["typed_config"]["@type"],
+            "type.googleapis.com/envoy.extensions.transport_sockets.tls.v3.UpstreamTlsContext"
+        );
+
+        json["@type"] = any.type_url.clone().into();
+        let encoded = registry.json_to_any(&json).unwrap();
+        assert_eq!(encoded.type_url, any.type_url);
+        assert_eq!(Cluster::decode(&*encoded.value).unwrap(), cluster);
+    }
+
+    #[test]
+    fn well_known_types_have_a_representation_of_their_own() {
+        let registry = Registry::new().unwrap();
+        let config = serde_json::json!({"timeout": 5.0, "services": ["web"], "debug": null});
+        let any = TypeUrls::default()
+            .pack(&json_to_struct(config.clone()).unwrap())
+            .unwrap();
+        assert_eq!(registry.any_to_json(&any).unwrap(), config);
+
+        let json = serde_json::json!({
+            "@type": "type.googleapis.com/envoy.config.cluster.v3.Cluster",
+            "name": "backend",
+            "connectTimeout": "0.250s",
+            "perConnectionBufferLimitBytes": 1024,
+        });
+        let cluster = Cluster::decode(&*registry.json_to_any(&json).unwrap().value).unwrap();
+        assert_eq!(cluster.name, "backend");
+        assert_eq!(cluster.connect_timeout.unwrap().nanos, 250_000_000);
+        assert_eq!(cluster.per_connection_buffer_limit_bytes, Some(1024));
+    }
+
+    #[test]
+    fn scalars_round_trip() {
+        let registry = Registry::new().unwrap();
+        let values = [
+            ("DoubleValue", serde_json::json!(1.5)),
+            ("DoubleValue", serde_json::json!("-Infinity")),
+            ("FloatValue", serde_json::json!(0.25)),
+            ("Int64Value", serde_json::json!("-42")),
+            ("UInt64Value", serde_json::json!("18446744073709551615")),
+            ("Int32Value", serde_json::json!(-7)),
+            ("UInt32Value", serde_json::json!(4_000_000_000u32)),
+            ("BoolValue", serde_json::json!(true)),
+            ("StringValue", serde_json::json!("\u{0}")),
+            ("StringValue", serde_json::json!("")),
+            ("BytesValue", serde_json::json!("AAE=")),
+            ("Duration", serde_json::json!("-0.500s")),
+            ("Duration", serde_json::json!("3600s")),
+            ("Value", serde_json::json!(null)),
+            (
+                "ListValue",
+                serde_json::json!([1.0, "web", false, {"debug": null}]),
+            ),
+            ("Struct", serde_json::json!({"timeout": {"seconds": [5.0]}})),
+        ];
+        for (name, value) in &values {
+            let json = serde_json::json!({
+                "@type": format!("type.googleapis.com/google.protobuf.{}", name),
+                "value": value,
+            });
+            let any = registry.json_to_any(&json).unwrap();
+            let expected = match value.as_str() {
+                // durations are written with their significant digits only
+                Some("-0.500s") => serde_json::json!("-0.5s"),
+                _ => value.clone(),
+            };
+            assert_eq!(registry.any_to_json(&any).unwrap(), expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn messages_round_trip() {
+        let registry = Registry::new().unwrap();
+        let json = serde_json::json!({
+            "name": "backend",
+            "type": "EDS",
+            "connect_timeout": "0.25s",
+            "metadata": {"filter_metadata": {"3scale": {"services": ["web"]}}},
+            "transport_socket": {
+                "name": "envoy.transport_sockets.tls",
+                "typed_config": {
+                    "@type": "type.googleapis.com/envoy.extensions.transport_sockets.tls.v3.UpstreamTlsContext",
+                    "sni": "\u{0}",
+                },
+            },
+        });
+        let mut typed = json.clone();
+        typed["@type"] = CLUSTER_TYPE_URL.into();
+        let any = registry.json_to_any(&typed).unwrap();
+        assert_eq!(registry.any_to_json(&any).unwrap(), json);
+    }
+
+    #[test]
+    fn null_and_unknown_fields_are_left_out() {
+        let registry = Registry::new().unwrap();
+        let json = serde_json::json!({
+            "@type": CLUSTER_TYPE_URL,
+            "name": "backend",
+            "connect_timeout": null,
+            "lb_policy": null,
+            "a_field_of_a_newer_envoy": {"enabled": true},
+        });
+        let any = registry.json_to_any(&json).unwrap();
+        assert_eq!(
+            registry.any_to_json(&any).unwrap(),
+            serde_json::json!({"name": "backend"})
+        );
+    }
+
+    #[test]
+    fn unknown_types_are_errors() {
+        let registry = Registry::new().unwrap();
+        let any = prost_types::Any {
+            type_url: "type.googleapis.com/envoy.config.Unknown".to_string(),
+            value: Vec::new(),
+        };
+        assert_eq!(
+            registry.any_to_json(&any).unwrap_err().to_string(),
+            "no descriptor of .envoy.config.Unknown"
+        );
+        let error = registry.json_to_any(&serde_json::json!({})).unwrap_err();
+        assert_eq!(error.to_string(), "the message has no @type");
+    }
+}
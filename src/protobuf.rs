@@ -3,14 +3,36 @@
 
 #[path = "protobuf"]
 pub mod google {
-    #[path = "google.api.rs"]
-    pub mod api;
+    #[path = "."]
+    pub mod api {
+        include!("protobuf/google.api.rs");
+
+        #[path = "."]
+        pub mod expr {
+            #[path = "google.api.expr.v1alpha1.rs"]
+            pub mod v1alpha1;
+        }
+    }
     #[path = "google.protobuf.rs"]
     pub mod protobuf;
     #[path = "google.rpc.rs"]
     pub mod rpc;
 }
 
+#[path = "protobuf"]
+pub mod grpc {
+    #[path = "."]
+    pub mod health {
+        #[path = "grpc.health.v1.rs"]
+        pub mod v1;
+    }
+    #[path = "."]
+    pub mod reflection {
+        #[path = "grpc.reflection.v1alpha.rs"]
+        pub mod v1alpha;
+    }
+}
+
 #[path = "protobuf"]
 pub mod udpa {
     #[path = "udpa.annotations.rs"]
@@ -35,6 +57,15 @@ pub mod envoy {
             pub mod v3;
         }
 
+        #[path = "."]
+        pub mod common {
+            #[path = "."]
+            pub mod key_value {
+                #[path = "envoy.config.common.key_value.v3.rs"]
+                pub mod v3;
+            }
+        }
+
         #[path = "."]
         pub mod core {
             #[path = "envoy.config.core.v3.rs"]
@@ -59,6 +90,12 @@ pub mod envoy {
             pub mod v3;
         }
 
+        #[path = "."]
+        pub mod rbac {
+            #[path = "envoy.config.rbac.v3.rs"]
+            pub mod v3;
+        }
+
         #[path = "."]
         pub mod route {
             #[path = "envoy.config.route.v3.rs"]
@@ -75,9 +112,51 @@ pub mod envoy {
     #[path = "."]
     pub mod extensions {
 
+        #[path = "."]
+        pub mod clusters {
+
+            #[path = "."]
+            pub mod dynamic_forward_proxy {
+                #[path = "envoy.extensions.clusters.dynamic_forward_proxy.v3.rs"]
+                pub mod v3;
+            }
+        }
+
+        #[path = "."]
+        pub mod common {
+
+            #[path = "."]
+            pub mod dynamic_forward_proxy {
+                #[path = "envoy.extensions.common.dynamic_forward_proxy.v3.rs"]
+                pub mod v3;
+            }
+
+            #[path = "."]
+            pub mod ratelimit {
+                #[path = "envoy.extensions.common.ratelimit.v3.rs"]
+                pub mod v3;
+            }
+        }
+
+        #[path = "."]
+        pub mod request_id {
+
+            #[path = "."]
+            pub mod uuid {
+                #[path = "envoy.extensions.request_id.uuid.v3.rs"]
+                pub mod v3;
+            }
+        }
+
         #[path = "."]
         pub mod transport_sockets {
 
+            #[path = "."]
+            pub mod quic {
+                #[path = "envoy.extensions.transport_sockets.quic.v3.rs"]
+                pub mod v3;
+            }
+
             #[path = "."]
             pub mod tls {
                 #[path = "envoy.extensions.transport_sockets.tls.v3.rs"]
@@ -102,6 +181,15 @@ pub mod envoy {
                 }
             }
 
+            #[path = "."]
+            pub mod listener {
+                #[path = "."]
+                pub mod original_dst {
+                    #[path = "envoy.extensions.filters.listener.original_dst.v3.rs"]
+                    pub mod v3;
+                }
+            }
+
             #[path = "."]
             pub mod http {
                 #[path = "."]
@@ -121,6 +209,30 @@ pub mod envoy {
                     #[path = "envoy.extensions.filters.http.jwt_authn.v3.rs"]
                     pub mod v3;
                 }
+
+                #[path = "."]
+                pub mod local_ratelimit {
+                    #[path = "envoy.extensions.filters.http.local_ratelimit.v3.rs"]
+                    pub mod v3;
+                }
+
+                #[path = "."]
+                pub mod rbac {
+                    #[path = "envoy.extensions.filters.http.rbac.v3.rs"]
+                    pub mod v3;
+                }
+
+                #[path = "."]
+                pub mod dynamic_forward_proxy {
+                    #[path = "envoy.extensions.filters.http.dynamic_forward_proxy.v3.rs"]
+                    pub mod v3;
+                }
+
+                #[path = "."]
+                pub mod cors {
+                    #[path = "envoy.extensions.filters.http.cors.v3.rs"]
+                    pub mod v3;
+                }
             }
         }
     }
@@ -144,6 +256,12 @@ pub mod envoy {
             #[path = "envoy.service.listener.v3.rs"]
             pub mod v3;
         }
+
+        #[path = "."]
+        pub mod secret {
+            #[path = "envoy.service.secret.v3.rs"]
+            pub mod v3;
+        }
     }
 
     #[path = "."]
@@ -0,0 +1,363 @@
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex, RwLock, Weak};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+
+use crate::configuration::{self, Config};
+use crate::leader::{Leadership, Role};
+use crate::panics;
+use crate::util::concurrency;
+
+/// Counters of the publications.
+#[derive(Debug, Clone, Copy, Default, Serialize, PartialEq)]
+pub struct PublisherStats {
+    pub triggers: u64,
+    // triggers folded into a rebuild already pending
+    pub coalesced: u64,
+    pub rebuilds: u64,
+    // rebuilds a follower left to the leader
+    pub skipped: u64,
+}
+
+#[derive(Default)]
+struct Pending {
+    // the latest services to publish, whether the files the services
+    // refer to, or only their filters, should be read again, and whether
+    // the services that failed to export are due to be retried
+    services: Option<Config>,
+    reexport: bool,
+    filters: bool,
+    retry: bool,
+    // the spans of the triggers, for the rebuild to follow from them
+    triggers: Vec<tracing::Span>,
+    // tickets handed to the triggers, the last one a finished rebuild
+    // covered, and whether that rebuild published a new version or why it
+    // failed
+    requested: u64,
+    covered: u64,
+    updated: bool,
+    error: Option<std::string::String>,
+    stats: PublisherStats,
+}
+
+impl Pending {
+    fn is_empty(&self) -> bool {
+        self.services.is_none() && !self.reexport && !self.filters && !self.retry
+    }
+}
+
+struct Shared {
+    pending: Mutex<Pending>,
+    done: Condvar,
+    wake: Mutex<Sender<()>>,
+    leadership: Leadership,
+}
+
+/// Publishes the snapshots of the services sources. Triggers arriving
+/// within `window` of each other are coalesced into a single rebuild from
+/// the latest inputs, and a single rebuild runs at a time, so that Envoy
+/// doesn't warm every intermediate version.
+///
+/// Only the leader publishes. Followers keep the latest inputs pending, and
+/// publish them once elected.
+#[derive(Clone)]
+pub struct Publisher {
+    config: Arc<RwLock<Config>>,
+    shared: Arc<Shared>,
+}
+
+impl Publisher {
+    /// A publisher of a replica always leading.
+    #[cfg(test)]
+    pub fn new(config: Arc<RwLock<Config>>, window: Duration) -> Publisher {
+        Publisher::with_leadership(config, window, Leadership::default())
+    }
+
+    /// Publish to `config` from a background thread, which ends when the
+    /// last handle is dropped, for as long as the replica leads.
+    pub fn with_leadership(
+        config: Arc<RwLock<Config>>,
+        window: Duration,
+        leadership: Leadership,
+    ) -> Publisher {
+        let (wake, woken) = channel();
+        let shared = Arc::new(Shared {
+            pending: Mutex::new(Pending::default()),
+            done: Condvar::new(),
+            wake: Mutex::new(wake),
+            leadership,
+        });
+        // whatever is pending goes out on taking over
+        let elected = Arc::downgrade(&shared);
+        shared.leadership.on_change(move |role| {
+            if let (Role::Leader, Some(shared)) = (role, elected.upgrade()) {
+                let _ = shared.wake.lock().unwrap().send(());
+            }
+        });
+        let weak = Arc::downgrade(&shared);
+        let target = Arc::clone(&config);
+        concurrency::spawn(move || run(target, weak, woken, window));
+        Publisher { config, shared }
+    }
+
+    pub fn config(&self) -> &Arc<RwLock<Config>> {
+        &self.config
+    }
+
+    pub fn leadership(&self) -> &Leadership {
+        &self.shared.leadership
+    }
+
+    fn trigger(&self, update: impl FnOnce(&mut Pending)) -> u64 {
+        let mut pending = self.shared.pending.lock().unwrap();
+        if !pending.is_empty() {
+            pending.stats.coalesced += 1;
+        }
+        pending.stats.triggers += 1;
+        pending.triggers.push(tracing::Span::current());
+        update(&mut pending);
+        pending.requested += 1;
+        let ticket = pending.requested;
+        drop(pending);
+        // the thread only stops once every handle is gone
+        let _ = self.shared.wake.lock().unwrap().send(());
+        ticket
+    }
+
+    /// Schedule publishing `services`, replacing whatever services were
+    /// pending.
+    pub fn schedule(&self, services: Config) -> u64 {
+        self.trigger(|pending| pending.services = Some(services))
+    }
+
+    /// Schedule exporting the services being served again, see
+    /// `configuration::reexport`.
+    pub fn schedule_reexport(&self) -> u64 {
+        self.trigger(|pending| pending.reexport = true)
+    }
+
+    /// Schedule exporting the services whose filters changed again, see
+    /// `configuration::reexport_filters`.
+    pub fn schedule_filters(&self) -> u64 {
+        self.trigger(|pending| pending.filters = true)
+    }
+
+    /// Schedule exporting the services that failed to again, see
+    /// `configuration::retry_failed`.
+    pub fn schedule_retry(&self) -> u64 {
+        self.trigger(|pending| pending.retry = true)
+    }
+
+    // Wait for the rebuild covering `ticket`, returning whether it
+    // published a new version.
+    fn wait(&self, ticket: u64) -> Result<bool> {
+        let mut pending = self.shared.pending.lock().unwrap();
+        while pending.covered < ticket {
+            pending = self.shared.done.wait(pending).unwrap();
+        }
+        match pending.error {
+            Some(ref error) => Err(anyhow!("{}", error)),
+            None => Ok(pending.updated),
+        }
+    }
+
+    /// Publish `services`, or newer ones scheduled meanwhile, returning
+    /// whether a new version was published. Followers return right away
+    /// without publishing.
+    pub fn publish(&self, services: Config) -> Result<bool> {
+        let ticket = self.schedule(services);
+        self.wait(ticket)
+    }
+}
+
+fn rebuild(
+    config: &RwLock<Config>,
+    services: Option<Config>,
+    reexport: bool,
+    filters: bool,
+    retry: bool,
+) -> Result<bool> {
+    let mut result = Ok(false);
+    if let Some(services) = services {
+        result = configuration::publish(config, services);
+    }
+    // a new version has read the files already
+    if reexport && matches!(result, Ok(false)) {
+        result = configuration::reexport(config);
+    } else if filters && matches!(result, Ok(false)) {
+        result = configuration::reexport_filters(config);
+    } else if retry && matches!(result, Ok(false)) {
+        result = configuration::retry_failed(config);
+    }
+    result
+}
+
+fn run(config: Arc<RwLock<Config>>, shared: Weak<Shared>, woken: Receiver<()>, window: Duration) {
+    while woken.recv().is_ok() {
+        std::thread::sleep(window);
+        while woken.try_recv().is_ok() {}
+        let shared = match shared.upgrade() {
+            Some(shared) => shared,
+            None => break,
+        };
+        let (services, reexport, filters, retry, triggers, ticket) = {
+            let mut pending = shared.pending.lock().unwrap();
+            if pending.is_empty() {
+                continue;
+            }
+            if !shared.leadership.is_leader() {
+                pending.stats.skipped += 1;
+                let stats = pending.stats;
+                config.write().unwrap().set_publications(stats);
+                pending.triggers.clear();
+                pending.covered = pending.requested;
+                pending.updated = false;
+                pending.error = None;
+                shared.done.notify_all();
+                continue;
+            }
+            pending.stats.rebuilds += 1;
+            let stats = pending.stats;
+            config.write().unwrap().set_publications(stats);
+            let reexport = std::mem::take(&mut pending.reexport);
+            let filters = std::mem::take(&mut pending.filters);
+            let retry = std::mem::take(&mut pending.retry);
+            let triggers = std::mem::take(&mut pending.triggers);
+            (
+                pending.services.take(),
+                reexport,
+                filters,
+                retry,
+                triggers,
+                pending.requested,
+            )
+        };
+
+        let span = tracing::info_span!("rebuild", triggers = triggers.len());
+        for trigger in &triggers {
+            span.follows_from(trigger);
+        }
+        let _entered = span.enter();
+
+        // a panicking rebuild leaves the snapshot being served, and this
+        // thread, to the next one
+        let result = panics::catch("rebuild", || {
+            rebuild(&config, services, reexport, filters, retry)
+        })
+        .and_then(|result| result);
+        if config.is_poisoned() {
+            config.clear_poison();
+        }
+        match result {
+            Ok(true) => {
+                if let Err(e) = shared.leadership.persist(&config.read().unwrap()) {
+                    tracing::error!("Cannot persist the snapshot for the followers: {:#}", e);
+                }
+            }
+            Ok(false) => {}
+            Err(ref e) => tracing::error!("Publication failed: {:#}", e),
+        }
+
+        let mut pending = shared.pending.lock().unwrap();
+        pending.covered = ticket;
+        pending.updated = *result.as_ref().unwrap_or(&false);
+        pending.error = result.err().map(|e| format!("{:#}", e));
+        shared.done.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::WasmSettings;
+
+    fn services(ids: &[u32]) -> Config {
+        let services: Vec<_> = ids
+            .iter()
+            .map(|id| {
+                serde_json::json!({
+                    "id": id,
+                    "hosts": [format!("{}.app", id)],
+                    "policies": [],
+                    "target_domain": format!("http://{}.app:80", id),
+                    "proxy_rules": [],
+                })
+            })
+            .collect();
+        let content = serde_json::to_string(&services).unwrap();
+        Config::from_services(serde_json::from_str(&content).unwrap(), &content)
+    }
+
+    fn shared() -> Arc<RwLock<Config>> {
+        let mut config = Config::default();
+        config.set_wasm(WasmSettings {
+            skip_sha: true,
+            ..Default::default()
+        });
+        Arc::new(RwLock::new(config))
+    }
+
+    #[test]
+    fn bursts_of_triggers_publish_once() {
+        let config = shared();
+        let publisher = Publisher::new(Arc::clone(&config), Duration::from_millis(200));
+
+        for last in 1..5 {
+            let ids: Vec<_> = (1..=last).collect();
+            publisher.schedule(services(&ids));
+        }
+        publisher.schedule_reexport();
+        assert!(publisher.publish(services(&[1, 2, 3, 4, 5])).unwrap());
+
+        let config = config.read().unwrap();
+        assert_eq!(config.get_version(), 1);
+        assert_eq!(config.get_services().len(), 5);
+        assert_eq!(
+            config.publications(),
+            PublisherStats {
+                triggers: 6,
+                coalesced: 5,
+                rebuilds: 1,
+                skipped: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn followers_stop_publishing_until_elected() {
+        let config = shared();
+        let leadership = Leadership::default();
+        let publisher = Publisher::with_leadership(
+            Arc::clone(&config),
+            Duration::from_millis(0),
+            leadership.clone(),
+        );
+        assert!(publisher.publish(services(&[1])).unwrap());
+
+        leadership.set(Role::Follower);
+        assert!(!publisher.publish(services(&[1, 2])).unwrap());
+        publisher.schedule(services(&[1, 2, 3]));
+        std::thread::sleep(Duration::from_millis(100));
+        {
+            let config = config.read().unwrap();
+            assert_eq!(config.get_version(), 1);
+            assert_eq!(config.get_services().len(), 1);
+            assert_eq!(config.publications().rebuilds, 1);
+            assert!(config.publications().skipped > 0);
+        }
+
+        // the latest services go out on taking over
+        leadership.set(Role::Leader);
+        for _ in 0..100 {
+            if config.read().unwrap().get_version() > 1 {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let config = config.read().unwrap();
+        assert_eq!(config.get_version(), 2);
+        assert_eq!(config.get_services().len(), 3);
+    }
+}
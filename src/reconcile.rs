@@ -0,0 +1,181 @@
+use std::time::{Duration, Instant};
+
+use crate::publisher::Publisher;
+
+// How often the services that failed to export are checked for a retry
+// being due.
+const TICK: Duration = Duration::from_secs(1);
+// How long a service that failed waits before the first retry, doubled on
+// every retry failing up to the last.
+const FIRST_RETRY: Duration = Duration::from_secs(1);
+const LAST_RETRY: Duration = Duration::from_secs(60);
+
+/// How long to wait before exporting again a service that failed to
+/// `retries` times in a row since its config was published.
+pub fn backoff(retries: u32) -> Duration {
+    FIRST_RETRY
+        .checked_mul(1 << retries.min(16))
+        .map_or(LAST_RETRY, |wait| wait.min(LAST_RETRY))
+}
+
+async fn reconcile(publisher: Publisher, tick: Duration) {
+    loop {
+        tokio::time::delay_for(tick).await;
+        // followers serve the snapshot of the leader
+        if !publisher.leadership().is_leader() {
+            continue;
+        }
+        if publisher.config().read().unwrap().retry_due(Instant::now()) {
+            tracing::info!("Exporting the services that failed to again");
+            publisher.schedule_retry();
+        }
+    }
+}
+
+/// Export again, on a backoff, the services left out of the snapshot for
+/// failing to export, like those of an OIDC issuer that was down, for them
+/// to join the snapshot once they export, or, when strict, the config they
+/// made refused as a whole. A new config starts them over. Only the leader
+/// retries.
+pub fn spawn_reconciler(publisher: Publisher) {
+    tokio::spawn(reconcile(publisher, TICK));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configuration::Config;
+    use crate::envoy_helpers::LISTENER_TYPE_URL;
+    use crate::leader::Role;
+    use crate::oidc::serve_issuer_on;
+    use crate::service::WasmSettings;
+    use std::sync::{Arc, RwLock};
+
+    #[test]
+    fn backoff_doubles_up_to_the_last_retry() {
+        assert_eq!(backoff(0), FIRST_RETRY);
+        assert_eq!(backoff(1), FIRST_RETRY * 2);
+        assert_eq!(backoff(3), FIRST_RETRY * 8);
+        assert_eq!(backoff(10), LAST_RETRY);
+        assert_eq!(backoff(u32::MAX), LAST_RETRY);
+    }
+
+    fn services(issuer: &str) -> Config {
+        let content = serde_json::json!([
+            {"id": 1, "hosts": ["one"], "policies": [], "target_domain": "http://one:80", "proxy_rules": []},
+            {"id": 2, "hosts": ["two"], "policies": [], "target_domain": "http://two:80", "proxy_rules": [],
+             "oidc_issuer": issuer},
+        ])
+        .to_string();
+        Config::from_services(serde_json::from_str(&content).unwrap(), &content)
+    }
+
+    fn listeners(config: &RwLock<Config>) -> usize {
+        config
+            .read()
+            .unwrap()
+            .get_snapshot()
+            .resources(LISTENER_TYPE_URL)
+            .map_or(0, |listeners| listeners.len())
+    }
+
+    // Wait for `done` to hold, failing the test after a while.
+    async fn until(what: &str, done: impl Fn() -> bool) {
+        let waiting = async {
+            while !done() {
+                tokio::time::delay_for(Duration::from_millis(50)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(10), waiting)
+            .await
+            .unwrap_or_else(|_| panic!("timed out waiting for {}", what));
+    }
+
+    #[tokio::test]
+    async fn services_join_once_their_issuer_recovers() {
+        // the port of an issuer that is down, refusing connections
+        let down = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = down.local_addr().unwrap();
+        drop(down);
+        let issuer = format!("http://{}", address);
+
+        let mut config = Config::default();
+        config.set_wasm(WasmSettings {
+            skip_sha: true,
+            ..Default::default()
+        });
+        let shared = Arc::new(RwLock::new(config));
+        let publisher = Publisher::new(Arc::clone(&shared), Duration::from_millis(10));
+        let (changed, publishing) = (services(&issuer), publisher.clone());
+        tokio::task::spawn_blocking(move || publishing.publish(changed))
+            .await
+            .unwrap()
+            .unwrap();
+        {
+            let config = shared.read().unwrap();
+            let failures = config.export_failures();
+            assert_eq!(failures.len(), 1);
+            assert_eq!((failures[0].id, failures[0].retries), (2, 0));
+        }
+        assert_eq!(listeners(&shared), 1);
+        let version = shared.read().unwrap().get_version();
+
+        // followers leave the retries to the leader
+        publisher.leadership().set(Role::Follower);
+        tokio::spawn(reconcile(publisher.clone(), Duration::from_millis(50)));
+        tokio::time::delay_for(Duration::from_millis(300)).await;
+        assert_eq!(shared.read().unwrap().publications().skipped, 0);
+        assert_eq!(shared.read().unwrap().export_failures()[0].retries, 0);
+
+        publisher.leadership().set(Role::Leader);
+        // still down on the first retry
+        until("the first retry", || {
+            shared.read().unwrap().export_failures()[0].retries > 0
+        })
+        .await;
+        assert_eq!(shared.read().unwrap().get_version(), version);
+
+        serve_issuer_on(std::net::TcpListener::bind(address).unwrap());
+        until("the service to export", || {
+            shared.read().unwrap().export_failures().is_empty()
+        })
+        .await;
+        assert_eq!(listeners(&shared), 2);
+        assert_eq!(shared.read().unwrap().get_version(), version + 1);
+    }
+
+    #[tokio::test]
+    async fn strict_configs_are_published_once_their_issuer_recovers() {
+        let down = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = down.local_addr().unwrap();
+        drop(down);
+        let issuer = format!("http://{}", address);
+
+        let mut config = Config::default();
+        config.set_wasm(WasmSettings {
+            skip_sha: true,
+            ..Default::default()
+        });
+        config.set_strict(true);
+        let shared = Arc::new(RwLock::new(config));
+        let publisher = Publisher::new(Arc::clone(&shared), Duration::from_millis(10));
+        let (changed, publishing) = (services(&issuer), publisher.clone());
+        tokio::task::spawn_blocking(move || publishing.publish(changed))
+            .await
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(listeners(&shared), 0);
+
+        tokio::spawn(reconcile(publisher.clone(), Duration::from_millis(50)));
+        // refused again, a retry later
+        until("the first retry", || {
+            shared.read().unwrap().export_failures()[0].retries > 0
+        })
+        .await;
+        assert_eq!(listeners(&shared), 0);
+
+        serve_issuer_on(std::net::TcpListener::bind(address).unwrap());
+        until("the config to be published", || listeners(&shared) == 2).await;
+        assert!(shared.read().unwrap().export_failures().is_empty());
+    }
+}
@@ -0,0 +1,146 @@
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+
+use anyhow::Result;
+
+use crate::configuration;
+use crate::panics;
+
+/// What an on demand reload did.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome {
+    /// A new snapshot version was published.
+    Published(u32),
+    /// The services are the ones being served.
+    Unchanged,
+    /// The services could not be loaded, with the chain of errors why. The
+    /// current snapshot keeps being served.
+    Rejected(Vec<std::string::String>),
+    /// The services source only reloads on its own.
+    Unsupported,
+}
+
+type Reload = Box<dyn Fn() -> Outcome + Send + Sync>;
+
+#[derive(Default)]
+struct State {
+    running: bool,
+    // bumped at the end of every reload, for those waiting on it
+    generation: u64,
+    last: Option<Outcome>,
+}
+
+#[derive(Default)]
+struct Shared {
+    reload: RwLock<Option<Reload>>,
+    state: Mutex<State>,
+    done: Condvar,
+}
+
+/// Reloads the services on demand. Requests arriving while a reload is
+/// running wait for that one and share its outcome rather than queuing
+/// another.
+#[derive(Clone, Default)]
+pub struct Reloader {
+    shared: Arc<Shared>,
+}
+
+impl Reloader {
+    /// Reload with `reload`, returning whether a new version was published
+    /// to `config`, as the file watcher and the Porta poller do.
+    pub fn install(
+        &self,
+        config: Arc<RwLock<configuration::Config>>,
+        reload: impl Fn() -> Result<bool> + Send + Sync + 'static,
+    ) {
+        // a panic of the source fails the reload only
+        let reload = move || match panics::catch("reload", &reload).and_then(|result| result) {
+            Ok(true) => Outcome::Published(config.read().unwrap().get_version()),
+            Ok(false) => Outcome::Unchanged,
+            Err(e) => {
+                tracing::error!(
+                    version = config.read().unwrap().get_version(),
+                    "Requested reload failed, still serving the current version: {:#}",
+                    e
+                );
+                configuration::reload_failed(&config, &e);
+                Outcome::Rejected(e.chain().map(|cause| cause.to_string()).collect())
+            }
+        };
+        *self.shared.reload.write().unwrap() = Some(Box::new(reload));
+    }
+
+    /// Reload now, or wait for the reload already running. Blocks.
+    pub fn reload(&self) -> Outcome {
+        let mut state = self.shared.state.lock().unwrap();
+        if state.running {
+            let generation = state.generation;
+            while state.generation == generation {
+                state = self.shared.done.wait(state).unwrap();
+            }
+            return state.last.clone().unwrap();
+        }
+        state.running = true;
+        drop(state);
+
+        let outcome = match *self.shared.reload.read().unwrap() {
+            Some(ref reload) => reload(),
+            None => Outcome::Unsupported,
+        };
+
+        let mut state = self.shared.state.lock().unwrap();
+        state.running = false;
+        state.generation += 1;
+        state.last = Some(outcome.clone());
+        self.shared.done.notify_all();
+        outcome
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    #[test]
+    fn concurrent_reloads_share_the_running_one() {
+        let config = Arc::new(RwLock::new(configuration::Config::default()));
+        let reloader = Reloader::default();
+        assert_eq!(reloader.reload(), Outcome::Unsupported);
+
+        let runs = Arc::new(AtomicUsize::new(0));
+        let (started_tx, started) = mpsc::channel();
+        let started_tx = Mutex::new(started_tx);
+        let (release, release_rx) = mpsc::channel::<()>();
+        let release_rx = Mutex::new(release_rx);
+        let counted = Arc::clone(&runs);
+        reloader.install(config, move || {
+            counted.fetch_add(1, Ordering::SeqCst);
+            let _ = started_tx.lock().unwrap().send(());
+            let _ = release_rx.lock().unwrap().recv();
+            Ok(false)
+        });
+
+        let first = {
+            let reloader = reloader.clone();
+            std::thread::spawn(move || reloader.reload())
+        };
+        started.recv().unwrap();
+        let waiting: Vec<_> = (0..3)
+            .map(|_| {
+                let reloader = reloader.clone();
+                std::thread::spawn(move || reloader.reload())
+            })
+            .collect();
+        // let them reach the wait
+        std::thread::sleep(Duration::from_millis(100));
+        drop(release);
+
+        assert_eq!(first.join().unwrap(), Outcome::Unchanged);
+        for waiting in waiting {
+            assert_eq!(waiting.join().unwrap(), Outcome::Unchanged);
+        }
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+    }
+}
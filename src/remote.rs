@@ -0,0 +1,447 @@
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+
+use crate::configuration::{self, ParseOptions, ServicesFormat};
+use crate::http_client::HttpClient;
+use crate::publisher::Publisher;
+
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(30);
+/// The longest poll interval, polling less often than daily being no
+/// polling at all.
+pub const MAX_POLL_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+// Failing fetches are retried less and less often, up to this long apart
+// unless the poll interval is longer.
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// Services source polling a services document served over HTTP(S).
+#[derive(Debug, Clone)]
+pub struct HttpSource {
+    url: url::Url,
+    options: ParseOptions,
+    poll_interval: Duration,
+    token: Option<std::string::String>,
+    tls: TlsOptions,
+    client: HttpClient,
+    // how long fetches may fail before the replica is degraded, right away
+    // when unset
+    max_staleness: Option<Duration>,
+    // shared with the reloads on demand
+    state: Arc<Mutex<State>>,
+}
+
+/// Where the services document is fetched from and how often, as given on
+/// the command line.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HttpSourceSettings {
+    pub url: url::Url,
+    // at least a second, and at most `MAX_POLL_INTERVAL`
+    pub poll_interval: Duration,
+    pub max_staleness: Option<Duration>,
+    // sent as a bearer token
+    pub token: Option<std::string::String>,
+    pub tls: TlsOptions,
+}
+
+/// Files of the TLS settings of the fetches, the system CAs verifying the
+/// server unless given.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TlsOptions {
+    pub ca_file: Option<PathBuf>,
+    pub cert_file: Option<PathBuf>,
+    pub key_file: Option<PathBuf>,
+}
+
+#[derive(Debug)]
+struct State {
+    // validators of the document last published
+    etag: Option<std::string::String>,
+    last_modified: Option<std::string::String>,
+    // since the source started, until a fetch succeeds
+    last_success: Instant,
+    failures: u32,
+}
+
+/// What a fetch found.
+#[derive(Debug, PartialEq)]
+pub enum Fetched {
+    /// The document published last, which is not read again.
+    Unchanged,
+    Changed(Document),
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Document {
+    pub content: std::string::String,
+    pub content_type: Option<std::string::String>,
+    pub etag: Option<std::string::String>,
+    pub last_modified: Option<std::string::String>,
+}
+
+impl HttpSource {
+    pub fn new(url: url::Url, options: ParseOptions) -> HttpSource {
+        HttpSource {
+            url,
+            options,
+            poll_interval: DEFAULT_POLL_INTERVAL,
+            token: None,
+            tls: TlsOptions::default(),
+            client: HttpClient::default(),
+            max_staleness: None,
+            state: Arc::new(Mutex::new(State {
+                etag: None,
+                last_modified: None,
+                last_success: Instant::now(),
+                failures: 0,
+            })),
+        }
+    }
+
+    /// A source of `settings`, overriding the TLS settings of `client` the
+    /// requests go through.
+    pub fn from_settings(
+        settings: &HttpSourceSettings,
+        options: ParseOptions,
+        client: &HttpClient,
+    ) -> HttpSource {
+        let mut source = HttpSource::new(settings.url.clone(), options);
+        source.client = client.clone();
+        source.poll_interval = settings.poll_interval;
+        source.max_staleness = settings.max_staleness;
+        source.token = settings.token.clone();
+        source.tls = settings.tls.clone();
+        source
+    }
+
+    /// Fetch the document, conditionally on it having changed since the one
+    /// published last.
+    #[tracing::instrument(skip(self), fields(url = %self.url))]
+    pub fn fetch(&self) -> Result<Fetched> {
+        let mut headers = self.client.headers()?;
+        {
+            let state = self.state.lock().unwrap();
+            if let Some(ref etag) = state.etag {
+                headers.append(&format!("If-None-Match: {}", etag))?;
+            }
+            if let Some(ref last_modified) = state.last_modified {
+                headers.append(&format!("If-Modified-Since: {}", last_modified))?;
+            }
+        }
+        if let Some(ref token) = self.token {
+            headers.append(&format!("Authorization: Bearer {}", token))?;
+        }
+
+        let mut easy = self.client.easy(&self.url)?;
+        easy.http_headers(headers)?;
+        if let Some(ref ca_file) = self.tls.ca_file {
+            easy.cainfo(ca_file)?;
+        }
+        if let (Some(cert_file), Some(key_file)) = (&self.tls.cert_file, &self.tls.key_file) {
+            easy.ssl_cert(cert_file)?;
+            easy.ssl_key(key_file)?;
+        }
+
+        let mut body = Vec::new();
+        let mut response_headers = Vec::new();
+        {
+            let mut transfer = easy.transfer();
+            transfer.write_function(|data| {
+                body.extend_from_slice(data);
+                Ok(data.len())
+            })?;
+            transfer.header_function(|line| {
+                if let Ok(line) = std::str::from_utf8(line) {
+                    if let Some((name, value)) = line.split_once(':') {
+                        response_headers
+                            .push((name.trim().to_lowercase(), value.trim().to_string()));
+                    }
+                }
+                true
+            })?;
+            transfer
+                .perform()
+                .with_context(|| format!("GET {} failed", self.url))?;
+        }
+        let header = |name: &str| {
+            response_headers
+                .iter()
+                .rev()
+                .find(|(header, _)| header == name)
+                .map(|(_, value)| value.clone())
+        };
+        match easy.response_code()? {
+            200 => Ok(Fetched::Changed(Document {
+                content: std::string::String::from_utf8(body)
+                    .context("the services document is not UTF-8")?,
+                content_type: header("content-type"),
+                etag: header("etag"),
+                last_modified: header("last-modified"),
+            })),
+            304 => Ok(Fetched::Unchanged),
+            status => bail!("GET {} returned status {}", self.url, status),
+        }
+    }
+
+    // Format of the document, when not configured: YAML when served as
+    // such, or else the one of the extension of the URL.
+    fn format(&self, document: &Document) -> ServicesFormat {
+        if let Some(format) = self.options.format {
+            return format;
+        }
+        match document.content_type {
+            Some(ref content_type) if content_type.contains("yaml") => ServicesFormat::Yaml,
+            _ => ServicesFormat::of(Path::new(self.url.path())),
+        }
+    }
+
+    /// Fetch once, returning whether a new version was published. An
+    /// unchanged document is not read again.
+    #[tracing::instrument(skip(self, publisher), fields(url = %self.url))]
+    pub fn reload(&self, publisher: &Publisher) -> Result<bool> {
+        let document = match self.fetched()? {
+            Some(Fetched::Changed(document)) => document,
+            Some(Fetched::Unchanged) => {
+                configuration::reload_recovered(publisher.config());
+                return Ok(false);
+            }
+            None => return Ok(false),
+        };
+        let format = self.format(&document);
+        let config = configuration::Config::parse_content(&document.content, format, self.options)?;
+        let updated = publisher.publish(config)?;
+        // only a document published is known not to be read again
+        let mut state = self.state.lock().unwrap();
+        state.etag = document.etag;
+        state.last_modified = document.last_modified;
+        Ok(updated)
+    }
+
+    // Fetch, keeping track of the failures. Those failing for less than the
+    // staleness allowed are only warned about, and fetch nothing.
+    fn fetched(&self) -> Result<Option<Fetched>> {
+        let error = match self.fetch() {
+            Ok(fetched) => {
+                let mut state = self.state.lock().unwrap();
+                state.last_success = Instant::now();
+                state.failures = 0;
+                return Ok(Some(fetched));
+            }
+            Err(error) => error,
+        };
+        let stale = {
+            let mut state = self.state.lock().unwrap();
+            state.failures += 1;
+            state.last_success.elapsed()
+        };
+        match self.max_staleness {
+            Some(max_staleness) if stale <= max_staleness => {
+                tracing::warn!("Cannot fetch the services: {:#}", error);
+                Ok(None)
+            }
+            Some(_) => Err(error.context(format!("no services fetched for {}s", stale.as_secs()))),
+            None => Err(error),
+        }
+    }
+
+    fn reload_logged(&self, publisher: &Publisher) {
+        if let Err(e) = self.reload(publisher) {
+            let config = publisher.config();
+            tracing::error!(
+                version = config.read().unwrap().get_version(),
+                "Fetching {} failed, still serving the current version: {:#}",
+                self.url,
+                e
+            );
+            configuration::reload_failed(config, &e);
+        }
+    }
+
+    // How long to wait for the next fetch, doubling with each failure.
+    fn delay(&self) -> Duration {
+        let failures = self.state.lock().unwrap().failures.min(16);
+        let backoff = self.poll_interval.saturating_mul(2u32.pow(failures));
+        backoff.min(MAX_BACKOFF.max(self.poll_interval))
+    }
+
+    /// Fetch once and keep polling in the background, for as long as the
+    /// replica leads.
+    pub fn spawn(self, publisher: Publisher) {
+        if publisher.leadership().is_leader() {
+            self.reload_logged(&publisher);
+        }
+        std::thread::spawn(move || loop {
+            std::thread::sleep(self.delay());
+            if publisher.leadership().is_leader() {
+                self.reload_logged(&publisher);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http_harness::{Response, Server};
+    use std::sync::RwLock;
+
+    const SERVICES: &str = r#"[{"id": 1, "hosts": ["one"], "policies": [], "target_domain": "http://one:80", "proxy_rules": []}]"#;
+
+    // Answers with the responses queued, 404 once they run out, and keeps
+    // the headers of the requests around.
+    struct MockServer {
+        url: url::Url,
+        server: Server,
+        responses: Arc<Mutex<Vec<(u32, std::string::String)>>>,
+    }
+
+    impl MockServer {
+        fn start() -> MockServer {
+            let responses = Arc::new(Mutex::new(Vec::new()));
+            let queued = Arc::clone(&responses);
+            let server = Server::start(move |_| {
+                let mut responses = queued.lock().unwrap();
+                let (status, body) = match responses.len() {
+                    0 => (404, std::string::String::new()),
+                    _ => responses.remove(0),
+                };
+                let etag = format!("\"v{}\"", body.len());
+                Response::status(status, body).header("ETag", etag)
+            });
+            MockServer {
+                url: url::Url::parse(&format!("{}/services.json", server.url())).unwrap(),
+                server,
+                responses,
+            }
+        }
+
+        fn respond(&self, status: u32, body: &str) {
+            self.responses
+                .lock()
+                .unwrap()
+                .push((status, body.to_string()));
+        }
+
+        // headers of the last request
+        fn headers(&self) -> Vec<std::string::String> {
+            self.server.requests().pop().unwrap().headers
+        }
+
+        fn source(&self) -> HttpSource {
+            HttpSource::new(self.url.clone(), ParseOptions::default())
+        }
+    }
+
+    fn publisher() -> Publisher {
+        let config = Arc::new(RwLock::new(configuration::Config::default()));
+        Publisher::new(config, Duration::from_millis(0))
+    }
+
+    fn version(publisher: &Publisher) -> u32 {
+        publisher.config().read().unwrap().get_version()
+    }
+
+    #[test]
+    fn unchanged_documents_are_not_read_again() {
+        let server = MockServer::start();
+        let source = server.source();
+        let publisher = publisher();
+
+        server.respond(200, SERVICES);
+        assert!(source.reload(&publisher).unwrap());
+        assert_eq!(version(&publisher), 1);
+        assert!(!server
+            .headers()
+            .iter()
+            .any(|h| h.starts_with("If-None-Match")));
+
+        // the document just published is validated by its ETag
+        server.respond(304, "");
+        assert!(!source.reload(&publisher).unwrap());
+        let etag = format!("If-None-Match: \"v{}\"", SERVICES.len());
+        assert!(server.headers().contains(&etag), "{:?}", server.headers());
+        assert_eq!(version(&publisher), 1);
+
+        // a document of its own is read
+        server.respond(200, "[]");
+        source.reload(&publisher).unwrap();
+        assert!(publisher.config().read().unwrap().get_services().is_empty());
+    }
+
+    #[test]
+    fn failures_keep_the_services_and_back_off() {
+        let server = MockServer::start();
+        let mut source = server.source();
+        source.poll_interval = Duration::from_secs(10);
+        let publisher = publisher();
+
+        server.respond(200, SERVICES);
+        source.reload(&publisher).unwrap();
+        assert_eq!(source.delay(), Duration::from_secs(10));
+
+        server.respond(500, "");
+        let error = source.reload(&publisher).unwrap_err();
+        assert!(
+            error.to_string().contains("returned status 500"),
+            "{}",
+            error
+        );
+        server.respond(500, "");
+        assert!(source.reload(&publisher).is_err());
+        assert_eq!(source.delay(), Duration::from_secs(40));
+        assert_eq!(version(&publisher), 1);
+        assert_eq!(publisher.config().read().unwrap().get_services().len(), 1);
+
+        // failing for longer than allowed degrades the replica
+        source.max_staleness = Some(Duration::from_secs(60));
+        server.respond(500, "");
+        assert!(!source.reload(&publisher).unwrap());
+        source.max_staleness = Some(Duration::from_secs(0));
+        server.respond(500, "");
+        let error = source.reload(&publisher).unwrap_err();
+        assert!(
+            error.to_string().starts_with("no services fetched for"),
+            "{}",
+            error
+        );
+
+        server.respond(304, "");
+        source.reload(&publisher).unwrap();
+        assert_eq!(source.delay(), Duration::from_secs(10));
+
+        // invalid documents fail whatever the staleness allowed
+        server.respond(200, "{");
+        assert!(source.reload(&publisher).is_err());
+    }
+
+    #[test]
+    fn the_longest_intervals_back_off_without_overflowing() {
+        let server = MockServer::start();
+        let mut source = server.source();
+        source.poll_interval = MAX_POLL_INTERVAL;
+        source.state.lock().unwrap().failures = 16;
+        assert_eq!(source.delay(), MAX_POLL_INTERVAL);
+    }
+
+    #[test]
+    fn tokens_are_sent_as_bearer_tokens() {
+        let server = MockServer::start();
+        let mut source = server.source();
+        let publisher = publisher();
+
+        server.respond(200, SERVICES);
+        source.reload(&publisher).unwrap();
+        assert!(!server
+            .headers()
+            .iter()
+            .any(|h| h.starts_with("Authorization")));
+
+        source.token = Some("secret".to_string());
+        server.respond(304, "");
+        source.reload(&publisher).unwrap();
+        assert!(server
+            .headers()
+            .contains(&"Authorization: Bearer secret".to_string()));
+    }
+}
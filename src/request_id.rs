@@ -0,0 +1,67 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::protobuf::envoy::extensions::filters::network::http_connection_manager::v3::{
+    HttpConnectionManager, RequestIdExtension,
+};
+use crate::protobuf::envoy::extensions::request_id::uuid::v3::UuidRequestIdConfig;
+use crate::type_urls::TypeUrls;
+
+/// How the listener of a service handles the `x-request-id` header. Unset,
+/// Envoy generates the id when missing, replaces one coming from outside
+/// and leaves it out of the responses.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Hash)]
+#[serde(deny_unknown_fields)]
+pub struct RequestId {
+    #[serde(default)]
+    pub generate_request_id: Option<bool>,
+    // keep the id of requests of trusted hops, like a load balancer in
+    // front of Envoy
+    #[serde(default)]
+    pub preserve_external_request_id: Option<bool>,
+    // for the id to be handed to support along with the response
+    #[serde(default)]
+    pub always_set_request_id_in_response: Option<bool>,
+    // configure the UUID extension by name rather than have Envoy pick
+    // its default one
+    #[serde(default)]
+    pub uuid_extension: Option<bool>,
+    // tell the id in the bodies of the requests the wasm filter denies
+    #[serde(default)]
+    pub in_filter_denials: Option<bool>,
+}
+
+impl RequestId {
+    /// These settings, falling back to `defaults` for those not set.
+    pub fn or(self, defaults: RequestId) -> RequestId {
+        RequestId {
+            generate_request_id: self.generate_request_id.or(defaults.generate_request_id),
+            preserve_external_request_id: self
+                .preserve_external_request_id
+                .or(defaults.preserve_external_request_id),
+            always_set_request_id_in_response: self
+                .always_set_request_id_in_response
+                .or(defaults.always_set_request_id_in_response),
+            uuid_extension: self.uuid_extension.or(defaults.uuid_extension),
+            in_filter_denials: self.in_filter_denials.or(defaults.in_filter_denials),
+        }
+    }
+
+    pub fn apply(
+        &self,
+        connection_manager: &mut HttpConnectionManager,
+        type_urls: &TypeUrls,
+    ) -> Result<()> {
+        connection_manager.generate_request_id = self.generate_request_id;
+        connection_manager.preserve_external_request_id =
+            self.preserve_external_request_id.unwrap_or(false);
+        connection_manager.always_set_request_id_in_response =
+            self.always_set_request_id_in_response.unwrap_or(false);
+        if self.uuid_extension == Some(true) {
+            connection_manager.request_id_extension = Some(RequestIdExtension {
+                typed_config: Some(type_urls.pack(&UuidRequestIdConfig::default())?),
+            });
+        }
+        Ok(())
+    }
+}
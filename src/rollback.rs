@@ -0,0 +1,327 @@
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::sync::Arc;
+
+use serde::Serialize;
+
+use crate::envoy_helpers::SharedExports;
+use crate::node_status::Nack;
+use crate::snapshot::{Resources, Snapshot};
+
+/// Envoy resources of every service, by service id.
+pub type ServiceExports = BTreeMap<u32, SharedExports>;
+
+// Published snapshots kept around to find what a NACK is about.
+const HISTORY_SIZE: usize = 16;
+
+/// A service whose resources Envoy rejected.
+#[derive(Debug, Clone, Serialize)]
+pub struct Quarantine {
+    pub node: std::string::String,
+    pub type_url: std::string::String,
+    pub rejected_version: u32,
+    pub message: std::string::String,
+    pub resources: Vec<std::string::String>,
+    // served again since the last config change, waiting on an ACK
+    pub retrying: bool,
+    // resources of the service in the version Envoy last accepted
+    #[serde(skip)]
+    accepted: Option<SharedExports>,
+}
+
+#[derive(Debug, Clone)]
+struct Published {
+    snapshot: Arc<Snapshot>,
+    exports: ServiceExports,
+    held_back: BTreeSet<u32>,
+}
+
+/// Rollback of rejected updates: services whose resources changed in a
+/// version Envoy rejected are held back at the resources it last accepted,
+/// until a config change retries them.
+#[derive(Debug, Clone, Default)]
+pub struct Rollback {
+    history: VecDeque<Published>,
+    quarantined: BTreeMap<u32, Quarantine>,
+}
+
+impl Rollback {
+    pub fn quarantined(&self) -> &BTreeMap<u32, Quarantine> {
+        &self.quarantined
+    }
+
+    /// Serve every quarantined service again, as the config changed.
+    pub fn retry(&mut self) {
+        for quarantine in self.quarantined.values_mut() {
+            quarantine.retrying = true;
+        }
+    }
+
+    /// The exports to publish out of `exports`, along with the ids of the
+    /// services held back in them.
+    pub fn hold_back(&self, exports: &ServiceExports) -> (ServiceExports, BTreeSet<u32>) {
+        let mut result = exports.clone();
+        let mut held_back = BTreeSet::new();
+        for (id, quarantine) in &self.quarantined {
+            if quarantine.retrying {
+                continue;
+            }
+            held_back.insert(*id);
+            match quarantine.accepted {
+                Some(ref accepted) => result.insert(*id, accepted.clone()),
+                None => result.remove(id),
+            };
+        }
+        (result, held_back)
+    }
+
+    pub fn published(
+        &mut self,
+        snapshot: Arc<Snapshot>,
+        exports: ServiceExports,
+        held_back: BTreeSet<u32>,
+    ) {
+        if self.history.len() == HISTORY_SIZE {
+            self.history.pop_front();
+        }
+        self.history.push_back(Published {
+            snapshot,
+            exports,
+            held_back,
+        });
+    }
+
+    fn find(&self, type_url: &str, version: &str) -> Option<&Published> {
+        self.history
+            .iter()
+            .rev()
+            .find(|published| published.snapshot.type_version(type_url) == version)
+    }
+
+    /// Quarantine the services whose `type_url` resources changed between
+    /// the version `nack` is about and the `running` one the node kept.
+    /// Returns whether any service was quarantined.
+    ///
+    /// Versions are the type versions of state-of-the-world streams: delta
+    /// streams only resend what changed, so a rejected resource doesn't hold
+    /// the others back there.
+    pub fn on_nack(&mut self, node: &str, type_url: &str, nack: &Nack, running: &str) -> bool {
+        let rejected = match self.find(type_url, &nack.version) {
+            Some(rejected) => rejected,
+            None => {
+                tracing::warn!(
+                    "No snapshot of {} version {} to roll back",
+                    type_url,
+                    nack.version
+                );
+                return false;
+            }
+        };
+        let accepted = match self.find(type_url, running) {
+            Some(accepted) => accepted,
+            None => {
+                tracing::warn!(
+                    "No snapshot of {} version {} accepted by {} to roll back to",
+                    type_url,
+                    running,
+                    node
+                );
+                return false;
+            }
+        };
+
+        let rejected_resources = rejected.snapshot.resources(type_url);
+        let accepted_resources = accepted.snapshot.resources(type_url);
+        let mut quarantined = Vec::new();
+        for (id, exports) in &rejected.exports {
+            let changed: Vec<std::string::String> = exports
+                .iter()
+                .filter(|export| export.config.type_url() == type_url)
+                .map(|export| export.config.name())
+                .filter(|name| {
+                    let version = |resources: Option<&Resources>| {
+                        resources
+                            .and_then(|resources| resources.get(*name))
+                            .map(|resource| resource.version.clone())
+                    };
+                    version(rejected_resources) != version(accepted_resources)
+                })
+                .map(str::to_string)
+                .collect();
+            if changed.is_empty() {
+                continue;
+            }
+            quarantined.push((
+                *id,
+                Quarantine {
+                    node: node.to_string(),
+                    type_url: type_url.to_string(),
+                    rejected_version: rejected.snapshot.version(),
+                    message: nack.message.clone(),
+                    resources: changed,
+                    retrying: false,
+                    accepted: accepted.exports.get(id).cloned(),
+                },
+            ));
+        }
+
+        if quarantined.is_empty() {
+            tracing::warn!(
+                "No service changed {} between versions {} and {}, nothing to roll back",
+                type_url,
+                running,
+                nack.version
+            );
+            return false;
+        }
+        for (id, quarantine) in quarantined {
+            tracing::error!(
+                "Quarantining service {} after {} rejected {}",
+                id,
+                node,
+                quarantine.resources.join(", ")
+            );
+            self.quarantined.insert(id, quarantine);
+        }
+        true
+    }
+
+    /// Release the retried services that went out in the `type_url`
+    /// resources a node accepted.
+    pub fn on_ack(&mut self, type_url: &str, version: &str) {
+        let published = match self.find(type_url, version) {
+            Some(published) => published,
+            None => return,
+        };
+        let released: Vec<u32> = self
+            .quarantined
+            .iter()
+            .filter(|(id, quarantine)| {
+                quarantine.type_url == type_url
+                    && published.exports.contains_key(id)
+                    && !published.held_back.contains(id)
+            })
+            .map(|(id, _)| *id)
+            .collect();
+        for id in released {
+            tracing::info!("Service {} accepted again, leaving quarantine", id);
+            self.quarantined.remove(&id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::configuration::{Config, DEFAULT_NODE_GROUP};
+    use crate::envoy_helpers::{get_envoy_cluster, EnvoyExport, EnvoyResource, CLUSTER_TYPE_URL};
+    use crate::node_status::Nack;
+    use crate::rollback::ServiceExports;
+    use crate::snapshot::Snapshot;
+
+    fn exports(services: &[(u32, &str)]) -> ServiceExports {
+        services
+            .iter()
+            .map(|(id, url)| {
+                let export = EnvoyExport {
+                    key: format!("service::id::{}::cluster", id),
+                    config: EnvoyResource::Cluster(
+                        get_envoy_cluster(
+                            format!("Cluster::service::{}", id),
+                            url,
+                            &Default::default(),
+                        )
+                        .unwrap(),
+                    ),
+                };
+                (*id, vec![export].into())
+            })
+            .collect()
+    }
+
+    fn nack(config: &Config) -> Nack {
+        Nack {
+            version: config.get_snapshot().type_version(CLUSTER_TYPE_URL),
+            nonce: "2".to_string(),
+            message: "bad cluster".to_string(),
+            resources: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn nacked_services_are_rolled_back_and_retried() {
+        let mut config = Config::default();
+        config.enable_rollback();
+        config.import(
+            Vec::new(),
+            "a".to_string(),
+            exports(&[(1, "http://one:80"), (2, "http://two:80")]),
+        );
+        let accepted = config.get_snapshot().type_version(CLUSTER_TYPE_URL);
+        config.on_ack(DEFAULT_NODE_GROUP, "envoy-1", CLUSTER_TYPE_URL, &accepted);
+
+        config.import(
+            Vec::new(),
+            "b".to_string(),
+            exports(&[(1, "http://one:80"), (2, "http://two:81")]),
+        );
+        let rejected_snapshot = config.get_snapshot();
+        let rejected = nack(&config);
+        assert!(config.on_nack("envoy-1", CLUSTER_TYPE_URL, &rejected, &accepted));
+
+        // the accepted resources go out again as a new version
+        let snapshot = config.get_snapshot();
+        assert_eq!(snapshot.version(), 3);
+        assert_eq!(snapshot.type_version(CLUSTER_TYPE_URL), accepted);
+        let quarantined = config.quarantined();
+        assert_eq!(quarantined.len(), 1);
+        let quarantine = &quarantined[&2];
+        assert_eq!(quarantine.node, "envoy-1");
+        assert_eq!(quarantine.rejected_version, 2);
+        assert_eq!(quarantine.message, "bad cluster");
+        assert_eq!(quarantine.resources, ["Cluster::service::2"]);
+        assert!(!quarantine.retrying);
+
+        // other services still get their changes while 2 is held back
+        config.import(
+            Vec::new(),
+            "c".to_string(),
+            exports(&[(1, "http://one:8080"), (2, "http://two:81")]),
+        );
+        assert!(config.quarantined()[&2].retrying);
+        let retried = config.get_snapshot();
+        let version = |snapshot: &Snapshot| {
+            snapshot.resources(CLUSTER_TYPE_URL).unwrap()["Cluster::service::2"]
+                .version
+                .clone()
+        };
+        assert_eq!(version(&retried), version(&rejected_snapshot));
+
+        config.on_ack(
+            DEFAULT_NODE_GROUP,
+            "envoy-1",
+            CLUSTER_TYPE_URL,
+            &retried.type_version(CLUSTER_TYPE_URL),
+        );
+        assert!(config.quarantined().is_empty());
+    }
+
+    #[test]
+    fn nacks_are_ignored_unless_enabled() {
+        let mut config = Config::default();
+        config.import(
+            Vec::new(),
+            "a".to_string(),
+            exports(&[(1, "http://one:80")]),
+        );
+        let accepted = config.get_snapshot().type_version(CLUSTER_TYPE_URL);
+        config.import(
+            Vec::new(),
+            "b".to_string(),
+            exports(&[(1, "http://one:81")]),
+        );
+
+        let rejected = nack(&config);
+        assert!(!config.on_nack("envoy-1", CLUSTER_TYPE_URL, &rejected, &accepted));
+        assert_eq!(config.get_snapshot().version(), 2);
+        assert!(config.quarantined().is_empty());
+    }
+}
@@ -0,0 +1,374 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::field_errors::{field, index, Findings, Validate};
+use crate::rule_patterns;
+
+use crate::protobuf::envoy::config::route::v3::route_action::ClusterSpecifier;
+use crate::protobuf::envoy::config::route::v3::{
+    header_matcher::HeaderMatchSpecifier, query_parameter_matcher::QueryParameterMatchSpecifier,
+    route::Action, route_match::PathSpecifier, HeaderMatcher, QueryParameterMatcher, RouteMatch,
+    VirtualHost,
+};
+use crate::protobuf::envoy::r#type::matcher::v3::{string_matcher::MatchPattern, StringMatcher};
+
+/// The `routing` policy: requests proxied to other upstreams than the
+/// target domain of the service, by their headers or path.
+///
+/// Each rule becomes a route of its own, before the catch all one, in the
+/// order of the rules, the first rule matching a request winning. Each
+/// upstream the rules take the requests to is a cluster of the service,
+/// named after the cluster of the target domain and the upstream. A rule
+/// matching some of the requests of a later one, like one matching a header
+/// along with the path of a rule matching the path alone, goes first for
+/// the later one not to take them.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Routing {
+    // URLs of the upstreams, by name
+    #[serde(default)]
+    pub upstreams: BTreeMap<std::string::String, std::string::String>,
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+}
+
+/// Requests matching every header, query parameter and the path prefix of
+/// the rule, when it has them, go to its upstream.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Rule {
+    pub upstream: std::string::String,
+    // a single header, the way rules used to match them
+    #[serde(default)]
+    pub header: Option<HeaderMatch>,
+    #[serde(default)]
+    pub headers: Vec<HeaderMatch>,
+    #[serde(default)]
+    pub query_parameters: Vec<HeaderMatch>,
+    #[serde(default)]
+    pub path_prefix: Option<std::string::String>,
+}
+
+impl Rule {
+    fn headers(&self) -> impl Iterator<Item = &HeaderMatch> {
+        self.header.iter().chain(&self.headers)
+    }
+}
+
+/// A header, or query parameter, the request has, with the value or
+/// matching the regex given, if any.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HeaderMatch {
+    pub name: std::string::String,
+    #[serde(default)]
+    pub value: Option<std::string::String>,
+    // an RE2 regex the whole value matches
+    #[serde(default)]
+    pub regex: Option<std::string::String>,
+}
+
+impl HeaderMatch {
+    fn matcher(&self) -> HeaderMatcher {
+        let specifier = match (&self.value, &self.regex) {
+            (Some(value), _) => HeaderMatchSpecifier::ExactMatch(value.clone()),
+            (None, Some(regex)) => {
+                HeaderMatchSpecifier::SafeRegexMatch(rule_patterns::re2(regex.clone()))
+            }
+            (None, None) => HeaderMatchSpecifier::PresentMatch(true),
+        };
+        HeaderMatcher {
+            name: self.name.clone(),
+            header_match_specifier: Some(specifier),
+            ..Default::default()
+        }
+    }
+
+    fn query_matcher(&self) -> QueryParameterMatcher {
+        let pattern = match (&self.value, &self.regex) {
+            (Some(value), _) => Some(MatchPattern::Exact(value.clone())),
+            (None, Some(regex)) => Some(MatchPattern::SafeRegex(rule_patterns::re2(regex.clone()))),
+            (None, None) => None,
+        };
+        let specifier = match pattern {
+            Some(pattern) => QueryParameterMatchSpecifier::StringMatch(StringMatcher {
+                match_pattern: Some(pattern),
+                ..Default::default()
+            }),
+            None => QueryParameterMatchSpecifier::PresentMatch(true),
+        };
+        QueryParameterMatcher {
+            name: self.name.clone(),
+            query_parameter_match_specifier: Some(specifier),
+        }
+    }
+}
+
+impl Validate for HeaderMatch {
+    fn validate(&self, path: &str, findings: &mut Findings) {
+        if self.name.is_empty() || self.name.contains(|c: char| c.is_whitespace()) {
+            findings.error(
+                field(path, "name"),
+                format!("'{}' is not a name", self.name),
+            );
+        }
+        match (&self.value, &self.regex) {
+            (Some(_), Some(_)) => {
+                findings.error(field(path, "regex"), "cannot be given along with a value")
+            }
+            (None, Some(regex)) => {
+                if let Err(e) = regex::Regex::new(regex) {
+                    findings.error(field(path, "regex"), format!("not an RE2 regex: {}", e));
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The name of the cluster of `upstream`, for a service whose target
+/// domain is the cluster `cluster`.
+pub fn cluster_name(cluster: &str, upstream: &str) -> std::string::String {
+    format!("{}::{}", cluster, upstream)
+}
+
+impl Routing {
+    /// The upstreams some rule takes requests to, with their URLs.
+    pub fn referenced(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.upstreams
+            .iter()
+            .filter(move |(name, _)| self.rules.iter().any(|rule| &rule.upstream == *name))
+            .map(|(name, url)| (name.as_str(), url.as_str()))
+    }
+
+    /// Route the requests matching the rules of the virtual host, whose
+    /// routes end with the catch all one, to their upstreams.
+    pub fn apply(&self, host: &mut VirtualHost) {
+        let catch_all = match host.routes.pop() {
+            Some(route) => route,
+            None => return,
+        };
+        for rule in &self.rules {
+            let mut route = catch_all.clone();
+            route.r#match = Some(RouteMatch {
+                path_specifier: Some(PathSpecifier::Prefix(
+                    rule.path_prefix.clone().unwrap_or_else(|| "/".to_string()),
+                )),
+                headers: rule.headers().map(HeaderMatch::matcher).collect(),
+                query_parameters: rule
+                    .query_parameters
+                    .iter()
+                    .map(HeaderMatch::query_matcher)
+                    .collect(),
+                ..Default::default()
+            });
+            if let Some(Action::Route(ref mut action)) = route.action {
+                if let Some(ClusterSpecifier::Cluster(ref mut cluster)) = action.cluster_specifier {
+                    *cluster = cluster_name(cluster, &rule.upstream);
+                }
+            }
+            host.routes.push(route);
+        }
+        host.routes.push(catch_all);
+    }
+}
+
+impl Validate for Routing {
+    fn validate(&self, path: &str, findings: &mut Findings) {
+        let upstreams = field(path, "upstreams");
+        for (name, url) in &self.upstreams {
+            let allowed =
+                |c: char| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_';
+            let at = field(&upstreams, name);
+            if name.is_empty() || !name.chars().all(allowed) {
+                findings.error(
+                    &at,
+                    format!("'{}' must be lowercase letters, digits, '-' or '_'", name),
+                );
+            }
+            match url::Url::parse(url) {
+                Err(e) => findings.error(&at, format!("'{}': {}", url, e)),
+                Ok(url) if url.scheme() != "http" && url.scheme() != "https" => findings.error(
+                    &at,
+                    format!("scheme must be http or https, not '{}'", url.scheme()),
+                ),
+                Ok(url) if url.host_str().is_none() => {
+                    findings.error(&at, format!("'{}' has no host", url))
+                }
+                Ok(_) => {}
+            }
+        }
+
+        let rules = field(path, "rules");
+        if self.rules.is_empty() {
+            findings.error(&rules, "needs at least one rule");
+        }
+        for (i, rule) in self.rules.iter().enumerate() {
+            let rule_path = index(&rules, i);
+            if !self.upstreams.contains_key(&rule.upstream) {
+                findings.error(
+                    field(&rule_path, "upstream"),
+                    format!("'{}' is not one of the upstreams", rule.upstream),
+                );
+            }
+            if rule.headers().next().is_none()
+                && rule.query_parameters.is_empty()
+                && rule.path_prefix.is_none()
+            {
+                findings.error(
+                    &rule_path,
+                    "needs a header, a query parameter or a path prefix to match",
+                );
+            }
+            if let Some(ref header) = rule.header {
+                header.validate(&field(&rule_path, "header"), findings);
+            }
+            for (j, header) in rule.headers.iter().enumerate() {
+                header.validate(&index(&field(&rule_path, "headers"), j), findings);
+            }
+            for (j, parameter) in rule.query_parameters.iter().enumerate() {
+                parameter.validate(&index(&field(&rule_path, "query_parameters"), j), findings);
+            }
+            if let Some(ref prefix) = rule.path_prefix {
+                if !prefix.starts_with('/') {
+                    findings.error(
+                        field(&rule_path, "path_prefix"),
+                        format!("'{}' does not start with '/'", prefix),
+                    );
+                }
+            }
+        }
+
+        for name in self.upstreams.keys() {
+            if !self.rules.iter().any(|rule| &rule.upstream == name) {
+                // fine for Envoy, but likely a mistake
+                findings.warning(field(&upstreams, name), "is not the upstream of any rule");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protobuf::envoy::config::route::v3::{Route, RouteAction};
+
+    #[test]
+    fn rules_need_a_known_upstream_and_something_to_match() {
+        let routing: Routing = serde_json::from_value(serde_json::json!({
+            "upstreams": {"v2": "ftp://v2.backend", "Beta": "http://beta.backend"},
+            "rules": [
+                {"upstream": "v3", "header": {"name": "x-api-version", "value": "3", "regex": "3"}},
+                {"upstream": "v2"},
+                {"upstream": "v2", "header": {"name": "x-api", "regex": "(v2"}, "path_prefix": "v2"},
+                {"upstream": "v2", "headers": [{"name": "x-api-version", "value": "2"}, {"name": "x api"}],
+                 "query_parameters": [{"name": "beta", "regex": "[true"}]},
+            ],
+        }))
+        .unwrap();
+        let errors = routing.findings("").errors;
+        let paths: Vec<_> = errors.iter().map(|error| error.path.as_str()).collect();
+        assert_eq!(
+            paths,
+            [
+                "upstreams.Beta",
+                "upstreams.v2",
+                "rules[0].upstream",
+                "rules[0].header.regex",
+                "rules[1]",
+                "rules[2].header.regex",
+                "rules[2].path_prefix",
+                "rules[3].headers[1].name",
+                "rules[3].query_parameters[0].regex",
+            ]
+        );
+    }
+
+    #[test]
+    fn rules_match_headers_and_query_parameters_in_order() {
+        let routing: Routing = serde_json::from_value(serde_json::json!({
+            "upstreams": {"v2": "http://v2.backend", "beta": "http://beta.backend"},
+            "rules": [
+                {"upstream": "v2", "path_prefix": "/api", "headers": [
+                    {"name": "x-api-version", "value": "2"},
+                    {"name": "x-canary", "regex": "on|yes"},
+                    {"name": "x-debug"},
+                ]},
+                {"upstream": "beta", "path_prefix": "/api", "query_parameters": [
+                    {"name": "beta", "value": "true"},
+                    {"name": "tenant"},
+                ]},
+            ],
+        }))
+        .unwrap();
+        assert!(routing.findings("").errors.is_empty());
+        let mut host = VirtualHost {
+            routes: vec![Route {
+                action: Some(Action::Route(RouteAction {
+                    cluster_specifier: Some(ClusterSpecifier::Cluster("cluster".to_string())),
+                    ..Default::default()
+                })),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        routing.apply(&mut host);
+
+        // the header rule shadows the query one for the requests matching
+        // both, the first route matching winning
+        let clusters: Vec<_> = host
+            .routes
+            .iter()
+            .map(|route| match route.action {
+                Some(Action::Route(RouteAction {
+                    cluster_specifier: Some(ClusterSpecifier::Cluster(ref cluster)),
+                    ..
+                })) => cluster.as_str(),
+                ref action => panic!("{:?}", action),
+            })
+            .collect();
+        assert_eq!(clusters, ["cluster::v2", "cluster::beta", "cluster"]);
+
+        let matched = host.routes[0].r#match.clone().unwrap();
+        let specifiers: Vec<_> = matched
+            .headers
+            .into_iter()
+            .map(|header| header.header_match_specifier.unwrap())
+            .collect();
+        assert_eq!(
+            specifiers,
+            [
+                HeaderMatchSpecifier::ExactMatch("2".to_string()),
+                HeaderMatchSpecifier::SafeRegexMatch(rule_patterns::re2("on|yes".to_string())),
+                HeaderMatchSpecifier::PresentMatch(true),
+            ]
+        );
+
+        let matched = host.routes[1].r#match.clone().unwrap();
+        assert!(matched.headers.is_empty());
+        let specifiers: Vec<_> = matched
+            .query_parameters
+            .into_iter()
+            .map(|parameter| {
+                (
+                    parameter.name,
+                    parameter.query_parameter_match_specifier.unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            specifiers,
+            [
+                (
+                    "beta".to_string(),
+                    QueryParameterMatchSpecifier::StringMatch(StringMatcher {
+                        match_pattern: Some(MatchPattern::Exact("true".to_string())),
+                        ..Default::default()
+                    })
+                ),
+                (
+                    "tenant".to_string(),
+                    QueryParameterMatchSpecifier::PresentMatch(true)
+                ),
+            ]
+        );
+    }
+}
@@ -0,0 +1,180 @@
+use std::cmp::Reverse;
+
+use crate::protobuf::envoy::config::route::v3::route_match::PathSpecifier;
+use crate::protobuf::envoy::r#type::matcher::v3::{regex_matcher, RegexMatcher};
+
+use filter_config::rule_pattern::prefix;
+pub use filter_config::rule_pattern::{loose_regex, path_regex};
+
+/// How a route matches the paths of the mapping rule `pattern`: by its
+/// regex, or else by the part of the pattern before what can't be
+/// expressed, matching more paths than the rule.
+pub fn path_specifier(pattern: &str) -> PathSpecifier {
+    match path_regex(pattern) {
+        Ok(regex) => PathSpecifier::SafeRegex(re2(regex)),
+        Err(_) => PathSpecifier::Prefix(prefix(pattern).to_string()),
+    }
+}
+
+/// How specific the paths the route of the mapping rule `pattern` matches
+/// are: the more literal characters the more specific, then anchored, then
+/// with fewer placeholders. Envoy taking the first route or virtual cluster
+/// matching a request, those of the rules go from the most specific down,
+/// for `/` not to take the requests of `/things`.
+pub fn specificity(pattern: &str) -> (usize, bool, Reverse<usize>) {
+    if path_regex(pattern).is_err() {
+        // matched by its prefix
+        return (prefix(pattern).len(), false, Reverse(0));
+    }
+    let (path, anchored) = match pattern.strip_suffix('$') {
+        Some(path) => (path, true),
+        None => (pattern, false),
+    };
+    let mut placeholder = false;
+    let literal = path
+        .chars()
+        .filter(|c| {
+            match c {
+                '{' => placeholder = true,
+                '}' => {
+                    placeholder = false;
+                    return false;
+                }
+                _ => {}
+            }
+            !placeholder
+        })
+        .count();
+    (literal, anchored, Reverse(path.matches('{').count()))
+}
+
+/// The RE2 matcher of `regex`, for every matcher of the exports.
+pub fn re2(regex: std::string::String) -> RegexMatcher {
+    RegexMatcher {
+        regex,
+        engine_type: Some(regex_matcher::EngineType::GoogleRe2(Default::default())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn routes_and_the_filter_match_the_same_paths() {
+        use filter_config::{Decision, FilterConfig, MappingRule};
+
+        // as Envoy matches the path of the route, the query left out
+        let routed = |pattern: &str, path: &str| {
+            let path = path.split('?').next().unwrap();
+            match path_specifier(pattern) {
+                PathSpecifier::SafeRegex(matcher) => {
+                    regex::Regex::new(&format!("^(?:{})$", matcher.regex))
+                        .unwrap()
+                        .is_match(path)
+                }
+                PathSpecifier::Prefix(prefix) => path.starts_with(&prefix),
+                specifier => panic!("{:?}", specifier),
+            }
+        };
+        // as the filter decides on the request of the rule
+        let filtered = |pattern: &str, path: &str| {
+            let config = FilterConfig {
+                proxy_rules: vec![MappingRule {
+                    pattern: pattern.to_string(),
+                    http_method: "GET".to_string(),
+                    metric_system_name: "hits".to_string(),
+                    delta: 1,
+                }],
+                ..Default::default()
+            };
+            match config.decide("GET".to_string(), path.to_string(), "") {
+                Decision::Authrep(_) => true,
+                Decision::Deny(..) => false,
+                decision => panic!("{:?}", decision),
+            }
+        };
+
+        let paths = [
+            "/",
+            "/things",
+            "/things?id=1",
+            "/things/1",
+            "/things/1?expand=parts",
+            "/things/1/parts/2",
+            "/things/1/parts/2/x",
+            "/things//parts/2",
+            "/v1.0/things+7.json",
+            "/v1x0/things+7.json",
+            "/things/{id",
+        ];
+        for pattern in &[
+            "/",
+            "/things$",
+            "/things/{id}",
+            "/things/{id}/parts/{part}$",
+            "/v1.0/things+{id}.json$",
+            "/things/{id",
+        ] {
+            for path in &paths {
+                assert_eq!(
+                    routed(pattern, path),
+                    filtered(pattern, path),
+                    "{} on {}",
+                    pattern,
+                    path
+                );
+            }
+        }
+        // the route of a pattern with a query string matching by prefix, the
+        // filter matches some of its requests alone
+        for path in &paths {
+            if filtered("/things?id={id}", path) {
+                assert!(routed("/things?id={id}", path), "{}", path);
+            }
+        }
+    }
+
+    #[test]
+    fn the_most_specific_patterns_come_first() {
+        let mut patterns = vec![
+            "/",
+            "/things/{id}",
+            "/things",
+            "/things?id={id}",
+            "/things/{id}/parts",
+            "/things$",
+            "/things/new$",
+        ];
+        patterns.sort_by_key(|pattern| Reverse(specificity(pattern)));
+        assert_eq!(
+            patterns,
+            [
+                "/things/{id}/parts",
+                "/things/new$",
+                "/things/{id}",
+                "/things$",
+                "/things",
+                "/things?id={id}",
+                "/"
+            ]
+        );
+    }
+
+    #[test]
+    fn patterns_not_expressed_fall_back_to_prefixes() {
+        for (pattern, prefix) in &[
+            ("/things?id={id}", "/things"),
+            ("/things/{id}/parts?all$", "/things/"),
+            ("/things/{id", "/things/"),
+            ("/things/id}", "/things/id}"),
+        ] {
+            assert!(path_regex(pattern).is_err(), "{}", pattern);
+            assert_eq!(
+                path_specifier(pattern),
+                PathSpecifier::Prefix(prefix.to_string())
+            );
+            assert_eq!(loose_regex(pattern), format!("{}.*", regex::escape(prefix)));
+        }
+    }
+}
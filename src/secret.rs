@@ -0,0 +1,324 @@
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use tokio_rustls::rustls::internal::pemfile;
+use tokio_rustls::rustls::{sign, SignatureScheme};
+use tokio_rustls::webpki;
+
+use crate::configuration;
+use crate::protobuf::envoy::config::core::v3::config_source::ConfigSourceSpecifier;
+use crate::protobuf::envoy::config::core::v3::data_source::Specifier;
+use crate::protobuf::envoy::config::core::v3::transport_socket::ConfigType;
+use crate::protobuf::envoy::config::core::v3::{
+    AggregatedConfigSource, ApiVersion, ConfigSource, DataSource, TransportSocket,
+};
+use crate::protobuf::envoy::extensions::transport_sockets::quic::v3::QuicDownstreamTransport;
+use crate::protobuf::envoy::extensions::transport_sockets::tls::v3::{
+    secret, CommonTlsContext, DownstreamTlsContext, SdsSecretConfig, Secret, TlsCertificate,
+};
+use crate::publisher::Publisher;
+use crate::type_urls::TypeUrls;
+
+// How often the certificate files of the services are checked for changes.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+/// TLS terminated by the listener of a service.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ListenerTls {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+    // serve the certificate as an SDS secret the listener refers to, so that
+    // rotating it leaves the listener untouched
+    #[serde(default)]
+    pub sds: bool,
+}
+
+impl ListenerTls {
+    /// Name of the secret of the certificate of service `id`.
+    pub fn secret_name(id: u32) -> std::string::String {
+        format!("service::{}::tls", id)
+    }
+
+    fn certificate(&self) -> Result<TlsCertificate> {
+        let chain = std::fs::read(&self.cert_path)
+            .with_context(|| format!("cannot read {}", self.cert_path.display()))?;
+        let key = std::fs::read(&self.key_path)
+            .with_context(|| format!("cannot read {}", self.key_path.display()))?;
+        check_key_pair(&chain, &key).with_context(|| {
+            format!(
+                "invalid certificate {} with key {}",
+                self.cert_path.display(),
+                self.key_path.display()
+            )
+        })?;
+        let inline = |bytes| DataSource {
+            specifier: Some(Specifier::InlineBytes(bytes)),
+        };
+        Ok(TlsCertificate {
+            certificate_chain: Some(inline(chain)),
+            private_key: Some(inline(key)),
+            ..Default::default()
+        })
+    }
+
+    /// The secret of the certificate, served over SDS.
+    pub fn secret(&self, id: u32) -> Result<Secret> {
+        Ok(Secret {
+            name: ListenerTls::secret_name(id),
+            r#type: Some(secret::Type::TlsCertificate(self.certificate()?)),
+        })
+    }
+
+    /// The transport socket of the listener of service `id`, referring to
+    /// its secret over ADS when served by SDS and with the certificate
+    /// inlined otherwise.
+    pub fn transport_socket(&self, id: u32, type_urls: &TypeUrls) -> Result<TransportSocket> {
+        Ok(TransportSocket {
+            name: "envoy.transport_sockets.tls".to_string(),
+            config_type: Some(ConfigType::TypedConfig(
+                type_urls.pack(&self.tls_context(id)?)?,
+            )),
+        })
+    }
+
+    /// The transport socket of the HTTP/3 listener of service `id`, QUIC
+    /// taking the certificate the TCP listener has.
+    pub fn quic_transport_socket(&self, id: u32, type_urls: &TypeUrls) -> Result<TransportSocket> {
+        Ok(TransportSocket {
+            name: "envoy.transport_sockets.quic".to_string(),
+            config_type: Some(ConfigType::TypedConfig(type_urls.pack(
+                &QuicDownstreamTransport {
+                    downstream_tls_context: Some(self.tls_context(id)?),
+                    ..Default::default()
+                },
+            )?)),
+        })
+    }
+
+    fn tls_context(&self, id: u32) -> Result<DownstreamTlsContext> {
+        let common_tls_context = if self.sds {
+            CommonTlsContext {
+                tls_certificate_sds_secret_configs: vec![SdsSecretConfig {
+                    name: ListenerTls::secret_name(id),
+                    sds_config: Some(ConfigSource {
+                        resource_api_version: ApiVersion::V3 as i32,
+                        config_source_specifier: Some(ConfigSourceSpecifier::Ads(
+                            AggregatedConfigSource {},
+                        )),
+                        ..Default::default()
+                    }),
+                }],
+                ..Default::default()
+            }
+        } else {
+            CommonTlsContext {
+                tls_certificates: vec![self.certificate()?],
+                ..Default::default()
+            }
+        };
+        Ok(DownstreamTlsContext {
+            common_tls_context: Some(common_tls_context),
+            ..Default::default()
+        })
+    }
+
+    fn paths(&self) -> [&Path; 2] {
+        [&self.cert_path, &self.key_path]
+    }
+}
+
+// Sign with the key and verify with the certificate, Envoy refusing a
+// listener whose key doesn't go with its certificate.
+fn check_key_pair(chain: &[u8], key: &[u8]) -> Result<()> {
+    let certs = pemfile::certs(&mut &chain[..]).map_err(|_| anyhow!("invalid certificate PEM"))?;
+    let cert = certs
+        .first()
+        .ok_or_else(|| anyhow!("no certificate in the chain"))?;
+    let mut keys =
+        pemfile::pkcs8_private_keys(&mut &key[..]).map_err(|_| anyhow!("invalid key PEM"))?;
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut &key[..]).map_err(|_| anyhow!("invalid key PEM"))?;
+    }
+    let key = keys.first().ok_or_else(|| anyhow!("no private key"))?;
+    let key = sign::any_supported_type(key).map_err(|_| anyhow!("unsupported private key"))?;
+
+    let schemes = [
+        (
+            SignatureScheme::ECDSA_NISTP256_SHA256,
+            &webpki::ECDSA_P256_SHA256,
+        ),
+        (
+            SignatureScheme::ECDSA_NISTP384_SHA384,
+            &webpki::ECDSA_P384_SHA384,
+        ),
+        (SignatureScheme::ED25519, &webpki::ED25519),
+        (
+            SignatureScheme::RSA_PKCS1_SHA256,
+            &webpki::RSA_PKCS1_2048_8192_SHA256,
+        ),
+    ];
+    let offered: Vec<_> = schemes.iter().map(|(scheme, _)| *scheme).collect();
+    let signer = key
+        .choose_scheme(&offered)
+        .ok_or_else(|| anyhow!("unsupported private key"))?;
+    let algorithm = schemes
+        .iter()
+        .find(|(scheme, _)| *scheme == signer.get_scheme())
+        .map(|(_, algorithm)| *algorithm)
+        .unwrap();
+
+    let message = b"gateway-ng-controller key pair check";
+    let signature = signer
+        .sign(message)
+        .map_err(|e| anyhow!("cannot sign with the key: {}", e))?;
+    webpki::EndEntityCert::from(&cert.0)
+        .map_err(|e| anyhow!("invalid certificate: {:?}", e))?
+        .verify_signature(algorithm, message, &signature)
+        .map_err(|_| anyhow!("the private key does not match the certificate"))
+}
+
+fn modified(config: &RwLock<configuration::Config>) -> Vec<(PathBuf, Option<SystemTime>)> {
+    config
+        .read()
+        .unwrap()
+        .get_services()
+        .iter()
+        .filter_map(|service| service.tls.as_ref())
+        .flat_map(|tls| {
+            tls.paths()
+                .iter()
+                .map(|path| path.to_path_buf())
+                .collect::<Vec<_>>()
+        })
+        .map(|path| {
+            let modified = path.metadata().and_then(|m| m.modified()).ok();
+            (path, modified)
+        })
+        .collect()
+}
+
+/// Export the services again whenever their certificate files change, only
+/// the secrets, or the listeners inlining them, getting new versions.
+pub fn spawn_reloader(publisher: Publisher) {
+    tokio::spawn(async move {
+        let config = publisher.config();
+        let mut last_modified = modified(config);
+        loop {
+            tokio::time::delay_for(RELOAD_INTERVAL).await;
+            let current = modified(config);
+            if current == last_modified {
+                continue;
+            }
+            last_modified = current;
+            tracing::info!("Certificates of the services changed, exporting them again");
+            publisher.schedule_reexport();
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::envoy_helpers::{LISTENER_TYPE_URL, SECRET_TYPE_URL};
+    use crate::service::WasmSettings;
+    use std::sync::Arc;
+
+    // Copy the self-signed `from.pem` certificate of `testdata/tls` into
+    // `dir`, with its key, as `to.pem` and `to.key`.
+    fn copy(dir: &Path, from: &str, to: &str) {
+        let testdata = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/tls"));
+        for ext in &["pem", "key"] {
+            std::fs::copy(
+                testdata.join(format!("{}.{}", from, ext)),
+                dir.join(format!("{}.{}", to, ext)),
+            )
+            .unwrap();
+        }
+    }
+
+    fn services(dir: &Path, sds: bool) -> configuration::Config {
+        let content = serde_json::json!([{
+            "id": 1,
+            "hosts": ["web.app"],
+            "policies": [],
+            "target_domain": "http://web.app:80",
+            "proxy_rules": [],
+            "tls": {
+                "cert_path": dir.join("web.pem"),
+                "key_path": dir.join("web.key"),
+                "sds": sds,
+            },
+        }])
+        .to_string();
+        let services = serde_json::from_str(&content).unwrap();
+        configuration::Config::from_services(services, &content)
+    }
+
+    fn shared() -> Arc<RwLock<configuration::Config>> {
+        let mut config = configuration::Config::default();
+        config.set_wasm(WasmSettings {
+            skip_sha: true,
+            ..Default::default()
+        });
+        Arc::new(RwLock::new(config))
+    }
+
+    fn resource(config: &RwLock<configuration::Config>, type_url: &str) -> (String, Vec<u8>) {
+        let snapshot = config.read().unwrap().get_snapshot();
+        let resource = snapshot
+            .resources(type_url)
+            .unwrap()
+            .values()
+            .next()
+            .unwrap();
+        (
+            snapshot.type_version(type_url),
+            resource.resource.value.clone(),
+        )
+    }
+
+    #[test]
+    fn rotating_a_secret_leaves_the_listener_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        copy(dir.path(), "web", "web");
+        let config = shared();
+        assert!(configuration::publish(&config, services(dir.path(), true)).unwrap());
+        let (secret_version, _) = resource(&config, SECRET_TYPE_URL);
+        let listener = resource(&config, LISTENER_TYPE_URL);
+
+        // same files, same versions
+        assert!(!configuration::reexport(&config).unwrap());
+
+        copy(dir.path(), "web-v2", "web");
+        assert!(configuration::reexport(&config).unwrap());
+        assert_ne!(resource(&config, SECRET_TYPE_URL).0, secret_version);
+        assert_eq!(resource(&config, LISTENER_TYPE_URL), listener);
+    }
+
+    #[test]
+    fn mismatched_keys_fail_to_load() {
+        let dir = tempfile::tempdir().unwrap();
+        copy(dir.path(), "web", "web");
+        copy(dir.path(), "other-web", "other");
+        std::fs::rename(dir.path().join("other.key"), dir.path().join("web.key")).unwrap();
+
+        for sds in &[true, false] {
+            let (_, errors) = services(dir.path(), *sds).export_concurrently(
+                &WasmSettings {
+                    skip_sha: true,
+                    ..Default::default()
+                },
+                1,
+            );
+            let error = format!("{:#}", errors[0].1);
+            assert!(
+                error.contains("does not match the certificate"),
+                "{}",
+                error
+            );
+        }
+    }
+}
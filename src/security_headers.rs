@@ -0,0 +1,274 @@
+use serde::{Deserialize, Serialize};
+
+use crate::field_errors::{field, Findings, Validate};
+
+use crate::protobuf::envoy::config::core::v3::{HeaderValue, HeaderValueOption};
+use crate::protobuf::envoy::config::route::v3::VirtualHost;
+
+// The values browsers understand, `Referrer-Policy` refusing the others.
+const REFERRER_POLICIES: &[&str] = &[
+    "no-referrer",
+    "no-referrer-when-downgrade",
+    "origin",
+    "origin-when-cross-origin",
+    "same-origin",
+    "strict-origin",
+    "strict-origin-when-cross-origin",
+    "unsafe-url",
+];
+
+/// The `security_headers` policy: the security headers compliance asks of
+/// every response, set by Envoy on the responses of the service, replacing
+/// the values the upstream gave them.
+///
+/// Every header is set by default but `Content-Security-Policy`, which
+/// depends on the pages, each left out when set to `null`, or `false` for
+/// `X-Content-Type-Options`. `Strict-Transport-Security` is only sent by
+/// the services with TLS, browsers ignoring it over plain HTTP.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SecurityHeaders {
+    #[serde(default = "default_hsts")]
+    pub strict_transport_security: Option<Hsts>,
+    #[serde(default = "deny_framing")]
+    pub frame_options: Option<FrameOptions>,
+    // `nosniff`
+    #[serde(default = "enabled")]
+    pub content_type_options: bool,
+    #[serde(default = "strict_referrer")]
+    pub referrer_policy: Option<std::string::String>,
+    #[serde(default)]
+    pub content_security_policy: Option<std::string::String>,
+}
+
+/// `Strict-Transport-Security`, having browsers only reach the hosts of the
+/// service over HTTPS for `max_age` seconds.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Hsts {
+    #[serde(default = "a_year")]
+    pub max_age: u64,
+    #[serde(default)]
+    pub include_subdomains: bool,
+    #[serde(default)]
+    pub preload: bool,
+}
+
+impl Default for Hsts {
+    fn default() -> Hsts {
+        Hsts {
+            max_age: a_year(),
+            include_subdomains: false,
+            preload: false,
+        }
+    }
+}
+
+/// Whether the pages may be framed, `X-Frame-Options`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum FrameOptions {
+    Deny,
+    SameOrigin,
+}
+
+fn default_hsts() -> Option<Hsts> {
+    Some(Hsts::default())
+}
+
+fn deny_framing() -> Option<FrameOptions> {
+    Some(FrameOptions::Deny)
+}
+
+fn enabled() -> bool {
+    true
+}
+
+fn strict_referrer() -> Option<std::string::String> {
+    Some("strict-origin-when-cross-origin".to_string())
+}
+
+fn a_year() -> u64 {
+    365 * 24 * 60 * 60
+}
+
+impl Default for SecurityHeaders {
+    fn default() -> SecurityHeaders {
+        SecurityHeaders {
+            strict_transport_security: default_hsts(),
+            frame_options: deny_framing(),
+            content_type_options: enabled(),
+            referrer_policy: strict_referrer(),
+            content_security_policy: None,
+        }
+    }
+}
+
+impl Validate for SecurityHeaders {
+    fn validate(&self, path: &str, findings: &mut Findings) {
+        if let Some(ref policy) = self.referrer_policy {
+            if !REFERRER_POLICIES.contains(&policy.as_str()) {
+                findings.error(
+                    field(path, "referrer_policy"),
+                    format!("'{}' is not a referrer policy", policy),
+                );
+            }
+        }
+        if let Some(ref policy) = self.content_security_policy {
+            if policy.trim().is_empty() || policy.contains(|c: char| c.is_control()) {
+                findings.error(
+                    field(path, "content_security_policy"),
+                    "needs directives on a single line",
+                );
+            }
+        }
+        if let Some(ref hsts) = self.strict_transport_security {
+            if hsts.preload && (!hsts.include_subdomains || hsts.max_age < a_year()) {
+                findings.error(
+                    field(path, "strict_transport_security"),
+                    "preload needs include_subdomains and a max_age of a year at least",
+                );
+            }
+        }
+    }
+}
+
+impl SecurityHeaders {
+    /// The headers set on the responses, `Strict-Transport-Security` only
+    /// when served over `tls`.
+    pub fn headers(&self, tls: bool) -> Vec<(&'static str, std::string::String)> {
+        let mut headers = Vec::new();
+        if let (Some(hsts), true) = (&self.strict_transport_security, tls) {
+            let mut value = format!("max-age={}", hsts.max_age);
+            if hsts.include_subdomains {
+                value.push_str("; includeSubDomains");
+            }
+            if hsts.preload {
+                value.push_str("; preload");
+            }
+            headers.push(("strict-transport-security", value));
+        }
+        if let Some(frame_options) = self.frame_options {
+            let value = match frame_options {
+                FrameOptions::Deny => "DENY",
+                FrameOptions::SameOrigin => "SAMEORIGIN",
+            };
+            headers.push(("x-frame-options", value.to_string()));
+        }
+        if self.content_type_options {
+            headers.push(("x-content-type-options", "nosniff".to_string()));
+        }
+        if let Some(ref policy) = self.referrer_policy {
+            headers.push(("referrer-policy", policy.clone()));
+        }
+        if let Some(ref policy) = self.content_security_policy {
+            headers.push(("content-security-policy", policy.clone()));
+        }
+        headers
+    }
+
+    pub fn apply(&self, host: &mut VirtualHost, tls: bool) {
+        let options = self
+            .headers(tls)
+            .into_iter()
+            .map(|(key, value)| HeaderValueOption {
+                header: Some(HeaderValue {
+                    key: key.to_string(),
+                    value,
+                    ..Default::default()
+                }),
+                // replacing those of the upstream
+                append: Some(false),
+                ..Default::default()
+            });
+        host.response_headers_to_add.extend(options);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response_headers(
+        headers: &SecurityHeaders,
+        tls: bool,
+    ) -> Vec<(std::string::String, std::string::String)> {
+        let mut host = VirtualHost::default();
+        headers.apply(&mut host, tls);
+        host.response_headers_to_add
+            .into_iter()
+            .map(|option| {
+                assert_eq!(option.append, Some(false));
+                let header = option.header.unwrap();
+                (header.key, header.value)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn headers_replace_those_of_the_upstream() {
+        let headers: SecurityHeaders = serde_json::from_value(serde_json::json!({
+            "strict_transport_security": {"max_age": 600, "include_subdomains": true},
+            "frame_options": "same_origin",
+            "referrer_policy": null,
+            "content_security_policy": "default-src 'self'",
+        }))
+        .unwrap();
+        let expected = |hsts: bool| {
+            let mut expected = vec![
+                ("x-frame-options", "SAMEORIGIN"),
+                ("x-content-type-options", "nosniff"),
+                ("content-security-policy", "default-src 'self'"),
+            ];
+            if hsts {
+                expected.insert(
+                    0,
+                    (
+                        "strict-transport-security",
+                        "max-age=600; includeSubDomains",
+                    ),
+                );
+            }
+            expected
+                .into_iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(response_headers(&headers, true), expected(true));
+        assert_eq!(response_headers(&headers, false), expected(false));
+
+        let defaults = response_headers(&SecurityHeaders::default(), true);
+        let names: Vec<_> = defaults.iter().map(|(key, _)| key.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "strict-transport-security",
+                "x-frame-options",
+                "x-content-type-options",
+                "referrer-policy"
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_values_are_refused() {
+        let headers: SecurityHeaders = serde_json::from_value(serde_json::json!({
+            "strict_transport_security": {"max_age": 600, "preload": true},
+            "referrer_policy": "everywhere",
+            "content_security_policy": "default-src 'self'\r\nx-injected: 1",
+        }))
+        .unwrap();
+        let paths: Vec<_> = headers
+            .findings("")
+            .errors
+            .into_iter()
+            .map(|error| error.path)
+            .collect();
+        assert_eq!(
+            paths,
+            [
+                "referrer_policy",
+                "content_security_policy",
+                "strict_transport_security"
+            ]
+        );
+    }
+}
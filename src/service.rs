@@ -1,18 +1,47 @@
 use anyhow::{Context, Result};
 use data_encoding::HEXUPPER;
+use filter_config::{
+    FilterConfig, LocalLimits, MappingRule, MetricsHeader, NoMatchAction, ReportOn,
+};
 use prost_types::Duration;
 use serde::{Deserialize, Serialize};
-use std::fs::File;
-use std::io::BufReader;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::IpAddr;
 use std::path::Path;
 
-use crate::envoy_helpers::{encode, get_envoy_cluster, EnvoyExport, EnvoyResource};
-use crate::oidc::OIDCConfig;
+use crate::conflicts;
+use crate::environment::{self, Environment, Environments};
+use crate::envoy_helpers::{
+    canonical_hash, get_envoy_cluster, json_to_struct, sort_by_key, EnvoyExport, EnvoyResource,
+};
+use crate::field_errors::{field, index, FieldError, FieldErrors, Findings, Severity, Validate};
+use crate::forward_proxy::ForwardProxy;
+use crate::forwarded::Forwarded;
+use crate::header_options::HeaderOptions;
+use crate::http_client::HttpClient;
+use crate::http_filters;
+use crate::listener_address::ListenerAddress;
+use crate::metadata;
+use crate::oidc::{self, OIDCConfig};
+use crate::original_dst::{self, OriginalDst};
+use crate::policy::Policy;
+use crate::request_id::RequestId;
+use crate::routing;
+use crate::rule_patterns;
+use crate::secret::ListenerTls;
 use crate::threescale_auth::ThreescaleAuth;
+use crate::type_urls::TypeUrls;
 use crate::util;
+use crate::util::file_utils::DigestAlgorithm;
+use crate::wasm_module::RemoteModule;
 
 use crate::protobuf::envoy::config::core::v3::AsyncDataSource;
+use crate::protobuf::envoy::config::core::v3::HeaderValue;
+use crate::protobuf::envoy::config::core::v3::HeaderValueOption;
+use crate::protobuf::envoy::config::core::v3::Http3ProtocolOptions;
 use crate::protobuf::envoy::config::core::v3::HttpUri;
+use crate::protobuf::envoy::config::core::v3::Metadata;
 use crate::protobuf::envoy::config::core::v3::RemoteDataSource;
 use crate::protobuf::envoy::config::core::v3::async_data_source::Specifier;
 use crate::protobuf::envoy::config::core::v3::http_uri::HttpUpstreamType;
@@ -23,82 +52,1206 @@ use crate::protobuf::envoy::config::cluster::v3::Cluster;
 use crate::protobuf::envoy::config::core::v3::Address;
 use crate::protobuf::envoy::config::core::v3::SocketAddress;
 use crate::protobuf::envoy::config::core::v3::address::Address as AddressType;
-use crate::protobuf::envoy::config::core::v3::socket_address::PortSpecifier;
+use crate::protobuf::envoy::config::core::v3::socket_address::{PortSpecifier, Protocol};
 use crate::protobuf::envoy::config::listener::v3::Filter;
 use crate::protobuf::envoy::config::listener::v3::FilterChain;
 use crate::protobuf::envoy::config::listener::v3::Listener;
+use crate::protobuf::envoy::config::listener::v3::QuicProtocolOptions;
+use crate::protobuf::envoy::config::listener::v3::UdpListenerConfig;
 use crate::protobuf::envoy::config::listener::v3::filter::ConfigType;
+use crate::protobuf::envoy::config::route::v3::HeaderMatcher;
 use crate::protobuf::envoy::config::route::v3::Route;
 use crate::protobuf::envoy::config::route::v3::RouteAction;
 use crate::protobuf::envoy::config::route::v3::RouteConfiguration;
 use crate::protobuf::envoy::config::route::v3::RouteMatch;
+use crate::protobuf::envoy::config::route::v3::VirtualCluster;
 use crate::protobuf::envoy::config::route::v3::VirtualHost;
+use crate::protobuf::envoy::config::route::v3::header_matcher::HeaderMatchSpecifier;
 use crate::protobuf::envoy::config::route::v3::route::Action;
 use crate::protobuf::envoy::config::route::v3::route_action::ClusterSpecifier;
 use crate::protobuf::envoy::config::route::v3::route_match::PathSpecifier;
 use crate::protobuf::envoy::extensions::filters::http::router::v3::Router;
 use crate::protobuf::envoy::extensions::filters::network::http_connection_manager::v3::HttpConnectionManager;
 use crate::protobuf::envoy::extensions::filters::network::http_connection_manager::v3::HttpFilter;
-use crate::protobuf::envoy::extensions::filters::network::http_connection_manager::v3::http_connection_manager::RouteSpecifier;
+use crate::protobuf::envoy::extensions::filters::network::http_connection_manager::v3::http_connection_manager::{CodecType, RouteSpecifier};
 use crate::protobuf::envoy::extensions::filters::network::http_connection_manager::v3::http_filter;
 use crate::protobuf::envoy::extensions::filters::http::jwt_authn::v3::JwtAuthentication;
 
-const WASM_FILTER_PATH: &str = "static/filter.wasm";
+/// The cluster Envoy fetches the wasm filters through, which the bootstrap
+/// of the nodes defines, or else the one the export command writes.
+pub const WASM_FILES_CLUSTER: &str = "wasm_files";
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+/// Where Envoy fetches the wasm filters from, the types it knows the filters
+/// by, and the settings of the listeners the services don't set.
+#[derive(Debug, Clone, PartialEq, Hash)]
+pub struct WasmSettings {
+    // URL the static files are served at, the filters being under `static/`
+    pub base_url: std::string::String,
+    // path of the services filter, on disk and under `base_url`
+    pub filter_path: std::path::PathBuf,
+    // leave the filter digests out, so that the files need not exist
+    pub skip_sha: bool,
+    // fail the exports of the services whose filters are missing, rather
+    // than export them without, as in development
+    pub wasm_required: bool,
+    // where the services filter is downloaded from, its copy being at the
+    // filter path
+    pub remote: Option<RemoteModule>,
+    // the other filters the services may pick, by name
+    pub modules: BTreeMap<std::string::String, WasmModule>,
+    pub type_urls: TypeUrls,
+    pub request_id: RequestId,
+    // the most virtual clusters of a service, none when 0
+    pub virtual_clusters: usize,
+    // the 3scale ids on the routes and clusters, for access logs to refer to
+    pub metadata: bool,
+    // services may be dynamic forward proxies
+    pub dynamic_forward_proxy: bool,
+    // original_dst services may take their upstreams from a header
+    pub original_dst_header: bool,
+    // services may have HTTP/3 listeners, Envoy being built with QUIC
+    pub http3: bool,
+    pub header_options: HeaderOptions,
+    pub listener_address: ListenerAddress,
+    // the requests of the controller itself, as the OIDC discoveries
+    pub http_client: HttpClient,
+}
+
+/// Why `path` can't be the wasm module of a service, if it can't: a path
+/// relative to the directory of the services filter, that can't leave it.
+pub fn check_module_path(path: &Path) -> Result<(), &'static str> {
+    let plain = path
+        .components()
+        .all(|component| matches!(component, std::path::Component::Normal(_)));
+    if path.as_os_str().is_empty() || !plain {
+        return Err("must be relative to the directory of the services filter, without '..'");
+    }
+    Ok(())
+}
+
+/// A filter of the registry of the controller, for services to run rather
+/// than the services filter, like a new version of it during a rollout.
+#[derive(Debug, Clone, PartialEq, Hash)]
+pub struct WasmModule {
+    // on disk and under the wasm base URL
+    pub path: std::path::PathBuf,
+    // the SHA-256 the file must have, when pinned
+    pub sha256: Option<std::string::String>,
+}
+
+/// The filter a service runs: one at a path, or one of the registry.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum WasmModuleRef {
+    Path(std::path::PathBuf),
+    Name(std::string::String),
+}
+
+impl Default for WasmSettings {
+    fn default() -> Self {
+        WasmSettings {
+            base_url: "http://control-plane-main:5001".to_string(),
+            filter_path: "static/filter.wasm".into(),
+            skip_sha: false,
+            wasm_required: true,
+            remote: None,
+            modules: BTreeMap::new(),
+            type_urls: TypeUrls::default(),
+            request_id: RequestId::default(),
+            virtual_clusters: 0,
+            metadata: true,
+            dynamic_forward_proxy: false,
+            original_dst_header: false,
+            http3: false,
+            header_options: HeaderOptions::default(),
+            listener_address: ListenerAddress::default(),
+            http_client: HttpClient::default(),
+        }
+    }
+}
+
+impl WasmSettings {
+    /// The cluster of the base URL the filters are fetched through.
+    pub fn files_cluster(&self) -> Result<Cluster> {
+        get_envoy_cluster(
+            WASM_FILES_CLUSTER.to_string(),
+            &self.base_url,
+            &self.type_urls,
+        )
+        .with_context(|| format!("invalid wasm base URL {}", self.base_url))
+    }
+
+    /// URL of a file at `path` under the base URL, an absolute path being
+    /// taken from the root of the base URL.
+    pub fn url(&self, path: impl AsRef<Path>) -> std::string::String {
+        format!(
+            "{}/{}",
+            self.base_url.trim_end_matches('/'),
+            path.as_ref().display().to_string().trim_start_matches('/')
+        )
+    }
+
+    /// URL Envoy fetches the filter at `path` from, the services filter
+    /// being fetched from its own URL when downloaded.
+    pub fn filter_url(&self, path: &Path) -> std::string::String {
+        match self.remote {
+            Some(ref remote) if !remote.serve_copy && path == self.filter_path => {
+                remote.url.to_string()
+            }
+            _ => self.url(path),
+        }
+    }
+
+    /// Path of the filter `module` is, the services filter when none. The
+    /// path of a service is under the directory of the services filter,
+    /// see `check_module_path`.
+    pub fn module_path(&self, module: Option<&WasmModuleRef>) -> Result<std::path::PathBuf> {
+        match module {
+            None => Ok(self.filter_path.clone()),
+            Some(WasmModuleRef::Path(path)) => {
+                check_module_path(path)
+                    .map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?;
+                let dir = self.filter_path.parent().unwrap_or_else(|| Path::new(""));
+                Ok(dir.join(path))
+            }
+            Some(WasmModuleRef::Name(name)) => match self.modules.get(name) {
+                Some(module) => Ok(module.path.clone()),
+                None => anyhow::bail!("'{}' is not a wasm module of the registry", name),
+            },
+        }
+    }
+
+    /// Whether the services run the filter at `path`, those missing being
+    /// left out when filters are not required.
+    pub fn runs_filter(&self, path: &Path) -> bool {
+        self.wasm_required || self.skip_sha || path.exists()
+    }
+
+    /// The services filters of `services` left out for missing, none when
+    /// filters are required. The 3scale auth filters are never left out, the
+    /// services missing theirs failing to export instead.
+    pub fn missing_filters(&self, services: &[Service]) -> BTreeSet<std::path::PathBuf> {
+        services
+            .iter()
+            .filter_map(|service| self.module_path(service.wasm_module.as_ref()).ok())
+            .filter(|path| !self.runs_filter(path))
+            .collect()
+    }
+
+    /// Check that every filter of the registry can be read, and has the
+    /// digest it is pinned to.
+    pub fn check_modules(&self) -> Result<()> {
+        for (name, module) in &self.modules {
+            if !self.runs_filter(&module.path) {
+                continue;
+            }
+            self.sha256(&module.path)
+                .with_context(|| format!("invalid wasm module '{}'", name))?;
+        }
+        Ok(())
+    }
+
+    /// SHA-256 of the filter at `path`, Envoy checking the fetched file
+    /// against it. The copy of a remote filter, and the filters of the
+    /// registry pinned to a digest, must still have it.
+    pub fn sha256(&self, path: impl AsRef<Path>) -> Result<std::string::String> {
+        if self.skip_sha {
+            return Ok(std::string::String::new());
+        }
+        let path = path.as_ref();
+        if let Some(ref remote) = self.remote {
+            if path == self.filter_path {
+                return remote.verify(path);
+            }
+        }
+        if !path.exists() {
+            let resolved = std::env::current_dir()
+                .map(|dir| dir.join(path))
+                .unwrap_or_else(|_| path.to_path_buf());
+            anyhow::bail!(
+                "wasm filter {} is missing, --wasm-optional exports the services without it",
+                resolved.display()
+            );
+        }
+        let sha = Service::get_wasm_filter_sha(path, DigestAlgorithm::Sha256)
+            .context("could not compute SHA-256")?;
+        let pinned = self
+            .modules
+            .values()
+            .filter(|module| module.path == path)
+            .filter_map(|module| module.sha256.as_ref());
+        for pinned in pinned {
+            if !pinned.eq_ignore_ascii_case(&sha) {
+                anyhow::bail!(
+                    "{} has digest {}, not the pinned {}",
+                    path.display(),
+                    sha,
+                    pinned
+                );
+            }
+        }
+        Ok(sha)
+    }
+}
+
+// Seconds the clients remember the HTTP/3 listeners the `alt-svc` header
+// advertises.
+const ALT_SVC_MAX_AGE: u32 = 86400;
+
+// Methods of the mapping rules, which the filter compares as they are.
+const HTTP_METHODS: [&str; 9] = [
+    "GET", "HEAD", "POST", "PUT", "DELETE", "PATCH", "OPTIONS", "TRACE", "CONNECT",
+];
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct MappingRules {
     pattern: std::string::String,
     http_method: std::string::String, // @TODO this should be a enum, maybe from hyper
     metric_system_name: std::string::String,
     delta: u32,
+    // the requests of the rule are neither authenticated nor authorized,
+    // like health checks
+    #[serde(default)]
+    pub skip_auth: bool,
+    // nor reported by the services filter
+    #[serde(default)]
+    pub skip_wasm: bool,
+    // the URL of the upstream of the requests of the rule, rather than the
+    // target domain of the service
+    #[serde(default)]
+    pub upstream: Option<std::string::String>,
+}
+
+impl MappingRules {
+    pub fn new(
+        pattern: std::string::String,
+        http_method: std::string::String,
+        metric_system_name: std::string::String,
+        delta: u32,
+    ) -> MappingRules {
+        MappingRules {
+            pattern,
+            http_method,
+            metric_system_name,
+            delta,
+            skip_auth: false,
+            skip_wasm: false,
+            upstream: None,
+        }
+    }
+
+    /// The rule as in `GET /v2/things → hits`, with the delta when not 1.
+    pub fn describe(&self) -> std::string::String {
+        let rule = format!(
+            "{} {} → {}",
+            self.http_method, self.pattern, self.metric_system_name
+        );
+        match self.delta {
+            1 => rule,
+            delta => format!("{} +{}", rule, delta),
+        }
+    }
+
+    // Problems with the fields of the rule, by their names.
+    fn check(&self) -> Vec<(&'static str, std::string::String)> {
+        let mut problems = Vec::new();
+        if !self.pattern.starts_with('/') {
+            problems.push(("pattern", format!("'{}' must start with /", self.pattern)));
+        }
+        // placeholders, as in /things/{id}, are neither nested nor left open
+        let mut open = false;
+        let balanced = self.pattern.chars().all(|c| match c {
+            '{' => !std::mem::replace(&mut open, true),
+            '}' => std::mem::replace(&mut open, false),
+            _ => true,
+        });
+        if !balanced || open {
+            problems.push((
+                "pattern",
+                format!("'{}' has an unbalanced placeholder", self.pattern),
+            ));
+        }
+        if !HTTP_METHODS.contains(&self.http_method.as_str()) {
+            problems.push((
+                "http_method",
+                format!("'{}' is not an HTTP method in upper case", self.http_method),
+            ));
+        }
+        if self.metric_system_name.is_empty() {
+            problems.push(("metric_system_name", "cannot be empty".to_string()));
+        }
+        problems
+    }
+
+    /// The route of the requests of the rule, out of the route catching
+    /// every path, matching them by method and by the path of the pattern,
+    /// with the filters the rule skips disabled.
+    fn route(&self, catch_all: &Route, type_urls: &TypeUrls) -> Result<Route> {
+        let mut skipped = Vec::new();
+        if self.skip_auth {
+            skipped.extend(&[http_filters::JWT_AUTHN, http_filters::THREESCALE_AUTH]);
+        }
+        if self.skip_wasm {
+            skipped.push(http_filters::WASM);
+        }
+        Ok(Route {
+            name: self.describe(),
+            r#match: Some(RouteMatch {
+                path_specifier: Some(rule_patterns::path_specifier(&self.pattern)),
+                headers: vec![MappingRules::method_matcher(&[&self.http_method])],
+                ..Default::default()
+            }),
+            typed_per_filter_config: http_filters::disabled(&skipped, type_urls)?,
+            ..catch_all.clone()
+        })
+    }
+
+    // Rules skipping filters, or taking their requests to an upstream of
+    // their own, need routes of their own.
+    fn needs_route(&self) -> bool {
+        self.skip_auth || self.skip_wasm || self.upstream.is_some()
+    }
+
+    fn method_matcher(methods: &[&str]) -> HeaderMatcher {
+        let specifier = match methods {
+            [method] => HeaderMatchSpecifier::ExactMatch(method.to_string()),
+            methods => HeaderMatchSpecifier::SafeRegexMatch(rule_patterns::re2(methods.join("|"))),
+        };
+        HeaderMatcher {
+            name: ":method".to_string(),
+            header_match_specifier: Some(specifier),
+            ..Default::default()
+        }
+    }
+
+    pub fn filter_rule(&self) -> MappingRule {
+        MappingRule {
+            pattern: self.pattern.clone(),
+            http_method: self.http_method.clone(),
+            metric_system_name: self.metric_system_name.clone(),
+            delta: self.delta,
+        }
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Service {
     pub id: u32,
+    // readable name of the service, naming its resources along with the id
+    #[serde(default)]
+    pub name: Option<std::string::String>,
     pub hosts: Vec<std::string::String>,
-    pub policies: Vec<std::string::String>,
+    pub policies: Vec<Policy>,
+    // the upstream, but for dynamic forward proxies
+    #[serde(default)]
     pub target_domain: std::string::String,
     pub proxy_rules: Vec<MappingRules>,
     pub oidc_issuer: Option<String>,
+    // the headers and credentials of the discovery requests of the issuer
+    #[serde(default)]
+    pub oidc_discovery: Option<oidc::Discovery>,
     pub auth_config: Option<ThreescaleAuth>,
+    #[serde(default)]
+    pub no_match_action: NoMatchAction,
+    #[serde(default)]
+    pub metrics_header: Option<MetricsHeader>,
+    #[serde(default)]
+    pub local_limits: Option<LocalLimits>,
+    #[serde(default)]
+    pub report_on: ReportOn,
+    // the team the service belongs to, whose nodes alone get it
+    #[serde(default = "default_tenant")]
+    pub tenant: std::string::String,
+    // Envoy node groups the service is served to, the default one if empty.
+    #[serde(default)]
+    pub node_groups: Vec<std::string::String>,
+    // labels of the service, like `team: payments`, set as metadata of its
+    // listeners and clusters and handed to the services filter
+    #[serde(default)]
+    pub annotations: BTreeMap<std::string::String, std::string::String>,
+    // plain HTTP without it
+    #[serde(default)]
+    pub tls: Option<ListenerTls>,
+    // a QUIC listener along with the TLS one, whose responses advertise it
+    #[serde(default)]
+    pub http3: bool,
+    // disabled services are checked, but not exported
+    #[serde(default = "enabled_by_default")]
+    pub enabled: bool,
+    // the services filter unless set
+    #[serde(default)]
+    pub wasm_module: Option<WasmModuleRef>,
+    // merged setting by setting over the request id options of the
+    // controller
+    #[serde(default)]
+    pub request_id: Option<RequestId>,
+    // Envoy's handling of the forwarded headers unless set
+    #[serde(default)]
+    pub forwarded: Option<Forwarded>,
+    // each option set here wins over the one of the controller
+    #[serde(default)]
+    pub header_options: Option<HeaderOptions>,
+    // replaces the address of the controller as a whole
+    #[serde(default)]
+    pub listener_address: Option<ListenerAddress>,
+    // a route for each mapping rule, named after it, for the requests of
+    // each rule to be told apart in the access logs of Envoy
+    #[serde(default)]
+    pub rule_routes: bool,
+    #[serde(default)]
+    pub kind: ServiceKind,
+    // the settings of the dynamic forward proxies
+    #[serde(default)]
+    pub forward_proxy: Option<ForwardProxy>,
+    // the settings of the original destination services
+    #[serde(default)]
+    pub original_dst: Option<OriginalDst>,
+    // the staging and production environments served at once, each
+    // overriding some of the settings above, production alone when empty
+    #[serde(default)]
+    pub environments: Environments,
+}
+
+fn enabled_by_default() -> bool {
+    true
+}
+
+/// The tenant of the services naming none, and of the nodes.
+pub const DEFAULT_TENANT: &str = "default";
+
+fn default_tenant() -> std::string::String {
+    DEFAULT_TENANT.to_string()
+}
+
+/// Why `tenant` cannot name one, if it can't: tenants are named like
+/// Kubernetes namespaces.
+pub fn check_tenant(tenant: &str) -> Result<(), &'static str> {
+    let allowed = |c: char| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-';
+    if tenant.is_empty() || tenant.len() > 63 || !tenant.chars().all(allowed) {
+        return Err("must be at most 63 lowercase letters, digits or '-'");
+    }
+    if tenant.starts_with('-') || tenant.ends_with('-') {
+        return Err("cannot start or end with '-'");
+    }
+    Ok(())
+}
+
+// Whether both URLs name the same upstream, as `http://a` and
+// `http://a:80`, those that don't parse being compared as they are.
+fn same_url(a: &str, b: &str) -> bool {
+    match (url::Url::parse(a), url::Url::parse(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+/// What the upstream of a service is: its target domain, or, for
+/// `dynamic_forward_proxy` services, the host each request names, or, for
+/// `original_dst` ones, the address each connection was sent to. The
+/// controller only exports dynamic forward proxies when allowed to, being
+/// open proxies if misconfigured.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ServiceKind {
+    #[default]
+    Api,
+    DynamicForwardProxy,
+    OriginalDst,
+}
+
+/// Builds a service out of code rather than out of a services file, checked
+/// the same way when built.
+///
+/// ```
+/// use gateway_ng_controller::service::{MappingRules, Service};
+///
+/// let hits = || MappingRules::new("/".into(), "GET".into(), "hits".into(), 1);
+/// let service = Service::builder()
+///     .id(7)
+///     .host("web.app")
+///     .target_domain("http://web.app:80")
+///     .mapping_rule(hits())
+///     .build()
+///     .unwrap();
+/// assert_eq!(service.hosts, ["web.app"]);
+///
+/// // refused as the services files would be, the scheme missing
+/// let error = Service::builder()
+///     .id(7)
+///     .host("web.app")
+///     .target_domain("web.app")
+///     .mapping_rule(hits())
+///     .build()
+///     .unwrap_err();
+/// assert!(error.to_string().starts_with("target_domain: "));
+/// ```
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ServiceBuilder {
+    id: Option<u32>,
+    hosts: Vec<std::string::String>,
+    policies: Vec<Policy>,
+    target_domain: Option<std::string::String>,
+    proxy_rules: Vec<MappingRules>,
+    oidc_issuer: Option<std::string::String>,
+    auth_config: Option<ThreescaleAuth>,
+}
+
+impl ServiceBuilder {
+    pub fn id(mut self, id: u32) -> ServiceBuilder {
+        self.id = Some(id);
+        self
+    }
+
+    /// Serve the service to `host`, along with those given already.
+    pub fn host(mut self, host: impl Into<std::string::String>) -> ServiceBuilder {
+        self.hosts.push(host.into());
+        self
+    }
+
+    /// Apply `policy` to the requests, after those given already.
+    pub fn policy(mut self, policy: Policy) -> ServiceBuilder {
+        self.policies.push(policy);
+        self
+    }
+
+    pub fn target_domain(mut self, url: impl Into<std::string::String>) -> ServiceBuilder {
+        self.target_domain = Some(url.into());
+        self
+    }
+
+    /// Report the requests matching `rule`, after those given already.
+    pub fn mapping_rule(mut self, rule: MappingRules) -> ServiceBuilder {
+        self.proxy_rules.push(rule);
+        self
+    }
+
+    pub fn oidc_issuer(mut self, issuer: impl Into<std::string::String>) -> ServiceBuilder {
+        self.oidc_issuer = Some(issuer.into());
+        self
+    }
+
+    pub fn threescale_auth(mut self, auth: ThreescaleAuth) -> ServiceBuilder {
+        self.auth_config = Some(auth);
+        self
+    }
+
+    /// The service, failing with every problem found in it, the way the
+    /// services files are loaded with --strict-config.
+    pub fn build(self) -> Result<Service> {
+        let mut missing = Vec::new();
+        if self.id.is_none() {
+            missing.push(FieldError::new("id", "is missing"));
+        }
+        if self.target_domain.is_none() {
+            missing.push(FieldError::new("target_domain", "is missing"));
+        }
+        if !missing.is_empty() {
+            return Err(FieldErrors(missing).into());
+        }
+        let service = Service {
+            id: self.id.unwrap(),
+            name: None,
+            hosts: self.hosts,
+            policies: self.policies,
+            target_domain: self.target_domain.unwrap(),
+            proxy_rules: self.proxy_rules,
+            oidc_issuer: self.oidc_issuer,
+            oidc_discovery: None,
+            auth_config: self.auth_config,
+            no_match_action: NoMatchAction::default(),
+            metrics_header: None,
+            local_limits: None,
+            report_on: ReportOn::default(),
+            tenant: default_tenant(),
+            node_groups: Vec::new(),
+            annotations: BTreeMap::new(),
+            tls: None,
+            http3: false,
+            enabled: true,
+            wasm_module: None,
+            request_id: None,
+            forwarded: None,
+            header_options: None,
+            listener_address: None,
+            rule_routes: false,
+            kind: ServiceKind::Api,
+            forward_proxy: None,
+            original_dst: None,
+            environments: Environments::new(),
+        };
+        let mut findings = Findings::strict();
+        Validate::validate(&service, "", &mut findings);
+        if !findings.errors.is_empty() {
+            return Err(FieldErrors(findings.errors).into());
+        }
+        Ok(service)
+    }
+}
+
+/// Every problem with the settings of the service, at their paths under
+/// `path`, the one of the service in its services file.
+impl Validate for Service {
+    fn validate(&self, path: &str, findings: &mut Findings) {
+        if let Some(ref name) = self.name {
+            let allowed =
+                |c: char| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_';
+            if name.is_empty() || name.len() > 63 || !name.chars().all(allowed) {
+                findings.error(
+                    field(path, "name"),
+                    format!(
+                        "'{}' must be 1 to 63 lowercase letters, digits, '-' or '_'",
+                        name
+                    ),
+                );
+            }
+        }
+        // the hosts and the mapping rules used to be taken as they were
+        if self.hosts.is_empty() {
+            findings.deprecated(field(path, "hosts"), "needs at least one host");
+        }
+        for (i, host) in self.hosts.iter().enumerate() {
+            if host.is_empty() || host.contains(|c: char| c.is_whitespace() || c == '/') {
+                findings.deprecated(
+                    index(&field(path, "hosts"), i),
+                    format!("'{}' is not a host name", host),
+                );
+            }
+        }
+
+        self.validate_target_domain(&self.target_domain, field(path, "target_domain"), findings);
+
+        if let Some(ref options) = self.header_options {
+            options.validate(&field(path, "header_options"), findings);
+        }
+        if self.http3 && self.tls.is_none() {
+            findings.error(field(path, "http3"), "needs tls, QUIC being encrypted");
+        }
+        if let Some(ref address) = self.listener_address {
+            address.validate(&field(path, "listener_address"), findings);
+        }
+
+        match (self.kind, &self.forward_proxy) {
+            (ServiceKind::DynamicForwardProxy, Some(proxy)) => {
+                proxy.validate(&field(path, "forward_proxy"), findings);
+                if self.rule_routes {
+                    findings.warning(
+                        field(path, "rule_routes"),
+                        "is ignored, dynamic forward proxies routing by the host of the requests",
+                    );
+                }
+            }
+            (ServiceKind::DynamicForwardProxy, None) => findings.error(
+                field(path, "forward_proxy"),
+                "is missing, a dynamic forward proxy needs the domains it may reach",
+            ),
+            (_, Some(_)) => findings.warning(
+                field(path, "forward_proxy"),
+                "is ignored, the service not being a dynamic forward proxy",
+            ),
+            (_, None) => {}
+        }
+        match self.original_dst {
+            Some(ref original_dst) if self.kind == ServiceKind::OriginalDst => {
+                original_dst.validate(&field(path, "original_dst"), findings)
+            }
+            Some(_) => findings.warning(
+                field(path, "original_dst"),
+                "is ignored, the service not being of kind original_dst",
+            ),
+            None => {}
+        }
+
+        for (i, rule) in self.proxy_rules.iter().enumerate() {
+            let rule_path = index(&field(path, "proxy_rules"), i);
+            let problems = rule.check();
+            let pattern_checked = problems.iter().all(|(name, _)| *name != "pattern");
+            for problem in problems {
+                findings.deprecated(field(&rule_path, problem.0), problem.1);
+            }
+            // checked as the target domain, whatever the kind of the service
+            if let Some(ref upstream) = rule.upstream {
+                self.validate_target_domain(upstream, field(&rule_path, "upstream"), findings);
+            }
+            if let (true, Err(e)) = (pattern_checked, rule_patterns::path_regex(&rule.pattern)) {
+                findings.warning(
+                    field(&rule_path, "pattern"),
+                    format!("'{}' {}, its route matches by prefix", rule.pattern, e),
+                );
+                // the route would skip the filters for more than the rule
+                for (skipped, name) in
+                    &[(rule.skip_auth, "skip_auth"), (rule.skip_wasm, "skip_wasm")]
+                {
+                    if *skipped {
+                        findings.error(
+                            field(&rule_path, name),
+                            "needs a pattern its route matches exactly, not by prefix",
+                        );
+                    }
+                }
+            }
+        }
+
+        self.validate_policies(self.policies.iter().enumerate(), path, findings);
+
+        if let Some(ref issuer) = self.oidc_issuer {
+            if let Err(e) = url::Url::parse(issuer) {
+                findings.error(field(path, "oidc_issuer"), format!("'{}': {}", issuer, e));
+            }
+        }
+        if let Some(WasmModuleRef::Path(ref module)) = self.wasm_module {
+            if let Err(e) = check_module_path(module) {
+                findings.error(field(&field(path, "wasm_module"), "path"), e);
+            }
+        }
+        if let Some(ref auth) = self.auth_config {
+            auth.validate(&field(path, "auth_config"), findings);
+        }
+        if let Some(ref discovery) = self.oidc_discovery {
+            let discovery_path = field(path, "oidc_discovery");
+            if self.oidc_issuer.is_none() {
+                findings.warning(&discovery_path, "is only read along with an oidc_issuer");
+            }
+            discovery.validate(&discovery_path, findings);
+        }
+
+        if let Err(e) = check_tenant(&self.tenant) {
+            findings.error(field(path, "tenant"), e);
+        }
+        for (i, group) in self.node_groups.iter().enumerate() {
+            if group.is_empty() {
+                findings.error(index(&field(path, "node_groups"), i), "cannot be empty");
+            } else if group.contains('/') {
+                // the snapshots of the tenants are told apart by it
+                findings.error(index(&field(path, "node_groups"), i), "cannot contain '/'");
+            }
+        }
+        for (key, value) in &self.annotations {
+            let annotation = field(&field(path, "annotations"), key);
+            if let Err(e) = metadata::check_annotation(key, value) {
+                findings.error(annotation, e);
+            }
+        }
+
+        if let Some(ref tls) = self.tls {
+            for (name, file) in &[("cert_path", &tls.cert_path), ("key_path", &tls.key_path)] {
+                if file.as_os_str().is_empty() {
+                    findings.error(field(&field(path, "tls"), name), "cannot be empty");
+                }
+            }
+        }
+
+        if let Err(e) = self.no_match_action.validate() {
+            findings.error(field(path, "no_match_action"), e);
+        }
+        if let Some(Err(e)) = self.metrics_header.as_ref().map(MetricsHeader::validate) {
+            findings.error(field(path, "metrics_header"), e);
+        }
+        if let Some(Err(e)) = self.local_limits.as_ref().map(LocalLimits::validate) {
+            findings.error(field(path, "local_limits"), e);
+        }
+        if let Err(e) = self.report_on.validate() {
+            findings.error(field(path, "report_on"), e);
+        }
+
+        // the settings the environments override, staging being served to
+        // hosts apart from those of production
+        let production = self.in_environment(Environment::Production);
+        for (environment, overrides) in &self.environments {
+            let environment_path = field(&field(path, "environments"), environment.as_str());
+            if let Some(ref hosts) = overrides.hosts {
+                if hosts.is_empty() {
+                    findings.error(field(&environment_path, "hosts"), "needs at least one host");
+                }
+            }
+            if *environment == Environment::Staging {
+                match overrides.hosts {
+                    None => findings.error(
+                        field(&environment_path, "hosts"),
+                        "is missing, staging needing hosts apart from those of production",
+                    ),
+                    Some(ref hosts) => {
+                        for (i, host) in hosts.iter().enumerate() {
+                            let shared = production
+                                .hosts
+                                .iter()
+                                .find(|production| conflicts::overlap(host, production));
+                            let message = match shared {
+                                None => continue,
+                                Some(shared) if shared.eq_ignore_ascii_case(host) => {
+                                    format!("'{}' is a host of production too", host)
+                                }
+                                Some(shared) => {
+                                    format!("'{}' overlaps '{}' of production", host, shared)
+                                }
+                            };
+                            findings.error(index(&field(&environment_path, "hosts"), i), message);
+                        }
+                    }
+                }
+            }
+            if let Some(ref target_domain) = overrides.target_domain {
+                let target_path = field(&environment_path, "target_domain");
+                self.validate_target_domain(target_domain, target_path, findings);
+            }
+            for (i, policy) in overrides.policies.iter().flatten().enumerate() {
+                policy.validate(&index(&field(&environment_path, "policies"), i), findings);
+            }
+        }
+    }
 }
 
 impl Service {
-    pub fn oidc_import(&self) -> Option<Result<(JwtAuthentication, Cluster)>> {
+    pub fn builder() -> ServiceBuilder {
+        ServiceBuilder::default()
+    }
+
+    // Every problem with `policies`, at their index in the policies of the
+    // service, and between them, as upstreams routing policies disagree on.
+    fn validate_policies<'a>(
+        &self,
+        policies: impl Iterator<Item = (usize, &'a Policy)>,
+        path: &str,
+        findings: &mut Findings,
+    ) {
+        let mut upstreams = BTreeMap::new();
+        for (i, policy) in policies {
+            let policy_path = index(&field(path, "policies"), i);
+            policy.validate(&policy_path, findings);
+            if let Policy::SecurityHeaders(headers) = policy {
+                if headers.strict_transport_security.is_some() && self.tls.is_none() {
+                    findings.warning(
+                        field(
+                            &field(&policy_path, "configuration"),
+                            "strict_transport_security",
+                        ),
+                        "is left out, the service has no TLS",
+                    );
+                }
+            }
+            // the clusters of upstreams are named after them alone
+            if let Policy::Routing(routing) = policy {
+                for (name, url) in &routing.upstreams {
+                    match upstreams.insert(name, url) {
+                        Some(other) if other != url => findings.error(
+                            field(
+                                &field(&field(&policy_path, "configuration"), "upstreams"),
+                                name,
+                            ),
+                            format!("is '{}' in an earlier routing policy", other),
+                        ),
+                        _ => {}
+                    }
+                }
+            }
+        }
+    }
+
+    // Every problem with `target_url` as the target domain of the service,
+    // at `target_domain`.
+    fn validate_target_domain(
+        &self,
+        target_url: &str,
+        target_domain: std::string::String,
+        findings: &mut Findings,
+    ) {
+        match url::Url::parse(target_url) {
+            _ if self.kind == ServiceKind::DynamicForwardProxy => {
+                if !target_url.is_empty() {
+                    findings.warning(
+                        target_domain,
+                        "is not used, the upstream being the host of each request",
+                    );
+                }
+            }
+            _ if self.kind == ServiceKind::OriginalDst => {
+                if !target_url.is_empty() {
+                    findings.error(
+                            target_domain,
+                            "must be unset, the upstream being the original destination of each connection",
+                        );
+                }
+            }
+            _ if target_url.is_empty() => findings.error(target_domain, "is missing"),
+            Err(e) => findings.error(target_domain, format!("'{}': {}", target_url, e)),
+            Ok(url) if url.scheme() != "http" && url.scheme() != "https" => findings.error(
+                target_domain,
+                format!("scheme must be http or https, not '{}'", url.scheme()),
+            ),
+            Ok(url) if url.host_str().is_none() => {
+                findings.error(target_domain, format!("'{}' has no host", url))
+            }
+            Ok(url) if url.port() == Some(0) => {
+                findings.error(target_domain, "port must be within 1 and 65535")
+            }
+            Ok(_) => {}
+        }
+    }
+
+    /// The filters the listener of the service has Envoy fetch.
+    pub fn wasm_files(&self, wasm: &WasmSettings) -> Vec<std::path::PathBuf> {
+        let mut files: Vec<_> = wasm
+            .module_path(self.wasm_module.as_ref())
+            .ok()
+            .into_iter()
+            .collect();
+        if let Some(ref auth) = self.auth_config {
+            files.push(auth.wasm_path().into());
+        }
+        files
+    }
+
+    pub fn oidc_import(
+        &self,
+        client: &HttpClient,
+        type_urls: &TypeUrls,
+    ) -> Option<Result<(JwtAuthentication, Option<Cluster>)>> {
         self.oidc_issuer.as_ref().map(|oidc_issuer| {
-            let mut oidc_discovery = OIDCConfig::new(oidc_issuer.to_string());
-            oidc_discovery.export(self.id)
+            let mut oidc_discovery = OIDCConfig::new(
+                oidc_issuer.to_string(),
+                self.oidc_discovery.as_ref(),
+                client,
+            );
+            oidc_discovery.export(self.id, type_urls)
         })
     }
 
-    pub fn export(&self) -> Result<Vec<EnvoyExport>> {
-        let mut result: Vec<EnvoyExport> = Vec::new();
-        let cluster = self
-            .export_clusters()
-            .with_context(|| format!("failed to export cluster for service {}", self.id))?;
+    /// The keys of the OIDC issuer the controller inlines in the exports
+    /// of the service, if any.
+    pub fn inline_jwks(&self, client: &HttpClient) -> Option<Result<std::string::String>> {
+        let discovery = self.oidc_discovery.as_ref()?;
+        if discovery.jwks_mode != oidc::JwksMode::Inline {
+            return None;
+        }
+        let issuer = self.oidc_issuer.as_ref()?;
+        Some(OIDCConfig::new(issuer.to_string(), Some(discovery), client).jwks(self.id))
+    }
+
+    /// The service of `value`, found at `path` in its services file, along
+    /// with every problem found in it, the service being there unless some
+    /// problem is an error. Each policy is read on its own, for serde to
+    /// point at every policy it cannot read rather than the first one, and
+    /// the service is validated once read. Fields serde doesn't know, and
+    /// the deprecated problems, are errors when `strict`, and only warnings
+    /// otherwise.
+    pub fn from_value(
+        value: &serde_json::Value,
+        path: &str,
+        strict: bool,
+    ) -> (Option<Service>, Findings) {
+        let id = value.get("id").and_then(serde_json::Value::as_u64);
+        let mut findings = if strict {
+            Findings::strict()
+        } else {
+            Findings::default()
+        };
+        let unknown = if strict {
+            Severity::Error
+        } else {
+            Severity::Warning
+        };
+
+        let mut value = value.clone();
+        let policies_path = field(path, "policies");
+        let mut policies = Vec::new();
+        if let Some(raw) = value.get_mut("policies").and_then(|p| p.as_array_mut()) {
+            for (i, raw) in raw.drain(..).enumerate() {
+                let at = index(&policies_path, i);
+                match crate::field_errors::from_value::<Policy>(&raw, &at) {
+                    Ok(policy) => {
+                        for field in Policy::unknown_fields(&raw, &at) {
+                            findings.push(unknown, FieldError::new(field, "unknown field"));
+                        }
+                        policies.push((i, policy));
+                    }
+                    Err(error) => findings.errors.push(error),
+                }
+            }
+        }
+
+        let service = match crate::field_errors::from_value::<Service>(&value, path) {
+            Ok(mut service) => {
+                for field in crate::field_errors::unknown_fields::<Service>(&value, path) {
+                    findings.push(unknown, FieldError::new(field, "unknown field"));
+                }
+                // the policies read keep their place in the list, and are
+                // checked against each other even when some couldn't be
+                Validate::validate(&service, path, &mut findings);
+                let read = policies.iter().map(|(i, policy)| (*i, policy));
+                service.validate_policies(read, path, &mut findings);
+                service.policies = policies.into_iter().map(|(_, policy)| policy).collect();
+                Some(service).filter(|_| findings.errors.is_empty())
+            }
+            Err(error) => {
+                findings.errors.push(error);
+                None
+            }
+        };
+        (service, findings.of_service(id))
+    }
+
+    /// Check the settings of the service, failing with every problem found.
+    pub fn validate(&self) -> Result<()> {
+        let errors = self.findings("").errors;
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(FieldErrors(errors).into())
+        }
+    }
+
+    /// The subset of the service the wasm filter needs, in its wire format,
+    /// with the controller settings the service doesn't override.
+    pub fn filter_config(&self, wasm: &WasmSettings) -> FilterConfig {
+        FilterConfig {
+            id: self.id,
+            proxy_rules: self
+                .proxy_rules
+                .iter()
+                .map(MappingRules::filter_rule)
+                .collect(),
+            no_match_action: self.no_match_action.clone(),
+            metrics_header: self.metrics_header.clone(),
+            local_limits: self.local_limits.clone(),
+            report_on: self.report_on.clone(),
+            policies: self
+                .policies
+                .iter()
+                .filter_map(Policy::filter_policy)
+                .collect(),
+            annotations: self.annotations.clone(),
+            request_id_in_denials: self.request_id(wasm).in_filter_denials.unwrap_or(false),
+        }
+    }
 
-        result.push(EnvoyExport {
-            key: format!("service::id::{}::cluster", self.id),
-            config: EnvoyResource::Cluster(cluster),
+    // The request id settings of the service, those it doesn't set taken
+    // from the controller.
+    fn request_id(&self, wasm: &WasmSettings) -> RequestId {
+        self.request_id.unwrap_or_default().or(wasm.request_id)
+    }
+
+    #[tracing::instrument(
+        name = "export",
+        skip(self, wasm),
+        fields(service.id = self.id, service.hosts = ?self.hosts)
+    )]
+    pub fn export(&self, wasm: &WasmSettings) -> Result<Vec<EnvoyExport>> {
+        self.validate()
+            .with_context(|| format!("invalid configuration for service {}", self.id))?;
+        if self.kind == ServiceKind::DynamicForwardProxy && !wasm.dynamic_forward_proxy {
+            anyhow::bail!(
+                "service {} is a dynamic forward proxy, which the controller only exports with --allow-dynamic-forward-proxy",
+                self.id
+            );
+        }
+        let original_dst_header = self.original_dst.as_ref().filter(|original_dst| {
+            self.kind == ServiceKind::OriginalDst && original_dst.use_http_header
         });
+        if original_dst_header.is_some() && !wasm.original_dst_header {
+            anyhow::bail!(
+                "service {} takes its upstreams from a header, which the controller only exports with --allow-original-dst-header",
+                self.id
+            );
+        }
+        if self.http3 && !wasm.http3 {
+            anyhow::bail!(
+                "service {} has an HTTP/3 listener, which the controller only exports with --allow-http3",
+                self.id
+            );
+        }
+        if self.environments.is_empty() {
+            return self.export_resources(wasm);
+        }
+
+        // production, then staging, sharing the resources named after
+        // neither, like the clusters of the issuers
+        let mut result = self
+            .in_environment(Environment::Production)
+            .export_resources(wasm)?;
+        if self.environments.contains_key(&Environment::Staging) {
+            let staging = self.in_environment(Environment::Staging);
+            staging.validate().with_context(|| {
+                format!("invalid configuration for service {} in staging", self.id)
+            })?;
+            for export in staging.export_resources(wasm)? {
+                let shared = result.iter().find(|other| {
+                    other.config.type_url() == export.config.type_url()
+                        && other.config.name() == export.config.name()
+                });
+                match shared {
+                    Some(other)
+                        if canonical_hash(&other.config) == canonical_hash(&export.config) => {}
+                    Some(_) => anyhow::bail!(
+                        "service {} exports {} unlike in production in staging",
+                        self.id,
+                        export.config.name()
+                    ),
+                    None => result.push(export),
+                }
+            }
+        }
+        sort_by_key(&mut result)?;
+        Ok(result)
+    }
+
+    /// The service as served in `environment`: with the settings the
+    /// environment overrides, its resources annotated with it. Those of
+    /// staging are named after it, as in `1437_payments_staging`, those of
+    /// production keeping the names of the service.
+    pub fn in_environment(&self, environment: Environment) -> Service {
+        let mut service = self.clone();
+        service.environments = Environments::new();
+        if let Some(overrides) = self.environments.get(&environment) {
+            if let Some(ref hosts) = overrides.hosts {
+                service.hosts = hosts.clone();
+            }
+            if let Some(ref target_domain) = overrides.target_domain {
+                service.target_domain = target_domain.clone();
+            }
+            if let Some(ref policies) = overrides.policies {
+                service.policies = policies.clone();
+            }
+        }
+        if environment == Environment::Staging {
+            service.name = Some(match self.name {
+                Some(ref name) => format!("{}_{}", name, environment),
+                None => environment.to_string(),
+            });
+        }
+        service
+            .annotations
+            .insert(environment::ANNOTATION.to_string(), environment.to_string());
+        service
+    }
 
-        let oidc_envoy_filter = match self.oidc_import() {
+    // The resources of the service, in a single environment.
+    fn export_resources(&self, wasm: &WasmSettings) -> Result<Vec<EnvoyExport>> {
+        let mut result: Vec<EnvoyExport> = Vec::new();
+        let clusters = self
+            .export_clusters(wasm)
+            .with_context(|| format!("failed to export cluster for service {}", self.id))?;
+        for (key, cluster) in clusters {
+            result.push(EnvoyExport {
+                key,
+                config: EnvoyResource::Cluster(cluster),
+            });
+        }
+
+        let oidc_envoy_filter = match self.oidc_import(&wasm.http_client, &wasm.type_urls) {
             Some(oidc_import) => {
                 let (oidc_filter, oidc_cluster) = oidc_import?;
 
-                result.push(EnvoyExport {
-                    key: oidc_cluster.clone().name,
-                    config: EnvoyResource::Cluster(oidc_cluster),
-                });
+                if let Some(oidc_cluster) = oidc_cluster {
+                    result.push(EnvoyExport {
+                        key: oidc_cluster.name.clone(),
+                        config: EnvoyResource::Cluster(oidc_cluster),
+                    });
+                }
 
                 Some(HttpFilter {
-                    name: "envoy.filters.http.jwt_authn".to_string(),
-                    config_type: Some(http_filter::ConfigType::TypedConfig(prost_types::Any {
-                        type_url: "type.googleapis.com/envoy.extensions.filters.http.jwt_authn.v3.JwtAuthentication"
-                            .to_string(),
-                        value: encode(oidc_filter)?,
-                    })),
+                    name: http_filters::JWT_AUTHN.to_string(),
+                    config_type: Some(http_filter::ConfigType::TypedConfig(
+                        wasm.type_urls.pack(&oidc_filter)?,
+                    )),
                 })
             }
             None => None,
@@ -108,168 +1261,2999 @@ impl Service {
         // be optional - we could just extract a trait to provide a cluster(s)
         // and add them here if we wanted to make this code more generic
         if let Some(ref auth_config) = self.auth_config {
-            let auth_cluster = auth_config.cluster()?;
+            let auth_cluster = auth_config.cluster(&wasm.type_urls)?;
             result.push(EnvoyExport {
                 key: auth_cluster.name.clone(),
                 config: EnvoyResource::Cluster(auth_cluster),
             });
         }
 
-        // Listener entries
-        let listener = self
-            .export_listener(oidc_envoy_filter)
+        if let Some(ref tls) = self.tls {
+            if tls.sds {
+                result.push(EnvoyExport {
+                    key: format!("service::id::{}::secret", self.label()),
+                    config: EnvoyResource::Secret(tls.secret(self.id)?),
+                });
+            }
+        }
+
+        // Listener entries, the HTTP/3 one next to the TLS one, and a
+        // second one of the other family of each for dual stack ones,
+        // sharing the filter chains
+        let (listener, http3_listener) = self
+            .export_listeners(oidc_envoy_filter, wasm)
             .with_context(|| format!("failed to export listener for service {}", self.id))?;
-        result.push(EnvoyExport {
-            key: format!("service::id::{}::listener", self.id),
-            config: EnvoyResource::Listener(listener),
-        });
+        let key = format!("service::id::{}::listener", self.label());
+        let mut listeners = vec![(key.clone(), listener)];
+        if let Some(listener) = http3_listener {
+            listeners.push((format!("{}::http3", key), listener));
+        }
+        for (key, listener) in listeners {
+            for address in self.listener_addresses(wasm).into_iter().skip(1) {
+                let family = if address.is_ipv6() { "ipv6" } else { "ipv4" };
+                let mut other = listener.clone();
+                other.name = format!("{} {}", listener.name, family);
+                // the protocol and port are those of the listener
+                if let Some(AddressType::SocketAddress(ref mut socket)) = other
+                    .address
+                    .as_mut()
+                    .and_then(|other| other.address.as_mut())
+                {
+                    socket.address = address.to_string();
+                }
+                result.push(EnvoyExport {
+                    key: format!("{}::{}", key, family),
+                    config: EnvoyResource::Listener(other),
+                });
+            }
+            result.push(EnvoyExport {
+                key,
+                config: EnvoyResource::Listener(listener),
+            });
+        }
 
+        sort_by_key(&mut result)?;
         Ok(result)
     }
 
+    /// What the resources of the service are named after: its id, followed
+    /// by its name when it has one, as in `1437_payments`. The id keeps the
+    /// names of services apart, and renaming a service renames its
+    /// resources, the old ones being removed.
+    pub fn label(&self) -> std::string::String {
+        match self.name {
+            Some(ref name) => format!("{}_{}", self.id, name),
+            None => self.id.to_string(),
+        }
+    }
+
     fn cluster_name(&self) -> std::string::String {
-        return format!("Cluster::service::{}", self.id);
+        format!("Cluster::service::{}", self.label())
     }
 
-    fn export_clusters(&self) -> Result<Cluster> {
-        get_envoy_cluster(self.cluster_name(), self.target_domain.clone())
+    /// The upstreams the routing policies take requests to, by name, with
+    /// their URLs.
+    fn upstreams(&self) -> BTreeMap<&str, &str> {
+        self.policies
+            .iter()
+            .filter_map(|policy| match policy {
+                Policy::Routing(routing) => Some(routing.referenced()),
+                _ => None,
+            })
+            .flatten()
+            .collect()
     }
 
-    fn export_listener(&self, http_filter: Option<HttpFilter>) -> Result<Listener> {
-        let mut filters = Vec::new();
+    /// The upstreams the mapping rules take their requests to rather than
+    /// the target domain, each once whatever the way its URL is written, in
+    /// the order of the rules, with the names of their clusters.
+    fn rule_upstreams(&self) -> Vec<(&str, std::string::String)> {
+        let mut upstreams: Vec<&str> = Vec::new();
+        // dynamic forward proxies route by host alone, and original
+        // destination services to where each connection was sent
+        let rules = self
+            .proxy_rules
+            .iter()
+            .filter(|_| self.kind == ServiceKind::Api);
+        for url in rules.filter_map(|rule| rule.upstream.as_deref()) {
+            let known = |other: &&str| same_url(url, other);
+            if !same_url(url, &self.target_domain) && !upstreams.iter().any(known) {
+                upstreams.push(url);
+            }
+        }
+        upstreams
+            .into_iter()
+            .enumerate()
+            .map(|(i, url)| (url, format!("{}::rule::{}", self.cluster_name(), i)))
+            .collect()
+    }
 
-        let config = prost_types::Any {
-            type_url: "type.googleapis.com/envoy.extensions.filters.http.router.v3.Router"
-                .to_string(),
-            value: encode(Router {
-                ..Default::default()
-            })?,
+    // The cluster of the target domain, then one for each upstream of the
+    // routing policies and of the mapping rules, with their keys.
+    fn export_clusters(&self, wasm: &WasmSettings) -> Result<Vec<(std::string::String, Cluster)>> {
+        let key = format!("service::id::{}::cluster", self.label());
+        let cluster = |name, url: &str| -> Result<Cluster> {
+            let mut cluster = get_envoy_cluster(name, url, &wasm.type_urls)?;
+            if wasm.metadata {
+                let id = self.id.to_string();
+                cluster.metadata = Some(metadata::metadata(&[
+                    ("service_id", &id),
+                    ("target_domain", url),
+                ]));
+            }
+            Ok(cluster)
         };
-
-        // WASM section, @TODO move out to a new method
-        let wasm_filter = Wasm {
-            config: Some(PluginConfig {
-                name: format!("Service::{:?}", self.id),
-                root_id: format!("Service::{:?}", self.id),
-                vm: Some(Vm::VmConfig(VmConfig {
-                    vm_id: format!("Service::{:?}", self.id),
-                    runtime: "envoy.wasm.runtime.v8".to_string(),
-                    configuration: Some(prost_types::Any {
-                        type_url: "type.googleapis.com/google.protobuf.StringValue".to_string(),
-                        value: encode(serde_json::to_string(&self.clone())?)?,
-                    }),
-                    code: Some(AsyncDataSource {
-                        specifier: Some(Specifier::Remote(RemoteDataSource {
-                            http_uri: Some(HttpUri {
-                                uri: format!("http://control-plane-main:5001/{}", WASM_FILTER_PATH),
-                                timeout: Some(Duration {
-                                    seconds: 100,
-                                    nanos: 0,
-                                }),
-                                http_upstream_type: Some(HttpUpstreamType::Cluster(
-                                    "wasm_files".to_string(),
-                                )),
-                            }),
-                            sha256: Self::get_wasm_filter_sha(WASM_FILTER_PATH)
-                                .context("could not compute SHA-256")?,
+        let upstream = match self.dynamic_forward_proxy() {
+            Some(proxy) => {
+                let mut cluster =
+                    proxy.cluster(self.cluster_name(), &self.dns_cache(), &wasm.type_urls)?;
+                if wasm.metadata {
+                    let id = self.id.to_string();
+                    cluster.metadata = Some(metadata::metadata(&[("service_id", &id)]));
+                }
+                cluster
+            }
+            None if self.kind == ServiceKind::OriginalDst => {
+                let mut cluster = self
+                    .original_dst
+                    .clone()
+                    .unwrap_or_default()
+                    .cluster(self.cluster_name());
+                if wasm.metadata {
+                    let id = self.id.to_string();
+                    cluster.metadata = Some(metadata::metadata(&[("service_id", &id)]));
+                }
+                cluster
+            }
+            None => cluster(self.cluster_name(), &self.target_domain)?,
+        };
+        let mut clusters = vec![(key.clone(), upstream)];
+        for (name, url) in self.upstreams() {
+            let cluster_name = routing::cluster_name(&self.cluster_name(), name);
+            clusters.push((format!("{}::{}", key, name), cluster(cluster_name, url)?));
+        }
+        for (i, (url, cluster_name)) in self.rule_upstreams().into_iter().enumerate() {
+            clusters.push((format!("{}::rule::{}", key, i), cluster(cluster_name, url)?));
+        }
+        for (_, cluster) in &mut clusters {
+            metadata::annotate(&mut cluster.metadata, &self.annotations);
+        }
+        Ok(clusters)
+    }
+
+    /// The settings of the service when a dynamic forward proxy.
+    fn dynamic_forward_proxy(&self) -> Option<&ForwardProxy> {
+        self.forward_proxy
+            .as_ref()
+            .filter(|_| self.kind == ServiceKind::DynamicForwardProxy)
+    }
+
+    // The DNS cache of a dynamic forward proxy, its filter and cluster
+    // sharing it.
+    fn dns_cache(&self) -> std::string::String {
+        format!("service_{}_dns_cache", self.label())
+    }
+
+    /// The metadata of the routes of the service, naming the metric and the
+    /// pattern of the mapping `rule` of the route, if any.
+    fn route_metadata(&self, rule: Option<&MappingRules>) -> Metadata {
+        let id = self.id.to_string();
+        let mut fields = vec![("service_id", id.as_str())];
+        if let Some(rule) = rule {
+            fields.push(("metric_system_name", &rule.metric_system_name));
+            fields.push(("pattern", &rule.pattern));
+        }
+        metadata::metadata(&fields)
+    }
+
+    /// Whether the service has mapping rules beyond the first `max`, which
+    /// get no virtual cluster.
+    pub fn has_rules_beyond(&self, max: usize) -> bool {
+        max > 0 && self.proxy_rules.len() > max
+    }
+
+    /// A virtual cluster for each of the first `max` mapping rules, named
+    /// after its metric, for Envoy to keep stats of the requests of each
+    /// metric. Envoy counting a request in the first virtual cluster
+    /// matching it, they go from the most specific rule down, and match
+    /// the method and path of their rule alone.
+    fn virtual_clusters(&self, max: usize) -> Vec<VirtualCluster> {
+        let mut rules: Vec<_> = self.proxy_rules.iter().take(max).collect();
+        rules.sort_by_key(|rule| Reverse(rule_patterns::specificity(&rule.pattern)));
+        rules
+            .into_iter()
+            .map(|rule| {
+                // the `:path` header has the query string of the request
+                let path = format!(
+                    "(?:{})(?:\\?.*)?",
+                    rule_patterns::loose_regex(&rule.pattern)
+                );
+                VirtualCluster {
+                    name: rule.metric_system_name.clone(),
+                    headers: vec![
+                        MappingRules::method_matcher(&[&rule.http_method]),
+                        HeaderMatcher {
+                            name: ":path".to_string(),
+                            header_match_specifier: Some(HeaderMatchSpecifier::SafeRegexMatch(
+                                rule_patterns::re2(path),
+                            )),
                             ..Default::default()
-                        })),
-                    }),
+                        },
+                    ],
+                }
+            })
+            .collect()
+    }
+
+    #[cfg(test)]
+    fn export_listener(
+        &self,
+        http_filter: Option<HttpFilter>,
+        wasm: &WasmSettings,
+    ) -> Result<Listener> {
+        Ok(self.export_listeners(http_filter, wasm)?.0)
+    }
+
+    // The listener of the service, and its HTTP/3 one if any.
+    fn export_listeners(
+        &self,
+        http_filter: Option<HttpFilter>,
+        wasm: &WasmSettings,
+    ) -> Result<(Listener, Option<Listener>)> {
+        let request_id = self.request_id(wasm);
+
+        let config = wasm.type_urls.pack(&Router {
+            ..Default::default()
+        })?;
+
+        // WASM section, @TODO move out to a new method
+        let filter_path = wasm.module_path(self.wasm_module.as_ref())?;
+        let filter_config = self.filter_config(wasm);
+        // the filters missing in development are left out, see
+        // `Config::readiness`
+        let wasm_filter = if wasm.runs_filter(&filter_path) {
+            Some(Wasm {
+                config: Some(PluginConfig {
+                    name: format!("Service::{}", self.label()),
+                    root_id: format!("Service::{:?}", self.id),
+                    vm: Some(Vm::VmConfig(VmConfig {
+                        vm_id: format!("Service::{:?}", self.id),
+                        runtime: "envoy.wasm.runtime.v8".to_string(),
+                        configuration: Some(
+                            wasm.type_urls
+                                .pack(&json_to_struct(serde_json::to_value(filter_config)?)?)?,
+                        ),
+                        code: Some(AsyncDataSource {
+                            specifier: Some(Specifier::Remote(RemoteDataSource {
+                                http_uri: Some(HttpUri {
+                                    uri: wasm.filter_url(&filter_path),
+                                    timeout: Some(Duration {
+                                        seconds: 100,
+                                        nanos: 0,
+                                    }),
+                                    http_upstream_type: Some(HttpUpstreamType::Cluster(
+                                        WASM_FILES_CLUSTER.to_string(),
+                                    )),
+                                }),
+                                sha256: wasm.sha256(&filter_path)?,
+                                ..Default::default()
+                            })),
+                        }),
+                        ..Default::default()
+                    })),
+                    ..Default::default()
+                }),
+            })
+        } else {
+            None
+        };
+
+        let mut virtual_host = VirtualHost {
+            name: format!("service_{}_vhost", self.label()),
+            domains: self.hosts.clone(),
+            routes: vec![Route {
+                r#match: Some(RouteMatch {
+                    path_specifier: Some(PathSpecifier::Prefix("/".to_string())),
+                    ..Default::default()
+                }),
+                action: Some(Action::Route(RouteAction {
+                    cluster_specifier: Some(ClusterSpecifier::Cluster(self.cluster_name())),
                     ..Default::default()
                 })),
                 ..Default::default()
-            }),
+            }],
+            virtual_clusters: self.virtual_clusters(wasm.virtual_clusters),
+            ..Default::default()
         };
-
+        // the policies come first, refusing requests before they are
+        // authenticated and reported
         let mut http_filters = Vec::new();
+        let stat_prefix = format!("service_{}", self.label());
+        for policy in &self.policies {
+            policy
+                .apply(
+                    &mut virtual_host,
+                    &mut http_filters,
+                    &stat_prefix,
+                    &wasm.type_urls,
+                    self.tls.is_some(),
+                )
+                .with_context(|| format!("cannot apply policy '{}'", policy.name()))?;
+        }
+        // ahead of the one catching every path, matching the requests the
+        // filter reports for the rule, for every rule or for those skipping
+        // filters, but for dynamic forward proxies routing by host alone,
+        // the most specific first for none to shadow another
+        if let Some(catch_all) = virtual_host.routes.pop() {
+            let mut rules: Vec<_> = self
+                .proxy_rules
+                .iter()
+                .filter(|_| self.kind != ServiceKind::DynamicForwardProxy)
+                .filter(|rule| self.rule_routes || rule.needs_route())
+                .collect();
+            rules.sort_by_key(|rule| Reverse(rule_patterns::specificity(&rule.pattern)));
+            let upstreams = self.rule_upstreams();
+            for rule in rules {
+                let mut route = rule.route(&catch_all, &wasm.type_urls)?;
+                let upstream = rule.upstream.as_deref().and_then(|url| {
+                    upstreams
+                        .iter()
+                        .find(|(upstream, _)| same_url(upstream, url))
+                });
+                if let (Some((_, cluster)), Some(Action::Route(ref mut action))) =
+                    (upstream, &mut route.action)
+                {
+                    action.cluster_specifier = Some(ClusterSpecifier::Cluster(cluster.clone()));
+                }
+                if wasm.metadata {
+                    route.metadata = Some(self.route_metadata(Some(rule)));
+                }
+                virtual_host.routes.push(route);
+            }
+            virtual_host.routes.push(catch_all);
+        }
+        // the requests to the allowed domains alone reach the proxy, by any
+        // of the routes, those of the policies too
+        if let Some(proxy) = self.dynamic_forward_proxy() {
+            let route_config = proxy.route_config(&wasm.type_urls)?;
+            for route in &mut virtual_host.routes {
+                if let Some(ref mut matched) = route.r#match {
+                    matched.headers.push(proxy.destination_matcher());
+                }
+                route.typed_per_filter_config.extend(route_config.clone());
+            }
+        }
+        // and those naming an allowed destination, for original
+        // destinations taken from the header
+        let destination_matcher = self
+            .original_dst
+            .as_ref()
+            .filter(|_| self.kind == ServiceKind::OriginalDst)
+            .and_then(OriginalDst::destination_matcher);
+        if let Some(ref matcher) = destination_matcher {
+            for route in &mut virtual_host.routes {
+                if let Some(ref mut matched) = route.r#match {
+                    matched.headers.push(matcher.clone());
+                }
+            }
+        }
+        if wasm.metadata {
+            for route in &mut virtual_host.routes {
+                if route.metadata.is_none() {
+                    route.metadata = Some(self.route_metadata(None));
+                }
+            }
+        }
+        if let Some(ref forwarded) = self.forwarded {
+            forwarded.apply_to_host(&mut virtual_host);
+        }
         if let Some(filter) = http_filter {
             http_filters.push(filter);
         }
 
+        // a service authorizing its requests is never exported open
         if let Some(ref threescale_auth) = self.auth_config {
+            let path = threescale_auth.wasm_path();
+            if !wasm.skip_sha && !path.exists() {
+                anyhow::bail!(
+                    "3scale auth filter {} is missing, the service is not exported without it",
+                    path.display()
+                );
+            }
             http_filters.push(HttpFilter {
-                name: "envoy.filters.http.wasm".to_string(),
-                config_type: Some(http_filter::ConfigType::TypedConfig(prost_types::Any {
-                    type_url: "type.googleapis.com/envoy.extensions.filters.http.wasm.v3.Wasm"
-                        .to_string(),
-                    value: encode(threescale_auth.build_wasm(self.id)?)?,
-                })),
+                name: http_filters::THREESCALE_AUTH.to_string(),
+                config_type: Some(http_filter::ConfigType::TypedConfig(
+                    wasm.type_urls
+                        .pack(&threescale_auth.build_wasm(self.id, wasm)?)?,
+                )),
             });
         }
 
-        http_filters.push(HttpFilter {
-            name: "envoy.filters.http.wasm".to_string(),
-            config_type: Some(http_filter::ConfigType::TypedConfig(prost_types::Any {
-                type_url: "type.googleapis.com/envoy.extensions.filters.http.wasm.v3.Wasm"
-                    .to_string(),
-                value: encode(wasm_filter)?,
-            })),
-        });
+        if let Some(wasm_filter) = wasm_filter {
+            http_filters.push(HttpFilter {
+                name: http_filters::WASM.to_string(),
+                config_type: Some(http_filter::ConfigType::TypedConfig(
+                    wasm.type_urls.pack(&wasm_filter)?,
+                )),
+            });
+        }
+        if let Some(proxy) = self.dynamic_forward_proxy() {
+            http_filters.push(proxy.filter(&self.dns_cache(), &wasm.type_urls)?);
+        }
 
+        // Envoy answers every request by itself in maintenance, nothing to
+        // authorize or report
+        if self.policies.iter().any(Policy::is_total_maintenance) {
+            http_filters.clear();
+        }
         http_filters.push(HttpFilter {
-            name: "envoy.filters.http.router".to_string(),
+            name: http_filters::ROUTER.to_string(),
             config_type: Some(http_filter::ConfigType::TypedConfig(config)),
         });
+        for route in &mut virtual_host.routes {
+            http_filters::retain_present(&mut route.typed_per_filter_config, &http_filters);
+        }
+        // the metrics header a client sends never reaches the upstream: the
+        // filter strips it, and the requests the filter is off for go with
+        // it removed, all of them without the filter. Envoy removes the
+        // headers of the virtual host once the filters ran, which would
+        // take the metrics the filter forwards along.
+        if let Some(ref header) = self.metrics_header {
+            let filtered = http_filters
+                .iter()
+                .any(|filter| filter.name == http_filters::WASM);
+            if !filtered {
+                virtual_host
+                    .request_headers_to_remove
+                    .push(header.name.clone());
+            } else {
+                for route in &mut virtual_host.routes {
+                    if route
+                        .typed_per_filter_config
+                        .contains_key(http_filters::WASM)
+                    {
+                        route.request_headers_to_remove.push(header.name.clone());
+                    }
+                }
+            }
+        }
 
-        let connection_manager = HttpConnectionManager {
+        let mut connection_manager = HttpConnectionManager {
             stat_prefix: "ingress_http".to_string(),
             codec_type: 0,
             http_filters,
             route_specifier: Some(RouteSpecifier::RouteConfig(RouteConfiguration {
-                name: format!("service_{:?}_route", self.id),
-                virtual_hosts: vec![VirtualHost {
-                    name: format!("service_{:?}_vhost", self.id),
-                    domains: self.hosts.clone(),
-                    routes: vec![Route {
-                        r#match: Some(RouteMatch {
-                            path_specifier: Some(PathSpecifier::Prefix("/".to_string())),
+                name: format!("service_{}_route", self.label()),
+                virtual_hosts: vec![virtual_host],
+                // the requests to the upstreams carry the identity of the
+                // controller, as its own do
+                request_headers_to_add: wasm
+                    .http_client
+                    .identity
+                    .request_header()
+                    .into_iter()
+                    .collect(),
+                // the header picking the upstream is only taken from the
+                // internal requests, as those of a sidecar, the others
+                // having it stripped
+                internal_only_headers: destination_matcher
+                    .iter()
+                    .map(|_| original_dst::HEADER.to_string())
+                    .collect(),
+                ..Default::default()
+            })),
+            ..Default::default()
+        };
+        request_id.apply(&mut connection_manager, &wasm.type_urls)?;
+        if let Some(ref forwarded) = self.forwarded {
+            forwarded.apply(&mut connection_manager);
+        }
+        self.header_options
+            .clone()
+            .unwrap_or_default()
+            .or(&wasm.header_options)
+            .apply(&mut connection_manager);
+
+        // the HTTP/3 listener has the routes of the TLS one, whose clients
+        // learn of it by the `alt-svc` header of the responses
+        let http3_manager = match self.tls {
+            Some(_) if self.http3 => {
+                let mut manager = connection_manager.clone();
+                manager.codec_type = CodecType::Http3 as i32;
+                manager.http3_protocol_options = Some(Http3ProtocolOptions::default());
+                if let Some(RouteSpecifier::RouteConfig(ref mut routes)) =
+                    connection_manager.route_specifier
+                {
+                    routes.response_headers_to_add.push(HeaderValueOption {
+                        header: Some(HeaderValue {
+                            key: "alt-svc".to_string(),
+                            value: format!(
+                                "h3=\":{}\"; ma={}",
+                                self.listener_port(),
+                                ALT_SVC_MAX_AGE
+                            ),
                             ..Default::default()
                         }),
-                        action: Some(Action::Route(RouteAction {
-                            cluster_specifier: Some(ClusterSpecifier::Cluster(self.cluster_name())),
-                            ..Default::default()
-                        })),
+                        append: Some(false),
+                        ..Default::default()
+                    });
+                }
+                Some(manager)
+            }
+            _ => None,
+        };
+
+        let address = match self.listener_addresses(wasm).first() {
+            Some(address) => *address,
+            None => anyhow::bail!("the listener has no address"),
+        };
+        let mut metadata = None;
+        metadata::annotate(&mut metadata, &self.annotations);
+        let filters = |manager: &HttpConnectionManager| -> Result<Vec<Filter>> {
+            Ok(vec![Filter {
+                name: "envoy.filters.network.http_connection_manager".to_string(),
+                config_type: Some(ConfigType::TypedConfig(wasm.type_urls.pack(manager)?)),
+            }])
+        };
+
+        let listener_filters = match self.kind {
+            ServiceKind::OriginalDst => vec![OriginalDst::listener_filter(&wasm.type_urls)?],
+            _ => Vec::new(),
+        };
+        let listener = Listener {
+            name: format!("service {}", self.label()),
+            listener_filters,
+            address: Some(Service::listener_socket(address, self.listener_port())),
+            filter_chains: vec![FilterChain {
+                filters: filters(&connection_manager)?,
+                transport_socket: match self.tls {
+                    Some(ref tls) => Some(tls.transport_socket(self.id, &wasm.type_urls)?),
+                    None => None,
+                },
+                ..Default::default()
+            }],
+            metadata: metadata.clone(),
+            ..Default::default()
+        };
+        let http3_listener = match (&self.tls, http3_manager) {
+            (Some(tls), Some(manager)) => {
+                let mut address = Service::listener_socket(address, self.listener_port());
+                if let Some(AddressType::SocketAddress(ref mut socket)) = address.address {
+                    socket.protocol = Protocol::Udp as i32;
+                }
+                Some(Listener {
+                    name: format!("service {} http3", self.label()),
+                    address: Some(address),
+                    filter_chains: vec![FilterChain {
+                        filters: filters(&manager)?,
+                        transport_socket: Some(
+                            tls.quic_transport_socket(self.id, &wasm.type_urls)?,
+                        ),
                         ..Default::default()
                     }],
+                    udp_listener_config: Some(UdpListenerConfig {
+                        quic_options: Some(QuicProtocolOptions::default()),
+                        ..Default::default()
+                    }),
+                    metadata,
                     ..Default::default()
-                }],
+                })
+            }
+            _ => None,
+        };
+        Ok((listener, http3_listener))
+    }
+
+    // The addresses the listeners of the service bind, the first one being
+    // that of the listener of the service.
+    fn listener_addresses(&self, wasm: &WasmSettings) -> Vec<IpAddr> {
+        self.listener_address
+            .as_ref()
+            .unwrap_or(&wasm.listener_address)
+            .addresses()
+    }
+
+    fn listener_port(&self) -> u32 {
+        match self.tls {
+            Some(_) => 443,
+            None => 80,
+        }
+    }
+
+    fn listener_socket(address: IpAddr, port: u32) -> Address {
+        Address {
+            address: Some(AddressType::SocketAddress(SocketAddress {
+                address: address.to_string(),
+                port_specifier: Some(PortSpecifier::PortValue(port)),
                 ..Default::default()
             })),
+        }
+    }
+
+    /// The digest of the filter at `path`, in lowercase hex. Envoy only
+    /// checks SHA-256 ones.
+    pub fn get_wasm_filter_sha(
+        path: impl AsRef<Path>,
+        algorithm: DigestAlgorithm,
+    ) -> Result<std::string::String> {
+        let path = path.as_ref();
+        let result = util::file_utils::file_digest_blocking(algorithm, path.to_path_buf())
+            .with_context(|| format!("failed to hash wasm filter: {}", path.display()))?;
+        Ok(HEXUPPER.encode(result.as_ref()).to_lowercase())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::envoy_helpers::encode;
+
+    fn service(extra: &str) -> Service {
+        let config = format!(
+            r#"{{
+                "id": 1,
+                "hosts": ["web.app"],
+                "policies": [],
+                "target_domain": "http://web.app:80",
+                "proxy_rules": [
+                    {{"pattern": "/", "http_method": "GET", "metric_system_name": "hits", "delta": 1}},
+                    {{"pattern": "/ticks", "http_method": "GET", "metric_system_name": "ticks", "delta": 2}}
+                ]
+                {}
+            }}"#,
+            extra
+        );
+        serde_json::from_str(config.as_str()).unwrap()
+    }
+
+    #[test]
+    fn metrics_header_is_disabled_by_default() {
+        let service = service("");
+        assert!(service.metrics_header.is_none());
+        assert!(service
+            .filter_config(&Default::default())
+            .metrics_header
+            .is_none());
+    }
+
+    #[test]
+    fn metrics_headers_are_removed_where_the_filter_is_off() {
+        use prost::Message;
+
+        let mut service = service(r#", "metrics_header": {"name": "x-3scale-metrics"}"#);
+        let mut health = MappingRules::new("/health$".into(), "GET".into(), "health".into(), 0);
+        health.skip_wasm = true;
+        service.proxy_rules.push(health);
+        let removed = |manager: HttpConnectionManager| {
+            let host = match manager.route_specifier {
+                Some(RouteSpecifier::RouteConfig(mut config)) => config.virtual_hosts.remove(0),
+                specifier => panic!("{:?}", specifier),
+            };
+            let routes: Vec<_> = host
+                .routes
+                .into_iter()
+                .map(|route| (route.name, route.request_headers_to_remove))
+                .collect();
+            (host.request_headers_to_remove, routes)
+        };
+
+        // the filter strips it, but for the rule skipping the filter
+        let (host, routes) = removed(connection_manager(&service));
+        assert!(host.is_empty());
+        assert_eq!(
+            routes,
+            [
+                (
+                    "GET /health$ → health +0".to_string(),
+                    vec!["x-3scale-metrics".to_string()]
+                ),
+                (std::string::String::new(), vec![])
+            ]
+        );
+
+        // a service exported without the filter
+        let dir = tempfile::tempdir().unwrap();
+        let optional = WasmSettings {
+            filter_path: dir.path().join("filter.wasm"),
+            wasm_required: false,
             ..Default::default()
         };
+        let listener = service.export_listener(None, &optional).unwrap();
+        let manager = match listener.filter_chains[0].filters[0].config_type {
+            Some(ConfigType::TypedConfig(ref any)) => {
+                HttpConnectionManager::decode(any.value.as_slice()).unwrap()
+            }
+            ref config => panic!("{:?}", config),
+        };
+        let (host, routes) = removed(manager);
+        assert_eq!(host, ["x-3scale-metrics"]);
+        assert!(routes.iter().all(|(_, removed)| removed.is_empty()));
+    }
+
+    #[test]
+    fn unmatched_requests_reach_the_filter() {
+        let mut service = service("");
+        let mut health = MappingRules::new("/health$".into(), "GET".into(), "health".into(), 0);
+        health.skip_wasm = true;
+        service.proxy_rules.push(health);
+        service.rule_routes = true;
+
+        let manager = connection_manager(&service);
+        assert!(manager
+            .http_filters
+            .iter()
+            .any(|filter| filter.name == http_filters::WASM));
+        let routes = match manager.route_specifier {
+            Some(RouteSpecifier::RouteConfig(config)) => config.virtual_hosts[0].routes.clone(),
+            specifier => panic!("{:?}", specifier),
+        };
+        // the routes of the rules first, then the one catching every other
+        // path, the filter on, so that the no-match action is the only one
+        let (catch_all, rules) = routes.split_last().unwrap();
+        assert_eq!(rules.len(), service.proxy_rules.len());
+        assert!(rules.iter().all(|route| !route.name.is_empty()));
+        let catch_all_match = catch_all.r#match.as_ref().unwrap();
+        assert_eq!(
+            catch_all_match.path_specifier,
+            Some(PathSpecifier::Prefix("/".to_string()))
+        );
+        assert!(catch_all_match.headers.is_empty());
+        assert!(catch_all.typed_per_filter_config.is_empty());
+    }
+
+    #[test]
+    fn invalid_filter_settings_fail_validation() {
+        let deny_ok = service(r#", "no_match_action": {"action": "deny", "status": 200}"#);
+        assert!(deny_ok.validate().is_err());
+
+        let pseudo_header = service(r#", "metrics_header": {"name": ":path"}"#);
+        assert!(pseudo_header.validate().is_err());
+    }
+
+    #[test]
+    fn filter_config_round_trip() {
+        let service = service(
+            r#", "no_match_action": {"action": "report_default_metric", "metric": "no_match"},
+                "metrics_header": {"name": "x-3scale-metrics", "format": "comma_separated"},
+                "report_on": "successful_only""#,
+        );
+        let expected = service.filter_config(&Default::default());
+
+        // same serialization export_listener embeds into the PluginConfig
+        let wire = serde_json::to_string(&expected).unwrap();
+        let imported: FilterConfig = serde_json::from_str(wire.as_str()).unwrap();
+
+        assert_eq!(imported, expected);
+        assert_eq!(imported.id, 1);
+        assert_eq!(imported.proxy_rules.len(), 2);
+        assert_eq!(imported.proxy_rules[1].metric_system_name, "ticks");
+    }
+
+    // Render a Struct as JSON the way Envoy hands it over to the filter.
+    fn struct_to_json(value: &prost_types::Value) -> serde_json::Value {
+        use prost_types::value::Kind;
+        match value.kind.as_ref().unwrap() {
+            Kind::NullValue(_) => serde_json::Value::Null,
+            Kind::BoolValue(b) => serde_json::Value::Bool(*b),
+            Kind::NumberValue(n) if n.fract() == 0.0 => serde_json::json!(*n as i64),
+            Kind::NumberValue(n) => serde_json::json!(*n),
+            Kind::StringValue(s) => serde_json::Value::String(s.clone()),
+            Kind::ListValue(list) => list.values.iter().map(struct_to_json).collect(),
+            Kind::StructValue(s) => serde_json::Value::Object(
+                s.fields
+                    .iter()
+                    .map(|(k, v)| (k.clone(), struct_to_json(v)))
+                    .collect(),
+            ),
+        }
+    }
+
+    #[test]
+    fn filter_config_struct_round_trip() {
+        let service = service(
+            r#", "no_match_action": {"action": "deny", "status": 404, "body": "Ningún método — 未找到"}"#,
+        );
+        let expected = service.filter_config(&Default::default());
+
+        let config = json_to_struct(serde_json::to_value(&expected).unwrap()).unwrap();
+        let bytes = encode(&config).unwrap();
+        let config: prost_types::Struct = prost::Message::decode(bytes.as_slice()).unwrap();
+        let rendered = struct_to_json(&prost_types::Value {
+            kind: Some(prost_types::value::Kind::StructValue(config)),
+        });
 
-        filters.push(Filter {
-            name: "envoy.filters.network.http_connection_manager".to_string(),
-            config_type: Some(ConfigType::TypedConfig(
-              prost_types::Any {
-                type_url: "type.googleapis.com/envoy.extensions.filters.network.http_connection_manager.v3.HttpConnectionManager".to_string(),
-                value: encode(connection_manager)?,
-              }))
+        let imported = FilterConfig::from_bytes(rendered.to_string().as_bytes()).unwrap();
+        assert_eq!(imported, expected);
+    }
+
+    #[test]
+    fn custom_policies_pass_through_to_the_filter() {
+        let mut service = service("");
+        service.policies = serde_json::from_value(serde_json::json!([
+            {"name": "soap", "configuration": {"rules": [{"pattern": "/soap", "delta": 2}]}},
+            "cors",
+            "liquid_context_debug",
+            {"name": "camel", "configuration": {"https_proxy": "http://proxy:8080", "all_proxy": null}},
+        ]))
+        .unwrap();
+
+        let config = json_to_struct(
+            serde_json::to_value(service.filter_config(&Default::default())).unwrap(),
+        )
+        .unwrap();
+        let bytes = encode(&config).unwrap();
+        let config: prost_types::Struct = prost::Message::decode(bytes.as_slice()).unwrap();
+        let rendered = struct_to_json(&prost_types::Value {
+            kind: Some(prost_types::value::Kind::StructValue(config)),
         });
+        let imported = FilterConfig::from_bytes(rendered.to_string().as_bytes()).unwrap();
+
+        // the ones Envoy applies left out, the others in their order
+        let policies: Vec<_> = imported
+            .policies
+            .iter()
+            .map(|policy| (policy.name.as_str(), policy.configuration.clone()))
+            .collect();
+        assert_eq!(
+            policies,
+            [
+                (
+                    "soap",
+                    serde_json::json!({"rules": [{"pattern": "/soap", "delta": 2}]})
+                ),
+                ("liquid_context_debug", serde_json::Value::Null),
+                (
+                    "camel",
+                    serde_json::json!({"https_proxy": "http://proxy:8080", "all_proxy": null})
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn non_objects_are_not_structs() {
+        assert!(json_to_struct(serde_json::json!(["a", 1])).is_err());
+        let nested = json_to_struct(serde_json::json!({"a": {"b": [1, null, true]}})).unwrap();
+        assert_eq!(nested.fields.len(), 1);
+    }
+
+    // Records the fields of every span created, as `name: field=value ...`.
+    #[derive(Clone, Default)]
+    struct Spans(std::sync::Arc<std::sync::Mutex<Vec<std::string::String>>>);
+
+    impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for Spans {
+        fn new_span(
+            &self,
+            attrs: &tracing::span::Attributes<'_>,
+            _: &tracing::span::Id,
+            _: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            let mut span = format!("{}:", attrs.metadata().name());
+            attrs.record(
+                &mut |field: &tracing::field::Field, value: &dyn std::fmt::Debug| {
+                    span.push_str(&format!(" {}={:?}", field, value));
+                },
+            );
+            self.0.lock().unwrap().push(span);
+        }
+    }
+
+    // The connection manager of the listener of `service`.
+    fn connection_manager(service: &Service) -> HttpConnectionManager {
+        exported_connection_manager(service, &WasmSettings::default())
+    }
+
+    fn exported_connection_manager(
+        service: &Service,
+        wasm: &WasmSettings,
+    ) -> HttpConnectionManager {
+        use prost::Message;
+
+        let wasm = WasmSettings {
+            skip_sha: true,
+            ..wasm.clone()
+        };
+        let listener = service.export_listener(None, &wasm).unwrap();
+        match listener.filter_chains[0].filters[0].config_type {
+            Some(ConfigType::TypedConfig(ref any)) => {
+                HttpConnectionManager::decode(any.value.as_slice()).unwrap()
+            }
+            ref config => panic!("{:?}", config),
+        }
+    }
+
+    // Names of the HTTP filters of the listener of `service`.
+    fn http_filters(service: &Service) -> Vec<std::string::String> {
+        connection_manager(service)
+            .http_filters
+            .into_iter()
+            .map(|filter| filter.name)
+            .collect()
+    }
+
+    #[test]
+    fn missing_filters_fail_the_exports_unless_optional() {
+        use prost::Message;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("filter.wasm");
+        let filters = |wasm: &WasmSettings| -> Result<Vec<std::string::String>> {
+            let listener = service("").export_listener(None, wasm)?;
+            let manager = match listener.filter_chains[0].filters[0].config_type {
+                Some(ConfigType::TypedConfig(ref any)) => {
+                    HttpConnectionManager::decode(any.value.as_slice()).unwrap()
+                }
+                ref config => panic!("{:?}", config),
+            };
+            Ok(manager.http_filters.into_iter().map(|f| f.name).collect())
+        };
+        let required = WasmSettings {
+            filter_path: path.clone(),
+            ..Default::default()
+        };
+        let optional = WasmSettings {
+            wasm_required: false,
+            ..required.clone()
+        };
+
+        let error = format!("{:#}", filters(&required).unwrap_err());
+        assert_eq!(
+            error,
+            format!(
+                "wasm filter {} is missing, --wasm-optional exports the services without it",
+                path.display()
+            )
+        );
+        assert_eq!(filters(&optional).unwrap(), [http_filters::ROUTER]);
+        assert_eq!(
+            optional.missing_filters(&[service("")]),
+            std::iter::once(path.clone()).collect()
+        );
+
+        std::fs::write(&path, b"\0asm").unwrap();
+        for wasm in &[required, optional.clone()] {
+            assert_eq!(
+                filters(wasm).unwrap(),
+                [http_filters::WASM, http_filters::ROUTER]
+            );
+            assert!(wasm.missing_filters(&[service("")]).is_empty());
+        }
+
+        // unlike the 3scale auth filter, even when optional
+        let auth = dir.path().join("auth.wasm");
+        let service = service(&format!(
+            r#", "auth_config": {{
+                "path": "{}",
+                "wasm_config": {{"backend": {{"cluster_name": "backend", "url": "https://backend.app/"}}}}
+            }}"#,
+            auth.display()
+        ));
+        let error = format!(
+            "{:#}",
+            service.export_listener(None, &optional).unwrap_err()
+        );
+        assert_eq!(
+            error,
+            format!(
+                "3scale auth filter {} is missing, the service is not exported without it",
+                auth.display()
+            )
+        );
+        assert!(optional.missing_filters(&[service]).is_empty());
+    }
+
+    #[test]
+    fn request_ids_take_the_controller_settings_unless_set() {
+        let manager = connection_manager(&service(""));
+        assert_eq!(manager.generate_request_id, None);
+        assert!(!manager.preserve_external_request_id);
+        assert!(!manager.always_set_request_id_in_response);
+        assert_eq!(manager.request_id_extension, None);
+
+        let wasm = WasmSettings {
+            request_id: RequestId {
+                preserve_external_request_id: Some(true),
+                always_set_request_id_in_response: Some(true),
+                uuid_extension: Some(true),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let overridden = service(
+            r#", "request_id": {"generate_request_id": false, "preserve_external_request_id": false}"#,
+        );
+        let manager = exported_connection_manager(&overridden, &wasm);
+        assert_eq!(manager.generate_request_id, Some(false));
+        assert!(!manager.preserve_external_request_id);
+        assert!(manager.always_set_request_id_in_response);
+        let extension = manager.request_id_extension.unwrap().typed_config.unwrap();
+        assert_eq!(
+            extension.type_url,
+            "type.googleapis.com/envoy.extensions.request_id.uuid.v3.UuidRequestIdConfig"
+        );
+        assert!(
+            !overridden
+                .filter_config(&Default::default())
+                .request_id_in_denials
+        );
+
+        // the denials of the filter tell the id only when asked to
+        let denying = service(r#", "request_id": {"in_filter_denials": true}"#);
+        assert!(
+            denying
+                .filter_config(&Default::default())
+                .request_id_in_denials
+        );
+        // as do those of any service with the controller setting
+        let wasm = WasmSettings {
+            request_id: RequestId {
+                in_filter_denials: Some(true),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert!(service("").filter_config(&wasm).request_id_in_denials);
+        let quiet = service(r#", "request_id": {"in_filter_denials": false}"#);
+        assert!(!quiet.filter_config(&wasm).request_id_in_denials);
+    }
+
+    #[test]
+    fn header_options_take_the_controller_settings_unless_set() {
+        use crate::header_options::{ServerHeader, StripHostPort};
+        use crate::protobuf::envoy::extensions::filters::network::http_connection_manager::v3::http_connection_manager::{
+            ServerHeaderTransformation, StripPortMode,
+        };
+
+        let manager = connection_manager(&service(""));
+        assert!(!manager.strip_matching_host_port);
+        assert_eq!(manager.strip_port_mode, None);
+        assert_eq!(manager.server_header_transformation, 0);
+        assert_eq!(manager.server_name, "");
+
+        let wasm = WasmSettings {
+            header_options: HeaderOptions {
+                strip_host_port: Some(StripHostPort::Matching),
+                server_header: Some(ServerHeader::AppendIfAbsent),
+                server_name: Some("gateway".to_string()),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let manager = exported_connection_manager(&service(""), &wasm);
+        assert!(manager.strip_matching_host_port);
+        assert_eq!(manager.strip_port_mode, None);
+        assert_eq!(
+            manager.server_header_transformation,
+            ServerHeaderTransformation::AppendIfAbsent as i32
+        );
+        assert_eq!(manager.server_name, "gateway");
+
+        let overridden = service(
+            r#", "header_options": {"strip_host_port": "any", "server_header": "pass_through"}"#,
+        );
+        let manager = exported_connection_manager(&overridden, &wasm);
+        assert!(!manager.strip_matching_host_port);
+        assert_eq!(
+            manager.strip_port_mode,
+            Some(StripPortMode::StripAnyHostPort(true))
+        );
+        assert_eq!(
+            manager.server_header_transformation,
+            ServerHeaderTransformation::PassThrough as i32
+        );
+        assert_eq!(manager.server_name, "gateway");
+
+        // a name of its own the header never carries
+        let unsent = service(
+            r#", "header_options": {"server_header": "pass_through", "server_name": "api"}"#,
+        );
+        let warnings: Vec<_> = unsent
+            .findings("")
+            .warnings
+            .into_iter()
+            .map(|w| w.path)
+            .collect();
+        assert_eq!(warnings, ["header_options.server_name"]);
+    }
+
+    #[test]
+    fn codecs_take_the_controller_settings_unless_set() {
+        use crate::header_options::Codec;
+
+        let manager = connection_manager(&service(""));
+        assert_eq!(manager.codec_type, CodecType::Auto as i32);
+
+        let wasm = WasmSettings {
+            header_options: HeaderOptions {
+                codec: Some(Codec::Http1),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let manager = exported_connection_manager(&service(""), &wasm);
+        assert_eq!(manager.codec_type, CodecType::Http1 as i32);
+
+        let overridden = service(r#", "header_options": {"codec": "http2"}"#);
+        let manager = exported_connection_manager(&overridden, &wasm);
+        assert_eq!(manager.codec_type, CodecType::Http2 as i32);
+    }
+
+    #[test]
+    fn proxied_requests_carry_the_identity_of_the_controller() {
+        let routes = |wasm: &WasmSettings| match exported_connection_manager(&service(""), wasm)
+            .route_specifier
+        {
+            Some(RouteSpecifier::RouteConfig(config)) => config,
+            specifier => panic!("{:?}", specifier),
+        };
+        assert!(routes(&WasmSettings::default())
+            .request_headers_to_add
+            .is_empty());
+
+        let wasm = |header: &str| WasmSettings {
+            http_client: HttpClient {
+                identity: crate::identity::Identity {
+                    header: Some(header.to_string()),
+                    cluster: Some("eu-west".to_string()),
+                },
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let header = routes(&wasm("x-gateway")).request_headers_to_add[0].clone();
+        assert_eq!(
+            header.header.unwrap().value,
+            format!(
+                "gateway-ng-controller/{} (cluster eu-west)",
+                env!("CARGO_PKG_VERSION")
+            )
+        );
+        // rather than the one of the client
+        assert_eq!(header.append, Some(false));
+        // but for via, which the proxies on the way tell the loops by
+        let via = routes(&wasm("via")).request_headers_to_add[0].clone();
+        assert_eq!(via.append, Some(true));
+    }
+
+    #[test]
+    fn http_timeouts_take_the_controller_settings_unless_set() {
+        use crate::header_options::Timeout;
+
+        // Envoy's defaults without the block
+        let manager = connection_manager(&service(""));
+        assert_eq!(manager.common_http_protocol_options, None);
+        assert_eq!(manager.drain_timeout, None);
+        assert_eq!(manager.delayed_close_timeout, None);
+
+        let seconds = |seconds| Some(std::time::Duration::from_secs(seconds).into());
+        let wasm = WasmSettings {
+            header_options: HeaderOptions {
+                idle_timeout: Some(Timeout(std::time::Duration::from_secs(600))),
+                drain_timeout: Some(Timeout(std::time::Duration::from_secs(10))),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let manager = exported_connection_manager(&service(""), &wasm);
+        let idle = |manager: &HttpConnectionManager| {
+            manager
+                .common_http_protocol_options
+                .as_ref()
+                .and_then(|options| options.idle_timeout.clone())
+        };
+        assert_eq!(idle(&manager), seconds(600));
+        assert_eq!(manager.drain_timeout, seconds(10));
+        assert_eq!(manager.delayed_close_timeout, None);
+
+        let overridden = service(
+            r#", "header_options": {"idle_timeout": "1h", "delayed_close_timeout": "0", "drain_timeout": "1500ms"}"#,
+        );
+        assert_eq!(
+            overridden.header_options.as_ref().unwrap().drain_timeout,
+            Some(Timeout(std::time::Duration::from_millis(1500)))
+        );
+        let manager = exported_connection_manager(&overridden, &wasm);
+        assert_eq!(idle(&manager), seconds(3600));
+        assert_eq!(
+            manager.drain_timeout,
+            Some(std::time::Duration::from_millis(1500).into())
+        );
+        // 0 disables the delayed close rather than leave it to Envoy
+        assert_eq!(manager.delayed_close_timeout, seconds(0));
+
+        let errors: Vec<_> = service(r#", "header_options": {"drain_timeout": "0"}"#)
+            .findings("")
+            .errors
+            .into_iter()
+            .map(|e| e.path)
+            .collect();
+        assert_eq!(errors, ["header_options.drain_timeout"]);
+        for timeout in &["30", "5d", "s", "-1s"] {
+            let options = format!(r#"{{"idle_timeout": "{}"}}"#, timeout);
+            assert!(
+                serde_json::from_str::<HeaderOptions>(&options).is_err(),
+                "{}",
+                timeout
+            );
+        }
+    }
+
+    #[test]
+    fn dual_stack_services_get_a_listener_per_family() {
+        let wasm = WasmSettings {
+            skip_sha: true,
+            ..Default::default()
+        };
+        // key, name and address of each listener
+        let listeners = |service: &Service, wasm: &WasmSettings| -> Vec<_> {
+            service
+                .export(wasm)
+                .unwrap()
+                .into_iter()
+                .filter_map(|export| match export.config {
+                    EnvoyResource::Listener(listener) => Some((export.key, listener)),
+                    _ => None,
+                })
+                .map(|(key, listener)| {
+                    let address = match listener.address.unwrap().address {
+                        Some(AddressType::SocketAddress(socket)) => socket.address,
+                        address => panic!("{:?}", address),
+                    };
+                    (key, listener.name, address, listener.filter_chains)
+                })
+                .collect()
+        };
 
-        Ok(Listener {
-            name: format!("service {}", self.id),
-            address: Some(Address {
-                address: Some(AddressType::SocketAddress(SocketAddress {
-                    address: "0.0.0.0".to_string(),
-                    port_specifier: Some(PortSpecifier::PortValue(80)),
+        let v6 = service(r#", "listener_address": {"address": "::"}"#);
+        let exported = listeners(&v6, &wasm);
+        assert_eq!(exported.len(), 1);
+        assert_eq!(exported[0].0, "service::id::1::listener");
+        assert_eq!(exported[0].2, "::");
+
+        let dual = listeners(
+            &service(""),
+            &WasmSettings {
+                listener_address: ListenerAddress {
+                    dual_stack: true,
                     ..Default::default()
-                })),
-            }),
-            filter_chains: vec![FilterChain {
-                filters,
+                },
+                ..wasm.clone()
+            },
+        );
+        let bound: Vec<_> = dual
+            .iter()
+            .map(|(key, name, address, _)| (key.as_str(), name.as_str(), address.as_str()))
+            .collect();
+        assert_eq!(
+            bound,
+            [
+                ("service::id::1::listener", "service 1", "0.0.0.0"),
+                ("service::id::1::listener::ipv6", "service 1 ipv6", "::"),
+            ]
+        );
+        assert_eq!(dual[0].3, dual[1].3);
+
+        // the address of the service wins over the controller's
+        let pinned = service(r#", "listener_address": {"address": "10.0.0.1"}"#);
+        let exported = listeners(
+            &pinned,
+            &WasmSettings {
+                listener_address: ListenerAddress {
+                    dual_stack: true,
+                    ..Default::default()
+                },
+                ..wasm
+            },
+        );
+        assert_eq!(exported.len(), 1);
+        assert_eq!(exported[0].2, "10.0.0.1");
+
+        let scoped = service(r#", "listener_address": {"address": "fe80::1%eth0"}"#);
+        let errors: Vec<_> = scoped
+            .findings("")
+            .errors
+            .into_iter()
+            .map(|error| error.path)
+            .collect();
+        assert_eq!(errors, ["listener_address.address"]);
+    }
+
+    #[test]
+    fn forwarded_headers_are_left_to_envoy_unless_set() {
+        use crate::protobuf::envoy::config::core::v3::scheme_header_transformation::Transformation;
+
+        let host = |manager: &HttpConnectionManager| match manager.route_specifier {
+            Some(RouteSpecifier::RouteConfig(ref config)) => config.virtual_hosts[0].clone(),
+            ref specifier => panic!("{:?}", specifier),
+        };
+        let appends_host = |host: &VirtualHost| match host.routes[0].action {
+            Some(Action::Route(ref action)) => action.append_x_forwarded_host,
+            ref action => panic!("{:?}", action),
+        };
+
+        let manager = connection_manager(&service(""));
+        assert_eq!(manager.xff_num_trusted_hops, 0);
+        assert_eq!(manager.scheme_header_transformation, None);
+        assert!(!appends_host(&host(&manager)));
+        assert!(host(&manager).request_headers_to_add.is_empty());
+
+        let manager = connection_manager(&service(
+            r#", "forwarded": {"xff_num_trusted_hops": 2, "append_x_forwarded_host": true, "scheme": "https"}"#,
+        ));
+        assert_eq!(manager.xff_num_trusted_hops, 2);
+        assert_eq!(
+            manager
+                .scheme_header_transformation
+                .clone()
+                .unwrap()
+                .transformation,
+            Some(Transformation::SchemeToOverwrite("https".to_string()))
+        );
+        let host = host(&manager);
+        assert!(appends_host(&host));
+        let proto = host.request_headers_to_add[0].clone();
+        assert_eq!(proto.append, Some(false));
+        let header = proto.header.unwrap();
+        assert_eq!(
+            (header.key.as_str(), header.value.as_str()),
+            ("x-forwarded-proto", "https")
+        );
+    }
+
+    #[test]
+    fn mapping_rules_get_routes_of_their_own() {
+        use crate::protobuf::envoy::r#type::matcher::v3::{regex_matcher, RegexMatcher};
+
+        let mut service = service(r#", "rule_routes": true"#);
+        service.proxy_rules.push(MappingRules::new(
+            "/things/{id}?fields={fields}".into(),
+            "PUT".into(),
+            "updates".into(),
+            1,
+        ));
+        service.policies = serde_json::from_value(serde_json::json!([{
+            "name": "routing",
+            "configuration": {
+                "upstreams": {"v2": "http://v2.backend"},
+                "rules": [{"upstream": "v2", "header": {"name": "x-api-version", "value": "2"}}],
+            },
+        }]))
+        .unwrap();
+        let findings = service.findings("");
+        assert!(findings.errors.is_empty(), "{:?}", findings.errors);
+        let warnings: Vec<_> = findings.warnings.into_iter().map(|w| w.path).collect();
+        assert_eq!(warnings, ["proxy_rules[2].pattern"]);
+
+        let routes = match connection_manager(&service).route_specifier {
+            Some(RouteSpecifier::RouteConfig(config)) => config.virtual_hosts[0].routes.clone(),
+            specifier => panic!("{:?}", specifier),
+        };
+        // the routing rules first, for the rules not to take their requests
+        let matched: Vec<_> = routes
+            .iter()
+            .map(|route| {
+                let matcher = route.r#match.clone().unwrap();
+                let method = matcher
+                    .headers
+                    .iter()
+                    .find(|header| header.name == ":method")
+                    .map(|header| header.header_match_specifier.clone().unwrap());
+                (route.name.as_str(), matcher.path_specifier.unwrap(), method)
+            })
+            .collect();
+        let regex = |regex: &str| {
+            PathSpecifier::SafeRegex(RegexMatcher {
+                regex: regex.to_string(),
+                engine_type: Some(regex_matcher::EngineType::GoogleRe2(Default::default())),
+            })
+        };
+        let method = |method: &str| Some(HeaderMatchSpecifier::ExactMatch(method.to_string()));
+        assert_eq!(
+            matched,
+            [
+                ("", PathSpecifier::Prefix("/".to_string()), None),
+                (
+                    "PUT /things/{id}?fields={fields} → updates",
+                    PathSpecifier::Prefix("/things/".to_string()),
+                    method("PUT")
+                ),
+                ("GET /ticks → ticks +2", regex("/ticks.*"), method("GET")),
+                // last, for the rule catching every path not to take the
+                // requests of the others
+                ("GET / → hits", regex("/.*"), method("GET")),
+                ("", PathSpecifier::Prefix("/".to_string()), None),
+            ]
+        );
+
+        service.rule_routes = false;
+        match connection_manager(&service).route_specifier {
+            Some(RouteSpecifier::RouteConfig(config)) => {
+                assert_eq!(config.virtual_hosts[0].routes.len(), 2)
+            }
+            specifier => panic!("{:?}", specifier),
+        }
+    }
+
+    #[test]
+    fn mapping_rules_may_have_upstreams_of_their_own() {
+        let mut service = service("");
+        service.proxy_rules.clear();
+        for (pattern, upstream) in &[
+            ("/search", Some("http://search:8080")),
+            ("/orders", Some("http://orders:8080")),
+            // the same upstreams, however written
+            ("/orders/{id}", Some("http://orders:8080/")),
+            ("/health", Some("http://web.app")),
+            ("/", None),
+        ] {
+            let mut rule = MappingRules::new(pattern.to_string(), "GET".into(), "hits".into(), 1);
+            rule.upstream = upstream.map(str::to_string);
+            service.proxy_rules.push(rule);
+        }
+        assert_eq!(service.findings(""), Findings::default());
+
+        let wasm = WasmSettings {
+            skip_sha: true,
+            ..Default::default()
+        };
+        let clusters: Vec<_> = service
+            .export_clusters(&wasm)
+            .unwrap()
+            .into_iter()
+            .map(|(key, cluster)| (key, cluster.name))
+            .collect();
+        assert_eq!(
+            clusters,
+            [
+                (
+                    "service::id::1::cluster".to_string(),
+                    "Cluster::service::1".to_string()
+                ),
+                (
+                    "service::id::1::cluster::rule::0".to_string(),
+                    "Cluster::service::1::rule::0".to_string()
+                ),
+                (
+                    "service::id::1::cluster::rule::1".to_string(),
+                    "Cluster::service::1::rule::1".to_string()
+                ),
+            ]
+        );
+
+        let routes = match connection_manager(&service).route_specifier {
+            Some(RouteSpecifier::RouteConfig(config)) => config.virtual_hosts[0].routes.clone(),
+            specifier => panic!("{:?}", specifier),
+        };
+        let wired: Vec<_> = routes
+            .iter()
+            .map(|route| match route.action {
+                Some(Action::Route(RouteAction {
+                    cluster_specifier: Some(ClusterSpecifier::Cluster(ref cluster)),
+                    ..
+                })) => (route.name.as_str(), cluster.as_str()),
+                ref action => panic!("{:?}", action),
+            })
+            .collect();
+        assert_eq!(
+            wired,
+            [
+                ("GET /orders/{id} → hits", "Cluster::service::1::rule::1"),
+                ("GET /search → hits", "Cluster::service::1::rule::0"),
+                ("GET /orders → hits", "Cluster::service::1::rule::1"),
+                ("GET /health → hits", "Cluster::service::1"),
+                ("", "Cluster::service::1"),
+            ]
+        );
+        // the filter accounts by metric whatever the upstream
+        assert!(
+            !serde_json::to_string(&service.filter_config(&Default::default()))
+                .unwrap()
+                .contains("http://search:8080")
+        );
+
+        for upstream in &["ftp://search", "http://search:0"] {
+            service.proxy_rules[0].upstream = Some(upstream.to_string());
+            let errors: Vec<_> = service
+                .findings("")
+                .errors
+                .into_iter()
+                .map(|e| e.path)
+                .collect();
+            assert_eq!(errors, ["proxy_rules[0].upstream"]);
+        }
+
+        // original destination services have no upstream of their own
+        service.proxy_rules[0].upstream = Some("http://search:8080".to_string());
+        service.kind = ServiceKind::OriginalDst;
+        service.target_domain.clear();
+        let errors: Vec<_> = service
+            .findings("")
+            .errors
+            .into_iter()
+            .map(|e| e.path)
+            .collect();
+        assert_eq!(
+            errors,
+            (0..4)
+                .map(|i| format!("proxy_rules[{}].upstream", i))
+                .collect::<Vec<_>>()
+        );
+        assert!(service.rule_upstreams().is_empty());
+    }
+
+    #[test]
+    fn environments_are_served_apart() {
+        use crate::protobuf::envoy::config::endpoint::v3::lb_endpoint::HostIdentifier;
+        use prost::Message;
+
+        let service = service(
+            r#", "environments": {
+                "staging": {"hosts": ["staging.web.app"], "target_domain": "http://web-v2.app:80"}
+            }"#,
+        );
+        assert_eq!(service.findings(""), Findings::default());
+
+        let wasm = WasmSettings {
+            skip_sha: true,
+            ..Default::default()
+        };
+        let exports = service.export(&wasm).unwrap();
+        let names: Vec<_> = exports
+            .iter()
+            .map(|export| (export.key.as_str(), export.config.name()))
+            .collect();
+        assert_eq!(
+            names,
+            [
+                ("service::id::1::cluster", "Cluster::service::1"),
+                ("service::id::1::listener", "service 1"),
+                (
+                    "service::id::1_staging::cluster",
+                    "Cluster::service::1_staging"
+                ),
+                ("service::id::1_staging::listener", "service 1_staging"),
+            ]
+        );
+
+        let environment = |metadata: &Option<Metadata>| {
+            let fields =
+                &metadata.as_ref().unwrap().filter_metadata[metadata::ANNOTATIONS_NAMESPACE].fields;
+            match fields[environment::ANNOTATION].kind {
+                Some(prost_types::value::Kind::StringValue(ref value)) => value.clone(),
+                ref kind => panic!("{:?}", kind),
+            }
+        };
+        let mut served = Vec::new();
+        for export in &exports {
+            match export.config {
+                EnvoyResource::Cluster(ref cluster) => {
+                    let upstream = match cluster.load_assignment {
+                        Some(ref assignment) => {
+                            match assignment.endpoints[0].lb_endpoints[0].host_identifier {
+                                Some(HostIdentifier::Endpoint(ref endpoint)) => {
+                                    match endpoint.address.as_ref().unwrap().address {
+                                        Some(AddressType::SocketAddress(ref socket)) => {
+                                            socket.address.clone()
+                                        }
+                                        ref address => panic!("{:?}", address),
+                                    }
+                                }
+                                ref host => panic!("{:?}", host),
+                            }
+                        }
+                        None => panic!("{:?}", cluster),
+                    };
+                    served.push((environment(&cluster.metadata), upstream));
+                }
+                EnvoyResource::Listener(ref listener) => {
+                    let connection_manager = match listener.filter_chains[0].filters[0].config_type
+                    {
+                        Some(ConfigType::TypedConfig(ref any)) => {
+                            HttpConnectionManager::decode(any.value.as_slice()).unwrap()
+                        }
+                        ref config => panic!("{:?}", config),
+                    };
+                    let domains = match connection_manager.route_specifier {
+                        Some(RouteSpecifier::RouteConfig(config)) => {
+                            config.virtual_hosts[0].domains.join(",")
+                        }
+                        specifier => panic!("{:?}", specifier),
+                    };
+                    served.push((environment(&listener.metadata), domains));
+                }
+                EnvoyResource::Secret(_) => panic!("{:?}", export),
+            }
+        }
+        let served: Vec<_> = served
+            .iter()
+            .map(|(environment, what)| (environment.as_str(), what.as_str()))
+            .collect();
+        assert_eq!(
+            served,
+            [
+                ("production", "web.app"),
+                ("production", "web.app"),
+                ("staging", "web-v2.app"),
+                ("staging", "staging.web.app"),
+            ]
+        );
+
+        // staging is served to hosts of its own
+        let mut shared = service.clone();
+        for (hosts, error) in &[
+            (None, "environments.staging.hosts"),
+            (
+                Some(vec!["web.app".to_string()]),
+                "environments.staging.hosts[0]",
+            ),
+            // whatever the case, or through a wildcard
+            (
+                Some(vec!["WEB.APP".to_string()]),
+                "environments.staging.hosts[0]",
+            ),
+            (
+                Some(vec!["*.app".to_string()]),
+                "environments.staging.hosts[0]",
+            ),
+        ] {
+            shared
+                .environments
+                .get_mut(&Environment::Staging)
+                .unwrap()
+                .hosts = hosts.clone();
+            let errors: Vec<_> = shared
+                .findings("")
+                .errors
+                .into_iter()
+                .map(|e| e.path)
+                .collect();
+            assert_eq!(errors, [*error]);
+        }
+        assert!(shared.export(&wasm).is_err());
+    }
+
+    // The string fields of the metadata of the controller.
+    fn metadata_fields(
+        metadata: &Option<Metadata>,
+    ) -> Vec<(std::string::String, std::string::String)> {
+        let fields = match metadata {
+            Some(metadata) => metadata.filter_metadata[metadata::NAMESPACE].fields.clone(),
+            None => return Vec::new(),
+        };
+        fields
+            .into_iter()
+            .map(|(key, value)| match value.kind {
+                Some(prost_types::value::Kind::StringValue(value)) => (key, value),
+                kind => panic!("{}: {:?}", key, kind),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn routes_and_clusters_carry_3scale_ids() {
+        use prost::Message;
+
+        let mut service = service(r#", "rule_routes": true"#);
+        service.policies = serde_json::from_value(serde_json::json!([{
+            "name": "routing",
+            "configuration": {
+                "upstreams": {"v2": "http://v2.backend:8080"},
+                "rules": [{"upstream": "v2", "path_prefix": "/v2/"}],
+            },
+        }]))
+        .unwrap();
+        let mut wasm = WasmSettings {
+            skip_sha: true,
+            ..Default::default()
+        };
+        let fields = |fields: &[(&str, &str)]| -> Vec<_> {
+            fields
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect()
+        };
+
+        let routes = match exported_connection_manager(&service, &wasm).route_specifier {
+            Some(RouteSpecifier::RouteConfig(config)) => config.virtual_hosts[0].routes.clone(),
+            specifier => panic!("{:?}", specifier),
+        };
+        let metadata: Vec<_> = routes
+            .iter()
+            .map(|route| metadata_fields(&route.metadata))
+            .collect();
+        let id = fields(&[("service_id", "1")]);
+        assert_eq!(
+            metadata,
+            [
+                id.clone(),
+                fields(&[
+                    ("metric_system_name", "ticks"),
+                    ("pattern", "/ticks"),
+                    ("service_id", "1")
+                ]),
+                fields(&[
+                    ("metric_system_name", "hits"),
+                    ("pattern", "/"),
+                    ("service_id", "1")
+                ]),
+                id,
+            ]
+        );
+
+        let clusters = |wasm: &WasmSettings| -> Vec<_> {
+            service
+                .export(wasm)
+                .unwrap()
+                .into_iter()
+                .filter(|export| export.key.contains("::cluster"))
+                .map(|export| {
+                    let any = export.config.to_any().unwrap();
+                    metadata_fields(&Cluster::decode(any.value.as_slice()).unwrap().metadata)
+                })
+                .collect()
+        };
+        assert_eq!(
+            clusters(&wasm),
+            [
+                fields(&[("service_id", "1"), ("target_domain", "http://web.app:80")]),
+                fields(&[
+                    ("service_id", "1"),
+                    ("target_domain", "http://v2.backend:8080")
+                ]),
+            ]
+        );
+
+        // none when turned off
+        wasm.metadata = false;
+        assert!(clusters(&wasm).iter().all(Vec::is_empty));
+        match exported_connection_manager(&service, &wasm).route_specifier {
+            Some(RouteSpecifier::RouteConfig(config)) => assert!(config.virtual_hosts[0]
+                .routes
+                .iter()
+                .all(|route| route.metadata.is_none())),
+            specifier => panic!("{:?}", specifier),
+        }
+    }
+
+    #[test]
+    fn annotations_label_listeners_and_clusters() {
+        use prost::Message;
+
+        let annotated = service(r#", "annotations": {"tier": "gold", "team": "payments"}"#);
+        let wasm = WasmSettings {
+            skip_sha: true,
+            ..Default::default()
+        };
+        let annotations = |metadata: &Option<Metadata>| -> Vec<_> {
+            metadata.as_ref().unwrap().filter_metadata[metadata::ANNOTATIONS_NAMESPACE]
+                .fields
+                .iter()
+                .map(|(key, value)| match value.kind {
+                    Some(prost_types::value::Kind::StringValue(ref value)) => {
+                        (key.clone(), value.clone())
+                    }
+                    ref kind => panic!("{}: {:?}", key, kind),
+                })
+                .collect()
+        };
+        let expected = [
+            ("team".to_string(), "payments".to_string()),
+            ("tier".to_string(), "gold".to_string()),
+        ];
+        for export in annotated.export(&wasm).unwrap() {
+            let any = export.config.to_any().unwrap();
+            match export.config {
+                EnvoyResource::Cluster(_) => {
+                    let cluster = Cluster::decode(any.value.as_slice()).unwrap();
+                    assert_eq!(annotations(&cluster.metadata), expected);
+                    // along with the ids of the controller
+                    assert!(
+                        cluster.metadata.unwrap().filter_metadata[metadata::NAMESPACE]
+                            .fields
+                            .contains_key("service_id")
+                    );
+                }
+                EnvoyResource::Listener(_) => {
+                    let listener = Listener::decode(any.value.as_slice()).unwrap();
+                    assert_eq!(annotations(&listener.metadata), expected);
+                }
+                EnvoyResource::Secret(_) => {}
+            }
+        }
+        assert_eq!(
+            annotated
+                .filter_config(&Default::default())
+                .annotations
+                .keys()
+                .collect::<Vec<_>>(),
+            ["team", "tier"]
+        );
+
+        let invalid = service(r#", "annotations": {"-team": "payments", "tier": "gold\n"}"#);
+        let errors: Vec<_> = invalid
+            .findings("")
+            .errors
+            .into_iter()
+            .map(|error| error.path)
+            .collect();
+        assert_eq!(errors, ["annotations.-team", "annotations.tier"]);
+    }
+
+    #[test]
+    fn skipping_rules_come_before_broader_ones() {
+        // a route for the rule catching every path, listed first
+        let mut service = service(r#", "rule_routes": true"#);
+        let mut health = MappingRules::new("/health$".into(), "GET".into(), "health".into(), 0);
+        health.skip_wasm = true;
+        service.proxy_rules.push(health);
+
+        let routes = match connection_manager(&service).route_specifier {
+            Some(RouteSpecifier::RouteConfig(config)) => config.virtual_hosts[0].routes.clone(),
+            specifier => panic!("{:?}", specifier),
+        };
+        let skipping: Vec<_> = routes
+            .iter()
+            .map(|route| {
+                let skipped = route
+                    .typed_per_filter_config
+                    .contains_key(http_filters::WASM);
+                (route.name.as_str(), skipped)
+            })
+            .collect();
+        assert_eq!(
+            skipping,
+            [
+                ("GET /health$ → health +0", true),
+                ("GET /ticks → ticks +2", false),
+                ("GET / → hits", false),
+                ("", false),
+            ]
+        );
+    }
+
+    #[test]
+    fn rules_skip_filters_on_their_routes() {
+        use crate::protobuf::envoy::config::route::v3::FilterConfig;
+        use crate::type_urls::TypeUrl;
+        use prost::Message;
+
+        let mut service = service(
+            r#", "auth_config": {
+                "path": "static/threescale_wasm_auth.wasm",
+                "wasm_config": {"backend": {"cluster_name": "backend", "url": "https://backend.app/"}}
+            }"#,
+        );
+        let mut health = MappingRules::new("/health$".into(), "GET".into(), "health".into(), 0);
+        health.skip_auth = true;
+        health.skip_wasm = true;
+        let mut status = MappingRules::new("/status$".into(), "GET".into(), "status".into(), 1);
+        status.skip_wasm = true;
+        service.proxy_rules = vec![health, status];
+        let wasm = WasmSettings {
+            skip_sha: true,
+            ..Default::default()
+        };
+        let jwt = HttpFilter {
+            name: http_filters::JWT_AUTHN.to_string(),
+            config_type: Some(http_filter::ConfigType::TypedConfig(
+                wasm.type_urls.pack(&JwtAuthentication::default()).unwrap(),
+            )),
+        };
+        let routes = |jwt: Option<HttpFilter>| {
+            let listener = service.export_listener(jwt, &wasm).unwrap();
+            let manager = match listener.filter_chains[0].filters[0].config_type {
+                Some(ConfigType::TypedConfig(ref any)) => {
+                    HttpConnectionManager::decode(any.value.as_slice()).unwrap()
+                }
+                ref config => panic!("{:?}", config),
+            };
+            match manager.route_specifier {
+                Some(RouteSpecifier::RouteConfig(config)) => config.virtual_hosts[0].routes.clone(),
+                specifier => panic!("{:?}", specifier),
+            }
+        };
+        // the filters each route disables, by name
+        let disabled = |route: &Route| -> Vec<std::string::String> {
+            route
+                .typed_per_filter_config
+                .iter()
+                .map(|(name, any)| {
+                    assert_eq!(any.type_url, wasm.type_urls.url(FilterConfig::NAME));
+                    let config = FilterConfig::decode(any.value.as_slice()).unwrap();
+                    assert!(config.disabled && config.config.is_none());
+                    name.clone()
+                })
+                .collect()
+        };
+
+        // the rules skipping filters get routes, ahead of the catch all one
+        let with_jwt = routes(Some(jwt));
+        let names: Vec<_> = with_jwt.iter().map(|route| route.name.as_str()).collect();
+        assert_eq!(
+            names,
+            ["GET /health$ → health +0", "GET /status$ → status", ""]
+        );
+        let skipped: Vec<_> = with_jwt.iter().map(disabled).collect();
+        assert_eq!(
+            skipped,
+            [
+                vec![
+                    http_filters::JWT_AUTHN,
+                    http_filters::WASM,
+                    http_filters::THREESCALE_AUTH
+                ],
+                vec![http_filters::WASM],
+                vec![],
+            ]
+        );
+        // only those of the listener, which has them all
+        let names: Vec<_> = exported_connection_manager(&service, &wasm)
+            .http_filters
+            .into_iter()
+            .map(|filter| filter.name)
+            .collect();
+        assert_eq!(
+            names,
+            [
+                http_filters::THREESCALE_AUTH,
+                http_filters::WASM,
+                http_filters::ROUTER
+            ]
+        );
+        assert_eq!(
+            disabled(&routes(None)[0]),
+            [http_filters::WASM, http_filters::THREESCALE_AUTH]
+        );
+
+        // skipping the filters for more paths than those of the rule
+        service.proxy_rules[0] = serde_json::from_value(serde_json::json!({
+            "pattern": "/health?full", "http_method": "GET", "metric_system_name": "health",
+            "delta": 0, "skip_auth": true,
+        }))
+        .unwrap();
+        let errors: Vec<_> = service
+            .findings("")
+            .errors
+            .into_iter()
+            .map(|error| error.path)
+            .collect();
+        assert_eq!(errors, ["proxy_rules[0].skip_auth"]);
+    }
+
+    #[test]
+    fn original_destinations_are_the_upstreams_of_their_services() {
+        use crate::protobuf::envoy::config::cluster::v3::cluster::{
+            ClusterDiscoveryType, DiscoveryType, LbConfig, LbPolicy,
+        };
+        use crate::protobuf::envoy::config::listener::v3::listener_filter;
+
+        let mut service = service(
+            r#", "kind": "original_dst",
+                "original_dst": {"use_http_header": true, "allowed_destinations": ["10.0.0.5"]}"#,
+        );
+        let errors: Vec<_> = service
+            .findings("")
+            .errors
+            .into_iter()
+            .map(|e| e.path)
+            .collect();
+        assert_eq!(errors, ["target_domain"]);
+        service.target_domain = std::string::String::new();
+        assert_eq!(service.findings(""), Findings::default());
+
+        let mut wasm = WasmSettings {
+            skip_sha: true,
+            ..Default::default()
+        };
+        let error = service.export(&wasm).unwrap_err();
+        assert!(
+            error.to_string().contains("--allow-original-dst-header"),
+            "{}",
+            error
+        );
+        wasm.original_dst_header = true;
+        let exports = service.export(&wasm).unwrap();
+        let cluster = match exports[0].config {
+            EnvoyResource::Cluster(ref cluster) => cluster.clone(),
+            ref config => panic!("{:?}", config),
+        };
+        assert_eq!(cluster.name, "Cluster::service::1");
+        assert!(cluster.load_assignment.is_none());
+        assert_eq!(
+            cluster.cluster_discovery_type,
+            Some(ClusterDiscoveryType::Type(
+                DiscoveryType::OriginalDst as i32
+            ))
+        );
+        assert_eq!(cluster.lb_policy, LbPolicy::ClusterProvided as i32);
+        match cluster.lb_config {
+            Some(LbConfig::OriginalDstLbConfig(config)) => assert!(config.use_http_header),
+            config => panic!("{:?}", config),
+        }
+
+        let listener = service.export_listener(None, &wasm).unwrap();
+        assert_eq!(listener.listener_filters.len(), 1);
+        let filter = &listener.listener_filters[0];
+        assert_eq!(filter.name, "envoy.filters.listener.original_dst");
+        match filter.config_type {
+            Some(listener_filter::ConfigType::TypedConfig(ref any)) => assert_eq!(
+                any.type_url,
+                "type.googleapis.com/envoy.extensions.filters.listener.original_dst.v3.OriginalDst"
+            ),
+            ref config => panic!("{:?}", config),
+        }
+        // the filters still authorize the requests, routed to the cluster
+        let manager = exported_connection_manager(&service, &wasm);
+        let filters: Vec<_> = manager
+            .http_filters
+            .iter()
+            .map(|f| f.name.as_str())
+            .collect();
+        assert_eq!(filters, [http_filters::WASM, http_filters::ROUTER]);
+        let routes = match manager.route_specifier {
+            Some(RouteSpecifier::RouteConfig(config)) => config,
+            specifier => panic!("{:?}", specifier),
+        };
+        // the header is taken from the internal requests alone, naming an
+        // allowed destination
+        assert_eq!(routes.internal_only_headers, [original_dst::HEADER]);
+        let route = routes.virtual_hosts[0].routes[0].clone();
+        let headers = route.r#match.unwrap().headers;
+        assert_eq!(headers.len(), 1);
+        assert_eq!(headers[0].name, original_dst::HEADER);
+        match route.action {
+            Some(Action::Route(RouteAction {
+                cluster_specifier: Some(ClusterSpecifier::Cluster(ref cluster)),
+                ..
+            })) => assert_eq!(cluster, "Cluster::service::1"),
+            ref action => panic!("{:?}", action),
+        }
+
+        // the other services take their connections as they come
+        let api = self::service("");
+        assert!(api
+            .export_listener(None, &wasm)
+            .unwrap()
+            .listener_filters
+            .is_empty());
+    }
+
+    #[test]
+    fn forward_proxies_route_only_the_allowed_domains() {
+        let mut service = service(
+            r#", "kind": "dynamic_forward_proxy",
+                "forward_proxy": {"allowed_domains": ["api.example.com"]}"#,
+        );
+        service.target_domain = std::string::String::new();
+        service.policies = serde_json::from_value(serde_json::json!([{
+            "name": "routing",
+            "configuration": {
+                "upstreams": {"v2": "http://v2.backend"},
+                "rules": [{"upstream": "v2", "header": {"name": "x-api-version", "value": "2"}}],
+            },
+        }]))
+        .unwrap();
+        let wasm = WasmSettings {
+            skip_sha: true,
+            dynamic_forward_proxy: true,
+            ..Default::default()
+        };
+        let routes = match exported_connection_manager(&service, &wasm).route_specifier {
+            Some(RouteSpecifier::RouteConfig(config)) => config.virtual_hosts[0].routes.clone(),
+            specifier => panic!("{:?}", specifier),
+        };
+        assert_eq!(routes.len(), 2);
+        // Envoy routes a request by the first route whose headers all match
+        let matches = |route: &Route, host: &str| {
+            route.r#match.clone().unwrap().headers.iter().all(|header| {
+                match (header.name.as_str(), &header.header_match_specifier) {
+                    (":authority", Some(HeaderMatchSpecifier::SafeRegexMatch(matcher))) => {
+                        regex::Regex::new(&format!("^(?:{})$", matcher.regex))
+                            .unwrap()
+                            .is_match(host)
+                    }
+                    _ => true,
+                }
+            })
+        };
+        assert!(routes.iter().all(|route| matches(route, "api.example.com")));
+        assert!(!routes.iter().any(|route| matches(route, "evil.com")));
+    }
+
+    #[test]
+    fn dynamic_forward_proxies_are_exported_when_allowed() {
+        use crate::protobuf::envoy::config::cluster::v3::cluster::ClusterDiscoveryType;
+        use crate::protobuf::envoy::extensions::clusters::dynamic_forward_proxy::v3::{
+            cluster_config, ClusterConfig,
+        };
+        use crate::protobuf::envoy::extensions::common::dynamic_forward_proxy::v3::DnsCacheConfig;
+        use crate::protobuf::envoy::extensions::filters::http::dynamic_forward_proxy::v3::{
+            filter_config, per_route_config, FilterConfig, PerRouteConfig,
+        };
+        use prost::Message;
+
+        let mut service = service(
+            r#", "kind": "dynamic_forward_proxy", "rule_routes": true,
+                "forward_proxy": {
+                    "allowed_domains": ["api.example.com"],
+                    "host_header": "x-destination",
+                    "dns_refresh_rate": 30,
+                    "max_hosts": 16
+                }"#,
+        );
+        service.target_domain = std::string::String::new();
+        let findings = service.findings("");
+        assert!(findings.errors.is_empty(), "{:?}", findings.errors);
+        let warnings: Vec<_> = findings.warnings.into_iter().map(|w| w.path).collect();
+        assert_eq!(warnings, ["rule_routes"]);
+
+        let mut wasm = WasmSettings {
+            skip_sha: true,
+            ..Default::default()
+        };
+        let error = service.export(&wasm).unwrap_err();
+        assert!(
+            error.to_string().contains("--allow-dynamic-forward-proxy"),
+            "{}",
+            error
+        );
+        wasm.dynamic_forward_proxy = true;
+        let exports = service.export(&wasm).unwrap();
+
+        // the filter and the cluster share the DNS cache
+        let cache = |cache: DnsCacheConfig| {
+            assert_eq!(cache.name, "service_1_dns_cache");
+            assert_eq!(
+                cache.dns_refresh_rate,
+                Some(Duration {
+                    seconds: 30,
+                    nanos: 0
+                })
+            );
+            assert_eq!(
+                cache.host_ttl,
+                Some(Duration {
+                    seconds: 300,
+                    nanos: 0
+                })
+            );
+            assert_eq!(cache.max_hosts, Some(16));
+        };
+        let cluster = match exports[0].config {
+            EnvoyResource::Cluster(ref cluster) => cluster.clone(),
+            ref config => panic!("{:?}", config),
+        };
+        assert_eq!(cluster.name, "Cluster::service::1");
+        assert!(cluster.load_assignment.is_none());
+        match cluster.cluster_discovery_type {
+            Some(ClusterDiscoveryType::ClusterType(custom)) => {
+                assert_eq!(custom.name, "envoy.clusters.dynamic_forward_proxy");
+                let config =
+                    ClusterConfig::decode(custom.typed_config.unwrap().value.as_slice()).unwrap();
+                match config.cluster_implementation_specifier {
+                    Some(cluster_config::ClusterImplementationSpecifier::DnsCacheConfig(dns)) => {
+                        cache(dns)
+                    }
+                    specifier => panic!("{:?}", specifier),
+                }
+            }
+            discovery => panic!("{:?}", discovery),
+        }
+
+        let manager = exported_connection_manager(&service, &wasm);
+        let filters: Vec<_> = manager
+            .http_filters
+            .iter()
+            .map(|f| f.name.as_str())
+            .collect();
+        assert_eq!(
+            filters,
+            [
+                http_filters::WASM,
+                http_filters::DYNAMIC_FORWARD_PROXY,
+                http_filters::ROUTER
+            ]
+        );
+        match manager.http_filters[1].config_type {
+            Some(http_filter::ConfigType::TypedConfig(ref any)) => {
+                let config = FilterConfig::decode(any.value.as_slice()).unwrap();
+                match config.implementation_specifier {
+                    Some(filter_config::ImplementationSpecifier::DnsCacheConfig(dns)) => cache(dns),
+                    specifier => panic!("{:?}", specifier),
+                }
+            }
+            ref config => panic!("{:?}", config),
+        }
+
+        // a single route, to the allowed hosts named by the header
+        let routes = match manager.route_specifier {
+            Some(RouteSpecifier::RouteConfig(config)) => config.virtual_hosts[0].routes.clone(),
+            specifier => panic!("{:?}", specifier),
+        };
+        assert_eq!(routes.len(), 1);
+        let headers = routes[0].r#match.clone().unwrap().headers;
+        assert_eq!(headers.len(), 1);
+        assert_eq!(headers[0].name, "x-destination");
+        let per_route = &routes[0].typed_per_filter_config[http_filters::DYNAMIC_FORWARD_PROXY];
+        assert_eq!(
+            PerRouteConfig::decode(per_route.value.as_slice())
+                .unwrap()
+                .host_rewrite_specifier,
+            Some(per_route_config::HostRewriteSpecifier::HostRewriteHeader(
+                "x-destination".to_string()
+            ))
+        );
+
+        // no open proxies
+        service.forward_proxy = None;
+        let errors: Vec<_> = service
+            .findings("")
+            .errors
+            .into_iter()
+            .map(|error| error.path)
+            .collect();
+        assert_eq!(errors, ["forward_proxy"]);
+    }
+
+    #[test]
+    fn rules_get_a_virtual_cluster_each() {
+        let mut service = service("");
+        for (pattern, method, metric) in &[
+            ("/things/{id}$", "GET", "things"),
+            ("/things/{id}$", "DELETE", "things"),
+            ("/ticks/{id}", "GET", "ticks"),
+            ("/search?q={q}", "GET", "search"),
+        ] {
+            service.proxy_rules.push(MappingRules::new(
+                pattern.to_string(),
+                method.to_string(),
+                metric.to_string(),
+                1,
+            ));
+        }
+        let virtual_clusters = |max| {
+            let wasm = WasmSettings {
+                virtual_clusters: max,
                 ..Default::default()
-            }],
+            };
+            match exported_connection_manager(&service, &wasm).route_specifier {
+                Some(RouteSpecifier::RouteConfig(config)) => {
+                    config.virtual_hosts[0].virtual_clusters.clone()
+                }
+                specifier => panic!("{:?}", specifier),
+            }
+        };
+        let matchers =
+            |cluster: &VirtualCluster| -> Vec<(std::string::String, std::string::String)> {
+                cluster
+                    .headers
+                    .iter()
+                    .map(|header| {
+                        let value = match header.header_match_specifier {
+                            Some(HeaderMatchSpecifier::ExactMatch(ref value)) => value.clone(),
+                            Some(HeaderMatchSpecifier::SafeRegexMatch(ref regex)) => {
+                                format!("~{}", regex.regex)
+                            }
+                            ref specifier => panic!("{:?}", specifier),
+                        };
+                        (header.name.clone(), value)
+                    })
+                    .collect()
+            };
+
+        assert!(virtual_clusters(0).is_empty());
+        let clusters = virtual_clusters(6);
+        let described: Vec<_> = clusters
+            .iter()
+            .map(|cluster| (cluster.name.as_str(), matchers(cluster)))
+            .collect();
+        let headers = |method: &str, path: &str| {
+            vec![
+                (":method".to_string(), method.to_string()),
+                (":path".to_string(), format!("~(?:{})(?:\\?.*)?", path)),
+            ]
+        };
+        assert_eq!(
+            described,
+            [
+                ("things", headers("GET", "/things/([^/]+)")),
+                ("things", headers("DELETE", "/things/([^/]+)")),
+                ("search", headers("GET", "/search.*")),
+                ("ticks", headers("GET", "/ticks/([^/]+).*")),
+                ("ticks", headers("GET", "/ticks.*")),
+                // last, for the rule catching every path not to count the
+                // requests of the others
+                ("hits", headers("GET", "/.*")),
+            ]
+        );
+
+        // those of the first rules
+        let names: Vec<_> = virtual_clusters(2)
+            .into_iter()
+            .map(|cluster| cluster.name)
+            .collect();
+        assert_eq!(names, ["ticks", "hits"]);
+    }
+
+    #[test]
+    fn hsts_is_only_sent_over_tls() {
+        let headers = |manager: HttpConnectionManager| -> Vec<std::string::String> {
+            let host = match manager.route_specifier {
+                Some(RouteSpecifier::RouteConfig(config)) => config.virtual_hosts[0].clone(),
+                specifier => panic!("{:?}", specifier),
+            };
+            host.response_headers_to_add
+                .into_iter()
+                .map(|option| option.header.unwrap().key)
+                .collect()
+        };
+        let with_headers = |extra: &str| {
+            let mut service = service(extra);
+            service.policies =
+                serde_json::from_value(serde_json::json!(["security_headers"])).unwrap();
+            service
+        };
+
+        let plain = with_headers("");
+        let findings = plain.findings("");
+        assert!(findings.errors.is_empty(), "{:?}", findings.errors);
+        assert_eq!(
+            findings.warnings[0].path,
+            "policies[0].configuration.strict_transport_security"
+        );
+        assert!(!headers(connection_manager(&plain)).contains(&"strict-transport-security".into()));
+
+        let tls = with_headers(
+            r#", "tls": {"cert_path": "tls.pem", "key_path": "tls.key", "sds": true}"#,
+        );
+        assert!(tls.findings("").warnings.is_empty());
+        assert_eq!(
+            headers(connection_manager(&tls)),
+            [
+                "strict-transport-security",
+                "x-frame-options",
+                "x-content-type-options",
+                "referrer-policy"
+            ]
+        );
+    }
+
+    #[test]
+    fn http3_listeners_share_the_routes_of_the_tls_ones() {
+        use crate::protobuf::envoy::config::core::v3::transport_socket;
+        use crate::protobuf::envoy::extensions::transport_sockets::quic::v3::QuicDownstreamTransport;
+        use prost::Message;
+
+        let decoded = |listener: &Listener| match listener.filter_chains[0].filters[0].config_type {
+            Some(ConfigType::TypedConfig(ref any)) => {
+                HttpConnectionManager::decode(any.value.as_slice()).unwrap()
+            }
+            ref config => panic!("{:?}", config),
+        };
+        let routes = |manager: &HttpConnectionManager| match manager.route_specifier {
+            Some(RouteSpecifier::RouteConfig(ref config)) => config.clone(),
+            ref specifier => panic!("{:?}", specifier),
+        };
+
+        let plain = service(r#", "http3": true"#);
+        let errors: Vec<_> = plain
+            .findings("")
+            .errors
+            .into_iter()
+            .map(|e| e.path)
+            .collect();
+        assert_eq!(errors, ["http3"]);
+
+        let service = service(
+            r#", "http3": true, "tls": {"cert_path": "tls.pem", "key_path": "tls.key", "sds": true}"#,
+        );
+        let mut wasm = WasmSettings {
+            skip_sha: true,
             ..Default::default()
-        })
+        };
+        let error = service.export(&wasm).unwrap_err();
+        assert!(error.to_string().contains("--allow-http3"), "{}", error);
+        wasm.http3 = true;
+
+        let (tcp, udp) = service.export_listeners(None, &wasm).unwrap();
+        let udp = udp.unwrap();
+        assert_eq!(udp.name, "service 1 http3");
+        let socket = |listener: &Listener| match listener.address.clone().unwrap().address {
+            Some(AddressType::SocketAddress(socket)) => socket,
+            address => panic!("{:?}", address),
+        };
+        assert_eq!(socket(&tcp).protocol, Protocol::Tcp as i32);
+        assert_eq!(socket(&udp).protocol, Protocol::Udp as i32);
+        assert_eq!(
+            socket(&udp).port_specifier,
+            Some(PortSpecifier::PortValue(443))
+        );
+        assert!(udp
+            .udp_listener_config
+            .as_ref()
+            .unwrap()
+            .quic_options
+            .is_some());
+
+        let quic = udp.filter_chains[0].transport_socket.clone().unwrap();
+        assert_eq!(quic.name, "envoy.transport_sockets.quic");
+        let tls_context = match quic.config_type {
+            Some(transport_socket::ConfigType::TypedConfig(any)) => {
+                QuicDownstreamTransport::decode(any.value.as_slice())
+                    .unwrap()
+                    .downstream_tls_context
+            }
+            config => panic!("{:?}", config),
+        };
+        let tcp_tls = match tcp.filter_chains[0]
+            .transport_socket
+            .clone()
+            .unwrap()
+            .config_type
+        {
+            Some(transport_socket::ConfigType::TypedConfig(any)) => any.value,
+            config => panic!("{:?}", config),
+        };
+        // the same certificate, over SDS
+        assert_eq!(encode(&tls_context.unwrap()).unwrap(), tcp_tls);
+
+        let tcp = decoded(&tcp);
+        let udp = decoded(&udp);
+        assert_eq!(tcp.codec_type, CodecType::Auto as i32);
+        assert_eq!(tcp.http3_protocol_options, None);
+        assert_eq!(udp.codec_type, CodecType::Http3 as i32);
+        assert!(udp.http3_protocol_options.is_some());
+
+        // the TLS responses alone advertise the HTTP/3 listener
+        let alt_svc: Vec<_> = routes(&tcp)
+            .response_headers_to_add
+            .into_iter()
+            .map(|header| header.header.unwrap())
+            .map(|header| (header.key, header.value))
+            .collect();
+        assert_eq!(
+            alt_svc,
+            [("alt-svc".to_string(), "h3=\":443\"; ma=86400".to_string())]
+        );
+        let mut udp_routes = routes(&udp);
+        assert!(udp_routes.response_headers_to_add.is_empty());
+        udp_routes.response_headers_to_add = routes(&tcp).response_headers_to_add;
+        assert_eq!(udp_routes, routes(&tcp));
+
+        // none without asking for it
+        let mut tls_only = service.clone();
+        tls_only.http3 = false;
+        let (tcp, udp) = tls_only.export_listeners(None, &wasm).unwrap();
+        assert!(udp.is_none());
+        assert!(routes(&decoded(&tcp)).response_headers_to_add.is_empty());
     }
 
-    pub fn get_wasm_filter_sha(path: impl AsRef<Path>) -> Result<std::string::String> {
-        let path = path.as_ref();
-        let input = File::open(path)
-            .with_context(|| format!("failed to open wasm filter: {}", path.display()))?;
-        let reader = BufReader::new(input);
-        let result = util::file_utils::sha256_digest(reader)?;
-        Ok(HEXUPPER.encode(result.as_ref()).to_lowercase())
+    #[test]
+    fn maintenance_leaves_envoy_answering() {
+        let with_policies = |policies| {
+            let mut service = service("");
+            service.policies = serde_json::from_value(policies).unwrap();
+            service
+        };
+        let total = with_policies(serde_json::json!(["cors", "maintenance_mode"]));
+        assert_eq!(http_filters(&total), ["envoy.filters.http.router"]);
+
+        let scoped = with_policies(serde_json::json!([{
+            "name": "maintenance_mode",
+            "configuration": {"condition": {"operations": [
+                {"left": "{{ uri }}", "left_type": "liquid", "op": "==", "right": "/ticks"},
+            ]}},
+        }]));
+        assert_eq!(
+            http_filters(&scoped),
+            ["envoy.filters.http.wasm", "envoy.filters.http.router"]
+        );
+    }
+
+    #[test]
+    fn routing_rules_route_ahead_of_the_default() {
+        let mut service = service("");
+        service.policies = serde_json::from_value(serde_json::json!([{
+            "name": "routing",
+            "configuration": {
+                "upstreams": {
+                    "v2": "http://v2.backend:8080",
+                    "beta": "https://beta.backend",
+                    "legacy": "http://legacy.backend",
+                },
+                "rules": [
+                    {"upstream": "v2", "header": {"name": "x-api-version", "value": "2"}},
+                    {"upstream": "beta", "header": {"name": "x-beta"}, "path_prefix": "/api/"},
+                    {"upstream": "v2", "path_prefix": "/v2/"},
+                ],
+            },
+        }]))
+        .unwrap();
+        let findings = service.findings("");
+        assert!(findings.errors.is_empty(), "{:?}", findings.errors);
+        let warnings: Vec<_> = findings.warnings.into_iter().map(|w| w.path).collect();
+        assert_eq!(warnings, ["policies[0].configuration.upstreams.legacy"]);
+
+        // a cluster for each upstream some rule takes requests to
+        let clusters: Vec<_> = service
+            .export_clusters(&WasmSettings::default())
+            .unwrap()
+            .into_iter()
+            .map(|(key, cluster)| (key, cluster.name))
+            .collect();
+        let cluster = |upstream: &str| format!("Cluster::service::1::{}", upstream);
+        assert_eq!(
+            clusters,
+            [
+                (
+                    "service::id::1::cluster".to_string(),
+                    "Cluster::service::1".to_string()
+                ),
+                ("service::id::1::cluster::beta".to_string(), cluster("beta")),
+                ("service::id::1::cluster::v2".to_string(), cluster("v2")),
+            ]
+        );
+
+        let routes = match connection_manager(&service).route_specifier {
+            Some(RouteSpecifier::RouteConfig(config)) => config.virtual_hosts[0].routes.clone(),
+            specifier => panic!("{:?}", specifier),
+        };
+        let routed: Vec<_> = routes
+            .iter()
+            .map(|route| {
+                let matcher = route.r#match.clone().unwrap();
+                let headers: Vec<_> = matcher
+                    .headers
+                    .iter()
+                    .map(|header| (header.name.clone(), header.header_match_specifier.clone()))
+                    .collect();
+                let cluster = match route.action {
+                    Some(Action::Route(ref action)) => action.cluster_specifier.clone(),
+                    ref action => panic!("{:?}", action),
+                };
+                (matcher.path_specifier, headers, cluster)
+            })
+            .collect();
+        let prefix = |prefix: &str| Some(PathSpecifier::Prefix(prefix.to_string()));
+        let to = |cluster: std::string::String| Some(ClusterSpecifier::Cluster(cluster));
+        assert_eq!(
+            routed,
+            [
+                (
+                    prefix("/"),
+                    vec![(
+                        "x-api-version".to_string(),
+                        Some(HeaderMatchSpecifier::ExactMatch("2".to_string()))
+                    )],
+                    to(cluster("v2"))
+                ),
+                (
+                    prefix("/api/"),
+                    vec![(
+                        "x-beta".to_string(),
+                        Some(HeaderMatchSpecifier::PresentMatch(true))
+                    )],
+                    to(cluster("beta"))
+                ),
+                (prefix("/v2/"), Vec::new(), to(cluster("v2"))),
+                (
+                    prefix("/"),
+                    Vec::new(),
+                    to("Cluster::service::1".to_string())
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn exports_run_in_a_span_of_the_service() {
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let spans = Spans::default();
+        let subscriber = tracing_subscriber::registry().with(spans.clone());
+        let wasm = WasmSettings {
+            skip_sha: true,
+            ..Default::default()
+        };
+        tracing::subscriber::with_default(subscriber, || {
+            service("").export(&wasm).unwrap();
+        });
+        assert_eq!(
+            *spans.0.lock().unwrap(),
+            [r#"export: service.id=1 service.hosts=["web.app"]"#]
+        );
+    }
+
+    #[test]
+    fn names_label_the_resources() {
+        let wasm = WasmSettings {
+            skip_sha: true,
+            ..Default::default()
+        };
+        let names = |service: &Service| -> Vec<std::string::String> {
+            let exports = service.export(&wasm).unwrap();
+            exports
+                .iter()
+                .map(|export| format!("{} {}", export.key, export.config.name()))
+                .collect()
+        };
+        assert_eq!(
+            names(&service("")),
+            [
+                "service::id::1::cluster Cluster::service::1",
+                "service::id::1::listener service 1"
+            ]
+        );
+
+        let named = service(r#", "name": "payments""#);
+        assert_eq!(named.label(), "1_payments");
+        assert_eq!(
+            names(&named),
+            [
+                "service::id::1_payments::cluster Cluster::service::1_payments",
+                "service::id::1_payments::listener service 1_payments"
+            ]
+        );
+
+        let long = "a".repeat(64);
+        for name in &["", "Payments", "pay ments", "a.b", &long] {
+            let errors = service(&format!(r#", "name": "{}""#, name))
+                .findings("[0]")
+                .errors;
+            assert_eq!(errors.len(), 1, "{}", name);
+            assert_eq!(errors[0].path, "[0].name");
+        }
+    }
+
+    #[test]
+    fn resource_keys_are_unique() {
+        let wasm = WasmSettings {
+            skip_sha: true,
+            ..Default::default()
+        };
+        // a 3scale backend named after the key of the cluster of the service
+        let auth = |cluster_name: &str| {
+            service(&format!(
+                r#", "auth_config": {{
+                    "path": "static/threescale_wasm_auth.wasm",
+                    "wasm_config": {{"backend": {{"cluster_name": "{}", "url": "https://backend.app/"}}}}
+                }}"#,
+                cluster_name
+            ))
+        };
+        let keys: Vec<_> = auth("backend")
+            .export(&wasm)
+            .unwrap()
+            .into_iter()
+            .map(|export| export.key)
+            .collect();
+        assert_eq!(
+            keys,
+            [
+                "backend",
+                "service::id::1::cluster",
+                "service::id::1::listener"
+            ]
+        );
+
+        let error = auth("service::id::1::cluster").export(&wasm).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "resource key 'service::id::1::cluster' is exported twice"
+        );
+    }
+
+    // The filter the listener of `service` has Envoy fetch.
+    fn remote_filter(service: &Service, wasm: &WasmSettings) -> Result<RemoteDataSource> {
+        use prost::Message;
+
+        let listener = service.export_listener(None, wasm)?;
+        let manager = match listener.filter_chains[0].filters[0].config_type {
+            Some(ConfigType::TypedConfig(ref any)) => {
+                HttpConnectionManager::decode(any.value.as_slice()).unwrap()
+            }
+            ref config => panic!("{:?}", config),
+        };
+        let filter = manager
+            .http_filters
+            .iter()
+            .find(|filter| filter.name == "envoy.filters.http.wasm")
+            .unwrap();
+        let wasm = match filter.config_type {
+            Some(http_filter::ConfigType::TypedConfig(ref any)) => {
+                Wasm::decode(any.value.as_slice()).unwrap()
+            }
+            ref config => panic!("{:?}", config),
+        };
+        match wasm.config.unwrap().vm {
+            Some(Vm::VmConfig(VmConfig {
+                code:
+                    Some(AsyncDataSource {
+                        specifier: Some(Specifier::Remote(remote)),
+                    }),
+                ..
+            })) => Ok(remote),
+            vm => panic!("{:?}", vm),
+        }
+    }
+
+    #[test]
+    fn services_pick_their_filter() {
+        let dir = tempfile::tempdir().unwrap();
+        let (v1, v2) = (
+            dir.path().join("filter.wasm"),
+            dir.path().join("filter-v2.wasm"),
+        );
+        std::fs::write(&v1, "filter v1").unwrap();
+        std::fs::write(&v2, "filter v2").unwrap();
+        let sha = |path| Service::get_wasm_filter_sha(path, DigestAlgorithm::Sha256).unwrap();
+        let mut wasm = WasmSettings {
+            base_url: "http://files".to_string(),
+            filter_path: v1.clone(),
+            ..Default::default()
+        };
+        wasm.modules.insert(
+            "v2".to_string(),
+            WasmModule {
+                path: v2.clone(),
+                sha256: Some(sha(&v2)),
+            },
+        );
+        wasm.check_modules().unwrap();
+
+        let stable = service("");
+        let mut canary = service("");
+        canary.id = 42;
+        canary.wasm_module = Some(WasmModuleRef::Name("v2".to_string()));
+        let (stable_filter, canary_filter) = (
+            remote_filter(&stable, &wasm).unwrap(),
+            remote_filter(&canary, &wasm).unwrap(),
+        );
+        assert_eq!(
+            stable_filter.http_uri.unwrap().uri,
+            format!("http://files{}", v1.display())
+        );
+        assert_eq!(stable_filter.sha256, sha(&v1));
+        assert_eq!(
+            canary_filter.http_uri.unwrap().uri,
+            format!("http://files{}", v2.display())
+        );
+        assert_eq!(canary_filter.sha256, sha(&v2));
+        assert_eq!(stable.wasm_files(&wasm), std::slice::from_ref(&v1));
+        assert_eq!(canary.wasm_files(&wasm), std::slice::from_ref(&v2));
+
+        // a path of its own, next to the services filter
+        canary.wasm_module = Some(WasmModuleRef::Path("filter-v2.wasm".into()));
+        let filter = remote_filter(&canary, &wasm).unwrap();
+        assert_eq!(
+            filter.http_uri.unwrap().uri,
+            format!("http://files{}", v2.display())
+        );
+        assert_eq!(filter.sha256, sha(&v2));
+        assert_eq!(canary.wasm_files(&wasm), std::slice::from_ref(&v2));
+        for outside in &[v1.clone(), "../filter.wasm".into(), "".into()] {
+            canary.wasm_module = Some(WasmModuleRef::Path(outside.clone()));
+            assert!(remote_filter(&canary, &wasm).is_err(), "{:?}", outside);
+            assert_eq!(
+                canary.findings("").errors[0].path,
+                "wasm_module.path",
+                "{:?}",
+                outside
+            );
+        }
+
+        // unknown modules or ones that changed don't export
+        canary.wasm_module = Some(WasmModuleRef::Name("v3".to_string()));
+        assert!(remote_filter(&canary, &wasm).is_err());
+        canary.wasm_module = Some(WasmModuleRef::Name("v2".to_string()));
+        std::fs::write(&v2, "filter v2, patched").unwrap();
+        assert!(remote_filter(&canary, &wasm).is_err());
+        assert!(wasm.check_modules().is_err());
+    }
+
+    #[test]
+    fn services_with_policies_export_their_clusters_filters_and_routes() {
+        use crate::protobuf::envoy::config::core::v3::address::Address as AddressType;
+        use crate::protobuf::envoy::config::endpoint::v3::lb_endpoint::HostIdentifier;
+
+        let mut service = service("");
+        service.target_domain = "https://web.app".to_string();
+        service.policies = serde_json::from_value(serde_json::json!([
+            "cors",
+            {"name": "soap", "configuration": {"rules": [{"pattern": "/soap", "delta": 2}]}},
+            {
+                "name": "routing",
+                "configuration": {
+                    "upstreams": {"v2": "http://v2.backend:8080"},
+                    "rules": [{"upstream": "v2", "path_prefix": "/v2/"}],
+                },
+            },
+        ]))
+        .unwrap();
+        let wasm = WasmSettings {
+            skip_sha: true,
+            ..Default::default()
+        };
+        let exports = service.export(&wasm).unwrap();
+        let names: Vec<_> = exports
+            .iter()
+            .map(|export| (export.key.as_str(), export.config.name()))
+            .collect();
+        assert_eq!(
+            names,
+            [
+                ("service::id::1::cluster", "Cluster::service::1"),
+                ("service::id::1::cluster::v2", "Cluster::service::1::v2"),
+                ("service::id::1::listener", "service 1"),
+            ]
+        );
+
+        // the upstream of each cluster, and whether it is reached over TLS
+        let upstreams: Vec<_> = exports
+            .iter()
+            .filter_map(|export| match export.config {
+                EnvoyResource::Cluster(ref cluster) => Some(cluster),
+                _ => None,
+            })
+            .map(|cluster| {
+                let endpoint =
+                    &cluster.load_assignment.as_ref().unwrap().endpoints[0].lb_endpoints[0];
+                let address = match endpoint.host_identifier {
+                    Some(HostIdentifier::Endpoint(ref endpoint)) => {
+                        endpoint.address.clone().unwrap().address
+                    }
+                    ref identifier => panic!("{:?}", identifier),
+                };
+                let upstream = match address {
+                    Some(AddressType::SocketAddress(address)) => match address.port_specifier {
+                        Some(PortSpecifier::PortValue(port)) => {
+                            format!("{}:{}", address.address, port)
+                        }
+                        port => panic!("{:?}", port),
+                    },
+                    address => panic!("{:?}", address),
+                };
+                (upstream, cluster.transport_socket.is_some())
+            })
+            .collect();
+        assert_eq!(
+            upstreams,
+            [
+                ("web.app:443".to_string(), true),
+                ("v2.backend:8080".to_string(), false),
+            ]
+        );
+
+        // soap goes to the filter, the routing rules to routes of their own
+        assert_eq!(
+            http_filters(&service),
+            [
+                "envoy.filters.http.cors",
+                "envoy.filters.http.wasm",
+                "envoy.filters.http.router",
+            ]
+        );
+        let routes = match connection_manager(&service).route_specifier {
+            Some(RouteSpecifier::RouteConfig(config)) => config.virtual_hosts[0].routes.clone(),
+            specifier => panic!("{:?}", specifier),
+        };
+        let routed: Vec<_> = routes
+            .into_iter()
+            .map(|route| {
+                let cluster = match route.action {
+                    Some(Action::Route(action)) => action.cluster_specifier,
+                    action => panic!("{:?}", action),
+                };
+                (route.r#match.unwrap().path_specifier, cluster)
+            })
+            .collect();
+        let prefix = |prefix: &str| Some(PathSpecifier::Prefix(prefix.to_string()));
+        let to = |cluster: &str| Some(ClusterSpecifier::Cluster(cluster.to_string()));
+        assert_eq!(
+            routed,
+            [
+                (prefix("/v2/"), to("Cluster::service::1::v2")),
+                (prefix("/"), to("Cluster::service::1")),
+            ]
+        );
+    }
+
+    #[test]
+    fn built_services_are_checked() {
+        let rule = || MappingRules::new("/".into(), "GET".into(), "hits".into(), 1);
+        let service = Service::builder()
+            .id(7)
+            .host("web.app")
+            .target_domain("http://web.app:80")
+            .mapping_rule(rule())
+            .build()
+            .unwrap();
+        assert_eq!(service.hosts, ["web.app"]);
+        assert_eq!(service.proxy_rules, [rule()]);
+        assert_eq!(service.no_match_action, NoMatchAction::default());
+        assert_eq!(
+            service,
+            serde_json::from_value(serde_json::to_value(&service).unwrap()).unwrap()
+        );
+
+        let error = Service::builder().host("web.app").build().unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "2 errors: id: is missing; target_domain: is missing"
+        );
+        let error = Service::builder()
+            .id(7)
+            .target_domain("web.app")
+            .mapping_rule(MappingRules::new(
+                "/".into(),
+                "get".into(),
+                "hits".into(),
+                1,
+            ))
+            .build()
+            .unwrap_err();
+        let paths: Vec<_> = error
+            .downcast_ref::<FieldErrors>()
+            .unwrap()
+            .0
+            .iter()
+            .map(|error| error.path.as_str())
+            .collect();
+        assert_eq!(
+            paths,
+            ["hosts", "target_domain", "proxy_rules[0].http_method"]
+        );
+
+        // the policies checked as those of the services files
+        let headers = |remove: &str| {
+            Policy::Headers(crate::policy::Headers {
+                request: crate::policy::HeaderOperations {
+                    remove: vec![remove.to_string()],
+                    ..Default::default()
+                },
+                ..Default::default()
+            })
+        };
+        let built = |policy| {
+            Service::builder()
+                .id(7)
+                .host("web.app")
+                .target_domain("http://web.app:80")
+                .policy(policy)
+                .build()
+        };
+        assert_eq!(
+            built(headers("x-debug")).unwrap().policies,
+            [headers("x-debug")]
+        );
+        let error = built(headers("host")).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "policies[0].configuration.request.remove[0]: 'host' is not a header that can be changed"
+        );
+    }
+
+    #[test]
+    fn policies_are_checked_together_when_some_cannot_be_read() {
+        let routing = |url| {
+            serde_json::json!({"name": "routing", "configuration": {
+                "upstreams": {"v2": url},
+                "rules": [{"upstream": "v2", "path_prefix": "/api"}],
+            }})
+        };
+        let value = serde_json::json!({
+            "id": 1, "hosts": ["one.app"], "target_domain": "http://one.app:80", "proxy_rules": [],
+            "policies": [routing("http://v2.backend"), {"name": "rate_limit"}, routing("http://other.backend")],
+        });
+        let (service, findings) = Service::from_value(&value, "[0]", false);
+        assert!(service.is_none());
+        let paths: Vec<_> = findings.errors.iter().map(|e| e.path.as_str()).collect();
+        assert_eq!(
+            paths,
+            [
+                "[0].policies[1]",
+                "[0].policies[2].configuration.upstreams.v2"
+            ]
+        );
     }
 }
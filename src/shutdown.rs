@@ -0,0 +1,218 @@
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use tokio::sync::watch;
+
+/// Shutdown state shared by the servers and the discovery streams. Once
+/// triggered, new streams are refused, open ones are closed after their
+/// in-flight responses and the controller reports itself as not ready.
+#[derive(Debug, Clone)]
+pub struct Shutdown {
+    trigger: Arc<watch::Sender<bool>>,
+    triggered: watch::Receiver<bool>,
+    // end streams with UNAVAILABLE, so that Envoy keeps its last config and
+    // reconnects with backoff rather than right away
+    notify_streams: bool,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Shutdown::new(false)
+    }
+}
+
+impl Shutdown {
+    pub fn new(notify_streams: bool) -> Shutdown {
+        let (trigger, triggered) = watch::channel(false);
+        Shutdown {
+            trigger: Arc::new(trigger),
+            triggered,
+            notify_streams,
+        }
+    }
+
+    pub fn trigger(&self) {
+        if !self.is_draining() {
+            let _ = self.trigger.broadcast(true);
+        }
+    }
+
+    pub fn is_draining(&self) -> bool {
+        *self.triggered.borrow()
+    }
+
+    /// Resolve once shutdown is triggered.
+    pub async fn wait(&self) {
+        let mut triggered = self.triggered.clone();
+        loop {
+            if *triggered.borrow() {
+                return;
+            }
+            if triggered.recv().await.is_none() {
+                return;
+            }
+        }
+    }
+
+    /// What to end a discovery stream with when shutting down, if anything.
+    pub fn stream_end(&self) -> Option<tonic::Status> {
+        if self.notify_streams {
+            Some(refused())
+        } else {
+            None
+        }
+    }
+
+    /// Trigger shutdown on SIGTERM or SIGINT. SIGHUP reloads what handles
+    /// it, like the services file and the TLS certificates, and is ignored
+    /// otherwise rather than terminating the controller.
+    pub fn on_signals(&self) -> Result<()> {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut terminate = signal(SignalKind::terminate()).context("cannot handle SIGTERM")?;
+        let mut interrupt = signal(SignalKind::interrupt()).context("cannot handle SIGINT")?;
+        let mut hangups = signal(SignalKind::hangup()).context("cannot handle SIGHUP")?;
+        tokio::spawn(async move { while hangups.recv().await.is_some() {} });
+        let shutdown = self.clone();
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = terminate.recv() => {}
+                _ = interrupt.recv() => {}
+            }
+            tracing::info!("Shutting down, draining discovery streams");
+            shutdown.trigger();
+        });
+        Ok(())
+    }
+
+    /// Run `server` until it stops after shutdown was triggered, giving up on
+    /// the connections that are still open after `grace`.
+    pub async fn drain<F, E>(&self, server: F, grace: Duration) -> Result<(), E>
+    where
+        F: Future<Output = Result<(), E>>,
+    {
+        tokio::pin!(server);
+        tokio::select! {
+            result = &mut server => return result,
+            _ = self.wait() => {}
+        }
+        match tokio::time::timeout(grace, server).await {
+            Ok(result) => result,
+            Err(_) => {
+                tracing::warn!("Connections still open after {:?}, closing them", grace);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// What streams opened while draining are refused with.
+pub fn refused() -> tonic::Status {
+    tonic::Status::unavailable("control plane shutting down")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configuration;
+    use crate::envoy_ads;
+    use crate::envoy_helpers::CLUSTER_TYPE_URL;
+    use crate::node_status::NodeStatuses;
+    use crate::protobuf::envoy::service::discovery::v3::aggregated_discovery_service_client::AggregatedDiscoveryServiceClient;
+    use crate::protobuf::envoy::service::discovery::v3::aggregated_discovery_service_server::AggregatedDiscoveryServiceServer;
+    use crate::protobuf::envoy::service::discovery::v3::DiscoveryRequest;
+    use crate::signal_harness;
+    use std::sync::RwLock;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::mpsc;
+    use tonic::transport::{Endpoint, Server, Uri};
+
+    #[tokio::test]
+    async fn open_streams_are_drained_on_shutdown() {
+        let config = Arc::new(RwLock::new(configuration::Config::default()));
+        configuration::publish(
+            &config,
+            configuration::Config::from_services(Vec::new(), "[]"),
+        )
+        .unwrap();
+        let shutdown = Shutdown::new(true);
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let mut listener = TcpListener::from_std(listener).unwrap();
+        let ads = envoy_ads::ADS::new(config, NodeStatuses::default(), shutdown.clone());
+        let server = {
+            let shutdown = shutdown.clone();
+            tokio::spawn(async move {
+                let server = Server::builder()
+                    .add_service(AggregatedDiscoveryServiceServer::new(ads))
+                    .serve_with_incoming_shutdown(listener.incoming(), shutdown.wait());
+                shutdown.drain(server, Duration::from_secs(5)).await
+            })
+        };
+
+        // fake Envoy holding an ADS stream open
+        let channel = Endpoint::from_static("http://localhost")
+            .connect_with_connector(tower::service_fn(move |_: Uri| async move {
+                let stream = std::net::TcpStream::connect(addr)?;
+                stream.set_nonblocking(true)?;
+                TcpStream::from_std(stream)
+            }))
+            .await
+            .unwrap();
+        let (mut requests, stream) = mpsc::channel(1);
+        requests
+            .send(DiscoveryRequest {
+                type_url: CLUSTER_TYPE_URL.to_string(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        let mut responses = AggregatedDiscoveryServiceClient::new(channel)
+            .stream_aggregated_resources(stream)
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(responses.message().await.unwrap().is_some());
+
+        shutdown.trigger();
+        let end = tokio::time::timeout(Duration::from_secs(2), responses.message())
+            .await
+            .expect("the stream was not closed");
+        assert_eq!(end.unwrap_err().code(), tonic::Code::Unavailable);
+        tokio::time::timeout(Duration::from_secs(2), server)
+            .await
+            .expect("the server did not stop")
+            .unwrap()
+            .unwrap();
+        drop(requests);
+    }
+
+    #[tokio::test]
+    async fn hangups_are_ignored() {
+        if signal_harness::in_child("shutdown::tests::hangups_are_ignored") {
+            return;
+        }
+        let shutdown = Shutdown::default();
+        shutdown.on_signals().unwrap();
+        signal_harness::raise("HUP");
+        // a SIGHUP nothing handled would have terminated the child by now
+        tokio::time::delay_for(Duration::from_millis(200)).await;
+        assert!(!shutdown.is_draining());
+    }
+
+    #[tokio::test]
+    async fn stuck_connections_are_given_up_on() {
+        let shutdown = Shutdown::default();
+        shutdown.trigger();
+        let never = futures::future::pending::<Result<(), ()>>();
+        let drained = shutdown.drain(never, Duration::from_millis(10));
+        assert_eq!(
+            tokio::time::timeout(Duration::from_secs(1), drained).await,
+            Ok(Ok(()))
+        );
+    }
+}
@@ -0,0 +1,44 @@
+use std::process::Command;
+
+// Set in the environment of the child processes of `in_child`.
+const CHILD: &str = "GATEWAY_NG_CONTROLLER_TEST_CHILD";
+
+/// Run the test `name`, as in `shutdown::tests::hangups_are_ignored`,
+/// again in a child process of the test binary, for the signals it sends
+/// itself to reach no other test. True in the parent once the child
+/// passed, false in the child, which goes on with the test.
+pub fn in_child(name: &str) -> bool {
+    if std::env::var_os(CHILD).is_some() {
+        return false;
+    }
+    let output = Command::new(std::env::current_exe().unwrap())
+        .args([name, "--exact", "--test-threads", "1"])
+        .env(CHILD, "1")
+        .output()
+        .unwrap();
+    let stdout = std::string::String::from_utf8_lossy(&output.stdout);
+    assert!(
+        output.status.success(),
+        "{} failed in its child process, {}:\n{}{}",
+        name,
+        output.status,
+        stdout,
+        std::string::String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(stdout.contains("1 passed"), "no test {}:\n{}", name, stdout);
+    true
+}
+
+/// Send the signal `name`, as in `HUP`, to the child process of `in_child`
+/// the test runs in.
+pub fn raise(name: &str) {
+    assert!(
+        std::env::var_os(CHILD).is_some(),
+        "signals are only sent to child processes"
+    );
+    let status = Command::new("kill")
+        .args([&format!("-{}", name), &std::process::id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+}
@@ -0,0 +1,213 @@
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use data_encoding::HEXLOWER;
+use ring::digest;
+
+use crate::envoy_helpers::{canonical_hash, EnvoyExport};
+
+/// A resource ready to be sent to Envoy, versioned by its content so that
+/// only the resources that actually changed are resent.
+#[derive(Debug, Clone)]
+pub struct VersionedResource {
+    pub name: std::string::String,
+    pub version: std::string::String,
+    pub resource: prost_types::Any,
+    // how many exports share the resource, like the services of an OIDC
+    // issuer its cluster: it leaves the snapshot with the last of them
+    pub references: usize,
+}
+
+pub type Resources = BTreeMap<std::string::String, VersionedResource>;
+
+/// The encoded resources of a config version, by type URL and name.
+///
+/// Every type gets a version derived from the versions of its resources and
+/// the snapshot a hash of all of them, so identical resources always end up
+/// with identical versions.
+#[derive(Debug, Clone, Default)]
+pub struct Snapshot {
+    version: u32,
+    hash: std::string::String,
+    resources: HashMap<&'static str, Resources>,
+    type_versions: BTreeMap<&'static str, std::string::String>,
+    // names of the resources of the previous version that are gone
+    removed: BTreeMap<&'static str, BTreeSet<std::string::String>>,
+}
+
+fn content_hash(data: &[u8]) -> std::string::String {
+    HEXLOWER.encode(digest::digest(&digest::SHA256, data).as_ref())
+}
+
+impl Snapshot {
+    pub fn new<'a>(version: u32, exports: impl IntoIterator<Item = &'a EnvoyExport>) -> Snapshot {
+        let mut resources: HashMap<&'static str, Resources> = HashMap::new();
+        for export in exports {
+            let resource = match export.config.to_any() {
+                Ok(resource) => resource,
+                Err(e) => {
+                    tracing::error!("Cannot encode {}: {:#}", export.key, e);
+                    continue;
+                }
+            };
+            let name = export.config.name().to_string();
+            let version = HEXLOWER.encode(&canonical_hash(&export.config));
+            match resources
+                .entry(export.config.type_url())
+                .or_default()
+                .entry(name.clone())
+            {
+                // shared as they are, the exports claiming their names
+                // being refused otherwise
+                Entry::Occupied(mut shared) => shared.get_mut().references += 1,
+                Entry::Vacant(entry) => {
+                    entry.insert(VersionedResource {
+                        name,
+                        version,
+                        resource,
+                        references: 1,
+                    });
+                }
+            }
+        }
+
+        let type_versions: BTreeMap<&'static str, std::string::String> = resources
+            .iter()
+            .map(|(type_url, resources)| {
+                let mut content = std::string::String::new();
+                for resource in resources.values() {
+                    content.push_str(&resource.name);
+                    content.push('\n');
+                    content.push_str(&resource.version);
+                    content.push('\n');
+                }
+                (*type_url, content_hash(content.as_bytes()))
+            })
+            .collect();
+
+        let mut content = std::string::String::new();
+        for (type_url, version) in &type_versions {
+            content.push_str(type_url);
+            content.push('\n');
+            content.push_str(version);
+            content.push('\n');
+        }
+
+        Snapshot {
+            version,
+            hash: content_hash(content.as_bytes()),
+            resources,
+            type_versions,
+            removed: BTreeMap::new(),
+        }
+    }
+
+    /// The snapshot following `previous`, knowing which of its resources
+    /// were removed. State-of-the-world responses leave them out as any
+    /// other resource missing, delta ones list them as removed.
+    pub fn following(mut self, previous: &Snapshot) -> Snapshot {
+        for (type_url, resources) in &previous.resources {
+            let current = self.resources.get(type_url);
+            let removed: BTreeSet<_> = resources
+                .keys()
+                .filter(|name| !current.map(|c| c.contains_key(*name)).unwrap_or(false))
+                .cloned()
+                .collect();
+            if !removed.is_empty() {
+                self.removed.insert(type_url, removed);
+            }
+        }
+        self
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// Hash of every resource in the snapshot.
+    pub fn hash(&self) -> &str {
+        &self.hash
+    }
+
+    pub fn resources(&self, type_url: &str) -> Option<&Resources> {
+        self.resources.get(type_url)
+    }
+
+    /// Version of the resources of a type, the same for identical resources.
+    pub fn type_version(&self, type_url: &str) -> std::string::String {
+        self.type_versions
+            .get(type_url)
+            .cloned()
+            .unwrap_or_else(|| content_hash(b""))
+    }
+
+    pub fn type_versions(&self) -> &BTreeMap<&'static str, std::string::String> {
+        &self.type_versions
+    }
+
+    /// Names of the resources of the previous version that are gone, by
+    /// type URL.
+    pub fn removed(&self) -> &BTreeMap<&'static str, BTreeSet<std::string::String>> {
+        &self.removed
+    }
+
+    pub fn removed_count(&self) -> usize {
+        self.removed.values().map(BTreeSet::len).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::envoy_helpers::{get_envoy_cluster, EnvoyResource, CLUSTER_TYPE_URL};
+
+    fn exports(services: &[(u32, &str)]) -> Vec<EnvoyExport> {
+        services
+            .iter()
+            .map(|(id, url)| EnvoyExport {
+                key: format!("service::id::{}::cluster", id),
+                config: EnvoyResource::Cluster(
+                    get_envoy_cluster(
+                        format!("Cluster::service::{}", id),
+                        url,
+                        &Default::default(),
+                    )
+                    .unwrap(),
+                ),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn identical_resources_hash_the_same() {
+        let first = Snapshot::new(1, &exports(&[(1, "http://one:80"), (2, "http://two:80")]));
+        // order of the exports doesn't matter
+        let second = Snapshot::new(2, &exports(&[(2, "http://two:80"), (1, "http://one:80")]));
+
+        assert_eq!(first.hash(), second.hash());
+        assert_eq!(
+            first.type_version(CLUSTER_TYPE_URL),
+            second.type_version(CLUSTER_TYPE_URL)
+        );
+    }
+
+    #[test]
+    fn only_changed_resources_get_new_versions() {
+        let first = Snapshot::new(1, &exports(&[(1, "http://one:80"), (2, "http://two:80")]));
+        let second = Snapshot::new(2, &exports(&[(1, "http://one:80"), (2, "http://two:81")]));
+        let (first_clusters, second_clusters) = (
+            first.resources(CLUSTER_TYPE_URL).unwrap(),
+            second.resources(CLUSTER_TYPE_URL).unwrap(),
+        );
+
+        assert_ne!(first.hash(), second.hash());
+        assert_eq!(
+            first_clusters["Cluster::service::1"].version,
+            second_clusters["Cluster::service::1"].version
+        );
+        assert_ne!(
+            first_clusters["Cluster::service::2"].version,
+            second_clusters["Cluster::service::2"].version
+        );
+    }
+}
@@ -0,0 +1,170 @@
+use std::sync::{Arc, RwLock};
+
+use tokio::sync::watch;
+
+use crate::configuration::Config;
+use crate::node_status::Nack;
+use crate::protobuf::envoy::config::core::v3::Node;
+use crate::snapshot::Snapshot;
+
+/// What the discovery streams serve: the snapshot of each node group, and
+/// where the acks and nacks of the nodes go.
+///
+/// The streams depend on nothing else, the config being served being one
+/// cache among others, like the mocks of the tests.
+pub trait SnapshotCache: std::fmt::Debug + Send + Sync {
+    /// The node group of an Envoy node.
+    fn node_group(&self, node: Option<&Node>) -> std::string::String;
+
+    /// The snapshot served to the nodes of `group`.
+    fn snapshot(&self, group: &str) -> Arc<Snapshot>;
+
+    /// The versions published from now on.
+    fn subscribe(&self) -> Subscription;
+
+    /// Handle `node` of `group` acking the `version` of `type_url`
+    /// resources.
+    fn on_ack(&self, group: &str, node: &str, type_url: &str, version: &str);
+
+    /// Handle `node` rejecting `type_url` resources while it runs the
+    /// `running` version of them, returning whether a new version was
+    /// published.
+    fn on_nack(&self, node: &str, type_url: &str, nack: &Nack, running: &str) -> bool;
+}
+
+impl SnapshotCache for RwLock<Config> {
+    fn node_group(&self, node: Option<&Node>) -> std::string::String {
+        self.read().unwrap().node_group(node)
+    }
+
+    fn snapshot(&self, group: &str) -> Arc<Snapshot> {
+        self.read().unwrap().group_snapshot(group)
+    }
+
+    fn subscribe(&self) -> Subscription {
+        self.read().unwrap().versions().subscribe()
+    }
+
+    fn on_ack(&self, group: &str, node: &str, type_url: &str, version: &str) {
+        self.write().unwrap().on_ack(group, node, type_url, version)
+    }
+
+    fn on_nack(&self, node: &str, type_url: &str, nack: &Nack, running: &str) -> bool {
+        self.write().unwrap().on_nack(node, type_url, nack, running)
+    }
+}
+
+/// The versions of a cache, for streams to wake up as soon as a new one is
+/// published rather than at their next poll. Clones publish to the same
+/// subscriptions.
+#[derive(Debug, Clone)]
+pub struct Versions {
+    sender: Arc<watch::Sender<u32>>,
+    // keeps the channel open while nothing subscribes
+    receiver: watch::Receiver<u32>,
+}
+
+impl Default for Versions {
+    fn default() -> Versions {
+        let (sender, receiver) = watch::channel(0);
+        Versions {
+            sender: Arc::new(sender),
+            receiver,
+        }
+    }
+}
+
+impl Versions {
+    pub fn publish(&self, version: u32) {
+        let _ = self.sender.broadcast(version);
+    }
+
+    /// The versions published after the current one.
+    pub fn subscribe(&self) -> Subscription {
+        Subscription {
+            seen: *self.receiver.borrow(),
+            receiver: self.receiver.clone(),
+        }
+    }
+}
+
+/// The versions published to a subscriber, each of them once, those
+/// published in between two calls being skipped for the last one.
+#[derive(Debug)]
+pub struct Subscription {
+    receiver: watch::Receiver<u32>,
+    seen: u32,
+}
+
+impl Subscription {
+    /// The next version published, `None` once the cache is gone.
+    pub async fn next(&mut self) -> Option<u32> {
+        while let Some(version) = self.receiver.recv().await {
+            if version != self.seen {
+                self.seen = version;
+                return Some(version);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    use crate::envoy_helpers::{get_envoy_cluster, EnvoyExport, EnvoyResource};
+
+    fn exports(url: &str) -> Vec<EnvoyExport> {
+        vec![EnvoyExport {
+            key: "service::id::1::cluster".to_string(),
+            config: EnvoyResource::Cluster(
+                get_envoy_cluster("Cluster::service::1".to_string(), url, &Default::default())
+                    .unwrap(),
+            ),
+        }]
+    }
+
+    // Serve `exports` to the nodes of `group` as version `version` of them,
+    // as the config does when it imports services.
+    fn publish(cache: &RwLock<Config>, group: &str, version: u32, exports: &[EnvoyExport]) {
+        let snapshot = Arc::new(Snapshot::new(version, exports));
+        cache.write().unwrap().set_group_snapshot(group, snapshot);
+    }
+
+    // The next version, unless none is published in a while.
+    async fn next(subscription: &mut Subscription) -> Option<u32> {
+        tokio::time::timeout(Duration::from_millis(100), subscription.next())
+            .await
+            .ok()
+            .flatten()
+    }
+
+    #[tokio::test]
+    async fn publishing_wakes_subscribers_once_per_version() {
+        let cache = RwLock::new(Config::default());
+        let mut first = cache.subscribe();
+        let mut second = cache.subscribe();
+        // nothing published yet
+        assert_eq!(next(&mut first).await, None);
+
+        publish(&cache, "default", 1, &exports("http://a"));
+        assert_eq!(next(&mut first).await, Some(1));
+        assert_eq!(next(&mut first).await, None);
+        assert_eq!(next(&mut second).await, Some(1));
+        assert_eq!(cache.snapshot("default").version(), 1);
+
+        // a subscription starts at the current version
+        let mut late = cache.subscribe();
+        assert_eq!(next(&mut late).await, None);
+
+        // versions published in between are skipped
+        publish(&cache, "default", 2, &exports("http://b"));
+        publish(&cache, "edge", 1, &exports("http://c"));
+        assert_eq!(next(&mut first).await, Some(3));
+        assert_eq!(next(&mut first).await, None);
+        assert_eq!(next(&mut late).await, Some(3));
+        assert_eq!(cache.snapshot("edge").version(), 1);
+    }
+}
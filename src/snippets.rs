@@ -0,0 +1,209 @@
+use crate::field_errors::{field, index, FieldError};
+
+/// Field of an object naming the snippets it includes, one or a list of
+/// them.
+pub const INCLUDE: &str = "include";
+
+/// Expand the snippets the service `value`, found at `path`, includes. The
+/// service and each of its policies may include snippets of `snippets`, the
+/// `snippets` map of its services file: the snippets are merged in the order
+/// they are named, and the fields of the service or policy itself on top.
+/// Objects are merged field by field, anything else, lists included, is
+/// replaced. Snippets may include others, but not themselves. The `include`
+/// of any other object, as the configurations passed through to the
+/// filters, is theirs and left as it is.
+pub fn expand(
+    value: &mut serde_json::Value,
+    snippets: &serde_json::Map<std::string::String, serde_json::Value>,
+    path: &str,
+) -> Result<(), FieldError> {
+    expand_at(value, snippets, path, &mut Vec::new()).map_err(|mut error| {
+        error.service = value.get("id").and_then(serde_json::Value::as_u64);
+        error
+    })
+}
+
+// Expand the service, policy or snippet `value`, `chain` holding the
+// snippets being expanded, the innermost last.
+fn expand_at(
+    value: &mut serde_json::Value,
+    snippets: &serde_json::Map<std::string::String, serde_json::Value>,
+    path: &str,
+    chain: &mut Vec<std::string::String>,
+) -> Result<(), FieldError> {
+    let fields = match value {
+        serde_json::Value::Object(fields) => fields,
+        _ => return Ok(()),
+    };
+    let include = field(path, INCLUDE);
+    let names = match fields.remove(INCLUDE) {
+        None => Vec::new(),
+        Some(serde_json::Value::String(name)) => vec![name],
+        Some(serde_json::Value::Array(names)) => names
+            .into_iter()
+            .map(|name| match name {
+                serde_json::Value::String(name) => Ok(name),
+                _ => Err(FieldError::new(&include, "snippets are named by strings")),
+            })
+            .collect::<Result<_, _>>()?,
+        Some(_) => {
+            return Err(FieldError::new(
+                include,
+                "must name a snippet or a list of them",
+            ))
+        }
+    };
+    if let Some(serde_json::Value::Array(policies)) = fields.get_mut("policies") {
+        let policies_path = field(path, "policies");
+        for (i, policy) in policies.iter_mut().enumerate() {
+            expand_at(policy, snippets, &index(&policies_path, i), chain)?;
+        }
+    }
+
+    let mut merged = serde_json::Map::new();
+    for name in names {
+        if chain.contains(&name) {
+            let mut cycle = chain.clone();
+            cycle.push(name.clone());
+            let message = format!("snippet '{}' includes itself: {}", name, cycle.join(" -> "));
+            return Err(FieldError::new(include, message));
+        }
+        let mut snippet = match snippets.get(&name) {
+            Some(snippet @ serde_json::Value::Object(_)) => snippet.clone(),
+            Some(_) => {
+                let message = format!("snippet '{}' is not an object", name);
+                return Err(FieldError::new(include, message));
+            }
+            None => {
+                let message = format!("unknown snippet '{}'", name);
+                return Err(FieldError::new(include, message));
+            }
+        };
+        // errors in a snippet point into the snippets map
+        chain.push(name.clone());
+        expand_at(&mut snippet, snippets, &field("snippets", &name), chain)?;
+        chain.pop();
+        if let serde_json::Value::Object(snippet) = snippet {
+            merge(&mut merged, snippet);
+        }
+    }
+    if !merged.is_empty() {
+        merge(&mut merged, std::mem::take(fields));
+        *fields = merged;
+    }
+    Ok(())
+}
+
+// Merge the fields of `overriding` into `target`, theirs winning.
+fn merge(
+    target: &mut serde_json::Map<std::string::String, serde_json::Value>,
+    overriding: serde_json::Map<std::string::String, serde_json::Value>,
+) {
+    for (name, value) in overriding {
+        match (target.get_mut(&name), value) {
+            (Some(serde_json::Value::Object(target)), serde_json::Value::Object(value)) => {
+                merge(target, value)
+            }
+            (_, value) => {
+                target.insert(name, value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snippets(
+        value: serde_json::Value,
+    ) -> serde_json::Map<std::string::String, serde_json::Value> {
+        match value {
+            serde_json::Value::Object(snippets) => snippets,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn services_override_their_snippets() {
+        let snippets = snippets(serde_json::json!({
+            "defaults": {
+                "target_domain": "http://default:80",
+                "auth_config": {"path": "auth.wasm", "wasm_config": {"a": 1, "b": 1}},
+                "hosts": ["default"],
+            },
+            "cors": {"name": "cors", "configuration": {"allow_origin": "*"}},
+            "strict": {"auth_config": {"wasm_config": {"b": 2}}},
+        }));
+        let mut service = serde_json::json!({
+            "id": 3,
+            "include": ["defaults", "strict"],
+            "hosts": ["three"],
+            "auth_config": {"wasm_config": {"c": 3}},
+            "policies": [{"include": "cors", "configuration": {"max_age": 60}}],
+        });
+        expand(&mut service, &snippets, "[0]").unwrap();
+        assert_eq!(
+            service,
+            serde_json::json!({
+                "id": 3,
+                "target_domain": "http://default:80",
+                // lists are replaced, objects merged, later snippets winning
+                "hosts": ["three"],
+                "auth_config": {"path": "auth.wasm", "wasm_config": {"a": 1, "b": 2, "c": 3}},
+                "policies": [{"name": "cors", "configuration": {"allow_origin": "*", "max_age": 60}}],
+            })
+        );
+
+        let mut service = serde_json::json!({"id": 4, "include": "missing"});
+        assert_eq!(
+            expand(&mut service, &snippets, "[1]")
+                .unwrap_err()
+                .to_string(),
+            "[1].include (service 4): unknown snippet 'missing'"
+        );
+    }
+
+    #[test]
+    fn passed_through_settings_keep_their_includes() {
+        let snippets = snippets(serde_json::json!({"cors": {"name": "cors"}}));
+        let mut service = serde_json::json!({
+            "id": 5,
+            "auth_config": {"wasm_config": {"include": "cors"}},
+            "policies": [{"name": "headers", "configuration": {"include": ["cors"]}}],
+        });
+        let expected = service.clone();
+        expand(&mut service, &snippets, "[0]").unwrap();
+        assert_eq!(service, expected);
+    }
+
+    #[test]
+    fn recursive_includes_are_refused() {
+        let snippets = snippets(serde_json::json!({
+            "a": {"include": "b"},
+            "b": {"policies": [{"include": ["c", "a"]}]},
+            "c": {"name": "c"},
+            "self": {"include": "self"},
+        }));
+        let mut service = serde_json::json!({"id": 1, "include": "a"});
+        assert_eq!(
+            expand(&mut service, &snippets, "[0]")
+                .unwrap_err()
+                .to_string(),
+            "snippets.b.policies[0].include (service 1): snippet 'a' includes itself: a -> b -> a"
+        );
+
+        let mut service = serde_json::json!({"id": 2, "include": "self"});
+        let error = expand(&mut service, &snippets, "[1]").unwrap_err();
+        assert_eq!(error.path, "snippets.self.include");
+        assert_eq!(
+            error.message,
+            "snippet 'self' includes itself: self -> self"
+        );
+
+        // a snippet included twice, but not by itself, is fine
+        let mut service = serde_json::json!({"id": 3, "include": ["c", "c"]});
+        expand(&mut service, &snippets, "[2]").unwrap();
+        assert_eq!(service, serde_json::json!({"id": 3, "name": "c"}));
+    }
+}
@@ -0,0 +1,171 @@
+use std::sync::Arc;
+
+use anyhow::{bail, Result};
+
+use crate::cli::ControllerConfig;
+#[cfg(feature = "git-source")]
+use crate::git;
+#[cfg(feature = "kube-source")]
+use crate::kubernetes;
+use crate::porta;
+use crate::publisher::Publisher;
+use crate::reload::Reloader;
+use crate::remote;
+use crate::watcher;
+
+/// Kind of source the services are read from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    File,
+    Porta,
+    Http,
+    #[cfg(feature = "git-source")]
+    Git,
+    #[cfg(feature = "kube-source")]
+    Kube,
+}
+
+impl Kind {
+    /// Names of the kinds this build supports.
+    pub fn names() -> Vec<&'static str> {
+        #[allow(unused_mut)]
+        let mut names = vec!["file", "porta", "http"];
+        #[cfg(feature = "git-source")]
+        names.push("git");
+        #[cfg(feature = "kube-source")]
+        names.push("kube");
+        names
+    }
+}
+
+impl std::str::FromStr for Kind {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> Result<Kind> {
+        match name {
+            "file" => Ok(Kind::File),
+            "porta" => Ok(Kind::Porta),
+            "http" => Ok(Kind::Http),
+            #[cfg(feature = "git-source")]
+            "git" => Ok(Kind::Git),
+            #[cfg(feature = "kube-source")]
+            "kube" => Ok(Kind::Kube),
+            _ => bail!("unknown services source '{}'", name),
+        }
+    }
+}
+
+/// Where the services configuration comes from.
+pub enum Source {
+    /// A JSON or YAML file, reloaded whenever it changes. The format is the
+    /// one of its extension unless given.
+    File(Arc<watcher::ConfigWatcher>),
+    /// The 3scale Porta Admin API, polled periodically.
+    Porta(porta::PortaSource),
+    /// A services document served over HTTP(S), polled periodically.
+    Http(remote::HttpSource),
+    /// Services files of a branch of a git repository, polled periodically.
+    #[cfg(feature = "git-source")]
+    Git(git::GitSource),
+    /// `GatewayService` custom resources, watched in the configured
+    /// namespaces.
+    #[cfg(feature = "kube-source")]
+    Kube(kubernetes::KubeSource),
+}
+
+impl Source {
+    /// Set up the source of `settings`, the file source reading the services
+    /// config in the parse options, as the HTTP and git sources read theirs,
+    /// and publishing with `publisher`. The Porta and HTTP sources request
+    /// through the HTTP client of the controller.
+    pub fn new(settings: &ControllerConfig, publisher: &Publisher) -> Result<Source> {
+        let options = settings.parse_options();
+        let client = &settings.wasm.http_client;
+        match settings.services_source {
+            Kind::File => Ok(Source::File(Arc::new(watcher::ConfigWatcher::new(
+                &settings.services_config,
+                options,
+                publisher.clone(),
+            )))),
+            Kind::Porta => match settings.porta_source {
+                Some(ref porta) => Ok(Source::Porta(porta::PortaSource::from_settings(
+                    porta,
+                    client,
+                    options.host_conflicts,
+                ))),
+                None => bail!("the porta source needs --porta-admin-url and --porta-access-token"),
+            },
+            Kind::Http => match settings.http_source {
+                Some(ref http) => Ok(Source::Http(remote::HttpSource::from_settings(
+                    http, options, client,
+                ))),
+                None => bail!("the http source needs --http-source-url"),
+            },
+            #[cfg(feature = "git-source")]
+            Kind::Git => match settings.git_source {
+                Some(ref git) => Ok(Source::Git(git::GitSource::from_settings(git, options))),
+                None => bail!("the git source needs --git-source-url"),
+            },
+            #[cfg(feature = "kube-source")]
+            Kind::Kube => Ok(Source::Kube(kubernetes::KubeSource::new(
+                settings.kube_namespaces.clone(),
+                options,
+            ))),
+        }
+    }
+
+    /// Let `reloader` reload the services on demand, for the sources that
+    /// load them as a whole. Kubernetes resources are watched one by one.
+    pub fn install_reload(&self, reloader: &Reloader, publisher: &Publisher) {
+        let config = Arc::clone(publisher.config());
+        match self {
+            Source::File(watcher) => {
+                let watcher = Arc::clone(watcher);
+                reloader.install(config, move || watcher.reload());
+            }
+            Source::Porta(porta) => {
+                let porta = porta.clone();
+                let publisher = publisher.clone();
+                reloader.install(config, move || porta.reload(&publisher));
+            }
+            Source::Http(http) => {
+                let http = http.clone();
+                let publisher = publisher.clone();
+                reloader.install(config, move || http.reload(&publisher));
+            }
+            #[cfg(feature = "git-source")]
+            Source::Git(git) => {
+                let git = git.clone();
+                let publisher = publisher.clone();
+                reloader.install(config, move || git.reload(&publisher));
+            }
+            #[cfg(feature = "kube-source")]
+            Source::Kube(_) => {}
+        }
+    }
+
+    /// Load the services once and keep them up to date in the background.
+    pub fn spawn(self, publisher: Publisher) -> Result<()> {
+        match self {
+            Source::File(watcher) => watcher.spawn(),
+            Source::Porta(porta) => {
+                porta.spawn(publisher);
+                Ok(())
+            }
+            Source::Http(http) => {
+                http.spawn(publisher);
+                Ok(())
+            }
+            #[cfg(feature = "git-source")]
+            Source::Git(git) => {
+                git.spawn(publisher);
+                Ok(())
+            }
+            #[cfg(feature = "kube-source")]
+            Source::Kube(kube) => {
+                kube.spawn(publisher);
+                Ok(())
+            }
+        }
+    }
+}
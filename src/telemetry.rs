@@ -0,0 +1,183 @@
+use tracing_subscriber::registry::LookupSpan;
+
+pub const DEFAULT_SERVICE_NAME: &str = env!("CARGO_PKG_NAME");
+
+/// Where the spans of the controller are exported to over OTLP, as those
+/// of a reload up to the first ack of what it published. Without the `otel`
+/// feature the controller has no exporter, and refuses these settings.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Telemetry {
+    // the gRPC endpoint of the collector, as http://otel-collector:4317
+    pub endpoint: std::string::String,
+    // of the traces the controller starts, from 0 to 1, the spans within
+    // one going with it
+    pub sampling_ratio: f64,
+    pub service_name: std::string::String,
+}
+
+/// Keeps the exporter running, the spans not exported yet being flushed
+/// when dropped.
+pub struct Guard {
+    #[cfg(feature = "otel")]
+    _uninstall: Option<opentelemetry_otlp::Uninstall>,
+}
+
+#[cfg(feature = "otel")]
+impl Telemetry {
+    /// The sampler and resource of the spans exported.
+    pub fn trace_config(&self) -> opentelemetry::sdk::trace::Config {
+        use opentelemetry::sdk::trace::Sampler;
+        // the spans within a trace follow the decision of its root
+        let sampler =
+            Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(self.sampling_ratio)));
+        opentelemetry::sdk::trace::config()
+            .with_default_sampler(sampler)
+            .with_resource(opentelemetry::sdk::Resource::new(vec![
+                opentelemetry::KeyValue::new("service.name", self.service_name.clone()),
+            ]))
+    }
+}
+
+/// The layer exporting the spans to the collector of `settings`, if any,
+/// with the guard to keep for as long as the controller runs. It has to be
+/// called within the runtime, the spans being exported in batches by a
+/// task of it.
+#[cfg(feature = "otel")]
+pub fn layer<S>(
+    settings: Option<&Telemetry>,
+) -> anyhow::Result<(impl tracing_subscriber::Layer<S>, Guard)>
+where
+    S: tracing::Subscriber + for<'a> LookupSpan<'a>,
+{
+    let settings = match settings {
+        Some(settings) => settings,
+        None => return Ok((None, Guard { _uninstall: None })),
+    };
+    let (tracer, uninstall) = opentelemetry_otlp::new_pipeline()
+        .with_endpoint(settings.endpoint.as_str())
+        .with_trace_config(settings.trace_config())
+        .install()?;
+    let layer = tracing_opentelemetry::layer().with_tracer(tracer);
+    Ok((
+        Some(layer),
+        Guard {
+            _uninstall: Some(uninstall),
+        },
+    ))
+}
+
+/// No layer at all, the command line refusing the settings of an exporter.
+#[cfg(not(feature = "otel"))]
+pub fn layer<S>(_: Option<&Telemetry>) -> anyhow::Result<(impl tracing_subscriber::Layer<S>, Guard)>
+where
+    S: tracing::Subscriber + for<'a> LookupSpan<'a>,
+{
+    Ok((tracing_subscriber::layer::Identity::new(), Guard {}))
+}
+
+#[cfg(all(test, feature = "otel"))]
+mod tests {
+    use super::*;
+    use crate::configuration::{self, Config, DEFAULT_NODE_GROUP};
+    use crate::envoy_helpers::CLUSTER_TYPE_URL;
+    use crate::service::WasmSettings;
+    use opentelemetry::sdk::export::trace::SpanData;
+    use opentelemetry::trace::TracerProvider;
+    use opentelemetry::{Key, Value};
+    use std::sync::RwLock;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    fn attribute(span: &SpanData, key: &'static str) -> Option<Value> {
+        span.attributes.get(&Key::new(key)).cloned()
+    }
+
+    #[test]
+    fn a_reload_cycle_is_exported_as_spans() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("services.json");
+        std::fs::write(
+            &path,
+            r#"[{"id": 1, "hosts": ["one"], "policies": [], "target_domain": "http://one:80", "proxy_rules": []}]"#,
+        )
+        .unwrap();
+        let mut config = Config::default();
+        config.set_wasm(WasmSettings {
+            skip_sha: true,
+            ..Default::default()
+        });
+        let shared = RwLock::new(config);
+
+        let settings = Telemetry {
+            endpoint: "http://127.0.0.1:4317".to_string(),
+            sampling_ratio: 1.0,
+            service_name: "controller-eu".to_string(),
+        };
+        let (exporter, exported, _shutdown) = opentelemetry::testing::trace::new_test_exporter();
+        let provider = opentelemetry::sdk::trace::TracerProvider::builder()
+            .with_simple_exporter(exporter)
+            .with_config(settings.trace_config())
+            .build();
+        let layer = tracing_opentelemetry::layer().with_tracer(provider.get_tracer("tests", None));
+        tracing::subscriber::with_default(tracing_subscriber::registry().with(layer), || {
+            tracing::info_span!("reload").in_scope(|| {
+                let services =
+                    Config::parse_config(&path.to_string_lossy(), Default::default()).unwrap();
+                assert!(configuration::publish(&shared, services).unwrap());
+            });
+            let version = shared
+                .read()
+                .unwrap()
+                .get_snapshot()
+                .type_version(CLUSTER_TYPE_URL);
+            shared.write().unwrap().on_ack(
+                DEFAULT_NODE_GROUP,
+                "envoy-1",
+                CLUSTER_TYPE_URL,
+                &version,
+            );
+            // the versions of the other types, never acked, keep the
+            // publication open until then
+            drop(shared);
+        });
+
+        let spans: Vec<SpanData> = exported.try_iter().collect();
+        let span = |name: &str| {
+            spans
+                .iter()
+                .find(|span| span.name == name)
+                .unwrap_or_else(|| panic!("no {} span in {:?}", name, spans))
+        };
+        let (reload, publish, ack) = (span("reload"), span("publish"), span("ack"));
+        let trace_id = reload.span_context.trace_id();
+        for name in &["fetch", "validate", "publish"] {
+            assert_eq!(span(name).parent_span_id, reload.span_context.span_id());
+            assert_eq!(span(name).span_context.trace_id(), trace_id);
+        }
+        assert_eq!(
+            span("export_services").parent_span_id,
+            publish.span_context.span_id()
+        );
+        assert!(attribute(publish, "version").is_some());
+
+        // the ack is a trace of its own, linked to the propagation of the
+        // version, which the publish span is the parent of
+        let propagation = spans
+            .iter()
+            .find(|span| {
+                span.name == "propagation"
+                    && attribute(span, "type_url") == Some(CLUSTER_TYPE_URL.into())
+            })
+            .unwrap();
+        assert_eq!(propagation.parent_span_id, publish.span_context.span_id());
+        assert_ne!(ack.span_context.trace_id(), trace_id);
+        let links: Vec<_> = ack.links.iter().collect();
+        assert_eq!(links.len(), 1);
+        assert_eq!(links[0].span_context(), &propagation.span_context);
+        assert_eq!(attribute(ack, "node.id"), Some("envoy-1".into()));
+        assert!(spans.iter().all(|span| {
+            span.resource.iter().any(|(key, value)| {
+                key.as_str() == "service.name" && value == &"controller-eu".into()
+            })
+        }));
+    }
+}
@@ -1,4 +1,6 @@
-use crate::envoy_helpers::{encode, get_envoy_cluster};
+use crate::envoy_helpers::{get_envoy_cluster, json_to_struct};
+use crate::field_errors::{field, index, Findings, Validate};
+use crate::protobuf::envoy::config::cluster::v3::cluster::LbPolicy;
 use crate::protobuf::envoy::config::cluster::v3::Cluster;
 use crate::protobuf::envoy::config::core::v3::async_data_source::Specifier;
 use crate::protobuf::envoy::config::core::v3::http_uri::HttpUpstreamType;
@@ -9,50 +11,217 @@ use crate::protobuf::envoy::extensions::filters::http::wasm::v3::Wasm;
 use crate::protobuf::envoy::extensions::wasm::v3::plugin_config::Vm;
 use crate::protobuf::envoy::extensions::wasm::v3::{PluginConfig, VmConfig};
 use crate::service;
+use crate::type_urls::TypeUrls;
 use anyhow::{Context, Result};
 use prost_types::Duration;
 use serde::{Deserialize, Serialize};
 use std::path::Path;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Where the 3scale auth filter is served from, under the wasm base URL.
+pub const WASM_PATH: &str = "static/threescale_wasm_auth.wasm";
+
+/// Name of the cluster of the 3scale backend of service `id`.
+pub fn backend_cluster_name(id: impl std::fmt::Display) -> String {
+    format!("Service::{}::backend", id)
+}
+
+/// The 3scale backend the filter reports to: the one at `url`, or the
+/// replicas of `endpoints`, as `backend-1.3scale:3000`, the filter knowing
+/// either as the cluster it sends its requests to.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 struct Backend {
     pub cluster_name: String,
-    pub url: url::Url,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<url::Url>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    endpoints: Vec<BackendEndpoint>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    lb_policy: Option<BackendLbPolicy>,
     #[serde(flatten)]
-    other: std::collections::HashMap<String, serde_json::Value>,
+    other: std::collections::BTreeMap<String, serde_json::Value>,
+}
+
+/// A replica of the backend, weighted by `weight` when given.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(untagged)]
+enum BackendEndpoint {
+    Address(String),
+    Weighted { address: String, weight: u32 },
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum BackendLbPolicy {
+    RoundRobin,
+    LeastRequest,
+    Random,
+}
+
+impl BackendEndpoint {
+    fn address(&self) -> &str {
+        match self {
+            BackendEndpoint::Address(address) => address,
+            BackendEndpoint::Weighted { address, .. } => address,
+        }
+    }
+
+    fn weight(&self) -> Option<u32> {
+        match self {
+            BackendEndpoint::Address(_) => None,
+            BackendEndpoint::Weighted { weight, .. } => Some(*weight),
+        }
+    }
+
+    // The replica as the URL of its plain HTTP API, when it is a host and
+    // a port.
+    fn url(&self) -> Option<url::Url> {
+        let url = url::Url::parse(&format!("http://{}", self.address())).ok()?;
+        let bare = url.path() == "/" && url.query().is_none() && url.username().is_empty();
+        Some(url).filter(|url| bare && url.port().is_some())
+    }
+}
+
+impl Validate for Backend {
+    fn validate(&self, path: &str, findings: &mut Findings) {
+        match (&self.url, self.endpoints.is_empty()) {
+            (None, true) => {
+                findings.error(field(path, "url"), "is missing, nor are endpoints given")
+            }
+            (Some(_), false) => findings.error(
+                field(path, "endpoints"),
+                "cannot be given along with a url, the backend being either",
+            ),
+            _ => {}
+        }
+        for (i, endpoint) in self.endpoints.iter().enumerate() {
+            let path = index(&field(path, "endpoints"), i);
+            if endpoint.url().is_none() {
+                findings.error(
+                    &path,
+                    format!("'{}' is not a host and a port", endpoint.address()),
+                );
+            }
+            if endpoint.weight() == Some(0) {
+                findings.error(field(&path, "weight"), "must be above 0");
+            }
+        }
+        if self.lb_policy.is_some() && self.endpoints.len() < 2 {
+            findings.warning(
+                field(path, "lb_policy"),
+                "is only used with several endpoints",
+            );
+        }
+    }
 }
 
 impl Backend {
-    pub fn cluster(&self) -> Result<Cluster> {
-        get_envoy_cluster(self.cluster_name.clone(), self.url.to_string())
+    pub fn cluster(&self, type_urls: &TypeUrls) -> Result<Cluster> {
+        let mut cluster = get_envoy_cluster(
+            self.cluster_name.clone(),
+            self.logical_url()?.as_str(),
+            type_urls,
+        )?;
+        if self.endpoints.is_empty() {
+            return Ok(cluster);
+        }
+        let mut lb_endpoints = Vec::with_capacity(self.endpoints.len());
+        for endpoint in &self.endpoints {
+            let url = endpoint.url().with_context(|| {
+                format!(
+                    "backend endpoint '{}' is not a host and a port",
+                    endpoint.address()
+                )
+            })?;
+            let replica = get_envoy_cluster(self.cluster_name.clone(), url.as_str(), type_urls)?;
+            let mut lb_endpoint = replica
+                .load_assignment
+                .and_then(|assignment| assignment.endpoints.into_iter().next())
+                .and_then(|locality| locality.lb_endpoints.into_iter().next())
+                .unwrap_or_default();
+            lb_endpoint.load_balancing_weight = endpoint.weight();
+            lb_endpoints.push(lb_endpoint);
+        }
+        if let Some(ref mut assignment) = cluster.load_assignment {
+            assignment.endpoints[0].lb_endpoints = lb_endpoints;
+        }
+        cluster.lb_policy = match self.lb_policy {
+            Some(BackendLbPolicy::LeastRequest) => LbPolicy::LeastRequest,
+            Some(BackendLbPolicy::Random) => LbPolicy::Random,
+            Some(BackendLbPolicy::RoundRobin) | None => LbPolicy::RoundRobin,
+        } as i32;
+        Ok(cluster)
+    }
+
+    // The URL the filter knows the backend by, that of the first replica
+    // when there are several, its requests all going to the cluster.
+    fn logical_url(&self) -> Result<url::Url> {
+        match self.url {
+            Some(ref url) => Ok(url.clone()),
+            None => self
+                .endpoints
+                .first()
+                .and_then(BackendEndpoint::url)
+                .context("the backend has neither a url nor endpoints"),
+        }
+    }
+
+    // The backend as the filter reads it, a single one whatever the
+    // replicas.
+    fn wasm_config(&self) -> Result<serde_json::Value> {
+        let mut backend = serde_json::Map::new();
+        backend.insert("cluster_name".into(), self.cluster_name.clone().into());
+        backend.insert("url".into(), self.logical_url()?.as_str().into());
+        for (key, value) in &self.other {
+            backend.insert(key.clone(), value.clone());
+        }
+        Ok(backend.into())
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct ThreescaleAuth {
     path: String,
     wasm_config: WasmConfig,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 struct WasmConfig {
     backend: Backend,
     #[serde(flatten)]
-    other: std::collections::HashMap<String, serde_json::Value>,
+    other: std::collections::BTreeMap<String, serde_json::Value>,
+}
+
+impl Validate for ThreescaleAuth {
+    fn validate(&self, path: &str, findings: &mut Findings) {
+        self.wasm_config
+            .backend
+            .validate(&field(&field(path, "wasm_config"), "backend"), findings);
+    }
 }
 
 impl ThreescaleAuth {
-    pub fn cluster(&self) -> Result<Cluster> {
-        self.wasm_config.backend.cluster()
+    pub fn cluster(&self, type_urls: &TypeUrls) -> Result<Cluster> {
+        self.wasm_config.backend.cluster(type_urls)
+    }
+
+    /// The 3scale auth filter, on disk.
+    pub fn wasm_path(&self) -> &Path {
+        Path::new(&self.path)
     }
 
-    pub fn build_wasm(&self, id: u32) -> Result<Wasm> {
-        let wasm_config_s = serde_json::to_string_pretty(&self.wasm_config)?;
-        get_wasm_filter(self.path.clone(), wasm_config_s.as_str(), id)
+    pub fn build_wasm(&self, id: u32, wasm: &service::WasmSettings) -> Result<Wasm> {
+        let mut wasm_config = serde_json::to_value(&self.wasm_config)?;
+        wasm_config["backend"] = self.wasm_config.backend.wasm_config()?;
+        get_wasm_filter(self.path.clone(), wasm_config, id, wasm)
     }
 }
 
-fn get_wasm_filter(path: impl AsRef<Path>, auth_config: &str, id: u32) -> Result<Wasm> {
+fn get_wasm_filter(
+    path: impl AsRef<Path>,
+    auth_config: serde_json::Value,
+    id: u32,
+    wasm: &service::WasmSettings,
+) -> Result<Wasm> {
     let path = path.as_ref();
     let filename = path
         .file_name()
@@ -64,39 +233,171 @@ fn get_wasm_filter(path: impl AsRef<Path>, auth_config: &str, id: u32) -> Result
             vm: Some(Vm::VmConfig(VmConfig {
                 vm_id: format!("Service::{:?}", id),
                 runtime: "envoy.wasm.runtime.v8".to_string(),
-                configuration: Some(prost_types::Any {
-                    type_url: "type.googleapis.com/google.protobuf.StringValue".to_string(),
-                    value: encode("vm config".to_string())?,
-                }),
+                configuration: Some(wasm.type_urls.pack(&"vm config".to_string())?),
                 code: Some(AsyncDataSource {
                     specifier: Some(Specifier::Remote(RemoteDataSource {
                         http_uri: Some(HttpUri {
-                            uri: format!(
-                                "http://control-plane-main:5001/static/{}",
+                            uri: wasm.url(format!(
+                                "static/{}",
                                 filename
                                     .to_str()
                                     .context("invalid unicode in wasm file name")?
-                            ),
+                            )),
                             timeout: Some(Duration {
                                 seconds: 100,
                                 nanos: 0,
                             }),
                             http_upstream_type: Some(HttpUpstreamType::Cluster(
-                                "wasm_files".to_string(),
+                                service::WASM_FILES_CLUSTER.to_string(),
                             )),
                         }),
-                        sha256: service::Service::get_wasm_filter_sha(path)
-                            .context("could not compute SHA-256")?,
+                        sha256: wasm.sha256(path)?,
                         ..Default::default()
                     })),
                 }),
                 ..Default::default()
             })),
-            configuration: Some(prost_types::Any {
-                type_url: "type.googleapis.com/google.protobuf.StringValue".to_string(),
-                value: encode(auth_config.to_string())?,
-            }),
+            configuration: Some(wasm.type_urls.pack(&json_to_struct(auth_config)?)?),
             ..Default::default()
         }),
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protobuf::envoy::config::core::v3::address::Address as AddressType;
+    use crate::protobuf::envoy::config::core::v3::socket_address::PortSpecifier;
+    use crate::protobuf::envoy::config::endpoint::v3::lb_endpoint::HostIdentifier;
+
+    fn auth(backend: serde_json::Value) -> ThreescaleAuth {
+        serde_json::from_value(serde_json::json!({
+            "path": WASM_PATH,
+            "wasm_config": {"backend": backend, "services": []},
+        }))
+        .unwrap()
+    }
+
+    fn wasm_backend(auth: &ThreescaleAuth) -> serde_json::Value {
+        let wasm = service::WasmSettings {
+            skip_sha: true,
+            ..Default::default()
+        };
+        let config = auth.build_wasm(7, &wasm).unwrap().config.unwrap();
+        let config: prost_types::Struct =
+            prost::Message::decode(config.configuration.unwrap().value.as_slice()).unwrap();
+        let backend = match config.fields["backend"].kind {
+            Some(prost_types::value::Kind::StructValue(ref backend)) => backend.clone(),
+            ref kind => panic!("{:?}", kind),
+        };
+        let mut keys: Vec<_> = backend.fields.keys().cloned().collect();
+        keys.sort();
+        serde_json::json!(keys)
+    }
+
+    // The addresses, ports and weights of the endpoints of `cluster`.
+    fn endpoints(cluster: &Cluster) -> Vec<(String, u32, Option<u32>)> {
+        cluster.load_assignment.as_ref().unwrap().endpoints[0]
+            .lb_endpoints
+            .iter()
+            .map(|lb_endpoint| {
+                let address = match lb_endpoint.host_identifier {
+                    Some(HostIdentifier::Endpoint(ref endpoint)) => {
+                        match endpoint.address.as_ref().unwrap().address {
+                            Some(AddressType::SocketAddress(ref address)) => address.clone(),
+                            ref address => panic!("{:?}", address),
+                        }
+                    }
+                    ref host => panic!("{:?}", host),
+                };
+                let port = match address.port_specifier {
+                    Some(PortSpecifier::PortValue(port)) => port,
+                    ref port => panic!("{:?}", port),
+                };
+                (address.address, port, lb_endpoint.load_balancing_weight)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn backends_are_a_url_or_replicas() {
+        let single = auth(serde_json::json!({
+            "cluster_name": "backend",
+            "url": "https://su1.3scale.net/",
+            "timeout": 5,
+        }));
+        assert!(single.findings("").errors.is_empty());
+        let cluster = single.cluster(&TypeUrls::default()).unwrap();
+        assert_eq!(endpoints(&cluster), [("su1.3scale.net".into(), 443, None)]);
+        assert!(cluster.transport_socket.is_some());
+        assert_eq!(
+            wasm_backend(&single),
+            serde_json::json!(["cluster_name", "timeout", "url"])
+        );
+
+        let replicas = auth(serde_json::json!({
+            "cluster_name": "backend",
+            "endpoints": [
+                "backend-1.3scale:3000",
+                {"address": "backend-2.3scale:3000", "weight": 3},
+            ],
+            "lb_policy": "least_request",
+            "timeout": 5,
+        }));
+        assert!(replicas.findings("").errors.is_empty());
+        let cluster = replicas.cluster(&TypeUrls::default()).unwrap();
+        assert_eq!(
+            endpoints(&cluster),
+            [
+                ("backend-1.3scale".into(), 3000, None),
+                ("backend-2.3scale".into(), 3000, Some(3)),
+            ]
+        );
+        assert_eq!(cluster.lb_policy, LbPolicy::LeastRequest as i32);
+        assert!(cluster.transport_socket.is_none());
+        // a single backend, the cluster, for the filter
+        assert_eq!(
+            wasm_backend(&replicas),
+            serde_json::json!(["cluster_name", "timeout", "url"])
+        );
+    }
+
+    #[test]
+    fn backends_need_a_url_or_valid_endpoints() {
+        for (backend, paths) in &[
+            (
+                serde_json::json!({"cluster_name": "b"}),
+                vec!["backend.url"],
+            ),
+            (
+                serde_json::json!({
+                    "cluster_name": "b",
+                    "url": "http://backend:3000",
+                    "endpoints": ["backend:3000"],
+                }),
+                vec!["backend.endpoints"],
+            ),
+            (
+                serde_json::json!({
+                    "cluster_name": "b",
+                    "endpoints": ["backend", {"address": "backend:3000/path", "weight": 0}],
+                }),
+                vec![
+                    "backend.endpoints[0]",
+                    "backend.endpoints[1]",
+                    "backend.endpoints[1].weight",
+                ],
+            ),
+        ] {
+            let errors: Vec<_> = auth(backend.clone())
+                .wasm_config
+                .backend
+                .findings("backend")
+                .errors
+                .into_iter()
+                .map(|error| error.path)
+                .collect();
+            assert_eq!(&errors, paths);
+        }
+    }
+}
@@ -0,0 +1,413 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::task::{Context as TaskContext, Poll};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{anyhow, Context, Result};
+use futures::{Stream, StreamExt};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_rustls::rustls::internal::pemfile;
+use tokio_rustls::rustls::{
+    AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, Certificate, NoClientAuth,
+    PrivateKey, RootCertStore, ServerConfig, Session,
+};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use tonic::transport::server::Connected;
+
+// How often the certificate files are checked for changes.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+/// TLS settings of the discovery server.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TlsSettings {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+    // CA client certificates are verified against, for mutual TLS
+    pub client_ca_path: Option<PathBuf>,
+    pub require_client_cert: bool,
+}
+
+impl TlsSettings {
+    fn server_config(&self) -> Result<ServerConfig> {
+        let verifier = match self.client_ca_path {
+            Some(ref path) => {
+                let mut roots = RootCertStore::empty();
+                for cert in read_certs(path)? {
+                    roots
+                        .add(&cert)
+                        .map_err(|e| anyhow!("invalid client CA {:?}: {:?}", path, e))?;
+                }
+                if self.require_client_cert {
+                    AllowAnyAuthenticatedClient::new(roots)
+                } else {
+                    AllowAnyAnonymousOrAuthenticatedClient::new(roots)
+                }
+            }
+            None if self.require_client_cert => {
+                return Err(anyhow!("requiring client certificates needs a client CA"))
+            }
+            None => NoClientAuth::new(),
+        };
+
+        let mut config = ServerConfig::new(verifier);
+        config
+            .set_single_cert(read_certs(&self.cert_path)?, read_key(&self.key_path)?)
+            .with_context(|| format!("invalid certificate {:?}", self.cert_path))?;
+        // gRPC runs on HTTP/2
+        config.set_protocols(&[b"h2".to_vec()]);
+        Ok(config)
+    }
+
+    fn paths(&self) -> Vec<&Path> {
+        let mut paths = vec![self.cert_path.as_path(), self.key_path.as_path()];
+        paths.extend(self.client_ca_path.as_deref());
+        paths
+    }
+}
+
+fn open(path: &Path) -> Result<BufReader<File>> {
+    let file = File::open(path).with_context(|| format!("cannot open {:?}", path))?;
+    Ok(BufReader::new(file))
+}
+
+fn read_certs(path: &Path) -> Result<Vec<Certificate>> {
+    let certs = pemfile::certs(&mut open(path)?).map_err(|_| anyhow!("invalid PEM {:?}", path))?;
+    if certs.is_empty() {
+        return Err(anyhow!("no certificate in {:?}", path));
+    }
+    Ok(certs)
+}
+
+fn read_key(path: &Path) -> Result<PrivateKey> {
+    let invalid = |_| anyhow!("invalid PEM {:?}", path);
+    let mut keys = pemfile::pkcs8_private_keys(&mut open(path)?).map_err(invalid)?;
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut open(path)?).map_err(invalid)?;
+    }
+    keys.into_iter()
+        .next()
+        .ok_or_else(|| anyhow!("no private key in {:?}", path))
+}
+
+fn modified(paths: &[&Path]) -> Vec<Option<SystemTime>> {
+    paths
+        .iter()
+        .map(|path| path.metadata().and_then(|m| m.modified()).ok())
+        .collect()
+}
+
+/// A TLS acceptor whose certificates can be reloaded while serving: new
+/// connections use the last loaded ones, established ones are untouched.
+#[derive(Clone)]
+pub struct ReloadableAcceptor {
+    settings: TlsSettings,
+    acceptor: Arc<RwLock<TlsAcceptor>>,
+}
+
+impl ReloadableAcceptor {
+    pub fn new(settings: TlsSettings) -> Result<ReloadableAcceptor> {
+        let acceptor = TlsAcceptor::from(Arc::new(settings.server_config()?));
+        Ok(ReloadableAcceptor {
+            settings,
+            acceptor: Arc::new(RwLock::new(acceptor)),
+        })
+    }
+
+    /// Load the certificates again, keeping the current ones on failure.
+    pub fn reload(&self) -> Result<()> {
+        let acceptor = TlsAcceptor::from(Arc::new(self.settings.server_config()?));
+        *self.acceptor.write().unwrap() = acceptor;
+        tracing::info!("Reloaded TLS certificate {:?}", self.settings.cert_path);
+        Ok(())
+    }
+
+    fn acceptor(&self) -> TlsAcceptor {
+        self.acceptor.read().unwrap().clone()
+    }
+
+    /// Reload on SIGHUP, and whenever the certificate files change.
+    pub fn spawn_reloader(&self) -> Result<()> {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut hangups = signal(SignalKind::hangup()).context("cannot handle SIGHUP")?;
+        let acceptor = self.clone();
+        tokio::spawn(async move {
+            let mut last_modified = modified(&acceptor.settings.paths());
+            loop {
+                tokio::select! {
+                    _ = hangups.recv() => {}
+                    _ = tokio::time::delay_for(RELOAD_INTERVAL) => {
+                        let current = modified(&acceptor.settings.paths());
+                        if current == last_modified {
+                            continue;
+                        }
+                        last_modified = current;
+                    }
+                }
+                if let Err(e) = acceptor.reload() {
+                    tracing::error!("Cannot reload the TLS certificates: {:#}", e);
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
+/// An accepted TLS connection, as served by tonic.
+pub struct TlsConnection(TlsStream<TcpStream>);
+
+impl Connected for TlsConnection {
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        self.0.get_ref().0.peer_addr().ok()
+    }
+
+    // tonic only has `from_pem`, which keeps the bytes as they are: the
+    // certificates stay DER encoded, as rustls has them and as the xDS
+    // auth parses them
+    fn peer_certs(&self) -> Option<Vec<tonic::transport::Certificate>> {
+        let certs = self.0.get_ref().1.get_peer_certificates()?;
+        Some(
+            certs
+                .into_iter()
+                .map(|cert| tonic::transport::Certificate::from_pem(cert.0))
+                .collect(),
+        )
+    }
+}
+
+impl AsyncRead for TlsConnection {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for TlsConnection {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
+
+/// TLS connections accepted on `listener`, to serve with
+/// `serve_with_incoming`. Handshakes run apart from the accept loop so that
+/// a slow or failing client doesn't hold the others back.
+pub fn incoming(
+    mut listener: TcpListener,
+    acceptor: ReloadableAcceptor,
+    keepalive: Option<std::time::Duration>,
+) -> impl Stream<Item = Result<TlsConnection, std::io::Error>> {
+    let (tx, rx) = mpsc::channel(16);
+    tokio::spawn(async move {
+        let mut connections = listener.incoming();
+        while let Some(connection) = connections.next().await {
+            let stream = match connection {
+                Ok(stream) => stream,
+                Err(e) => {
+                    tracing::warn!("Cannot accept a connection: {:?}", e);
+                    continue;
+                }
+            };
+            if let Err(e) = stream.set_keepalive(keepalive) {
+                tracing::warn!("Cannot keep the connection alive: {:?}", e);
+            }
+            let peer = stream
+                .peer_addr()
+                .map(|addr| addr.to_string())
+                .unwrap_or_else(|_| "unknown peer".to_string());
+            let handshake = acceptor.acceptor().accept(stream);
+            let mut tx = tx.clone();
+            tokio::spawn(async move {
+                match handshake.await {
+                    Ok(stream) => {
+                        let _ = tx.send(Ok(TlsConnection(stream))).await;
+                    }
+                    // client certificate verification failures end up here
+                    Err(e) => tracing::warn!("TLS handshake with {} failed: {}", peer, e),
+                }
+            });
+        }
+    });
+    rx
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::rustls::ClientConfig;
+    use tokio_rustls::webpki::DNSNameRef;
+    use tokio_rustls::TlsConnector;
+
+    // Copy the `name-ca` CA of `testdata/tls` into `dir`, with the
+    // `name-server` certificate for localhost it signed, and the
+    // `name-client` one for `name.envoy.internal`.
+    fn fixtures(dir: &Path, name: &str) {
+        let testdata = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/tls"));
+        for file in &[
+            "ca.pem",
+            "server.pem",
+            "server.key",
+            "client.pem",
+            "client.key",
+        ] {
+            let file = format!("{}-{}", name, file);
+            std::fs::copy(testdata.join(&file), dir.join(&file)).unwrap();
+        }
+    }
+
+    fn settings(dir: &Path, name: &str, require_client_cert: bool) -> TlsSettings {
+        TlsSettings {
+            cert_path: dir.join(format!("{}-server.pem", name)),
+            key_path: dir.join(format!("{}-server.key", name)),
+            client_ca_path: Some(dir.join(format!("{}-ca.pem", name))),
+            require_client_cert,
+        }
+    }
+
+    async fn serve(
+        settings: TlsSettings,
+    ) -> (
+        SocketAddr,
+        ReloadableAcceptor,
+        impl Stream<Item = Result<TlsConnection, std::io::Error>>,
+    ) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let listener = TcpListener::from_std(listener).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let acceptor = ReloadableAcceptor::new(settings).unwrap();
+        let connections = incoming(listener, acceptor.clone(), None);
+        (addr, acceptor, connections)
+    }
+
+    // Connect trusting the `ca` CA, with the `client` certificate if any,
+    // and exchange a byte to get through the whole handshake.
+    async fn connect(
+        addr: SocketAddr,
+        dir: &Path,
+        ca: &str,
+        client: Option<&str>,
+    ) -> std::io::Result<()> {
+        let mut config = ClientConfig::new();
+        config
+            .root_store
+            .add_pem_file(&mut open(&dir.join(format!("{}-ca.pem", ca))).unwrap())
+            .unwrap();
+        config.set_protocols(&[b"h2".to_vec()]);
+        if let Some(client) = client {
+            let certs = read_certs(&dir.join(format!("{}-client.pem", client))).unwrap();
+            let key = read_key(&dir.join(format!("{}-client.key", client))).unwrap();
+            config.set_single_client_cert(certs, key).unwrap();
+        }
+
+        let stream = std::net::TcpStream::connect(addr)?;
+        stream.set_nonblocking(true)?;
+        let stream = TcpStream::from_std(stream)?;
+        let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+        let mut stream = TlsConnector::from(Arc::new(config))
+            .connect(domain, stream)
+            .await?;
+        stream.write_all(b"x").await?;
+        let mut buffer = [0; 1];
+        stream.read_exact(&mut buffer).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn client_certificates_are_verified() {
+        let dir = tempfile::tempdir().unwrap();
+        fixtures(dir.path(), "one");
+        fixtures(dir.path(), "other");
+        let (addr, _, mut connections) = serve(settings(dir.path(), "one", true)).await;
+
+        // neither a missing certificate nor one of another CA get through
+        assert!(connect(addr, dir.path(), "one", None).await.is_err());
+        assert!(connect(addr, dir.path(), "one", Some("other"))
+            .await
+            .is_err());
+
+        let client = {
+            let dir = dir.path().to_path_buf();
+            tokio::spawn(async move { connect(addr, &dir, "one", Some("one")).await })
+        };
+        let mut stream = connections.next().await.unwrap().unwrap();
+        let mut buffer = [0; 1];
+        stream.read_exact(&mut buffer).await.unwrap();
+        stream.write_all(b"y").await.unwrap();
+        client.await.unwrap().unwrap();
+
+        assert!(stream.remote_addr().is_some());
+        // the certificate of the client, DER encoded as webpki reads it
+        let certs = stream.peer_certs().unwrap();
+        assert_eq!(certs.len(), 1);
+        let client = read_certs(&dir.path().join("one-client.pem")).unwrap();
+        assert_eq!(certs[0].get_ref(), client[0].0.as_slice());
+        let cert = tokio_rustls::webpki::EndEntityCert::from(certs[0].get_ref()).unwrap();
+        let name = DNSNameRef::try_from_ascii_str("one.envoy.internal").unwrap();
+        assert!(cert.verify_is_valid_for_dns_name(name).is_ok());
+        // CN=one-client
+        let subject = [
+            0x0c, 10, b'o', b'n', b'e', b'-', b'c', b'l', b'i', b'e', b'n', b't',
+        ];
+        assert!(certs[0]
+            .get_ref()
+            .windows(subject.len())
+            .any(|window| window == subject));
+    }
+
+    #[tokio::test]
+    async fn certificates_reload_on_the_same_listener() {
+        let dir = tempfile::tempdir().unwrap();
+        fixtures(dir.path(), "one");
+        fixtures(dir.path(), "two");
+        let (addr, acceptor, mut connections) = serve(settings(dir.path(), "one", false)).await;
+
+        let accept = tokio::spawn(async move {
+            let mut stream = connections.next().await.unwrap().unwrap();
+            let mut buffer = [0; 1];
+            stream.read_exact(&mut buffer).await.unwrap();
+            stream.write_all(b"y").await.unwrap();
+        });
+
+        // swap in the certificate of the second CA
+        for ext in &["pem", "key"] {
+            std::fs::copy(
+                dir.path().join(format!("two-server.{}", ext)),
+                dir.path().join(format!("one-server.{}", ext)),
+            )
+            .unwrap();
+        }
+        acceptor.reload().unwrap();
+        assert!(connect(addr, dir.path(), "one", None).await.is_err());
+        connect(addr, dir.path(), "two", None).await.unwrap();
+        accept.await.unwrap();
+
+        // a broken certificate keeps the last good one
+        std::fs::write(dir.path().join("one-server.pem"), "garbage").unwrap();
+        assert!(acceptor.reload().is_err());
+    }
+}
@@ -0,0 +1,200 @@
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+
+use crate::envoy_helpers::encode;
+use crate::protobuf::envoy::config::cluster::v3::Cluster;
+use crate::protobuf::envoy::config::endpoint::v3::ClusterLoadAssignment;
+use crate::protobuf::envoy::config::listener::v3::Listener;
+use crate::protobuf::envoy::config::route::v3::RouteConfiguration;
+use crate::protobuf::envoy::extensions::transport_sockets::tls::v3::Secret;
+
+pub const TYPE_URL_PREFIX: &str = "type.googleapis.com/";
+
+pub const CLUSTER_TYPE_URL: &str = <Cluster as TypeUrl>::TYPE_URL;
+pub const LISTENER_TYPE_URL: &str = <Listener as TypeUrl>::TYPE_URL;
+pub const ROUTE_TYPE_URL: &str = <RouteConfiguration as TypeUrl>::TYPE_URL;
+pub const SECRET_TYPE_URL: &str = <Secret as TypeUrl>::TYPE_URL;
+pub const ENDPOINT_TYPE_URL: &str = <ClusterLoadAssignment as TypeUrl>::TYPE_URL;
+
+/// A message with the full name of its protobuf type, and the type URL made
+/// of it. The build script implements it for every generated message, out
+/// of the descriptors, so that no type URL is spelled by hand.
+pub trait TypeUrl: prost::Message {
+    const NAME: &'static str;
+    // the upstream one, whatever the overrides
+    const TYPE_URL: &'static str;
+}
+
+impl TypeUrl for prost_types::Struct {
+    const NAME: &'static str = "google.protobuf.Struct";
+    const TYPE_URL: &'static str = "type.googleapis.com/google.protobuf.Struct";
+}
+
+// prost encodes the StringValue wrapper as a plain string
+impl TypeUrl for std::string::String {
+    const NAME: &'static str = "google.protobuf.StringValue";
+    const TYPE_URL: &'static str = "type.googleapis.com/google.protobuf.StringValue";
+}
+
+/// The types the Envoy being configured knows the messages by, for builds
+/// whose names differ from those of the upstream protos, like those with
+/// contrib filters. Types without an override keep their own name.
+#[derive(Debug, Clone, Default, PartialEq, Hash)]
+pub struct TypeUrls {
+    // full type name to the name Envoy expects instead
+    pub overrides: BTreeMap<std::string::String, std::string::String>,
+}
+
+impl TypeUrls {
+    /// The type URL of the type named `name`.
+    pub fn url(&self, name: &str) -> std::string::String {
+        let name = self.overrides.get(name).map_or(name, |name| name.as_str());
+        format!("{}{}", TYPE_URL_PREFIX, name)
+    }
+
+    pub fn pack<M: TypeUrl>(&self, message: &M) -> Result<prost_types::Any> {
+        Ok(prost_types::Any {
+            type_url: self.url(M::NAME),
+            value: encode(message)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configuration::Config;
+    use crate::oidc::serve_issuer;
+    use crate::proto_json::Registry;
+    use crate::protobuf::envoy::extensions::filters::http::jwt_authn::v3::JwtAuthentication;
+    use crate::protobuf::envoy::extensions::filters::http::local_ratelimit::v3::LocalRateLimit;
+    use crate::protobuf::envoy::extensions::filters::http::rbac::v3::Rbac;
+    use crate::protobuf::envoy::extensions::filters::http::router::v3::Router;
+    use crate::protobuf::envoy::extensions::filters::http::wasm::v3::Wasm;
+    use crate::protobuf::envoy::extensions::filters::network::http_connection_manager::v3::HttpConnectionManager;
+    use crate::protobuf::envoy::extensions::transport_sockets::tls::v3::UpstreamTlsContext;
+    use crate::service::WasmSettings;
+
+    fn type_url<M: TypeUrl>() -> std::string::String {
+        TypeUrls::default().url(M::NAME)
+    }
+
+    #[test]
+    fn resource_type_urls_name_their_messages() {
+        assert_eq!(
+            CLUSTER_TYPE_URL,
+            "type.googleapis.com/envoy.config.cluster.v3.Cluster"
+        );
+        assert_eq!(CLUSTER_TYPE_URL, type_url::<Cluster>());
+        assert_eq!(LISTENER_TYPE_URL, type_url::<Listener>());
+        assert_eq!(ROUTE_TYPE_URL, type_url::<RouteConfiguration>());
+        assert_eq!(SECRET_TYPE_URL, type_url::<Secret>());
+        assert_eq!(ENDPOINT_TYPE_URL, type_url::<ClusterLoadAssignment>());
+    }
+
+    #[test]
+    fn nested_messages_are_named_after_their_parents() {
+        use crate::protobuf::envoy::config::cluster::v3::cluster::lb_subset_config::LbSubsetSelector;
+        use crate::protobuf::envoy::config::cluster::v3::cluster::LbSubsetConfig;
+
+        assert_eq!(
+            LbSubsetConfig::NAME,
+            "envoy.config.cluster.v3.Cluster.LbSubsetConfig"
+        );
+        assert_eq!(
+            LbSubsetSelector::TYPE_URL,
+            "type.googleapis.com/envoy.config.cluster.v3.Cluster.LbSubsetConfig.LbSubsetSelector"
+        );
+        assert_eq!(Rbac::NAME, "envoy.extensions.filters.http.rbac.v3.RBAC");
+    }
+
+    // Every `Any` of the JSON encoding of a resource, by its type URL.
+    fn type_urls(value: &serde_json::Value, found: &mut Vec<std::string::String>) {
+        match value {
+            serde_json::Value::Object(object) => {
+                if let Some(serde_json::Value::String(url)) = object.get("@type") {
+                    found.push(url.clone());
+                }
+                object.values().for_each(|value| type_urls(value, found));
+            }
+            serde_json::Value::Array(values) => {
+                values.iter().for_each(|value| type_urls(value, found))
+            }
+            _ => {}
+        }
+    }
+
+    #[test]
+    fn exported_filters_decode_as_their_type_urls() {
+        let content = serde_json::json!([{
+            "id": 1,
+            "hosts": ["web.app"],
+            "policies": [
+                {"name": "ip_check", "configuration": {"ips": ["10.0.0.0/8"], "check_type": "allow"}},
+                {"name": "rate_limit", "configuration": {"requests": 10, "interval": 60}},
+            ],
+            "target_domain": "https://web.app:443",
+            "proxy_rules": [],
+            "oidc_issuer": serve_issuer(),
+            "auth_config": {
+                "path": "static/threescale_wasm_auth.wasm",
+                "wasm_config": {"backend": {"cluster_name": "backend", "url": "https://backend.app/"}},
+            },
+        }])
+        .to_string();
+        let services = Config::from_services(serde_json::from_str(&content).unwrap(), &content);
+        let wasm = WasmSettings {
+            skip_sha: true,
+            ..Default::default()
+        };
+        let (exports, errors) = services.export_concurrently(&wasm, 1);
+        assert!(errors.is_empty(), "{:?}", errors);
+
+        // the JSON encoding decodes each `Any` as the message its type URL
+        // names, failing on the unknown ones and on those not decoding
+        let registry = Registry::new().unwrap();
+        let mut found = Vec::new();
+        for export in exports.values().flat_map(|exports| exports.iter()) {
+            let any = export.config.to_any().unwrap();
+            let json = registry.any_to_json(&any).unwrap();
+            found.push(any.type_url);
+            type_urls(&json, &mut found);
+        }
+        found.sort();
+        found.dedup();
+        assert_eq!(
+            found,
+            vec![
+                type_url::<Cluster>(),
+                type_url::<Listener>(),
+                type_url::<JwtAuthentication>(),
+                type_url::<LocalRateLimit>(),
+                type_url::<Rbac>(),
+                type_url::<Router>(),
+                type_url::<Wasm>(),
+                type_url::<HttpConnectionManager>(),
+                type_url::<UpstreamTlsContext>(),
+                type_url::<std::string::String>(),
+                type_url::<prost_types::Struct>(),
+            ]
+        );
+    }
+
+    #[test]
+    fn overrides_rename_types() {
+        let mut compatibility = TypeUrls::default();
+        compatibility.overrides.insert(
+            Wasm::NAME.to_string(),
+            "envoy.extensions.filters.http.wasm.v4alpha.Wasm".to_string(),
+        );
+        assert_eq!(
+            compatibility.pack(&Wasm::default()).unwrap().type_url,
+            "type.googleapis.com/envoy.extensions.filters.http.wasm.v4alpha.Wasm"
+        );
+        assert_eq!(
+            compatibility.pack(&Cluster::default()).unwrap().type_url,
+            CLUSTER_TYPE_URL
+        );
+    }
+}
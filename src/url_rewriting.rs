@@ -0,0 +1,343 @@
+use serde::{Deserialize, Serialize};
+
+use crate::field_errors::{field, index, Findings, Validate};
+use crate::rule_patterns;
+
+use crate::protobuf::envoy::config::route::v3::{route::Action, route_match::PathSpecifier};
+use crate::protobuf::envoy::config::route::v3::{Route, RouteMatch};
+use crate::protobuf::envoy::r#type::matcher::v3::RegexMatchAndSubstitute;
+
+/// The `url_rewriting` policy of APIcast: `sub` and `gsub` commands run on
+/// the path, in order, a command with `break` stopping the others once it
+/// matched.
+///
+/// Each command becomes a route of its own, before the catch all one,
+/// matching the paths the command applies to and rewriting them. Envoy
+/// rewrites a path once, by the first route matching it, so the commands
+/// are taken as alternatives: only the last command may leave out `break`,
+/// for the paths it rewrote to be rewritten again. Chains of commands are
+/// refused rather than run by a Lua or wasm filter of their own, which
+/// would need to be shipped to Envoy along with the 3scale one.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct UrlRewriting {
+    #[serde(default)]
+    pub commands: Vec<Command>,
+    // APIcast rewrites the query arguments too, which routes cannot
+    #[serde(default)]
+    pub query_args_commands: Vec<serde_json::Value>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Command {
+    pub op: Op,
+    pub regex: std::string::String,
+    pub replace: std::string::String,
+    // the flags of the PCRE regex, as given to `ngx.re`
+    #[serde(default)]
+    pub options: std::string::String,
+    #[serde(default)]
+    pub r#break: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Op {
+    /// The first match of the regex is replaced.
+    Sub,
+    /// Every match is.
+    Gsub,
+}
+
+/// The rewrite of a command, in Envoy's terms.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Rewrite {
+    /// Paths starting with `prefix` get it replaced.
+    Prefix {
+        prefix: std::string::String,
+        rewrite: std::string::String,
+    },
+    /// The paths matching `pattern`, an RE2 regex, get every match of it
+    /// replaced by `substitution`.
+    Regex {
+        pattern: std::string::String,
+        substitution: std::string::String,
+    },
+}
+
+// Characters with a meaning in regexes, which a prefix has none of.
+const SPECIAL: &[char] = &[
+    '\\', '.', '+', '*', '?', '(', ')', '|', '[', ']', '{', '}', '^', '$',
+];
+
+// The inline flags of the ngx.re options RE2 knows. `o` and `j` only tune
+// how nginx compiles the regex.
+fn flags(options: &str) -> Result<std::string::String, std::string::String> {
+    let mut flags = std::string::String::new();
+    for option in options.chars() {
+        match option {
+            'i' | 'm' | 's' => flags.push(option),
+            'o' | 'j' => {}
+            _ => return Err(format!("option '{}' has no Envoy equivalent", option)),
+        }
+    }
+    if flags.is_empty() {
+        Ok(flags)
+    } else {
+        Ok(format!("(?{})", flags))
+    }
+}
+
+// The RE2 substitution of an ngx.re replacement, `$1` or `${1}` for the
+// groups and `$$` for `$`, the groups numbered `shift` more.
+fn substitution(
+    replace: &str,
+    shift: usize,
+    groups: usize,
+) -> Result<std::string::String, std::string::String> {
+    let mut substitution = std::string::String::new();
+    let mut chars = replace.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => substitution.push_str("\\\\"),
+            '$' => {
+                let braced = chars.peek() == Some(&'{');
+                if chars.peek() == Some(&'$') {
+                    chars.next();
+                    substitution.push('$');
+                    continue;
+                }
+                if braced {
+                    chars.next();
+                }
+                let mut number = std::string::String::new();
+                while let Some(digit) = chars.peek().filter(|c| c.is_ascii_digit()) {
+                    number.push(*digit);
+                    chars.next();
+                }
+                if braced && chars.next() != Some('}') {
+                    return Err("'${' is not closed by '}'".to_string());
+                }
+                let group: usize = number
+                    .parse()
+                    .map_err(|_| "'$' is followed by neither a group nor '$'".to_string())?;
+                if group >= groups {
+                    return Err(format!("there is no group {} in the regex", group));
+                }
+                // RE2 only substitutes the first nine groups
+                if group + shift > 9 {
+                    return Err(format!(
+                        "group {} is past the ones Envoy substitutes",
+                        group
+                    ));
+                }
+                substitution.push_str(&format!("\\{}", group + shift));
+            }
+            c => substitution.push(c),
+        }
+    }
+    Ok(substitution)
+}
+
+impl Command {
+    /// Translate the command, failing with why it cannot be.
+    pub fn rewrite(&self) -> Result<Rewrite, std::string::String> {
+        let flags = flags(&self.options)?;
+        let regex = format!("{}{}", flags, self.regex);
+        // RE2 and the regex crate share their syntax, both leaving out what
+        // PCRE has to backtrack for, like look-arounds and back references
+        let groups = regex::Regex::new(&regex)
+            .map_err(|e| match e {
+                regex::Error::Syntax(e) => format!("not an RE2 regex: {}", e),
+                e => e.to_string(),
+            })?
+            .captures_len();
+
+        if let Some(prefix) = self.regex.strip_prefix('^') {
+            if flags.is_empty() && !prefix.contains(SPECIAL) && !self.replace.contains('$') {
+                return Ok(Rewrite::Prefix {
+                    prefix: prefix.to_string(),
+                    rewrite: self.replace.clone(),
+                });
+            }
+        }
+        // an anchored regex matches once anyway, and the first match of
+        // any other one is the one following the shortest text
+        if self.op == Op::Gsub || self.regex.starts_with('^') {
+            return Ok(Rewrite::Regex {
+                pattern: regex,
+                substitution: substitution(&self.replace, 0, groups)?,
+            });
+        }
+        Ok(Rewrite::Regex {
+            pattern: format!("{}^(.*?)({})", flags, self.regex),
+            substitution: format!("\\1{}", substitution(&self.replace, 2, groups)?),
+        })
+    }
+}
+
+impl Validate for UrlRewriting {
+    fn validate(&self, path: &str, findings: &mut Findings) {
+        let commands = field(path, "commands");
+        if self.commands.is_empty() {
+            findings.error(&commands, "needs at least one command");
+        }
+        for (i, command) in self.commands.iter().enumerate() {
+            let command_path = index(&commands, i);
+            if let Err(e) = command.rewrite() {
+                findings.error(field(&command_path, "regex"), e);
+            }
+            if !command.r#break && i + 1 < self.commands.len() {
+                findings.error(
+                    field(&command_path, "break"),
+                    "is needed by every command but the last, Envoy rewriting a path once",
+                );
+            }
+        }
+        if !self.query_args_commands.is_empty() {
+            findings.error(
+                field(path, "query_args_commands"),
+                "query arguments cannot be rewritten by Envoy routes",
+            );
+        }
+    }
+}
+
+impl UrlRewriting {
+    /// The routes of the commands, taking after the catch all `route` of
+    /// the service.
+    pub fn routes(&self, route: &Route) -> Vec<Route> {
+        self.commands
+            .iter()
+            .filter_map(|command| command.rewrite().ok())
+            .map(|rewrite| {
+                let mut route = route.clone();
+                let (path_specifier, prefix_rewrite, regex_rewrite) = match rewrite {
+                    Rewrite::Prefix { prefix, rewrite } => {
+                        (PathSpecifier::Prefix(prefix), rewrite, None)
+                    }
+                    Rewrite::Regex {
+                        pattern,
+                        substitution,
+                    } => (
+                        // routes match whole paths
+                        PathSpecifier::SafeRegex(rule_patterns::re2(format!(
+                            ".*(?:{}).*",
+                            pattern
+                        ))),
+                        std::string::String::new(),
+                        Some(RegexMatchAndSubstitute {
+                            pattern: Some(rule_patterns::re2(pattern)),
+                            substitution,
+                        }),
+                    ),
+                };
+                route.r#match = Some(RouteMatch {
+                    path_specifier: Some(path_specifier),
+                    ..Default::default()
+                });
+                if let Some(Action::Route(ref mut action)) = route.action {
+                    action.prefix_rewrite = prefix_rewrite;
+                    action.regex_rewrite = regex_rewrite;
+                }
+                route
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::field_errors::FieldError;
+
+    // APIcast url_rewriting policies, with the rewrites of their commands,
+    // or the start of the errors telling why they cannot be translated.
+    const FIXTURES: &str = include_str!("../testdata/apicast/url_rewriting.json");
+
+    #[derive(Deserialize)]
+    struct Fixture {
+        policy: serde_json::Value,
+        #[serde(default)]
+        rewrites: Vec<serde_json::Value>,
+        #[serde(default)]
+        errors: Vec<std::string::String>,
+    }
+
+    fn described(rewrite: Rewrite) -> serde_json::Value {
+        match rewrite {
+            Rewrite::Prefix { prefix, rewrite } => {
+                serde_json::json!({"prefix": prefix, "rewrite": rewrite})
+            }
+            Rewrite::Regex {
+                pattern,
+                substitution,
+            } => serde_json::json!({"pattern": pattern, "substitution": substitution}),
+        }
+    }
+
+    #[test]
+    fn apicast_commands_translate_to_rewrites() {
+        let fixtures: Vec<Fixture> = serde_json::from_str(FIXTURES).unwrap();
+        for fixture in fixtures {
+            let rewriting: UrlRewriting =
+                serde_json::from_value(fixture.policy["configuration"].clone()).unwrap();
+            let errors = rewriting.findings("").errors;
+            let errors: Vec<_> = errors.iter().map(FieldError::to_string).collect();
+            assert_eq!(errors.len(), fixture.errors.len(), "{:?}", errors);
+            for (error, expected) in errors.iter().zip(&fixture.errors) {
+                assert!(error.starts_with(expected), "{}", error);
+            }
+            if errors.is_empty() {
+                let rewrites: Vec<_> = rewriting
+                    .commands
+                    .iter()
+                    .map(|command| described(command.rewrite().unwrap()))
+                    .collect();
+                assert_eq!(rewrites, fixture.rewrites, "{}", fixture.policy);
+            }
+        }
+    }
+
+    // What Envoy makes of a path, as far as the regex crate tells.
+    fn rewritten(command: &Command, path: &str) -> std::string::String {
+        match command.rewrite().unwrap() {
+            Rewrite::Prefix { prefix, rewrite } => match path.strip_prefix(&prefix) {
+                Some(rest) => format!("{}{}", rewrite, rest),
+                None => path.to_string(),
+            },
+            Rewrite::Regex {
+                pattern,
+                substitution,
+            } => {
+                let substitution = regex::Regex::new(r"\\(\d)")
+                    .unwrap()
+                    .replace_all(&substitution.replace("$", "$$"), "$${$1}")
+                    .replace("\\\\", "\\");
+                regex::Regex::new(&pattern)
+                    .unwrap()
+                    .replace_all(path, substitution.as_str())
+                    .into_owned()
+            }
+        }
+    }
+
+    #[test]
+    fn rewrites_replace_like_ngx() {
+        let command = |op, regex: &str, replace: &str| Command {
+            op,
+            regex: regex.to_string(),
+            replace: replace.to_string(),
+            options: std::string::String::new(),
+            r#break: false,
+        };
+        let sub = command(Op::Sub, "o(\\w)", "0$1");
+        assert_eq!(rewritten(&sub, "/foo/bob"), "/f0o/bob");
+        let gsub = command(Op::Gsub, "o(\\w)", "0$1");
+        assert_eq!(rewritten(&gsub, "/foo/bob"), "/f0o/b0b");
+        let prefix = command(Op::Sub, "^/v1/", "/api/");
+        assert_eq!(rewritten(&prefix, "/v1/users"), "/api/users");
+        assert_eq!(rewritten(&prefix, "/v2/users"), "/v2/users");
+        let dollars = command(Op::Sub, "^/price/(\\d+)", "/price/$$${1}");
+        assert_eq!(rewritten(&dollars, "/price/12"), "/price/$12");
+    }
+}
@@ -1,16 +1,71 @@
 /// Common helpers
 use anyhow::Result;
 
-use ring::digest::{Context, Digest, SHA256};
+use ring::digest::{Context, Digest};
 use std::io::Read;
 
-pub(crate) mod file_utils {
+pub mod file_utils {
 
     pub(self) use super::*;
+    use anyhow::{bail, Context as _};
+    use data_encoding::HEXLOWER;
+    use std::io::Write;
+    use std::path::{Path, PathBuf};
+    use std::sync::atomic::{AtomicUsize, Ordering};
 
-    pub fn sha256_digest<R: Read>(mut reader: R) -> Result<Digest> {
-        let mut context = Context::new(&SHA256);
-        let mut buffer = [0; 1024];
+    // Read at a time, large enough for the filters to take few reads.
+    const CHUNK_SIZE: usize = 64 * 1024;
+
+    // Starts the last line of the files written by `write_verified`, as in
+    // `#verified 1234 sha256 <hex>`.
+    const FOOTER: &str = "#verified";
+
+    // Tells the temporary files of concurrent writers apart.
+    static WRITES: AtomicUsize = AtomicUsize::new(0);
+
+    /// Digests the controller computes. Envoy only checks SHA-256 ones, the
+    /// others being for the integrity of what the controller itself reads,
+    /// like the snapshots the leader persists.
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+    pub enum DigestAlgorithm {
+        #[default]
+        Sha256,
+        Sha512,
+    }
+
+    impl DigestAlgorithm {
+        pub const NAMES: &'static [&'static str] = &["sha256", "sha512"];
+
+        pub fn name(&self) -> &'static str {
+            match self {
+                DigestAlgorithm::Sha256 => "sha256",
+                DigestAlgorithm::Sha512 => "sha512",
+            }
+        }
+
+        fn algorithm(&self) -> &'static ring::digest::Algorithm {
+            match self {
+                DigestAlgorithm::Sha256 => &ring::digest::SHA256,
+                DigestAlgorithm::Sha512 => &ring::digest::SHA512,
+            }
+        }
+    }
+
+    impl std::str::FromStr for DigestAlgorithm {
+        type Err = anyhow::Error;
+
+        fn from_str(name: &str) -> Result<DigestAlgorithm> {
+            match name {
+                "sha256" => Ok(DigestAlgorithm::Sha256),
+                "sha512" => Ok(DigestAlgorithm::Sha512),
+                _ => anyhow::bail!("unknown digest algorithm '{}'", name),
+            }
+        }
+    }
+
+    pub fn digest<R: Read>(algorithm: DigestAlgorithm, mut reader: R) -> Result<Digest> {
+        let mut context = Context::new(algorithm.algorithm());
+        let mut buffer = vec![0; CHUNK_SIZE];
 
         loop {
             let count = reader.read(&mut buffer)?;
@@ -22,4 +77,333 @@ pub(crate) mod file_utils {
 
         Ok(context.finish())
     }
+
+    /// The digest of the file at `path`, opened through the runtime and read
+    /// and hashed on its blocking threads rather than the one awaiting it.
+    pub async fn file_digest(algorithm: DigestAlgorithm, path: PathBuf) -> Result<Digest> {
+        let file = tokio::fs::File::open(&path)
+            .await
+            .with_context(|| format!("cannot open {}", path.display()))?
+            .into_std()
+            .await;
+        tokio::task::spawn_blocking(move || digest(algorithm, file)).await?
+    }
+
+    /// `file_digest` for the callers that can't await, like the exports: on
+    /// a thread of its own waiting on the runtime, as the caller may be one
+    /// of the runtime's, or else on the caller's, without a runtime.
+    pub fn file_digest_blocking(algorithm: DigestAlgorithm, path: PathBuf) -> Result<Digest> {
+        match tokio::runtime::Handle::try_current() {
+            Ok(handle) => std::thread::spawn(move || handle.block_on(file_digest(algorithm, path)))
+                .join()
+                .unwrap_or_else(|_| bail!("hashing panicked")),
+            Err(_) => {
+                let file = std::fs::File::open(&path)
+                    .with_context(|| format!("cannot open {}", path.display()))?;
+                digest(algorithm, file)
+            }
+        }
+    }
+
+    /// Replace the file at `path` with `content`, written to a temporary
+    /// file of the same directory, synced and renamed over it, for readers
+    /// to find either the previous content or the whole of the new one,
+    /// even after a crash.
+    pub fn write_atomic(path: &Path, content: &[u8]) -> Result<()> {
+        let name = path
+            .file_name()
+            .with_context(|| format!("{} is not a file", path.display()))?;
+        let mut temporary_name = std::ffi::OsString::from(".");
+        temporary_name.push(name);
+        temporary_name.push(format!(
+            ".{}.{}.tmp",
+            std::process::id(),
+            WRITES.fetch_add(1, Ordering::Relaxed)
+        ));
+        let temporary = path.with_file_name(temporary_name);
+        let written = std::fs::File::create(&temporary)
+            .and_then(|mut file| {
+                file.write_all(content)?;
+                file.sync_all()
+            })
+            .with_context(|| format!("cannot write {}", temporary.display()))
+            .and_then(|_| {
+                std::fs::rename(&temporary, path)
+                    .with_context(|| format!("cannot replace {}", path.display()))
+            });
+        if written.is_err() {
+            let _ = std::fs::remove_file(&temporary);
+            return written;
+        }
+        // the rename itself survives a crash once the directory is synced
+        if let Some(directory) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            std::fs::File::open(directory).and_then(|dir| dir.sync_all())?;
+        }
+        Ok(())
+    }
+
+    /// Write `content` atomically along with a footer of its length and
+    /// digest, for `read_verified` to tell a whole file from a truncated or
+    /// corrupted one.
+    pub fn write_verified(path: &Path, content: &[u8], algorithm: DigestAlgorithm) -> Result<()> {
+        let hex = HEXLOWER.encode(digest(algorithm, content)?.as_ref());
+        let mut framed = content.to_vec();
+        framed.extend_from_slice(
+            format!(
+                "\n{} {} {} {}\n",
+                FOOTER,
+                content.len(),
+                algorithm.name(),
+                hex
+            )
+            .as_bytes(),
+        );
+        write_atomic(path, &framed)
+    }
+
+    /// Whether `content` ends with the footer of `write_verified`.
+    pub fn has_footer(content: &[u8]) -> bool {
+        footer(content).is_some()
+    }
+
+    // The content before the footer and the footer itself.
+    fn footer(content: &[u8]) -> Option<(&[u8], &str)> {
+        let framed = content.strip_suffix(b"\n")?;
+        let start = framed.iter().rposition(|byte| *byte == b'\n')?;
+        let footer = std::str::from_utf8(&framed[start + 1..]).ok()?;
+        Some((&framed[..start], footer)).filter(|_| footer.starts_with(FOOTER))
+    }
+
+    /// The content of a file written by `write_verified`, failing unless
+    /// it has the length and the digest of its footer.
+    pub fn read_verified(path: &Path) -> Result<Vec<u8>> {
+        let content =
+            std::fs::read(path).with_context(|| format!("cannot read {}", path.display()))?;
+        verify(&content).with_context(|| format!("{} is corrupted", path.display()))
+    }
+
+    fn verify(content: &[u8]) -> Result<Vec<u8>> {
+        let (content, footer) = match footer(content) {
+            Some(framed) => framed,
+            None => bail!("it has no footer, as if truncated"),
+        };
+        let fields: Vec<_> = footer.split(' ').collect();
+        let (length, algorithm, expected) = match fields.as_slice() {
+            [_, length, algorithm, hex] => (length.parse::<usize>()?, algorithm.parse()?, hex),
+            _ => bail!("its footer is invalid"),
+        };
+        if content.len() != length {
+            bail!("it has {} bytes, not {}", content.len(), length);
+        }
+        let actual = HEXLOWER.encode(digest(algorithm, content)?.as_ref());
+        if actual != *expected {
+            bail!("its {} is {}, not {}", algorithm.name(), actual, expected);
+        }
+        Ok(content.to_vec())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        // the digests of "abc", from FIPS 180-2
+        const ABC_SHA256: &str = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        const ABC_SHA512: &str = "ddaf35a193617abacc417349ae20413112e6fa4e89a97ea20a9eeee64b55d39a\
+                                  2192992a274fc1a836ba3c23a3feebbd454d4423643ce80e2a9ac94fa54ca49f";
+
+        fn hex(digest: Digest) -> std::string::String {
+            HEXLOWER.encode(digest.as_ref())
+        }
+
+        #[test]
+        fn digests_are_the_known_ones() {
+            assert_eq!(
+                hex(digest(DigestAlgorithm::Sha256, &b"abc"[..]).unwrap()),
+                ABC_SHA256
+            );
+            assert_eq!(
+                hex(digest(DigestAlgorithm::Sha512, &b"abc"[..]).unwrap()),
+                ABC_SHA512
+            );
+            // across chunks, as one read
+            let long = vec![b'a'; 3 * CHUNK_SIZE + 1];
+            assert_eq!(
+                digest(DigestAlgorithm::Sha512, long.as_slice())
+                    .unwrap()
+                    .as_ref(),
+                ring::digest::digest(&ring::digest::SHA512, &long).as_ref()
+            );
+        }
+
+        #[tokio::test(threaded_scheduler)]
+        async fn files_are_hashed_off_the_runtime() {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("abc");
+            std::fs::write(&path, "abc").unwrap();
+            let digests = futures::future::join_all((0..8).map(|i| {
+                let algorithm = if i % 2 == 0 {
+                    DigestAlgorithm::Sha256
+                } else {
+                    DigestAlgorithm::Sha512
+                };
+                tokio::spawn(file_digest(algorithm, path.clone()))
+            }))
+            .await;
+            for (i, digest) in digests.into_iter().enumerate() {
+                let expected = if i % 2 == 0 { ABC_SHA256 } else { ABC_SHA512 };
+                assert_eq!(hex(digest.unwrap().unwrap()), expected);
+            }
+            assert!(
+                file_digest(DigestAlgorithm::Sha256, dir.path().join("missing"))
+                    .await
+                    .is_err()
+            );
+
+            // the same from the runtime's threads as from outside of it
+            let blocking = file_digest_blocking(DigestAlgorithm::Sha512, path.clone()).unwrap();
+            assert_eq!(hex(blocking), ABC_SHA512);
+            let outside =
+                std::thread::spawn(move || file_digest_blocking(DigestAlgorithm::Sha256, path));
+            assert_eq!(hex(outside.join().unwrap().unwrap()), ABC_SHA256);
+        }
+
+        #[test]
+        fn truncated_and_corrupted_files_are_refused() {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("state.json");
+            let content = br#"{"services": []}"#;
+            write_verified(&path, content, DigestAlgorithm::Sha512).unwrap();
+            assert_eq!(read_verified(&path).unwrap(), content);
+            // none of the temporary files left behind
+            assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+
+            let framed = std::fs::read(&path).unwrap();
+            for cut in &[framed.len() - 1, framed.len() - 10, content.len(), 5] {
+                std::fs::write(&path, &framed[..*cut]).unwrap();
+                assert!(read_verified(&path).is_err(), "cut at {}", cut);
+            }
+            let corrupted = std::str::from_utf8(&framed)
+                .unwrap()
+                .replace("services", "servicez");
+            std::fs::write(&path, corrupted).unwrap();
+            let error = format!("{:#}", read_verified(&path).unwrap_err());
+            assert!(error.contains("its sha512 is"), "{}", error);
+            // the footer claiming a length of its own
+            let lying = std::str::from_utf8(&framed).unwrap().replacen(
+                &format!(" {} ", content.len()),
+                " 3 ",
+                1,
+            );
+            std::fs::write(&path, lying).unwrap();
+            let error = format!("{:#}", read_verified(&path).unwrap_err());
+            assert!(error.contains("not 3"), "{}", error);
+        }
+
+        #[test]
+        fn concurrent_writers_never_leave_half_a_file() {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("snapshot.json");
+            let contents: Vec<Vec<u8>> = (0..4u8).map(|i| vec![b'a' + i; 256 * 1024]).collect();
+            write_atomic(&path, &contents[0]).unwrap();
+            std::thread::scope(|scope| {
+                for content in &contents {
+                    let path = &path;
+                    scope.spawn(move || {
+                        for _ in 0..10 {
+                            write_atomic(path, content).unwrap();
+                        }
+                    });
+                }
+                scope.spawn(|| {
+                    for _ in 0..100 {
+                        let read = std::fs::read(&path).unwrap();
+                        assert!(contents.contains(&read), "read {} bytes", read.len());
+                    }
+                });
+            });
+            assert!(contents.contains(&std::fs::read(&path).unwrap()));
+            assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+        }
+    }
+}
+
+pub mod concurrency {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+    use tokio::runtime::Handle;
+
+    /// A thread running `f` within the runtime of the caller, if any, for
+    /// the files `f` hashes to be read through it, see `file_digest_blocking`.
+    pub fn spawn<F: FnOnce() + Send + 'static>(f: F) -> std::thread::JoinHandle<()> {
+        let runtime = Handle::try_current().ok();
+        std::thread::spawn(move || within(runtime.as_ref(), f))
+    }
+
+    fn within<R>(runtime: Option<&Handle>, f: impl FnOnce() -> R) -> R {
+        match runtime {
+            Some(runtime) => runtime.enter(f),
+            None => f(),
+        }
+    }
+
+    /// `f` applied to every item on up to `limit` threads, the results in
+    /// the order of the items whatever order they complete in.
+    pub fn map_bounded<T, R, F>(items: &[T], limit: usize, f: F) -> Vec<R>
+    where
+        T: Sync,
+        R: Send,
+        F: Fn(&T) -> R + Sync,
+    {
+        let workers = limit.max(1).min(items.len());
+        if workers <= 1 {
+            return items.iter().map(f).collect();
+        }
+        let next = AtomicUsize::new(0);
+        let results = Mutex::new(Vec::with_capacity(items.len()));
+        let runtime = Handle::try_current().ok();
+        std::thread::scope(|scope| {
+            for _ in 0..workers {
+                scope.spawn(|| {
+                    within(runtime.as_ref(), || loop {
+                        let index = next.fetch_add(1, Ordering::Relaxed);
+                        let item = match items.get(index) {
+                            Some(item) => item,
+                            None => break,
+                        };
+                        let result = f(item);
+                        results.lock().unwrap().push((index, result));
+                    })
+                });
+            }
+        });
+        let mut results = results.into_inner().unwrap();
+        results.sort_by_key(|(index, _)| *index);
+        results.into_iter().map(|(_, result)| result).collect()
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use std::sync::Barrier;
+
+        #[test]
+        fn items_run_up_to_the_limit_at_once() {
+            // every item waits for three others, which only run alongside it
+            // with four workers
+            let barrier = Barrier::new(4);
+            let running = AtomicUsize::new(0);
+            let most = AtomicUsize::new(0);
+            let items: Vec<u64> = (1..=8).collect();
+            let results = map_bounded(&items, 4, |item| {
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                most.fetch_max(now, Ordering::SeqCst);
+                barrier.wait();
+                running.fetch_sub(1, Ordering::SeqCst);
+                item * 2
+            });
+
+            assert_eq!(results, items.iter().map(|i| i * 2).collect::<Vec<_>>());
+            assert_eq!(most.into_inner(), 4);
+        }
+    }
 }
@@ -0,0 +1,581 @@
+use std::fmt::Write;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use crate::cli::{ControllerConfig, ReportFormat};
+use crate::configuration::{services_files, ServicesFormat};
+use crate::conflicts::{self, HostConflicts, Placed};
+use crate::envoy_helpers::EnvoyExport;
+use crate::field_errors::{index, FieldError, FieldErrors};
+use crate::migration;
+use crate::service::{Service, WasmSettings};
+use crate::snippets;
+
+/// Outcome of checking a single service.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct ServiceReport {
+    // missing when the service is not even an object with an id
+    pub id: Option<u64>,
+    pub passed: bool,
+    pub error: Option<std::string::String>,
+    // the fields at fault, when the service is not valid
+    pub errors: Vec<FieldError>,
+    // what doesn't fail it, like the hosts it shares with other services
+    // or the policies left to the wasm filter
+    pub warnings: Vec<FieldError>,
+}
+
+/// Outcome of checking a services file.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct FileReport {
+    pub path: std::string::String,
+    // why the file could not be read as a list of services at all
+    pub error: Option<std::string::String>,
+    // what was migrated for the file to be in the current version
+    pub migrations: Vec<std::string::String>,
+    pub services: Vec<ServiceReport>,
+}
+
+impl FileReport {
+    fn passed(&self) -> bool {
+        self.error.is_none() && self.services.iter().all(|service| service.passed)
+    }
+}
+
+/// What `validate` found in every services file.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct Report {
+    pub passed: bool,
+    pub files: Vec<FileReport>,
+}
+
+impl Report {
+    pub fn exit_code(&self) -> i32 {
+        if self.passed {
+            0
+        } else {
+            1
+        }
+    }
+
+    pub fn render(&self, format: ReportFormat) -> std::string::String {
+        match format {
+            ReportFormat::Json => serde_json::to_string_pretty(self).unwrap() + "\n",
+            ReportFormat::Text => self.render_text(),
+        }
+    }
+
+    fn render_text(&self) -> std::string::String {
+        let mut text = std::string::String::new();
+        let (mut total, mut failed) = (0, 0);
+        for file in &self.files {
+            match file.error {
+                Some(ref error) => writeln!(text, "FAIL {}: {}", file.path, error).unwrap(),
+                None => writeln!(text, "{}", file.path).unwrap(),
+            }
+            for migration in &file.migrations {
+                writeln!(text, "  migrated: {}", migration).unwrap();
+            }
+            for service in &file.services {
+                let id = service
+                    .id
+                    .map(|id| id.to_string())
+                    .unwrap_or_else(|| "without id".to_string());
+                match service.error {
+                    Some(_) if !service.errors.is_empty() => {
+                        writeln!(text, "  FAIL service {}:", id).unwrap();
+                        for error in &service.errors {
+                            writeln!(text, "    {}: {}", error.path, error.message).unwrap();
+                        }
+                    }
+                    Some(ref error) => writeln!(text, "  FAIL service {}: {}", id, error).unwrap(),
+                    None => writeln!(text, "  PASS service {}", id).unwrap(),
+                }
+                for warning in &service.warnings {
+                    writeln!(text, "    warning: {}: {}", warning.path, warning.message).unwrap();
+                }
+                total += 1;
+                failed += !service.passed as usize;
+            }
+        }
+        writeln!(text, "{} services checked, {} failed", total, failed).unwrap();
+        text
+    }
+}
+
+// Export the service and encode its resources the way they are served.
+fn check(service: &Service, wasm: &WasmSettings, offline: bool) -> Result<Vec<EnvoyExport>> {
+    let mut service = service.clone();
+    if offline {
+        if let Some(issuer) = service.oidc_issuer.take() {
+            url::Url::parse(&issuer)
+                .with_context(|| format!("invalid OIDC issuer '{}'", issuer))?;
+        }
+    }
+    let exports = service.export(wasm)?;
+    for export in &exports {
+        export
+            .config
+            .to_any()
+            .with_context(|| format!("cannot encode {}", export.key))?;
+    }
+    Ok(exports)
+}
+
+/// Check a single service the way the validate command does, along with
+/// its resources when it passes. `path` is where the service is in its
+/// services file, for the errors to point at its fields, and unknown
+/// fields fail it when `strict`.
+pub fn check_service(
+    value: serde_json::Value,
+    path: &str,
+    strict: bool,
+    wasm: &WasmSettings,
+    offline: bool,
+) -> (ServiceReport, Option<Vec<EnvoyExport>>) {
+    let (report, _, exports) = check_value(value, path, strict, wasm, offline);
+    (report, exports)
+}
+
+// `check_service`, along with the service when it could be read, for it to
+// be checked against the others.
+fn check_value(
+    value: serde_json::Value,
+    path: &str,
+    strict: bool,
+    wasm: &WasmSettings,
+    offline: bool,
+) -> (ServiceReport, Option<Service>, Option<Vec<EnvoyExport>>) {
+    let id = value.get("id").and_then(serde_json::Value::as_u64);
+    let (service, findings) = Service::from_value(&value, path, strict);
+    let service = match service {
+        Some(service) => service,
+        None => {
+            let report = ServiceReport {
+                id,
+                passed: false,
+                error: Some(format!(
+                    "invalid service: {}",
+                    FieldErrors(findings.errors.clone())
+                )),
+                errors: findings.errors,
+                warnings: findings.warnings,
+            };
+            return (report, None, None);
+        }
+    };
+    let result = check(&service, wasm, offline);
+    let report = ServiceReport {
+        id,
+        passed: result.is_ok(),
+        error: result.as_ref().err().map(|error| format!("{:#}", error)),
+        errors: Vec::new(),
+        warnings: findings.warnings,
+    };
+    (report, Some(service), result.ok())
+}
+
+// A service read out of the services file `file` of a report, the
+// `service`th of the file at `path`.
+struct Read {
+    file: usize,
+    service: usize,
+    path: std::string::String,
+    parsed: Service,
+}
+
+// Check the services read against each other, failing the conflicting
+// ones. The errors name the files of the services when `files`, the
+// services being read out of more than one.
+fn check_conflicts(reports: &mut [FileReport], read: &[Read], files: bool, policy: HostConflicts) {
+    let placed: Vec<_> = read
+        .iter()
+        .map(|read| Placed {
+            service: &read.parsed,
+            file: Some(reports[read.file].path.clone()).filter(|_| files),
+            path: read.path.clone(),
+        })
+        .collect();
+    let found = conflicts::check(&placed, policy);
+    for (i, error) in found.errors {
+        let service = &mut reports[read[i].file].services[read[i].service];
+        service.passed = false;
+        service.error.get_or_insert_with(|| error.to_string());
+        service.errors.push(error);
+    }
+    for (i, warning) in found.warnings {
+        reports[read[i].file].services[read[i].service]
+            .warnings
+            .push(warning);
+    }
+}
+
+// Check the services file at `path`, the `file`th one of the reports,
+// returning the services read for them to be checked together.
+fn check_file(
+    path: &Path,
+    file: usize,
+    format: ServicesFormat,
+    strict: bool,
+    wasm: &WasmSettings,
+    offline: bool,
+) -> (FileReport, Vec<Read>) {
+    let mut report = FileReport {
+        path: path.display().to_string(),
+        error: None,
+        migrations: Vec::new(),
+        services: Vec::new(),
+    };
+    let migrated = std::fs::read_to_string(path)
+        .with_context(|| format!("cannot read {}", path.display()))
+        .and_then(|content| format.parse(&content).context("not a list of services"))
+        .and_then(migration::migrate);
+    let migrated = match migrated {
+        Ok(migrated) => migrated,
+        Err(error) => {
+            report.error = Some(format!("{:#}", error));
+            return (report, Vec::new());
+        }
+    };
+    report.migrations = migrated.applied;
+    let base = migrated.path;
+    let snippets = migrated.snippets;
+
+    // services one by one, so that a bad one doesn't hide the others
+    let (values, single) = match migrated.services {
+        serde_json::Value::Array(values) => (values, false),
+        value @ serde_json::Value::Object(_) => (vec![value], true),
+        _ => {
+            report.error =
+                Some("not a list of services: neither a service nor a list of them".to_string());
+            return (report, Vec::new());
+        }
+    };
+    let mut read = Vec::new();
+    for (i, mut value) in values.into_iter().enumerate() {
+        // paths into the list, unless the file is a single service
+        let path = if single {
+            base.to_string()
+        } else {
+            index(base, i)
+        };
+        if let Err(error) = snippets::expand(&mut value, &snippets, &path) {
+            report.services.push(ServiceReport {
+                id: error.service,
+                passed: false,
+                error: Some(format!("invalid service: {}", error)),
+                errors: vec![error],
+                warnings: Vec::new(),
+            });
+            continue;
+        }
+        let (service, parsed, _) = check_value(value, &path, strict, wasm, offline);
+        if let Some(parsed) = parsed {
+            read.push(Read {
+                file,
+                service: i,
+                path,
+                parsed,
+            });
+        }
+        report.services.push(service);
+    }
+    (report, read)
+}
+
+// Check the services files of a directory, which are served together so
+// that their services are checked against those of the other files.
+fn check_dir(dir: &Path, settings: &ControllerConfig) -> Vec<FileReport> {
+    let paths = match services_files(dir) {
+        Ok(paths) => paths,
+        Err(error) => {
+            return vec![FileReport {
+                path: dir.display().to_string(),
+                error: Some(format!("{:#}", error)),
+                migrations: Vec::new(),
+                services: Vec::new(),
+            }]
+        }
+    };
+    let mut reports = Vec::new();
+    let mut read = Vec::new();
+    for (file, path) in paths.iter().enumerate() {
+        let (report, services) = check_path(path, file, settings);
+        reports.push(report);
+        read.extend(services);
+    }
+    check_conflicts(&mut reports, &read, true, settings.host_conflicts);
+    reports
+}
+
+fn check_path(path: &Path, file: usize, settings: &ControllerConfig) -> (FileReport, Vec<Read>) {
+    let format = settings
+        .services_format
+        .unwrap_or_else(|| ServicesFormat::of(path));
+    check_file(
+        path,
+        file,
+        format,
+        settings.strict_config,
+        &settings.wasm,
+        settings.validation.offline,
+    )
+}
+
+/// Check the services files of the validate command, without serving them.
+/// Directories are checked file by file.
+pub fn run(settings: &ControllerConfig) -> Report {
+    let files: Vec<FileReport> = settings
+        .validation
+        .files
+        .iter()
+        .flat_map(|path| {
+            if path.is_dir() {
+                check_dir(path, settings)
+            } else {
+                let (mut report, read) = check_path(path, 0, settings);
+                check_conflicts(
+                    std::slice::from_mut(&mut report),
+                    &read,
+                    false,
+                    settings.host_conflicts,
+                );
+                vec![report]
+            }
+        })
+        .collect();
+    Report {
+        passed: files.iter().all(FileReport::passed),
+        files,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GOOD: &str = r#"[
+        {"id": 1, "hosts": ["one"], "policies": [], "target_domain": "http://one:80", "proxy_rules": []},
+        {"id": 2, "hosts": ["two"], "policies": [], "target_domain": "http://two:80", "proxy_rules": [],
+         "oidc_issuer": "http://keycloak:8080/auth/realms/two"}
+    ]"#;
+
+    const BAD: &str = r#"[
+        {"id": 1, "hosts": ["one"], "policies": [], "target_domain": "http://one:80", "proxy_rules": []},
+        {"id": 2, "hosts": ["two"], "policies": [], "target_domain": "", "proxy_rules": []},
+        {"id": 3, "hosts": "three"}
+    ]"#;
+
+    fn validate(dir: &Path, args: &str) -> Report {
+        let args = format!(
+            "gateway-ng-controller validate --wasm-filter-path {} {}",
+            dir.join("filter.wasm").display(),
+            args
+        );
+        run(&ControllerConfig::from_args(args.split_whitespace()).unwrap())
+    }
+
+    fn fixture(dir: &Path, name: &str, content: &str) -> std::string::String {
+        let path = dir.join(name);
+        std::fs::write(&path, content).unwrap();
+        path.display().to_string()
+    }
+
+    #[test]
+    fn good_services_pass_offline() {
+        let dir = tempfile::tempdir().unwrap();
+        let good = fixture(dir.path(), "good.json", GOOD);
+
+        // neither the filter nor the OIDC issuer are reachable
+        let report = validate(dir.path(), &format!("--offline --skip-sha {}", good));
+        assert_eq!(report.exit_code(), 0, "{:?}", report);
+        let text = report.render(ReportFormat::Text);
+        assert!(
+            text.contains("  PASS service 1\n  PASS service 2\n"),
+            "{}",
+            text
+        );
+        assert!(text.ends_with("2 services checked, 0 failed\n"), "{}", text);
+
+        // the filter digest needs the filter
+        let report = validate(dir.path(), &format!("--offline {}", good));
+        assert_eq!(report.exit_code(), 1);
+        let error = report.files[0].services[0].error.as_ref().unwrap();
+        assert!(error.contains("filter.wasm is missing"), "{}", error);
+        fixture(dir.path(), "filter.wasm", "filter");
+        assert_eq!(
+            validate(dir.path(), &format!("--offline {}", good)).exit_code(),
+            0
+        );
+    }
+
+    #[test]
+    fn every_failure_is_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let bad = fixture(dir.path(), "bad.json", BAD);
+        let broken = fixture(dir.path(), "broken.json", "{");
+
+        let report = validate(
+            dir.path(),
+            &format!("--offline --skip-sha {} {}", bad, broken),
+        );
+        assert_eq!(report.exit_code(), 1);
+        let json: serde_json::Value =
+            serde_json::from_str(&report.render(ReportFormat::Json)).unwrap();
+        assert_eq!(json["passed"], false);
+        let services = json["files"][0]["services"].as_array().unwrap();
+        let passed: Vec<_> = services
+            .iter()
+            .map(|service| (service["id"].as_u64(), service["passed"].as_bool()))
+            .collect();
+        assert_eq!(
+            passed,
+            [
+                (Some(1), Some(true)),
+                (Some(2), Some(false)),
+                (Some(3), Some(false))
+            ]
+        );
+        let error = services[2]["error"].as_str().unwrap();
+        assert!(error.starts_with("invalid service"), "{}", error);
+        let paths: Vec<_> = services
+            .iter()
+            .map(|service| service["errors"][0]["path"].as_str())
+            .collect();
+        assert_eq!(paths, [None, Some("[1].target_domain"), Some("[2].hosts")]);
+        assert!(report
+            .render(ReportFormat::Text)
+            .contains("  FAIL service 3:\n    [2].hosts: invalid type"));
+        assert!(json["files"][1]["error"]
+            .as_str()
+            .unwrap()
+            .starts_with("not a list of services"));
+    }
+
+    // Services with several broken policies each, and policies only warned
+    // about.
+    const MESSY: &str = include_str!("../testdata/validate/messy.json");
+
+    #[test]
+    fn every_problem_is_reported_at_its_place() {
+        let dir = tempfile::tempdir().unwrap();
+        let messy = fixture(dir.path(), "messy.json", MESSY);
+        let report = validate(dir.path(), &format!("--offline --skip-sha {}", messy));
+        let problems = |problems: &[FieldError]| -> Vec<_> {
+            problems
+                .iter()
+                .map(|problem| (problem.service, problem.path.clone()))
+                .collect()
+        };
+        let services = &report.files[0].services;
+        assert!(!services[0].passed);
+        let at = |id, path: &str| (Some(id), path.to_string());
+        assert_eq!(
+            problems(&services[0].errors),
+            [
+                at(7, "[0].policies[0]"),
+                at(7, "[0].policies[1].configuration.ips[0]"),
+                at(7, "[0].policies[3].configuration.commands"),
+            ]
+        );
+        assert!(services[0].errors[0]
+            .message
+            .starts_with("policy 'rate_limit': missing field `interval`"));
+        assert_eq!(
+            problems(&services[0].warnings),
+            [
+                at(7, "[0].hosts[1]"),
+                at(7, "[0].policies[2].configuration")
+            ]
+        );
+
+        // warnings alone don't fail a service
+        assert!(services[1].passed, "{:?}", services[1]);
+        assert_eq!(
+            problems(&services[1].warnings),
+            [
+                at(8, "[1].policies[0].configuration"),
+                at(8, "[1].policies[1].configuration.upstreams.legacy"),
+            ]
+        );
+        let text = report.render(ReportFormat::Text);
+        assert!(
+            text.contains(
+                "  PASS service 8\n    warning: [1].policies[0].configuration: 'liquid_context_debug' is not a known policy"
+            ),
+            "{}",
+            text
+        );
+    }
+
+    #[test]
+    fn legacy_files_report_their_migrations() {
+        let dir = tempfile::tempdir().unwrap();
+        let legacy = fixture(
+            dir.path(),
+            "legacy.json",
+            r#"[{"id": 1, "hosts": ["one"], "policies": [], "target_domain": "http://one:80",
+                 "proxy_rules": [], "backend": "https://backend.app/"}]"#,
+        );
+        let newer = fixture(
+            dir.path(),
+            "newer.json",
+            r#"{"version": 99, "services": []}"#,
+        );
+
+        let report = validate(
+            dir.path(),
+            &format!("--offline --skip-sha {} {}", legacy, newer),
+        );
+        let text = report.render(ReportFormat::Text);
+        assert!(
+            text.contains("legacy.json\n  migrated: backend URL moved into auth_config"),
+            "{}",
+            text
+        );
+        assert!(report.files[0].passed());
+        let error = report.files[1].error.as_ref().unwrap();
+        assert!(error.contains("version 99 is newer"), "{}", error);
+    }
+
+    #[test]
+    fn conflicts_are_checked_across_a_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let services = dir.path().join("services");
+        std::fs::create_dir(&services).unwrap();
+        fixture(&services, "a.json", GOOD);
+        fixture(
+            &services,
+            "b.json",
+            r#"[{"id": 2, "hosts": ["other"], "policies": [], "target_domain": "http://two:80", "proxy_rules": []},
+                {"id": 3, "hosts": ["*ne"], "policies": [], "target_domain": "http://three:80", "proxy_rules": []}]"#,
+        );
+        let args = format!("--offline --skip-sha {}", services.display());
+
+        // overlapping hosts only warn by default
+        let report = validate(dir.path(), &args);
+        assert_eq!(report.exit_code(), 1);
+        let b = &report.files[1].services;
+        assert!(!b[0].passed);
+        let error = &b[0].errors[0];
+        assert_eq!(error.path, "[0].id");
+        assert!(
+            error.message.contains("a.json at [1] has the same id"),
+            "{}",
+            error
+        );
+        assert!(b[1].passed);
+        let text = report.render(ReportFormat::Text);
+        assert!(
+            text.contains("  PASS service 3\n    warning: [1].hosts[0]: '*ne' overlaps 'one'"),
+            "{}",
+            text
+        );
+
+        let report = validate(dir.path(), &format!("--host-conflicts error {}", args));
+        assert!(!report.files[1].services[1].passed);
+        assert!(report.files[1].services[1].warnings.is_empty());
+    }
+}
@@ -0,0 +1,212 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::RwLock;
+use std::time::{Duration, SystemTime};
+
+use crate::configuration;
+use crate::publisher::Publisher;
+
+// How often the filters of the services are checked for changes.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(2);
+// How long a changed filter must stay the same before it is hashed, for a
+// module being copied not to be published half written.
+const SETTLE: Duration = Duration::from_millis(500);
+
+// What tells that a filter changed without reading it, `None` while the
+// file is missing.
+type Stamp = Option<(u64, SystemTime)>;
+
+fn stamps(config: &RwLock<configuration::Config>) -> BTreeMap<PathBuf, Stamp> {
+    let config = config.read().unwrap();
+    let wasm = config.wasm();
+    if wasm.skip_sha {
+        // the exports don't depend on the content of the filters
+        return BTreeMap::new();
+    }
+    config
+        .get_services()
+        .iter()
+        .flat_map(|service| service.wasm_files(wasm))
+        .map(|path| {
+            let stamp = path
+                .metadata()
+                .and_then(|m| Ok((m.len(), m.modified()?)))
+                .ok();
+            (path, stamp)
+        })
+        .collect()
+}
+
+// The stamps of the filters last published, and those of the filters
+// changed since, until they settle.
+struct Watch {
+    published: BTreeMap<PathBuf, Stamp>,
+    changing: Option<BTreeMap<PathBuf, Stamp>>,
+}
+
+impl Watch {
+    fn new(published: BTreeMap<PathBuf, Stamp>) -> Watch {
+        Watch {
+            published,
+            changing: None,
+        }
+    }
+
+    // How long to wait before the next check, shorter while the filters
+    // settle.
+    fn delay(&self, interval: Duration, settle: Duration) -> Duration {
+        match self.changing {
+            Some(_) => settle,
+            None => interval,
+        }
+    }
+
+    // Whether the filters of `current`, the stamps just taken, changed and
+    // stayed the same since the previous check, and are to be published.
+    fn check(&mut self, current: BTreeMap<PathBuf, Stamp>) -> bool {
+        match self.changing.take() {
+            Some(previous) if previous == current => {
+                self.published = current;
+                true
+            }
+            // until the files stop changing
+            Some(_) => {
+                self.changing = Some(current);
+                false
+            }
+            None if current != self.published => {
+                self.changing = Some(current);
+                false
+            }
+            None => false,
+        }
+    }
+}
+
+async fn reload(publisher: Publisher, interval: Duration, settle: Duration) {
+    let config = publisher.config();
+    let mut watch = Watch::new(stamps(config));
+    loop {
+        tokio::time::delay_for(watch.delay(interval, settle)).await;
+        if watch.check(stamps(config)) {
+            tracing::info!("Filters of the services changed, exporting them again");
+            publisher.schedule_filters();
+        }
+    }
+}
+
+/// Export the services again whenever the filters they have Envoy fetch
+/// change, the listeners of those services getting the new digests.
+pub fn spawn_reloader(publisher: Publisher) {
+    tokio::spawn(reload(publisher, RELOAD_INTERVAL, SETTLE));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::envoy_helpers::LISTENER_TYPE_URL;
+    use crate::protobuf::envoy::config::core::v3::async_data_source::Specifier;
+    use crate::protobuf::envoy::config::listener::v3::{filter, Listener};
+    use crate::protobuf::envoy::extensions::filters::http::wasm::v3::Wasm;
+    use crate::protobuf::envoy::extensions::filters::network::http_connection_manager::v3::{
+        http_filter, HttpConnectionManager,
+    };
+    use crate::protobuf::envoy::extensions::wasm::v3::plugin_config::Vm;
+    use crate::service::{Service, WasmSettings};
+    use crate::util::file_utils::DigestAlgorithm;
+    use prost::Message;
+    use std::sync::Arc;
+
+    fn services() -> configuration::Config {
+        let content = serde_json::json!([{
+            "id": 1,
+            "hosts": ["web.app"],
+            "policies": [],
+            "target_domain": "http://web.app:80",
+            "proxy_rules": [],
+        }])
+        .to_string();
+        let services = serde_json::from_str(&content).unwrap();
+        configuration::Config::from_services(services, &content)
+    }
+
+    // The version of the snapshot, and the digest its listener has Envoy
+    // check the filter against.
+    fn published(config: &RwLock<configuration::Config>) -> (u32, std::string::String) {
+        let config = config.read().unwrap();
+        let snapshot = config.get_snapshot();
+        let resource = snapshot
+            .resources(LISTENER_TYPE_URL)
+            .unwrap()
+            .values()
+            .next()
+            .unwrap();
+        let listener = Listener::decode(resource.resource.value.as_slice()).unwrap();
+        let manager = match listener.filter_chains[0].filters[0].config_type {
+            Some(filter::ConfigType::TypedConfig(ref any)) => {
+                HttpConnectionManager::decode(any.value.as_slice()).unwrap()
+            }
+            ref other => panic!("{:?}", other),
+        };
+        let filter = manager
+            .http_filters
+            .iter()
+            .find(|filter| filter.name == "envoy.filters.http.wasm")
+            .unwrap();
+        let wasm = match filter.config_type {
+            Some(http_filter::ConfigType::TypedConfig(ref any)) => {
+                Wasm::decode(any.value.as_slice()).unwrap()
+            }
+            ref other => panic!("{:?}", other),
+        };
+        let code = match wasm.config.unwrap().vm {
+            Some(Vm::VmConfig(vm)) => vm.code.unwrap(),
+            other => panic!("{:?}", other),
+        };
+        match code.specifier {
+            Some(Specifier::Remote(remote)) => (config.get_version(), remote.sha256),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn swapped_filters_are_published_once_written() {
+        let dir = tempfile::tempdir().unwrap();
+        let filter = dir.path().join("filter.wasm");
+        std::fs::write(&filter, "filter v1").unwrap();
+        let mut config = configuration::Config::default();
+        config.set_wasm(WasmSettings {
+            filter_path: filter.clone(),
+            ..Default::default()
+        });
+        let config = Arc::new(RwLock::new(config));
+        assert!(configuration::publish(&config, services()).unwrap());
+        let sha = |content: &str| {
+            let path = dir.path().join("expected.wasm");
+            std::fs::write(&path, content).unwrap();
+            Service::get_wasm_filter_sha(&path, DigestAlgorithm::Sha256).unwrap()
+        };
+        assert_eq!(published(&config), (1, sha("filter v1")));
+
+        let interval = Duration::from_secs(2);
+        let settle = Duration::from_millis(500);
+        let mut watch = Watch::new(stamps(&config));
+        assert!(!watch.check(stamps(&config)));
+        assert_eq!(watch.delay(interval, settle), interval);
+
+        // copied in two writes, the first one not to be published
+        std::fs::write(&filter, "filter").unwrap();
+        assert!(!watch.check(stamps(&config)));
+        assert_eq!(watch.delay(interval, settle), settle);
+        std::fs::write(&filter, "filter v2, copied").unwrap();
+        assert!(!watch.check(stamps(&config)));
+        assert!(watch.check(stamps(&config)));
+        assert!(configuration::reexport_filters(&config).unwrap());
+        assert_eq!(published(&config), (2, sha("filter v2, copied")));
+        assert_eq!(watch.delay(interval, settle), interval);
+
+        // nothing new once published
+        assert!(!watch.check(stamps(&config)));
+        assert_eq!(published(&config).0, 2);
+    }
+}
@@ -0,0 +1,228 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use data_encoding::HEXLOWER;
+
+use crate::http_client::HttpClient;
+use crate::util::file_utils::{self, digest, DigestAlgorithm};
+
+// modules are larger than the other responses
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(100);
+
+/// Directory the copies of the modules go to unless told otherwise, under
+/// the default wasm server root.
+pub const DEFAULT_CACHE: &str = "static/remote";
+
+/// Whether `value` is a SHA-256 in hex, of either case.
+pub fn is_sha256(value: &str) -> bool {
+    value.len() == 64 && value.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// The services filter fetched from a URL, like an artifact registry,
+/// rather than read from a path, and pinned to its SHA-256. The filter path
+/// of the settings is the copy of it, for the digests to be read out of and
+/// the wasm server to serve.
+#[derive(Debug, Clone, PartialEq, Hash)]
+pub struct RemoteModule {
+    pub url: url::Url,
+    // lowercase hex
+    pub sha256: std::string::String,
+    // directory the copies go to, under the wasm server root for it to
+    // serve them
+    pub cache: PathBuf,
+    // Envoy fetches the copy from the wasm base URL rather than the module
+    // from its own URL
+    pub serve_copy: bool,
+}
+
+fn sha256(content: &[u8]) -> Result<std::string::String> {
+    Ok(HEXLOWER.encode(digest(DigestAlgorithm::Sha256, content)?.as_ref()))
+}
+
+impl RemoteModule {
+    /// Where the copy of the module is, named after its URL so that another
+    /// URL is downloaded anew.
+    pub fn path(&self) -> PathBuf {
+        let name = sha256(self.url.as_str().as_bytes()).unwrap_or_default();
+        self.cache.join(format!("{}.wasm", &name[..16]))
+    }
+
+    /// Download the module unless its copy has the pinned digest already,
+    /// returning the path of the copy. A module with another digest is
+    /// refused, and left out of the cache.
+    pub fn fetch(&self, client: &HttpClient) -> Result<PathBuf> {
+        let path = self.path();
+        if let Ok(content) = std::fs::read(&path) {
+            if sha256(&content)? == self.sha256 {
+                return Ok(path);
+            }
+            tracing::warn!(path = %path.display(), "Cached wasm module changed, downloading it again");
+        }
+
+        tracing::info!(url = %self.url, "Downloading the wasm module");
+        let content = self.download(client)?;
+        let actual = sha256(&content)?;
+        if actual != self.sha256 {
+            bail!(
+                "wasm module {} has digest {}, not the pinned {}",
+                self.url,
+                actual,
+                self.sha256
+            );
+        }
+        std::fs::create_dir_all(&self.cache)
+            .with_context(|| format!("cannot create {}", self.cache.display()))?;
+        // never a half written copy for the wasm server to serve
+        file_utils::write_atomic(&path, &content)?;
+        Ok(path)
+    }
+
+    fn download(&self, client: &HttpClient) -> Result<Vec<u8>> {
+        let mut easy = client.easy(&self.url)?;
+        easy.follow_location(true)?;
+        easy.timeout(client.timeout.max(DOWNLOAD_TIMEOUT))?;
+        let mut body = Vec::new();
+        {
+            let mut transfer = easy.transfer();
+            transfer.write_function(|data| {
+                body.extend_from_slice(data);
+                Ok(data.len())
+            })?;
+            transfer
+                .perform()
+                .with_context(|| format!("GET {} failed", self.url))?;
+        }
+        match easy.response_code()? {
+            200 => Ok(body),
+            status => bail!("GET {} returned status {}", self.url, status),
+        }
+    }
+
+    /// Check the copy at `path` still has the pinned digest, returning it.
+    pub fn verify(&self, path: &Path) -> Result<std::string::String> {
+        let content =
+            std::fs::read(path).with_context(|| format!("cannot read {}", path.display()))?;
+        let actual = sha256(&content)?;
+        if actual != self.sha256 {
+            bail!(
+                "{} has digest {}, not the pinned {}",
+                path.display(),
+                actual,
+                self.sha256
+            );
+        }
+        Ok(actual)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configuration;
+    use crate::http_harness::{Response, Server};
+    use crate::service::WasmSettings;
+
+    // Serves `modules` by path, 404 for the others.
+    fn serve(modules: &[(&str, &str)]) -> (url::Url, Server) {
+        let modules: Vec<(std::string::String, std::string::String)> = modules
+            .iter()
+            .map(|(path, body)| (format!("/{}", path), body.to_string()))
+            .collect();
+        let server = Server::start(move |request| {
+            match modules.iter().find(|(served, _)| served == request.path()) {
+                Some((_, body)) => Response::ok(body.as_str()),
+                None => Response::not_found(),
+            }
+        });
+        let url = url::Url::parse(&format!("{}/", server.url())).unwrap();
+        (url, server)
+    }
+
+    fn module(url: &url::Url, path: &str, content: &str, cache: &Path) -> RemoteModule {
+        RemoteModule {
+            url: url.join(path).unwrap(),
+            sha256: sha256(content.as_bytes()).unwrap(),
+            cache: cache.to_path_buf(),
+            serve_copy: false,
+        }
+    }
+
+    #[test]
+    fn modules_are_downloaded_once_per_url() {
+        let (url, server) = serve(&[
+            ("v1/filter.wasm", "filter v1"),
+            ("v2/filter.wasm", "filter v2"),
+        ]);
+        let cache = tempfile::tempdir().unwrap();
+
+        let v1 = module(&url, "v1/filter.wasm", "filter v1", cache.path());
+        let path = v1.fetch(&HttpClient::default()).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "filter v1");
+        assert_eq!(v1.verify(&path).unwrap(), v1.sha256);
+        assert_eq!(v1.fetch(&HttpClient::default()).unwrap(), path);
+        assert_eq!(server.requests().len(), 1);
+
+        // another URL is another copy
+        let v2 = module(&url, "v2/filter.wasm", "filter v2", cache.path());
+        let other = v2.fetch(&HttpClient::default()).unwrap();
+        assert_ne!(other, path);
+        assert_eq!(std::fs::read_to_string(&other).unwrap(), "filter v2");
+        assert_eq!(server.requests().len(), 2);
+
+        // a copy changed on disk is downloaded again
+        std::fs::write(&path, "tampered").unwrap();
+        assert!(v1.verify(&path).is_err());
+        v1.fetch(&HttpClient::default()).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "filter v1");
+        assert_eq!(server.requests().len(), 3);
+    }
+
+    #[test]
+    fn mismatched_modules_are_refused() {
+        let (url, _) = serve(&[("filter.wasm", "filter v2")]);
+        let cache = tempfile::tempdir().unwrap();
+
+        let pinned = module(&url, "filter.wasm", "filter v1", cache.path());
+        let error = format!("{:#}", pinned.fetch(&HttpClient::default()).unwrap_err());
+        assert!(error.contains("not the pinned"), "{}", error);
+        assert!(!pinned.path().exists());
+
+        let missing = module(&url, "missing.wasm", "filter v1", cache.path());
+        let error = format!("{:#}", missing.fetch(&HttpClient::default()).unwrap_err());
+        assert!(error.contains("status 404"), "{}", error);
+    }
+
+    #[test]
+    fn services_only_export_with_the_pinned_module() {
+        let (url, _) = serve(&[("filter.wasm", "filter v1")]);
+        let cache = tempfile::tempdir().unwrap();
+        let content = r#"[{"id": 1, "hosts": ["one"], "policies": [], "target_domain": "http://one:80", "proxy_rules": []}]"#;
+        let services =
+            configuration::Config::from_services(serde_json::from_str(content).unwrap(), content);
+        let settings = |remote: RemoteModule| WasmSettings {
+            filter_path: remote.path(),
+            remote: Some(remote),
+            ..Default::default()
+        };
+
+        let wasm = settings(module(&url, "filter.wasm", "filter v2", cache.path()));
+        let (exports, errors) = services.export_concurrently(&wasm, 1);
+        assert!(exports.is_empty());
+        assert_eq!(errors.len(), 1);
+        assert!(format!("{:#}", errors[0].1).contains("not the pinned"));
+
+        let wasm = settings(module(&url, "filter.wasm", "filter v1", cache.path()));
+        let (exports, errors) = services.export_concurrently(&wasm, 1);
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(exports.len(), 1);
+        assert_eq!(
+            wasm.filter_url(&wasm.filter_path),
+            url.join("filter.wasm").unwrap().as_str()
+        );
+        assert_eq!(
+            wasm.sha256(&wasm.filter_path).unwrap(),
+            sha256(b"filter v1").unwrap()
+        );
+    }
+}
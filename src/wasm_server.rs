@@ -0,0 +1,235 @@
+use std::ffi::OsStr;
+use std::path::{Component, Path, PathBuf};
+
+use warp::http::{header, Method, Response, StatusCode};
+use warp::Filter;
+
+use crate::service::Service;
+use crate::util::file_utils::DigestAlgorithm;
+
+// A single byte range of a `len` bytes file, `None` when the header is not
+// one we serve partially and the whole file goes out.
+fn byte_range(range: &str, len: u64) -> Option<Result<(u64, u64), ()>> {
+    let spec = range.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let mut bounds = spec.splitn(2, '-');
+    let (start, end) = (bounds.next()?.trim(), bounds.next()?.trim());
+    let range = match (start.parse::<u64>(), end.parse::<u64>()) {
+        (Ok(start), Ok(end)) if start <= end => (start, end.min(len.saturating_sub(1))),
+        (Ok(start), Err(_)) if end.is_empty() => (start, len.saturating_sub(1)),
+        // the last `suffix` bytes
+        (Err(_), Ok(suffix)) if start.is_empty() && suffix > 0 => {
+            (len.saturating_sub(suffix), len.saturating_sub(1))
+        }
+        _ => return None,
+    };
+    if range.0 >= len {
+        return Some(Err(()));
+    }
+    Some(Ok(range))
+}
+
+// The file under `root` at the request path, wasm modules only so that the
+// rest of the directory stays private.
+fn file(root: &Path, tail: &str) -> Option<PathBuf> {
+    let relative = Path::new(tail);
+    let plain = relative
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)));
+    if !plain || relative.extension() != Some(OsStr::new("wasm")) {
+        return None;
+    }
+    Some(root.join(relative))
+}
+
+fn respond(
+    root: &Path,
+    tail: &str,
+    method: &Method,
+    range: Option<&str>,
+    if_none_match: Option<&str>,
+) -> Response<Vec<u8>> {
+    let status = |status| Response::builder().status(status).body(Vec::new()).unwrap();
+    let path = match file(root, tail) {
+        Some(path) => path,
+        None => return status(StatusCode::NOT_FOUND),
+    };
+    let content = match std::fs::read(&path) {
+        Ok(content) => content,
+        Err(_) => return status(StatusCode::NOT_FOUND),
+    };
+    // the digest Envoy checks the fetched module against
+    let etag = match Service::get_wasm_filter_sha(&path, DigestAlgorithm::Sha256) {
+        Ok(sha) => format!("\"{}\"", sha),
+        Err(e) => {
+            tracing::error!("Cannot serve {}: {:#}", path.display(), e);
+            return status(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+
+    let response = Response::builder()
+        .header(header::ETAG, &etag)
+        .header(header::ACCEPT_RANGES, "bytes");
+    let matches = if_none_match
+        .into_iter()
+        .flat_map(|tags| tags.split(','))
+        .any(|tag| tag.trim() == "*" || tag.trim() == etag);
+    if matches {
+        return response
+            .status(StatusCode::NOT_MODIFIED)
+            .body(Vec::new())
+            .unwrap();
+    }
+
+    let len = content.len() as u64;
+    let (response, body) = match range.and_then(|range| byte_range(range, len)) {
+        Some(Ok((start, end))) => (
+            response.status(StatusCode::PARTIAL_CONTENT).header(
+                header::CONTENT_RANGE,
+                format!("bytes {}-{}/{}", start, end, len),
+            ),
+            content[start as usize..=end as usize].to_vec(),
+        ),
+        Some(Err(())) => {
+            return response
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header(header::CONTENT_RANGE, format!("bytes */{}", len))
+                .body(Vec::new())
+                .unwrap()
+        }
+        None => (response.status(StatusCode::OK), content),
+    };
+    let response = response
+        .header(header::CONTENT_TYPE, "application/wasm")
+        .header(header::CONTENT_LENGTH, body.len());
+    let body = if method == Method::HEAD {
+        Vec::new()
+    } else {
+        body
+    };
+    response.body(body).unwrap()
+}
+
+/// Serves the wasm modules under `root` at their path relative to it, for
+/// Envoy to fetch the filters from the control plane itself.
+pub fn routes(
+    root: PathBuf,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::get()
+        .or(warp::head())
+        .unify()
+        .and(warp::method())
+        .and(warp::path::tail())
+        .and(warp::header::optional::<std::string::String>("range"))
+        .and(warp::header::optional::<std::string::String>(
+            "if-none-match",
+        ))
+        .map(
+            move |method: Method,
+                  tail: warp::path::Tail,
+                  range: Option<std::string::String>,
+                  if_none_match: Option<std::string::String>| {
+                respond(
+                    &root,
+                    tail.as_str(),
+                    &method,
+                    range.as_deref(),
+                    if_none_match.as_deref(),
+                )
+            },
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn root() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("static")).unwrap();
+        std::fs::write(dir.path().join("static/filter.wasm"), b"\0asm-filter").unwrap();
+        std::fs::write(dir.path().join("log.json"), b"[]").unwrap();
+        dir
+    }
+
+    async fn request(
+        dir: &tempfile::TempDir,
+        request: warp::test::RequestBuilder,
+    ) -> Response<warp::hyper::body::Bytes> {
+        request.reply(&routes(dir.path().to_path_buf())).await
+    }
+
+    #[tokio::test]
+    async fn modules_are_served_with_their_digest() {
+        let dir = root();
+        let sha = Service::get_wasm_filter_sha(
+            dir.path().join("static/filter.wasm"),
+            DigestAlgorithm::Sha256,
+        )
+        .unwrap();
+
+        let response = request(&dir, warp::test::request().path("/static/filter.wasm")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["etag"], format!("\"{}\"", sha).as_str());
+        assert_eq!(response.headers()["content-length"], "11");
+        assert_eq!(response.body().as_ref(), b"\0asm-filter");
+
+        let response = request(
+            &dir,
+            warp::test::request()
+                .method("HEAD")
+                .path("/static/filter.wasm"),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["content-length"], "11");
+        assert!(response.body().is_empty());
+
+        let response = request(
+            &dir,
+            warp::test::request()
+                .path("/static/filter.wasm")
+                .header("if-none-match", format!("\"{}\"", sha)),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+
+        for path in &["/log.json", "/static/../log.json", "/static/missing.wasm"] {
+            let response = request(&dir, warp::test::request().path(path)).await;
+            assert_eq!(response.status(), StatusCode::NOT_FOUND, "{}", path);
+        }
+    }
+
+    #[tokio::test]
+    async fn ranges_are_served_partially() {
+        let dir = root();
+        let range = |range: &'static str| {
+            request(
+                &dir,
+                warp::test::request()
+                    .path("/static/filter.wasm")
+                    .header("range", range),
+            )
+        };
+
+        let response = range("bytes=1-3").await;
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers()["content-range"], "bytes 1-3/11");
+        assert_eq!(response.headers()["content-length"], "3");
+        assert_eq!(response.body().as_ref(), b"asm");
+
+        assert_eq!(range("bytes=5-").await.body().as_ref(), b"filter");
+        assert_eq!(range("bytes=-3").await.body().as_ref(), b"ter");
+
+        let response = range("bytes=20-30").await;
+        assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(response.headers()["content-range"], "bytes */11");
+
+        // several ranges get the whole module
+        let response = range("bytes=0-1,4-5").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.body().len(), 11);
+    }
+}
@@ -0,0 +1,305 @@
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use notify::{DebouncedEvent, RecursiveMode, Watcher};
+
+use crate::configuration;
+use crate::publisher::Publisher;
+
+// Editors usually write a file more than once when saving it, so wait for
+// things to settle before reloading.
+const DEBOUNCE: Duration = Duration::from_millis(500);
+// Used when the platform watcher cannot be set up.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Reloads the services config whenever it changes on disk and publishes a
+/// new snapshot version. Streams pick up the new version on their own, so
+/// nothing is dropped, and a config that fails to load keeps the previous
+/// snapshot in place.
+pub struct ConfigWatcher {
+    path: PathBuf,
+    options: configuration::ParseOptions,
+    publisher: Publisher,
+    debounce: Duration,
+}
+
+impl ConfigWatcher {
+    pub fn new(
+        path: impl AsRef<Path>,
+        options: configuration::ParseOptions,
+        publisher: Publisher,
+    ) -> Self {
+        ConfigWatcher {
+            path: path.as_ref().to_path_buf(),
+            options,
+            publisher,
+            debounce: DEBOUNCE,
+        }
+    }
+
+    /// Load the config once, returning whether a new version was published.
+    #[tracing::instrument(skip(self), fields(path = %self.path.display()))]
+    pub fn reload(&self) -> Result<bool> {
+        let path = self.path.to_string_lossy();
+        let new_config = configuration::Config::parse_config(&path, self.options)?;
+        self.publisher.publish(new_config)
+    }
+
+    fn reload_logged(&self) {
+        if let Err(e) = self.reload() {
+            tracing::error!(
+                version = self.publisher.config().read().unwrap().get_version(),
+                "Failed to reload {}, still serving the current version: {:#}",
+                self.path.display(),
+                e
+            );
+            configuration::reload_failed(self.publisher.config(), &e);
+        }
+    }
+
+    /// Reload once per burst of change notifications, until the sender goes
+    /// away.
+    pub fn run(&self, events: Receiver<()>) {
+        while events.recv().is_ok() {
+            loop {
+                match events.recv_timeout(self.debounce) {
+                    Ok(()) => continue,
+                    Err(RecvTimeoutError::Timeout) => break,
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+            }
+            self.reload_logged();
+        }
+    }
+
+    /// Do the initial load and keep watching the config in the background,
+    /// reloading on SIGHUP as well.
+    pub fn spawn(self: Arc<Self>) -> Result<()> {
+        self.reload_logged();
+
+        let (tx, rx) = channel();
+        on_hangup(tx.clone())?;
+        match self.fs_watcher(tx.clone()) {
+            Ok(fs_watcher) => {
+                std::thread::spawn(move || {
+                    // keep the watcher alive for as long as we are running
+                    let _fs_watcher = fs_watcher;
+                    self.run(rx)
+                });
+            }
+            Err(e) => {
+                tracing::warn!(
+                    "Cannot watch {}, polling every {:?} instead: {:?}",
+                    self.path.display(),
+                    POLL_INTERVAL,
+                    e
+                );
+                std::thread::spawn(move || loop {
+                    std::thread::sleep(POLL_INTERVAL);
+                    if tx.send(()).is_err() {
+                        break;
+                    }
+                });
+                std::thread::spawn(move || self.run(rx));
+            }
+        }
+        Ok(())
+    }
+
+    fn fs_watcher(&self, tx: Sender<()>) -> Result<notify::RecommendedWatcher> {
+        let (fs_tx, fs_rx) = channel();
+        let mut fs_watcher = notify::watcher(fs_tx, self.debounce)
+            .context("failed to create the config file watcher")?;
+
+        // Watch the parent directory for files since editors and config maps
+        // replace the file rather than writing to it.
+        let (target, mode) = if self.path.is_dir() {
+            (self.path.clone(), RecursiveMode::Recursive)
+        } else {
+            let parent = match self.path.parent() {
+                Some(parent) if parent != Path::new("") => parent.to_path_buf(),
+                _ => PathBuf::from("."),
+            };
+            (parent, RecursiveMode::NonRecursive)
+        };
+        fs_watcher
+            .watch(&target, mode)
+            .with_context(|| format!("failed to watch {}", target.display()))?;
+
+        let path = self.path.clone();
+        std::thread::spawn(move || {
+            for event in fs_rx {
+                let changed = match event {
+                    DebouncedEvent::Create(p)
+                    | DebouncedEvent::Write(p)
+                    | DebouncedEvent::Chmod(p)
+                    | DebouncedEvent::Remove(p)
+                    | DebouncedEvent::Rename(_, p) => is_config_path(&path, &p),
+                    DebouncedEvent::Rescan => true,
+                    _ => false,
+                };
+                if changed && tx.send(()).is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(fs_watcher)
+    }
+}
+
+// Reloads go through the same channel as the change notifications, so that
+// they are serialized and coalesced with them.
+fn on_hangup(tx: Sender<()>) -> Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangups = signal(SignalKind::hangup()).context("cannot handle SIGHUP")?;
+    tokio::spawn(async move {
+        while hangups.recv().await.is_some() {
+            tracing::info!("SIGHUP received, reloading the services config");
+            if tx.send(()).is_err() {
+                break;
+            }
+        }
+    });
+    Ok(())
+}
+
+fn is_config_path(config_path: &Path, changed: &Path) -> bool {
+    if changed.starts_with(config_path) {
+        return true;
+    }
+    // events carry absolute paths
+    match (config_path.canonicalize(), changed.canonicalize()) {
+        (Ok(config_path), Ok(changed)) => changed.starts_with(config_path),
+        _ => changed.file_name() == config_path.file_name(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::WasmSettings;
+    use crate::signal_harness;
+    use std::io::Write;
+    use std::sync::{Arc, RwLock};
+
+    fn watcher(dir: &tempfile::TempDir) -> ConfigWatcher {
+        let mut watcher = ConfigWatcher::new(
+            dir.path().join("services.json"),
+            Default::default(),
+            Publisher::new(
+                Arc::new(RwLock::new(configuration::Config::default())),
+                Duration::from_millis(0),
+            ),
+        );
+        watcher.debounce = Duration::from_millis(50);
+        watcher
+    }
+
+    fn write(watcher: &ConfigWatcher, content: &str) {
+        let mut file = std::fs::File::create(&watcher.path).unwrap();
+        file.write_all(content.as_bytes()).unwrap();
+    }
+
+    fn version(watcher: &ConfigWatcher) -> u32 {
+        watcher.publisher.config().read().unwrap().get_version()
+    }
+
+    #[test]
+    fn burst_of_writes_rebuilds_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let watcher = watcher(&dir);
+
+        let (tx, rx) = channel();
+        for content in &["[", "[]", "[ ]", "[\n]"] {
+            write(&watcher, content);
+            tx.send(()).unwrap();
+        }
+        drop(tx);
+        watcher.run(rx);
+
+        assert_eq!(version(&watcher), 1);
+        assert_eq!(
+            watcher
+                .publisher
+                .config()
+                .read()
+                .unwrap()
+                .get_services()
+                .len(),
+            0
+        );
+    }
+
+    #[test]
+    fn invalid_content_keeps_the_previous_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        let watcher = watcher(&dir);
+
+        write(&watcher, "[]");
+        assert!(watcher.reload().unwrap());
+        let hash = watcher.publisher.config().read().unwrap().get_hash();
+
+        write(&watcher, r#"[{"id": "not a number"}]"#);
+        let (tx, rx) = channel();
+        tx.send(()).unwrap();
+        drop(tx);
+        watcher.run(rx);
+
+        assert_eq!(version(&watcher), 1);
+        assert_eq!(watcher.publisher.config().read().unwrap().get_hash(), hash);
+    }
+
+    #[tokio::test]
+    async fn hangups_reload_changed_content_only() {
+        if signal_harness::in_child("watcher::tests::hangups_reload_changed_content_only") {
+            return;
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let watcher = watcher(&dir);
+        let config = Arc::clone(watcher.publisher.config());
+        config.write().unwrap().set_wasm(WasmSettings {
+            skip_sha: true,
+            ..Default::default()
+        });
+        let version = move || config.read().unwrap().get_version();
+        write(&watcher, "[]");
+        assert!(watcher.reload().unwrap());
+
+        let (tx, rx) = channel();
+        on_hangup(tx).unwrap();
+        let path = watcher.path.clone();
+        std::thread::spawn(move || watcher.run(rx));
+
+        signal_harness::raise("HUP");
+        tokio::time::delay_for(Duration::from_millis(300)).await;
+        assert_eq!(version(), 1);
+
+        let service = r#"[{"id": 1, "hosts": ["one"], "policies": [],
+            "target_domain": "http://one:80", "proxy_rules": []}]"#;
+        std::fs::write(&path, service).unwrap();
+        signal_harness::raise("HUP");
+        for _ in 0..40 {
+            if version() == 2 {
+                break;
+            }
+            tokio::time::delay_for(Duration::from_millis(50)).await;
+        }
+        assert_eq!(version(), 2);
+    }
+
+    #[test]
+    fn unchanged_content_does_not_publish() {
+        let dir = tempfile::tempdir().unwrap();
+        let watcher = watcher(&dir);
+
+        write(&watcher, "[]");
+        assert!(watcher.reload().unwrap());
+        assert!(!watcher.reload().unwrap());
+        assert_eq!(version(&watcher), 1);
+    }
+}
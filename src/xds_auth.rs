@@ -0,0 +1,415 @@
+use std::collections::BTreeSet;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use tokio_rustls::webpki::{DNSNameRef, EndEntityCert};
+use tonic::metadata::{MetadataMap, MetadataValue};
+use tonic::{Interceptor, Request, Status};
+
+use crate::configuration;
+use crate::interpolation::SecretValue;
+use crate::protobuf::envoy::config::core::v3::Node;
+use crate::service;
+use crate::snapshot_cache::SnapshotCache;
+
+// Where the interceptor tells the discovery services who the node is, what
+// the node put there itself being dropped first.
+const AUTHORIZED_METADATA: &str = "x-gateway-ng-authorized-bin";
+
+static UNAUTHENTICATED: AtomicU64 = AtomicU64::new(0);
+static FORBIDDEN: AtomicU64 = AtomicU64::new(0);
+
+/// The discovery requests refused since the controller started, as the
+/// admin snapshot tells them.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct Rejections {
+    pub xds_unauthenticated_total: u64,
+    pub xds_forbidden_node_group_total: u64,
+}
+
+pub fn stats() -> Rejections {
+    Rejections {
+        xds_unauthenticated_total: UNAUTHENTICATED.load(Ordering::Relaxed),
+        xds_forbidden_node_group_total: FORBIDDEN.load(Ordering::Relaxed),
+    }
+}
+
+/// An identity of the `--xds-auth-config` file: the nodes presenting its
+/// token as a bearer one, or a client certificate valid for one of its DNS
+/// names, get the resources of its node groups.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+struct IdentityConfig {
+    name: std::string::String,
+    #[serde(default)]
+    token: Option<SecretValue>,
+    #[serde(default)]
+    dns_names: Vec<std::string::String>,
+    // every node group when unset
+    #[serde(default)]
+    node_groups: Option<BTreeSet<std::string::String>>,
+    // the nodes are of it whatever their metadata says, of the default
+    // tenant when unset
+    #[serde(default)]
+    tenant: Option<std::string::String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct AuthConfig {
+    identities: Vec<IdentityConfig>,
+}
+
+#[derive(Debug)]
+struct Identity {
+    name: std::string::String,
+    token: Option<std::string::String>,
+    dns_names: Vec<std::string::String>,
+    node_groups: Option<BTreeSet<std::string::String>>,
+    tenant: Option<std::string::String>,
+}
+
+/// Who may stream discovery and which node groups each may get, anyone
+/// getting any of them unless identities are configured.
+#[derive(Debug, Clone, Default)]
+pub struct XdsAuth {
+    identities: Option<Arc<Vec<Identity>>>,
+}
+
+impl XdsAuth {
+    /// Read the identities of the file at `path`, in YAML or JSON, their
+    /// tokens out of the environment when they name a variable. Those told
+    /// by their DNS names need the client certificates to be verified,
+    /// `mutual_tls`.
+    pub fn load(path: &Path, mutual_tls: bool) -> Result<XdsAuth> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("cannot read {}", path.display()))?;
+        let config: AuthConfig = serde_yaml::from_str(&content)
+            .with_context(|| format!("invalid xDS auth config {}", path.display()))?;
+        let mut names = BTreeSet::new();
+        let identities = config
+            .identities
+            .into_iter()
+            .map(|identity| {
+                if !names.insert(identity.name.clone()) {
+                    return Err(anyhow!("identity '{}' is listed twice", identity.name));
+                }
+                if identity.token.is_none() && identity.dns_names.is_empty() {
+                    return Err(anyhow!(
+                        "identity '{}' has neither a token nor DNS names",
+                        identity.name
+                    ));
+                }
+                if !identity.dns_names.is_empty() && !mutual_tls {
+                    return Err(anyhow!(
+                        "identity '{}' is told by its certificate, which needs --tls-client-ca",
+                        identity.name
+                    ));
+                }
+                if let Some(name) = identity
+                    .dns_names
+                    .iter()
+                    .find(|name| DNSNameRef::try_from_ascii_str(name).is_err())
+                {
+                    return Err(anyhow!(
+                        "'{}' of identity '{}' is not a DNS name",
+                        name,
+                        identity.name
+                    ));
+                }
+                if let Some(Err(e)) = identity.tenant.as_deref().map(service::check_tenant) {
+                    return Err(anyhow!("tenant of identity '{}' {}", identity.name, e));
+                }
+                let token = identity
+                    .token
+                    .as_ref()
+                    .map(SecretValue::resolve)
+                    .transpose()
+                    .with_context(|| format!("no token for identity '{}'", identity.name))?;
+                if token
+                    .as_deref()
+                    .is_some_and(|token| token.trim().is_empty())
+                {
+                    return Err(anyhow!(
+                        "the token of identity '{}' is empty",
+                        identity.name
+                    ));
+                }
+                Ok(Identity {
+                    name: identity.name,
+                    token,
+                    dns_names: identity.dns_names,
+                    node_groups: identity.node_groups,
+                    tenant: identity.tenant,
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(XdsAuth {
+            identities: Some(Arc::new(identities)),
+        })
+    }
+
+    // The identity presenting `token`, or else a client certificate valid
+    // for one of its names, `certs` having been verified by the handshake.
+    // An empty token is none of them.
+    fn identify(
+        &self,
+        token: Option<&str>,
+        certs: Option<&[tonic::transport::Certificate]>,
+    ) -> Option<&Identity> {
+        let identities = self.identities.as_ref()?;
+        if let Some(token) = token {
+            if token.is_empty() {
+                return None;
+            }
+            return identities.iter().find(|identity| {
+                identity.token.as_ref().is_some_and(|expected| {
+                    ring::constant_time::verify_slices_are_equal(
+                        expected.as_bytes(),
+                        token.as_bytes(),
+                    )
+                    .is_ok()
+                })
+            });
+        }
+        // the first certificate is the one of the node, the others those of
+        // its chain
+        let cert = certs?.first()?;
+        let cert = EndEntityCert::from(cert.get_ref()).ok()?;
+        identities.iter().find(|identity| {
+            identity.dns_names.iter().any(|name| {
+                DNSNameRef::try_from_ascii_str(name)
+                    .is_ok_and(|name| cert.verify_is_valid_for_dns_name(name).is_ok())
+            })
+        })
+    }
+
+    /// What the node presenting `authorization`, as the header of the same
+    /// name, and `certs` may get. Nodes that are none of the identities are
+    /// refused, and counted, `peer` telling them apart in the logs.
+    // the statuses are those of tonic, large as they are
+    #[allow(clippy::result_large_err)]
+    pub fn authorize(
+        &self,
+        authorization: Option<&str>,
+        certs: Option<&[tonic::transport::Certificate]>,
+        peer: &str,
+    ) -> Result<Authorized, Status> {
+        if self.identities.is_none() {
+            return Ok(Authorized::default());
+        }
+        let token = authorization.map(|value| value.strip_prefix("Bearer ").unwrap_or(value));
+        match self.identify(token, certs) {
+            Some(identity) => Ok(Authorized {
+                identity: Some(identity.name.clone()),
+                node_groups: identity.node_groups.clone(),
+                tenant: Some(
+                    identity
+                        .tenant
+                        .clone()
+                        .unwrap_or_else(|| service::DEFAULT_TENANT.to_string()),
+                ),
+            }),
+            None => {
+                let total = UNAUTHENTICATED.fetch_add(1, Ordering::Relaxed) + 1;
+                tracing::warn!(
+                    peer,
+                    token = token.is_some(),
+                    certificate = certs.is_some_and(|certs| !certs.is_empty()),
+                    xds_unauthenticated_total = total,
+                    "Refused discovery to an unknown node"
+                );
+                Err(Status::unauthenticated(
+                    "a known token or client certificate is needed",
+                ))
+            }
+        }
+    }
+
+    /// The interceptor of the discovery services, refusing the unknown
+    /// nodes before any of their requests is handled, and telling the
+    /// services what the others may get.
+    #[allow(clippy::result_large_err)]
+    pub fn interceptor(&self) -> Interceptor {
+        let auth = self.clone();
+        Interceptor::new(move |request| auth.intercept(request))
+    }
+
+    #[allow(clippy::result_large_err)]
+    fn intercept(&self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        request.metadata_mut().remove_bin(AUTHORIZED_METADATA);
+        let peer = request
+            .remote_addr()
+            .map(|addr| addr.to_string())
+            .unwrap_or_else(|| "unknown peer".to_string());
+        let certs = request.peer_certs();
+        let authorization = request
+            .metadata()
+            .get("authorization")
+            .and_then(|value| value.to_str().ok());
+        let authorized =
+            self.authorize(authorization, certs.as_deref().map(Vec::as_slice), &peer)?;
+        if authorized.identity.is_some() {
+            let value = serde_json::to_vec(&authorized).expect("identities serialize");
+            request
+                .metadata_mut()
+                .insert_bin(AUTHORIZED_METADATA, MetadataValue::from_bytes(&value));
+        }
+        Ok(request)
+    }
+}
+
+/// What a node streaming discovery may get, as its identity allows.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Authorized {
+    // None when the nodes aren't authenticated
+    pub identity: Option<std::string::String>,
+    // every node group when unset
+    pub node_groups: Option<BTreeSet<std::string::String>>,
+    // the one the node tells when the nodes aren't authenticated
+    pub tenant: Option<std::string::String>,
+}
+
+impl Authorized {
+    /// What the interceptor let the node of `metadata` get.
+    pub fn of(metadata: &MetadataMap) -> Authorized {
+        metadata
+            .get_bin(AUTHORIZED_METADATA)
+            .and_then(|value| value.to_bytes().ok())
+            .and_then(|value| serde_json::from_slice(&value).ok())
+            .unwrap_or_default()
+    }
+
+    /// The key of the snapshot `node` gets, the tenant of its identity
+    /// replacing the one of its metadata, so that the node groups of an
+    /// identity are those of its own tenant, or why it gets none: the
+    /// refusals are logged and counted.
+    #[allow(clippy::result_large_err)]
+    pub fn snapshot_key(
+        &self,
+        cache: &dyn SnapshotCache,
+        node: Option<&Node>,
+    ) -> Result<std::string::String, Status> {
+        let key = match self.tenant {
+            Some(ref tenant) => {
+                let mut node = node.cloned().unwrap_or_default();
+                let fields = &mut node.metadata.get_or_insert_with(Default::default).fields;
+                fields.insert(
+                    configuration::TENANT_METADATA.to_string(),
+                    prost_types::Value {
+                        kind: Some(prost_types::value::Kind::StringValue(tenant.clone())),
+                    },
+                );
+                cache.node_group(Some(&node))
+            }
+            None => cache.node_group(node),
+        };
+        let (_, group) = configuration::split_key(&key);
+        match self.node_groups {
+            Some(ref groups) if !groups.contains(group) => {
+                let identity = self.identity.as_deref().unwrap_or("");
+                let node = node.map(|node| node.id.as_str()).unwrap_or("");
+                let total = FORBIDDEN.fetch_add(1, Ordering::Relaxed) + 1;
+                tracing::warn!(
+                    identity,
+                    node,
+                    node_group = group,
+                    xds_forbidden_node_group_total = total,
+                    "Refused the resources of another node group"
+                );
+                Err(Status::permission_denied(format!(
+                    "{} may not get the resources of node group {}",
+                    identity, group
+                )))
+            }
+            _ => Ok(key),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tonic::transport::Certificate;
+    use tonic::Code;
+
+    fn load(content: &str, mutual_tls: bool) -> Result<XdsAuth> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("auth.yaml");
+        std::fs::write(&path, content).unwrap();
+        XdsAuth::load(&path, mutual_tls)
+    }
+
+    #[test]
+    fn identities_are_checked() {
+        for (content, error) in &[
+            (
+                "identities: [{name: a, token: x}, {name: a, token: y}]",
+                "identity 'a' is listed twice",
+            ),
+            (
+                "identities: [{name: a}]",
+                "identity 'a' has neither a token nor DNS names",
+            ),
+            (
+                "identities: [{name: a, dns_names: [envoy.internal]}]",
+                "identity 'a' is told by its certificate, which needs --tls-client-ca",
+            ),
+            (
+                "identities: [{name: a, token: ''}]",
+                "the token of identity 'a' is empty",
+            ),
+            (
+                "identities: [{name: a, token: '${GATEWAY_SECRET_XDS_AUTH_TEST_UNSET}'}]",
+                "no token for identity 'a': GATEWAY_SECRET_XDS_AUTH_TEST_UNSET is not set: environment variable not found",
+            ),
+        ] {
+            assert_eq!(format!("{:#}", load(content, false).unwrap_err()), *error);
+        }
+        let error = load("identities: [{name: a, dns_names: ['not a name']}]", true).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "'not a name' of identity 'a' is not a DNS name"
+        );
+    }
+
+    #[test]
+    fn nodes_are_told_by_their_token_or_certificate() {
+        let auth = load(
+            "identities: [{name: edge, token: s3cr3t, node_groups: [edge]}, \
+             {name: mesh, dns_names: [one.envoy.internal]}]",
+            true,
+        )
+        .unwrap();
+        let identity = |authorization: Option<&str>, certs: Option<&[Certificate]>| {
+            auth.authorize(authorization, certs, "127.0.0.1:1")
+                .map(|authorized| authorized.identity.unwrap())
+                .map_err(|status| status.code())
+        };
+        assert_eq!(
+            identity(Some("Bearer s3cr3t"), None),
+            Ok("edge".to_string())
+        );
+        for token in &["Bearer ", "", "Bearer other"] {
+            assert_eq!(identity(Some(token), None), Err(Code::Unauthenticated));
+        }
+
+        // the certificates of the handshake, DER encoded
+        let cert = |name: &str| {
+            let path = format!("{}/testdata/tls/{}.pem", env!("CARGO_MANIFEST_DIR"), name);
+            let mut pem = std::io::BufReader::new(std::fs::File::open(path).unwrap());
+            let der = tokio_rustls::rustls::internal::pemfile::certs(&mut pem).unwrap();
+            Certificate::from_pem(&der[0].0)
+        };
+        let one = [cert("one-client")];
+        assert_eq!(identity(None, Some(&one)), Ok("mesh".to_string()));
+        // an empty token doesn't fall back on the certificate
+        assert_eq!(identity(Some(""), Some(&one)), Err(Code::Unauthenticated));
+        let other = [cert("other-client")];
+        assert_eq!(identity(None, Some(&other)), Err(Code::Unauthenticated));
+        assert_eq!(identity(None, None), Err(Code::Unauthenticated));
+    }
+}
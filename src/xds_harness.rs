@@ -0,0 +1,665 @@
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use prost::Message;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tonic::codec::Streaming;
+use tonic::transport::{Channel, Endpoint, Uri};
+
+use crate::cli::ControllerConfig;
+use crate::configuration::{Config, NODE_GROUP_METADATA, TENANT_METADATA};
+use crate::node_status::NodeStatuses;
+use crate::processor::MasterProcess;
+use crate::protobuf::envoy::config::core::v3::Node;
+use crate::protobuf::envoy::service::cluster::v3::cluster_discovery_service_client::ClusterDiscoveryServiceClient;
+use crate::protobuf::envoy::service::discovery::v3::{DiscoveryRequest, DiscoveryResponse};
+use crate::protobuf::envoy::service::listener::v3::listener_discovery_service_client::ListenerDiscoveryServiceClient;
+use crate::protobuf::google::rpc::Status as RpcStatus;
+use crate::protobuf::grpc::health::v1::health_client::HealthClient;
+use crate::protobuf::grpc::health::v1::HealthCheckRequest;
+use crate::reload::{Outcome, Reloader};
+use crate::shutdown::Shutdown;
+
+// How long a response may take before the stream is deemed idle.
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(5);
+// The initial windows of the streams and connections of Envoy.
+const ENVOY_WINDOW: u32 = 256 * 1024 * 1024;
+
+/// The controller serving discovery on an ephemeral port, reading its
+/// services from a file the harness writes, for tests to go from the
+/// services to the responses Envoy gets without running Envoy.
+pub struct Harness {
+    pub address: std::net::SocketAddr,
+    config: Arc<RwLock<Config>>,
+    statuses: NodeStatuses,
+    reloader: Reloader,
+    shutdown: Shutdown,
+    services: PathBuf,
+    // removed with the harness
+    _dir: tempfile::TempDir,
+}
+
+impl Harness {
+    /// Serve `services`, the controller taking `args` as its command line
+    /// otherwise. The filter digests are left out, the filters not needing
+    /// to exist.
+    pub async fn start(services: serde_json::Value, args: &[&str]) -> Harness {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("services.json");
+        std::fs::write(&path, services.to_string()).unwrap();
+        let path_arg = path.to_string_lossy().into_owned();
+        let command_line = ["gateway-ng-controller", "--services-config", &path_arg];
+        let mut settings =
+            ControllerConfig::from_args(command_line.iter().chain(args.iter())).unwrap();
+        settings.wasm.skip_sha = true;
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let listener = TcpListener::from_std(listener).unwrap();
+        let address = listener.local_addr().unwrap();
+        let mut process = MasterProcess::new(settings);
+        let harness = Harness {
+            address,
+            config: process.config(),
+            statuses: process.statuses(),
+            reloader: process.reloader(),
+            shutdown: process.shutdown(),
+            services: path,
+            _dir: dir,
+        };
+        tokio::spawn(async move { process.serve(listener).await.unwrap() });
+        harness
+    }
+
+    /// Replace the services, returning what reloading them did.
+    pub async fn publish(&self, services: serde_json::Value) -> Outcome {
+        std::fs::write(&self.services, services.to_string()).unwrap();
+        let reloader = self.reloader.clone();
+        tokio::task::spawn_blocking(move || reloader.reload())
+            .await
+            .unwrap()
+    }
+
+    /// Whether `node` acks the `version` of `type_url` resources, waiting
+    /// a while for the ack to be handled.
+    pub async fn acked(&self, node: &str, type_url: &str, version: &str) -> bool {
+        for _ in 0..50 {
+            let acked = self
+                .statuses
+                .get()
+                .get(node)
+                .and_then(|status| status.types.get(type_url))
+                .and_then(|status| status.last_acked_version.clone());
+            if acked.as_deref() == Some(version) {
+                return true;
+            }
+            tokio::time::delay_for(Duration::from_millis(20)).await;
+        }
+        false
+    }
+
+    pub fn config(&self) -> &Arc<RwLock<Config>> {
+        &self.config
+    }
+
+    pub fn statuses(&self) -> &NodeStatuses {
+        &self.statuses
+    }
+
+    pub fn reloader(&self) -> &Reloader {
+        &self.reloader
+    }
+
+    // connected the way the shutdown tests do, from a std stream, with the
+    // windows Envoy opens
+    async fn channel(&self) -> Channel {
+        let address = self.address;
+        Endpoint::from_static("http://localhost")
+            .initial_stream_window_size(ENVOY_WINDOW)
+            .initial_connection_window_size(ENVOY_WINDOW)
+            .connect_with_connector(tower::service_fn(move |_: Uri| async move {
+                let stream = std::net::TcpStream::connect(address)?;
+                stream.set_nonblocking(true)?;
+                TcpStream::from_std(stream)
+            }))
+            .await
+            .unwrap()
+    }
+
+    /// A node of `node_group`, or of the default one, streaming clusters.
+    pub async fn cds(&self, node_id: &str, node_group: Option<&str>) -> XdsClient {
+        self.cds_with_token(node_id, node_group, None)
+            .await
+            .unwrap()
+    }
+
+    /// A node streaming clusters with `token` as its bearer token, unless
+    /// the stream is refused.
+    pub async fn cds_with_token(
+        &self,
+        node_id: &str,
+        node_group: Option<&str>,
+        token: Option<&str>,
+    ) -> Result<XdsClient, tonic::Status> {
+        let (requests, stream) = mpsc::channel(8);
+        let mut request = tonic::Request::new(stream);
+        if let Some(token) = token {
+            let value = format!("Bearer {}", token).parse().unwrap();
+            request.metadata_mut().insert("authorization", value);
+        }
+        let responses = ClusterDiscoveryServiceClient::new(self.channel().await)
+            .stream_clusters(request)
+            .await?
+            .into_inner();
+        Ok(XdsClient::new(
+            node(node_id, node_group),
+            requests,
+            responses,
+        ))
+    }
+
+    /// The health of the server, as a node with `token` as its bearer
+    /// token checks it.
+    pub async fn health(&self, token: Option<&str>) -> Result<i32, tonic::Status> {
+        let mut request = tonic::Request::new(HealthCheckRequest::default());
+        if let Some(token) = token {
+            let value = format!("Bearer {}", token).parse().unwrap();
+            request.metadata_mut().insert("authorization", value);
+        }
+        let response = HealthClient::new(self.channel().await)
+            .check(request)
+            .await?;
+        Ok(response.into_inner().status)
+    }
+
+    /// A node of `node_group`, or of the default one, streaming listeners.
+    pub async fn lds(&self, node_id: &str, node_group: Option<&str>) -> XdsClient {
+        let (requests, stream) = mpsc::channel(8);
+        let responses = ListenerDiscoveryServiceClient::new(self.channel().await)
+            .stream_listeners(stream)
+            .await
+            .unwrap()
+            .into_inner();
+        XdsClient::new(node(node_id, node_group), requests, responses)
+    }
+}
+
+impl Drop for Harness {
+    fn drop(&mut self) {
+        self.shutdown.trigger();
+    }
+}
+
+fn node(id: &str, node_group: Option<&str>) -> Node {
+    let metadata = node_group.map(|group| {
+        let mut fields = std::collections::BTreeMap::new();
+        fields.insert(
+            NODE_GROUP_METADATA.to_string(),
+            prost_types::Value {
+                kind: Some(prost_types::value::Kind::StringValue(group.to_string())),
+            },
+        );
+        prost_types::Struct { fields }
+    });
+    Node {
+        id: id.to_string(),
+        cluster: "test".to_string(),
+        metadata,
+        ..Default::default()
+    }
+}
+
+/// One discovery stream of a fake Envoy node, acking or nacking the
+/// responses the way Envoy does.
+pub struct XdsClient {
+    node: Node,
+    requests: mpsc::Sender<DiscoveryRequest>,
+    responses: Streaming<DiscoveryResponse>,
+    // what the node runs, for its requests to tell
+    version: std::string::String,
+}
+
+impl XdsClient {
+    fn new(
+        node: Node,
+        requests: mpsc::Sender<DiscoveryRequest>,
+        responses: Streaming<DiscoveryResponse>,
+    ) -> XdsClient {
+        XdsClient {
+            node,
+            requests,
+            responses,
+            version: std::string::String::new(),
+        }
+    }
+
+    /// The node telling it is of `tenant` in its metadata.
+    pub fn of_tenant(mut self, tenant: &str) -> XdsClient {
+        let metadata = self.node.metadata.get_or_insert_with(Default::default);
+        metadata.fields.insert(
+            TENANT_METADATA.to_string(),
+            prost_types::Value {
+                kind: Some(prost_types::value::Kind::StringValue(tenant.to_string())),
+            },
+        );
+        self
+    }
+
+    async fn send(&mut self, request: DiscoveryRequest) {
+        self.requests.send(request).await.unwrap();
+    }
+
+    /// The first request of the stream, for every resource.
+    pub async fn subscribe(&mut self) {
+        let request = DiscoveryRequest {
+            node: Some(self.node.clone()),
+            ..Default::default()
+        };
+        self.send(request).await;
+    }
+
+    /// The next response, `None` when none comes in a while.
+    pub async fn next(&mut self) -> Option<DiscoveryResponse> {
+        self.next_within(RESPONSE_TIMEOUT).await
+    }
+
+    /// The next response, unless none comes within `timeout`.
+    pub async fn next_within(&mut self, timeout: Duration) -> Option<DiscoveryResponse> {
+        tokio::time::timeout(timeout, self.responses.message())
+            .await
+            .ok()
+            .map(|response| response.unwrap().expect("the stream was closed"))
+    }
+
+    /// The status the stream ends with instead of a response, if any.
+    pub async fn refusal(&mut self) -> Option<tonic::Status> {
+        tokio::time::timeout(RESPONSE_TIMEOUT, self.responses.message())
+            .await
+            .ok()?
+            .err()
+    }
+
+    /// Accept `response`, running its version from now on.
+    pub async fn ack(&mut self, response: &DiscoveryResponse) {
+        self.version = response.version_info.clone();
+        let request = DiscoveryRequest {
+            version_info: self.version.clone(),
+            response_nonce: response.nonce.clone(),
+            type_url: response.type_url.clone(),
+            ..Default::default()
+        };
+        self.send(request).await;
+    }
+
+    /// Reject `response` with `message`, still running the version before.
+    pub async fn nack(&mut self, response: &DiscoveryResponse, message: &str) {
+        let request = DiscoveryRequest {
+            version_info: self.version.clone(),
+            response_nonce: response.nonce.clone(),
+            type_url: response.type_url.clone(),
+            error_detail: Some(RpcStatus {
+                code: tonic::Code::InvalidArgument as i32,
+                message: message.to_string(),
+                details: Vec::new(),
+            }),
+            ..Default::default()
+        };
+        self.send(request).await;
+    }
+}
+
+/// The resources of `response`, decoded.
+pub fn resources<M: Message + Default>(response: &DiscoveryResponse) -> Vec<M> {
+    response
+        .resources
+        .iter()
+        .map(|any| M::decode(any.value.as_slice()).unwrap())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::envoy_helpers::{CLUSTER_TYPE_URL, LISTENER_TYPE_URL};
+    use crate::protobuf::envoy::config::cluster::v3::Cluster;
+    use crate::protobuf::envoy::config::listener::v3::Listener;
+
+    fn services(ids: &[u32]) -> serde_json::Value {
+        ids.iter()
+            .map(|id| {
+                serde_json::json!({
+                    "id": id,
+                    "hosts": [format!("{}.app", id)],
+                    "policies": [],
+                    "target_domain": format!("http://{}.app:80", id),
+                    "proxy_rules": [],
+                })
+            })
+            .collect()
+    }
+
+    fn names<M: Message + Default>(
+        response: &DiscoveryResponse,
+        name: fn(&M) -> &str,
+    ) -> Vec<std::string::String> {
+        let mut names: Vec<_> = resources(response)
+            .iter()
+            .map(|resource| name(resource).to_string())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[tokio::test]
+    async fn nodes_sync_and_get_changes_pushed() {
+        let harness = Harness::start(services(&[1]), &["--publish-window", "0"]).await;
+        let mut cds = harness.cds("envoy-1", None).await;
+        let mut lds = harness.lds("envoy-1", None).await;
+        cds.subscribe().await;
+        lds.subscribe().await;
+
+        let clusters = cds.next().await.unwrap();
+        assert_eq!(clusters.type_url, CLUSTER_TYPE_URL);
+        assert_eq!(
+            names(&clusters, |cluster: &Cluster| &cluster.name),
+            ["Cluster::service::1"]
+        );
+        let listeners = lds.next().await.unwrap();
+        assert_eq!(listeners.type_url, LISTENER_TYPE_URL);
+        assert_eq!(
+            names(&listeners, |listener: &Listener| &listener.name),
+            ["service 1"]
+        );
+        cds.ack(&clusters).await;
+        lds.ack(&listeners).await;
+
+        // acked, nothing more is sent until the services change
+        assert!(cds.next_within(Duration::from_millis(200)).await.is_none());
+        assert!(matches!(
+            harness.publish(services(&[1, 2])).await,
+            Outcome::Published(_)
+        ));
+        let clusters = cds.next().await.unwrap();
+        assert_eq!(
+            names(&clusters, |cluster: &Cluster| &cluster.name),
+            ["Cluster::service::1", "Cluster::service::2"]
+        );
+        let listeners = lds.next().await.unwrap();
+        assert_eq!(
+            names(&listeners, |listener: &Listener| &listener.name),
+            ["service 1", "service 2"]
+        );
+        cds.ack(&clusters).await;
+
+        let acked = harness
+            .acked("envoy-1", CLUSTER_TYPE_URL, &clusters.version_info)
+            .await;
+        assert!(acked, "the ack was not recorded");
+    }
+
+    #[tokio::test]
+    async fn nacked_versions_are_not_sent_again() {
+        let harness = Harness::start(services(&[1]), &["--publish-window", "0"]).await;
+        let mut cds = harness.cds("envoy-1", None).await;
+        cds.subscribe().await;
+        let first = cds.next().await.unwrap();
+        cds.ack(&first).await;
+        assert!(
+            harness
+                .acked("envoy-1", CLUSTER_TYPE_URL, &first.version_info)
+                .await
+        );
+
+        harness.publish(services(&[1, 2])).await;
+        let second = cds.next().await.unwrap();
+        assert_ne!(second.version_info, first.version_info);
+        cds.nack(&second, "cluster rejected").await;
+
+        // the node keeps running the first version
+        assert!(cds.next_within(Duration::from_millis(200)).await.is_none());
+        let status = &harness.statuses().get()["envoy-1"].types[CLUSTER_TYPE_URL];
+        assert_eq!(
+            status.last_acked_version.as_deref(),
+            Some(first.version_info.as_str())
+        );
+        let nack = status.last_nack.as_ref().unwrap();
+        assert_eq!(nack.version, second.version_info);
+        assert_eq!(nack.message, "cluster rejected");
+    }
+
+    #[tokio::test]
+    async fn nacked_services_are_rolled_back() {
+        let harness = Harness::start(
+            services(&[1]),
+            &["--publish-window", "0", "--rollback-on-nack"],
+        )
+        .await;
+        let mut cds = harness.cds("envoy-1", None).await;
+        cds.subscribe().await;
+        let first = cds.next().await.unwrap();
+        cds.ack(&first).await;
+        assert!(
+            harness
+                .acked("envoy-1", CLUSTER_TYPE_URL, &first.version_info)
+                .await
+        );
+
+        harness.publish(services(&[1, 2])).await;
+        let second = cds.next().await.unwrap();
+        cds.nack(&second, "cluster rejected").await;
+
+        // the service that came with the rejected version is held back
+        let rolled_back = cds.next().await.unwrap();
+        assert_eq!(
+            names(&rolled_back, |cluster: &Cluster| &cluster.name),
+            ["Cluster::service::1"]
+        );
+        assert!(harness.config().read().unwrap().get_version() > 2);
+    }
+
+    #[tokio::test]
+    async fn panicking_sources_leave_discovery_serving() {
+        let harness = Harness::start(services(&[1]), &["--publish-window", "0"]).await;
+        let mut cds = harness.cds("envoy-1", None).await;
+        cds.subscribe().await;
+        let first = cds.next().await.unwrap();
+        cds.ack(&first).await;
+
+        let before = crate::panics::total();
+        harness
+            .reloader()
+            .install(Arc::clone(harness.config()), || panic!("mock source"));
+        let reloader = harness.reloader().clone();
+        let outcome = tokio::task::spawn_blocking(move || reloader.reload())
+            .await
+            .unwrap();
+        assert_eq!(
+            outcome,
+            Outcome::Rejected(vec!["reload panicked: mock source".to_string()])
+        );
+        assert!(crate::panics::total() > before);
+
+        // the last snapshot keeps being served, to new nodes as well
+        let mut other = harness.cds("envoy-2", None).await;
+        other.subscribe().await;
+        let clusters = other.next().await.unwrap();
+        assert_eq!(clusters.version_info, first.version_info);
+        assert_eq!(
+            names(&clusters, |cluster: &Cluster| &cluster.name),
+            ["Cluster::service::1"]
+        );
+        assert!(cds.next_within(Duration::from_millis(200)).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn huge_snapshots_reach_the_nodes() {
+        let rules: Vec<_> = (0..5_000)
+            .map(|rule| {
+                serde_json::json!({
+                    "pattern": format!("/{}/{}", "resource".repeat(100), rule),
+                    "http_method": "GET",
+                    "metric_system_name": "hits",
+                    "delta": 1,
+                })
+            })
+            .collect();
+        let mut huge = services(&[1]);
+        huge[0]["proxy_rules"] = rules.into();
+        let args = [
+            "--publish-window",
+            "0",
+            "--xds-stream-window",
+            "8388608",
+            "--xds-connection-window",
+            "33554432",
+        ];
+        let harness = Harness::start(huge, &args).await;
+        let mut lds = harness.lds("envoy-1", None).await;
+        lds.subscribe().await;
+        let listeners = lds.next().await.unwrap();
+        assert_eq!(
+            names(&listeners, |listener: &Listener| &listener.name),
+            ["service 1"]
+        );
+        // beyond the 4 MiB gRPC clients and servers often cap messages at
+        assert!(listeners.encoded_len() > 4 * 1024 * 1024);
+        lds.ack(&listeners).await;
+        assert!(
+            harness
+                .acked("envoy-1", LISTENER_TYPE_URL, &listeners.version_info)
+                .await
+        );
+    }
+
+    #[tokio::test]
+    async fn nodes_only_get_the_groups_of_their_identity() {
+        let dir = tempfile::tempdir().unwrap();
+        let auth = dir.path().join("auth.yaml");
+        std::fs::write(
+            &auth,
+            "identities:\n\
+             - {name: public-fleet, token: public-s3cr3t, node_groups: [public]}\n\
+             - {name: internal-fleet, token: internal-s3cr3t}\n",
+        )
+        .unwrap();
+        let auth = auth.to_string_lossy().into_owned();
+        let args = ["--publish-window", "0", "--xds-auth-config", &auth];
+        let mut grouped = services(&[1, 2]);
+        grouped[0]["node_groups"] = serde_json::json!(["public"]);
+        grouped[1]["node_groups"] = serde_json::json!(["internal"]);
+        let harness = Harness::start(grouped, &args).await;
+        let before = crate::xds_auth::stats();
+
+        let mut public = harness
+            .cds_with_token("envoy-1", Some("public"), Some("public-s3cr3t"))
+            .await
+            .unwrap();
+        public.subscribe().await;
+        let clusters = public.next().await.unwrap();
+        assert_eq!(
+            names(&clusters, |cluster: &Cluster| &cluster.name),
+            ["Cluster::service::1"]
+        );
+        let mut internal = harness
+            .cds_with_token("envoy-2", Some("internal"), Some("internal-s3cr3t"))
+            .await
+            .unwrap();
+        internal.subscribe().await;
+        let clusters = internal.next().await.unwrap();
+        assert_eq!(
+            names(&clusters, |cluster: &Cluster| &cluster.name),
+            ["Cluster::service::2"]
+        );
+
+        for token in &[None, Some("guess")] {
+            let refused = harness.cds_with_token("envoy-3", None, *token).await;
+            assert_eq!(refused.err().unwrap().code(), tonic::Code::Unauthenticated);
+            let refused = harness.health(*token).await;
+            assert_eq!(refused.unwrap_err().code(), tonic::Code::Unauthenticated);
+        }
+        assert!(harness.health(Some("public-s3cr3t")).await.is_ok());
+
+        // a public node asking for the snapshot of the internal group
+        let mut intruder = harness
+            .cds_with_token("envoy-4", Some("internal"), Some("public-s3cr3t"))
+            .await
+            .unwrap();
+        intruder.subscribe().await;
+        let refusal = intruder.refusal().await.unwrap();
+        assert_eq!(refusal.code(), tonic::Code::PermissionDenied);
+        assert_eq!(
+            refusal.message(),
+            "public-fleet may not get the resources of node group internal"
+        );
+
+        // nor one telling it is of another tenant, the identity having none
+        let mut elsewhere = harness
+            .cds_with_token("envoy-5", Some("public"), Some("public-s3cr3t"))
+            .await
+            .unwrap()
+            .of_tenant("team-b");
+        elsewhere.subscribe().await;
+        let clusters = elsewhere.next().await.unwrap();
+        assert_eq!(
+            names(&clusters, |cluster: &Cluster| &cluster.name),
+            ["Cluster::service::1"]
+        );
+
+        let after = crate::xds_auth::stats();
+        assert!(after.xds_unauthenticated_total >= before.xds_unauthenticated_total + 2);
+        assert!(after.xds_forbidden_node_group_total > before.xds_forbidden_node_group_total);
+    }
+
+    #[tokio::test]
+    async fn tenants_only_get_their_services() {
+        let mut tenants = services(&[1, 2]);
+        tenants[0]["tenant"] = serde_json::json!("team-a");
+        tenants[1]["tenant"] = serde_json::json!("team-b");
+        let harness = Harness::start(tenants.clone(), &["--publish-window", "0"]).await;
+
+        // the same id being two nodes, one of each tenant
+        let mut team_a = harness.cds("envoy", None).await.of_tenant("team-a");
+        let mut team_b = harness.cds("envoy", None).await.of_tenant("team-b");
+        team_a.subscribe().await;
+        team_b.subscribe().await;
+        let clusters = team_a.next().await.unwrap();
+        assert_eq!(
+            names(&clusters, |cluster: &Cluster| &cluster.name),
+            ["Cluster::service::1"]
+        );
+        team_a.ack(&clusters).await;
+        let clusters = team_b.next().await.unwrap();
+        assert_eq!(
+            names(&clusters, |cluster: &Cluster| &cluster.name),
+            ["Cluster::service::2"]
+        );
+        team_b.ack(&clusters).await;
+        let team_b_version = clusters.version_info;
+        let statuses = harness.statuses().get();
+        assert_eq!(
+            statuses.keys().collect::<Vec<_>>(),
+            ["team-a/envoy", "team-b/envoy"]
+        );
+
+        // a change of team-a is none of the business of team-b
+        tenants[0]["target_domain"] = serde_json::json!("http://1.app:8080");
+        tenants[0]["hosts"] = serde_json::json!(["1.app", "one.app"]);
+        assert!(matches!(
+            harness.publish(tenants).await,
+            Outcome::Published(_)
+        ));
+        assert!(team_a.next().await.is_some());
+        assert!(team_b
+            .next_within(Duration::from_millis(200))
+            .await
+            .is_none());
+        let config = harness.config().read().unwrap();
+        let snapshot = &config.group_snapshots()["team-b/default"];
+        assert_eq!(snapshot.type_version(CLUSTER_TYPE_URL), team_b_version);
+        assert!(config.group_snapshots()["default"]
+            .resources(CLUSTER_TYPE_URL)
+            .is_none_or(|clusters| clusters.is_empty()));
+    }
+}
@@ -1,34 +1,114 @@
 use chrono::{DateTime, Utc};
+use filter_config::access_log;
+use filter_config::pending_reports::{self, Usage};
+use filter_config::{Decision, LimitKey, SharedStore, REQUEST_ID_HEADER};
 use log::info;
 use proxy_wasm::traits::*;
 use proxy_wasm::types::*;
+use std::collections::BTreeMap;
 use std::time::Duration;
 
 mod config;
 
 const AUTH_BACKEND: &str = "httpbin";
+const AUTHREP_PATH: &str = "/headers";
+const AUTHORIZE_PATH: &str = "/headers";
+const REPORT_PATH: &str = "/anything";
+// How long the VM being shut down waits for its last report.
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(1);
 
 #[no_mangle]
 pub fn _start() {
     proxy_wasm::set_log_level(LogLevel::Trace);
     proxy_wasm::set_http_context(|context_id, _| -> Box<dyn HttpContext> {
-        Box::new(HttpHeaders { context_id })
+        Box::new(HttpHeaders {
+            context_id,
+            metrics: None,
+            auth_call: None,
+            report_call: None,
+            request_id: std::string::String::new(),
+            credential_type: access_log::NO_CREDENTIAL,
+        })
+    });
+    proxy_wasm::set_root_context(|_| -> Box<dyn RootContext> {
+        Box::new(ConfigContext::default())
     });
-    proxy_wasm::set_root_context(|_| -> Box<dyn RootContext> { Box::new(ConfigContext) });
 }
 
 struct HttpHeaders {
     context_id: u32,
+    // Metrics matched in the request phase, kept for the upstream header and
+    // for reporting in the response phase.
+    metrics: Option<BTreeMap<std::string::String, u32>>,
+    // Token of the call the request is paused on; report calls don't resume.
+    auth_call: Option<u32>,
+    // Token and usage of the report call in flight, the usage being kept
+    // for the next report if the call fails.
+    report_call: Option<(u32, Usage)>,
+    // Id of the request, for every log line of it to tell it.
+    request_id: std::string::String,
+    // What the request is told apart by, for the access logs.
+    credential_type: &'static str,
 }
 
-struct ConfigContext;
+#[derive(Default)]
+struct ConfigContext {
+    // Token and usage of the last report of the VM being shut down.
+    flush_call: Option<(u32, Usage)>,
+}
 
-impl Context for ConfigContext {}
+impl Context for ConfigContext {
+    // The VM is shut down, on a reload of its configuration as well: the
+    // usage kept is reported a last time rather than left behind.
+    fn on_done(&mut self) -> bool {
+        let service_id = config::get_config().id;
+        let usage = pending_reports::take(&SharedData::default(), service_id);
+        if usage.is_empty() {
+            return true;
+        }
+        let body = serde_json::to_string(&usage).unwrap();
+        match backend_call(self, REPORT_PATH, body.as_str(), FLUSH_TIMEOUT) {
+            Ok(token) => {
+                log::info!("Flushing the usage of service {}", service_id);
+                self.flush_call = Some((token, usage));
+                false
+            }
+            Err(e) => {
+                log::error!("Cannot flush the usage, err='{:?}'", e);
+                pending_reports::lose(&SharedData::default(), service_id, &usage);
+                true
+            }
+        }
+    }
+
+    fn on_http_call_response(&mut self, token_id: u32, _: usize, _: usize, _: usize) {
+        if let Some((token, usage)) = self.flush_call.take() {
+            if token == token_id && !succeeded(&self.get_http_call_response_headers()) {
+                pending_reports::lose(&SharedData::default(), config::get_config().id, &usage);
+            }
+            self.done();
+        }
+    }
+}
 
 impl RootContext for ConfigContext {
     fn on_vm_start(&mut self, _: usize) -> bool {
-        let config = self.get_configuration();
-        config::import_config(std::str::from_utf8(&config.unwrap()).unwrap());
+        let config = match self.get_configuration() {
+            Some(config) => config,
+            None => {
+                log::error!("No configuration found for the filter");
+                return false;
+            }
+        };
+        // the default configuration is of no service
+        let previous = Some(config::get_config().id).filter(|id| *id != 0);
+        match config::import_config(&config) {
+            Ok(service) => pending_reports::reloaded(&SharedData::default(), previous, service.id),
+            Err(e) => {
+                log::error!("Cannot parse the config, err='{}'", e);
+                return false;
+            }
+        }
         self.set_tick_period(Duration::from_secs(20));
         true
     }
@@ -39,23 +119,111 @@ impl RootContext for ConfigContext {
     }
 }
 
+// Whether the backend answered the call with the `headers` successfully,
+// the calls that timed out getting none.
+fn succeeded(headers: &[(std::string::String, std::string::String)]) -> bool {
+    headers
+        .iter()
+        .any(|(name, value)| name.as_str() == ":status" && value.starts_with('2'))
+}
+
+fn backend_call(
+    context: &impl Context,
+    path: &str,
+    body: &str,
+    timeout: Duration,
+) -> Result<u32, Status> {
+    // @TODO move this headers to a proper ones.
+    context.dispatch_http_call(
+        AUTH_BACKEND,
+        vec![
+            (":method", "GET"),
+            (":path", path),
+            (":authority", "httpbin.org"),
+        ],
+        Some(body.as_bytes()),
+        Vec::new(),
+        timeout,
+    )
+}
+
 impl Context for HttpHeaders {
-    fn on_http_call_response(&mut self, _: u32, _: usize, _: usize, _: usize) {
+    fn on_http_call_response(&mut self, token_id: u32, _: usize, _: usize, _: usize) {
+        if self.auth_call != Some(token_id) {
+            log::debug!(
+                "Usage report call {} completed, request_id='{}'",
+                token_id,
+                self.request_id
+            );
+            if let Some((_, usage)) = self
+                .report_call
+                .take()
+                .filter(|(token, _)| *token == token_id)
+            {
+                if !succeeded(&self.get_http_call_response_headers()) {
+                    log::warn!(
+                        "Usage report failed, keeping it for the next one, request_id='{}'",
+                        self.request_id
+                    );
+                    pending_reports::keep(&self.shared_data(), config::get_config().id, &usage);
+                }
+            }
+            return;
+        }
+
         let headers = self.get_http_call_response_headers();
         for (name, value) in &headers {
             if name.as_str() == ":status" && value.as_str() == "200" {
-                log::info!("Access granted.");
+                log::info!("Access granted, request_id='{}'", self.request_id);
+                self.tell_decision(true);
+                self.forward_metrics();
                 self.resume_http_request();
                 return;
             }
         }
 
-        self.send_http_response(403, vec![], Some(b"Access forbidden.\n"));
-        return;
+        log::info!("Access forbidden, request_id='{}'", self.request_id);
+        self.tell_decision(false);
+        let body = self.denial_body("Access forbidden.\n");
+        self.send_http_response(403, vec![], Some(body.as_bytes()));
     }
 }
 
 impl HttpHeaders {
+    fn shared_data(&self) -> SharedData<'_> {
+        SharedData {
+            request_id: Some(&self.request_id),
+        }
+    }
+
+    // The body of a denial, telling the id of the request if the service
+    // wants it to.
+    fn denial_body(&self, body: &str) -> std::string::String {
+        if config::get_config().request_id_in_denials {
+            filter_config::denial_body(body, &self.request_id)
+        } else {
+            body.to_string()
+        }
+    }
+
+    // Tell the access logs of Envoy what was decided about the request.
+    fn tell_decision(&self, allowed: bool) {
+        let properties =
+            access_log::properties(allowed, self.metrics.as_ref(), self.credential_type);
+        for (name, value) in properties {
+            if let Err(e) =
+                proxy_wasm::hostcalls::set_property(vec![name.as_str()], Some(value.as_bytes()))
+            {
+                log::warn!(
+                    "Cannot set property '{}', err='{:?}', request_id='{}'",
+                    name,
+                    e,
+                    self.request_id
+                );
+            }
+        }
+    }
+
     fn get_method(&self) -> Option<std::string::String> {
         return self.get_http_request_header(":method");
     }
@@ -64,38 +232,185 @@ impl HttpHeaders {
         return self.get_http_request_header(":path");
     }
 
-    fn authrep(&self, metrics: std::string::String) {
-        // @TODO move this headers to a proper ones.
-        self.dispatch_http_call(
-            AUTH_BACKEND,
-            vec![
-                (":method", "GET"),
-                (":path", "/headers"),
-                (":authority", "httpbin.org"),
-            ],
-            Some(metrics.as_bytes()),
-            Vec::new(),
-            Duration::from_secs(5),
-        )
-        .unwrap();
+    // Returns the key this request is throttled by, if it carries one.
+    fn local_limits_key(&self, key: &LimitKey) -> Option<std::string::String> {
+        match key {
+            LimitKey::Credential { header } => self.get_http_request_header(header),
+            LimitKey::ClientIp => {
+                let address = self.get_property(vec!["source", "address"])?;
+                let address = std::string::String::from_utf8(address).ok()?;
+                // strip the port, keeping IPv6 literals intact
+                match address.rfind(':') {
+                    Some(idx) if !address.ends_with(']') => Some(address[..idx].to_string()),
+                    _ => Some(address),
+                }
+            }
+        }
+    }
+
+    fn forward_metrics(&self) {
+        let config = config::get_config();
+        let headers = self.get_http_request_headers();
+        if let Some((name, value)) = config.upstream_metrics_header(&headers, self.metrics.as_ref())
+        {
+            self.set_http_request_header(name, value.as_deref());
+        }
+    }
+
+    fn authrep(&mut self, metrics: std::string::String) {
+        self.auth_call = Some(self.backend_call(AUTHREP_PATH, metrics));
+    }
+
+    fn authorize(&mut self, metrics: std::string::String) {
+        self.auth_call = Some(self.backend_call(AUTHORIZE_PATH, metrics));
+    }
+
+    // Report `usage` along with the usage the failed reports left.
+    fn report(&mut self, mut usage: Usage) {
+        let service_id = config::get_config().id;
+        for (metric, delta) in pending_reports::take(&self.shared_data(), service_id) {
+            let total = usage.entry(metric).or_default();
+            *total = total.saturating_add(delta);
+        }
+        let body = serde_json::to_string(&usage).unwrap();
+        match backend_call(self, REPORT_PATH, body.as_str(), Duration::from_secs(5)) {
+            Ok(token) => self.report_call = Some((token, usage)),
+            Err(e) => {
+                log::warn!(
+                    "Cannot send the usage report, keeping it for the next one, err='{:?}', request_id='{}'",
+                    e,
+                    self.request_id
+                );
+                pending_reports::keep(&self.shared_data(), service_id, &usage);
+            }
+        }
+    }
+
+    fn backend_call(&self, path: &str, metrics: std::string::String) -> u32 {
+        backend_call(self, path, metrics.as_str(), Duration::from_secs(5)).unwrap()
+    }
+}
+
+/// Envoy shared data, as the filters of every worker share it, the failed
+/// writes being logged along with `request_id` when any.
+#[derive(Default)]
+struct SharedData<'a> {
+    request_id: Option<&'a str>,
+}
+
+impl SharedStore for SharedData<'_> {
+    fn get(&self, key: &str) -> (Option<Vec<u8>>, Option<u32>) {
+        proxy_wasm::hostcalls::get_shared_data(key).unwrap()
+    }
+
+    fn set(&self, key: &str, value: &[u8], cas: Option<u32>) -> bool {
+        match proxy_wasm::hostcalls::set_shared_data(key, Some(value), cas) {
+            Ok(()) => true,
+            Err(Status::CasMismatch) => false,
+            Err(e) => {
+                log::error!(
+                    "Cannot store shared data '{}', err='{:?}', request_id='{}'",
+                    key,
+                    e,
+                    self.request_id.unwrap_or_default()
+                );
+                // treat it as stored, there is nothing a retry would fix
+                true
+            }
+        }
     }
 }
 
 impl HttpContext for HttpHeaders {
     fn on_http_request_headers(&mut self, _: usize) -> Action {
         let config = config::get_config();
+        let now = self
+            .get_current_time()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default();
+        self.request_id = filter_config::request_id(
+            self.get_http_request_header(REQUEST_ID_HEADER),
+            self.context_id,
+            now.as_nanos() as u64,
+        );
 
-        let (status, metrics) =
-            config.match_mapping_rule(self.get_method().unwrap(), self.get_path().unwrap());
-        if !status {
-            self.send_http_response(403, vec![], Some(b"Mapping rule not found\n"));
-        } else {
-            self.authrep(metrics);
+        // Never let a client-provided value through to the upstream, nothing
+        // having matched yet.
+        let headers = self.get_http_request_headers();
+        if let Some((name, value)) = config.upstream_metrics_header(&headers, None) {
+            self.set_http_request_header(name, value.as_deref());
+        }
+
+        if let Some(ref limits) = config.local_limits {
+            if let Some(key) = self.local_limits_key(&limits.key) {
+                self.credential_type = access_log::credential_type(&limits.key);
+                if !limits.check(&self.shared_data(), config.id, key.as_str(), now.as_secs()) {
+                    log::info!(
+                        "Local limits exceeded for service {}, request_id='{}'",
+                        config.id,
+                        self.request_id
+                    );
+                    self.tell_decision(false);
+                    let body = self.denial_body(&limits.body);
+                    self.send_http_response(limits.status, vec![], Some(body.as_bytes()));
+                    return Action::Pause;
+                }
+            }
+        }
+
+        match config.decide(
+            self.get_method().unwrap(),
+            self.get_path().unwrap(),
+            &self.request_id,
+        ) {
+            Decision::Authrep(metrics) => {
+                let payload = serde_json::to_string(&metrics).unwrap();
+                if config.report_on.on_response() {
+                    self.authorize(payload);
+                } else {
+                    self.authrep(payload);
+                }
+                self.metrics = Some(metrics);
+            }
+            Decision::Allow => {
+                self.tell_decision(true);
+                return Action::Continue;
+            }
+            Decision::Deny(status, body) => {
+                self.tell_decision(false);
+                self.send_http_response(status, vec![], Some(body.as_bytes()))
+            }
         }
         Action::Pause
     }
 
+    fn on_http_response_headers(&mut self, _: usize) -> Action {
+        let config = config::get_config();
+        if !config.report_on.on_response() {
+            return Action::Continue;
+        }
+
+        let status = self
+            .get_http_response_header(":status")
+            .and_then(|status| status.parse::<u32>().ok());
+        if let (Some(status), Some(metrics)) = (status, self.metrics.clone()) {
+            if config.report_on.should_report(status) {
+                self.report(metrics);
+            } else {
+                log::debug!(
+                    "Not reporting usage for upstream status {}, request_id='{}'",
+                    status,
+                    self.request_id
+                );
+            }
+        }
+        Action::Continue
+    }
+
     fn on_log(&mut self) {
-        info!("#Request with context_id='{}' completed.", self.context_id);
+        info!(
+            "#Request with context_id='{}' completed, request_id='{}'",
+            self.context_id, self.request_id
+        );
     }
 }